@@ -0,0 +1,6 @@
+// 从 proto/coke_db.proto 生成 gRPC 的代码 使用打包的 protoc 不需要另外安装
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    tonic_build::compile_protos("proto/coke_db.proto")?;
+    Ok(())
+}
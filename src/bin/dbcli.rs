@@ -1,16 +1,24 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Read;
 use std::ops::RangeInclusive;
+use std::path::PathBuf;
 
-use clap::{arg, Parser};
+use clap::{arg, Parser, ValueEnum};
 use coke_db::client::{self, Client};
 use coke_db::errors::*;
 use coke_db::sql::execution::ResultSet;
-use coke_db::sql::parser::laxer::{Laxer, Token};
+use coke_db::sql::parser::laxer::{Laxer, Token, KEYWORDS};
+use coke_db::sql::execution::Column;
+use coke_db::sql::Value;
 use coke_db::storage::kv::mvcc::Mode;
 use futures_util::future::ok;
+use rustyline::completion::{Completer, Pair};
+use rustyline::highlight::Highlighter;
 use rustyline::history::FileHistory;
 use rustyline::validate::{ValidationContext, ValidationResult, Validator};
-use rustyline::Editor;
-use rustyline_derive::{Completer, Helper, Highlighter, Hinter};
+use rustyline::{Context, Editor};
+use rustyline_derive::{Helper, Hinter};
 
 use std::result::Result as R;
 
@@ -23,11 +31,37 @@ async fn main() -> Result<()> {
 
     let c1 = DbCli::parse();
 
+    // 批量执行的时候只输出语句的结果
+    let script = match (&c1.execute, &c1.file) {
+        (Some(sql), _) => Some(sql.clone()),
+        (None, Some(path)) if path.as_os_str() == "-" => {
+            let mut script = String::new();
+            std::io::stdin().read_to_string(&mut script)?;
+            Some(script)
+        }
+        (None, Some(path)) => Some(std::fs::read_to_string(path)?),
+        (None, None) => None,
+    };
+    if let Some(script) = script {
+        let client = Client::new(&c1.host, c1.port).await?;
+        std::process::exit(batch(client, c1.format, &script).await?);
+    }
+
     println!("try to connect {}:{}", c1.host, c1.port);
     println!("use try to input \"!h\" to get help");
     let client = Client::new(&c1.host, c1.port).await?;
 
-    run(client).await?;
+    // 只做健康检查 供探针使用 没有就绪时返回非0
+    if c1.ping {
+        let health = client.ping().await?;
+        println!("{:?}", health);
+        if !health.ready {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
+    run(client, c1.format).await?;
 
     Ok(())
 }
@@ -48,24 +82,154 @@ struct DbCli {
     #[arg(default_value_t = 9653)]
     #[arg(help = "port column headers")]
     port: u16,
+    #[arg(long)]
+    #[arg(help = "check server health and exit")]
+    ping: bool,
+    #[arg(long)]
+    #[arg(value_enum, default_value_t = Format::Table)]
+    #[arg(help = "output format of query results")]
+    format: Format,
+    #[arg(short, long, conflicts_with = "file")]
+    #[arg(help = "execute the statements and exit")]
+    execute: Option<String>,
+    #[arg(short, long)]
+    #[arg(help = "execute the statements in the file and exit, - reads from stdin")]
+    file: Option<PathBuf>,
+}
+
+/// 查询结果的输出格式
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+enum Format {
+    /// 用 | 分隔的文本
+    Table,
+    /// 第一行是列名 需要的时候加上引号
+    Csv,
+    /// 每一行是一个json对象
+    Json,
+}
+
+impl Format {
+    fn print(&self, columns: &[Column], rows: Vec<Vec<Value>>) {
+        let names: Vec<&str> = columns
+            .iter()
+            .map(|c| c.name.as_deref().unwrap_or("?"))
+            .collect();
+        match self {
+            Format::Table => {
+                println!("{}", names.join("|"));
+                for row in rows.into_iter() {
+                    println!(
+                        "{}",
+                        row.into_iter()
+                            .map(|v| format!("{}", v))
+                            .collect::<Vec<_>>()
+                            .join("|")
+                    );
+                }
+            }
+            Format::Csv => {
+                println!(
+                    "{}",
+                    names.iter().map(|n| csv_field(n)).collect::<Vec<_>>().join(",")
+                );
+                for row in rows.into_iter() {
+                    println!(
+                        "{}",
+                        row.into_iter()
+                            .map(|v| match v {
+                                // null 输出成空字段
+                                Value::Null => String::new(),
+                                v => csv_field(&v.to_string()),
+                            })
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    );
+                }
+            }
+            Format::Json => {
+                for row in rows.into_iter() {
+                    // 按照列的顺序输出 不使用map 避免列的顺序被打乱
+                    let fields = names
+                        .iter()
+                        .zip(row.iter())
+                        .map(|(name, value)| format!("{}:{}", json_string(name), json_value(value)))
+                        .collect::<Vec<_>>();
+                    println!("{{{}}}", fields.join(","));
+                }
+            }
+        }
+    }
+}
+
+/// 包含分隔符 引号或者换行的字段需要用引号括起来 引号写两次
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn json_string(s: &str) -> String {
+    serde_json::Value::from(s).to_string()
+}
+
+fn json_value(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Integer(i) => i.to_string(),
+        // NaN和无穷大在json中没有对应的值 输出null
+        Value::Float(f) => serde_json::Number::from_f64(*f)
+            .map(|n| n.to_string())
+            .unwrap_or_else(|| "null".to_string()),
+        Value::String(s) => json_string(s),
+        // 直接输出十进制的数字 不经过浮点数
+        Value::Decimal(d) => d.to_string(),
+        Value::Bytes(_) => json_string(&value.to_string()),
+    }
 }
 
 struct Cli {
     client: Client,
-    editor: Editor<InputValidator, FileHistory>,
+    /// 批量执行的时候没有
+    editor: Option<Editor<InputHelper, FileHistory>>,
+    /// 查询结果的输出格式
+    format: Format,
+    /// 事务中的语句出错的时候自动回滚
+    abort_on_error: bool,
 }
 impl Cli {
+    /// 重新读取补全使用的表和列
+    async fn refresh_catalog(&mut self) -> Result<()> {
+        if self.editor.is_none() {
+            return Ok(());
+        }
+        let mut tables = BTreeMap::new();
+        for name in self.client.list_tables().await? {
+            let table = self.client.get_table(&name).await?;
+            tables.insert(name, table.columns.into_iter().map(|c| c.name).collect());
+        }
+        if let Some(helper) = self.editor.as_mut().and_then(|e| e.helper_mut()) {
+            helper.tables = tables;
+        }
+        Ok(())
+    }
+
     fn get_prompt(&self) -> Result<String> {
         let propmt = match self.client.txn() {
-            Some((id, _)) => {
-                format!("coke_db: {} >> ", id)
+            Some((id, Mode::ReadWrite)) => format!("coke_db: {} >> ", id),
+            Some((id, Mode::ReadOnly)) => format!("coke_db: {} (read-only) >> ", id),
+            Some((id, Mode::ReadCommitted)) => format!("coke_db: {} (read-committed) >> ", id),
+            Some((id, Mode::Snapshot { version })) => {
+                format!("coke_db: {} (snapshot {}) >> ", id, version)
             }
             None => "coke_db >> ".to_string(),
         };
         Ok(propmt)
     }
 
-    async fn execute(&self, query: &str) -> Result<()> {
+    async fn execute(&mut self, query: &str) -> Result<()> {
         if query.starts_with("!") {
             let mut command = query.split_whitespace();
             let mut getnext = || -> R<&str, Error> {
@@ -81,6 +245,14 @@ ctrl+c => quit
 !tables => get all tables
 !table <table> => get table
 !status => get status
+!ping => check server health
+!format csv|json|table => set output format of query results
+!begin => begin a transaction
+!commit => commit the transaction
+!rollback => roll back the transaction
+!abort-on-error on|off => roll back the transaction when a statement fails
+ctrl+c while executing => cancel the statement
+tab => complete keywords, tables and columns
 "
                     )
                 }
@@ -90,6 +262,7 @@ ctrl+c => quit
                     for table in tables {
                         println!("{table}")
                     }
+                    self.refresh_catalog().await?;
                 }
                 "!table" => {
                     let table = getnext()?;
@@ -100,14 +273,75 @@ ctrl+c => quit
                     let status = self.client.get_status().await?;
                     println!("server status {:#?}", status);
                 }
+                "!ping" => {
+                    let health = self.client.ping().await?;
+                    println!("server health {:#?}", health);
+                }
+                "!format" => {
+                    let format = getnext()?;
+                    self.format = Format::from_str(format, true)
+                        .map_err(|_| Error::Parse(format!("unknown format {}", format)))?;
+                    println!("output format is {:?}", self.format);
+                }
+                "!begin" => return self.execute_sql("begin transaction;").await,
+                "!commit" => return self.execute_sql("commit;").await,
+                "!rollback" => return self.execute_sql("rollback;").await,
+                "!abort-on-error" => {
+                    self.abort_on_error = match getnext()? {
+                        "on" => true,
+                        "off" => false,
+                        s => return Err(Error::Parse(format!("expected on or off, got {}", s))),
+                    };
+                    let state = if self.abort_on_error { "on" } else { "off" };
+                    println!("abort on error is {}", state);
+                }
                 de => {}
             }
             Ok(())
         } else if !query.is_empty() {
-            match self.client.execute(query).await? {
+            self.execute_sql(query).await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// 执行sql语句 输出每条语句的结果
+    async fn execute_sql(&mut self, query: &str) -> Result<()> {
+        // 执行的时候按下ctrl+c 取消正在执行的语句
+        let results = {
+            let execute = self.client.execute(query);
+            tokio::pin!(execute);
+            loop {
+                tokio::select! {
+                    result = &mut execute => break result,
+                    _ = tokio::signal::ctrl_c() => {
+                        println!("Cancelling statement");
+                        self.client.cancel().await?;
+                    }
+                }
+            }
+        };
+        let results = match results {
+            Ok(results) => results,
+            Err(error) => {
+                // 语句出错的时候回滚事务 不需要再手动执行 rollback
+                if let (true, Some((id, _))) = (self.abort_on_error, self.client.txn()) {
+                    self.client.execute("rollback;").await?;
+                    println!("Rolled back transaction {} after the error", id);
+                }
+                return Err(error);
+            }
+        };
+        // 表的定义变化之后重新读取补全使用的表
+        let mut ddl = false;
+        for result in results {
+            match result {
                 ResultSet::Begin { id, mode } => match mode {
                     Mode::ReadWrite => println!("Began transaction {}", id),
                     Mode::ReadOnly => println!("Began read-only transaction {}", id),
+                    Mode::ReadCommitted => {
+                        println!("Began read-committed transaction {}", id)
+                    }
                     Mode::Snapshot { version, .. } => println!(
                         "Began read-only transaction {} in snapshot at version {}",
                         id, version
@@ -115,61 +349,86 @@ ctrl+c => quit
                 },
                 ResultSet::Commit { id } => println!("Committed transaction {}", id),
                 ResultSet::Rollback { id } => println!("Rolled back transaction {}", id),
+                ResultSet::Savepoint { name } => println!("Created savepoint {}", name),
+                ResultSet::RollbackToSavepoint { name } => {
+                    println!("Rolled back to savepoint {}", name)
+                }
+                ResultSet::ReleaseSavepoint { name } => println!("Released savepoint {}", name),
                 ResultSet::Create { count } => println!("Created {} rows", count),
                 ResultSet::Delete { count } => println!("Deleted {} rows", count),
                 ResultSet::Update { count } => println!("Updated {} rows", count),
-                ResultSet::CreateTable { name } => println!("Created table {}", name),
-                ResultSet::DropTable { name } => println!("Dropped table {}", name),
-                ResultSet::Explain(plan) => println!("{}", plan.to_string()),
-                ResultSet::Query { columns, rows } => {
-                    println!(
-                        "{}",
-                        columns
-                            .iter()
-                            .map(|c| c.as_deref().unwrap_or("?"))
-                            .collect::<Vec<_>>()
-                            .join("|")
-                    );
-                    for row in rows.into_iter() {
-                        println!(
-                            "{}",
-                            row.into_iter()
-                                .map(|v| format!("{}", v))
-                                .collect::<Vec<_>>()
-                                .join("|")
-                        );
-                    }
+                ResultSet::CreateTable { name } => {
+                    ddl = true;
+                    println!("Created table {}", name)
+                }
+                ResultSet::DropTable { name } => {
+                    ddl = true;
+                    println!("Dropped table {}", name)
+                }
+                ResultSet::CreatePolicy { name } => println!("Created policy {}", name),
+                ResultSet::DropPolicy { name } => println!("Dropped policy {}", name),
+                ResultSet::CheckTable { name, rows } => {
+                    println!("Checked table {}, {} rows ok", name, rows)
+                }
+                ResultSet::Analyze { name, rows } => {
+                    println!("Analyzed table {}, {} rows", name, rows)
+                }
+                ResultSet::Backup { version, keys } => {
+                    println!("Backed up {} keys at version {}", keys, version)
+                }
+                ResultSet::Restore { version, keys } => {
+                    println!("Restored {} keys from version {}", keys, version)
+                }
+                ResultSet::Explain { plan, estimates } => {
+                    println!("{}", plan.format_explain(&estimates))
+                }
+                ResultSet::ExplainAnalyze { plan, stats } => {
+                    println!("{}", plan.format_analyze(&stats))
                 }
+                ResultSet::ExplainJson { json } => println!("{}", json),
+                ResultSet::Set { name, value } => println!("Set {} = {}", name, value),
+                ResultSet::Query { columns, rows } => self.format.print(&columns, rows),
             }
-            Ok(())
-        } else {
-            Ok(())
         }
+        if ddl {
+            self.refresh_catalog().await?;
+        }
+        Ok(())
     }
 }
 
 const PORT_RANGE: RangeInclusive<usize> = 1..=65535;
 
-async fn run(client: Client) -> Result<()> {
-    let mut editor: Editor<InputValidator, _> = Editor::new()?;
+async fn run(client: Client, format: Format) -> Result<()> {
+    let mut editor: Editor<InputHelper, _> = Editor::new()?;
     let history_path =
         std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".sql_history"));
     if let Some(history) = &history_path {
         let _ = editor.load_history(history);
     }
-    editor.set_helper(Some(InputValidator {}));
+    editor.set_helper(Some(InputHelper::default()));
 
-    let mut cli = Cli { client, editor };
+    let mut cli = Cli {
+        client,
+        editor: Some(editor),
+        format,
+        abort_on_error: false,
+    };
 
     let status = cli.client.get_status().await?;
     println!("{:?}", status);
+    cli.refresh_catalog().await?;
 
     loop {
         let propmt = cli.get_prompt()?;
+        let editor = match cli.editor.as_mut() {
+            Some(editor) => editor,
+            None => break,
+        };
 
-        let input = match cli.editor.readline(&propmt) {
+        let input = match editor.readline(&propmt) {
             Ok(input) => {
-                cli.editor.add_history_entry(&input)?;
+                editor.add_history_entry(&input)?;
                 Ok(input)
             }
             Err(err) => Err(err),
@@ -179,16 +438,94 @@ async fn run(client: Client) -> Result<()> {
         match cli.execute(&input).await {
             Ok(()) => {}
             error @ Err(Error::Internal(_)) => return error,
-            Err(error) => println!("Error: {}", error.to_string()),
+            Err(error) => {
+                println!("Error {}: {}", error.code(), error);
+                if let Some(snippet) = error.snippet() {
+                    println!("{}", snippet);
+                }
+            }
         }
     }
 
-    if let Some(history) = &history_path {
-        cli.editor.save_history(&history)?;
+    if let (Some(history), Some(editor)) = (&history_path, cli.editor.as_mut()) {
+        editor.save_history(&history)?;
     }
     Ok(())
 }
 
+/// 依次执行脚本中的语句 输出每条语句的结果 出错的时候停止 返回进程的退出码
+async fn batch(client: Client, format: Format, script: &str) -> Result<i32> {
+    let mut cli = Cli {
+        client,
+        editor: None,
+        format,
+        abort_on_error: false,
+    };
+    let statements = match split_statements(script) {
+        Ok(statements) => statements,
+        Err(error) => {
+            print_error(&error);
+            return Ok(1);
+        }
+    };
+    for statement in statements {
+        match cli.execute_sql(statement).await {
+            Ok(()) => {}
+            error @ Err(Error::Internal(_)) => return error.map(|_| 1),
+            Err(error) => {
+                print_error(&error);
+                return Ok(1);
+            }
+        }
+    }
+    Ok(0)
+}
+
+/// 错误输出到标准错误 批量执行的时候不和结果混在一起
+fn print_error(error: &Error) {
+    eprintln!("Error {}: {}", error.code(), error);
+    if let Some(snippet) = error.snippet() {
+        eprintln!("{}", snippet);
+    }
+}
+
+/// 按照分号把脚本分成语句 字符串和注释中的分号不算 最后没有分号的语句也返回 执行的时候报错
+fn split_statements(script: &str) -> Result<Vec<&str>> {
+    // 位置的行和列都从1开始 列按照字符计算
+    let offset = |position: Position| -> usize {
+        let mut start = 0;
+        for (i, line) in script.split_inclusive('\n').enumerate() {
+            if i + 1 == position.line {
+                return start
+                    + line
+                        .char_indices()
+                        .nth(position.column - 1)
+                        .map(|(i, _)| i)
+                        .unwrap_or(line.len());
+            }
+            start += line.len();
+        }
+        script.len()
+    };
+    let mut laxer = Laxer::new(script);
+    let mut statements = Vec::new();
+    let (mut start, mut empty) = (0, true);
+    while let Some(token) = laxer.get_next()? {
+        match token {
+            Token::Semicolon => {
+                let end = offset(laxer.start()) + ';'.len_utf8();
+                statements.push(script[start..end].trim());
+                (start, empty) = (end, true);
+            }
+            _ => empty = false,
+        }
+    }
+    if !empty {
+        statements.push(script[start..].trim());
+    }
+    Ok(statements)
+}
+
 fn host_default() -> String {
     "127.0.0.1".to_string()
 }
@@ -204,11 +541,156 @@ fn port_in_range(s: &str) -> R<u16, String> {
     }
 }
 
-#[derive(Completer, Helper, Highlighter, Hinter)]
-struct InputValidator;
+/// 补全 高亮和检查输入 表和列在启动的时候和执行DDL之后从服务器读取
+#[derive(Default, Helper, Hinter)]
+struct InputHelper {
+    /// 表名到列名
+    tables: BTreeMap<String, Vec<String>>,
+}
+
+const COMMANDS: &[&str] = &[
+    "!abort-on-error",
+    "!begin",
+    "!commit",
+    "!format",
+    "!h",
+    "!help",
+    "!ping",
+    "!rollback",
+    "!status",
+    "!table",
+    "!tables",
+];
+
+fn word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+fn is_keyword(word: &str) -> bool {
+    KEYWORDS.binary_search(&word.to_uppercase().as_str()).is_ok()
+}
+
+impl InputHelper {
+    /// 以 word 开头的关键字 表名和列名 输入小写的时候关键字也用小写
+    fn candidates(&self, word: &str) -> BTreeSet<String> {
+        let matches = |name: &str| {
+            name.len() >= word.len()
+                && name.is_char_boundary(word.len())
+                && name[..word.len()].eq_ignore_ascii_case(word)
+        };
+        let mut candidates: BTreeSet<String> =
+            self.tables.keys().filter(|t| matches(t)).cloned().collect();
+        if word.is_empty() {
+            return candidates;
+        }
+        candidates.extend(self.tables.values().flatten().filter(|c| matches(c)).cloned());
+        let lower = word.starts_with(|c: char| c.is_lowercase());
+        candidates.extend(KEYWORDS.iter().filter(|k| matches(k)).map(|k| match lower {
+            true => k.to_lowercase(),
+            false => k.to_string(),
+        }));
+        candidates
+    }
+}
+
+impl Completer for InputHelper {
+    type Candidate = Pair;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<Pair>)> {
+        let line = &line[..pos];
+        // 光标前面的单词 表名.列名也算一个单词 ! 开头的命令用空白分开
+        let command = line.starts_with('!');
+        let start = line
+            .char_indices()
+            .rev()
+            .find(|(_, c)| match command {
+                true => c.is_whitespace(),
+                false => !(word_char(*c) || *c == '.'),
+            })
+            .map(|(i, c)| i + c.len_utf8())
+            .unwrap_or(0);
+        let word = &line[start..];
+        let candidates: Vec<String> = if command {
+            match line.split_whitespace().next() {
+                _ if start == 0 => {
+                    COMMANDS.iter().filter(|c| c.starts_with(word)).map(|c| c.to_string()).collect()
+                }
+                Some("!table") => {
+                    self.tables.keys().filter(|t| t.starts_with(word)).cloned().collect()
+                }
+                Some("!format") => ["csv", "json", "table"]
+                    .iter()
+                    .filter(|f| f.starts_with(word))
+                    .map(|f| f.to_string())
+                    .collect(),
+                Some("!abort-on-error") => ["off", "on"]
+                    .iter()
+                    .filter(|f| f.starts_with(word))
+                    .map(|f| f.to_string())
+                    .collect(),
+                _ => Vec::new(),
+            }
+        } else if let Some((table, column)) = word.split_once('.') {
+            self.tables
+                .get(table)
+                .into_iter()
+                .flatten()
+                .filter(|c| c.starts_with(column))
+                .map(|c| format!("{}.{}", table, c))
+                .collect()
+        } else {
+            self.candidates(word).into_iter().collect()
+        };
+        let pairs = candidates
+            .into_iter()
+            .map(|c| Pair {
+                display: c.clone(),
+                replacement: c,
+            })
+            .collect();
+        Ok((start, pairs))
+    }
+}
+
+impl Highlighter for InputHelper {
+    /// 关键字用蓝色加粗 字符串中的不算
+    fn highlight<'l>(&self, line: &'l str, _: usize) -> Cow<'l, str> {
+        if line.starts_with('!') {
+            return Cow::Borrowed(line);
+        }
+        let mut highlighted = String::with_capacity(line.len());
+        let mut rest = line;
+        while let Some(c) = rest.chars().next() {
+            let len = match c {
+                '"' | '\'' => rest[1..].find(c).map(|i| i + 2).unwrap_or(rest.len()),
+                c if word_char(c) => rest.find(|c| !word_char(c)).unwrap_or(rest.len()),
+                c => c.len_utf8(),
+            };
+            let (token, next) = rest.split_at(len);
+            if word_char(c) && is_keyword(token) {
+                highlighted.push_str("\x1b[1;34m");
+                highlighted.push_str(token);
+                highlighted.push_str("\x1b[0m");
+            } else {
+                highlighted.push_str(token);
+            }
+            rest = next;
+        }
+        Cow::Owned(highlighted)
+    }
+
+    fn highlight_char(&self, _: &str, _: usize) -> bool {
+        true
+    }
+}
 
 // 检查是否合法
-impl Validator for InputValidator {
+impl Validator for InputHelper {
     fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
         let input = ctx.input();
 
@@ -217,15 +699,19 @@ impl Validator for InputValidator {
             return Ok(ValidationResult::Valid(None));
         }
 
+        // 粘贴多条语句的时候 最后一个token是分号才算结束
+        let mut last = None;
         for result in Laxer::new(ctx.input()) {
             match result {
-                Ok(Token::Semicolon) => return Ok(ValidationResult::Valid(None)),
+                Ok((token, _)) => last = Some(token),
                 Err(_) => return Ok(ValidationResult::Valid(None)),
-                _ => {}
             }
         }
-        // 如果上面都没有返回就说明语句没有结束
-        Ok(ValidationResult::Incomplete)
+        match last {
+            Some(Token::Semicolon) => Ok(ValidationResult::Valid(None)),
+            // 语句没有结束
+            _ => Ok(ValidationResult::Incomplete),
+        }
     }
 
     fn validate_while_typing(&self) -> bool {
@@ -1,14 +1,28 @@
 use clap::{arg, command, Parser};
-use coke_db::{errors::*, storage::kv::b_tree::BtreeStore, server::Server};
+use coke_db::storage::kv::cache::DEFAULT_ROW_CACHE_SIZE;
+use coke_db::storage::kv::mvcc::DEFAULT_COMMIT_LOG_RETENTION;
+use coke_db::storage::registry::{Registry, StoreOptions};
+use coke_db::storage::wal::SyncPolicy;
+use coke_db::sql::execution::parallel;
+use coke_db::sql::engine::{
+    cache::DEFAULT_PLAN_CACHE_SIZE, default_workers, Engine, DEFAULT_MAX_CURSORS,
+    DEFAULT_WORK_MEMORY,
+};
+use coke_db::{
+    errors::*,
+    server::{Options, Server},
+};
 use config::File;
 use log::{debug, info};
 use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::time::Duration;
 #[tokio::main]
 pub async fn main() -> Result<()> {
     // parse and get config
     let dbSer = DbSer::parse();
     println!("db: {:?}", dbSer);
-    let config = Config::new(&dbSer.config)?;
+    let config = Config::new(&dbSer)?;
     println!("{:?}", config);
     debug!("get config : \n {:?}", config);
 
@@ -18,17 +32,125 @@ pub async fn main() -> Result<()> {
     logconfig.add_filter_allow_str("coke_db");
     simplelog::SimpleLogger::init(loglevel, logconfig.build())?;
 
-    //let data_dir = std::path::Path::new(&config.data_dir);
+    // data_dir 为空的时候只保存在内存中
+    let sync = match config.wal_sync.as_str() {
+        "always" => SyncPolicy::Always,
+        "interval" => SyncPolicy::Interval(Duration::from_millis(config.wal_sync_interval)),
+        sync => return Err(Error::Config(format!("unknown wal_sync {}", sync))),
+    };
+    let registry = Registry::default();
+    let backend = match config.storage.as_str() {
+        "wal" if config.data_dir.is_empty() => "memory",
+        "memory" => "memory",
+        _ if config.data_dir.is_empty() => {
+            let message = format!("storage {} needs data_dir", config.storage);
+            return Err(Error::Config(message));
+        }
+        storage => storage,
+    };
+    let store_options = StoreOptions {
+        dir: config.data_dir.clone().into(),
+        sync,
+    };
+    info!("storage backend is {}", backend);
+    let open_store = |name: &str| registry.open(backend, name, &store_options);
+    let options = Options {
+        vacuum_interval: Duration::from_secs(config.vacuum_interval),
+        scan_batch_size: config.scan_batch_size,
+        work_memory: config.work_memory,
+        workers: match config.parallel_workers {
+            0 => default_workers(),
+            n => n,
+        },
+        shutdown_timeout: Duration::from_secs(config.shutdown_timeout),
+        slow_query_threshold: match config.slow_query_threshold {
+            0 => None,
+            ms => Some(Duration::from_millis(ms)),
+        },
+        bloom_filter: config.bloom_filter,
+        row_cache_size: config.row_cache_size,
+        plan_cache_size: config.plan_cache_size,
+        commit_log_retention: config.commit_log_retention,
+        admin_password: Some(config.admin_password.clone()).filter(|p| !p.is_empty()),
+        max_cursors: config.max_cursors,
+    };
 
-    let store = BtreeStore::new();
-    let server = Server::new(&config.listen_sql_addr, Box::new(store));
+    // 所有会话的连接和聚合共享这些线程
+    parallel::init_pool(options.workers);
     info!("server will listen on {}",config.listen_sql_addr);
     debug!("server id is {}",config.id);
-    server.server().await?;
+    // 地址为空的时候不提供监控指标 http查询接口 postgres协议和grpc接口
+    if !config.listen_metrics_addr.is_empty() {
+        info!("metrics will listen on {}", config.listen_metrics_addr);
+    }
+    if !config.listen_http_addr.is_empty() {
+        info!("http will listen on {}", config.listen_http_addr);
+    }
+    if !config.listen_pg_addr.is_empty() {
+        info!("postgres protocol will listen on {}", config.listen_pg_addr);
+    }
+    if !config.listen_grpc_addr.is_empty() {
+        info!("grpc will listen on {}", config.listen_grpc_addr);
+    }
+    match config.engine.as_str() {
+        "kv" => {
+            let server = Server::new(
+                &config.listen_sql_addr,
+                open_store("sql")?,
+                &options,
+            )?;
+            serve(server, &config).await?;
+        }
+        "raft" => {
+            let server = Server::new_raft(
+                &config.id,
+                &config.listen_sql_addr,
+                &config.listen_raft_addr,
+                config.peers.clone(),
+                open_store("raft")?,
+                open_store("sql")?,
+                &options,
+            )?;
+            info!("raft will listen on {}", config.listen_raft_addr);
+            serve(server, &config).await?;
+        }
+        "replica" => {
+            if config.primary_addr.is_empty() {
+                return Err(Error::Config("replica engine needs primary_addr".into()));
+            }
+            let server = Server::new_replica(
+                &config.listen_sql_addr,
+                &config.primary_addr,
+                open_store("sql")?,
+                &options,
+            )?;
+            info!("replicate from {}", config.primary_addr);
+            serve(server, &config).await?;
+        }
+        engine => return Err(Error::Config(format!("unknown engine {}", engine))),
+    }
     Ok(())
 }
 
-
+async fn serve<E>(mut server: Server<E>, config: &Config) -> Result<()>
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    if !config.listen_metrics_addr.is_empty() {
+        server = server.with_metrics(&config.listen_metrics_addr);
+    }
+    if !config.listen_http_addr.is_empty() {
+        server = server.with_http(&config.listen_http_addr);
+    }
+    if !config.listen_pg_addr.is_empty() {
+        server = server.with_postgres(&config.listen_pg_addr);
+    }
+    if !config.listen_grpc_addr.is_empty() {
+        server = server.with_grpc(&config.listen_grpc_addr);
+    }
+    server.server().await
+}
 
 #[derive(Parser)]
 #[command(name = "dbServer")]
@@ -38,9 +160,39 @@ pub async fn main() -> Result<()> {
 #[derive(Debug)]
 struct DbSer {
     #[arg(short, long)]
-    #[arg(default_value_t = default_file_path())]
-    #[arg(help = "config file path")]
-    config: String,
+    #[arg(help = "config file path, default is ~/.config/coke_db/coke_db.yml")]
+    config: Option<String>,
+    // 下面的参数会覆盖配置文件中的值
+    #[arg(long, help = "server id")]
+    id: Option<String>,
+    #[arg(long, help = "sql listen address")]
+    listen_sql_addr: Option<String>,
+    #[arg(long, help = "raft listen address")]
+    listen_raft_addr: Option<String>,
+    #[arg(long, help = "metrics http address, empty disables it")]
+    listen_metrics_addr: Option<String>,
+    #[arg(long, help = "http query api address, empty disables it")]
+    listen_http_addr: Option<String>,
+    #[arg(long, help = "postgres protocol address, empty disables it")]
+    listen_pg_addr: Option<String>,
+    #[arg(long, help = "grpc address, empty disables it")]
+    listen_grpc_addr: Option<String>,
+    #[arg(long, help = "log level")]
+    log_level: Option<String>,
+    #[arg(long, help = "data directory, empty keeps data only in memory")]
+    data_dir: Option<String>,
+    #[arg(long, alias = "store", help = "storage backend: memory, wal or file")]
+    storage: Option<String>,
+    #[arg(long, help = "sql engine: kv, raft or replica")]
+    engine: Option<String>,
+    #[arg(long, help = "sql address of the primary for the replica engine")]
+    primary_addr: Option<String>,
+    #[arg(long, help = "vacuum interval in seconds, 0 disables background vacuum")]
+    vacuum_interval: Option<u64>,
+    #[arg(long, help = "memory for sort and aggregation per statement in bytes")]
+    work_memory: Option<u64>,
+    #[arg(long, help = "threads for join and aggregation, 0 uses the cpu count")]
+    parallel_workers: Option<u64>,
 }
 
 fn default_file_path() -> String {
@@ -66,16 +218,106 @@ struct Config {
     listen_sql_addr: String,
     log_level: String,
     data_dir: String,
+    /// 垃圾回收间隔 单位秒
+    vacuum_interval: u64,
+    /// 扫描时每批从存储中拿取的数量
+    scan_batch_size: usize,
+    /// sql引擎 kv raft 或者 replica
+    engine: String,
+    /// replica 引擎复制的主节点的sql地址
+    primary_addr: String,
+    /// raft 监听的地址
+    listen_raft_addr: String,
+    /// 监控指标的http地址 空的时候不提供
+    listen_metrics_addr: String,
+    /// http查询接口的地址 空的时候不提供
+    listen_http_addr: String,
+    /// postgres协议的地址 空的时候不提供
+    listen_pg_addr: String,
+    /// grpc接口的地址 空的时候不提供
+    listen_grpc_addr: String,
+    /// raft 其他节点的id和地址
+    #[serde(default)]
+    peers: HashMap<String, String>,
+    /// 预写日志 fsync 的策略 always 或者 interval
+    wal_sync: String,
+    /// wal_sync 是 interval 的时候 fsync 的间隔 单位毫秒
+    wal_sync_interval: u64,
+    /// 存储 wal 写预写日志 memory 只保存在内存中
+    storage: String,
+    /// 每条语句的排序和聚合可以使用的内存 单位字节
+    work_memory: usize,
+    /// 连接和聚合使用的线程数 0 表示根据cpu数量决定
+    parallel_workers: usize,
+    /// 关闭的时候等待会话结束事务的时间 单位秒
+    shutdown_timeout: u64,
+    /// 超过这个时间的语句连同计划写到查询日志 单位毫秒 0 表示不记录
+    slow_query_threshold: u64,
+    /// 点查询之前先检查布隆过滤器
+    bloom_filter: bool,
+    /// 点查询缓存最多使用的字节数 0 表示不使用
+    row_cache_size: usize,
+    /// 计划缓存最多缓存的语句数 0 表示不缓存
+    plan_cache_size: usize,
+    /// 垃圾回收的时候保留最近多少条提交日志 0 表示全部保留
+    commit_log_retention: u64,
+    /// 会话切换成管理员用的密码 空的时候不能切换
+    admin_password: String,
+    /// 每个会话最多同时打开的游标数
+    max_cursors: usize,
 }
 
 impl Config {
-    fn new(config: &str) -> Result<Self> {
+    /// 读取配置文件 命令行参数优先
+    /// 没有指定配置文件的时候 默认位置的配置文件可以不存在
+    fn new(args: &DbSer) -> Result<Self> {
+        let file = match &args.config {
+            Some(path) => File::with_name(path),
+            None => File::with_name(&default_file_path()).required(false),
+        };
         let c = config::Config::builder()
             .set_default("id", "coke_db")?
             .set_default("listen_sql_addr", "0.0.0.0:9653")?
             .set_default("log_level", "info")?
             .set_default("data_dir", "")?
-            .add_source(File::with_name(config))
+            .set_default("vacuum_interval", 60)?
+            .set_default("scan_batch_size", 1024)?
+            .set_default("engine", "kv")?
+            .set_default("primary_addr", "")?
+            .set_default("listen_raft_addr", "0.0.0.0:9705")?
+            .set_default("listen_metrics_addr", "")?
+            .set_default("listen_http_addr", "")?
+            .set_default("listen_pg_addr", "")?
+            .set_default("listen_grpc_addr", "")?
+            .set_default("wal_sync", "always")?
+            .set_default("wal_sync_interval", 100)?
+            .set_default("storage", "wal")?
+            .set_default("work_memory", DEFAULT_WORK_MEMORY as u64)?
+            .set_default("parallel_workers", 0)?
+            .set_default("shutdown_timeout", 30)?
+            .set_default("slow_query_threshold", 1000)?
+            .set_default("bloom_filter", true)?
+            .set_default("row_cache_size", DEFAULT_ROW_CACHE_SIZE as u64)?
+            .set_default("plan_cache_size", DEFAULT_PLAN_CACHE_SIZE as u64)?
+            .set_default("commit_log_retention", DEFAULT_COMMIT_LOG_RETENTION)?
+            .set_default("admin_password", "")?
+            .set_default("max_cursors", DEFAULT_MAX_CURSORS as u64)?
+            .add_source(file)
+            .set_override_option("id", args.id.clone())?
+            .set_override_option("listen_sql_addr", args.listen_sql_addr.clone())?
+            .set_override_option("listen_raft_addr", args.listen_raft_addr.clone())?
+            .set_override_option("listen_metrics_addr", args.listen_metrics_addr.clone())?
+            .set_override_option("listen_http_addr", args.listen_http_addr.clone())?
+            .set_override_option("listen_pg_addr", args.listen_pg_addr.clone())?
+            .set_override_option("listen_grpc_addr", args.listen_grpc_addr.clone())?
+            .set_override_option("log_level", args.log_level.clone())?
+            .set_override_option("data_dir", args.data_dir.clone())?
+            .set_override_option("storage", args.storage.clone())?
+            .set_override_option("engine", args.engine.clone())?
+            .set_override_option("primary_addr", args.primary_addr.clone())?
+            .set_override_option("vacuum_interval", args.vacuum_interval)?
+            .set_override_option("work_memory", args.work_memory)?
+            .set_override_option("parallel_workers", args.parallel_workers)?
             .build()?;
         Ok(c.try_deserialize()?)
     }
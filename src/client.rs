@@ -1,20 +1,29 @@
 use crate::errors::*;
-use crate::server::{Request, Response};
-use crate::sql::execution::ResultSet;
-use crate::sql::Table;
-use crate::storage::kv::mvcc::{Mode, Status};
+use crate::server::{Health, Request, Response};
+use crate::sql::decimal::Decimal;
+use crate::sql::execution::{Columns, ResultSet, Row, Rows};
+use crate::sql::{ColumnType, Table, Value};
+use crate::sql::engine::{Change, Commit, KvItems, Status};
+use crate::storage::kv::mvcc::Mode;
 use futures::future::FutureExt as _;
 use futures::sink::SinkExt as _;
 use log::debug;
-use std::{cell::Cell, sync::Arc};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::{
+    cell::{Cell, RefCell},
+    sync::Arc,
+};
 
-use futures::stream::TryStreamExt as _;
+use futures::stream::{StreamExt as _, TryStreamExt as _};
 use futures_util::TryStream;
 
 use std::future::Future;
 use std::ops::{Deref, Drop};
 use tokio::net::{TcpStream, ToSocketAddrs};
 use tokio::sync::{Mutex, MutexGuard};
+use tokio::task::JoinHandle;
 use tokio_util::codec::{Framed, FramedRead, FramedWrite, LengthDelimitedCodec};
 
 /// 定义一个connection
@@ -30,26 +39,64 @@ type Connection = tokio_serde::Framed<
 pub struct Client {
     conn: Arc<Mutex<Connection>>,
     txn: Cell<Option<(u64, Mode)>>,
+    /// 服务端的地址 取消语句的时候需要建立新的连接
+    addr: (String, u16),
+    /// 服务端分配的会话id
+    session: u64,
+    /// 没有读完就丢弃的 RowStream 的每批行数和取消语句的任务
+    /// 下一个请求之前先等取消完成 再读完剩下的行 取消不会落到下一条语句上
+    draining: RefCell<Option<(usize, Option<JoinHandle<()>>)>>,
 }
 
 impl Client {
     /// Creates a new client
     pub async fn new(host: &str, port: u16) -> Result<Self> {
-        Ok(Self {
-            conn: Arc::new(Mutex::new(tokio_serde::Framed::new(
-                Framed::new(
-                    TcpStream::connect((host, port)).await?,
-                    LengthDelimitedCodec::new(),
-                ),
-                tokio_serde::formats::Bincode::default(),
-            ))),
+        let mut client = Self {
+            conn: Arc::new(Mutex::new(Self::connect(host, port).await?)),
             txn: Cell::new(None),
-        })
+            addr: (host.to_string(), port),
+            session: 0,
+            draining: RefCell::new(None),
+        };
+        client.session = match client.call(Request::Session).await? {
+            Response::Session(id) => id,
+            resp => return Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+        };
+        Ok(client)
+    }
+
+    async fn connect(host: &str, port: u16) -> Result<Connection> {
+        Ok(tokio_serde::Framed::new(
+            Framed::new(
+                TcpStream::connect((host, port)).await?,
+                LengthDelimitedCodec::new(),
+            ),
+            tokio_serde::formats::Bincode::default(),
+        ))
+    }
+
+    /// 取消当前正在执行的语句
+    /// 执行语句的连接在等待结果 所以通过一个新的连接发送取消请求
+    pub async fn cancel(&self) -> Result<()> {
+        cancel_session(self.addr.clone(), self.session).await
+    }
+
+    /// 读完丢弃的 RowStream 剩下的行
+    async fn drain(&self, conn: &mut Connection) -> Result<()> {
+        let (frame, cancel) = match self.draining.take() {
+            Some(draining) => draining,
+            None => return Ok(()),
+        };
+        if let Some(cancel) = cancel {
+            cancel.await.ok();
+        }
+        drain(conn, frame).await
     }
 
     /// Call a server method
-    async fn call(&self, request: Request) -> Result<Response> {
+    pub(crate) async fn call(&self, request: Request) -> Result<Response> {
         let mut conn = self.conn.lock().await;
+        self.drain(&mut conn).await?;
         debug!("send request : {:?}", request);
         conn.send(request).await?;
         debug!("send success");
@@ -59,15 +106,49 @@ impl Client {
         }
     }
 
-    pub async fn execute(&self, query: &str) -> Result<ResultSet> {
+    /// 执行一条查询 结果按照每批最多frame行从服务端发送过来 一边读取一边返回
+    /// 服务端在客户端读完一批之后才继续执行 两边都不需要缓存整个结果
+    /// 读取的时候占用这个连接 没有读完就丢弃的时候取消语句 下一个请求之前读完剩下的行
+    pub async fn execute_stream(&self, query: &str, frame: usize) -> Result<RowStream<'_>> {
+        let mut conn = self.conn.lock().await;
+        self.drain(&mut conn).await?;
+        conn.send(Request::Stream { sql: query.into(), frame }).await?;
+        match receive(&mut conn).await? {
+            Response::Cursor { columns, .. } => Ok(RowStream {
+                client: self,
+                conn,
+                columns,
+                frame,
+                rows: Vec::new().into_iter(),
+                done: false,
+            }),
+            resp => Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+        }
+    }
+
+
+    /// 执行一条或者多条语句 返回每条语句的结果
+    pub async fn execute(&self, query: &str) -> Result<Vec<ResultSet>> {
         debug!("try to query {}", query);
 
-        let resultset = match self.call(Request::Execute(query.into())).await? {
-            Response::Execute(rs) => rs,
-            resp => return Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+        let resultsets = match self.call(Request::Execute(query.into())).await {
+            Ok(Response::Execute(rs)) => rs,
+            // 出错之前执行完的语句同样会改变事务的状态
+            Ok(Response::Partial { results, error }) => {
+                self.track_txn(&results);
+                return Err(error);
+            }
+            Ok(resp) => return Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+            // 出错之前的语句可能开启或者结束了事务 从服务端读取事务的状态
+            Err(e) => {
+                if let Ok(Response::Txn(txn)) = self.call(Request::Txn).await {
+                    self.txn.set(txn);
+                }
+                return Err(e);
+            }
         };
 
-        debug!("get result {:?}", resultset);
+        debug!("get result {:?}", resultsets);
 
         // if let ResultSet::Query { columns, .. } = resultset {
         //     let mut rows = Vec::new();
@@ -87,13 +168,31 @@ impl Client {
         //     resultset = ResultSet::Query { columns, rows }
         // };
 
-        match &resultset {
-            ResultSet::Begin { id, mode } => self.txn.set(Some((*id, *mode))),
-            ResultSet::Commit { .. } => self.txn.set(None),
-            ResultSet::Rollback { .. } => self.txn.set(None),
-            _ => {}
+        self.track_txn(&resultsets);
+        Ok(resultsets)
+    }
+
+    /// 执行语句 把最后一条语句的查询结果转换成T
+    /// ```ignore
+    /// let rows: Vec<(i64, Option<String>)> = client.query("select id, name from t;").await?;
+    /// ```
+    pub async fn query<T: FromRow>(&self, query: &str) -> Result<Vec<T>> {
+        match self.execute(query).await?.pop() {
+            Some(ResultSet::Query { columns, rows }) => from_rows(&columns, rows),
+            r => Err(Error::Executor(format!("expected a query result, got {:?}", r))),
+        }
+    }
+
+    /// 根据语句的结果更新本地记录的事务状态
+    fn track_txn(&self, resultsets: &[ResultSet]) {
+        for resultset in resultsets.iter() {
+            match resultset {
+                ResultSet::Begin { id, mode } => self.txn.set(Some((*id, *mode))),
+                ResultSet::Commit { .. } => self.txn.set(None),
+                ResultSet::Rollback { .. } => self.txn.set(None),
+                _ => {}
+            }
         }
-        Ok(resultset)
     }
 
     ///  获得当前事务的状态
@@ -123,4 +222,664 @@ impl Client {
             resp => Err(Error::Executor(format!("Unexpected response: {:?}", resp))),
         }
     }
+
+    /// 健康检查
+    pub async fn ping(&self) -> Result<Health> {
+        match self.call(Request::Ping).await? {
+            Response::Pong(h) => Ok(h),
+            resp => Err(Error::Executor(format!("Unexpected response: {:?}", resp))),
+        }
+    }
+
+    /// 为一条查询打开服务端的游标 每次 fetch_next 读取最多batch_size行
+    /// 结果很大的时候不需要一次全部返回
+    pub async fn query_cursor(&self, query: &str, batch_size: usize) -> Result<Cursor<'_>> {
+        if batch_size == 0 {
+            return Err(Error::Executor("cursor batch size must be positive".into()));
+        }
+        match self.call(Request::OpenCursor(query.into())).await? {
+            Response::Cursor { id, columns } => Ok(Cursor {
+                client: self,
+                id,
+                columns,
+                batch_size,
+                done: false,
+            }),
+            resp => Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+        }
+    }
+
+    /// 订阅表中之后提交的行修改 通过新的连接接收 不影响当前连接执行语句
+    pub async fn subscribe(&self, table: &str) -> Result<Subscription> {
+        self.subscribe_after(table, None).await
+    }
+
+    /// 从某个序号之后继续订阅 断开之后使用 Subscription::seq 恢复
+    pub async fn subscribe_after(&self, table: &str, after: Option<u64>) -> Result<Subscription> {
+        let mut conn = Self::connect(&self.addr.0, self.addr.1).await?;
+        conn.send(Request::Subscribe {
+            table: table.to_string(),
+            after,
+        })
+        .await?;
+        match receive(&mut conn).await? {
+            Response::Subscribe(seq) => Ok(Subscription {
+                conn,
+                changes: VecDeque::new(),
+                seq,
+            }),
+            resp => Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+        }
+    }
+}
+
+/// 服务端的游标 读完之后服务端自动关闭 没有读完的时候需要调用close
+/// 没有关闭的游标在会话结束的时候释放
+pub struct Cursor<'a> {
+    client: &'a Client,
+    id: u64,
+    columns: Columns,
+    batch_size: usize,
+    /// 服务端的游标已经关闭
+    done: bool,
+}
+
+impl Cursor<'_> {
+    /// 查询结果的列
+    pub fn columns(&self) -> &Columns {
+        &self.columns
+    }
+
+    /// 下一批行 全部读完的时候返回None
+    pub async fn fetch_next(&mut self) -> Result<Option<Rows>> {
+        if self.done {
+            return Ok(None);
+        }
+        let request = Request::Fetch {
+            cursor: self.id,
+            count: self.batch_size,
+        };
+        let rows = match self.client.call(request).await {
+            Ok(Response::Fetch(rows)) => rows,
+            Ok(resp) => return Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+            // 出错的时候服务端已经关闭了游标
+            Err(e) => {
+                self.done = true;
+                return Err(e);
+            }
+        };
+        self.done = rows.len() < self.batch_size;
+        Ok((!rows.is_empty()).then_some(rows))
+    }
+
+    /// 不再读取剩下的行 释放服务端的游标
+    pub async fn close(mut self) -> Result<()> {
+        if self.done {
+            return Ok(());
+        }
+        self.done = true;
+        match self.client.call(Request::CloseCursor(self.id)).await? {
+            Response::CloseCursor(_) => Ok(()),
+            resp => Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+        }
+    }
+}
+
+/// execute_stream 返回的查询结果 每次产生一行
+pub struct RowStream<'a> {
+    client: &'a Client,
+    conn: MutexGuard<'a, Connection>,
+    columns: Columns,
+    frame: usize,
+    /// 当前这一批中还没有返回的行
+    rows: std::vec::IntoIter<Row>,
+    /// 服务端已经发送完最后一批或者出错
+    done: bool,
+}
+
+impl RowStream<'_> {
+    /// 查询结果的列
+    pub fn columns(&self) -> &Columns {
+        &self.columns
+    }
+}
+
+impl futures::Stream for RowStream<'_> {
+    type Item = Result<Row>;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        use std::task::Poll;
+        loop {
+            if let Some(row) = self.rows.next() {
+                return Poll::Ready(Some(Ok(row)));
+            }
+            if self.done {
+                return Poll::Ready(None);
+            }
+            let response = match futures::ready!(self.conn.poll_next_unpin(cx)) {
+                Some(Ok(response)) => response,
+                Some(Err(e)) => Err(e.into()),
+                None => Err(Error::Internal("server disconnect".to_string())),
+            };
+            match response {
+                Ok(Response::Fetch(rows)) => {
+                    self.done = rows.len() < self.frame;
+                    self.rows = rows.into_iter();
+                }
+                Ok(resp) => {
+                    self.done = true;
+                    let e = Error::Internal(format!("Unexpected response {:?}", resp));
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+            }
+        }
+    }
+}
+
+impl Drop for RowStream<'_> {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        // 在新的连接上取消语句 服务端停止执行 剩下要读的行不多
+        let cancel = tokio::runtime::Handle::try_current().ok().map(|runtime| {
+            let (addr, session) = (self.client.addr.clone(), self.client.session);
+            runtime.spawn(async move {
+                if let Err(e) = cancel_session(addr, session).await {
+                    debug!("cancel stream get error {}", e);
+                }
+            })
+        });
+        self.client.draining.replace(Some((self.frame, cancel)));
+    }
+}
+
+/// 在新的连接上取消一个会话正在执行的语句
+async fn cancel_session(addr: (String, u16), session: u64) -> Result<()> {
+    let mut conn = Client::connect(&addr.0, addr.1).await?;
+    conn.send(Request::Cancel(session)).await?;
+    match receive(&mut conn).await? {
+        Response::Cancel(_) => Ok(()),
+        resp => Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+    }
+}
+
+/// 读完丢弃的 RowStream 剩下的行 直到最后一批或者错误
+async fn drain(conn: &mut Connection, frame: usize) -> Result<()> {
+    loop {
+        match conn.try_next().await? {
+            Some(Ok(Response::Fetch(rows))) if rows.len() >= frame => continue,
+            Some(_) => return Ok(()),
+            None => return Err(Error::Internal("server disconnect".to_string())),
+        }
+    }
+}
+
+/// 连接池的配置
+#[derive(Clone, Debug)]
+pub struct PoolOptions {
+    /// 连接的数量
+    pub size: usize,
+    /// 连接断开之后最多重试几次重新连接
+    pub retries: usize,
+    /// 第一次重试之前等待的时间 之后每次翻倍
+    pub backoff: Duration,
+    /// 重试等待的最长时间
+    pub max_backoff: Duration,
+}
+
+impl Default for PoolOptions {
+    fn default() -> Self {
+        Self {
+            size: 4,
+            retries: 5,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+/// 多个连接组成的连接池 可以在多个任务之间共享
+/// 每个请求使用一个空闲的连接 所以事务需要在同一次execute中开始和结束
+/// 连接断开的时候请求返回错误 不会重试 下一次使用这个连接的时候重新连接
+pub struct Pool {
+    addr: (String, u16),
+    options: PoolOptions,
+    /// 断开的连接是None
+    conns: Vec<Mutex<Option<Connection>>>,
+    /// 下一个请求优先使用的连接
+    next: AtomicUsize,
+}
+
+impl Pool {
+    /// 建立全部的连接
+    pub async fn new(host: &str, port: u16, options: PoolOptions) -> Result<Self> {
+        if options.size == 0 {
+            return Err(Error::Config("pool size must be positive".into()));
+        }
+        let mut conns = Vec::with_capacity(options.size);
+        for _ in 0..options.size {
+            conns.push(Mutex::new(Some(Client::connect(host, port).await?)));
+        }
+        Ok(Self {
+            addr: (host.to_string(), port),
+            options,
+            conns,
+            next: AtomicUsize::new(0),
+        })
+    }
+
+    /// 连接的数量
+    pub fn size(&self) -> usize {
+        self.conns.len()
+    }
+
+    /// 在一个连接上执行一条或者多条语句
+    pub async fn execute(&self, query: &str) -> Result<Vec<ResultSet>> {
+        match self.pipeline(&[query]).await?.pop() {
+            Some(result) => result,
+            None => Err(Error::Internal("no response".into())),
+        }
+    }
+
+    /// 在同一个连接上先发送全部的请求再依次接收结果 只需要等待一次往返
+    /// 每个请求的结果是独立的 一个请求出错不影响之后的请求
+    pub async fn pipeline(&self, queries: &[&str]) -> Result<Vec<Result<Vec<ResultSet>>>> {
+        let mut guard = self.acquire().await?;
+        let Some(conn) = guard.as_mut() else {
+            return Err(Error::Internal("pool connection is not established".into()));
+        };
+        let results = match Self::send_all(conn, queries).await {
+            Ok(results) => results,
+            Err(e) => {
+                *guard = None;
+                return Err(e);
+            }
+        };
+
+        // 出错的时候不知道事务有没有结束 回滚之后连接才能给之后的请求使用
+        let mut open = false;
+        let mut failed = false;
+        for result in results.iter() {
+            match result {
+                Ok(resultsets) => {
+                    for resultset in resultsets {
+                        match resultset {
+                            ResultSet::Begin { .. } => open = true,
+                            ResultSet::Commit { .. } | ResultSet::Rollback { .. } => open = false,
+                            _ => {}
+                        }
+                    }
+                }
+                Err(_) => failed = true,
+            }
+        }
+        if open || failed {
+            if let Err(e) = Self::send_all(conn, &["rollback;"]).await {
+                *guard = None;
+                return Err(e);
+            }
+        }
+        if open {
+            return Err(Error::Executor(
+                "transaction must be committed or rolled back in the same pool request".into(),
+            ));
+        }
+        Ok(results)
+    }
+
+    /// 发送的时候断开返回错误 语句执行的错误放在对应的结果中
+    async fn send_all(
+        conn: &mut Connection,
+        queries: &[&str],
+    ) -> Result<Vec<Result<Vec<ResultSet>>>> {
+        for query in queries {
+            conn.feed(Request::Execute(query.to_string())).await?;
+        }
+        conn.flush().await?;
+        let mut results = Vec::with_capacity(queries.len());
+        for _ in queries {
+            let result = match conn.try_next().await? {
+                Some(Ok(Response::Execute(resultsets))) => Ok(resultsets),
+                // 部分执行成功的请求同样按照出错处理 之后会回滚连接上的事务
+                Some(Ok(Response::Partial { error, .. })) => Err(error),
+                Some(Ok(resp)) => Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+                Some(Err(e)) => Err(e),
+                None => return Err(Error::Internal("server disconnect".to_string())),
+            };
+            results.push(result);
+        }
+        Ok(results)
+    }
+
+    /// 优先使用空闲的连接 都在使用中的时候排队等待其中一个
+    async fn acquire(&self) -> Result<MutexGuard<'_, Option<Connection>>> {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let n = self.conns.len();
+        let idle = (0..n).find_map(|i| self.conns[(start + i) % n].try_lock().ok());
+        let mut guard = match idle {
+            Some(guard) => guard,
+            None => self.conns[start % n].lock().await,
+        };
+        if guard.is_none() {
+            *guard = Some(self.reconnect().await?);
+        }
+        Ok(guard)
+    }
+
+    /// 按照指数退避重新连接
+    async fn reconnect(&self) -> Result<Connection> {
+        let mut backoff = self.options.backoff;
+        let mut attempt = 0;
+        loop {
+            match Client::connect(&self.addr.0, self.addr.1).await {
+                Ok(conn) => return Ok(conn),
+                Err(e) if attempt >= self.options.retries => return Err(e),
+                Err(e) => debug!("reconnect get error {}, retry after {:?}", e, backoff),
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.options.max_backoff);
+            attempt += 1;
+        }
+    }
+}
+
+/// 接收一个响应 server断开的时候返回错误
+async fn receive(conn: &mut Connection) -> Result<Response> {
+    match conn.try_next().await? {
+        Some(resp) => resp,
+        None => Err(Error::Internal("server disconnect".to_string())),
+    }
+}
+
+/// 按照提交的顺序接收一个表的行修改
+pub struct Subscription {
+    conn: Connection,
+    /// 已经收到还没有返回的修改
+    changes: VecDeque<Change>,
+    /// 修改已经全部返回的最后一个事务的序号
+    seq: u64,
+}
+
+impl Subscription {
+    /// 等待下一个修改 server关闭的时候返回错误
+    pub async fn next(&mut self) -> Result<Change> {
+        while self.changes.is_empty() {
+            match receive(&mut self.conn).await? {
+                Response::Changes(changes) => self.changes.extend(changes),
+                resp => return Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+            }
+        }
+        let change = self.changes.pop_front().ok_or(Error::Internal("no change".into()))?;
+        // 一个事务的修改总是在同一个响应中
+        if self.changes.front().is_none_or(|c| c.seq != change.seq) {
+            self.seq = change.seq;
+        }
+        Ok(change)
+    }
+
+    /// 修改已经全部返回的最后一个事务的序号 从这里恢复订阅不会丢失也不会重复
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+/// 按照提交的顺序接收server的提交日志 副本使用
+pub struct Replication {
+    conn: Connection,
+    /// 开始复制的序号 有快照的时候是快照对应的序号
+    seq: u64,
+    /// 还有没有接收完的快照
+    snapshot: bool,
+    /// 已经收到还没有返回的提交
+    commits: VecDeque<Commit>,
+}
+
+impl Replication {
+    /// 作为副本复制server的提交日志 after为None的时候先接收全部数据的快照
+    pub async fn connect(host: &str, port: u16, after: Option<u64>) -> Result<Self> {
+        let mut conn = Client::connect(host, port).await?;
+        conn.send(Request::Replicate(after)).await?;
+        match receive(&mut conn).await? {
+            Response::Replicate { seq, snapshot } => Ok(Self {
+                conn,
+                seq,
+                snapshot,
+                commits: VecDeque::new(),
+            }),
+            resp => Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+        }
+    }
+
+    /// 开始复制的序号 有快照的时候是快照对应的序号
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// 是否需要先接收快照
+    pub fn has_snapshot(&self) -> bool {
+        self.snapshot
+    }
+
+    /// 快照的下一页 快照结束的时候返回None
+    pub async fn next_snapshot(&mut self) -> Result<Option<KvItems>> {
+        if !self.snapshot {
+            return Ok(None);
+        }
+        match receive(&mut self.conn).await? {
+            Response::Dump(items) if items.is_empty() => {
+                self.snapshot = false;
+                Ok(None)
+            }
+            Response::Dump(items) => Ok(Some(items)),
+            resp => Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+        }
+    }
+
+    /// 等待下一个提交 需要先接收完快照
+    pub async fn next(&mut self) -> Result<Commit> {
+        if self.snapshot {
+            return Err(Error::Internal("snapshot is not finished".to_string()));
+        }
+        while self.commits.is_empty() {
+            match receive(&mut self.conn).await? {
+                Response::Commits(commits) => self.commits.extend(commits),
+                resp => return Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+            }
+        }
+        let commit = self.commits.pop_front().ok_or(Error::Internal("no commit".into()))?;
+        self.seq = commit.seq;
+        Ok(commit)
+    }
+}
+
+/// 查询结果中的一个值转换成Self
+pub trait FromValue: Sized {
+    /// 这个类型的列能否转换成Self 类型未知的列只检查每个值
+    fn accepts(column_type: &ColumnType) -> bool;
+    /// null 只能转换成 Option
+    fn from_value(value: Value) -> Result<Self>;
+}
+
+macro_rules! from_value {
+    ($ty:ty, $($column:pat => $variant:ident),+) => {
+        impl FromValue for $ty {
+            fn accepts(column_type: &ColumnType) -> bool {
+                matches!(column_type, $($column)|+)
+            }
+
+            fn from_value(value: Value) -> Result<Self> {
+                match value {
+                    $(Value::$variant(v) => Ok(v.into()),)+
+                    v => Err(Error::Executor(format!(
+                        "can not convert {} to {}",
+                        v,
+                        std::any::type_name::<Self>()
+                    ))),
+                }
+            }
+        }
+    };
+}
+
+from_value!(bool, ColumnType::Bool => Bool);
+from_value!(i64, ColumnType::Integer => Integer);
+from_value!(String, ColumnType::String => String);
+from_value!(Vec<u8>, ColumnType::Bytes => Bytes);
+from_value!(Decimal, ColumnType::Decimal(..) => Decimal);
+
+/// 整数可以转换成浮点数
+impl FromValue for f64 {
+    fn accepts(column_type: &ColumnType) -> bool {
+        matches!(column_type, ColumnType::Float | ColumnType::Integer)
+    }
+
+    fn from_value(value: Value) -> Result<Self> {
+        match value {
+            Value::Float(f) => Ok(f),
+            Value::Integer(i) => Ok(i as f64),
+            v => Err(Error::Executor(format!("can not convert {} to f64", v))),
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn accepts(column_type: &ColumnType) -> bool {
+        T::accepts(column_type)
+    }
+
+    fn from_value(value: Value) -> Result<Self> {
+        match value {
+            Value::Null => Ok(None),
+            v => T::from_value(v).map(Some),
+        }
+    }
+}
+
+/// 查询结果的一行转换成Self 用 from_row! 定义的结构体按照名字对应列 元组按照位置对应列
+pub trait FromRow: Sized {
+    /// 检查结果的列 返回每个字段在行中的位置 只在开始的时候检查一次
+    fn positions(columns: &Columns) -> Result<Vec<usize>>;
+    fn from_row(row: Row, positions: &[usize]) -> Result<Self>;
+}
+
+/// 检查列的类型能否转换成T 返回列的位置
+#[doc(hidden)]
+pub fn check_column<T: FromValue>(columns: &Columns, position: usize, name: &str) -> Result<usize> {
+    let column = columns
+        .get(position)
+        .ok_or_else(|| Error::Executor(format!("no column {} in the result", name)))?;
+    match &column.column_type {
+        Some(column_type) if !T::accepts(column_type) => Err(Error::Executor(format!(
+            "column {} is {}, can not convert to {}",
+            name,
+            column_type,
+            std::any::type_name::<T>()
+        ))),
+        _ => Ok(position),
+    }
+}
+
+/// 按照名字找到列
+#[doc(hidden)]
+pub fn find_column<T: FromValue>(columns: &Columns, name: &str) -> Result<usize> {
+    let position = columns.iter().position(|c| c.name.as_deref() == Some(name));
+    check_column::<T>(columns, position.unwrap_or(columns.len()), name)
+}
+
+/// 取出行中的第i个值 出错的时候带上字段的名字
+#[doc(hidden)]
+pub fn take_value<T: FromValue>(row: &mut Row, i: usize, name: &str) -> Result<T> {
+    let value = std::mem::replace(&mut row[i], Value::Null);
+    T::from_value(value).map_err(|e| Error::Executor(format!("column {}: {}", name, e)))
+}
+
+macro_rules! from_row_tuple {
+    ($len:expr, $($ty:ident $i:tt),+) => {
+        impl<$($ty: FromValue),+> FromRow for ($($ty,)+) {
+            fn positions(columns: &Columns) -> Result<Vec<usize>> {
+                if columns.len() != $len {
+                    return Err(Error::Executor(format!(
+                        "expected {} columns, got {}",
+                        $len,
+                        columns.len()
+                    )));
+                }
+                Ok(vec![$(check_column::<$ty>(columns, $i, &$i.to_string())?),+])
+            }
+
+            fn from_row(mut row: Row, positions: &[usize]) -> Result<Self> {
+                Ok(($(take_value::<$ty>(&mut row, positions[$i], &$i.to_string())?,)+))
+            }
+        }
+    };
+}
+
+from_row_tuple!(1, A 0);
+from_row_tuple!(2, A 0, B 1);
+from_row_tuple!(3, A 0, B 1, C 2);
+from_row_tuple!(4, A 0, B 1, C 2, D 3);
+from_row_tuple!(5, A 0, B 1, C 2, D 3, E 4);
+from_row_tuple!(6, A 0, B 1, C 2, D 3, E 4, F 5);
+
+/// 定义一个结构体并实现 FromRow 每个字段对应查询结果中同名的列
+/// 列的类型不能转换成字段的类型的时候在读取行之前报错 可以是null的列使用 Option
+/// ```ignore
+/// coke_db::from_row! {
+///     #[derive(Debug)]
+///     pub struct User {
+///         pub id: i64,
+///         pub name: Option<String>,
+///     }
+/// }
+/// let users: Vec<User> = client.query("select id, name from users;").await?;
+/// ```
+#[macro_export]
+macro_rules! from_row {
+    (
+        $(#[$meta:meta])*
+        $vis:vis struct $name:ident {
+            $($(#[$field_meta:meta])* $field_vis:vis $field:ident : $ty:ty),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis struct $name {
+            $($(#[$field_meta])* $field_vis $field: $ty),*
+        }
+
+        impl $crate::client::FromRow for $name {
+            fn positions(
+                columns: &$crate::sql::execution::Columns,
+            ) -> $crate::errors::Result<Vec<usize>> {
+                Ok(vec![$($crate::client::find_column::<$ty>(columns, stringify!($field))?),*])
+            }
+
+            fn from_row(
+                mut row: $crate::sql::execution::Row,
+                positions: &[usize],
+            ) -> $crate::errors::Result<Self> {
+                let mut positions = positions.iter();
+                Ok(Self {
+                    $($field: $crate::client::take_value::<$ty>(
+                        &mut row,
+                        *positions.next().unwrap_or(&0),
+                        stringify!($field),
+                    )?),*
+                })
+            }
+        }
+    };
+}
+
+/// 把查询结果转换成T
+pub fn from_rows<T: FromRow>(columns: &Columns, rows: Rows) -> Result<Vec<T>> {
+    let positions = T::positions(columns)?;
+    rows.into_iter().map(|row| T::from_row(row, &positions)).collect()
 }
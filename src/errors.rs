@@ -31,18 +31,230 @@ pub enum Error {
     IO(String),
     Rustyline(String),
     Config(String),
-    LogError(String)
+    LogError(String),
+    /// 语句被取消或者执行超时
+    Cancelled(String),
+    /// 存储的数据校验失败
+    Corruption(String),
+    /// 带有错误码的sql错误 客户端可以根据错误码区分错误的种类
+    Sql(Box<SqlError>),
+}
+
+/// sql错误的种类 每种都有一个固定的 SQLSTATE 错误码
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// 语法错误
+    Syntax,
+    UndefinedTable,
+    UndefinedColumn,
+    /// 没有限定表名的列在多个表中都有
+    AmbiguousColumn,
+    DuplicateTable,
+    /// 同一个作用域中表名或者别名重复
+    DuplicateAlias,
+    /// 写冲突或者等待锁超时 重试事务可能成功
+    SerializationFailure,
+    /// 事务之间互相等待
+    Deadlock,
+    /// 只读事务中执行修改的语句
+    ReadOnlyTransaction,
+    /// 修改主键的时候新的主键已经存在
+    UniqueViolation,
+    /// 事务写入的行数或者字节数超过了限制
+    WriteLimitExceeded,
+    /// 只有管理员的会话可以执行 或者写入的行不满足行级安全策略
+    InsufficientPrivilege,
+    /// 保存点不存在
+    InvalidSavepoint,
+    /// AS OF 读取的版本已经被垃圾回收
+    SnapshotTooOld,
+    /// 会话打开的游标超过了限制
+    TooManyCursors,
+}
+
+impl ErrorCode {
+    pub fn sqlstate(&self) -> &'static str {
+        match self {
+            ErrorCode::Syntax => "42601",
+            ErrorCode::UndefinedTable => "42P01",
+            ErrorCode::UndefinedColumn => "42703",
+            ErrorCode::AmbiguousColumn => "42702",
+            ErrorCode::DuplicateTable => "42P07",
+            ErrorCode::DuplicateAlias => "42712",
+            ErrorCode::SerializationFailure => "40001",
+            ErrorCode::Deadlock => "40P01",
+            ErrorCode::ReadOnlyTransaction => "25006",
+            ErrorCode::UniqueViolation => "23505",
+            ErrorCode::WriteLimitExceeded => "54000",
+            ErrorCode::InsufficientPrivilege => "42501",
+            ErrorCode::InvalidSavepoint => "3B001",
+            ErrorCode::SnapshotTooOld => "72000",
+            ErrorCode::TooManyCursors => "54000",
+        }
+    }
+}
+
+/// 语句中的位置 行和列都从1开始 列按照字符计算
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Default for Position {
+    fn default() -> Self {
+        Self { line: 1, column: 1 }
+    }
+}
+
+/// 一个token所在的范围 end是token之后的位置
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SqlError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// 出错的token的位置 只有语法错误有
+    pub position: Option<Position>,
+    /// 出错的表名或者列名
+    pub identifier: Option<String>,
+    /// 出错的那一行输入 下面用^标出出错的token
+    pub snippet: Option<String>,
+}
+
+impl Error {
+    pub fn sql(code: ErrorCode, message: String) -> Self {
+        Error::Sql(Box::new(SqlError {
+            code,
+            message,
+            position: None,
+            identifier: None,
+            snippet: None,
+        }))
+    }
+
+    /// 带上出错的表名或者列名
+    pub fn with_identifier(self, identifier: &str) -> Self {
+        match self {
+            Error::Sql(mut e) => {
+                e.identifier = Some(identifier.to_string());
+                Error::Sql(e)
+            }
+            e => e,
+        }
+    }
+
+    /// 语法错误带上出错的位置和input中对应的一行 其他错误不变
+    pub fn at(self, span: Span, input: &str) -> Self {
+        match self {
+            Error::Parse(message) => Error::Sql(Box::new(SqlError {
+                code: ErrorCode::Syntax,
+                message,
+                position: Some(span.start),
+                identifier: None,
+                snippet: Some(snippet(input, span)),
+            })),
+            e => e,
+        }
+    }
+
+    /// SQLSTATE 风格的错误码 没有细分种类的错误按照类型给一个大类的错误码
+    pub fn code(&self) -> &'static str {
+        match self {
+            Error::Sql(e) => e.code.sqlstate(),
+            Error::Parse(_) => "42601",
+            Error::Plan(_) | Error::Schema(_) => "42000",
+            Error::Evaluate(_) => "22000",
+            Error::Table(_) | Error::Row(_) | Error::Index(_) => "23000",
+            Error::Cancelled(_) => "57014",
+            Error::IO(_) => "58030",
+            Error::Config(_) => "F0000",
+            Error::Corruption(_) => "XX001",
+            // 可以重试的写冲突是 Error::Sql 这里的都是内部错误 重试也不会成功
+            Error::Lock(_)
+            | Error::Mvcc(_)
+            | Error::Optimizer(_)
+            | Error::Encoding(_)
+            | Error::BinCode(_)
+            | Error::Internal(_)
+            | Error::Executor(_)
+            | Error::Rustyline(_)
+            | Error::LogError(_) => "XX000",
+        }
+    }
+
+    /// 重新执行整个事务可能成功的错误 例如写冲突和死锁
+    pub fn retryable(&self) -> bool {
+        matches!(
+            self,
+            Error::Sql(e) if matches!(e.code, ErrorCode::SerializationFailure | ErrorCode::Deadlock)
+        )
+    }
+
+    /// 出错的位置 只有语法错误有
+    pub fn position(&self) -> Option<Position> {
+        match self {
+            Error::Sql(e) => e.position,
+            _ => None,
+        }
+    }
+
+    /// 标出出错位置的输入片段 只有语法错误有
+    pub fn snippet(&self) -> Option<&str> {
+        match self {
+            Error::Sql(e) => e.snippet.as_deref(),
+            _ => None,
+        }
+    }
+}
+
+/// 输出span开始的那一行 前面是行号 下一行用^标出span 跨行的span标到行尾
+/// 输入结束的地方没有token 标一个^
+fn snippet(input: &str, span: Span) -> String {
+    let line = input.split('\n').nth(span.start.line - 1).unwrap_or("");
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    let chars = line.chars().count();
+    let start = (span.start.column - 1).min(chars);
+    let end = match span.end.line == span.start.line {
+        true => (span.end.column - 1).min(chars),
+        false => chars,
+    };
+    // tab保留下来 ^才能和上面的字符对齐
+    let indent: String = line
+        .chars()
+        .take(start)
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let number = span.start.line.to_string();
+    let gutter = " ".repeat(number.len());
+    format!(
+        "{} |\n{} | {}\n{} | {}{}",
+        gutter,
+        number,
+        line,
+        gutter,
+        indent,
+        "^".repeat(end.saturating_sub(start).max(1))
+    )
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> fmt::Result {
         use Error::*;
         match self {
-            LogError(s)|Config(s) | Rustyline(s) | IO(s) | Executor(s) | Index(s) | Mvcc(s) | Lock(s)
+            Corruption(s) | Cancelled(s) | LogError(s)|Config(s) | Rustyline(s) | IO(s) | Executor(s) | Index(s) | Mvcc(s) | Lock(s)
             | Internal(s) | Row(s) | Table(s) | BinCode(s) | Parse(s) | Schema(s) | Plan(s)
             | Evaluate(s) | Optimizer(s) | Encoding(s) => {
                 write!(f, "{}", s)
             }
+            Sql(e) => match e.position {
+                Some(p) => write!(f, "{} at line {}, column {}", e.message, p.line, p.column),
+                None => write!(f, "{}", e.message),
+            },
         }
     }
 }
@@ -77,6 +289,12 @@ impl From<Box<bincode::ErrorKind>> for Error {
     }
 }
 
+impl From<serde_json::Error> for Error {
+    fn from(value: serde_json::Error) -> Self {
+        Self::Encoding(value.to_string())
+    }
+}
+
 impl<T> From<PoisonError<T>> for Error {
     fn from(value: PoisonError<T>) -> Self {
         Self::Lock(format!("error get value {:?} lock", value))
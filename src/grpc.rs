@@ -0,0 +1,358 @@
+//! gRPC 的接口 定义在 proto/coke_db.proto 中 其他语言的客户端可以用它生成代码
+//! 和 http 接口一样不在连接上保存会话 开启事务之后返回 txn 之后的请求带上它在同一个事务中执行
+//! 出错的时候 status 的 metadata 中 sqlstate 是错误码
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::error;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::TcpListenerStream;
+use tonic::metadata::MetadataValue;
+use tonic::{Code, Request, Response, Status};
+
+use crate::errors::*;
+use crate::http::{self, Txns, TXN_IDLE_TIMEOUT};
+use crate::server::blocking;
+use crate::sql::decimal::Decimal;
+use crate::sql::engine::{Engine, SqlSession};
+use crate::sql::execution::ResultSet;
+use crate::sql::schema::Catalog;
+use crate::storage::kv::mvcc::Mode;
+
+/// 从 proto 生成的消息 服务端和客户端
+pub mod proto {
+    tonic::include_proto!("coke_db");
+}
+
+use proto::database_server::{Database, DatabaseServer};
+use proto::{result, value};
+
+struct Service<E: Engine> {
+    engine: E,
+    txns: Arc<Txns<E>>,
+    slow_query: Option<Duration>,
+}
+
+pub(crate) async fn serve<E>(listener: TcpListener, engine: E, slow_query: Option<Duration>)
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    let txns = Arc::new(Txns::new());
+    tokio::spawn(http::expire(txns.clone(), TXN_IDLE_TIMEOUT));
+    let service = Service {
+        engine,
+        txns,
+        slow_query,
+    };
+    let result = tonic::transport::Server::builder()
+        .add_service(DatabaseServer::new(service))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await;
+    if let Err(e) = result {
+        error!("grpc server get error {}", e);
+    }
+}
+
+#[tonic::async_trait]
+impl<E> Database for Service<E>
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    async fn execute(
+        &self,
+        request: Request<proto::ExecuteRequest>,
+    ) -> std::result::Result<Response<proto::ExecuteResponse>, Status> {
+        let request = request.into_inner();
+        let params = request.params.into_iter().map(param).collect::<Result<Vec<_>>>();
+        let params = params.map_err(status)?;
+        let mut session = match self.session(&request.txn).map_err(status)? {
+            Some(session) => session,
+            None => return Err(not_found(&request.txn)),
+        };
+        let sql = request.sql;
+        let (session, result) = blocking(move || {
+            let result = session.execute_params(&sql, params);
+            Ok((session, result))
+        })
+        .await
+        .map_err(status)?;
+        // 还在事务中的时候保存会话 出错的时候客户端可以用 ResumeTransaction 检查事务是否还在
+        let txn = match session.in_transaction() {
+            true => {
+                let token = match request.txn.is_empty() {
+                    true => self.txns.token(),
+                    false => request.txn,
+                };
+                self.txns.put(token.clone(), session).map_err(status)?;
+                token
+            }
+            false => String::new(),
+        };
+        let results = match result {
+            Ok(results) => results,
+            // 出错之前的语句可能开启了事务 metadata 中带上事务的标识
+            Err(partial) => {
+                let mut status = status(partial.error);
+                match MetadataValue::try_from(txn.as_str()) {
+                    Ok(value) if !txn.is_empty() => {
+                        status.metadata_mut().insert("txn", value);
+                    }
+                    _ => {}
+                }
+                return Err(status);
+            }
+        };
+        let results = results.into_iter().map(result_message).collect::<Result<_>>();
+        Ok(Response::new(proto::ExecuteResponse {
+            results: results.map_err(status)?,
+            txn,
+        }))
+    }
+
+    async fn status(
+        &self,
+        _: Request<proto::StatusRequest>,
+    ) -> std::result::Result<Response<proto::StatusResponse>, Status> {
+        let engine = self.engine.clone();
+        let s = blocking(move || engine.status()).await.map_err(status)?;
+        Ok(Response::new(proto::StatusResponse {
+            txns: s.mvcc.txns,
+            txns_active: s.mvcc.txns_active,
+            storage: s.mvcc.storage,
+            keys: s.mvcc.keys,
+            versions: s.mvcc.versions,
+            size: s.mvcc.size,
+            tables: s.tables.into_iter().collect(),
+        }))
+    }
+
+    async fn list_tables(
+        &self,
+        _: Request<proto::ListTablesRequest>,
+    ) -> std::result::Result<Response<proto::ListTablesResponse>, Status> {
+        let mut session = self.engine.session().map_err(status)?;
+        let tables = blocking(move || session.with_txn(Mode::ReadOnly, |txn| txn.scan_tables()))
+            .await
+            .map_err(status)?;
+        Ok(Response::new(proto::ListTablesResponse {
+            tables: tables.into_iter().map(|t| t.name).collect(),
+        }))
+    }
+
+    async fn resume_transaction(
+        &self,
+        request: Request<proto::ResumeTransactionRequest>,
+    ) -> std::result::Result<Response<proto::ResumeTransactionResponse>, Status> {
+        let token = request.into_inner().txn;
+        let session = match self.session(&token).map_err(status)? {
+            Some(session) => session,
+            None => return Err(not_found(&token)),
+        };
+        let transaction = session.txn().map(|(id, mode)| transaction(id, mode));
+        self.txns.put(token, session).map_err(status)?;
+        Ok(Response::new(proto::ResumeTransactionResponse { transaction }))
+    }
+}
+
+impl<E> Service<E>
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    /// txn 为空的时候使用新的会话 否则取出 txn 对应的会话 事务不存在的时候返回None
+    fn session(&self, txn: &str) -> Result<Option<SqlSession<E>>> {
+        if txn.is_empty() {
+            return Ok(Some(self.engine.session()?.with_slow_query(self.slow_query)));
+        }
+        self.txns.take(txn)
+    }
+}
+
+fn not_found(txn: &str) -> Status {
+    Status::not_found(format!("unknown transaction {}", txn))
+}
+
+/// 错误码对应 gRPC 的状态 sqlstate 放在 metadata 中
+fn status(error: Error) -> Status {
+    let code = match &error {
+        // 写冲突和死锁重试事务可能成功 客户端按照 ABORTED 重试
+        e if e.retryable() => Code::Aborted,
+        Error::Sql(e) if e.code == ErrorCode::InsufficientPrivilege => Code::PermissionDenied,
+        Error::Parse(_) | Error::Sql(_) | Error::Plan(_) | Error::Schema(_) => {
+            Code::InvalidArgument
+        }
+        Error::Evaluate(_) => Code::OutOfRange,
+        Error::Table(_) | Error::Row(_) | Error::Index(_) => Code::FailedPrecondition,
+        Error::Cancelled(_) => Code::Cancelled,
+        _ => Code::Internal,
+    };
+    let mut status = Status::new(code, error.to_string());
+    let sqlstate = MetadataValue::from_static(error.code());
+    status.metadata_mut().insert("sqlstate", sqlstate);
+    status
+}
+
+fn param(value: proto::Value) -> Result<crate::sql::Value> {
+    use crate::sql::Value;
+    match value.value {
+        None | Some(value::Value::Null(_)) => Ok(Value::Null),
+        Some(value::Value::Bool(b)) => Ok(Value::Bool(b)),
+        Some(value::Value::Integer(i)) => Ok(Value::Integer(i)),
+        Some(value::Value::Float(f)) => Ok(Value::Float(f)),
+        Some(value::Value::String(s)) => Ok(Value::String(s)),
+        Some(value::Value::Decimal(d)) => Ok(Value::Decimal(Decimal::parse(&d)?)),
+        Some(value::Value::Bytes(b)) => Ok(Value::Bytes(b)),
+    }
+}
+
+fn value_message(value: &crate::sql::Value) -> proto::Value {
+    use crate::sql::Value;
+    let value = match value {
+        Value::Null => value::Value::Null(true),
+        Value::Bool(b) => value::Value::Bool(*b),
+        Value::Integer(i) => value::Value::Integer(*i),
+        Value::Float(f) => value::Value::Float(*f),
+        Value::String(s) => value::Value::String(s.clone()),
+        Value::Decimal(d) => value::Value::Decimal(d.to_string()),
+        Value::Bytes(b) => value::Value::Bytes(b.clone()),
+    };
+    proto::Value { value: Some(value) }
+}
+
+fn transaction(id: u64, mode: Mode) -> proto::Transaction {
+    let (mode, version) = match mode {
+        Mode::ReadWrite => (proto::Mode::ReadWrite, 0),
+        Mode::ReadOnly => (proto::Mode::ReadOnly, 0),
+        Mode::Snapshot { version } => (proto::Mode::Snapshot, version),
+        Mode::ReadCommitted => (proto::Mode::ReadCommitted, 0),
+    };
+    proto::Transaction {
+        id,
+        mode: mode.into(),
+        version,
+    }
+}
+
+/// 常用的结果有对应的消息 其他的结果和 http 接口一样用 json
+fn result_message(result: ResultSet) -> Result<proto::Result> {
+    let result = match result {
+        ResultSet::Query { columns, rows } => result::Result::Query(proto::Query {
+            columns: columns.into_iter().map(|c| c.name.unwrap_or_default()).collect(),
+            rows: rows
+                .iter()
+                .map(|row| proto::Row {
+                    values: row.iter().map(value_message).collect(),
+                })
+                .collect(),
+        }),
+        ResultSet::Create { count } => result::Result::Create(count),
+        ResultSet::Update { count } => result::Result::Update(count),
+        ResultSet::Delete { count } => result::Result::Delete(count),
+        ResultSet::Begin { id, mode } => result::Result::Begin(transaction(id, mode)),
+        ResultSet::Commit { id } => result::Result::Commit(id),
+        ResultSet::Rollback { id } => result::Result::Rollback(id),
+        ResultSet::Explain { plan, estimates } => {
+            result::Result::Explain(plan.format_explain(&estimates))
+        }
+        ResultSet::ExplainAnalyze { plan, stats } => {
+            result::Result::Explain(plan.format_analyze(&stats))
+        }
+        ResultSet::ExplainJson { json } => result::Result::Explain(json),
+        result => result::Result::Json(http::result_json(result)?.to_string()),
+    };
+    Ok(proto::Result {
+        result: Some(result),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::proto::database_client::DatabaseClient;
+    use super::*;
+    use crate::server::{spawn_test_server, test_server};
+
+    fn execute(sql: &str, params: Vec<value::Value>, txn: &str) -> proto::ExecuteRequest {
+        proto::ExecuteRequest {
+            sql: sql.to_string(),
+            params: params.into_iter().map(|v| proto::Value { value: Some(v) }).collect(),
+            txn: txn.to_string(),
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn grpc_test() -> Result<()> {
+        let addrs = spawn_test_server(test_server()?.with_grpc("127.0.0.1:0")).await?;
+        let grpc_addr = addrs.grpc.ok_or_else(|| Error::Internal("grpc not bound".into()))?;
+        let mut client = DatabaseClient::connect(format!("http://{}", grpc_addr))
+            .await
+            .map_err(|e| Error::Internal(e.to_string()))?;
+        let call = |e: Status| Error::Internal(e.to_string());
+
+        let sql = "create table t ( id int primary key, s string ); \
+                   insert into t values ($1, $2);";
+        let params = vec![value::Value::Integer(1), value::Value::String("a".into())];
+        let response = client.execute(execute(sql, params, "")).await.map_err(call)?;
+        let response = response.into_inner();
+        assert!(response.txn.is_empty());
+        assert!(matches!(response.results[0].result, Some(result::Result::Json(_))));
+        assert_eq!(response.results[1].result, Some(result::Result::Create(1)));
+
+        // 在事务中执行 之后的请求带上 txn
+        let sql = "begin transaction; insert into t values (2, \"b\");";
+        let response = client.execute(execute(sql, vec![], "")).await.map_err(call)?;
+        let txn = response.into_inner().txn;
+        assert!(!txn.is_empty());
+        let request = proto::ResumeTransactionRequest { txn: txn.clone() };
+        let response = client.resume_transaction(request).await.map_err(call)?;
+        let transaction = response.into_inner().transaction.unwrap_or_default();
+        assert_eq!(transaction.mode(), proto::Mode::ReadWrite);
+        let sql = "select * from t where id = $1; commit;";
+        let params = vec![value::Value::Integer(2)];
+        let response = client.execute(execute(sql, params, &txn)).await.map_err(call)?;
+        let response = response.into_inner();
+        assert!(response.txn.is_empty());
+        let query = proto::Query {
+            columns: vec!["id".into(), "s".into()],
+            rows: vec![proto::Row {
+                values: vec![
+                    proto::Value { value: Some(value::Value::Integer(2)) },
+                    proto::Value { value: Some(value::Value::String("b".into())) },
+                ],
+            }],
+        };
+        assert_eq!(response.results[0].result, Some(result::Result::Query(query)));
+        assert_eq!(response.results[1].result, Some(result::Result::Commit(transaction.id)));
+
+        // 事务结束之后不存在 出错的时候带上错误码
+        let request = proto::ResumeTransactionRequest { txn };
+        let e = client.resume_transaction(request).await.unwrap_err();
+        assert_eq!(e.code(), Code::NotFound);
+        let e = client.execute(execute("select * from x;", vec![], "")).await.unwrap_err();
+        assert_eq!(e.code(), Code::InvalidArgument);
+        assert_eq!(e.metadata().get("sqlstate").map(|v| v.to_str().ok()), Some(Some("42P01")));
+
+        let response = client.list_tables(proto::ListTablesRequest {}).await.map_err(call)?;
+        assert_eq!(response.into_inner().tables, vec!["t"]);
+        let response = client.status(proto::StatusRequest {}).await.map_err(call)?;
+        assert_eq!(response.into_inner().tables.get("t"), Some(&2));
+        Ok(())
+    }
+
+    #[test]
+    fn status_test() {
+        // 可以重试的错误是 ABORTED 其他的sql错误是参数错误
+        for (code, expect) in [
+            (ErrorCode::SerializationFailure, Code::Aborted),
+            (ErrorCode::Deadlock, Code::Aborted),
+            (ErrorCode::InsufficientPrivilege, Code::PermissionDenied),
+            (ErrorCode::UndefinedTable, Code::InvalidArgument),
+        ] {
+            let s = status(Error::sql(code, "error".to_string()));
+            assert_eq!(s.code(), expect);
+            let sqlstate = s.metadata().get("sqlstate").and_then(|v| v.to_str().ok());
+            assert_eq!(sqlstate, Some(code.sqlstate()));
+        }
+    }
+}
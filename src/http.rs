@@ -0,0 +1,442 @@
+//! http 的查询接口 不需要 rust 的客户端 curl 和网页可以直接使用
+//! POST /query 的请求体是 {"sql": "...", "params": [...], "txn": "..."}
+//! 语句中的 $n 使用 params 中第n个值
+//! 成功的时候返回 {"results": [...]}
+//! 出错的时候返回 {"error": {"code": "...", "message": "..."}}
+//! 语句开启了事务的时候结果中带上 txn 之后的请求带上它在同一个事务中执行
+//! 同一个事务的请求需要依次发送 事务结束之后不再返回 txn
+//! 超过 TXN_IDLE_TIMEOUT 没有请求的事务被回滚
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::{debug, error, info};
+use serde_derive::Deserialize;
+use serde_json::{json, Value as Json};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::errors::*;
+use crate::server::blocking;
+use crate::sql::engine::{Engine, Partial, SqlSession};
+use crate::sql::execution::ResultSet;
+use crate::sql::Value;
+
+/// 事务最多空闲的时间 之后回滚
+pub const TXN_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// 请求头最大的长度
+const MAX_HEADER: usize = 8192;
+/// 请求体最大的长度
+const MAX_BODY: usize = 16 << 20;
+
+#[derive(Deserialize)]
+struct QueryRequest {
+    sql: String,
+    #[serde(default)]
+    params: Vec<Json>,
+    /// 之前的请求返回的事务
+    #[serde(default)]
+    txn: Option<String>,
+}
+
+/// 还在事务中的会话 和最后一次请求结束的时间 grpc 接口也使用
+pub(crate) struct Txns<E: Engine> {
+    sessions: Mutex<HashMap<String, (SqlSession<E>, Instant)>>,
+    next: AtomicU64,
+    random: RandomState,
+}
+
+impl<E: Engine> Txns<E> {
+    pub(crate) fn new() -> Self {
+        Self {
+            sessions: Mutex::new(HashMap::new()),
+            next: AtomicU64::new(1),
+            random: RandomState::new(),
+        }
+    }
+
+    /// 事务的标识 序号保证不重复 随机的部分防止被其他客户端猜到
+    pub(crate) fn token(&self) -> String {
+        let id = self.next.fetch_add(1, Ordering::Relaxed);
+        let mut hasher = self.random.build_hasher();
+        hasher.write_u64(id);
+        format!("{:x}-{:016x}", id, hasher.finish())
+    }
+
+    /// 取出会话 执行完还在事务中的时候再放回去
+    pub(crate) fn take(&self, token: &str) -> Result<Option<SqlSession<E>>> {
+        Ok(self.sessions.lock()?.remove(token).map(|(session, _)| session))
+    }
+
+    pub(crate) fn put(&self, token: String, session: SqlSession<E>) -> Result<()> {
+        self.sessions.lock()?.insert(token, (session, Instant::now()));
+        Ok(())
+    }
+
+    /// 取出空闲超过 timeout 的会话
+    fn expired(&self, timeout: Duration) -> Result<Vec<(String, SqlSession<E>)>> {
+        let mut sessions = self.sessions.lock()?;
+        let tokens: Vec<String> = sessions
+            .iter()
+            .filter(|(_, (_, used))| used.elapsed() >= timeout)
+            .map(|(token, _)| token.clone())
+            .collect();
+        Ok(tokens
+            .into_iter()
+            .filter_map(|token| sessions.remove(&token).map(|(session, _)| (token, session)))
+            .collect())
+    }
+}
+
+/// 每个连接只处理一个请求
+pub(crate) async fn serve<E>(listener: TcpListener, engine: E, slow_query: Option<Duration>)
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    let txns = Arc::new(Txns::new());
+    tokio::spawn(expire(txns.clone(), TXN_IDLE_TIMEOUT));
+    loop {
+        match listener.accept().await {
+            Ok((socket, _)) => {
+                let (engine, txns) = (engine.clone(), txns.clone());
+                tokio::spawn(async move {
+                    if let Err(e) = handle(socket, engine, txns, slow_query).await {
+                        debug!("http request get error {}", e);
+                    }
+                });
+            }
+            Err(e) => error!("http accept get error {}", e),
+        }
+    }
+}
+
+/// 定时回滚空闲的事务
+pub(crate) async fn expire<E>(txns: Arc<Txns<E>>, timeout: Duration)
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        ticker.tick().await;
+        let expired = match txns.expired(timeout) {
+            Ok(expired) => expired,
+            Err(e) => {
+                error!("expire transactions get error {}", e);
+                continue;
+            }
+        };
+        for (token, mut session) in expired {
+            match blocking(move || session.close()).await {
+                Ok(Some(id)) => info!("transaction {} is idle, rollback {}", token, id),
+                Ok(None) => {}
+                Err(e) => error!("rollback idle transaction get error {}", e),
+            }
+        }
+    }
+}
+
+async fn handle<E>(
+    mut socket: TcpStream,
+    engine: E,
+    txns: Arc<Txns<E>>,
+    slow_query: Option<Duration>,
+) -> Result<()>
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    let (status, body) = match read_request(&mut socket).await? {
+        Err(status) => (status, error_body(&Error::Parse(status.to_string()), None)),
+        Ok((method, path, body)) => match (method.as_str(), path.as_str()) {
+            ("POST", "/query") => match query(engine, txns, &body, slow_query).await {
+                Ok(response) => response,
+                Err(e) => ("500 Internal Server Error", error_body(&e, None)),
+            },
+            (_, "/query") => {
+                let error = Error::Parse(format!("method {} is not allowed", method));
+                ("405 Method Not Allowed", error_body(&error, None))
+            }
+            _ => ("404 Not Found", error_body(&Error::Parse(format!("no {}", path)), None)),
+        },
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\
+         Connection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+/// 读取请求行 请求头和 Content-Length 长度的请求体
+/// 请求不对的时候返回http的状态
+async fn read_request(
+    socket: &mut TcpStream,
+) -> Result<std::result::Result<(String, String, Vec<u8>), &'static str>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    let end = loop {
+        if let Some(i) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break i + 4;
+        }
+        if request.len() > MAX_HEADER {
+            return Ok(Err("431 Request Header Fields Too Large"));
+        }
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(Err("400 Bad Request"));
+        }
+        request.extend_from_slice(&buf[..n]);
+    };
+    let header = String::from_utf8_lossy(&request[..end]).to_string();
+    let mut lines = header.split("\r\n");
+    let mut line = lines.next().unwrap_or_default().split_whitespace();
+    let (method, path) = match (line.next(), line.next()) {
+        (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+        _ => return Ok(Err("400 Bad Request")),
+    };
+    let mut length = 0;
+    for line in lines {
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                length = match value.trim().parse::<usize>() {
+                    Ok(length) => length,
+                    Err(_) => return Ok(Err("400 Bad Request")),
+                };
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Ok(Err("413 Payload Too Large"));
+    }
+    let mut body = request.split_off(end);
+    while body.len() < length {
+        let n = socket.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(Err("400 Bad Request"));
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+    body.truncate(length);
+    Ok(Ok((method, path, body)))
+}
+
+/// 执行一个请求 返回http的状态和结果
+async fn query<E>(
+    engine: E,
+    txns: Arc<Txns<E>>,
+    body: &[u8],
+    slow_query: Option<Duration>,
+) -> Result<(&'static str, String)>
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    let request: QueryRequest = match serde_json::from_slice(body) {
+        Ok(request) => request,
+        Err(e) => return Ok(("400 Bad Request", error_body(&e.into(), None))),
+    };
+    let params = match request.params.iter().map(param).collect::<Result<Vec<_>>>() {
+        Ok(params) => params,
+        Err(e) => return Ok(("400 Bad Request", error_body(&e, request.txn.as_deref()))),
+    };
+    let mut session = match &request.txn {
+        Some(token) => match txns.take(token)? {
+            Some(session) => session,
+            None => {
+                let error = Error::Executor(format!("unknown transaction {}", token));
+                return Ok(("404 Not Found", error_body(&error, None)));
+            }
+        },
+        None => engine.session()?.with_slow_query(slow_query),
+    };
+    let sql = request.sql;
+    let (session, result) = blocking(move || {
+        let result = session.execute_params(&sql, params);
+        Ok((session, result))
+    })
+    .await?;
+    // 还在事务中的时候保存会话 之前没有事务的时候分配一个新的标识
+    let txn = match session.in_transaction() {
+        true => {
+            let token = request.txn.unwrap_or_else(|| txns.token());
+            txns.put(token.clone(), session)?;
+            Some(token)
+        }
+        false => None,
+    };
+    let results = match result {
+        Ok(results) => results,
+        // 出错之前执行完的语句的结果也返回 客户端可以知道事务有没有开启
+        Err(Partial { results, error }) => {
+            let mut body = error_json(&error, txn.as_deref());
+            if !results.is_empty() {
+                let results = results.into_iter().map(result_json).collect::<Result<Vec<_>>>()?;
+                body["results"] = Json::from(results);
+            }
+            return Ok(("400 Bad Request", body.to_string()));
+        }
+    };
+    let results = results.into_iter().map(result_json).collect::<Result<Vec<_>>>()?;
+    let mut body = json!({ "results": results });
+    if let Some(txn) = txn {
+        body["txn"] = Json::from(txn);
+    }
+    Ok(("200 OK", body.to_string()))
+}
+
+fn error_body(error: &Error, txn: Option<&str>) -> String {
+    error_json(error, txn).to_string()
+}
+
+fn error_json(error: &Error, txn: Option<&str>) -> Json {
+    let mut detail = json!({ "code": error.code(), "message": error.to_string() });
+    if let Error::Sql(e) = error {
+        if let Some(position) = e.position {
+            detail["position"] = json!({ "line": position.line, "column": position.column });
+        }
+    }
+    let mut body = json!({ "error": detail });
+    if let Some(txn) = txn {
+        body["txn"] = Json::from(txn);
+    }
+    body
+}
+
+/// 查询的结果中的值直接用json的值 其他的结果使用serde的编码
+pub(crate) fn result_json(result: ResultSet) -> Result<Json> {
+    match result {
+        ResultSet::Query { columns, rows } => {
+            let columns: Vec<Json> = columns.iter().map(|c| Json::from(c.name.clone())).collect();
+            let rows: Vec<Json> = rows
+                .iter()
+                .map(|row| Json::Array(row.iter().map(value_json).collect()))
+                .collect();
+            Ok(json!({ "Query": { "columns": columns, "rows": rows } }))
+        }
+        result => Ok(serde_json::to_value(result)?),
+    }
+}
+
+/// NaN和无穷大在json中没有对应的值 输出null 定点数输出字符串避免丢失精度
+fn value_json(value: &Value) -> Json {
+    match value {
+        Value::Null => Json::Null,
+        Value::Bool(b) => Json::from(*b),
+        Value::Integer(i) => Json::from(*i),
+        Value::Float(f) => serde_json::Number::from_f64(*f).map(Json::Number).unwrap_or_default(),
+        Value::String(s) => Json::from(s.as_str()),
+        Value::Decimal(d) => Json::from(d.to_string()),
+        Value::Bytes(_) => Json::from(value.to_string()),
+    }
+}
+
+fn param(value: &Json) -> Result<Value> {
+    match value {
+        Json::Null => Ok(Value::Null),
+        Json::Bool(b) => Ok(Value::Bool(*b)),
+        Json::Number(n) => match n.as_i64() {
+            Some(i) => Ok(Value::Integer(i)),
+            None => Ok(Value::Float(n.as_f64().unwrap_or(f64::NAN))),
+        },
+        Json::String(s) => Ok(Value::String(s.clone())),
+        value => Err(Error::Parse(format!("unsupported parameter {}", value))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{spawn_test_server, test_server};
+    use crate::sql::engine::kv::test_engine;
+
+    async fn post(port: u16, body: &str) -> Result<(String, Json)> {
+        let mut socket = TcpStream::connect(("127.0.0.1", port)).await?;
+        let request = format!(
+            "POST /query HTTP/1.1\r\nHost: localhost\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        socket.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        socket.read_to_string(&mut response).await?;
+        let (head, body) = response.split_once("\r\n\r\n").unwrap_or_default();
+        let status = head.lines().next().unwrap_or_default().to_string();
+        Ok((status, serde_json::from_str(body)?))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn http_test() -> Result<()> {
+        let addrs = spawn_test_server(test_server()?.with_http("127.0.0.1:0")).await?;
+        let http_port = addrs.http.map(|addr| addr.port()).unwrap_or_default();
+
+        let sql = r#"{"sql": "create table t ( id int primary key, s string );"}"#;
+        let (status, body) = post(http_port, sql).await?;
+        assert_eq!(status, "HTTP/1.1 200 OK", "{}", body);
+        assert_eq!(body["results"][0]["CreateTable"]["name"], "t");
+
+        // 参数作为值 不会被当作sql
+        let sql = r#"{"sql": "insert into t values ($1, $2);",
+                      "params": [1, "a\"; drop table t;"]}"#;
+        assert_eq!(post(http_port, sql).await?.1["results"][0]["Create"]["count"], 1);
+        let (_, body) = post(http_port, r#"{"sql": "select * from t;"}"#).await?;
+        assert_eq!(body["results"][0]["Query"]["columns"], json!(["id", "s"]));
+        assert_eq!(body["results"][0]["Query"]["rows"], json!([[1, "a\"; drop table t;"]]));
+
+        // 事务跨越多个请求 结束之后不再返回txn
+        let sql = r#"{"sql": "begin transaction; insert into t values (2, \"b\");"}"#;
+        let (_, body) = post(http_port, sql).await?;
+        let txn = body["txn"].as_str().map(|t| t.to_string()).unwrap_or_default();
+        assert!(!txn.is_empty(), "{}", body);
+        let (_, body) = post(http_port, r#"{"sql": "select id from t;"}"#).await?;
+        assert_eq!(body["results"][0]["Query"]["rows"], json!([[1]]));
+        let sql = json!({ "sql": "select id from t where id = $1;", "params": [2], "txn": txn });
+        let (_, body) = post(http_port, &sql.to_string()).await?;
+        assert_eq!(body["results"][0]["Query"]["rows"], json!([[2]]));
+        assert_eq!(body["txn"], txn);
+        // 事务中出错的时候事务还在
+        let sql = json!({ "sql": "select * from x;", "txn": txn });
+        let (status, body) = post(http_port, &sql.to_string()).await?;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        assert_eq!(body["error"]["code"], "42P01");
+        assert_eq!(body["txn"], txn);
+        let commit = json!({ "sql": "commit;", "txn": txn }).to_string();
+        let (_, body) = post(http_port, &commit).await?;
+        assert!(body.get("txn").is_none(), "{}", body);
+        let (status, _) = post(http_port, &commit).await?;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+
+        let (status, body) = post(http_port, r#"{"sql": "select $1;"}"#).await?;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        assert_eq!(body["error"]["code"], "42601");
+        let (status, _) = post(http_port, "not json").await?;
+        assert_eq!(status, "HTTP/1.1 400 Bad Request");
+        Ok(())
+    }
+
+    #[test]
+    fn expire_test() -> Result<()> {
+        let engine = test_engine();
+        let txns = Txns {
+            sessions: Mutex::new(HashMap::new()),
+            next: AtomicU64::new(1),
+            random: RandomState::new(),
+        };
+        let (a, b) = (txns.token(), txns.token());
+        assert_ne!(a, b);
+        txns.put(a.clone(), engine.session()?)?;
+        assert_eq!(txns.expired(Duration::from_secs(60))?.len(), 0);
+        let expired = txns.expired(Duration::ZERO)?;
+        assert_eq!(expired.into_iter().map(|(t, _)| t).collect::<Vec<_>>(), [a]);
+        assert!(txns.take(&b)?.is_none());
+        Ok(())
+    }
+}
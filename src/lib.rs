@@ -5,7 +5,13 @@ pub mod storage;
 pub mod errors;
 pub mod client;
 pub mod server;
+pub mod metrics;
+pub mod http;
+pub mod grpc;
+pub mod pgwire;
+pub mod replica;
 pub mod util;
+pub mod raft;
 
 /// .
 fn hello() {
@@ -0,0 +1,126 @@
+//! 服务端的监控指标 以 prometheus 的文本格式输出
+//! 语句和执行节点的统计是进程内全局的 存储相关的指标在输出的时候从引擎的计数器中读取
+//! 需要扫描存储的键数和表的行数不在这里输出 使用 status 查看
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::sql::engine::Metrics;
+
+/// 执行节点耗时直方图的桶 单位秒
+const BUCKETS: [f64; 10] = [0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0, 5.0];
+
+/// 每种语句执行的次数
+static STATEMENTS: Mutex<BTreeMap<&'static str, u64>> = Mutex::new(BTreeMap::new());
+/// 每种执行节点的耗时
+static NODES: Mutex<BTreeMap<&'static str, Histogram>> = Mutex::new(BTreeMap::new());
+
+/// 耗时的直方图 counts[i] 是耗时不超过 BUCKETS[i] 的次数 不是累计的
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Histogram {
+    pub counts: [u64; BUCKETS.len()],
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl Histogram {
+    fn observe(&mut self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        if let Some(i) = BUCKETS.iter().position(|b| secs <= *b) {
+            self.counts[i] += 1;
+        }
+        self.count += 1;
+        self.sum += elapsed;
+    }
+}
+
+/// 记录执行了一条语句
+pub fn record_statement(kind: &'static str) {
+    let mut statements = STATEMENTS.lock().unwrap_or_else(|e| e.into_inner());
+    *statements.entry(kind).or_default() += 1;
+}
+
+/// 记录一个执行节点的耗时 包括子节点
+pub fn record_node(name: &'static str, elapsed: Duration) {
+    let mut nodes = NODES.lock().unwrap_or_else(|e| e.into_inner());
+    nodes.entry(name).or_default().observe(elapsed);
+}
+
+/// 每种语句执行的次数
+pub fn statements() -> BTreeMap<&'static str, u64> {
+    STATEMENTS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// 每种执行节点的耗时
+pub fn nodes() -> BTreeMap<&'static str, Histogram> {
+    NODES.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// 输出全部的指标
+pub fn render(metrics: &Metrics) -> String {
+    let mut s = String::new();
+    s += "# HELP coke_db_statements_total Statements executed by type.\n";
+    s += "# TYPE coke_db_statements_total counter\n";
+    for (kind, count) in statements() {
+        let _ = writeln!(s, "coke_db_statements_total{{type=\"{}\"}} {}", kind, count);
+    }
+
+    s += "# HELP coke_db_node_duration_seconds Execution time of plan nodes by type.\n";
+    s += "# TYPE coke_db_node_duration_seconds histogram\n";
+    for (name, histogram) in nodes() {
+        let mut total = 0;
+        for (bucket, count) in BUCKETS.iter().zip(histogram.counts) {
+            total += count;
+            let _ = writeln!(
+                s,
+                "coke_db_node_duration_seconds_bucket{{node=\"{}\",le=\"{}\"}} {}",
+                name, bucket, total
+            );
+        }
+        let _ = writeln!(
+            s,
+            "coke_db_node_duration_seconds_bucket{{node=\"{}\",le=\"+Inf\"}} {}",
+            name, histogram.count
+        );
+        let _ = writeln!(
+            s,
+            "coke_db_node_duration_seconds_sum{{node=\"{}\"}} {}",
+            name,
+            histogram.sum.as_secs_f64()
+        );
+        let _ = writeln!(
+            s,
+            "coke_db_node_duration_seconds_count{{node=\"{}\"}} {}",
+            name, histogram.count
+        );
+    }
+
+    let gauges = [
+        ("transactions_active", "Active transactions.", metrics.mvcc.txns_active),
+        ("row_cache_bytes", "Bytes used by the row cache.", metrics.mvcc.cache.bytes),
+        ("plan_cache_entries", "Statements in the plan cache.", metrics.plans.entries),
+    ];
+    for (name, help, value) in gauges {
+        let _ = writeln!(s, "# HELP coke_db_{} {}", name, help);
+        let _ = writeln!(s, "# TYPE coke_db_{} gauge", name);
+        let _ = writeln!(s, "coke_db_{} {}", name, value);
+    }
+
+    let bloom = &metrics.mvcc.bloom;
+    let cache = &metrics.mvcc.cache;
+    let counters = [
+        ("bloom_checks_total", "Point lookups checked by bloom filters.", bloom.checks),
+        ("bloom_skipped_total", "Point lookups skipped by bloom filters.", bloom.skipped),
+        ("row_cache_hits_total", "Point lookups served by the row cache.", cache.hits),
+        ("row_cache_misses_total", "Point lookups missed in the row cache.", cache.misses),
+        ("plan_cache_hits_total", "Statements served by the plan cache.", metrics.plans.hits),
+        ("plan_cache_misses_total", "Statements planned again.", metrics.plans.misses),
+    ];
+    for (name, help, value) in counters {
+        let _ = writeln!(s, "# HELP coke_db_{} {}", name, help);
+        let _ = writeln!(s, "# TYPE coke_db_{} counter", name);
+        let _ = writeln!(s, "coke_db_{} {}", name, value);
+    }
+    s
+}
@@ -0,0 +1,492 @@
+//! PostgreSQL 协议的一部分 psql 和其他使用 libpq 的工具可以直接连接
+//! 支持启动 简单查询 (Query) 和取消请求 不支持 SSL 和扩展查询协议 (Parse/Bind/Execute)
+//! 不需要密码 所有的值都用文本格式发送 语句仍然是 CokeDB 的语法 例如字符串用双引号
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use log::{debug, error, info};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::errors::*;
+use crate::server::blocking;
+use crate::sql::engine::{Cancel, Engine, Partial, SqlSession};
+use crate::sql::execution::{Column, ResultSet};
+use crate::sql::{ColumnType, Value};
+
+const PROTOCOL_VERSION: i32 = 196608;
+const SSL_REQUEST: i32 = 80877103;
+const GSSENC_REQUEST: i32 = 80877104;
+const CANCEL_REQUEST: i32 = 80877102;
+/// 一条消息最大的长度
+const MAX_MESSAGE: usize = 64 << 20;
+
+/// 类型的 oid 和长度 长度为-1的是变长的类型
+const BOOL: (i32, i16) = (16, 1);
+const INT8: (i32, i16) = (20, 8);
+const FLOAT8: (i32, i16) = (701, 8);
+const NUMERIC: (i32, i16) = (1700, -1);
+const TEXT: (i32, i16) = (25, -1);
+const BYTEA: (i32, i16) = (17, -1);
+
+/// 连接的进程号到取消的密钥和会话的取消标记
+type Cancels = Arc<Mutex<HashMap<u32, (u32, Cancel)>>>;
+
+/// 每个连接一个会话
+pub(crate) async fn serve<E>(listener: TcpListener, engine: E, slow_query: Option<Duration>)
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    let cancels: Cancels = Arc::new(Mutex::new(HashMap::new()));
+    let next = Arc::new(AtomicU32::new(1));
+    let random = RandomState::new();
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                debug!("postgres connection from {}", addr);
+                let (engine, cancels) = (engine.clone(), cancels.clone());
+                let pid = next.fetch_add(1, Ordering::Relaxed);
+                let mut hasher = random.build_hasher();
+                hasher.write_u32(pid);
+                let secret = hasher.finish() as u32;
+                tokio::spawn(async move {
+                    let result = connection(socket, engine, slow_query, &cancels, pid, secret);
+                    if let Err(e) = result.await {
+                        debug!("postgres connection get error {}", e);
+                    }
+                    if let Ok(mut cancels) = cancels.lock() {
+                        cancels.remove(&pid);
+                    }
+                });
+            }
+            Err(e) => error!("postgres accept get error {}", e),
+        }
+    }
+}
+
+async fn connection<E>(
+    mut socket: TcpStream,
+    engine: E,
+    slow_query: Option<Duration>,
+    cancels: &Cancels,
+    pid: u32,
+    secret: u32,
+) -> Result<()>
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    let params = match startup(&mut socket, cancels).await? {
+        Some(params) => params,
+        None => return Ok(()),
+    };
+    debug!("postgres startup {:?}", params);
+    let mut session = engine.session()?.with_slow_query(slow_query);
+    cancels.lock()?.insert(pid, (secret, session.canceller()));
+
+    let mut out = Vec::new();
+    message(&mut out, b'R', &0i32.to_be_bytes());
+    for (name, value) in [
+        ("server_version", "14.0"),
+        ("server_encoding", "UTF8"),
+        ("client_encoding", "UTF8"),
+        ("DateStyle", "ISO, MDY"),
+        ("integer_datetimes", "on"),
+        ("standard_conforming_strings", "on"),
+    ] {
+        let mut body = Vec::new();
+        cstring(&mut body, name);
+        cstring(&mut body, value);
+        message(&mut out, b'S', &body);
+    }
+    let mut key = pid.to_be_bytes().to_vec();
+    key.extend_from_slice(&secret.to_be_bytes());
+    message(&mut out, b'K', &key);
+    ready(&mut out, &session);
+    socket.write_all(&out).await?;
+
+    // 扩展查询协议出错之后 忽略之后的消息直到 Sync
+    let mut skipping = false;
+    let result = loop {
+        let kind = match socket.read_u8().await {
+            Ok(kind) => kind,
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break Ok(()),
+            Err(e) => break Err(e.into()),
+        };
+        let len = socket.read_i32().await? as usize;
+        if !(4..=MAX_MESSAGE).contains(&len) {
+            break Err(Error::Parse(format!("invalid message length {}", len)));
+        }
+        let mut body = vec![0; len - 4];
+        socket.read_exact(&mut body).await?;
+        let mut out = Vec::new();
+        match kind {
+            b'Q' => {
+                let sql = String::from_utf8_lossy(body.strip_suffix(&[0]).unwrap_or(&body));
+                let sql = sql.to_string();
+                if sql.trim().trim_matches(';').trim().is_empty() {
+                    message(&mut out, b'I', &[]);
+                } else {
+                    let (s, sql, result) = blocking(move || {
+                        let result = session.execute_all(&sql);
+                        Ok((session, sql, result))
+                    })
+                    .await?;
+                    session = s;
+                    // 出错之前执行完的语句先返回结果
+                    match result {
+                        Ok(results) => results.into_iter().for_each(|r| write_result(&mut out, r)),
+                        Err(Partial { results, error }) => {
+                            results.into_iter().for_each(|r| write_result(&mut out, r));
+                            error_response(&mut out, &error, &sql)
+                        }
+                    }
+                }
+                ready(&mut out, &session);
+            }
+            b'X' => break Ok(()),
+            b'S' => {
+                skipping = false;
+                ready(&mut out, &session);
+            }
+            b'P' | b'B' | b'D' | b'E' | b'C' | b'H' | b'F' => {
+                if !skipping {
+                    let e = Error::Parse("extended query protocol is not supported".into());
+                    error_response(&mut out, &e, "");
+                    skipping = true;
+                }
+            }
+            kind => {
+                let e = Error::Parse(format!("unsupported message {}", kind as char));
+                error_response(&mut out, &e, "");
+                ready(&mut out, &session);
+            }
+        }
+        socket.write_all(&out).await?;
+    };
+    // 断开的时候还在事务中 回滚事务
+    match blocking(move || session.close()).await {
+        Ok(Some(id)) => info!("postgres connection {} closed, rollback transaction {}", pid, id),
+        Ok(None) => {}
+        Err(e) => error!("postgres connection {} rollback get error {}", pid, e),
+    }
+    result
+}
+
+/// 读取启动消息 返回客户端的参数 取消请求或者不支持的协议返回None
+async fn startup(
+    socket: &mut TcpStream,
+    cancels: &Cancels,
+) -> Result<Option<Vec<(String, String)>>> {
+    loop {
+        let len = socket.read_i32().await? as usize;
+        if !(8..=10240).contains(&len) {
+            return Err(Error::Parse(format!("invalid startup message length {}", len)));
+        }
+        let code = socket.read_i32().await?;
+        let mut body = vec![0; len - 8];
+        socket.read_exact(&mut body).await?;
+        match code {
+            // 不支持加密的连接 客户端可以继续用明文连接
+            SSL_REQUEST | GSSENC_REQUEST => socket.write_all(b"N").await?,
+            CANCEL_REQUEST if body.len() == 8 => {
+                let pid = u32::from_be_bytes([body[0], body[1], body[2], body[3]]);
+                let secret = u32::from_be_bytes([body[4], body[5], body[6], body[7]]);
+                if let Some((key, cancel)) = cancels.lock()?.get(&pid) {
+                    if *key == secret {
+                        cancel.cancel();
+                    }
+                }
+                return Ok(None);
+            }
+            PROTOCOL_VERSION => {
+                let mut strings = body
+                    .split(|b| *b == 0)
+                    .map(|s| String::from_utf8_lossy(s).to_string());
+                let mut params = Vec::new();
+                while let (Some(name), Some(value)) = (strings.next(), strings.next()) {
+                    if name.is_empty() {
+                        break;
+                    }
+                    params.push((name, value));
+                }
+                return Ok(Some(params));
+            }
+            code => {
+                let mut out = Vec::new();
+                let e = Error::Parse(format!("unsupported protocol version {}", code));
+                error_response(&mut out, &e, "");
+                socket.write_all(&out).await?;
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// 消息的类型 包括自己的长度 和内容
+fn message(out: &mut Vec<u8>, kind: u8, body: &[u8]) {
+    out.push(kind);
+    out.extend_from_slice(&(body.len() as i32 + 4).to_be_bytes());
+    out.extend_from_slice(body);
+}
+
+fn cstring(out: &mut Vec<u8>, s: &str) {
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+}
+
+/// 可以接收下一个查询 带上是否在事务中
+fn ready<E: Engine + 'static>(out: &mut Vec<u8>, session: &SqlSession<E>) {
+    message(out, b'Z', if session.in_transaction() { b"T" } else { b"I" });
+}
+
+/// 语法错误带上出错的位置 是 sql 中从1开始的第几个字符
+fn error_response(out: &mut Vec<u8>, error: &Error, sql: &str) {
+    let mut body = Vec::new();
+    for (field, value) in [(b'S', "ERROR"), (b'V', "ERROR"), (b'C', error.code())] {
+        body.push(field);
+        cstring(&mut body, value);
+    }
+    body.push(b'M');
+    cstring(&mut body, &error.to_string());
+    if let Error::Sql(e) = error {
+        if let Some(position) = e.position {
+            let lines = sql.split_inclusive('\n').take(position.line - 1);
+            let position = lines.map(|l| l.chars().count()).sum::<usize>() + position.column;
+            body.push(b'P');
+            cstring(&mut body, &position.to_string());
+        }
+    }
+    body.push(0);
+    message(out, b'E', &body);
+}
+
+fn write_result(out: &mut Vec<u8>, result: ResultSet) {
+    let tag = match result {
+        ResultSet::Query { columns, rows } => {
+            let types = columns
+                .iter()
+                .enumerate()
+                .map(|(i, column)| column_type(column, rows.iter().map(|row| &row[i])))
+                .collect::<Vec<_>>();
+            let names = columns.iter().map(|c| c.name.as_deref().unwrap_or("?column?"));
+            row_description(out, names.zip(types));
+            for row in rows.iter() {
+                data_row(out, row.iter().map(text));
+            }
+            format!("SELECT {}", rows.len())
+        }
+        ResultSet::Explain { plan, estimates } => {
+            return explain(out, &plan.format_explain(&estimates));
+        }
+        ResultSet::ExplainAnalyze { plan, stats } => {
+            return explain(out, &plan.format_analyze(&stats));
+        }
+        ResultSet::ExplainJson { json } => return explain(out, &json),
+        ResultSet::Begin { .. } => "BEGIN".into(),
+        ResultSet::Commit { .. } => "COMMIT".into(),
+        ResultSet::Rollback { .. } => "ROLLBACK".into(),
+        ResultSet::Savepoint { .. } => "SAVEPOINT".into(),
+        ResultSet::RollbackToSavepoint { .. } => "ROLLBACK".into(),
+        ResultSet::ReleaseSavepoint { .. } => "RELEASE".into(),
+        ResultSet::Create { count } => format!("INSERT 0 {}", count),
+        ResultSet::Update { count } => format!("UPDATE {}", count),
+        ResultSet::Delete { count } => format!("DELETE {}", count),
+        ResultSet::CreateTable { .. } => "CREATE TABLE".into(),
+        ResultSet::DropTable { .. } => "DROP TABLE".into(),
+        ResultSet::CreatePolicy { .. } => "CREATE POLICY".into(),
+        ResultSet::DropPolicy { .. } => "DROP POLICY".into(),
+        ResultSet::CheckTable { rows, .. } => format!("CHECK {}", rows),
+        ResultSet::Analyze { .. } => "ANALYZE".into(),
+        ResultSet::Backup { keys, .. } => format!("BACKUP {}", keys),
+        ResultSet::Restore { keys, .. } => format!("RESTORE {}", keys),
+        ResultSet::Set { .. } => "SET".into(),
+    };
+    let mut body = Vec::new();
+    cstring(&mut body, &tag);
+    message(out, b'C', &body);
+}
+
+/// 计划的每一行是结果的一行 和 postgres 一样列名是 QUERY PLAN
+fn explain(out: &mut Vec<u8>, plan: &str) {
+    row_description(out, [("QUERY PLAN", TEXT)].into_iter());
+    for line in plan.lines() {
+        data_row(out, [Some(line.to_string())].into_iter());
+    }
+    let mut body = Vec::new();
+    cstring(&mut body, "EXPLAIN");
+    message(out, b'C', &body);
+}
+
+fn row_description<'a>(
+    out: &mut Vec<u8>,
+    columns: impl ExactSizeIterator<Item = (&'a str, (i32, i16))>,
+) {
+    let mut body = (columns.len() as i16).to_be_bytes().to_vec();
+    for (name, (oid, size)) in columns {
+        cstring(&mut body, name);
+        body.extend_from_slice(&0i32.to_be_bytes());
+        body.extend_from_slice(&0i16.to_be_bytes());
+        body.extend_from_slice(&oid.to_be_bytes());
+        body.extend_from_slice(&size.to_be_bytes());
+        body.extend_from_slice(&(-1i32).to_be_bytes());
+        // 文本格式
+        body.extend_from_slice(&0i16.to_be_bytes());
+    }
+    message(out, b'T', &body);
+}
+
+fn data_row(out: &mut Vec<u8>, values: impl ExactSizeIterator<Item = Option<String>>) {
+    let mut body = (values.len() as i16).to_be_bytes().to_vec();
+    for value in values {
+        match value {
+            Some(value) => {
+                body.extend_from_slice(&(value.len() as i32).to_be_bytes());
+                body.extend_from_slice(value.as_bytes());
+            }
+            None => body.extend_from_slice(&(-1i32).to_be_bytes()),
+        }
+    }
+    message(out, b'D', &body);
+}
+
+/// 列的类型不确定的时候 使用第一个不是null的值的类型 都是null的时候用text
+fn column_type<'a>(column: &Column, mut values: impl Iterator<Item = &'a Value>) -> (i32, i16) {
+    let column_type = column.column_type.clone().or_else(|| {
+        values.find(|v| **v != Value::Null).and_then(|v| match v {
+            Value::Bool(_) => Some(ColumnType::Bool),
+            Value::Integer(_) => Some(ColumnType::Integer),
+            Value::Float(_) => Some(ColumnType::Float),
+            Value::Decimal(_) => Some(ColumnType::Decimal(0, 0)),
+            Value::Bytes(_) => Some(ColumnType::Bytes),
+            _ => None,
+        })
+    });
+    match column_type {
+        Some(ColumnType::Bool) => BOOL,
+        Some(ColumnType::Integer) => INT8,
+        Some(ColumnType::Float) => FLOAT8,
+        Some(ColumnType::Decimal(..)) => NUMERIC,
+        Some(ColumnType::Bytes) => BYTEA,
+        Some(ColumnType::String) | None => TEXT,
+    }
+}
+
+/// 值的文本格式 null 是 None
+fn text(value: &Value) -> Option<String> {
+    match value {
+        Value::Null => None,
+        Value::Bool(b) => Some(if *b { "t" } else { "f" }.to_string()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) if f.is_nan() => Some("NaN".to_string()),
+        Value::Float(f) if f.is_infinite() => {
+            Some(if *f > 0.0 { "Infinity" } else { "-Infinity" }.to_string())
+        }
+        Value::Float(f) => Some(f.to_string()),
+        Value::Decimal(d) => Some(d.to_string()),
+        Value::String(s) => Some(s.clone()),
+        Value::Bytes(b) => {
+            Some(format!("\\x{}", b.iter().map(|b| format!("{:02x}", b)).collect::<String>()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{spawn_test_server, test_server};
+
+    /// 读取消息直到 ReadyForQuery 返回每条消息的类型和内容
+    async fn messages(socket: &mut TcpStream) -> Result<Vec<(u8, Vec<u8>)>> {
+        let mut messages = Vec::new();
+        loop {
+            let kind = socket.read_u8().await?;
+            let mut body = vec![0; socket.read_i32().await? as usize - 4];
+            socket.read_exact(&mut body).await?;
+            messages.push((kind, body));
+            if kind == b'Z' {
+                return Ok(messages);
+            }
+        }
+    }
+
+    async fn query(socket: &mut TcpStream, sql: &str) -> Result<Vec<(u8, Vec<u8>)>> {
+        let mut out = Vec::new();
+        let mut body = Vec::new();
+        cstring(&mut body, sql);
+        message(&mut out, b'Q', &body);
+        socket.write_all(&out).await?;
+        messages(socket).await
+    }
+
+    fn kinds(messages: &[(u8, Vec<u8>)]) -> String {
+        messages.iter().map(|(kind, _)| *kind as char).collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pgwire_test() -> Result<()> {
+        let addrs = spawn_test_server(test_server()?.with_postgres("127.0.0.1:0")).await?;
+        let pg_port = addrs.pg.map(|addr| addr.port()).unwrap_or_default();
+        let mut socket = TcpStream::connect(("127.0.0.1", pg_port)).await?;
+
+        // 先请求 SSL 被拒绝之后用明文连接
+        socket.write_all(&[0, 0, 0, 8, 4, 210, 22, 47]).await?;
+        assert_eq!(socket.read_u8().await?, b'N');
+        let mut body = PROTOCOL_VERSION.to_be_bytes().to_vec();
+        for s in ["user", "coke", ""] {
+            cstring(&mut body, s);
+        }
+        let mut startup = (body.len() as i32 + 4).to_be_bytes().to_vec();
+        startup.extend_from_slice(&body);
+        socket.write_all(&startup).await?;
+        let reply = messages(&mut socket).await?;
+        assert_eq!(kinds(&reply), "RSSSSSSKZ");
+        assert_eq!(reply.last().map(|(_, body)| body.as_slice()), Some(&b"I"[..]));
+
+        let sql = "create table t ( id int primary key, s string null default null ); \
+                   insert into t values (1, null), (2, \"b\"); select * from t;";
+        let reply = query(&mut socket, sql).await?;
+        assert_eq!(kinds(&reply), "CCTDDCZ");
+        assert_eq!(reply[0].1, b"CREATE TABLE\0");
+        assert_eq!(reply[1].1, b"INSERT 0 2\0");
+        // 两列 id 是 int8 s 是 text
+        let description = &reply[2].1;
+        assert_eq!(&description[..2], &[0, 2]);
+        assert_eq!(&description[2..5], b"id\0");
+        assert_eq!(&description[11..15], &20i32.to_be_bytes());
+        // 第一行的 s 是 null
+        assert_eq!(reply[3].1, [&[0, 2][..], &[0, 0, 0, 1], b"1", &[255, 255, 255, 255]].concat());
+        assert_eq!(reply[4].1, [&[0, 2][..], &[0, 0, 0, 1], b"2", &[0, 0, 0, 1], b"b"].concat());
+        assert_eq!(reply[5].1, b"SELECT 2\0");
+
+        // 事务中的状态是 T 出错之后收到错误码
+        let reply = query(&mut socket, "begin transaction;").await?;
+        assert_eq!(reply.last().map(|(_, body)| body.as_slice()), Some(&b"T"[..]));
+        let reply = query(&mut socket, "select * from x;").await?;
+        assert_eq!(kinds(&reply), "EZ");
+        assert!(reply[0].1.windows(6).any(|w| w == b"C42P01"));
+        let reply = query(&mut socket, "rollback;").await?;
+        assert_eq!(reply.last().map(|(_, body)| body.as_slice()), Some(&b"I"[..]));
+        let reply = query(&mut socket, "select *\nfrom t were id = 1;").await?;
+        // were 被当作别名 出错的是第二行的 id
+        assert!(reply[0].1.windows(4).any(|w| w == b"P22\0"));
+        assert_eq!(kinds(&query(&mut socket, ";").await?), "IZ");
+
+        // 扩展查询协议返回一个错误 Sync 之后可以继续
+        let mut out = Vec::new();
+        message(&mut out, b'P', b"\0select 1;\0\0\0");
+        message(&mut out, b'B', b"\0\0\0\0\0\0\0\0");
+        message(&mut out, b'S', &[]);
+        socket.write_all(&out).await?;
+        assert_eq!(kinds(&messages(&mut socket).await?), "EZ");
+        let reply = query(&mut socket, "explain select * from t;").await?;
+        assert_eq!(kinds(&reply), "TDCZ");
+        assert_eq!(&reply[0].1[2..13], b"QUERY PLAN\0");
+        Ok(())
+    }
+}
@@ -0,0 +1,50 @@
+use tokio::sync::{mpsc, oneshot};
+
+use super::message::{Request, Response};
+use super::Status;
+use crate::errors::*;
+
+/// 发给本地raft server的请求和响应的通道
+pub(super) type ClientRequest = (Request, oneshot::Sender<Result<Response>>);
+
+/// 本地raft节点的客户端
+#[derive(Clone)]
+pub struct Client {
+    request_tx: mpsc::UnboundedSender<ClientRequest>,
+}
+
+impl Client {
+    pub(super) fn new(request_tx: mpsc::UnboundedSender<ClientRequest>) -> Self {
+        Self { request_tx }
+    }
+
+    async fn request(&self, request: Request) -> Result<Response> {
+        let (response_tx, response_rx) = oneshot::channel();
+        self.request_tx.send((request, response_tx))?;
+        response_rx.await?
+    }
+
+    /// 修改状态机 返回日志位置和状态机的执行结果
+    pub async fn mutate(&self, command: Vec<u8>) -> Result<(u64, Result<Vec<u8>>)> {
+        match self.request(Request::Mutate(command)).await? {
+            Response::Mutate { index, result } => Ok((index, result)),
+            resp => Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+        }
+    }
+
+    /// 读取状态机 本地状态机应用到min_index之后才会执行
+    pub async fn query(&self, command: Vec<u8>, min_index: u64) -> Result<Vec<u8>> {
+        match self.request(Request::Query { command, min_index }).await? {
+            Response::Query(result) => Ok(result),
+            resp => Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+        }
+    }
+
+    /// 获得raft状态
+    pub async fn status(&self) -> Result<Status> {
+        match self.request(Request::Status).await? {
+            Response::Status(status) => Ok(status),
+            resp => Err(Error::Internal(format!("Unexpected response {:?}", resp))),
+        }
+    }
+}
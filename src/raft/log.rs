@@ -0,0 +1,241 @@
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::*;
+use crate::storage::kv::{MyRange, SqlStore};
+
+/// 一条raft日志
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// 日志位置 从1开始
+    pub index: u64,
+    /// 写入日志时的任期
+    pub term: u64,
+    /// 状态机命令 None是leader上任时写入的空日志
+    pub command: Option<Vec<u8>>,
+}
+
+/// 日志在存储中的key
+enum Key {
+    /// 日志条目
+    Entry(u64),
+    /// 当前任期和投票给了谁
+    TermVote,
+    /// 已经提交的日志位置
+    CommitIndex,
+}
+
+impl Key {
+    fn encode(&self) -> Vec<u8> {
+        match self {
+            Key::Entry(index) => [&[0x00][..], &index.to_be_bytes()].concat(),
+            Key::TermVote => vec![0x01],
+            Key::CommitIndex => vec![0x02],
+        }
+    }
+}
+
+/// raft日志 保存在一个kv存储中
+/// 没有快照也没有日志压缩 日志会一直保留 新节点和落后的节点从第一条日志开始复制
+pub struct Log {
+    store: Box<dyn SqlStore>,
+    /// 最后一条日志的位置
+    pub last_index: u64,
+    /// 最后一条日志的任期
+    pub last_term: u64,
+    /// 已经提交的位置
+    pub commit_index: u64,
+    /// 已经提交的日志的任期
+    pub commit_term: u64,
+}
+
+impl Log {
+    /// 创建日志 会从存储中恢复之前的状态
+    pub fn new(store: Box<dyn SqlStore>) -> Result<Self> {
+        let (last_index, last_term) = match store
+            .scan_limit(
+                MyRange::new(Key::Entry(0).encode()..=Key::Entry(u64::MAX).encode()),
+                1,
+                true,
+            )?
+            .pop()
+        {
+            Some((_, v)) => {
+                let entry: Entry = deserialize(&v)?;
+                (entry.index, entry.term)
+            }
+            None => (0, 0),
+        };
+        let (commit_index, commit_term) = match store.get(&Key::CommitIndex.encode())? {
+            Some(v) => deserialize(&v)?,
+            None => (0, 0),
+        };
+        Ok(Self {
+            store,
+            last_index,
+            last_term,
+            commit_index,
+            commit_term,
+        })
+    }
+
+    /// 追加一条日志 返回日志位置 返回之前日志已经落盘
+    pub fn append(&mut self, term: u64, command: Option<Vec<u8>>) -> Result<u64> {
+        let index = self.write(term, command)?;
+        self.store.flush()?;
+        Ok(index)
+    }
+
+    /// 写入一条日志 不落盘
+    fn write(&mut self, term: u64, command: Option<Vec<u8>>) -> Result<u64> {
+        let entry = Entry {
+            index: self.last_index + 1,
+            term,
+            command,
+        };
+        self.store
+            .set(&Key::Entry(entry.index).encode(), serialize(&entry)?)?;
+        self.last_index = entry.index;
+        self.last_term = entry.term;
+        Ok(entry.index)
+    }
+
+    /// 提交到index 已经提交的不会回退
+    pub fn commit(&mut self, index: u64) -> Result<u64> {
+        if index <= self.commit_index {
+            return Ok(self.commit_index);
+        }
+        let entry = self
+            .get(index)?
+            .ok_or_else(|| Error::Internal(format!("can't commit non-existent entry {}", index)))?;
+        self.store.set(
+            &Key::CommitIndex.encode(),
+            serialize(&(entry.index, entry.term))?,
+        )?;
+        self.store.flush()?;
+        self.commit_index = entry.index;
+        self.commit_term = entry.term;
+        Ok(index)
+    }
+
+    /// 获得一条日志
+    pub fn get(&self, index: u64) -> Result<Option<Entry>> {
+        self.store
+            .get(&Key::Entry(index).encode())?
+            .map(|v| deserialize(&v))
+            .transpose()
+    }
+
+    /// 检查是否有对应位置和任期的日志 位置0 是所有日志的起点
+    pub fn has(&self, index: u64, term: u64) -> Result<bool> {
+        match index {
+            0 => Ok(term == 0),
+            _ => Ok(self.get(index)?.map(|e| e.term == term).unwrap_or(false)),
+        }
+    }
+
+    /// 从from开始 最多拿limit条日志
+    pub fn scan(&self, from: u64, limit: usize) -> Result<Vec<Entry>> {
+        self.store
+            .scan_limit(
+                MyRange::new(Key::Entry(from).encode()..=Key::Entry(u64::MAX).encode()),
+                limit,
+                false,
+            )?
+            .into_iter()
+            .map(|(_, v)| deserialize(&v))
+            .collect()
+    }
+
+    /// 把leader发来的日志合并进来 任期冲突的日志会和之后的日志一起被删除
+    /// 返回最后一条日志的位置 返回之前日志已经落盘 之后才能回复leader
+    pub fn splice(&mut self, entries: Vec<Entry>) -> Result<u64> {
+        for entry in entries {
+            if entry.index > self.last_index + 1 {
+                return Err(Error::Internal(format!(
+                    "entry {} is not continuous with last entry {}",
+                    entry.index, self.last_index
+                )));
+            }
+            match self.get(entry.index)? {
+                Some(e) if e.term == entry.term => continue,
+                Some(_) => self.truncate(entry.index)?,
+                None => {}
+            }
+            self.write(entry.term, entry.command)?;
+        }
+        self.store.flush()?;
+        Ok(self.last_index)
+    }
+
+    /// 删除from以及之后的日志 已经提交的日志不能删除
+    fn truncate(&mut self, from: u64) -> Result<()> {
+        if from <= self.commit_index {
+            return Err(Error::Internal(format!(
+                "can't truncate committed entry {}",
+                from
+            )));
+        }
+        self.store.delete_range(MyRange::new(
+            Key::Entry(from).encode()..=Key::Entry(self.last_index).encode(),
+        ))?;
+        self.last_index = from - 1;
+        self.last_term = match self.get(self.last_index)? {
+            Some(e) => e.term,
+            None => 0,
+        };
+        Ok(())
+    }
+
+    /// 读取当前任期和投票
+    pub fn load_term(&self) -> Result<(u64, Option<String>)> {
+        match self.store.get(&Key::TermVote.encode())? {
+            Some(v) => deserialize(&v),
+            None => Ok((0, None)),
+        }
+    }
+
+    /// 保存当前任期和投票 需要在回复消息之前落盘
+    pub fn save_term(&mut self, term: u64, voted_for: Option<&str>) -> Result<()> {
+        self.store
+            .set(&Key::TermVote.encode(), serialize(&(term, voted_for))?)?;
+        self.store.flush()
+    }
+}
+
+fn serialize<V: serde::Serialize>(value: &V) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(value)?)
+}
+
+fn deserialize<'a, V: serde::Deserialize<'a>>(bytes: &'a [u8]) -> Result<V> {
+    Ok(bincode::deserialize(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::kv::b_tree::BtreeStore;
+
+    #[test]
+    fn splice_test() -> Result<()> {
+        let mut log = Log::new(Box::new(BtreeStore::new()))?;
+        log.append(1, Some(vec![1]))?;
+        log.append(1, Some(vec![2]))?;
+        log.append(1, Some(vec![3]))?;
+        log.commit(1)?;
+
+        // 第三条任期冲突 会被替换掉
+        let entry = |index, term, c| Entry {
+            index,
+            term,
+            command: Some(vec![c]),
+        };
+        assert_eq!(log.splice(vec![entry(2, 1, 2), entry(3, 2, 4)])?, 3);
+        assert_eq!(log.get(3)?, Some(entry(3, 2, 4)));
+        assert_eq!((log.last_index, log.last_term), (3, 2));
+        assert!(log.has(2, 1)? && !log.has(3, 1)?);
+        // 已经提交的不能被覆盖
+        assert!(log.splice(vec![entry(1, 3, 9)]).is_err());
+        assert_eq!(log.scan(2, 10)?.len(), 2);
+        Ok(())
+    }
+}
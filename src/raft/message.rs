@@ -0,0 +1,65 @@
+use serde_derive::{Deserialize, Serialize};
+
+use super::log::Entry;
+use super::Status;
+use crate::errors::*;
+
+/// 节点之间传递的消息
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Message {
+    /// 发送方的任期 客户端消息为0 不参与任期比较
+    pub term: u64,
+    /// 发送方 客户端消息是接收客户端请求的节点
+    pub from: String,
+    /// 接收方 None表示广播给所有节点
+    pub to: Option<String>,
+    pub event: Event,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Event {
+    /// leader复制日志 没有日志的时候就是心跳
+    AppendEntries {
+        /// 新日志之前的一条日志
+        base_index: u64,
+        base_term: u64,
+        entries: Vec<Entry>,
+        /// leader已经提交的位置
+        commit_index: u64,
+    },
+    /// follower接受了日志
+    AcceptEntries { last_index: u64 },
+    /// follower的日志和base对不上 带上follower最后一条日志的位置
+    RejectEntries { last_index: u64 },
+    /// candidate请求投票
+    SolicitVote { last_index: u64, last_term: u64 },
+    /// 投票给candidate
+    GrantVote,
+    /// 客户端请求
+    ClientRequest { id: u64, request: Request },
+    /// 客户端响应
+    ClientResponse { id: u64, response: Result<Response> },
+}
+
+/// 客户端请求
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Request {
+    /// 修改状态机 需要由leader写入日志
+    Mutate(Vec<u8>),
+    /// 读取状态机 任何节点都可以处理 需要等本地状态机应用到min_index
+    Query { command: Vec<u8>, min_index: u64 },
+    /// raft状态
+    Status,
+}
+
+/// 客户端响应
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Response {
+    /// 日志位置和状态机的执行结果
+    Mutate {
+        index: u64,
+        result: Result<Vec<u8>>,
+    },
+    Query(Vec<u8>),
+    Status(Status),
+}
@@ -0,0 +1,34 @@
+//! raft 共识 用来在多个节点之间复制状态机
+//!
+//! leader 负责写日志和复制 写请求会被转发给leader
+//! 读请求由接收请求的节点在本地状态机上处理
+//! 日志追加之后先落盘再回复 没有快照和日志压缩 日志会一直增长
+mod client;
+mod log;
+mod message;
+mod node;
+mod server;
+mod state;
+
+pub use self::client::Client;
+pub use self::log::{Entry, Log};
+pub use self::message::{Message, Request, Response};
+pub use self::server::Server;
+pub use self::state::State;
+
+use serde_derive::{Deserialize, Serialize};
+
+/// raft 节点状态
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Status {
+    pub id: String,
+    /// 当前认为的leader
+    pub leader: Option<String>,
+    pub term: u64,
+    /// follower candidate leader
+    pub role: String,
+    pub last_index: u64,
+    pub commit_index: u64,
+    /// 状态机应用到的位置
+    pub applied_index: u64,
+}
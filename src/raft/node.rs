@@ -0,0 +1,564 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+
+use log::{debug, info};
+use tokio::sync::mpsc;
+
+use super::log::Log;
+use super::message::{Event, Message, Request, Response};
+use super::state::State;
+use super::Status;
+use crate::errors::*;
+
+/// leader 每隔多少个tick发送一次心跳
+const HEARTBEAT_INTERVAL: u64 = 3;
+/// 选举超时的范围 单位是tick
+const ELECTION_TIMEOUT_MIN: u64 = 10;
+const ELECTION_TIMEOUT_MAX: u64 = 20;
+/// 一次最多复制多少条日志
+const MAX_APPEND_ENTRIES: usize = 100;
+
+/// 节点角色
+#[derive(Debug)]
+enum Role {
+    Follower {
+        /// 距离上次收到leader消息过去了多少tick
+        ticks: u64,
+        timeout: u64,
+    },
+    Candidate {
+        votes: HashSet<String>,
+        ticks: u64,
+        timeout: u64,
+    },
+    Leader {
+        /// 每个follower的复制进度
+        progress: HashMap<String, Progress>,
+        ticks: u64,
+        /// 等待应用的写请求 日志位置 -> (接收请求的节点, 请求id)
+        writes: HashMap<u64, (String, u64)>,
+    },
+}
+
+/// follower 的复制进度
+#[derive(Debug)]
+struct Progress {
+    /// 下一条要发送的日志
+    next: u64,
+    /// 已经确认复制的日志
+    matched: u64,
+}
+
+/// raft 节点 只处理消息和tick 网络由server负责
+///
+/// 需要发送的消息都放到node_tx中
+pub struct Node {
+    id: String,
+    peers: Vec<String>,
+    term: u64,
+    voted_for: Option<String>,
+    leader: Option<String>,
+    role: Role,
+    log: Log,
+    state: Box<dyn State>,
+    applied_index: u64,
+    node_tx: mpsc::UnboundedSender<Message>,
+    /// 还没有leader 暂存的写请求
+    queued: Vec<Message>,
+    /// 等待状态机追上min_index的读请求
+    reads: Vec<(u64, Message)>,
+    /// 转发给leader 还没有响应的本地写请求
+    forwarded: HashSet<u64>,
+}
+
+impl Node {
+    /// 创建一个节点 会把已经提交但没有应用的日志交给状态机
+    pub fn new(
+        id: &str,
+        peers: Vec<String>,
+        log: Log,
+        state: Box<dyn State>,
+        node_tx: mpsc::UnboundedSender<Message>,
+    ) -> Result<Self> {
+        let (term, voted_for) = log.load_term()?;
+        let applied_index = state.applied_index();
+        let mut node = Self {
+            id: id.to_string(),
+            peers,
+            term,
+            voted_for,
+            leader: None,
+            role: Role::Follower {
+                ticks: 0,
+                timeout: election_timeout(),
+            },
+            log,
+            state,
+            applied_index,
+            node_tx,
+            queued: vec![],
+            reads: vec![],
+            forwarded: HashSet::new(),
+        };
+        node.apply()?;
+        Ok(node)
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// 逻辑时钟 推进选举超时和心跳
+    pub fn tick(&mut self) -> Result<()> {
+        match &mut self.role {
+            Role::Follower { ticks, timeout } | Role::Candidate { ticks, timeout, .. } => {
+                *ticks += 1;
+                if *ticks >= *timeout {
+                    self.become_candidate()?;
+                }
+            }
+            Role::Leader { ticks, .. } => {
+                *ticks += 1;
+                if *ticks >= HEARTBEAT_INTERVAL {
+                    *ticks = 0;
+                    for peer in self.peers.clone() {
+                        self.replicate(&peer)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 处理一条消息
+    pub fn step(&mut self, msg: Message) -> Result<()> {
+        // 客户端消息的任期是0 不参与任期比较
+        if msg.term > 0 && msg.term < self.term {
+            debug!("drop stale message {:?}", msg);
+            // 让过期的leader知道新的任期
+            if let Event::AppendEntries { .. } = msg.event {
+                let last_index = self.log.last_index;
+                self.send(Some(msg.from), Event::RejectEntries { last_index })?;
+            }
+            return Ok(());
+        }
+        if msg.term > self.term {
+            self.become_follower(msg.term, None)?;
+        }
+
+        match msg.event {
+            Event::AppendEntries {
+                base_index,
+                base_term,
+                entries,
+                commit_index,
+            } => {
+                if self.leader.as_deref() != Some(msg.from.as_str()) {
+                    self.become_follower(msg.term, Some(msg.from.clone()))?;
+                }
+                if let Role::Follower { ticks, .. } = &mut self.role {
+                    *ticks = 0;
+                }
+                if !self.log.has(base_index, base_term)? {
+                    let last_index = self.log.last_index;
+                    return self.send(Some(msg.from), Event::RejectEntries { last_index });
+                }
+                let last_index = base_index + entries.len() as u64;
+                self.log.splice(entries)?;
+                // 只能提交和leader确认一致的日志
+                let commit = commit_index.min(last_index);
+                if commit > self.log.commit_index {
+                    self.log.commit(commit)?;
+                    self.apply()?;
+                }
+                self.send(Some(msg.from), Event::AcceptEntries { last_index })
+            }
+
+            Event::AcceptEntries { last_index } => {
+                if let Role::Leader { progress, .. } = &mut self.role {
+                    if let Some(p) = progress.get_mut(&msg.from) {
+                        p.matched = p.matched.max(last_index);
+                        p.next = p.matched + 1;
+                    }
+                    self.maybe_commit()?;
+                    // 还有没复制完的日志就继续发
+                    if last_index < self.log.last_index {
+                        self.replicate(&msg.from)?;
+                    }
+                }
+                Ok(())
+            }
+
+            Event::RejectEntries { last_index } => {
+                if let Role::Leader { progress, .. } = &mut self.role {
+                    // 往前退一条 follower的日志比较短的话直接从它的末尾开始
+                    if let Some(p) = progress.get_mut(&msg.from) {
+                        p.next = (p.next - 1).min(last_index + 1).max(p.matched + 1);
+                    }
+                    self.replicate(&msg.from)?;
+                }
+                Ok(())
+            }
+
+            Event::SolicitVote {
+                last_index,
+                last_term,
+            } => {
+                if !matches!(self.role, Role::Follower { .. }) {
+                    return Ok(());
+                }
+                if let Some(voted_for) = &self.voted_for {
+                    if voted_for != &msg.from {
+                        return Ok(());
+                    }
+                }
+                // candidate 的日志至少要和自己一样新
+                if last_term < self.log.last_term
+                    || (last_term == self.log.last_term && last_index < self.log.last_index)
+                {
+                    return Ok(());
+                }
+                info!(
+                    "node {} vote for {} in term {}",
+                    self.id, msg.from, self.term
+                );
+                self.voted_for = Some(msg.from.clone());
+                self.log.save_term(self.term, self.voted_for.as_deref())?;
+                if let Role::Follower { ticks, .. } = &mut self.role {
+                    *ticks = 0;
+                }
+                self.send(Some(msg.from), Event::GrantVote)
+            }
+
+            Event::GrantVote => {
+                if let Role::Candidate { votes, .. } = &mut self.role {
+                    votes.insert(msg.from);
+                    if votes.len() >= self.quorum() {
+                        self.become_leader()?;
+                    }
+                }
+                Ok(())
+            }
+
+            Event::ClientRequest { id, request } => match request {
+                Request::Mutate(command) => match (&mut self.role, &self.leader) {
+                    (Role::Leader { writes, .. }, _) => {
+                        let index = self.log.append(self.term, Some(command))?;
+                        writes.insert(index, (msg.from, id));
+                        for peer in self.peers.clone() {
+                            self.replicate(&peer)?;
+                        }
+                        self.maybe_commit()
+                    }
+                    // 写请求由leader处理
+                    (_, Some(leader)) => {
+                        let leader = leader.clone();
+                        self.forward(
+                            leader,
+                            Message {
+                                event: Event::ClientRequest {
+                                    id,
+                                    request: Request::Mutate(command),
+                                },
+                                ..msg
+                            },
+                        )
+                    }
+                    (_, None) => {
+                        self.queued.push(Message {
+                            event: Event::ClientRequest {
+                                id,
+                                request: Request::Mutate(command),
+                            },
+                            ..msg
+                        });
+                        Ok(())
+                    }
+                },
+                // 读请求在本地处理
+                Request::Query { min_index, .. } => {
+                    let msg = Message {
+                        event: Event::ClientRequest { id, request },
+                        ..msg
+                    };
+                    if min_index <= self.applied_index {
+                        self.query(msg)
+                    } else {
+                        self.reads.push((min_index, msg));
+                        Ok(())
+                    }
+                }
+                Request::Status => self.respond(msg.from, id, Ok(Response::Status(self.status()))),
+            },
+
+            // leader 对转发的写请求的响应
+            Event::ClientResponse { id, response } => {
+                self.forwarded.remove(&id);
+                self.respond(self.id.clone(), id, response)
+            }
+        }
+    }
+
+    /// 当前节点的状态
+    pub fn status(&self) -> Status {
+        Status {
+            id: self.id.clone(),
+            leader: self.leader.clone(),
+            term: self.term,
+            role: match self.role {
+                Role::Follower { .. } => "follower",
+                Role::Candidate { .. } => "candidate",
+                Role::Leader { .. } => "leader",
+            }
+            .to_string(),
+            last_index: self.log.last_index,
+            commit_index: self.log.commit_index,
+            applied_index: self.applied_index,
+        }
+    }
+
+    /// 多数派的数量
+    fn quorum(&self) -> usize {
+        let size = self.peers.len() + 1;
+        size / 2 + 1
+    }
+
+    fn become_follower(&mut self, term: u64, leader: Option<String>) -> Result<()> {
+        if let Role::Leader { writes, .. } = &mut self.role {
+            // 不再是leader了 没有应用的写请求不知道最后会不会提交
+            for (_, (from, id)) in std::mem::take(writes) {
+                self.respond(
+                    from,
+                    id,
+                    Err(Error::Internal("leader changed, write is aborted".into())),
+                )?;
+            }
+        }
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.log.save_term(term, None)?;
+            self.abort_forwarded()?;
+        }
+        if let Some(leader) = &leader {
+            info!("node {} follow leader {} in term {}", self.id, leader, term);
+        }
+        self.leader = leader;
+        self.role = Role::Follower {
+            ticks: 0,
+            timeout: election_timeout(),
+        };
+        // 知道leader了 把暂存的写请求转给它
+        if let Some(leader) = self.leader.clone() {
+            for msg in std::mem::take(&mut self.queued) {
+                self.forward(leader.clone(), msg)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 把写请求转发给leader
+    fn forward(&mut self, leader: String, msg: Message) -> Result<()> {
+        if let Event::ClientRequest { id, .. } = &msg.event {
+            if msg.from == self.id {
+                self.forwarded.insert(*id);
+            }
+        }
+        self.node_tx.send(Message {
+            to: Some(leader),
+            ..msg
+        })?;
+        Ok(())
+    }
+
+    /// leader 变了 转发出去的请求可能已经丢失 让客户端知道
+    fn abort_forwarded(&mut self) -> Result<()> {
+        for id in std::mem::take(&mut self.forwarded) {
+            self.respond(
+                self.id.clone(),
+                id,
+                Err(Error::Internal("leader changed, write is aborted".into())),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn become_candidate(&mut self) -> Result<()> {
+        if let Role::Leader { .. } = self.role {
+            return Ok(());
+        }
+        self.term += 1;
+        self.voted_for = Some(self.id.clone());
+        self.log.save_term(self.term, Some(&self.id))?;
+        self.leader = None;
+        self.abort_forwarded()?;
+        info!("node {} start election in term {}", self.id, self.term);
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.id.clone()]),
+            ticks: 0,
+            timeout: election_timeout(),
+        };
+        if self.quorum() == 1 {
+            return self.become_leader();
+        }
+        self.send(
+            None,
+            Event::SolicitVote {
+                last_index: self.log.last_index,
+                last_term: self.log.last_term,
+            },
+        )
+    }
+
+    fn become_leader(&mut self) -> Result<()> {
+        info!("node {} become leader in term {}", self.id, self.term);
+        let next = self.log.last_index + 1;
+        self.leader = Some(self.id.clone());
+        self.role = Role::Leader {
+            progress: self
+                .peers
+                .iter()
+                .map(|p| (p.clone(), Progress { next, matched: 0 }))
+                .collect(),
+            ticks: 0,
+            writes: HashMap::new(),
+        };
+        // 写一条空日志 之前任期的日志才能跟着提交
+        self.log.append(self.term, None)?;
+        for peer in self.peers.clone() {
+            self.replicate(&peer)?;
+        }
+        self.maybe_commit()?;
+        for msg in std::mem::take(&mut self.queued) {
+            self.step(msg)?;
+        }
+        Ok(())
+    }
+
+    /// 把follower缺少的日志发过去 没有缺少就是心跳
+    fn replicate(&mut self, peer: &str) -> Result<()> {
+        let next = match &self.role {
+            Role::Leader { progress, .. } => match progress.get(peer) {
+                Some(p) => p.next,
+                None => return Ok(()),
+            },
+            _ => return Ok(()),
+        };
+        let base_index = next - 1;
+        let base_term = match self.log.get(base_index)? {
+            Some(e) => e.term,
+            None => 0,
+        };
+        let entries = self.log.scan(next, MAX_APPEND_ENTRIES)?;
+        self.send(
+            Some(peer.to_string()),
+            Event::AppendEntries {
+                base_index,
+                base_term,
+                entries,
+                commit_index: self.log.commit_index,
+            },
+        )
+    }
+
+    /// 多数派都复制了的日志就可以提交了 只能直接提交当前任期的日志
+    fn maybe_commit(&mut self) -> Result<()> {
+        let mut indexes = match &self.role {
+            Role::Leader { progress, .. } => {
+                progress.values().map(|p| p.matched).collect::<Vec<_>>()
+            }
+            _ => return Ok(()),
+        };
+        indexes.push(self.log.last_index);
+        indexes.sort_unstable_by(|a, b| b.cmp(a));
+        let index = indexes[self.quorum() - 1];
+        if index <= self.log.commit_index {
+            return Ok(());
+        }
+        match self.log.get(index)? {
+            Some(e) if e.term == self.term => {
+                self.log.commit(index)?;
+                self.apply()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// 把已经提交的日志交给状态机
+    fn apply(&mut self) -> Result<()> {
+        while self.applied_index < self.log.commit_index {
+            let entry = self
+                .log
+                .get(self.applied_index + 1)?
+                .ok_or_else(|| Error::Internal("committed entry is missing".into()))?;
+            let result = match entry.command {
+                Some(command) => self.state.mutate(entry.index, command),
+                None => Ok(vec![]),
+            };
+            self.applied_index = entry.index;
+            let write = match &mut self.role {
+                Role::Leader { writes, .. } => writes.remove(&entry.index),
+                _ => None,
+            };
+            if let Some((from, id)) = write {
+                self.respond(
+                    from,
+                    id,
+                    Ok(Response::Mutate {
+                        index: entry.index,
+                        result,
+                    }),
+                )?;
+            }
+        }
+        // 状态机追上了 处理等待的读请求
+        let (ready, waiting): (Vec<_>, Vec<_>) = std::mem::take(&mut self.reads)
+            .into_iter()
+            .partition(|(min_index, _)| *min_index <= self.applied_index);
+        self.reads = waiting;
+        for (_, msg) in ready {
+            self.query(msg)?;
+        }
+        Ok(())
+    }
+
+    fn query(&mut self, msg: Message) -> Result<()> {
+        if let Event::ClientRequest {
+            id,
+            request: Request::Query { command, .. },
+        } = msg.event
+        {
+            let response = self.state.query(command).map(Response::Query);
+            self.respond(msg.from, id, response)?;
+        }
+        Ok(())
+    }
+
+    fn respond(&self, to: String, id: u64, response: Result<Response>) -> Result<()> {
+        self.node_tx.send(Message {
+            term: 0,
+            from: self.id.clone(),
+            to: Some(to),
+            event: Event::ClientResponse { id, response },
+        })?;
+        Ok(())
+    }
+
+    fn send(&self, to: Option<String>, event: Event) -> Result<()> {
+        self.node_tx.send(Message {
+            term: self.term,
+            from: self.id.clone(),
+            to,
+            event,
+        })?;
+        Ok(())
+    }
+}
+
+/// 随机的选举超时 避免大家同时发起选举
+fn election_timeout() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(0);
+    ELECTION_TIMEOUT_MIN + hasher.finish() % (ELECTION_TIMEOUT_MAX - ELECTION_TIMEOUT_MIN)
+}
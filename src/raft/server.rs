@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use futures::sink::SinkExt as _;
+use futures::stream::TryStreamExt as _;
+use log::{debug, error, info};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use super::client::{Client, ClientRequest};
+use super::log::Log;
+use super::message::{Event, Message, Response};
+use super::node::Node;
+use super::state::State;
+use crate::errors::*;
+
+/// 逻辑时钟的间隔
+const TICK_INTERVAL: Duration = Duration::from_millis(100);
+/// 连接对端失败后重试的间隔
+const RECONNECT_INTERVAL: Duration = Duration::from_millis(500);
+/// 每个对端最多缓存多少条待发送的消息 满了就丢弃 raft会重发
+const PEER_BUFFER: usize = 1000;
+
+/// 节点之间的连接
+type PeerStream = tokio_serde::SymmetricallyFramed<
+    Framed<TcpStream, LengthDelimitedCodec>,
+    Message,
+    tokio_serde::formats::SymmetricalBincode<Message>,
+>;
+
+/// raft server 负责节点之间的网络和驱动节点
+pub struct Server {
+    node: Node,
+    /// 对端节点id -> 地址
+    peers: HashMap<String, String>,
+    node_rx: mpsc::UnboundedReceiver<Message>,
+    client_tx: mpsc::UnboundedSender<ClientRequest>,
+    client_rx: mpsc::UnboundedReceiver<ClientRequest>,
+}
+
+impl Server {
+    /// 创建一个raft server peers是其他节点的id和地址
+    pub fn new(
+        id: &str,
+        peers: HashMap<String, String>,
+        log: Log,
+        state: Box<dyn State>,
+    ) -> Result<Self> {
+        let (node_tx, node_rx) = mpsc::unbounded_channel();
+        let (client_tx, client_rx) = mpsc::unbounded_channel();
+        let node = Node::new(id, peers.keys().cloned().collect(), log, state, node_tx)?;
+        Ok(Self {
+            node,
+            peers,
+            node_rx,
+            client_tx,
+            client_rx,
+        })
+    }
+
+    /// 获得一个连接到本节点的客户端
+    pub fn client(&self) -> Client {
+        Client::new(self.client_tx.clone())
+    }
+
+    /// 开始服务 listener用于接收其他节点的连接
+    pub async fn serve(self, listener: TcpListener) -> Result<()> {
+        info!(
+            "raft node {} listen on {}",
+            self.node.id(),
+            listener.local_addr()?
+        );
+        let (tcp_in_tx, tcp_in_rx) = mpsc::unbounded_channel();
+        let (tcp_out_tx, tcp_out_rx) = mpsc::unbounded_channel();
+        // client_tx 会被丢弃 所有客户端都关闭之后不再接收请求
+        let Self {
+            node,
+            peers,
+            node_rx,
+            client_rx,
+            ..
+        } = self;
+        tokio::try_join!(
+            Self::tcp_receive(listener, tcp_in_tx),
+            Self::tcp_send(peers, tcp_out_rx),
+            Self::eventloop(node, node_rx, client_rx, tcp_in_rx, tcp_out_tx),
+        )?;
+        Ok(())
+    }
+
+    /// 驱动节点 处理tick 对端消息 和客户端请求
+    /// 给本地客户端的响应都由节点发出
+    async fn eventloop(
+        mut node: Node,
+        mut node_rx: mpsc::UnboundedReceiver<Message>,
+        mut client_rx: mpsc::UnboundedReceiver<ClientRequest>,
+        mut tcp_rx: mpsc::UnboundedReceiver<Message>,
+        tcp_tx: mpsc::UnboundedSender<Message>,
+    ) -> Result<()> {
+        let mut ticker = tokio::time::interval(TICK_INTERVAL);
+        // 本地客户端等待响应的请求
+        let mut requests: HashMap<u64, oneshot::Sender<Result<Response>>> = HashMap::new();
+        let mut next_id = 0;
+        loop {
+            tokio::select! {
+                _ = ticker.tick() => node.tick()?,
+
+                Some(msg) = tcp_rx.recv() => node.step(msg)?,
+
+                Some(msg) = node_rx.recv() => match msg.event {
+                    Event::ClientResponse { id, response } if msg.to.as_deref() == Some(node.id()) => {
+                        if let Some(tx) = requests.remove(&id) {
+                            let _ = tx.send(response);
+                        }
+                    }
+                    _ => tcp_tx.send(msg)?,
+                },
+
+                Some((request, response_tx)) = client_rx.recv() => {
+                    next_id += 1;
+                    requests.insert(next_id, response_tx);
+                    let id = node.id().to_string();
+                    node.step(Message {
+                        term: 0,
+                        from: id.clone(),
+                        to: Some(id),
+                        event: Event::ClientRequest { id: next_id, request },
+                    })?;
+                },
+
+                else => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// 接收其他节点的连接 收到的消息交给eventloop
+    async fn tcp_receive(
+        listener: TcpListener,
+        in_tx: mpsc::UnboundedSender<Message>,
+    ) -> Result<()> {
+        let mut listener = TcpListenerStream::new(listener);
+        while let Some(socket) = listener.try_next().await? {
+            let peer = socket.peer_addr()?;
+            let in_tx = in_tx.clone();
+            tokio::spawn(async move {
+                debug!("raft peer {} connected", peer);
+                let mut stream: PeerStream = tokio_serde::SymmetricallyFramed::new(
+                    Framed::new(socket, LengthDelimitedCodec::new()),
+                    tokio_serde::formats::SymmetricalBincode::default(),
+                );
+                loop {
+                    match stream.try_next().await {
+                        Ok(Some(msg)) => {
+                            if in_tx.send(msg).is_err() {
+                                break;
+                            }
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            error!("raft peer {} get error {}", peer, e);
+                            break;
+                        }
+                    }
+                }
+                debug!("raft peer {} disconnected", peer);
+            });
+        }
+        Ok(())
+    }
+
+    /// 把节点发出的消息路由到对应的对端
+    async fn tcp_send(
+        peers: HashMap<String, String>,
+        mut out_rx: mpsc::UnboundedReceiver<Message>,
+    ) -> Result<()> {
+        let mut peer_txs = HashMap::new();
+        for (id, addr) in peers {
+            let (tx, rx) = mpsc::channel(PEER_BUFFER);
+            peer_txs.insert(id, tx);
+            tokio::spawn(Self::tcp_send_peer(addr, rx));
+        }
+        while let Some(msg) = out_rx.recv().await {
+            let to = match &msg.to {
+                Some(to) => vec![to.clone()],
+                None => peer_txs.keys().cloned().collect(),
+            };
+            for id in to {
+                match peer_txs.get(&id) {
+                    Some(tx) => {
+                        if tx.try_send(msg.clone()).is_err() {
+                            debug!("raft peer {} is busy, drop message", id);
+                        }
+                    }
+                    None => error!("raft get unknown peer {}", id),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// 维持和一个对端的连接 断开了就一直重连
+    async fn tcp_send_peer(addr: String, mut rx: mpsc::Receiver<Message>) {
+        loop {
+            match TcpStream::connect(&addr).await {
+                Ok(socket) => {
+                    debug!("raft connected to peer {}", addr);
+                    let mut sink: PeerStream = tokio_serde::SymmetricallyFramed::new(
+                        Framed::new(socket, LengthDelimitedCodec::new()),
+                        tokio_serde::formats::SymmetricalBincode::default(),
+                    );
+                    loop {
+                        let msg = match rx.recv().await {
+                            Some(msg) => msg,
+                            None => return,
+                        };
+                        if let Err(e) = sink.send(msg).await {
+                            debug!("raft send to peer {} get error {}", addr, e);
+                            break;
+                        }
+                    }
+                }
+                Err(e) => debug!("raft connect to peer {} get error {}", addr, e),
+            }
+            tokio::time::sleep(RECONNECT_INTERVAL).await;
+        }
+    }
+}
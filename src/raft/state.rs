@@ -0,0 +1,15 @@
+use crate::errors::*;
+
+/// raft 状态机 已经提交的日志会按顺序交给状态机执行
+///
+/// 所有节点上的状态机按照同样的顺序执行同样的命令 所以执行结果必须是确定的
+pub trait State: Send {
+    /// 已经应用到状态机的日志位置
+    fn applied_index(&self) -> u64;
+
+    /// 执行一条修改命令 index是对应的日志位置
+    fn mutate(&mut self, index: u64, command: Vec<u8>) -> Result<Vec<u8>>;
+
+    /// 执行一条只读命令
+    fn query(&self, command: Vec<u8>) -> Result<Vec<u8>>;
+}
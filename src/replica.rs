@@ -0,0 +1,137 @@
+/* 只读副本 连接主节点之后先复制全部数据的快照 之后按照提交的顺序重放主节点的提交日志
+ * 重放的进度保存在元数据中 重启之后从上次的位置继续 连接断开之后重新连接
+ * 重放写入的是提交之后的值 同一个提交重放两次结果也一样 所以进度保存在提交之后就可以
+ * */
+
+use std::time::Duration;
+
+use log::{error, info};
+use tokio::sync::watch;
+
+use crate::client::Replication;
+use crate::errors::*;
+use crate::server::closing;
+use crate::sql::engine::kv::{KvTransaction, KV};
+use crate::sql::engine::{Commit, Engine, Transaction};
+use crate::sql::schema::Catalog;
+use crate::storage::kv::mvcc::Mode;
+
+/// 保存已经重放的最后一个提交的序号
+const REPLICA_SEQ: &[u8] = b"replica_seq";
+/// 连接断开之后 隔多久重新连接
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+pub struct Replica {
+    engine: KV,
+    /// 主节点的地址 host:port
+    host: String,
+    port: u16,
+}
+
+impl Replica {
+    pub fn new(engine: KV, primary_addr: &str) -> Result<Self> {
+        let (host, port) = primary_addr
+            .rsplit_once(':')
+            .ok_or_else(|| Error::Config(format!("invalid primary address {}", primary_addr)))?;
+        Ok(Self {
+            engine,
+            host: host.to_string(),
+            port: port.parse().map_err(|_| {
+                Error::Config(format!("invalid primary address {}", primary_addr))
+            })?,
+        })
+    }
+
+    /// 已经重放的最后一个提交的序号 还没有复制快照的时候是None
+    pub fn applied(&self) -> Result<Option<u64>> {
+        match self.engine.get_metadata(REPLICA_SEQ)? {
+            Some(ref v) => Ok(Some(bincode::deserialize(v)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 一直复制到server开始关闭 出错之后重新连接
+    pub async fn run(self, mut closed: watch::Receiver<bool>) {
+        loop {
+            tokio::select! {
+                result = self.replicate() => if let Err(e) = result {
+                    error!("replicate from {}:{} get error {}", self.host, self.port, e)
+                },
+                _ = closing(&mut closed) => return,
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(RETRY_INTERVAL) => {}
+                _ = closing(&mut closed) => return,
+            }
+        }
+    }
+
+    async fn replicate(&self) -> Result<()> {
+        let mut replication = Replication::connect(&self.host, self.port, self.applied()?).await?;
+        info!("replicate from {}:{} after {}", self.host, self.port, replication.seq());
+        if replication.has_snapshot() {
+            let mut txn = tokio::task::block_in_place(|| self.begin_snapshot())?;
+            match self.load_snapshot(&mut replication, &mut txn).await {
+                Ok(keys) => {
+                    tokio::task::block_in_place(|| -> Result<()> {
+                        txn.commit()?;
+                        self.set_applied(replication.seq())
+                    })?;
+                    info!("load snapshot {} keys at {}", keys, replication.seq());
+                }
+                Err(e) => {
+                    tokio::task::block_in_place(|| txn.rollback())?;
+                    return Err(e);
+                }
+            }
+        }
+        loop {
+            let commit = replication.next().await?;
+            tokio::task::block_in_place(|| self.apply(commit))?;
+        }
+    }
+
+    /// 快照只能复制到空的存储中
+    fn begin_snapshot(&self) -> Result<KvTransaction> {
+        let txn = self.engine.begin(Mode::ReadOnly)?;
+        let tables = txn.scan_tables()?;
+        txn.commit()?;
+        if !tables.is_empty() {
+            return Err(Error::Executor(
+                "replica without replication progress must start with an empty store".into(),
+            ));
+        }
+        self.engine.begin_replication()
+    }
+
+    async fn load_snapshot(
+        &self,
+        replication: &mut Replication,
+        txn: &mut KvTransaction,
+    ) -> Result<u64> {
+        let mut keys = 0;
+        while let Some(items) = replication.next_snapshot().await? {
+            keys += items.len() as u64;
+            tokio::task::block_in_place(|| txn.load(items))?;
+        }
+        Ok(keys)
+    }
+
+    /// 在一个事务中重放主节点的一个提交
+    fn apply(&self, commit: Commit) -> Result<()> {
+        let mut txn = self.engine.begin_replication()?;
+        let seq = commit.seq;
+        match txn.apply(commit) {
+            Ok(()) => txn.commit()?,
+            Err(e) => {
+                txn.rollback()?;
+                return Err(e);
+            }
+        }
+        self.set_applied(seq)
+    }
+
+    fn set_applied(&self, seq: u64) -> Result<()> {
+        self.engine.set_metadata(REPLICA_SEQ, bincode::serialize(&seq)?)
+    }
+}
@@ -1,67 +1,478 @@
 use crate::{
     errors::{Error, *},
+    grpc, http, metrics, pgwire, raft,
     sql::{
-        self,
         engine::{
-            kv::{KvTransaction, KV},
-            Engine, SqlSession, Transaction,
+            cache::DEFAULT_PLAN_CACHE_SIZE, default_workers, kv::KV, raft::Raft, Cancel, Change,
+            Commit, Engine, KvItems, Partial, SqlSession, Status, Transaction,
+            DEFAULT_MAX_CURSORS, DEFAULT_WORK_MEMORY,
         },
+        execution::BATCH_SIZE,
         schema::Catalog,
     },
-    storage::kv::mvcc::{Mode, Status},
+    replica::Replica,
+    storage::kv::{
+        cache::DEFAULT_ROW_CACHE_SIZE,
+        mvcc::{Mode, DEFAULT_COMMIT_LOG_RETENTION},
+    },
 };
 use futures_util::{future::ok, SinkExt, StreamExt};
 use log::{error, info, debug};
 use serde_derive::{Deserialize, Serialize};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch;
+use tokio::task::JoinSet;
 use tokio_stream::wrappers::TcpListenerStream;
 use tokio_util::codec::{Framed, LengthDelimitedCodec};
 
 use crate::{
     sql::{
-        execution::{ResultSet, Row},
+        execution::{Columns, ResultSet, Row, Rows},
         Table,
     },
     storage::kv::SqlStore,
 };
 
 use crate::storage::kv::mvcc::MVCC;
+use std::collections::HashMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-pub struct Server {
-    sql_listener: Option<TcpListener>,
-    sql_eninge: KV,
-    sql_addr: String
+/// 所有会话的取消标记 其他连接可以通过会话id取消正在执行的语句
+type Cancels = Arc<Mutex<HashMap<u64, Cancel>>>;
+
+type Connection = tokio_serde::Framed<
+    Framed<TcpStream, LengthDelimitedCodec>,
+    Request,
+    Result<Response>,
+    tokio_serde::formats::Bincode<Request, Result<Response>>,
+>;
+
+/// 订阅和复制的会话没有新的提交的时候 隔多久再读一次提交日志
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// 订阅和复制的时候每次最多读取多少个事务的提交日志
+const COMMIT_BATCH: usize = 128;
+
+/// 引擎的配置 由server的配置文件和命令行参数得到
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// 后台垃圾回收的间隔 为0的时候不在后台回收
+    pub vacuum_interval: Duration,
+    /// 扫描时每批从存储中拿取的数量
+    pub scan_batch_size: usize,
+    /// 每条语句的排序和聚合默认可以使用的内存 单位字节
+    pub work_memory: usize,
+    /// 连接和聚合默认使用的线程数
+    pub workers: usize,
+    /// 关闭的时候最多等待会话结束事务的时间
+    pub shutdown_timeout: Duration,
+    /// 超过这个时间的语句连同计划写到查询日志 None的时候不记录慢查询
+    pub slow_query_threshold: Option<Duration>,
+    /// 点查询之前先检查每张表和每个索引的布隆过滤器
+    pub bloom_filter: bool,
+    /// 点查询缓存最多使用的字节数 0的时候不使用缓存
+    pub row_cache_size: usize,
+    /// 计划缓存最多缓存的语句数 0的时候不缓存
+    pub plan_cache_size: usize,
+    /// 垃圾回收的时候保留最近多少条提交日志 0的时候全部保留
+    pub commit_log_retention: u64,
+    /// 会话用 set admin = 密码 切换成管理员 None的时候所有的会话都受行级安全策略的限制
+    pub admin_password: Option<String>,
+    /// 每个会话最多同时打开的游标数
+    pub max_cursors: usize,
 }
 
-impl Server {
-    // 创建一个server实例
-    pub fn new(sql_addr: &str, sql_store: Box<dyn SqlStore>) -> Self {
-        // create mvcc
-        let mvcc = MVCC::new(sql_store);
-        let kv_sql_engine = KV::new(mvcc);
+impl Default for Options {
+    fn default() -> Self {
         Self {
+            vacuum_interval: Duration::from_secs(60),
+            scan_batch_size: 1024,
+            work_memory: DEFAULT_WORK_MEMORY,
+            workers: default_workers(),
+            shutdown_timeout: Duration::from_secs(30),
+            slow_query_threshold: Some(Duration::from_secs(1)),
+            bloom_filter: true,
+            row_cache_size: DEFAULT_ROW_CACHE_SIZE,
+            plan_cache_size: DEFAULT_PLAN_CACHE_SIZE,
+            commit_log_retention: DEFAULT_COMMIT_LOG_RETENTION,
+            admin_password: None,
+            max_cursors: DEFAULT_MAX_CURSORS,
+        }
+    }
+}
+
+impl Options {
+    fn kv(&self, sql_store: Box<dyn SqlStore>) -> KV {
+        let mut mvcc = MVCC::new(sql_store)
+            .with_scan_batch_size(self.scan_batch_size)
+            .with_row_cache(self.row_cache_size)
+            .with_commit_log_retention(self.commit_log_retention);
+        if self.bloom_filter {
+            mvcc = mvcc.with_bloom_filters(KV::keyspace);
+        }
+        KV::new(mvcc)
+            .with_work_memory(self.work_memory)
+            .with_workers(self.workers)
+            .with_plan_cache(self.plan_cache_size)
+            .with_admin_password(self.admin_password.clone())
+            .with_max_cursors(self.max_cursors)
+    }
+}
+
+/// server 实际监听的地址 配置的端口是0的时候是系统分配的端口
+#[derive(Clone, Debug)]
+pub struct Addrs {
+    pub sql: SocketAddr,
+    pub raft: Option<SocketAddr>,
+    pub metrics: Option<SocketAddr>,
+    pub http: Option<SocketAddr>,
+    pub pg: Option<SocketAddr>,
+    pub grpc: Option<SocketAddr>,
+}
+
+/// sql 之外的接口已经绑定的端口
+#[derive(Default)]
+struct Listeners {
+    raft: Option<TcpListener>,
+    metrics: Option<TcpListener>,
+    http: Option<TcpListener>,
+    pg: Option<TcpListener>,
+    grpc: Option<TcpListener>,
+}
+
+pub struct Server<E: Engine> {
+    sql_listener: Option<TcpListener>,
+    listeners: Listeners,
+    sql_eninge: E,
+    sql_addr: String,
+    /// 后台垃圾回收的间隔
+    vacuum_interval: Duration,
+    /// 关闭的时候最多等待会话结束事务的时间
+    shutdown_timeout: Duration,
+    /// 新会话的慢查询阈值
+    slow_query: Option<Duration>,
+    /// raft server 和它监听的地址 只有raft引擎才有
+    raft: Option<(raft::Server, String)>,
+    /// 只读副本从主节点复制数据
+    replica: Option<Replica>,
+    /// 监控指标的http地址 None的时候不提供
+    metrics_addr: Option<String>,
+    /// http查询接口的地址 None的时候不提供
+    http_addr: Option<String>,
+    /// postgres 协议的地址 None的时候不提供
+    pg_addr: Option<String>,
+    /// grpc 接口的地址 None的时候不提供
+    grpc_addr: Option<String>,
+}
+
+impl Server<KV> {
+    // 创建一个server实例 上次没有正常关闭的话先回滚没有结束的事务
+    pub fn new(
+        sql_addr: &str,
+        sql_store: Box<dyn SqlStore>,
+        options: &Options,
+    ) -> Result<Self> {
+        let engine = options.kv(sql_store);
+        let recovered = engine.recover()?;
+        if recovered > 0 {
+            info!("rollback {} transactions left by last run", recovered);
+        }
+        let migrated = engine.migrate()?;
+        if migrated > 0 {
+            info!("migrate {} table definitions to the current encoding", migrated);
+        }
+        Ok(Self {
+            sql_listener: None,
+            listeners: Listeners::default(),
+            sql_eninge: engine,
+            sql_addr: sql_addr.to_string(),
+            vacuum_interval: options.vacuum_interval,
+            shutdown_timeout: options.shutdown_timeout,
+            slow_query: options.slow_query_threshold,
+            raft: None,
+            replica: None,
+            metrics_addr: None,
+            http_addr: None,
+            pg_addr: None,
+            grpc_addr: None,
+        })
+    }
+
+    /// 创建一个只读的副本 从primary_addr的主节点复制数据 只能执行只读的语句
+    pub fn new_replica(
+        sql_addr: &str,
+        primary_addr: &str,
+        sql_store: Box<dyn SqlStore>,
+        options: &Options,
+    ) -> Result<Self> {
+        let mut server = Self::new(sql_addr, sql_store, options)?;
+        server.sql_eninge = server.sql_eninge.with_read_only(true);
+        server.replica = Some(Replica::new(server.sql_eninge.clone(), primary_addr)?);
+        Ok(server)
+    }
+}
+
+impl Server<Raft> {
+    /// 创建一个基于raft的server
+    /// peers 是其他节点的id和raft地址 log_store 保存raft日志 sql_store 保存状态机数据
+    pub fn new_raft(
+        id: &str,
+        sql_addr: &str,
+        raft_addr: &str,
+        peers: HashMap<String, String>,
+        log_store: Box<dyn SqlStore>,
+        sql_store: Box<dyn SqlStore>,
+        options: &Options,
+    ) -> Result<Self> {
+        // 状态机不能在日志之外开启事务 旧的编码的表的定义不改写 读取的时候兼容
+        let state = Raft::new_state(options.kv(sql_store))?;
+        let raft_server = raft::Server::new(id, peers, raft::Log::new(log_store)?, Box::new(state))?;
+        let engine = Raft::new(raft_server.client())
+            .with_work_memory(options.work_memory)
+            .with_workers(options.workers)
+            .with_plan_cache(options.plan_cache_size)
+            .with_admin_password(options.admin_password.clone())
+            .with_max_cursors(options.max_cursors);
+        Ok(Self {
             sql_listener: None,
-            sql_eninge: kv_sql_engine,
-            sql_addr: sql_addr.to_string()
+            listeners: Listeners::default(),
+            sql_eninge: engine,
+            sql_addr: sql_addr.to_string(),
+            vacuum_interval: options.vacuum_interval,
+            shutdown_timeout: options.shutdown_timeout,
+            slow_query: options.slow_query_threshold,
+            raft: Some((raft_server, raft_addr.to_string())),
+            replica: None,
+            metrics_addr: None,
+            http_addr: None,
+            pg_addr: None,
+            grpc_addr: None,
+        })
+    }
+}
+
+impl<E> Server<E>
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    /// 在addr上提供http的 /metrics 接口 输出prometheus格式的监控指标
+    pub fn with_metrics(mut self, addr: &str) -> Self {
+        self.metrics_addr = Some(addr.to_string());
+        self
+    }
+
+    /// 在addr上提供http的 POST /query 接口 请求和结果都是json
+    pub fn with_http(mut self, addr: &str) -> Self {
+        self.http_addr = Some(addr.to_string());
+        self
+    }
+
+    /// 在addr上提供 postgres 协议 psql 等客户端可以直接连接
+    pub fn with_postgres(mut self, addr: &str) -> Self {
+        self.pg_addr = Some(addr.to_string());
+        self
+    }
+
+    /// 在addr上提供 proto/coke_db.proto 中定义的 grpc 接口
+    pub fn with_grpc(mut self, addr: &str) -> Self {
+        self.grpc_addr = Some(addr.to_string());
+        self
+    }
+
+    /// 绑定所有配置的端口 返回实际监听的地址 之后再调用 server 或者 serve_until
+    /// 没有调用的时候 server 开始的时候绑定
+    pub async fn listen(&mut self) -> Result<Addrs> {
+        if self.sql_listener.is_none() {
+            self.sql_listener = Some(TcpListener::bind(&self.sql_addr).await?);
+        }
+        match &self.raft {
+            Some((_, addr)) if self.listeners.raft.is_none() => {
+                self.listeners.raft = Some(TcpListener::bind(addr).await?);
+            }
+            _ => {}
+        }
+        // 地址绑定之后就去掉 再次调用的时候不会重复绑定
+        if let Some(addr) = self.metrics_addr.take() {
+            self.listeners.metrics = Some(TcpListener::bind(&addr).await?);
+        }
+        if let Some(addr) = self.http_addr.take() {
+            self.listeners.http = Some(TcpListener::bind(&addr).await?);
+        }
+        if let Some(addr) = self.pg_addr.take() {
+            self.listeners.pg = Some(TcpListener::bind(&addr).await?);
+        }
+        if let Some(addr) = self.grpc_addr.take() {
+            self.listeners.grpc = Some(TcpListener::bind(&addr).await?);
+        }
+
+        let addr = |l: &Option<TcpListener>| l.as_ref().map(|l| l.local_addr()).transpose();
+        let sql = self.sql_listener.as_ref().map(|l| l.local_addr()).transpose()?;
+        Ok(Addrs {
+            sql: sql.ok_or_else(|| Error::IO("no get a sql_listener".to_string()))?,
+            raft: addr(&self.listeners.raft)?,
+            metrics: addr(&self.listeners.metrics)?,
+            http: addr(&self.listeners.http)?,
+            pg: addr(&self.listeners.pg)?,
+            grpc: addr(&self.listeners.grpc)?,
+        })
+    }
+
+    /// 收到 SIGTERM 或者 ctrl+c 的时候关闭
+    pub async fn server(self) -> Result<()> {
+        self.serve_until(shutdown_signal()).await
+    }
+
+    /// shutdown 完成的时候关闭 不再接受新的连接 等待会话结束事务之后把数据写到磁盘
+    pub async fn serve_until(mut self, shutdown: impl Future<Output = ()>) -> Result<()> {
+        self.listen().await?;
+        let listeners = std::mem::take(&mut self.listeners);
+        if let (Some((raft_server, _)), Some(raft_listener)) = (self.raft.take(), listeners.raft) {
+            tokio::spawn(async move {
+                if let Err(e) = raft_server.serve(raft_listener).await {
+                    error!("raft server get error {}", e)
+                }
+            });
+        }
+        if let Some(metrics_listener) = listeners.metrics {
+            tokio::spawn(Self::serve_metrics(metrics_listener, self.sql_eninge.clone()));
+        }
+        if let Some(http_listener) = listeners.http {
+            tokio::spawn(http::serve(http_listener, self.sql_eninge.clone(), self.slow_query));
+        }
+        if let Some(pg_listener) = listeners.pg {
+            tokio::spawn(pgwire::serve(pg_listener, self.sql_eninge.clone(), self.slow_query));
+        }
+        if let Some(grpc_listener) = listeners.grpc {
+            tokio::spawn(grpc::serve(grpc_listener, self.sql_eninge.clone(), self.slow_query));
+        }
+        if self.vacuum_interval.is_zero() {
+            info!("background vacuum is disabled");
+        } else {
+            tokio::spawn(Self::vacuum(self.sql_eninge.clone(), self.vacuum_interval));
+        }
+        let engine = self.sql_eninge.clone();
+        self.handle_sql_request(shutdown).await?;
+        match tokio::task::spawn_blocking(move || engine.shutdown()).await {
+            Ok(Ok(true)) => info!("server shutdown"),
+            Ok(Ok(false)) => info!("server shutdown, recovery is needed on next start"),
+            Ok(Err(e)) => error!("shutdown get error {}", e),
+            Err(e) => error!("shutdown get error {}", e),
+        }
+        Ok(())
+    }
+
+    /// 每个http连接只处理一个请求
+    async fn serve_metrics(listener: TcpListener, engine: E) {
+        loop {
+            match listener.accept().await {
+                Ok((socket, _)) => {
+                    let engine = engine.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = Self::metrics_request(socket, engine).await {
+                            debug!("metrics request get error {}", e);
+                        }
+                    });
+                }
+                Err(e) => error!("metrics accept get error {}", e),
+            }
         }
     }
 
-    pub async fn server(mut self) -> Result<()> {
-        let sql_listener = TcpListener::bind(&self.sql_addr).await?;
-        self.sql_listener = Some(sql_listener);
-        self.handle_sql_request().await?;
+    /// 只支持 GET /metrics 只需要请求行 读到请求头结束为止
+    async fn metrics_request(mut socket: TcpStream, engine: E) -> Result<()> {
+        let mut request = Vec::new();
+        let mut buf = [0; 1024];
+        while !request.windows(4).any(|w| w == b"\r\n\r\n") && request.len() < 8192 {
+            let n = socket.read(&mut buf).await?;
+            if n == 0 {
+                break;
+            }
+            request.extend_from_slice(&buf[..n]);
+        }
+        let request = String::from_utf8_lossy(&request);
+        let mut line = request.split_whitespace();
+        let (status, body) = match (line.next(), line.next()) {
+            (Some("GET"), Some("/metrics")) => match blocking(move || engine.metrics()).await {
+                Ok(metrics) => ("200 OK", metrics::render(&metrics)),
+                Err(e) => ("500 Internal Server Error", format!("{}\n", e)),
+            },
+            _ => ("404 Not Found", "not found\n".to_string()),
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\
+             Connection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        socket.write_all(response.as_bytes()).await?;
+        socket.shutdown().await?;
         Ok(())
     }
 
-    async fn handle_sql_request(self) -> Result<()> {
+    /// 定时删除过期的行 并进行垃圾回收
+    async fn vacuum(engine: E, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        // 第一次tick是立刻返回的 跳过
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let reaper = engine.clone();
+            match tokio::task::spawn_blocking(move || reaper.expire()).await {
+                Ok(Ok(count)) => debug!("expire {} rows", count),
+                Ok(Err(e)) => error!("expire get error {}", e),
+                Err(e) => error!("expire get error {}", e),
+            }
+            let engine = engine.clone();
+            match tokio::task::spawn_blocking(move || engine.vacuum()).await {
+                Ok(Ok(status)) => debug!("vacuum done {:?}", status),
+                Ok(Err(e)) => error!("vacuum get error {}", e),
+                Err(e) => error!("vacuum get error {}", e),
+            }
+        }
+    }
+
+    async fn handle_sql_request(mut self, shutdown: impl Future<Output = ()>) -> Result<()> {
         if let Some(sql_listener) = self.sql_listener {
             let mut listener = TcpListenerStream::new(sql_listener);
-            while let Some(listener) = listener.next().await.transpose()? {
+            let cancels: Cancels = Arc::new(Mutex::new(HashMap::new()));
+            let (closing, closed) = watch::channel(false);
+            // 开始关闭的时候停止复制
+            if let Some(replica) = self.replica.take() {
+                tokio::spawn(replica.run(closed.clone()));
+            }
+            let mut sessions = JoinSet::new();
+            let mut next_id = 0;
+            tokio::pin!(shutdown);
+            loop {
+                let listener = tokio::select! {
+                    listener = listener.next() => listener,
+                    // 回收已经结束的会话
+                    Some(_) = sessions.join_next(), if !sessions.is_empty() => continue,
+                    _ = &mut shutdown => break,
+                };
+                let listener = match listener.transpose()? {
+                    Some(listener) => listener,
+                    None => break,
+                };
                 let addr = listener.peer_addr();
                 info!("get client connection {:?}", addr);
-                let session = Session::new(self.sql_eninge.clone(), listener)?;
+                next_id += 1;
+                let session = Session::new(
+                    next_id,
+                    self.sql_eninge.clone(),
+                    self.slow_query,
+                    listener,
+                    cancels.clone(),
+                    closed.clone(),
+                )?;
 
-                tokio::spawn(async {
+                sessions.spawn(async {
                     match session.serve().await {
                         Ok(_) => {
                             info!("disconnect")
@@ -72,6 +483,21 @@ impl Server {
                     }
                 });
             }
+
+            // 不再接受新的连接 等待会话结束
+            drop(listener);
+            info!("shutting down, wait for {} sessions", sessions.len());
+            let _ = closing.send(true);
+            let drain = async { while sessions.join_next().await.is_some() {} };
+            if tokio::time::timeout(self.shutdown_timeout, drain).await.is_err() {
+                // 超时之后取消正在执行的语句 没有结束的事务留给下次启动的时候回滚
+                error!("{} sessions are still running, abort them", sessions.len());
+                for cancel in cancels.lock()?.values() {
+                    cancel.cancel();
+                }
+                sessions.abort_all();
+                while sessions.join_next().await.is_some() {}
+            }
         } else {
             return Err(Error::IO("no get a sql_listener".to_string()));
         }
@@ -79,21 +505,99 @@ impl Server {
     }
 }
 
-pub struct Session {
-    // sql engine
-    engine: sql::engine::kv::KV,
-    sql_session: SqlSession<KV>,
+/// 等待 SIGTERM 或者 ctrl+c
+async fn shutdown_signal() {
+    let mut terminate = match signal(SignalKind::terminate()) {
+        Ok(terminate) => terminate,
+        Err(e) => {
+            error!("listen SIGTERM get error {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => info!("receive SIGTERM"),
+        _ = tokio::signal::ctrl_c() => info!("receive ctrl+c"),
+    }
+}
+
+/// 没有新的提交的时候等待一会 客户端断开或者server关闭的时候返回false
+/// 订阅和复制的连接上客户端不会再发送请求
+async fn poll_wait(stream: &mut Connection, closed: &mut watch::Receiver<bool>) -> Result<bool> {
+    tokio::select! {
+        _ = tokio::time::sleep(POLL_INTERVAL) => Ok(true),
+        req = stream.next() => match req {
+            Some(Ok(req)) => Err(Error::Internal(format!("unexpected request {:?}", req))),
+            Some(Err(e)) => Err(e.into()),
+            None => Ok(false),
+        },
+        _ = closing(closed) => Ok(false),
+    }
+}
+
+/// 等到server开始关闭
+pub(crate) async fn closing(closed: &mut watch::Receiver<bool>) {
+    while !*closed.borrow_and_update() {
+        // server已经不在了 也当作关闭
+        if closed.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+pub struct Session<E: Engine> {
+    handler: Handler<E>,
     socket: Option<TcpStream>,
+    /// server开始关闭的时候变成true
+    closed: watch::Receiver<bool>,
 }
 
-impl Session {
-    pub fn new(engine: KV, socket: TcpStream) -> Result<Self> {
+/// 执行请求需要的状态 可以移动到阻塞线程池中执行 执行语句的时候不占用tokio的工作线程
+#[derive(Clone)]
+pub struct Handler<E: Engine> {
+    /// 会话id 取消语句的时候使用
+    id: u64,
+    // sql engine
+    engine: E,
+    /// 同一个会话的请求是依次执行的 锁不会有竞争
+    sql_session: Arc<Mutex<SqlSession<E>>>,
+    cancels: Cancels,
+}
+
+/// 在阻塞线程池中执行 存储的读写和raft的等待都是同步的 不能在tokio的工作线程上执行
+pub(crate) async fn blocking<R, F>(f: F) -> Result<R>
+where
+    R: Send + 'static,
+    F: FnOnce() -> Result<R> + Send + 'static,
+{
+    tokio::task::spawn_blocking(f).await?
+}
+
+impl<E> Session<E>
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    fn new(
+        id: u64,
+        engine: E,
+        slow_query: Option<Duration>,
+        socket: TcpStream,
+        cancels: Cancels,
+        closed: watch::Receiver<bool>,
+    ) -> Result<Self> {
         let socket = Some(socket);
-        let sql_session = engine.session()?;
+        let sql_session = engine.session()?.with_slow_query(slow_query);
+        cancels.lock()?.insert(id, sql_session.canceller());
         Ok(Self {
-            engine,
-            sql_session,
+            handler: Handler {
+                id,
+                engine,
+                sql_session: Arc::new(Mutex::new(sql_session)),
+                cancels,
+            },
             socket,
+            closed,
         })
     }
 
@@ -104,36 +608,248 @@ impl Session {
         然后使用tokio_serde::formats::Bincode编解码器对对象进行序列化和反序列化。Bincode是用于Rust值的二进制序列化格式. */
         let socket = self.socket.take().unwrap();
 
-        let mut stream = tokio_serde::Framed::new(
+        let mut stream: Connection = tokio_serde::Framed::new(
             Framed::new(socket, LengthDelimitedCodec::new()),
             tokio_serde::formats::Bincode::default(),
         );
 
-        while let Some(req) = stream.next().await {
-            let req = req?;
-            let response = self.handle_request(req);
-            stream.send(response).await?;
+        let mut closed = self.closed.clone();
+        let result = loop {
+            let req = tokio::select! {
+                req = stream.next() => req,
+                // 关闭的时候 不在事务中的会话直接断开 在事务中的会话等到事务结束
+                _ = closing(&mut closed), if !self.handler.in_transaction() => break Ok(()),
+            };
+            let req = match req {
+                // 开始订阅之后这个连接只用来推送修改
+                Some(Ok(Request::Subscribe { table, after })) => {
+                    break self.subscribe(&mut stream, &mut closed, table, after).await
+                }
+                Some(Ok(Request::Replicate(after))) => {
+                    break self.replicate(&mut stream, &mut closed, after).await
+                }
+                Some(Ok(Request::Stream { sql, frame })) => {
+                    if let Err(e) = self.stream(&mut stream, sql, frame).await {
+                        break Err(e);
+                    }
+                    continue;
+                }
+                Some(Ok(req)) => req,
+                Some(Err(e)) => break Err(e.into()),
+                None => break Ok(()),
+            };
+            // 执行的时候其他连接的请求可以继续处理
+            let handler = self.handler.clone();
+            let response = blocking(move || handler.handle_request(req)).await;
+            if let Err(e) = stream.send(response).await {
+                break Err(e.into());
+            }
+        };
+        // 客户端断开的时候还在事务中 回滚事务释放锁住的数据
+        let handler = self.handler.clone();
+        match blocking(move || handler.sql_session.lock()?.close()).await {
+            Ok(Some(id)) => {
+                info!("session {} disconnect, rollback transaction {}", self.handler.id, id)
+            }
+            Ok(None) => {}
+            Err(e) => error!("session {} rollback get error {}", self.handler.id, e),
         }
-        Ok(())
+        result
     }
 
-    pub fn handle_request(&mut self, req: Request) -> Result<Response> {
-     
+    /// 分批发送一条查询的结果 每一批发送完才执行下一批
+    /// 客户端读得慢的时候发送会等待 kv引擎的扫描也是拉取一批读一批 服务端只保留当前的一批
+    /// 排序 聚合和连接的右边需要先读完输入 raft引擎的扫描一次读出整个表
+    /// 语句被取消或者超过 statement_timeout 的时候发送错误
+    async fn stream(&mut self, stream: &mut Connection, sql: String, frame: usize) -> Result<()> {
+        if frame == 0 {
+            let e = Error::Executor("stream frame size must be positive".into());
+            return Ok(stream.send(Err(e)).await?);
+        }
+        let handler = self.handler.clone();
+        let opened = blocking(move || {
+            let mut session = handler.sql_session.lock()?;
+            let (id, columns) = session.open_cursor(&sql)?;
+            Ok((id, columns, session.canceller()))
+        })
+        .await;
+        let (id, cancel) = match opened {
+            Ok((id, columns, cancel)) => {
+                stream.send(Ok(Response::Cursor { id, columns })).await?;
+                (id, cancel)
+            }
+            Err(e) => return Ok(stream.send(Err(e)).await?),
+        };
+        loop {
+            let (handler, cancel) = (self.handler.clone(), cancel.clone());
+            let rows = blocking(move || {
+                let mut session = handler.sql_session.lock()?;
+                // fetch 会重新开始计时 取消和超时在每一批之前检查
+                if let Err(e) = cancel.check() {
+                    session.close_cursor(id);
+                    return Err(e);
+                }
+                session.fetch(id, frame)
+            })
+            .await;
+            match rows {
+                Ok(rows) => {
+                    let done = rows.len() < frame;
+                    stream.send(Ok(Response::Fetch(rows))).await?;
+                    if done {
+                        return Ok(());
+                    }
+                }
+                Err(e) => return Ok(stream.send(Err(e)).await?),
+            }
+        }
+    }
+
+    /// 推送表的行修改 客户端断开或者server关闭的时候结束
+    /// after为None的时候从最新的修改之后开始
+    async fn subscribe(
+        &mut self,
+        stream: &mut Connection,
+        closed: &mut watch::Receiver<bool>,
+        table: String,
+        after: Option<u64>,
+    ) -> Result<()> {
+        let handler = self.handler.clone();
+        let name = table.clone();
+        let start = blocking(move || -> Result<u64> {
+            handler
+                .sql_session
+                .lock()?
+                .with_txn(Mode::ReadOnly, |txn| txn.must_read_table(&name))?;
+            match after {
+                Some(after) => Ok(after),
+                None => handler.engine.last_commit(),
+            }
+        })
+        .await;
+        let mut after = match start {
+            Ok(after) => after,
+            Err(e) => return Ok(stream.send(Err(e)).await?),
+        };
+        info!("session {} subscribe table {} after {}", self.handler.id, table, after);
+        stream.send(Ok(Response::Subscribe(after))).await?;
+        loop {
+            let engine = self.handler.engine.clone();
+            let commits = blocking(move || engine.commits(after, COMMIT_BATCH)).await?;
+            if let Some(commit) = commits.last() {
+                after = commit.seq;
+            }
+            let full = commits.len() >= COMMIT_BATCH;
+            let changes = commits
+                .into_iter()
+                .flat_map(|c| c.changes)
+                .filter(|c| c.table == table)
+                .collect::<Vec<_>>();
+            if !changes.is_empty() {
+                stream.send(Ok(Response::Changes(changes))).await?;
+            }
+            if !full && !poll_wait(stream, closed).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// 把提交日志推送给副本 after为None的时候先发送全部数据的快照
+    async fn replicate(
+        &mut self,
+        stream: &mut Connection,
+        closed: &mut watch::Receiver<bool>,
+        after: Option<u64>,
+    ) -> Result<()> {
+        let mut after = match after {
+            Some(after) => {
+                stream.send(Ok(Response::Replicate { seq: after, snapshot: false })).await?;
+                after
+            }
+            None => self.send_snapshot(stream).await?,
+        };
+        info!("session {} replicate after {}", self.handler.id, after);
+        loop {
+            let engine = self.handler.engine.clone();
+            let commits = blocking(move || engine.commits(after, COMMIT_BATCH)).await?;
+            let full = commits.len() >= COMMIT_BATCH;
+            if let Some(commit) = commits.last() {
+                after = commit.seq;
+                stream.send(Ok(Response::Commits(commits))).await?;
+            }
+            if !full && !poll_wait(stream, closed).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// 分页发送一个只读事务中的所有数据 最后发送一个空页 返回快照对应的提交序号
+    /// 先读序号再开启事务 序号之后的提交可能已经在快照中 副本重放的时候结果不变
+    async fn send_snapshot(&mut self, stream: &mut Connection) -> Result<u64> {
+        let engine = self.handler.engine.clone();
+        let (seq, txn) = blocking(move || -> Result<_> {
+            let seq = engine.last_commit()?;
+            Ok((seq, engine.begin(Mode::ReadOnly)?))
+        })
+        .await?;
+        let txn = Arc::new(Mutex::new(txn));
+        let result = Self::send_dump(stream, txn.clone(), seq).await;
+        // 只读事务 出错的时候也要结束
+        blocking(move || match Arc::try_unwrap(txn) {
+            Ok(txn) => txn.into_inner()?.rollback(),
+            Err(_) => Err(Error::Internal("snapshot transaction is still in use".into())),
+        })
+        .await?;
+        result.map(|_| seq)
+    }
+
+    /// 每一页在阻塞线程池中读取 发送的时候不占用阻塞线程
+    async fn send_dump(
+        stream: &mut Connection,
+        txn: Arc<Mutex<E::Transaction>>,
+        seq: u64,
+    ) -> Result<()> {
+        stream.send(Ok(Response::Replicate { seq, snapshot: true })).await?;
+        let mut last: Option<Vec<u8>> = None;
+        loop {
+            let page = txn.clone();
+            let start = last.take();
+            let items = blocking(move || page.lock()?.dump(start.as_deref(), BATCH_SIZE)).await?;
+            let done = items.is_empty();
+            last = items.last().map(|(key, _)| key.clone());
+            stream.send(Ok(Response::Dump(items))).await?;
+            if done {
+                return Ok(());
+            }
+        }
+    }
+}
+
+impl<E: Engine + 'static> Handler<E> {
+    fn in_transaction(&self) -> bool {
+        self.sql_session.lock().is_ok_and(|s| s.in_transaction())
+    }
+
+    pub fn handle_request(&self, req: Request) -> Result<Response> {
         // 根据request不同类型进行不同的执行
         let r = match req {
-            Request::Execute(sql) => {
-                let r = self.sql_session.execute(&sql)?;
-                Response::Execute(r)
-            }
+            // 一条语句都没有执行完的时候直接返回错误
+            Request::Execute(sql) => match self.sql_session.lock()?.execute_all(&sql) {
+                Ok(results) => Response::Execute(results),
+                Err(Partial { results, error }) if results.is_empty() => return Err(error),
+                Err(Partial { results, error }) => Response::Partial { results, error },
+            },
             Request::GetTable(s) => {
                 let r = self
                     .sql_session
+                    .lock()?
                     .with_txn(Mode::ReadOnly, |txn| txn.must_read_table(&s))?;
                 Response::GetTable(r)
             }
             Request::ListTables => {
                 let r = self
                     .sql_session
+                    .lock()?
                     .with_txn(Mode::ReadOnly, |txn| {
                         let tables = txn.scan_tables();
                         tables
@@ -143,12 +859,61 @@ impl Session {
                     .collect();
                 Response::ListTables(r)
             }
-            Request::Status => Response::Status(self.engine.get_statue()?),
+            Request::Status => Response::Status(self.engine.status()?),
+            Request::OpenCursor(sql) => {
+                let (id, columns) = self.sql_session.lock()?.open_cursor(&sql)?;
+                Response::Cursor { id, columns }
+            }
+            Request::Fetch { cursor, count } => {
+                Response::Fetch(self.sql_session.lock()?.fetch(cursor, count)?)
+            }
+            Request::CloseCursor(id) => {
+                Response::CloseCursor(self.sql_session.lock()?.close_cursor(id))
+            }
+            // 在serve中处理
+            Request::Subscribe { .. } | Request::Replicate(_) | Request::Stream { .. } => {
+                return Err(Error::Internal("subscribe is handled by session serve".into()))
+            }
+            Request::Session => Response::Session(self.id),
+            Request::Txn => Response::Txn(self.sql_session.lock()?.txn()),
+            Request::Cancel(id) => {
+                let cancel = self.cancels.lock()?.get(&id).cloned();
+                if let Some(cancel) = &cancel {
+                    info!("session {} cancel statement of session {}", self.id, id);
+                    cancel.cancel();
+                }
+                Response::Cancel(cancel.is_some())
+            }
+            Request::Ping => {
+                // 存储出错也需要正常返回 让探针知道节点没有就绪
+                let start = Instant::now();
+                let health = self.engine.ping().and_then(|_| self.engine.raft_status());
+                let (raft, error) = match health {
+                    Ok(raft) => (raft, None),
+                    Err(e) => (None, Some(e.to_string())),
+                };
+                // raft节点没有leader的时候 多数派不可用 写入不能完成
+                let quorum = raft.as_ref().is_none_or(|r| r.leader.is_some());
+                Response::Pong(Health {
+                    ready: error.is_none() && quorum,
+                    latency: start.elapsed().as_micros() as u64,
+                    error,
+                    raft,
+                })
+            }
         };
         Ok(r)
     }
 }
 
+impl<E: Engine> Drop for Session<E> {
+    fn drop(&mut self) {
+        if let Ok(mut cancels) = self.handler.cancels.lock() {
+            cancels.remove(&self.handler.id);
+        }
+    }
+}
+
 /// client Request
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Request {
@@ -156,14 +921,582 @@ pub enum Request {
     GetTable(String),
     ListTables,
     Status,
+    /// 健康检查
+    Ping,
+    /// 获得当前会话的id
+    Session,
+    /// 获得当前会话中显式开启的事务
+    Txn,
+    /// 取消某个会话正在执行的语句 需要从另一个连接发送
+    Cancel(u64),
+    /// 订阅表中提交的行修改 之后这个连接上只会收到修改
+    Subscribe {
+        table: String,
+        /// 从这个序号之后开始 为None的时候从最新的修改之后开始
+        after: Option<u64>,
+    },
+    /// 副本从这个序号之后复制提交日志 为None的时候先复制全部数据的快照
+    Replicate(Option<u64>),
+    /// 为一条查询打开游标
+    OpenCursor(String),
+    /// 从游标中读取最多count行
+    Fetch { cursor: u64, count: usize },
+    CloseCursor(u64),
+    /// 执行一条查询 先返回 Cursor 之后连续发送每批最多frame行的 Fetch
+    /// 少于frame行的一批是最后一批 出错的时候发送错误并结束
+    Stream { sql: String, frame: usize },
 }
 
 /// server Response
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
-    Execute(ResultSet),
+    /// 每条语句的结果
+    Execute(Vec<ResultSet>),
+    /// 执行到一半出错 之前执行完的语句的结果和出错的原因
+    Partial {
+        results: Vec<ResultSet>,
+        error: Error,
+    },
     Row(Option<Row>),
     GetTable(Table),
     ListTables(Vec<String>),
     Status(Status),
+    Pong(Health),
+    Session(u64),
+    /// 事务的id和模式 没有显式开启事务的时候为None
+    Txn(Option<(u64, Mode)>),
+    /// 会话是否存在
+    Cancel(bool),
+    /// 订阅开始的序号
+    Subscribe(u64),
+    /// 按照提交顺序的行修改
+    Changes(Vec<Change>),
+    /// 复制开始的序号 snapshot为true的时候之后先发送快照
+    Replicate { seq: u64, snapshot: bool },
+    /// 快照的一页 空页表示快照结束
+    Dump(KvItems),
+    /// 按照提交顺序的提交日志
+    Commits(Vec<Commit>),
+    /// 打开的游标id和查询结果的列
+    Cursor { id: u64, columns: Columns },
+    /// 游标中的下一批行 少于请求的行数的时候游标已经读完并关闭
+    Fetch(Rows),
+    /// 游标是否存在
+    CloseCursor(bool),
+}
+
+
+/// 健康检查的结果
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Health {
+    /// 存储是否可以正常响应
+    pub ready: bool,
+    /// 检查存储花费的时间 单位微秒
+    pub latency: u64,
+    /// 存储返回的错误
+    pub error: Option<String>,
+    /// raft节点的leader 任期和日志位置 不是raft节点的时候为None
+    pub raft: Option<raft::Status>,
+}
+
+/// 测试用的内存server 监听系统分配的端口
+#[cfg(test)]
+pub(crate) fn test_server() -> Result<Server<KV>> {
+    use crate::storage::kv::b_tree::BtreeStore;
+    Server::new("127.0.0.1:0", Box::new(BtreeStore::new()), &Options::default())
+}
+
+/// 绑定端口之后在后台运行server 返回的时候已经可以连接
+#[cfg(test)]
+pub(crate) async fn spawn_test_server<E>(mut server: Server<E>) -> Result<Addrs>
+where
+    E: Engine + Send + Sync + 'static,
+    E::Transaction: Send,
+{
+    let addrs = server.listen().await?;
+    tokio::spawn(server.server());
+    Ok(addrs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::storage::kv::b_tree::BtreeStore;
+    use crate::storage::wal::{SyncPolicy, Wal};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn shutdown_test() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("coke_db_shutdown_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let open = || -> Result<Box<dyn SqlStore>> {
+            Ok(Box::new(Wal::new(&path, Box::new(BtreeStore::new()), SyncPolicy::Always)?))
+        };
+        let mut server = Server::new("127.0.0.1:0", open()?, &Options::default())?;
+        let port = server.listen().await?.sql.port();
+        let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+        let handle = tokio::spawn(server.serve_until(async {
+            let _ = signal.await;
+        }));
+        let client = Client::new("127.0.0.1", port).await?;
+        let idle = Client::new("127.0.0.1", port).await?;
+        client.execute("create table t ( id int primary key );").await?;
+        client.execute("begin transaction; insert into t values (1);").await?;
+
+        shutdown.send(()).map_err(|_| Error::Internal("server stopped".into()))?;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // 不再接受新的连接 事务中的会话可以继续执行到事务结束
+        assert!(Client::new("127.0.0.1", port).await.is_err());
+        assert!(!handle.is_finished());
+        client.execute("insert into t values (2); commit;").await?;
+        handle.await.map_err(|e| Error::Internal(e.to_string()))??;
+        assert!(idle.execute("select * from t;").await.is_err());
+
+        // 正常关闭 重新启动的时候数据都在 也不需要恢复
+        let engine = Options::default().kv(open()?);
+        assert!(engine.get_metadata(b"clean_shutdown")?.is_some());
+        assert_eq!(engine.recover()?, 0);
+        assert_eq!(engine.status()?.tables.get("t"), Some(&2));
+        drop(engine);
+        let _ = std::fs::remove_file(&path);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn disconnect_test() -> Result<()> {
+        let port = spawn_test_server(test_server()?).await?.sql.port();
+        let client = Client::new("127.0.0.1", port).await?;
+        client.execute("create table t ( id int primary key );").await?;
+        client.execute("begin transaction; insert into t values (1);").await?;
+        // 客户端断开之后事务被回滚 其他会话可以写入同一行
+        drop(client);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let client = Client::new("127.0.0.1", port).await?;
+        client.execute("insert into t values (1);").await?;
+        assert_eq!(client.get_status().await?.mvcc.txns_active, 0);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn health_test() -> Result<()> {
+        let port = spawn_test_server(test_server()?).await?.sql.port();
+        let health = Client::new("127.0.0.1", port).await?.ping().await?;
+        assert!(health.ready && health.error.is_none() && health.raft.is_none());
+
+        // 只有一个节点的raft集群 自己选出自己之后才就绪
+        let server = Server::new_raft(
+            "a",
+            "127.0.0.1:0",
+            "127.0.0.1:0",
+            HashMap::new(),
+            Box::new(BtreeStore::new()),
+            Box::new(BtreeStore::new()),
+            &Options::default(),
+        )?;
+        let port = spawn_test_server(server).await?.sql.port();
+        let client = Client::new("127.0.0.1", port).await?;
+        let mut health = client.ping().await?;
+        for _ in 0..50 {
+            if health.ready {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            health = client.ping().await?;
+        }
+        assert!(health.ready);
+        let raft = health.raft.expect("raft status");
+        assert_eq!((raft.leader.as_deref(), raft.role.as_str()), (Some("a"), "leader"));
+        assert!(raft.term > 0);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn txn_state_test() -> Result<()> {
+        let port = spawn_test_server(test_server()?).await?.sql.port();
+        let client = Client::new("127.0.0.1", port).await?;
+        client.execute("create table t ( id int primary key );").await?;
+        // 后面的语句出错的时候 前面开启的事务仍然记录下来
+        assert!(client.execute("begin transaction read only; select * from x;").await.is_err());
+        assert!(matches!(client.txn(), Some((_, Mode::ReadOnly))));
+        assert!(client.execute("commit; select * from x;").await.is_err());
+        assert_eq!(client.txn(), None);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn stream_test() -> Result<()> {
+        use crate::sql::Value;
+        use futures::TryStreamExt;
+        let port = spawn_test_server(test_server()?).await?.sql.port();
+        let client = Client::new("127.0.0.1", port).await?;
+        client.execute("create table t ( id int primary key );").await?;
+        let values = (1..=25).map(|i| format!("({})", i)).collect::<Vec<_>>().join(", ");
+        client.execute(&format!("insert into t values {};", values)).await?;
+
+        let stream = client.execute_stream("select * from t;", 10).await?;
+        assert_eq!(stream.columns().len(), 1);
+        let rows: Rows = stream.try_collect().await?;
+        assert_eq!(rows.len(), 25);
+        assert_eq!(rows[24], vec![Value::Integer(25)]);
+        // 行数正好是每批的整数倍的时候 最后是一个空的批
+        let stream = client.execute_stream("select * from t where id <= 20;", 10).await?;
+        assert_eq!(stream.try_collect::<Rows>().await?.len(), 20);
+
+        // 没有读完就丢弃 之后的请求仍然得到自己的结果
+        let mut stream = client.execute_stream("select * from t;", 2).await?;
+        assert_eq!(stream.try_next().await?, Some(vec![Value::Integer(1)]));
+        drop(stream);
+        assert_eq!(client.query::<(i64,)>("select count(*) from t;").await?, vec![(25,)]);
+
+        assert!(client.execute_stream("select * from x;", 10).await.is_err());
+        assert!(client.execute_stream("delete from t;", 10).await.is_err());
+        assert!(client.execute_stream("select * from t;", 0).await.is_err());
+        assert_eq!(client.list_tables().await?, vec!["t"]);
+        Ok(())
+    }
+
+    crate::from_row! {
+        #[derive(Debug, PartialEq)]
+        struct User {
+            name: Option<String>,
+            id: i64,
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn from_row_test() -> Result<()> {
+        let port = spawn_test_server(test_server()?).await?.sql.port();
+        let client = Client::new("127.0.0.1", port).await?;
+        client
+            .execute(
+                "create table t ( id int primary key, name string null default null, \
+                 score float ); insert into t values (1, \"a\", 1.5), (2, null, 2.0);",
+            )
+            .await?;
+        // 结构体按照名字对应列 元组按照位置
+        let users: Vec<User> = client.query("select * from t;").await?;
+        assert_eq!(
+            users,
+            vec![
+                User { name: Some("a".into()), id: 1 },
+                User { name: None, id: 2 },
+            ]
+        );
+        let rows: Vec<(i64, f64)> = client.query("select id, score * 2 from t;").await?;
+        assert_eq!(rows, vec![(1, 3.0), (2, 4.0)]);
+
+        // 类型不对 列不存在 或者null转换成不是 Option 的类型都报错
+        let e = client.query::<(String, f64)>("select id, score from t;").await.unwrap_err();
+        assert!(e.to_string().contains("column 0 is INTEGER"), "{}", e);
+        assert!(client.query::<User>("select id from t;").await.is_err());
+        assert!(client.query::<(i64, String)>("select id, name from t;").await.is_err());
+        assert!(client.query::<(i64,)>("select id, name from t;").await.is_err());
+        assert_eq!(client.query::<(i64,)>("select count(*) from t;").await?, vec![(2,)]);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn subscribe_test() -> Result<()> {
+        use crate::sql::engine::ChangeKind;
+        use crate::sql::Value;
+        let port = spawn_test_server(test_server()?).await?.sql.port();
+        let client = Client::new("127.0.0.1", port).await?;
+        assert!(client.subscribe("t").await.is_err());
+        client.execute("create table t ( id int primary key, n int );").await?;
+        client.execute("create table u ( id int primary key );").await?;
+        client.execute("insert into t values (1, 10);").await?;
+        // 只收到订阅之后提交的修改
+        let mut subscription = client.subscribe("t").await?;
+        client.execute("insert into u values (1);").await?;
+        client.execute("update t set n = 11 where id = 1;").await?;
+        client.execute("begin transaction; delete from t; insert into t values (2, 20);").await?;
+        client.execute("commit;").await?;
+
+        let change = subscription.next().await?;
+        assert_eq!(change.kind, ChangeKind::Update);
+        assert_eq!(change.after, Some(vec![Value::Integer(1), Value::Integer(11)]));
+        let seq = subscription.seq();
+        assert_eq!(subscription.next().await?.kind, ChangeKind::Delete);
+        // 事务的修改还没有全部返回
+        assert_eq!(subscription.seq(), seq);
+        assert_eq!(subscription.next().await?.kind, ChangeKind::Insert);
+        assert_eq!(subscription.seq(), seq + 1);
+
+        // 从中断的位置恢复
+        let mut resumed = client.subscribe_after("t", Some(seq)).await?;
+        assert_eq!(resumed.next().await?.kind, ChangeKind::Delete);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn replica_test() -> Result<()> {
+        use crate::sql::execution::ResultSet;
+        use crate::sql::Value;
+        let primary_addr = spawn_test_server(test_server()?).await?.sql;
+        let primary = Client::new("127.0.0.1", primary_addr.port()).await?;
+        primary.execute("create table t ( id int primary key, n int );").await?;
+        primary.execute("insert into t values (1, 10), (2, 20);").await?;
+
+        // 先复制已有数据的快照
+        let replica = Server::new_replica(
+            "127.0.0.1:0",
+            &primary_addr.to_string(),
+            Box::new(BtreeStore::new()),
+            &Options::default(),
+        )?;
+        let replica_port = spawn_test_server(replica).await?.sql.port();
+        let replica = Client::new("127.0.0.1", replica_port).await?;
+        async fn ids(client: &Client, sql: &str) -> Result<Vec<Value>> {
+            match client.execute(sql).await?.pop() {
+                Some(ResultSet::Query { rows, .. }) => {
+                    Ok(rows.into_iter().map(|r| r[0].clone()).collect())
+                }
+                r => Err(Error::Internal(format!("unexpected result {:?}", r))),
+            }
+        }
+        // 副本是异步复制的 等到读到期望的结果
+        async fn wait(replica: &Client, sql: &str, expect: Vec<Value>) -> Result<()> {
+            for _ in 0..200 {
+                if ids(replica, sql).await.is_ok_and(|rows| rows == expect) {
+                    return Ok(());
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            Err(Error::Internal(format!("replica did not get {:?}", expect)))
+        }
+        wait(&replica, "select id from t;", vec![Value::Integer(1), Value::Integer(2)]).await?;
+
+        // 之后按照提交的顺序重放 包括建表
+        primary.execute("delete from t where id = 1;").await?;
+        primary.execute("create table u ( id int primary key );").await?;
+        primary.execute("insert into u values (3);").await?;
+        wait(&replica, "select id from u;", vec![Value::Integer(3)]).await?;
+        assert_eq!(ids(&replica, "select id from t;").await?, vec![Value::Integer(2)]);
+
+        // 副本上不能写入
+        assert!(replica.execute("insert into t values (4, 40);").await.is_err());
+        assert!(replica.execute("begin transaction;").await.is_err());
+        replica.execute("begin transaction read only;").await?;
+        replica.execute("commit;").await?;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cursor_test() -> Result<()> {
+        use crate::sql::Value;
+        let port = spawn_test_server(test_server()?).await?.sql.port();
+        let client = Client::new("127.0.0.1", port).await?;
+        client.execute("create table t ( id int primary key );").await?;
+        let values: Vec<String> = (1..=2500).map(|i| format!("({})", i)).collect();
+        client.execute(&format!("insert into t values {};", values.join(","))).await?;
+
+        // 分批读取全部结果
+        let mut cursor = client.query_cursor("select id from t order by id;", 1000).await?;
+        assert_eq!(cursor.columns().len(), 1);
+        let mut sizes = Vec::new();
+        let mut ids = Vec::new();
+        while let Some(rows) = cursor.fetch_next().await? {
+            sizes.push(rows.len());
+            ids.extend(rows.into_iter().map(|r| r[0].clone()));
+        }
+        assert_eq!(sizes, vec![1000, 1000, 500]);
+        assert_eq!(ids, (1..=2500).map(Value::Integer).collect::<Vec<_>>());
+        assert!(cursor.fetch_next().await?.is_none());
+
+        // 读完之后服务端已经关闭游标
+        let res = client.call(Request::Fetch { cursor: 1, count: 1 }).await;
+        assert!(res.is_err());
+
+        // 提前关闭的游标不能再读取 连接可以继续执行语句
+        let mut cursor = client.query_cursor("select id from t;", 10).await?;
+        assert_eq!(cursor.fetch_next().await?.map(|rows| rows.len()), Some(10));
+        cursor.close().await?;
+        let res = client.call(Request::Fetch { cursor: 2, count: 1 }).await;
+        assert!(res.is_err());
+        client.execute("delete from t where id > 10;").await?;
+
+        // 只有查询可以打开游标
+        assert!(client.query_cursor("delete from t;", 10).await.is_err());
+        assert!(client.query_cursor("select id from t;", 0).await.is_err());
+        Ok(())
+    }
+
+    /// 单线程的runtime中执行很慢的语句 其他连接仍然可以处理请求
+    #[tokio::test]
+    async fn blocking_test() -> Result<()> {
+        let port = spawn_test_server(test_server()?).await?.sql.port();
+        let client = Client::new("127.0.0.1", port).await?;
+        let values = (0..200).map(|i| format!("({})", i)).collect::<Vec<_>>();
+        for t in ["a", "b", "c"] {
+            client.execute(&format!("create table {} ( id int primary key );", t)).await?;
+            client.execute(&format!("insert into {} values {};", t, values.join(","))).await?;
+        }
+        // 防止取消没有生效的时候一直执行
+        client.execute("set statement_timeout = 60000;").await?;
+        let id = match client.call(Request::Session).await? {
+            Response::Session(id) => id,
+            r => panic!("unexpected response {:?}", r),
+        };
+        let heavy = client.execute("select a.id from a cross join b cross join c;");
+        let other = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let other = Client::new("127.0.0.1", port).await?;
+            assert!(other.ping().await?.ready);
+            assert_eq!(other.list_tables().await?.len(), 3);
+            other.call(Request::Cancel(id)).await
+        };
+        let (result, cancel) = tokio::join!(heavy, other);
+        assert!(matches!(cancel?, Response::Cancel(true)));
+
+        // 语句在执行中被取消 取消之后执行语句的连接可以继续使用
+        match result {
+            Err(Error::Cancelled(e)) => assert_eq!(e, "statement cancelled"),
+            r => panic!("unexpected result {:?}", r),
+        }
+        assert_eq!(client.list_tables().await?.len(), 3);
+        Ok(())
+    }
+
+    /// 单线程的runtime中发送快照 不能用block_in_place
+    #[tokio::test]
+    async fn snapshot_test() -> Result<()> {
+        use crate::client::Replication;
+        let port = spawn_test_server(test_server()?).await?.sql.port();
+        let client = Client::new("127.0.0.1", port).await?;
+        client.execute("create table t ( id int primary key );").await?;
+        client.execute("insert into t values (1), (2), (3);").await?;
+
+        let mut replication = Replication::connect("127.0.0.1", port, None).await?;
+        assert!(replication.has_snapshot());
+        let mut items = 0;
+        while let Some(page) = replication.next_snapshot().await? {
+            items += page.len();
+        }
+        assert!(items > 3);
+        // 快照的只读事务已经结束
+        drop(replication);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(client.get_status().await?.mvcc.txns_active, 0);
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn pool_test() -> Result<()> {
+        use crate::client::{Pool, PoolOptions};
+        use crate::sql::Value;
+        // 重启的时候监听同一个端口
+        async fn start(addr: String) -> Result<(u16, Shutdown, JoinHandle<Result<()>>)> {
+            let mut server = Server::new(&addr, Box::new(BtreeStore::new()), &Options::default())?;
+            let port = server.listen().await?.sql.port();
+            let (shutdown, signal) = tokio::sync::oneshot::channel::<()>();
+            let handle = tokio::spawn(server.serve_until(async {
+                let _ = signal.await;
+            }));
+            Ok((port, shutdown, handle))
+        }
+        type Shutdown = tokio::sync::oneshot::Sender<()>;
+        type JoinHandle<T> = tokio::task::JoinHandle<T>;
+        let (port, shutdown, handle) = start("127.0.0.1:0".to_string()).await?;
+        let options = PoolOptions {
+            size: 2,
+            backoff: Duration::from_millis(20),
+            retries: 20,
+            ..PoolOptions::default()
+        };
+        let pool = Arc::new(Pool::new("127.0.0.1", port, options).await?);
+        pool.execute("create table t ( id int primary key );").await?;
+        async fn count(pool: &Pool) -> Result<Value> {
+            match pool.execute("select count(id) from t;").await?.pop() {
+                Some(ResultSet::Query { mut rows, .. }) => Ok(rows.remove(0).remove(0)),
+                r => Err(Error::Internal(format!("unexpected result {:?}", r))),
+            }
+        }
+
+        // 多个任务共享连接池
+        let mut tasks = JoinSet::new();
+        for i in 0..8 {
+            let pool = pool.clone();
+            tasks.spawn(async move {
+                pool.execute(&format!("insert into t values ({});", i)).await.map(|_| ())
+            });
+        }
+        while let Some(result) = tasks.join_next().await {
+            result??;
+        }
+        assert_eq!(count(&pool).await?, Value::Integer(8));
+
+        // 一次发送多个请求 出错的请求不影响其他的请求
+        let results = pool
+            .pipeline(&["insert into t values (8);", "insert into t values (8);", "select 1;"])
+            .await?;
+        assert_eq!(results.len(), 3);
+        assert!(results[0].is_ok() && results[1].is_err() && results[2].is_ok());
+        assert_eq!(count(&pool).await?, Value::Integer(9));
+
+        // 事务需要在一个请求中结束 没有结束的事务会被回滚
+        assert!(pool.execute("begin transaction; insert into t values (100);").await.is_err());
+        assert!(pool.execute("begin transaction; insert into t values (9); x;").await.is_err());
+        pool.execute("begin transaction; insert into t values (10); commit;").await?;
+        let results = pool
+            .pipeline(&["begin transaction;", "insert into t values (11);", "commit;"])
+            .await?;
+        assert!(results.iter().all(|r| r.is_ok()));
+        assert_eq!(count(&pool).await?, Value::Integer(11));
+
+        // server重启之后断开的连接返回错误 之后重新连接
+        shutdown.send(()).map_err(|_| Error::Internal("server stopped".into()))?;
+        handle.await??;
+        for _ in 0..pool.size() {
+            assert!(pool.execute("select 1;").await.is_err());
+        }
+        let restart = tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            start(format!("127.0.0.1:{}", port)).await
+        });
+        pool.execute("create table t ( id int primary key );").await?;
+        pool.execute("insert into t values (1);").await?;
+        assert_eq!(count(&pool).await?, Value::Integer(1));
+        let (_, shutdown, handle) = restart.await??;
+        shutdown.send(()).map_err(|_| Error::Internal("server stopped".into()))?;
+        handle.await??;
+        Ok(())
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn metrics_test() -> Result<()> {
+        let addrs = spawn_test_server(test_server()?.with_metrics("127.0.0.1:0")).await?;
+        let metrics_port = addrs.metrics.map(|addr| addr.port()).unwrap_or_default();
+        let client = Client::new("127.0.0.1", addrs.sql.port()).await?;
+        client.execute("create table t ( id int primary key );").await?;
+        client.execute("insert into t values (1), (2);").await?;
+        client.execute("select id from t where id > 1; select id from t;").await?;
+        client.execute("begin transaction;").await?;
+
+        async fn get(port: u16, path: &str) -> Result<String> {
+            let mut socket = TcpStream::connect(("127.0.0.1", port)).await?;
+            let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+            socket.write_all(request.as_bytes()).await?;
+            let mut response = String::new();
+            socket.read_to_string(&mut response).await?;
+            Ok(response)
+        }
+        let response = get(metrics_port, "/metrics").await?;
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        // 统计是进程内全局的 其他测试执行的语句也会计算在内
+        for metric in [
+            "coke_db_statements_total{type=\"select\"}",
+            "coke_db_statements_total{type=\"insert\"}",
+            "coke_db_node_duration_seconds_count{node=\"Scan\"}",
+            "coke_db_node_duration_seconds_bucket{node=\"Scan\",le=\"+Inf\"}",
+        ] {
+            assert!(response.contains(metric), "{} not in {}", metric, response);
+        }
+        assert!(response.contains("\ncoke_db_transactions_active 1\n"), "{}", response);
+        assert!(response.contains("\ncoke_db_plan_cache_entries "), "{}", response);
+        assert!(!response.contains("coke_db_table_rows"), "{}", response);
+
+        let response = get(metrics_port, "/other").await?;
+        assert!(response.starts_with("HTTP/1.1 404 Not Found"), "{}", response);
+        Ok(())
+    }
 }
@@ -0,0 +1,434 @@
+//! 定点小数 DECIMAL(p,s) 的值 数值是 value / 10^scale
+//! 用i128保存 最多38位有效数字 运算都是精确的 需要舍入的时候四舍五入(远离零)
+use std::cmp::Ordering;
+use std::fmt::{self, Display};
+use std::hash::{Hash, Hasher};
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::*;
+
+/// 最多的有效数字 小数位数也不能超过这个
+pub const MAX_PRECISION: u8 = 38;
+/// 没有写精度的时候 DECIMAL 就是 DECIMAL(10,0)
+pub const DEFAULT_PRECISION: u8 = 10;
+/// 除法的结果至少保留的小数位数
+pub const DIVIDE_SCALE: u8 = 6;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Decimal {
+    value: i128,
+    scale: u8,
+}
+
+fn pow10(n: u8) -> i128 {
+    10i128.pow(n as u32)
+}
+
+fn overflow() -> Error {
+    Error::Evaluate("Decimal overflow".to_string())
+}
+
+/// 四舍五入的整数除法
+pub(crate) fn div_round(n: i128, d: i128) -> i128 {
+    let (q, r) = (n / d, n % d);
+    if r.unsigned_abs() >= d.unsigned_abs() - r.unsigned_abs() {
+        q + n.signum() * d.signum()
+    } else {
+        q
+    }
+}
+
+impl Decimal {
+    pub fn new(value: i128, scale: u8) -> Result<Self> {
+        if scale > MAX_PRECISION {
+            return Err(Error::Evaluate(format!(
+                "Decimal scale {} is larger than {}",
+                scale, MAX_PRECISION
+            )));
+        }
+        let decimal = Self { value, scale };
+        if decimal.digits() > MAX_PRECISION {
+            return Err(overflow());
+        }
+        Ok(decimal)
+    }
+
+    pub fn from_i64(i: i64) -> Self {
+        Self {
+            value: i as i128,
+            scale: 0,
+        }
+    }
+
+    /// 使用浮点数最短的十进制表示 1.1 转换成的就是 1.1
+    pub fn from_f64(f: f64) -> Result<Self> {
+        if !f.is_finite() {
+            return Err(Error::Evaluate(format!("Can't convert {} to decimal", f)));
+        }
+        Self::parse(&f.to_string())
+    }
+
+    /// 解析 -123.45 这样的字符串 超过最大位数的小数部分会被舍入
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = || Error::Parse(format!("invalid decimal {}", s));
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) => (true, rest),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (int, frac) = digits.split_once('.').unwrap_or((digits, ""));
+        if int.is_empty() && frac.is_empty()
+            || !int.chars().chain(frac.chars()).all(|c| c.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+        let keep = frac.len().min(MAX_PRECISION as usize);
+        let mut value: i128 = 0;
+        for c in int.chars().chain(frac[..keep].chars()) {
+            value = value
+                .checked_mul(10)
+                .and_then(|v| v.checked_add(c as i128 - '0' as i128))
+                .ok_or_else(overflow)?;
+        }
+        if frac[keep..].starts_with(|c: char| c >= '5') {
+            value = value.checked_add(1).ok_or_else(overflow)?;
+        }
+        Self::new(if negative { -value } else { value }, keep as u8)
+    }
+
+    pub fn value(&self) -> i128 {
+        self.value
+    }
+
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    /// 有效数字的个数 0也算一位
+    pub fn digits(&self) -> u8 {
+        self.value.unsigned_abs().checked_ilog10().map_or(1, |n| n as u8 + 1)
+    }
+
+    /// 改变小数的位数 位数变少的时候四舍五入
+    pub fn rescale(&self, scale: u8) -> Result<Self> {
+        if scale >= self.scale {
+            let value = self
+                .value
+                .checked_mul(
+                    10i128
+                        .checked_pow((scale - self.scale) as u32)
+                        .ok_or_else(overflow)?,
+                )
+                .ok_or_else(overflow)?;
+            Self::new(value, scale)
+        } else {
+            Self::new(div_round(self.value, pow10(self.scale - scale)), scale)
+        }
+    }
+
+    /// 转换成 DECIMAL(precision, scale) 的值 整数部分放不下的时候报错
+    pub fn fit(&self, precision: u8, scale: u8) -> Result<Self> {
+        let decimal = self.rescale(scale)?;
+        if decimal.digits() > precision {
+            return Err(Error::Row(format!(
+                "value {} is out of range for DECIMAL({},{})",
+                self, precision, scale
+            )));
+        }
+        Ok(decimal)
+    }
+
+    /// 去掉小数部分末尾的0 值相同的小数结果相同
+    pub fn normalize(&self) -> Self {
+        let mut decimal = *self;
+        while decimal.scale > 0 && decimal.value % 10 == 0 {
+            decimal.value /= 10;
+            decimal.scale -= 1;
+        }
+        decimal
+    }
+
+    pub fn to_f64(&self) -> f64 {
+        self.value as f64 / 10f64.powi(self.scale as i32)
+    }
+
+    /// 转换成相同的小数位数
+    fn align(&self, other: &Self) -> Result<(i128, i128, u8)> {
+        let scale = self.scale.max(other.scale);
+        Ok((
+            self.rescale(scale)?.value,
+            other.rescale(scale)?.value,
+            scale,
+        ))
+    }
+
+    pub fn checked_add(&self, other: &Self) -> Result<Self> {
+        let (a, b, scale) = self.align(other)?;
+        Self::new(a.checked_add(b).ok_or_else(overflow)?, scale)
+    }
+
+    pub fn checked_sub(&self, other: &Self) -> Result<Self> {
+        let (a, b, scale) = self.align(other)?;
+        Self::new(a.checked_sub(b).ok_or_else(overflow)?, scale)
+    }
+
+    /// 小数位数是两边之和 超过最大位数的时候舍入
+    pub fn checked_mul(&self, other: &Self) -> Result<Self> {
+        let value = self.value.checked_mul(other.value).ok_or_else(overflow)?;
+        let scale = self.scale as u32 + other.scale as u32;
+        match scale.checked_sub(MAX_PRECISION as u32) {
+            Some(extra) if extra > 0 => {
+                Self::new(div_round(value, 10i128.pow(extra)), MAX_PRECISION)
+            }
+            _ => Self::new(value, scale as u8),
+        }
+    }
+
+    /// 小数位数是两边和 DIVIDE_SCALE 中最大的
+    pub fn checked_div(&self, other: &Self) -> Result<Self> {
+        if other.value == 0 {
+            return Err(Error::Evaluate("Can't divide by zero".into()));
+        }
+        let scale = self.scale.max(other.scale).max(DIVIDE_SCALE);
+        // value = a * 10^(scale + sb - sa) / b
+        let shift = (scale + other.scale - self.scale) as u32;
+        let numerator = 10i128
+            .checked_pow(shift)
+            .and_then(|p| self.value.checked_mul(p))
+            .ok_or_else(overflow)?;
+        Self::new(div_round(numerator, other.value), scale)
+    }
+
+    /// 整除 结果向0取整 没有小数部分
+    pub fn checked_div_trunc(&self, other: &Self) -> Result<Self> {
+        if other.value == 0 {
+            return Err(Error::Evaluate("Can't divide by zero".into()));
+        }
+        let (a, b, _) = self.align(other)?;
+        Self::new(a.checked_div(b).ok_or_else(overflow)?, 0)
+    }
+
+    pub fn checked_rem(&self, other: &Self) -> Result<Self> {
+        if other.value == 0 {
+            return Err(Error::Evaluate("Can't divide by zero".into()));
+        }
+        let (a, b, scale) = self.align(other)?;
+        Self::new(a % b, scale)
+    }
+
+    pub fn neg(&self) -> Self {
+        Self {
+            value: -self.value,
+            scale: self.scale,
+        }
+    }
+
+    pub fn abs(&self) -> Self {
+        Self {
+            value: self.value.abs(),
+            scale: self.scale,
+        }
+    }
+
+    /// 向下取整 结果没有小数部分
+    pub fn floor(&self) -> Self {
+        let p = pow10(self.scale);
+        Self {
+            value: self.value.div_euclid(p),
+            scale: 0,
+        }
+    }
+
+    pub fn ceil(&self) -> Self {
+        let floor = self.floor();
+        match floor.cmp(self) {
+            Ordering::Equal => floor,
+            _ => Self {
+                value: floor.value + 1,
+                scale: 0,
+            },
+        }
+    }
+
+    /// 保留digits位小数 负数表示舍入到整数部分的位数 结果的小数位数不小于0
+    pub fn round(&self, digits: i64) -> Result<Self> {
+        if digits >= 0 {
+            return match digits as u64 >= self.scale as u64 {
+                true => Ok(*self),
+                false => self.rescale(digits as u8),
+            };
+        }
+        // 超过最大的精度之后 结果总是0
+        let p = u32::try_from(digits.unsigned_abs()).ok().and_then(|d| 10i128.checked_pow(d));
+        let p = match p {
+            Some(p) => p,
+            None => return Self::new(0, 0),
+        };
+        let value = div_round(self.rescale(0)?.value, p);
+        Self::new(value.checked_mul(p).ok_or_else(overflow)?, 0)
+    }
+}
+
+impl PartialEq for Decimal {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Decimal {}
+
+impl PartialOrd for Decimal {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Decimal {
+    /// 先比较整数部分 再比较对齐之后的小数部分 不会溢出
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (pa, pb) = (pow10(self.scale), pow10(other.scale));
+        let scale = self.scale.max(other.scale);
+        (self.value / pa).cmp(&(other.value / pb)).then_with(|| {
+            let fa = self.value % pa * pow10(scale - self.scale);
+            let fb = other.value % pb * pow10(scale - other.scale);
+            fa.cmp(&fb)
+        })
+    }
+}
+
+impl Hash for Decimal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        let decimal = self.normalize();
+        decimal.value.hash(state);
+        decimal.scale.hash(state);
+    }
+}
+
+impl Display for Decimal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let digits = format!(
+            "{:0>width$}",
+            self.value.unsigned_abs(),
+            width = self.scale as usize + 1
+        );
+        let (int, frac) = digits.split_at(digits.len() - self.scale as usize);
+        if self.value < 0 {
+            f.write_str("-")?;
+        }
+        match frac.is_empty() {
+            true => write!(f, "{}", int),
+            false => write!(f, "{}.{}", int, frac),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::engine::kv::test_engine;
+    use crate::sql::engine::Engine;
+    use crate::sql::ColumnType;
+    use crate::storage::kv::encoding::{encode_decimal, take_decimal};
+
+    #[test]
+    fn decimal_test() -> Result<()> {
+        let d = |s: &str| Decimal::parse(s).unwrap();
+        assert_eq!(d("-0.05").to_string(), "-0.05");
+        assert_eq!(d("12").to_string(), "12");
+        assert_eq!(Decimal::from_f64(0.1)?.to_string(), "0.1");
+        assert!(Decimal::parse("1.2.3").is_err() && Decimal::parse("-").is_err());
+
+        // 和浮点数不同 0.1 + 0.2 就是 0.3
+        assert_eq!(d("0.1").checked_add(&d("0.2"))?, d("0.3"));
+        assert_eq!(d("1.50"), d("1.5"));
+        assert!(d("-1.5") < d("-1.25") && d("0.3") > d("-0.5"));
+        assert_eq!(d("1.5").checked_mul(&d("-0.25"))?.to_string(), "-0.375");
+        assert_eq!(d("1").checked_div(&d("3"))?.to_string(), "0.333333");
+        assert_eq!(d("2").checked_div(&d("3"))?.to_string(), "0.666667");
+        assert!(d("1").checked_div(&d("0")).is_err());
+        assert_eq!(d("5.5").checked_rem(&d("2"))?.to_string(), "1.5");
+        assert_eq!(d("-7.5").checked_div_trunc(&d("2"))?.to_string(), "-3");
+
+        // 四舍五入远离零
+        assert_eq!(d("2.345").rescale(2)?.to_string(), "2.35");
+        assert_eq!(d("-2.345").rescale(2)?.to_string(), "-2.35");
+        assert_eq!(d("1.5").fit(5, 2)?.to_string(), "1.50");
+        assert!(d("1234.5").fit(5, 2).is_err());
+        assert_eq!(d("-1.5").floor().to_string(), "-2");
+        assert_eq!(d("-1.5").ceil().to_string(), "-1");
+        assert_eq!(d("1250").round(-2)?.to_string(), "1300");
+
+        // 超过38位有效数字
+        let max = d(&"9".repeat(38));
+        assert!(max.checked_add(&d("1")).is_err());
+        assert!(Decimal::parse(&"9".repeat(39)).is_err());
+
+        // 编码按照数值排序 和小数位数无关
+        let values = ["-100", "-1.5", "-1.25", "-0.05", "0", "0.05", "0.5", "1", "1.25", "100"];
+        let encoded = values.iter().map(|v| encode_decimal(&d(v))).collect::<Vec<_>>();
+        assert!(encoded.windows(2).all(|w| w[0] < w[1]));
+        assert_eq!(encode_decimal(&d("1.50")), encode_decimal(&d("1.5")));
+        for (v, bytes) in values.iter().zip(encoded) {
+            assert_eq!(take_decimal(&mut &bytes[..])?.to_string(), *v);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn decimal_column_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute(
+            "create table t ( id decimal(5,2) primary key, price numeric(10,2) index, \
+             n bigint default 0 );",
+        )?;
+        assert!(session.execute("create table u ( id decimal(2,3) primary key );").is_err());
+        // 写入的时候转换成列的位数 放不下的时候报错
+        session.execute("insert into t (id, price) values (1, 0.1), (2.005, 0.2), (3, 10);")?;
+        assert!(session.execute("insert into t (id, price) values (1000, 1);").is_err());
+        session.execute("update t set price = 30 where id = 3;")?;
+        session.execute("update t set n = 9223372036854775807;")?;
+
+        let mut query = |sql: &str| -> Result<Vec<Vec<String>>> {
+            Ok(session.query(sql)?
+                .into_iter()
+                .map(|r| r.iter().map(|v| v.to_string()).collect())
+                .collect())
+        };
+        let rows = |rows: &[&[&str]]| {
+            rows.iter()
+                .map(|r| r.iter().map(|v| v.to_string()).collect::<Vec<_>>())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            query("select id, price from t order by id asc;")?,
+            rows(&[&["1.00", "0.10"], &["2.01", "0.20"], &["3.00", "30.00"]])
+        );
+        // 0.1 + 0.2 没有误差
+        assert_eq!(
+            query("select sum(price), average(price), max(price) from t where price < 1;")?,
+            rows(&[&["0.30", "0.150000", "0.20"]])
+        );
+        // 主键和索引查询中的常量转换成小数
+        assert_eq!(query("select id from t where id = 2.01;")?, rows(&[&["2.01"]]));
+        assert_eq!(query("select id from t where price = 0.1;")?, rows(&[&["1.00"]]));
+        assert_eq!(query("select id from t where price > 0.15;")?.len(), 2);
+        assert_eq!(
+            query("select price / 3, price > 29.999 from t where id = 3;")?,
+            rows(&[&["10.000000", "TRUE"]])
+        );
+
+        // 整数求和溢出之后变成小数
+        assert_eq!(query("select sum(n) from t;")?, rows(&[&["27670116110564327421"]]));
+
+        // 运算结果的精度和小数位数从两边的类型推导
+        let sql = "select id + price, id * price, price / id, price % id, id - 1 from t;";
+        let (columns, _) = session.query_columns(sql)?;
+        assert_eq!(
+            columns.iter().map(|c| c.column_type.clone()).collect::<Vec<_>>(),
+            [(11, 2), (15, 4), (16, 6), (5, 2), (22, 2)]
+                .map(|(p, s)| Some(ColumnType::Decimal(p, s)))
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+//! 执行计划的缓存 相同的语句不需要再解析和规划
+//! key 是语句的token序列加上会话中影响规划的设置 同一个引擎的所有会话共享
+//! 缓存的计划记下了规划时读到的表的定义 使用之前在执行的事务中检查定义没有变化
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::Result;
+use crate::sql::plan::Node;
+use crate::sql::schema::Catalog;
+use crate::sql::Table;
+
+/// 计划缓存默认最多缓存的语句数
+pub const DEFAULT_PLAN_CACHE_SIZE: usize = 1024;
+
+/// 只缓存查询和修改语句的计划
+pub fn cacheable(kind: &str) -> bool {
+    matches!(kind, "select" | "insert" | "update" | "delete")
+}
+
+/// 修改表的定义的语句 执行之后清空缓存
+pub fn ddl(kind: &str) -> bool {
+    matches!(
+        kind,
+        "create_table" | "drop_table" | "create_policy" | "drop_policy" | "analyze" | "restore"
+    )
+}
+
+/// 缓存的统计信息
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct PlanCacheStatus {
+    /// 缓存了多少条语句
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// 一条语句优化之后的计划
+#[derive(Clone, Debug)]
+pub struct CachedPlan {
+    /// 语句的类型 统计语句的执行次数使用
+    pub kind: &'static str,
+    pub node: Node,
+    /// 规划的时候读到的表的定义
+    tables: Vec<Table>,
+}
+
+impl CachedPlan {
+    pub fn new(kind: &'static str, node: Node, catalog: &dyn Catalog) -> Result<Self> {
+        let tables = node
+            .tables()
+            .iter()
+            .map(|table| catalog.must_read_table(table))
+            .collect::<Result<_>>()?;
+        Ok(Self { kind, node, tables })
+    }
+
+    /// 计划用到的表的定义都没有变化的时候还能使用
+    pub fn valid(&self, catalog: &dyn Catalog) -> Result<bool> {
+        for table in self.tables.iter() {
+            if catalog.read_table(&table.name)?.as_ref() != Some(table) {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<String, (CachedPlan, u64)>,
+    /// 使用时间到key 最小的是最久没有使用的
+    order: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+/// 以语句数限制大小的 LRU 缓存
+pub struct PlanCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl PlanCache {
+    /// capacity 是最多缓存的语句数 0的时候不缓存
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// 缓存中的计划 是否能使用由调用的人检查 检查之后用 record 记录是否命中
+    pub fn get(&self, key: &str) -> Result<Option<CachedPlan>> {
+        let mut guard = self.lru.lock()?;
+        let lru = &mut *guard;
+        lru.tick += 1;
+        let tick = lru.tick;
+        let (plan, used) = match lru.entries.get_mut(key) {
+            Some(entry) => entry,
+            None => return Ok(None),
+        };
+        let plan = plan.clone();
+        let old = std::mem::replace(used, tick);
+        lru.order.remove(&old);
+        lru.order.insert(tick, key.to_string());
+        Ok(Some(plan))
+    }
+
+    pub fn insert(&self, key: String, plan: CachedPlan) -> Result<()> {
+        if self.capacity == 0 {
+            return Ok(());
+        }
+        let mut lru = self.lru.lock()?;
+        if let Some((_, used)) = lru.entries.remove(&key) {
+            lru.order.remove(&used);
+        }
+        while lru.entries.len() >= self.capacity {
+            let oldest = match lru.order.pop_first() {
+                Some((_, key)) => key,
+                None => break,
+            };
+            lru.entries.remove(&oldest);
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.order.insert(tick, key.clone());
+        lru.entries.insert(key, (plan, tick));
+        Ok(())
+    }
+
+    /// 记录一次查找 hit为false的是没有找到或者找到的计划不能使用
+    pub fn record(&self, hit: bool) {
+        match hit {
+            true => self.hits.fetch_add(1, Ordering::Relaxed),
+            false => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    /// 执行 DDL 之后清空
+    pub fn clear(&self) -> Result<()> {
+        let mut lru = self.lru.lock()?;
+        lru.entries.clear();
+        lru.order.clear();
+        Ok(())
+    }
+
+    pub fn status(&self) -> Result<PlanCacheStatus> {
+        Ok(PlanCacheStatus {
+            entries: self.lru.lock()?.entries.len() as u64,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plan_cache_test() -> Result<()> {
+        let cache = PlanCache::new(2);
+        let plan = |kind| CachedPlan {
+            kind,
+            node: Node::Nothing,
+            tables: Vec::new(),
+        };
+        cache.insert("a".into(), plan("select"))?;
+        cache.insert("b".into(), plan("insert"))?;
+        // a 最近使用过 放入 c 的时候淘汰的是 b
+        assert_eq!(cache.get("a")?.map(|p| p.kind), Some("select"));
+        cache.insert("c".into(), plan("delete"))?;
+        assert!(cache.get("b")?.is_none());
+        assert!(cache.get("a")?.is_some());
+        assert_eq!(cache.status()?.entries, 2);
+
+        cache.clear()?;
+        assert!(cache.get("c")?.is_none());
+        let disabled = PlanCache::new(0);
+        disabled.insert("a".into(), plan("select"))?;
+        assert!(disabled.get("a")?.is_none());
+        Ok(())
+    }
+}
@@ -1,17 +1,20 @@
 use std::borrow::Cow;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::ops::Bound;
+use std::sync::Arc;
 
-use log::debug;
+use log::{debug, error};
 use serde::{Deserialize, Serialize};
 
 use crate::errors::*;
-use crate::sql::engine::{Row, Transaction};
+use crate::sql::engine::cache::{PlanCache, DEFAULT_PLAN_CACHE_SIZE};
+use crate::sql::engine::{Cancel, Row, Transaction};
 use crate::sql::execution::Rows;
 use crate::sql::expression::Expression;
 use crate::sql::schema::Catalog;
-use crate::sql::{Table, Value};
+use crate::sql::{Column, ColumnType, Pending, ReferenceAction, Table, Value};
 use crate::storage::kv;
-use crate::storage::kv::mvcc::Status;
+use crate::storage::kv::mvcc::VacuumStatus;
 
 /// 一个基于kv的mvcc存储引擎
 
@@ -19,12 +22,76 @@ use crate::storage::kv::mvcc::Status;
 pub struct KV {
     /// The underlying key/value store
     pub(super) kv: kv::MVCC,
+    /// 新事务中排序和聚合可以使用的内存 会话变量可以覆盖
+    work_memory: usize,
+    /// 新事务中连接和聚合可以使用的线程数 会话变量可以覆盖
+    workers: usize,
+    /// 只读的副本 只能通过复制写入
+    read_only: bool,
+    plans: Arc<PlanCache>,
+    /// 会话切换成管理员用的密码
+    admin_password: Option<String>,
+    /// 每个会话最多同时打开的游标数
+    max_cursors: usize,
 }
 
 impl KV {
     /// new一个kv engine
     pub fn new(kv: kv::MVCC) -> Self {
-        Self { kv }
+        Self {
+            kv,
+            work_memory: super::DEFAULT_WORK_MEMORY,
+            workers: super::default_workers(),
+            read_only: false,
+            plans: Arc::new(PlanCache::new(DEFAULT_PLAN_CACHE_SIZE)),
+            admin_password: None,
+            max_cursors: super::DEFAULT_MAX_CURSORS,
+        }
+    }
+
+    /// 设置管理员的密码 会话中 set admin = 密码 之后不受行级安全策略的限制
+    pub fn with_admin_password(mut self, password: Option<String>) -> Self {
+        self.admin_password = password;
+        self
+    }
+
+    /// 设置每个会话最多同时打开的游标数
+    pub fn with_max_cursors(mut self, max_cursors: usize) -> Self {
+        self.max_cursors = max_cursors;
+        self
+    }
+
+    /// 设置计划缓存最多缓存的语句数 0的时候不缓存
+    pub fn with_plan_cache(mut self, capacity: usize) -> Self {
+        self.plans = Arc::new(PlanCache::new(capacity));
+        self
+    }
+
+    /// 作为只读的副本 会话中不能开启读写事务
+    pub fn with_read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// 设置默认的排序和聚合可以使用的内存
+    pub fn with_work_memory(mut self, work_memory: usize) -> Self {
+        self.work_memory = work_memory;
+        self
+    }
+
+    /// 设置默认的连接和聚合可以使用的线程数
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// 布隆过滤器使用的keyspace 每张表的行和每个索引各一个
+    pub fn keyspace(key: &[u8]) -> Option<Vec<u8>> {
+        match SqlKey::decode(key).ok()? {
+            SqlKey::Row(table, _) => Some(SqlKey::Row(table, None).encode()),
+            SqlKey::Index(table, column, _) => Some(SqlKey::Index(table, column, None).encode()),
+            SqlKey::Table(_) | SqlKey::Reference(..) => None,
+        }
     }
 
     /// 获得元数据
@@ -32,13 +99,30 @@ impl KV {
         self.kv.get_metadata(key)
     }
 
+    /// 启动的时候回滚上次没有结束的事务 正常关闭的话什么都不做 返回回滚的事务数
+    pub fn recover(&self) -> Result<u64> {
+        self.kv.recover()
+    }
+
     /// 设置元数据
     pub fn set_metadata(&self, key: &[u8], value: Vec<u8>) -> Result<()> {
         self.kv.set_metadata(key, value)
     }
 
-    pub(crate) fn get_statue(&self) -> Result<Status> {
-        self.kv.get_status()
+    /// 启动的时候把最早的编码的表的定义改写成当前的编码 返回改写的表数
+    pub fn migrate(&self) -> Result<u64> {
+        let mut txn = KvTransaction::new(self.kv.begin_with_mode(super::Mode::ReadWrite)?);
+        let count = txn.migrate_tables()?;
+        match count {
+            0 => txn.rollback()?,
+            _ => txn.commit()?,
+        }
+        Ok(count)
+    }
+
+    /// 复制使用的读写事务 副本也可以开启
+    pub fn begin_replication(&self) -> Result<KvTransaction> {
+        Ok(KvTransaction::new(self.kv.begin_with_mode(super::Mode::ReadWrite)?))
     }
 }
 
@@ -46,22 +130,228 @@ impl super::Engine for KV {
     type Transaction = KvTransaction;
 
     fn begin(&self, mode: super::Mode) -> Result<Self::Transaction> {
-        Ok(Self::Transaction::new(self.kv.begin_with_mode(mode)?))
+        let txn = Self::Transaction::new(self.kv.begin_with_mode(mode)?);
+        Ok(txn.with_limits(self.work_memory, self.workers))
     }
 
     fn resume(&self, id: u64) -> Result<Self::Transaction> {
-        Ok(Self::Transaction::new(self.kv.resume(id)?))
+        let txn = Self::Transaction::new(self.kv.resume(id)?);
+        Ok(txn.with_limits(self.work_memory, self.workers))
+    }
+
+    fn status(&self) -> Result<super::Status> {
+        let mvcc = self.kv.get_status()?;
+        let txn = self.begin(super::Mode::ReadOnly)?;
+        let tables = super::table_rows(&txn)?;
+        txn.commit()?;
+        let plans = self.plans.status()?;
+        Ok(super::Status {
+            mvcc,
+            tables,
+            plans,
+        })
+    }
+
+    fn metrics(&self) -> Result<super::Metrics> {
+        Ok(super::Metrics {
+            mvcc: self.kv.get_metrics()?,
+            plans: self.plans.status()?,
+        })
+    }
+
+    fn plan_cache(&self) -> &PlanCache {
+        &self.plans
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.kv.ping()
+    }
+
+    fn vacuum(&self) -> Result<VacuumStatus> {
+        self.kv.vacuum()
+    }
+
+    fn shutdown(&self) -> Result<bool> {
+        self.kv.shutdown()
+    }
+
+    fn commits(&self, after: u64, limit: usize) -> Result<Vec<super::Commit>> {
+        let mut commits = Vec::new();
+        for (seq, log) in self.kv.commit_log(after, limit)? {
+            let mut commit: super::Commit = deserialize(&log)?;
+            commit.seq = seq;
+            commit.changes.iter_mut().for_each(|change| change.seq = seq);
+            commits.push(commit);
+        }
+        Ok(commits)
+    }
+
+    fn last_commit(&self) -> Result<u64> {
+        self.kv.last_commit_seq()
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn admin_password(&self) -> Option<&str> {
+        self.admin_password.as_deref()
+    }
+
+    fn max_cursors(&self) -> usize {
+        self.max_cursors
     }
 }
 
 /// An SQL transaction based on an MVCC key/value transaction
 pub struct KvTransaction {
     txn: kv::mvcc::MvccTransaction,
+    /// 当前语句的取消标记
+    cancel: Cancel,
+    /// 排序和聚合可以使用的内存
+    work_memory: usize,
+    /// 连接和聚合可以使用的线程数
+    workers: usize,
+    /// 事务最多写入的行数和字节数 None 表示不限制
+    max_rows: Option<u64>,
+    max_bytes: Option<u64>,
+    /// 事务已经写入的行数和字节数 包括索引 回滚到保存点也不会减少
+    rows_written: u64,
+    bytes_written: u64,
 }
 impl KvTransaction {
     fn new(txn: kv::mvcc::MvccTransaction) -> Self {
-        Self { txn }
+        Self {
+            txn,
+            cancel: Cancel::default(),
+            work_memory: super::DEFAULT_WORK_MEMORY,
+            workers: super::default_workers(),
+            max_rows: None,
+            max_bytes: None,
+            rows_written: 0,
+            bytes_written: 0,
+        }
+    }
+
+    fn with_limits(mut self, work_memory: usize, workers: usize) -> Self {
+        self.work_memory = work_memory;
+        self.workers = workers;
+        self
+    }
+
+    /// 在写入之前记录写入量 超过限制的时候报错 这次写入不计入
+    /// 一个事务写入太多会长时间占着存储的锁 其他会话都要等待
+    fn account(&mut self, rows: u64, bytes: usize) -> Result<()> {
+        let rows = self.rows_written + rows;
+        let bytes = self.bytes_written + bytes as u64;
+        if let Some(max) = self.max_rows.filter(|max| rows > *max) {
+            let message = format!("transaction writes {} rows, exceeds max_txn_rows {}", rows, max);
+            return Err(Error::sql(ErrorCode::WriteLimitExceeded, message));
+        }
+        if let Some(max) = self.max_bytes.filter(|max| bytes > *max) {
+            let message =
+                format!("transaction writes {} bytes, exceeds max_txn_bytes {}", bytes, max);
+            return Err(Error::sql(ErrorCode::WriteLimitExceeded, message));
+        }
+        self.rows_written = rows;
+        self.bytes_written = bytes;
+        Ok(())
     }
+
+    /// 当前事务写入的所有key和修改过的行 提交的时候写到提交日志中 序号在读取的时候填上
+    fn commit_log(&self) -> Result<Option<super::Commit>> {
+        let writes = self.txn.writes()?;
+        let ranges = self.txn.range_deletes()?;
+        if writes.is_empty() && ranges.is_empty() {
+            return Ok(None);
+        }
+        // 清空表在这个事务里其他的修改之前
+        let mut changes = Vec::new();
+        for (start, _) in ranges.iter() {
+            if let Ok(SqlKey::Row(table, None)) = SqlKey::decode(start) {
+                changes.push(super::Change {
+                    seq: 0,
+                    txn: self.txn.get_id(),
+                    table: table.into_owned(),
+                    kind: super::ChangeKind::Truncate,
+                    before: None,
+                    after: None,
+                });
+            }
+        }
+        for (key, before, after) in writes.iter() {
+            let table = match SqlKey::decode(key) {
+                Ok(SqlKey::Row(table, Some(_))) => table.into_owned(),
+                _ => continue,
+            };
+            let decode = |value: &Option<Vec<u8>>| -> Result<Option<Row>> {
+                value.as_ref().map(|v| deserialize(verify_row(key, v)?.0)).transpose()
+            };
+            // 坏掉的行也要可以删除 不能因为它提交失败
+            let (before, after) = match (decode(before), decode(after)) {
+                (Ok(before), Ok(after)) => (before, after),
+                (Err(e), _) | (_, Err(e)) => {
+                    error!("skip change of transaction {}: {}", self.txn.get_id(), e);
+                    continue;
+                }
+            };
+            let kind = match (&before, &after) {
+                (None, Some(_)) => super::ChangeKind::Insert,
+                (Some(_), Some(_)) => super::ChangeKind::Update,
+                (Some(_), None) => super::ChangeKind::Delete,
+                // 在事务中插入之后又删除了
+                (None, None) => continue,
+            };
+            changes.push(super::Change {
+                seq: 0,
+                txn: self.txn.get_id(),
+                table,
+                kind,
+                before,
+                after,
+            });
+        }
+        Ok(Some(super::Commit {
+            seq: 0,
+            txn: self.txn.get_id(),
+            changes,
+            ranges,
+            writes: writes.into_iter().map(|(key, _, after)| (key, after)).collect(),
+        }))
+    }
+
+    /// 重放主节点的一个提交 写入的key value和主节点完全一样
+    /// 先做范围删除 范围删除之后的写入不会被删除
+    pub fn apply(&mut self, commit: super::Commit) -> Result<()> {
+        for (start, end) in commit.ranges {
+            self.txn.delete_range(&start, &end)?;
+        }
+        self.txn.write_batch(commit.writes)
+    }
+
+    /// 读取行和它的写入时间 不管有没有过期
+    fn get_row(&self, table: &str, id: &Value) -> Result<Option<(Row, u64)>> {
+        let key = SqlKey::Row(table.into(), Some(id.into())).encode();
+        match self.txn.get(&key)? {
+            Some(value) => {
+                let (row, written) = verify_row(&key, &value)?;
+                Ok(Some((deserialize(row)?, written)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// 主键是否已经存在 过期还没有删除的行直接删除 让出主键
+    fn exists(&mut self, table: &Table, id: &Value) -> Result<bool> {
+        match self.get_row(&table.name, id)? {
+            Some((row, written)) if table.expired(&row, written, now()) => {
+                self.delete(&table.name, id)?;
+                Ok(false)
+            }
+            r => Ok(r.is_some()),
+        }
+    }
+
     /// 保存一个索引
     /// 表名+字段名称+字段值 组成key
     /// hashset为 value
@@ -77,10 +367,135 @@ impl KvTransaction {
         // 设置value
         // 空了就删除，没空就设置
         if values.is_empty() {
+            self.account(0, key.len())?;
             self.txn.delete(&key)
         } else {
-            self.txn.set(&key, serialize(&values)?)
+            let value = serialize(&values)?;
+            self.account(0, key.len() + value.len())?;
+            self.txn.set(&key, value)
+        }
+    }
+
+    /// 写入之前读取表
+    /// 旧版本创建的表 唯一字段和外键字段可能没有索引 第一次写入的时候补上索引
+    fn must_write_table(&mut self, table: &str) -> Result<Table> {
+        let mut table = self.must_read_table(table)?;
+        let missing: Vec<usize> = table
+            .columns
+            .iter()
+            .enumerate()
+            .filter(|(_, c)| (c.unique || c.references.is_some()) && !c.primary_key && !c.index)
+            .map(|(i, _)| i)
+            .collect();
+        if missing.is_empty() {
+            return Ok(table);
+        }
+        let key_index = table.get_key_index()?;
+        let mut entries: HashMap<(usize, Value), HashSet<Value>> = HashMap::new();
+        for row in self.scan(&table.name, None, None)? {
+            for i in missing.iter() {
+                entries
+                    .entry((*i, row[*i].clone()))
+                    .or_default()
+                    .insert(row[key_index].clone());
+            }
+        }
+        for ((i, value), entry) in entries {
+            self.index_save(&table.name, &table.columns[i].name, &value, entry)?;
+        }
+        for i in missing {
+            table.columns[i].index = true;
+        }
+        self.txn.set(
+            &SqlKey::Table(Some(table.name.clone().into())).encode(),
+            encode_table(&table)?,
+        )?;
+        Ok(table)
+    }
+
+    fn migrate_tables(&mut self) -> Result<u64> {
+        let mut count = 0;
+        for item in self.txn.scan_prefix(&SqlKey::Table(None).encode())?.collect::<Vec<_>>() {
+            let (key, bytes) = item?;
+            let (table, legacy) = decode_table(&bytes)?;
+            if legacy {
+                self.txn.set(&key, encode_table(&table)?)?;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// 记录表的外键引用了哪些表 删除行的时候只需要读引用了它的表 不用扫描所有的表
+    fn save_references(&mut self, table: &Table, delete: bool) -> Result<()> {
+        let referenced: HashSet<&str> = table
+            .columns
+            .iter()
+            .filter_map(|c| c.references.as_ref().map(|r| r.table.as_str()))
+            .collect();
+        for r in referenced {
+            let key = SqlKey::Reference(r.into(), Some((&table.name).into())).encode();
+            if delete {
+                self.txn.delete(&key)?;
+            } else {
+                self.txn.set(&key, vec![])?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 外键引用了 table 的所有表 包括它自己
+    fn referencing_tables(&self, table: &str) -> Result<Vec<Table>> {
+        let prefix = SqlKey::Reference(table.into(), None).encode();
+        let mut tables = vec![];
+        for item in self.txn.scan_prefix(&prefix)?.collect::<Vec<_>>() {
+            if let SqlKey::Reference(_, Some(by)) = SqlKey::decode(&item?.0)? {
+                tables.push(self.must_read_table(&by)?);
+            }
+        }
+        Ok(tables)
+    }
+
+    /// 表被其他表的外键引用的时候报错 引用自己不算
+    fn check_unreferenced(&self, table: &str) -> Result<()> {
+        if let Some(t) = self.referencing_tables(table)?.into_iter().find(|t| t.name != table) {
+            return Err(Error::Table(format!(
+                "table {} is referenced by table {}",
+                table, t.name
+            )));
+        }
+        Ok(())
+    }
+
+    /// 找到通过外键引用了 table 中主键为 id 的行
+    /// 返回 (表名, 行主键, 删除策略) 引用自己的行不算
+    fn referenced_by(
+        &self,
+        table: &str,
+        id: &Value,
+    ) -> Result<Vec<(String, Value, ReferenceAction)>> {
+        let mut res = vec![];
+        for t in self.referencing_tables(table)? {
+            for column in t.columns.iter() {
+                let reference = match &column.references {
+                    Some(r) if r.table == table => r,
+                    _ => continue,
+                };
+                // 外键字段建表的时候就有索引 主键字段引用的行就是主键相同的行
+                let keys = if column.primary_key {
+                    self.read(&t.name, id)?.map(|_| id.clone()).into_iter().collect()
+                } else {
+                    self.read_index(&t.name, &column.name, id)?.into_iter().collect::<Vec<_>>()
+                };
+                for key in keys {
+                    if t.name == table && &key == id {
+                        continue;
+                    }
+                    res.push((t.name.clone(), key, reference.on_delete.clone()));
+                }
+            }
         }
+        Ok(res)
     }
 }
 
@@ -93,34 +508,108 @@ impl super::Transaction for KvTransaction {
         self.txn.mode()
     }
 
+    fn set_scan_batch_size(&mut self, size: usize) {
+        self.txn.set_scan_batch_size(size)
+    }
+
+    fn set_work_memory(&mut self, size: usize) {
+        self.work_memory = size;
+    }
+
+    fn work_memory(&self) -> usize {
+        self.work_memory
+    }
+
+    fn set_workers(&mut self, workers: usize) {
+        self.workers = workers;
+    }
+
+    fn workers(&self) -> usize {
+        self.workers
+    }
+
+    fn set_lock_timeout(&mut self, timeout: std::time::Duration) {
+        self.txn.set_lock_timeout(timeout)
+    }
+
+    fn set_write_limits(&mut self, rows: Option<u64>, bytes: Option<u64>) {
+        self.max_rows = rows;
+        self.max_bytes = bytes;
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        self.txn.refresh()
+    }
+
+    fn set_cancel(&mut self, cancel: Cancel) {
+        self.cancel = cancel;
+    }
+
+    fn canceller(&self) -> Cancel {
+        self.cancel.clone()
+    }
+
+    fn check_cancel(&self) -> Result<()> {
+        self.cancel.check()
+    }
+
+    fn reader(&self) -> Result<Option<Self>> {
+        Ok(Some(Self {
+            txn: self.txn.reader()?,
+            cancel: self.cancel.clone(),
+            work_memory: self.work_memory,
+            workers: self.workers,
+            max_rows: self.max_rows,
+            max_bytes: self.max_bytes,
+            rows_written: self.rows_written,
+            bytes_written: self.bytes_written,
+        }))
+    }
+
     fn commit(self) -> Result<()> {
-        self.txn.commit()
+        let log = match self.commit_log()? {
+            Some(commit) => Some(serialize(&commit)?),
+            None => None,
+        };
+        self.txn.commit_with_log(log)?;
+        Ok(())
     }
 
     fn rollback(self) -> Result<()> {
         self.txn.rollback()
     }
 
+    fn savepoint(&mut self, name: &str) -> Result<()> {
+        self.txn.savepoint(name)
+    }
+
+    fn rollback_to_savepoint(&mut self, name: &str) -> Result<()> {
+        self.txn.rollback_to_savepoint(name)
+    }
+
+    fn release_savepoint(&mut self, name: &str) -> Result<()> {
+        self.txn.release_savepoint(name)
+    }
+
     fn create(&mut self, table: &str, row: super::Row) -> Result<()> {
-        let table = self.must_read_table(table)?;
+        let table = self.must_write_table(table)?;
         // 检查数据是否正常 包括检查唯一索引
         table.check_row(&row, self)?;
         // 查找主键
         let id = table.get_row_key(&row)?;
         // 先去查看是否有同样的key or index
-        if self.read(&table.name, &id)?.is_some() {
+        if self.exists(&table, &id)? {
             return Err(Error::Executor(format!(
                 "Primary key {} already exists for table {}",
                 id, table.name
             )));
         };
 
-        self.txn.set(
-            // 插入 table+index为key,放入树中
-            //&SqlKey::Row(Some(table.name.clone().into())).encode(),
-            &SqlKey::Row(Cow::Borrowed(&table.name), Some(Cow::Borrowed(&id))).encode(),
-            serialize(&row)?,
-        )?;
+        // 插入 table+index为key,放入树中
+        let key = SqlKey::Row(Cow::Borrowed(&table.name), Some(Cow::Borrowed(&id))).encode();
+        let value = encode_row(&row, now())?;
+        self.account(1, key.len() + value.len())?;
+        self.txn.set(&key, value)?;
         // 设置索引
         for (index, column) in table.columns.iter().enumerate().filter(|(_, c)| c.index) {
             let mut entry = self.read_index(&table.name, &column.name, &row[index])?;
@@ -130,8 +619,67 @@ impl super::Transaction for KvTransaction {
         Ok(())
     }
 
+    fn create_batch(&mut self, table: &str, rows: Rows) -> Result<()> {
+        let table = self.must_write_table(table)?;
+        let key_index = table.get_key_index()?;
+        // 这一批里面已经检查过的行
+        let mut pending = Pending::default();
+        // 索引的entry 和这一批的修改合并之后一起写入
+        let mut indexes: HashMap<(usize, Value), HashSet<Value>> = HashMap::new();
+        let mut batch = Vec::with_capacity(rows.len());
+
+        for row in rows {
+            // 检查数据 唯一字段和外键也要算上这一批中前面的行
+            table.check_batch_row(&row, &pending, self)?;
+            let id = row[key_index].clone();
+            if pending.keys.contains(&id) || self.exists(&table, &id)? {
+                return Err(Error::Executor(format!(
+                    "Primary key {} already exists for table {}",
+                    id, table.name
+                )));
+            }
+            for (i, column) in table.columns.iter().enumerate().filter(|(_, c)| c.index) {
+                let entry = match indexes.entry((i, row[i].clone())) {
+                    std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                    std::collections::hash_map::Entry::Vacant(e) => {
+                        e.insert(self.read_index(&table.name, &column.name, &row[i])?)
+                    }
+                };
+                entry.insert(id.clone());
+            }
+            batch.push((
+                SqlKey::Row(Cow::Borrowed(&table.name), Some(Cow::Borrowed(&id))).encode(),
+                encode_row(&row, now())?,
+            ));
+            pending.push(&table, &row)?;
+        }
+        for ((i, value), entry) in indexes {
+            let key = SqlKey::Index(
+                table.name.as_str().into(),
+                table.columns[i].name.as_str().into(),
+                Some(value.into()),
+            );
+            batch.push((key.encode(), serialize(&entry)?));
+        }
+        let bytes = batch.iter().map(|(key, value)| key.len() + value.len()).sum();
+        self.account(pending.keys.len() as u64, bytes)?;
+        self.txn.set_batch(batch)
+    }
+
     fn delete(&mut self, table: &str, id: &Value) -> Result<()> {
-        let table = self.must_read_table(table)?;
+        let table = self.must_write_table(table)?;
+
+        // 先检查外键 有RESTRICT的引用就不能删除
+        let referenced = self.referenced_by(&table.name, id)?;
+        if let Some((t, key, _)) = referenced
+            .iter()
+            .find(|(_, _, action)| action == &ReferenceAction::Restrict)
+        {
+            return Err(Error::Row(format!(
+                "row {} of table {} is referenced by row {} of table {}",
+                id, table.name, key, t
+            )));
+        }
 
         let indexes: Vec<_> = table
             .columns
@@ -140,7 +688,8 @@ impl super::Transaction for KvTransaction {
             .filter(|(_, e)| e.index)
             .collect();
         if !indexes.is_empty() {
-            if let Some(row) = self.read(&table.name, id)? {
+            // 过期的行也要删除索引
+            if let Some((row, _)) = self.get_row(&table.name, id)? {
                 for (i, column) in indexes {
                     let mut index = self.read_index(&table.name, &column.name, &row[i])?;
                     index.remove(id);
@@ -148,17 +697,44 @@ impl super::Transaction for KvTransaction {
                 }
             }
         }
-        self.txn
-            .delete(&SqlKey::Row(table.name.into(), Some(id.to_owned().into())).encode())
+        let key = SqlKey::Row(table.name.into(), Some(id.to_owned().into())).encode();
+        self.account(1, key.len())?;
+        self.txn.delete(&key)?;
+
+        // 自己删除之后再级联删除 避免循环引用的时候一直删除下去
+        for (t, key, _) in referenced {
+            if self.read(&t, &key)?.is_some() {
+                self.delete(&t, &key)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 不需要逐行维护索引 行和索引的前缀各写一个范围删除
+    /// 删除的行数还是要数一遍 只读key 不写入
+    fn truncate(&mut self, table: &str) -> Result<u64> {
+        let table = self.must_read_table(table)?;
+        self.check_unreferenced(&table.name)?;
+        let prefix = SqlKey::Row((&table.name).into(), None).encode();
+        // 超过行数限制的时候什么都不删除
+        let count = self.txn.scan_prefix(&prefix)?.try_fold(0, |n, r| r.map(|_| n + 1))?;
+        self.account(count, 0)?;
+        for column in table.columns.iter().filter(|c| c.index) {
+            self.txn.delete_prefix(
+                &SqlKey::Index((&table.name).into(), (&column.name).into(), None).encode(),
+            )?;
+        }
+        self.txn.delete_prefix(&prefix)?;
+        Ok(count)
     }
 
     fn read(&self, table: &str, id: &Value) -> Result<Option<super::Row>> {
-        let r = self
-            .txn
-            .get(&SqlKey::Row(table.into(), Some(id.clone().into())).encode())?;
-        let r: Option<std::result::Result<Row, Error>> = r.map(|val| deserialize(&val));
+        let r = self.get_row(table, id)?;
         debug!("get row{:#?}",r);
-        return r.transpose();
+        match (r, self.read_table(table)?) {
+            (Some((row, written)), Some(table)) if table.expired(&row, written, now()) => Ok(None),
+            (r, _) => Ok(r.map(|(row, _)| row)),
+        }
     }
 
     fn read_index(&self, table: &str, column: &str, value: &Value) -> Result<HashSet<Value>> {
@@ -169,39 +745,89 @@ impl super::Transaction for KvTransaction {
         r.unwrap_or_else(|| Ok(HashSet::new()))
     }
 
-    fn scan(&self, table: &str, filter: Option<Expression>) -> Result<super::Rows> {
-        let r = self
+    fn scan(
+        &self,
+        table: &str,
+        filter: Option<Expression>,
+        columns: Option<Vec<usize>>,
+    ) -> Result<super::Rows> {
+        self.scan_iter(table, filter, columns)?.collect()
+    }
+
+    /// 底层的mvcc扫描分批读取存储 不会一直持有锁
+    fn scan_iter(
+        &self,
+        table: &str,
+        filter: Option<Expression>,
+        columns: Option<Vec<usize>>,
+    ) -> Result<super::SqlScan> {
+        let scan = self
             .txn
             .scan_prefix(&SqlKey::Row(table.into(), None).encode())?;
+        // 有过期时间的表 需要完整的行才能判断是否过期
+        let ttl = self.read_table(table)?.filter(|t| t.ttl.is_some());
+        let now = now();
 
-        let r: Result<Rows> = r
-            .map(|res| {
-                let (_, r) = res?;
-                deserialize(&r)
-            })
-            .collect();
+        // 每读完一批检查一下语句有没有被取消
+        let batch_size = self.txn.scan_batch_size().max(1);
+        let cancel = self.cancel.clone();
+        let mut read = 0;
+        Ok(Box::new(scan.filter_map(move |item| {
+            if read % batch_size == 0 {
+                if let Err(e) = cancel.check() {
+                    return Some(Err(e));
+                }
+            }
+            read += 1;
+            let row = match item.and_then(|(key, value)| {
+                decode_row(&key, &value, ttl.as_ref(), columns.as_deref(), now)
+            }) {
+                Ok(Some(row)) => row,
+                r => return r.transpose(),
+            };
+            // 利用filter进行计算，计算结果是true说明可以展示该数据
+            match filter.as_ref().map(|f| f.evaluate(Some(&row))).transpose() {
+                Ok(None) | Ok(Some(Value::Bool(true))) => Some(Ok(row)),
+                Ok(Some(_)) => None,
+                Err(e) => Some(Err(e)),
+            }
+        })))
+    }
 
-        // 利用filter进行计算，计算结果是true说明可以展示该数据
-        if let Some(filter) = filter {
-            if let Ok(rows) = r {
-                rows.into_iter()
-                    .filter_map(|row| {
-                        let rr = filter.evaluate(Some(&row));
-                        match rr {
-                            Ok(rr) => match rr {
-                                Value::Bool(true) => Some(Ok(row)),
-                                _ => None,
-                            },
-                            Err(err) => Some(Err(err)),
-                        }
-                    })
-                    .collect()
-            } else {
-                r
+    /// 先数出所有的key 再按照数量平均切分 key的编码保持主键的顺序 所以每一部分是一段连续的范围
+    fn scan_partitions(&self, table: &str, n: usize) -> Result<Vec<super::SqlScan>> {
+        let table = self.must_read_table(table)?;
+        let prefix = SqlKey::Row((&table.name).into(), None).encode();
+        let keys = self
+            .txn
+            .scan_prefix(&prefix)?
+            .map(|r| r.map(|(key, _)| key))
+            .collect::<Result<Vec<_>>>()?;
+        let n = n.max(1);
+        let mut bounds = vec![prefix.clone()];
+        for i in (1..n).map(|i| i * keys.len() / n) {
+            if i > 0 && bounds.last() != Some(&keys[i]) {
+                bounds.push(keys[i].clone());
             }
-        } else {
-            r
         }
+        bounds.push(kv::mvcc::prefix_end(&prefix));
+
+        let ttl = Some(table).filter(|t| t.ttl.is_some());
+        let now = now();
+        bounds
+            .windows(2)
+            .map(|range| {
+                let ttl = ttl.clone();
+                let scan = self.txn.scan(range[0].clone()..range[1].clone())?;
+                let scan: super::SqlScan = Box::new(scan.filter_map(move |r| match r {
+                    Ok((key, value)) => {
+                        decode_row(&key, &value, ttl.as_ref(), None, now).transpose()
+                    }
+                    Err(e) => Some(Err(e)),
+                }));
+                Ok(scan)
+            })
+            .collect()
     }
 
     fn scan_index(&self, table: &str, column: &str) -> Result<super::IndexScan> {
@@ -229,16 +855,78 @@ impl super::Transaction for KvTransaction {
         .collect()
     }
 
-    fn update(&mut self, table: &str, id: &Value, row: super::Row) -> Result<()> {
+    fn read_index_range(
+        &self,
+        table: &str,
+        column: &str,
+        range: super::IndexRange,
+    ) -> Result<super::IndexScan> {
         let table = self.must_read_table(table)?;
+        table.get_column_index(column)?;
+
+        let key = |v: Value| {
+            SqlKey::Index(table.name.as_str().into(), column.into(), Some(v.into())).encode()
+        };
+        let start = match range.0 {
+            Bound::Included(v) => Bound::Included(key(v)),
+            Bound::Excluded(v) => Bound::Excluded(key(v)),
+            // null 排在最前面 跳过null
+            Bound::Unbounded => Bound::Excluded(key(Value::Null)),
+        };
+        let end = match range.1 {
+            Bound::Included(v) => Bound::Included(key(v)),
+            Bound::Excluded(v) => Bound::Excluded(key(v)),
+            Bound::Unbounded => {
+                // 前缀以字符串的结束符 0x00 0x00 结尾 最后一位加1就是前缀的结束位置
+                let mut end =
+                    SqlKey::Index(table.name.as_str().into(), column.into(), None).encode();
+                if let Some(last) = end.last_mut() {
+                    *last += 1;
+                }
+                Bound::Excluded(end)
+            }
+        };
+
+        self.txn
+            .scan((start, end))?
+            .map(|r| -> Result<(Value, HashSet<Value>)> {
+                let (key, set) = r?;
+                match SqlKey::decode(&key)? {
+                    SqlKey::Index(_, _, Some(value)) => {
+                        Ok((value.into_owned(), deserialize(&set)?))
+                    }
+                    k => Err(Error::Index(format!("expect index SqlKey get {:?}", k))),
+                }
+            })
+            .collect()
+    }
+
+    fn update(&mut self, table: &str, id: &Value, row: super::Row) -> Result<()> {
+        let table = self.must_write_table(table)?;
 
         // 检查一遍
-        table.check_row(&row, self)?;
+        table.check_update_row(Some(id), &row, self)?;
 
         // 如果是主键被更新了 那就要删除原数据 并创建一条新的数据
-        // 但是这里有关问题 如果是连锁更新 比如 set id=id+1
-        // 这就会导致 id+1 这个位置是有可能有数据的
-        if id != &table.get_row_key(&row)? {
+        // 连锁更新 比如 set id=id+1 的时候 id+1 这个位置可能还有没更新的行
+        let new_id = table.get_row_key(&row)?;
+        if id != &new_id {
+            // 被外键引用的主键不能修改 否则引用的行就悬空了
+            if let Some((t, key, _)) = self.referenced_by(&table.name, id)?.first() {
+                return Err(Error::Row(format!(
+                    "cannot change primary key {} of table {}, it is referenced by row {} of table {}",
+                    id, table.name, key, t
+                )));
+            }
+            // 删除原来的行之前检查 不会出现删除了但是插入失败的情况
+            if self.exists(&table, &new_id)? {
+                let message = format!(
+                    "primary key collision on update: {} already exists in table {}",
+                    new_id, table.name
+                );
+                return Err(Error::sql(ErrorCode::UniqueViolation, message)
+                    .with_identifier(&table.name));
+            }
             self.delete(&table.name, id)?;
             self.create(&table.name, row)?;
             return Ok(());
@@ -255,7 +943,9 @@ impl super::Transaction for KvTransaction {
         if indexes.len() > 0 {
             // 我们这里的update 一般是先执行了查询，也就是说肯定是有这个数据的
             // 拿到老数据
-            let old_row = self.read(&table.name, id)?.unwrap();
+            let (old_row, _) = self.get_row(&table.name, id)?.ok_or_else(|| {
+                Error::Executor(format!("row {} of table {} does not exist", id, table.name))
+            })?;
             for (index, column) in indexes {
                 if old_row[index] != row[index] {
                     let mut old_entry =
@@ -271,38 +961,87 @@ impl super::Transaction for KvTransaction {
         };
 
         // 这个时候执行数据更新
-        self.txn.set(
-            &SqlKey::Row(table.name.into(), Some(id.into())).encode(),
-            serialize(&row)?,
-        )
+        let key = SqlKey::Row(table.name.into(), Some(id.into())).encode();
+        let value = encode_row(&row, now())?;
+        self.account(1, key.len() + value.len())?;
+        self.txn.set(&key, value)
+    }
+
+    fn expired(&self, table: &str) -> Result<Vec<Value>> {
+        let table = self.must_read_table(table)?;
+        if table.ttl.is_none() {
+            return Ok(Vec::new());
+        }
+        let key_index = table.get_key_index()?;
+        let now = now();
+        let mut ids = Vec::new();
+        for item in self
+            .txn
+            .scan_prefix(&SqlKey::Row((&table.name).into(), None).encode())?
+        {
+            let (key, value) = item?;
+            let (row, written) = verify_row(&key, &value)?;
+            let row: Row = deserialize(row)?;
+            if table.expired(&row, written, now) {
+                ids.push(row[key_index].clone());
+            }
+        }
+        Ok(ids)
+    }
+
+    fn dump(&self, start: Option<&[u8]>, limit: usize) -> Result<super::KvItems> {
+        let start = match start {
+            Some(start) => Bound::Excluded(start.to_vec()),
+            None => Bound::Unbounded,
+        };
+        self.txn.scan((start, Bound::Unbounded))?.take(limit).collect()
+    }
+
+    fn load(&mut self, items: super::KvItems) -> Result<()> {
+        self.txn.set_batch(items)
     }
 }
 
 impl super::Catalog for KvTransaction {
-    fn create_table(&mut self, table: Table) -> Result<()> {
+    fn create_table(&mut self, mut table: Table) -> Result<()> {
         // 检查是否存在相同的
         if self.must_read_table(&table.name).is_ok() {
-            return Err(Error::Table(format!("get same table for {}", table.name)));
+            let message = format!("get same table for {}", table.name);
+            return Err(Error::sql(ErrorCode::DuplicateTable, message).with_identifier(&table.name));
+        }
+        // 唯一字段自动建立索引 检查唯一性的时候只需要查一次索引
+        // 外键字段也自动建立索引 删除被引用的行的时候通过索引找到引用它的行
+        for column in table.columns.iter_mut() {
+            if (column.unique || column.references.is_some()) && !column.primary_key {
+                column.index = true;
+            }
         }
         // 检查
         table.validate(self)?;
 
         // 创建
+        self.save_references(&table, false)?;
+        self.txn.set(
+            &SqlKey::Table(Some(table.name.clone().into())).encode(),
+            encode_table(&table)?,
+        )
+    }
+
+    fn update_table(&mut self, table: Table) -> Result<()> {
+        self.must_read_table(&table.name)?;
         self.txn.set(
             &SqlKey::Table(Some(table.name.clone().into())).encode(),
-            serialize(&table)?,
+            encode_table(&table)?,
         )
     }
 
     fn delete_table(&mut self, table: &str) -> Result<()> {
         // 删除表之前 先删除表数据
 
+        // 和truncate一样直接删除行和索引的前缀 被其他表的外键引用时不能删除
         let table = self.must_read_table(table)?;
-        let scan = self.scan(&table.name, None)?;
-        for ele in scan.iter() {
-            self.delete(&table.name, &table.get_row_key(&ele)?)?;
-        }
-
+        self.truncate(&table.name)?;
+        self.save_references(&table, true)?;
         self.txn
             .delete(&SqlKey::Table(Some(table.name.into())).encode())
     }
@@ -310,8 +1049,7 @@ impl super::Catalog for KvTransaction {
     fn read_table(&self, table: &str) -> Result<Option<Table>> {
         let table = self.txn.get(&SqlKey::Table(Some(table.into())).encode())?;
         if let Some(table) = table {
-            let r = Some(deserialize(&table)).transpose();
-            return r;
+            return Ok(Some(decode_table(&table)?.0));
         }
         return Ok(None);
     }
@@ -321,8 +1059,7 @@ impl super::Catalog for KvTransaction {
         let tables: Result<Vec<Table>> = tables
             .map(|r| -> Result<Table> {
                 let (_, table) = r?;
-                let table: Table = deserialize(&table)?;
-                Ok(table)
+                Ok(decode_table(&table)?.0)
             })
             .collect();
         tables
@@ -336,12 +1073,15 @@ enum SqlKey<'a> {
     /// table column key_value
     Index(Cow<'a, str>, Cow<'a, str>, Option<Cow<'a, Value>>),
     Row(Cow<'a, str>, Option<Cow<'a, Value>>),
+    /// 外键的反向索引 被引用的表 引用它的表
+    Reference(Cow<'a, str>, Option<Cow<'a, str>>),
 }
 
 impl<'a> SqlKey<'a> {
     /// 0x01 -> table
     /// 0x02 -> index
     /// 0x03 -> row
+    /// 0x04 -> reference
     fn encode(self) -> Vec<u8> {
         use kv::encoding::*;
         match self {
@@ -361,6 +1101,10 @@ impl<'a> SqlKey<'a> {
             Self::Row(table, Some(pk)) => {
                 [&[0x03][..], &encode_string(&table), &encode_value(&pk)].concat()
             }
+            Self::Reference(table, None) => [&[0x04][..], &encode_string(&table)].concat(),
+            Self::Reference(table, Some(by)) => {
+                [&[0x04][..], &encode_string(&table), &encode_string(&by)].concat()
+            }
         }
     }
 
@@ -374,7 +1118,12 @@ impl<'a> SqlKey<'a> {
                 take_string(bytes)?.into(),
                 Some(take_value(bytes)?.into()),
             ),
-            0x03 => Self::Row(take_string(bytes)?.into(), Some(take_value(bytes)?.into())),
+            // 没有主键的是一个表所有行的前缀
+            0x03 => match take_string(bytes)? {
+                table if bytes.is_empty() => Self::Row(table.into(), None),
+                table => Self::Row(table.into(), Some(take_value(bytes)?.into())),
+            },
+            0x04 => Self::Reference(take_string(bytes)?.into(), Some(take_string(bytes)?.into())),
             b => {
                 return Err(Error::Encoding(format!(
                     "get unknown sql key prefix {:x}",
@@ -395,6 +1144,1253 @@ fn serialize<V: Serialize>(value: &V) -> Result<Vec<u8>> {
     Ok(bincode::serialize(value)?)
 }
 
+/// 表的定义的编码 [魔数 7字节][版本 u8][json编码的表]
+/// json中的字段有名字 旧版本解码的时候忽略不认识的字段 只有不兼容的修改才需要增加版本
+/// 最早的定义直接用bincode编码 开头是u64的表名长度 不会和魔数相同 启动的时候用 migrate 改写
+const TABLE_MAGIC: &[u8; 7] = b"COKETBL";
+const TABLE_VERSION: u8 = 1;
+
+fn encode_table(table: &Table) -> Result<Vec<u8>> {
+    let mut bytes = TABLE_MAGIC.to_vec();
+    bytes.push(TABLE_VERSION);
+    serde_json::to_writer(&mut bytes, table)?;
+    Ok(bytes)
+}
+
+/// 最早用bincode编码的表 bincode的编码没有字段名 这里的字段不能修改
+/// Value 和 ColumnType 只在后面增加过变体 旧的变体编码不变
+#[derive(Deserialize)]
+struct TableV0 {
+    name: String,
+    columns: Vec<ColumnV0>,
+}
+
+#[derive(Deserialize)]
+struct ColumnV0 {
+    name: String,
+    column_type: ColumnType,
+    primary_key: bool,
+    nullable: bool,
+    default: Option<Value>,
+    unique: bool,
+    index: bool,
+}
+
+impl From<TableV0> for Table {
+    fn from(table: TableV0) -> Self {
+        let columns = table
+            .columns
+            .into_iter()
+            .map(|c| Column {
+                name: c.name,
+                column_type: c.column_type,
+                primary_key: c.primary_key,
+                nullable: c.nullable,
+                default: c.default.map(Expression::Constant),
+                unique: c.unique,
+                index: c.index,
+                references: None,
+            })
+            .collect();
+        Table {
+            name: table.name,
+            columns,
+            checks: Vec::new(),
+            ttl: None,
+            policies: Vec::new(),
+            statistics: None,
+        }
+    }
+}
+
+/// 返回表的定义和它是不是最早的编码
+fn decode_table(bytes: &[u8]) -> Result<(Table, bool)> {
+    let bytes = match bytes.strip_prefix(&TABLE_MAGIC[..]) {
+        Some(bytes) => bytes,
+        None => return Ok((deserialize::<TableV0>(bytes)?.into(), true)),
+    };
+    match bytes.split_first() {
+        Some((&TABLE_VERSION, json)) => {
+            let mut deserializer = serde_json::Deserializer::from_slice(json);
+            // 表达式嵌套很深的时候也能解码
+            deserializer.disable_recursion_limit();
+            let table = Table::deserialize(&mut deserializer)?;
+            deserializer.end()?;
+            Ok((table, false))
+        }
+        Some((version, _)) => Err(Error::Encoding(format!(
+            "table definition version {} is newer than {}",
+            version, TABLE_VERSION
+        ))),
+        None => Err(Error::Encoding("table definition without version".into())),
+    }
+}
+
+/// 当前时间 单位秒
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// 行的编码 [bincode编码的行][写入时间 u64][CRC32 u32]
+fn encode_row(row: &Row, written: u64) -> Result<Vec<u8>> {
+    let mut bytes = serialize(row)?;
+    bytes.extend_from_slice(&written.to_be_bytes());
+    let checksum = crc32(&bytes);
+    bytes.extend_from_slice(&checksum.to_be_bytes());
+    Ok(bytes)
+}
+
+/// 检查行的校验和 返回编码的行和写入时间 key用来在错误中指出是哪一行
+fn verify_row<'a>(key: &[u8], bytes: &'a [u8]) -> Result<(&'a [u8], u64)> {
+    if bytes.len() >= 12 {
+        let (data, checksum) = bytes.split_at(bytes.len() - 4);
+        if crc32(data).to_be_bytes() == checksum {
+            let (row, written) = data.split_at(data.len() - 8);
+            return Ok((row, u64::from_be_bytes(written.try_into()?)));
+        }
+    }
+    Err(Error::Corruption(match SqlKey::decode(key) {
+        Ok(SqlKey::Row(table, Some(id))) => {
+            format!("checksum mismatch for row {} of table {}", id, table)
+        }
+        _ => format!("checksum mismatch for key {:?}", key),
+    }))
+}
+
+/// 解码扫描到的行 只解码columns中的列 table有过期时间的时候过期的行返回None
+fn decode_row(
+    key: &[u8],
+    value: &[u8],
+    table: Option<&Table>,
+    columns: Option<&[usize]>,
+    now: u64,
+) -> Result<Option<Row>> {
+    let (value, written) = verify_row(key, value)?;
+    let table = match table {
+        Some(table) => table,
+        None => {
+            return match columns {
+                Some(columns) => deserialize_columns(value, columns).map(Some),
+                None => deserialize(value).map(Some),
+            }
+        }
+    };
+    // 判断是否过期需要完整的行
+    let row: Row = deserialize(value)?;
+    if table.expired(&row, written, now) {
+        return Ok(None);
+    }
+    Ok(Some(match columns {
+        Some(columns) => columns.iter().map(|i| row[*i].clone()).collect(),
+        None => row,
+    }))
+}
+
+/// CRC32 (IEEE 802.3) 的查表
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                0xedb88320 ^ (crc >> 1)
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, b| {
+        CRC32_TABLE[((crc ^ *b as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
 fn deserialize<'a, V: Deserialize<'a>>(bytes: &'a [u8]) -> Result<V> {
     Ok(bincode::deserialize(bytes)?)
 }
+
+/// 只解码行中的一部分列 其他列跳过 结果中是null
+/// 和 bincode::deserialize 使用一样的编码配置
+fn deserialize_columns(bytes: &[u8], columns: &[usize]) -> Result<Row> {
+    use bincode::Options;
+    Ok(bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .deserialize_seed(RowColumns(columns), bytes)?)
+}
+
+/// 和Value的编码一样 用来跳过不需要的列 字符串直接借用不会分配内存
+/// 变体的顺序必须和Value一致
+#[derive(Deserialize)]
+#[allow(dead_code)]
+enum SkipValue<'a> {
+    Null,
+    Integer(i64),
+    Float(f64),
+    String(&'a str),
+    Bool(bool),
+    Decimal(crate::sql::decimal::Decimal),
+    Bytes(&'a [u8]),
+}
+
+struct RowColumns<'a>(&'a [usize]);
+
+impl<'de> serde::de::DeserializeSeed<'de> for RowColumns<'_> {
+    type Value = Row;
+
+    fn deserialize<D: serde::Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> std::result::Result<Row, D::Error> {
+        deserializer.deserialize_seq(self)
+    }
+}
+
+impl<'de> serde::de::Visitor<'de> for RowColumns<'_> {
+    type Value = Row;
+
+    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "a row")
+    }
+
+    fn visit_seq<A: serde::de::SeqAccess<'de>>(
+        self,
+        mut seq: A,
+    ) -> std::result::Result<Row, A::Error> {
+        let mut row = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        loop {
+            let value = if self.0.contains(&row.len()) {
+                seq.next_element::<Value>()?
+            } else {
+                seq.next_element::<SkipValue>()?.map(|_| Value::Null)
+            };
+            match value {
+                Some(value) => row.push(value),
+                None => return Ok(row),
+            }
+        }
+    }
+}
+
+/// 测试用的内存引擎
+#[cfg(test)]
+pub(crate) fn test_engine() -> KV {
+    KV::new(kv::MVCC::new(Box::new(kv::b_tree::BtreeStore::new())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::engine::{Engine, SqlSession};
+    use crate::sql::execution::ResultSet;
+    use crate::storage::kv::b_tree::BtreeStore;
+    use crate::storage::kv::mvcc::Mode;
+
+    #[test]
+    fn execute_all_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        let results = session.execute_all(
+            "create table t ( id int primary key ); begin transaction; insert into t values (1), (2); select id from t; commit;",
+        )?;
+        assert_eq!(results.len(), 5);
+        assert_eq!(results[2], ResultSet::Create { count: 2 });
+        assert!(matches!(&results[3], ResultSet::Query { rows, .. } if rows.len() == 2));
+        assert!(matches!(results[4], ResultSet::Commit { .. }));
+
+        // 有语法错误的时候一条都不执行
+        assert!(session.execute_all("insert into t values (3); select;").is_err());
+        // 执行出错的时候停止 之前的语句已经执行
+        assert!(session
+            .execute_all(
+                "insert into t values (4); insert into t values (1); insert into t values (5);"
+            )
+            .is_err());
+        assert_eq!(
+            session.query("select id from t;")?,
+            [1, 2, 4].iter().map(|i| vec![Value::Integer(*i)]).collect::<Vec<_>>()
+        );
+
+        // 出错的时候返回之前执行完的语句的结果 开启的事务仍然有效
+        let partial = session
+            .execute_all("begin transaction; insert into t values (1); select id from t;")
+            .unwrap_err();
+        assert!(matches!(partial.results[..], [ResultSet::Begin { .. }]));
+        assert!(matches!(partial.error, Error::Executor(_)));
+        assert!(session.txn().is_some());
+        session.execute("rollback;")?;
+        Ok(())
+    }
+
+    #[test]
+    fn truncate_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int index, s string unique );")?;
+        session.execute("create table u ( id int primary key, t int references t );")?;
+        session.execute("create table tt ( id int primary key );")?;
+        session.execute("insert into t values (1, 10, \"a\"), (2, 10, \"b\"), (3, 30, \"c\");")?;
+        session.execute("insert into tt values (1);")?;
+        let count = |session: &mut SqlSession<KV>, sql: &str| -> Result<usize> {
+            Ok(session.query(sql)?.len())
+        };
+
+        // 被其他表引用的时候不能清空
+        assert!(session.execute("truncate table t;").is_err());
+        session.execute("drop table u;")?;
+
+        // 回滚之后数据还在
+        session.execute("begin transaction;")?;
+        assert_eq!(session.execute("truncate t;")?, ResultSet::Delete { count: 3 });
+        assert_eq!(count(&mut session, "select id from t;")?, 0);
+        session.execute("rollback;")?;
+        assert_eq!(count(&mut session, "select id from t where n = 10;")?, 2);
+
+        assert_eq!(session.execute("truncate table t;")?, ResultSet::Delete { count: 3 });
+        assert_eq!(count(&mut session, "select id from t;")?, 0);
+        assert_eq!(count(&mut session, "select id from t where n = 10;")?, 0);
+        // 索引也一起清空了 唯一的值可以再次插入
+        session.execute("insert into t values (1, 10, \"a\");")?;
+        assert_eq!(count(&mut session, "select id from t where n = 10;")?, 1);
+        // 名字是前缀的表不受影响
+        assert_eq!(count(&mut session, "select id from tt;")?, 1);
+        assert!(session.execute("truncate table v;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn savepoint_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int index );")?;
+        session.execute("insert into t values (1, 1);")?;
+        let rows = |session: &mut SqlSession<KV>| -> Result<Vec<(i64, i64)>> {
+            Ok(session
+                .query("select id, n from t order by id;")?
+                .into_iter()
+                .map(|r| match (&r[0], &r[1]) {
+                    (Value::Integer(id), Value::Integer(n)) => (*id, *n),
+                    r => panic!("unexpected row {:?}", r),
+                })
+                .collect())
+        };
+        assert!(session.execute("savepoint a;").is_err());
+
+        session.execute("begin transaction;")?;
+        session.execute("savepoint a;")?;
+        session.execute("insert into t values (2, 2);")?;
+        session.execute("savepoint b;")?;
+        // 同一个key在不同的层修改 回滚到b的时候恢复成b的时候的值
+        session.execute("update t set n = 20 where id = 2;")?;
+        session.execute("update t set n = 10 where id = 1;")?;
+        session.execute("insert into t values (3, 3);")?;
+        session.execute("rollback to savepoint b;")?;
+        assert_eq!(rows(&mut session)?, vec![(1, 1), (2, 2)]);
+        // 保存点回滚之后还在 索引也恢复了
+        session.execute("delete from t where id = 2;")?;
+        session.execute("rollback to b;")?;
+        assert_eq!(session.query("select id from t where n = 2;")?, vec![vec![Value::Integer(2)]]);
+
+        // 释放之后的修改属于上一个保存点
+        session.execute("update t set n = 100 where id = 1;")?;
+        session.execute("release savepoint b;")?;
+        // 保存点不存在不是写冲突 重试也不会成功
+        let error = session.execute("rollback to b;").unwrap_err();
+        assert_eq!((error.code(), error.retryable()), ("3B001", false));
+        session.execute("savepoint c;")?;
+        session.execute("insert into t values (4, 4);")?;
+        session.execute("rollback to a;")?;
+        assert_eq!(rows(&mut session)?, vec![(1, 1)]);
+        assert!(session.execute("release c;").is_err());
+
+        session.execute("insert into t values (5, 5);")?;
+        session.execute("commit;")?;
+        assert_eq!(rows(&mut session)?, vec![(1, 1), (5, 5)]);
+        // 事务结束之后保存点也没有了
+        session.execute("begin transaction;")?;
+        assert!(session.execute("rollback to a;").is_err());
+        session.execute("rollback;")?;
+
+        // 只读的事务不能写入
+        let mut txn = engine.begin(Mode::ReadOnly)?;
+        let error = txn.create("t", vec![Value::Integer(6), Value::Integer(6)]).unwrap_err();
+        assert_eq!((error.code(), error.retryable()), ("25006", false));
+        txn.rollback()?;
+        Ok(())
+    }
+
+    #[test]
+    fn references_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table customers ( id int primary key, name string );")?;
+        session.execute(
+            "create table orders ( id int primary key, customer int references customers );",
+        )?;
+        session.execute(
+            "create table items ( id int primary key, ord int index references orders(id) on delete cascade );",
+        )?;
+        session.execute("insert into customers values (1, \"a\"), (2, \"b\");")?;
+        session.execute("insert into orders values (1, 1), (2, 1);")?;
+        session.execute("insert into items values (1, 1), (2, 1), (3, 2);")?;
+        // 外键字段自动建立索引
+        let txn = engine.begin(Mode::ReadOnly)?;
+        assert!(txn.must_read_table("orders")?.columns[1].index);
+        assert_eq!(
+            txn.read_index("orders", "customer", &Value::Integer(1))?,
+            [Value::Integer(1), Value::Integer(2)].into_iter().collect()
+        );
+        txn.commit()?;
+
+        // 引用的行不存在
+        assert!(session.execute("insert into orders values (3, 3);").is_err());
+        // RESTRICT 被引用的行不能删除
+        assert!(session.execute("delete from customers where id = 1;").is_err());
+        assert!(session.execute("drop table customers;").is_err());
+        // 主键也是外键的时候 引用它的就是主键相同的行
+        session.execute(
+            "create table profiles ( id int primary key references customers, bio string );",
+        )?;
+        session.execute("insert into profiles values (2, \"b\");")?;
+        assert!(session.execute("delete from customers where id = 2;").is_err());
+        session.execute("drop table profiles;")?;
+        session.execute("delete from customers where id = 2;")?;
+
+        // CASCADE 一起删除
+        session.execute("delete from orders where id = 1;")?;
+        assert_eq!(session.query("select id from items;")?, vec![vec![Value::Integer(3)]]);
+
+        // 引用它的表删除之后 同名的新表没有外键 被引用的表可以删除
+        session.execute("drop table items;")?;
+        session.execute("drop table orders;")?;
+        session.execute("create table orders ( id int primary key, customer int );")?;
+        session.execute("delete from customers where id = 1;")?;
+        session.execute("drop table customers;")?;
+        Ok(())
+    }
+
+    #[test]
+    fn as_of_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int );")?;
+        session.execute("insert into t values (1, 10);")?;
+        let version = session.begin_id("begin transaction read only;")?;
+        session.execute("commit;")?;
+        session.execute("insert into t values (2, 20);")?;
+        session.execute("update t set n = 11 where id = 1;")?;
+
+        assert_eq!(
+            session.execute(&format!("begin transaction read only as {};", version))?,
+            ResultSet::Begin {
+                id: version + 3,
+                mode: Mode::Snapshot { version }
+            }
+        );
+        assert_eq!(
+            session.query("select id, n from t;")?,
+            vec![vec![Value::Integer(1), Value::Integer(10)]]
+        );
+        // 历史版本只能读
+        assert!(session.execute("insert into t values (3, 30);").is_err());
+        session.execute("commit;")?;
+
+        // 版本不存在 或者不是只读事务
+        assert!(session.execute("begin transaction read only as 100;").is_err());
+        assert!(session
+            .execute(&format!("begin transaction as {};", version))
+            .is_err());
+        assert!(session.execute("select id from t;").is_ok());
+
+        // 垃圾回收之后 水位线之下的版本不能再读取
+        engine.vacuum()?;
+        let sql = format!("begin transaction read only as {};", version);
+        assert!(matches!(
+            session.execute(&sql).unwrap_err(),
+            Error::Sql(e) if e.code == ErrorCode::SnapshotTooOld
+        ));
+        assert!(matches!(
+            session.execute("begin transaction read only as 100;").unwrap_err(),
+            Error::Mvcc(message) if message == "version 100 does not exist"
+        ));
+        assert!(session.txn().is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn create_batch_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute(
+            "create table t ( id int primary key, u int unique, ui int unique index, g int index, p int references t );",
+        )?;
+        session.execute("insert into t values (1, 1, 1, 1, 1), (2, 2, 2, 1, 1), (3, 3, 3, 2, 2);")?;
+
+        // 这一批里面重复 或者和已有的数据重复 整批都不会写入
+        for sql in [
+            "insert into t values (4, 4, 4, 1, 1), (4, 5, 5, 1, 1);",
+            "insert into t values (4, 4, 4, 1, 1), (5, 4, 5, 1, 1);",
+            "insert into t values (4, 4, 4, 1, 1), (5, 5, 4, 1, 1);",
+            "insert into t values (4, 4, 4, 1, 1), (5, 1, 5, 1, 1);",
+            "insert into t values (4, 4, 4, 1, 1), (5, 5, 2, 1, 1);",
+            "insert into t values (4, 4, 4, 1, 6);",
+        ] {
+            assert!(session.execute(sql).is_err(), "{}", sql);
+        }
+        // 引用同一批中前面的行
+        session.execute("insert into t values (4, 4, 4, 2, 4), (5, 5, 5, 2, 4);")?;
+
+        let txn = engine.begin(Mode::ReadOnly)?;
+        assert_eq!(
+            txn.read_index("t", "g", &Value::Integer(2))?,
+            [3, 4, 5].into_iter().map(Value::Integer).collect()
+        );
+        assert_eq!(
+            txn.read_index("t", "ui", &Value::Integer(4))?,
+            [Value::Integer(4)].into_iter().collect()
+        );
+        assert_eq!(txn.scan("t", None, None)?.len(), 5);
+        Ok(())
+    }
+
+    #[test]
+    fn table_encoding_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, f float default 1.5 );")?;
+        session.execute("insert into t values (1, 2.5);")?;
+        session.execute("analyze t;")?;
+        let mut txn = engine.begin(Mode::ReadWrite)?;
+        let mut table = txn.must_read_table("t")?;
+        assert!(table.statistics.is_some());
+        // json不能表示的小数也能编码
+        table.columns[1].default = Some(Expression::Constant(Value::Float(f64::INFINITY)));
+        assert_eq!(decode_table(&encode_table(&table)?)?, (table.clone(), false));
+
+        // 旧版本用bincode写入的定义 id int primary key, f float default 1.5 启动的时候改写
+        let legacy = [
+            1, 0, 0, 0, 0, 0, 0, 0, b't', 2, 0, 0, 0, 0, 0, 0, 0, // 表名 列数
+            2, 0, 0, 0, 0, 0, 0, 0, b'i', b'd', 0, 0, 0, 0, 1, 0, 0, 0, 0, // id
+            1, 0, 0, 0, 0, 0, 0, 0, b'f', 1, 0, 0, 0, 0, 1, // f
+            1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xf8, 0x3f, 0, 0, // Some(Float(1.5))
+        ];
+        let key = SqlKey::Table(Some("t".into())).encode();
+        txn.txn.set(&key, legacy.to_vec())?;
+        let old = txn.must_read_table("t")?;
+        assert_eq!((old.columns[0].primary_key, old.columns[1].nullable), (true, true));
+        let default = Some(Expression::Constant(Value::Float(1.5)));
+        assert_eq!(old.columns[1].default, default);
+        assert!(old.checks.is_empty() && old.statistics.is_none());
+        txn.commit()?;
+        assert_eq!(engine.migrate()?, 1);
+        assert_eq!(engine.migrate()?, 0);
+        let txn = engine.begin(Mode::ReadOnly)?;
+        assert_eq!(decode_table(&txn.txn.get(&key)?.unwrap())?, (old, false));
+        txn.commit()?;
+
+        // 缺少的字段使用默认值 不认识的字段忽略
+        let json = r#"{"name":"u","columns":[{"name":"id","column_type":"Integer",
+            "primary_key":true,"nullable":false,"comment":"new"}],"owner":"admin"}"#;
+        let bytes = [&TABLE_MAGIC[..], &[TABLE_VERSION], json.as_bytes()].concat();
+        let (u, legacy) = decode_table(&bytes)?;
+        assert!(!legacy);
+        assert_eq!((u.name.as_str(), u.columns.len(), u.checks.len()), ("u", 1, 0));
+        assert!(!u.columns[0].index && u.statistics.is_none());
+        // 更新的版本不能解码
+        let bytes = [&TABLE_MAGIC[..], &[TABLE_VERSION + 1], json.as_bytes()].concat();
+        assert!(decode_table(&bytes).is_err());
+        assert_eq!(session.query("select f from t;")?, vec![vec![Value::Float(2.5)]]);
+        Ok(())
+    }
+
+    #[test]
+    fn unique_index_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, u int unique );")?;
+        session.execute("insert into t values (1, 1), (2, 2);")?;
+
+        // 模拟旧版本创建的表 唯一字段没有索引
+        let mut txn = engine.begin(Mode::ReadWrite)?;
+        let mut table = txn.must_read_table("t")?;
+        assert!(table.columns[1].index);
+        table.columns[1].index = false;
+        txn.txn.set(&SqlKey::Table(Some("t".into())).encode(), encode_table(&table)?)?;
+        for v in [1, 2] {
+            txn.index_save("t", "u", &Value::Integer(v), HashSet::new())?;
+        }
+        txn.commit()?;
+
+        // 第一次写入的时候补上索引
+        assert!(session.execute("insert into t values (3, 1);").is_err());
+        session.execute("insert into t values (3, 3);")?;
+        let txn = engine.begin(Mode::ReadOnly)?;
+        assert!(txn.must_read_table("t")?.columns[1].index);
+        assert_eq!(
+            txn.read_index("t", "u", &Value::Integer(2))?,
+            [Value::Integer(2)].into_iter().collect()
+        );
+        txn.commit()?;
+
+        // 更新成别人的值报错 保持自己的值没有问题
+        assert!(session.execute("update t set u = 2 where id = 1;").is_err());
+        session.execute("update t set u = 1 where id = 1;")?;
+        Ok(())
+    }
+
+    #[test]
+    fn cancel_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        let values = (0..100).map(|i| format!("({})", i)).collect::<Vec<_>>();
+        for t in ["a", "b", "c"] {
+            session.execute(&format!("create table {} ( id int primary key );", t))?;
+            session.execute(&format!("insert into {} values {};", t, values.join(",")))?;
+        }
+        let join = "select a.id from a cross join b cross join c;";
+
+        session.execute("set statement_timeout = 1;")?;
+        assert!(matches!(
+            session.execute(join).unwrap_err(),
+            Error::Cancelled(e) if e == "statement timeout"
+        ));
+        session.execute("set statement_timeout = 0;")?;
+
+        // 在其他线程取消
+        let cancel = session.canceller();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            cancel.cancel();
+        });
+        assert!(matches!(
+            session.execute(join).unwrap_err(),
+            Error::Cancelled(e) if e == "statement cancelled"
+        ));
+        handle.join().unwrap();
+
+        // 之前的取消不影响之后的语句
+        assert_eq!(session.query("select id from a where id = 1;")?, vec![vec![Value::Integer(1)]]);
+        Ok(())
+    }
+
+    #[test]
+    fn query_log_test() -> Result<()> {
+        use crate::sql::engine::QUERY_LOG;
+        use std::sync::Mutex;
+        // logger是全局的 只在这个测试中设置 其他测试的查询日志用表名过滤掉
+        static LOGS: Mutex<Vec<(log::Level, String)>> = Mutex::new(Vec::new());
+        struct Capture;
+        impl log::Log for Capture {
+            fn enabled(&self, metadata: &log::Metadata) -> bool {
+                metadata.target() == QUERY_LOG
+            }
+            fn log(&self, record: &log::Record) {
+                if self.enabled(record.metadata()) {
+                    let mut logs = LOGS.lock().unwrap_or_else(|e| e.into_inner());
+                    logs.push((record.level(), record.args().to_string()));
+                }
+            }
+            fn flush(&self) {}
+        }
+        log::set_logger(&Capture).map_err(|e| Error::Internal(e.to_string()))?;
+        log::set_max_level(log::LevelFilter::Debug);
+        let last = |table: &str| -> (log::Level, String) {
+            let logs = LOGS.lock().unwrap_or_else(|e| e.into_inner());
+            logs.iter().rev().find(|(_, l)| l.contains(table)).cloned().unwrap()
+        };
+
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        let values = (0..50).map(|i| format!("({})", i)).collect::<Vec<_>>();
+        for t in ["qlog_a", "qlog_b", "qlog_c"] {
+            session.execute(&format!("create table {} ( id int primary key );", t))?;
+            session.execute(&format!("insert into {} values {};", t, values.join(",")))?;
+        }
+        let (level, log) = last("insert into qlog_c");
+        assert_eq!(level, log::Level::Debug);
+        assert!(log.contains(" rows=50 txn="), "{}", log);
+
+        session.execute("select id from qlog_a where id > 47;")?;
+        let (level, log) = last("from qlog_a where");
+        assert_eq!(level, log::Level::Debug);
+        assert!(log.contains(" rows=2 ") && !log.contains("plan:"), "{}", log);
+        assert!(session.execute("select x from qlog_a;").is_err());
+        assert!(last("select x from qlog_a").1.contains(" error="));
+
+        // 显式事务中的语句记录事务的id
+        let id = session.begin_id("begin transaction;")?;
+        session.execute("delete from qlog_a where id = 0;")?;
+        session.execute("commit;")?;
+        let log = last("delete from qlog_a").1;
+        assert!(log.contains(&format!(" rows=1 txn={}", id)), "{}", log);
+
+        // 超过阈值的语句连同计划输出
+        session.execute("set slow_query_threshold = 1;")?;
+        session.execute("select qlog_a.id from qlog_a cross join qlog_b cross join qlog_c;")?;
+        let (level, log) = last("cross join qlog_c");
+        assert_eq!(level, log::Level::Warn);
+        assert!(log.contains(" rows=122500 ") && log.contains("plan:\n"), "{}", log);
+        assert!(log.contains("NestedLoopJoin"), "{}", log);
+        session.execute("set slow_query_threshold = 0;")?;
+        assert!(session.execute("set slow_query_threshold = -1;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn checksum_test() -> Result<()> {
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int index );")?;
+        session.execute("insert into t values (1, 10), (2, 20), (3, 20);")?;
+        assert_eq!(
+            session.execute("check table t;")?,
+            ResultSet::CheckTable {
+                name: "t".to_string(),
+                rows: 3
+            }
+        );
+
+        // 索引里面少了一个主键
+        let mut txn = engine.begin(Mode::ReadWrite)?;
+        txn.index_save("t", "n", &Value::Integer(20), [Value::Integer(2)].into())?;
+        txn.commit()?;
+        assert!(matches!(
+            session.execute("check table t;"),
+            Err(Error::Corruption(_))
+        ));
+        let mut txn = engine.begin(Mode::ReadWrite)?;
+        let entry = [Value::Integer(2), Value::Integer(3)].into();
+        txn.index_save("t", "n", &Value::Integer(20), entry)?;
+        txn.commit()?;
+
+        // 直接写入坏掉的行 读取和检查都会报错
+        let mut txn = engine.begin(Mode::ReadWrite)?;
+        let key = SqlKey::Row("t".into(), Some(Value::Integer(2).into())).encode();
+        let mut value = txn.txn.get(&key)?.unwrap();
+        value[0] ^= 0xff;
+        txn.txn.set(&key, value)?;
+        txn.commit()?;
+        for sql in ["select * from t;", "select * from t where id = 2;", "check table t;"] {
+            let error = session.execute(sql).unwrap_err();
+            assert_eq!(error.to_string(), "checksum mismatch for row 2 of table t", "{}", sql);
+            assert!(matches!(error, Error::Corruption(_)), "{}", sql);
+        }
+        // 其他的行还可以读取
+        assert!(session.execute("select * from t where id = 1;").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn status_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key );")?;
+        session.execute("create table u ( id int primary key );")?;
+        session.execute("insert into t values (1), (2), (3);")?;
+
+        let status = engine.status()?;
+        assert_eq!(
+            status.tables,
+            [("t".to_string(), 3), ("u".to_string(), 0)].into()
+        );
+        assert!(status.mvcc.keys > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn limits_test() -> Result<()> {
+        let engine = test_engine().with_work_memory(100).with_workers(3);
+        let txn = engine.begin(Mode::ReadOnly)?;
+        assert_eq!((txn.work_memory(), txn.workers()), (100, 3));
+        txn.commit()?;
+        // 会话变量覆盖引擎的默认值
+        let mut session = engine.session()?;
+        session.execute("set work_memory = 200;")?;
+        let limits = session.with_txn(Mode::ReadOnly, |txn| Ok((txn.work_memory(), txn.workers())));
+        assert_eq!(limits?, (200, 3));
+        Ok(())
+    }
+
+    #[test]
+    fn write_limits_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int unique );")?;
+        session.execute("insert into t values (1, 1), (2, 2), (3, 3);")?;
+
+        // 索引的写入也算在字节数里面 但是不算行数
+        let mut txn = engine.begin(Mode::ReadWrite)?;
+        txn.create("t", vec![Value::Integer(4), Value::Integer(4)])?;
+        let (rows, bytes) = (txn.rows_written, txn.bytes_written);
+        assert_eq!(rows, 1);
+        txn.rollback()?;
+
+        // 写到一半超过限制 语句的写入全部撤销 行和索引都和之前一样
+        session.execute("begin transaction;")?;
+        session.execute(&format!("set max_txn_bytes = {};", bytes * 2 - 1))?;
+        let err = session.execute("update t set n = n + 10;").unwrap_err();
+        assert_eq!(err.code(), "54000");
+        session.execute("commit;")?;
+        let txn = engine.begin(Mode::ReadOnly)?;
+        for id in 1..=3 {
+            let row = vec![Value::Integer(id), Value::Integer(id)];
+            assert_eq!(txn.read("t", &Value::Integer(id))?, Some(row));
+            let ids = txn.read_index("t", "n", &Value::Integer(id))?;
+            assert_eq!(ids, HashSet::from([Value::Integer(id)]));
+            assert!(txn.read_index("t", "n", &Value::Integer(id + 10))?.is_empty());
+        }
+        txn.rollback()?;
+
+        // truncate 先数出行数 超过限制的时候一行都不删除
+        let mut txn = engine.begin(Mode::ReadWrite)?;
+        txn.set_write_limits(Some(2), None);
+        assert_eq!(txn.truncate("t").unwrap_err().code(), "54000");
+        assert_eq!(txn.rows_written, 0);
+        assert_eq!(txn.scan("t", None, None)?.len(), 3);
+        txn.rollback()?;
+        Ok(())
+    }
+
+    #[test]
+    fn with_retry_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int );")?;
+        session.execute("insert into t values (1, 0);")?;
+        let increment = |txn: &mut KvTransaction| -> Result<i64> {
+            let mut row = txn.read("t", &Value::Integer(1))?.unwrap();
+            let n = match row[1] {
+                Value::Integer(n) => n + 1,
+                _ => unreachable!(),
+            };
+            row[1] = Value::Integer(n);
+            txn.update("t", &Value::Integer(1), row)?;
+            Ok(n)
+        };
+
+        // 第一次执行的时候其他会话修改了同一行 写冲突之后重新执行
+        let mut other = engine.session()?;
+        let mut attempts = 0;
+        let n = session.with_retry(Mode::ReadWrite, |txn| {
+            attempts += 1;
+            if attempts == 1 {
+                other.execute("update t set n = 10 where id = 1;")?;
+            }
+            increment(txn)
+        })?;
+        assert_eq!((n, attempts), (11, 2));
+
+        // 一直冲突的话最多执行 RETRY_ATTEMPTS 次
+        let mut attempts = 0;
+        let error = session
+            .with_retry(Mode::ReadWrite, |txn| {
+                attempts += 1;
+                other.execute("update t set n = 20 where id = 1;")?;
+                increment(txn)
+            })
+            .unwrap_err();
+        assert!(error.retryable());
+        assert_eq!(error.code(), "40001");
+        assert_eq!(attempts, crate::sql::engine::RETRY_ATTEMPTS);
+
+        // 其他错误不重试
+        let mut attempts = 0;
+        let result = session.with_retry(Mode::ReadWrite, |txn| {
+            attempts += 1;
+            txn.create("t", vec![Value::Integer(1), Value::Integer(0)])
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+        Ok(())
+    }
+
+    #[test]
+    fn policy_test() -> Result<()> {
+        let engine = test_engine();
+        let mut admin = engine.session()?.with_admin(true);
+        admin.execute("create table t ( id int primary key, tenant int, n int index );")?;
+        admin.execute("insert into t values (1, 1, 10), (2, 1, 20), (3, 2, 10);")?;
+        let policy = "create policy tenant on t using (tenant = current_setting(\"app.tenant\"));";
+        assert_eq!(admin.execute(policy)?, ResultSet::CreatePolicy { name: "tenant".into() });
+        assert!(admin.execute(policy).is_err());
+        let ids = |session: &mut SqlSession<KV>, sql: &str| -> Result<Vec<Value>> {
+            Ok(session.query(sql)?.into_iter().map(|r| r[0].clone()).collect())
+        };
+        let values = |ids: &[i64]| ids.iter().map(|i| Value::Integer(*i)).collect::<Vec<_>>();
+
+        // 没有设置的时候是null 一行都看不到
+        let mut session = engine.session()?;
+        assert_eq!(ids(&mut session, "select id from t;")?, values(&[]));
+        session.execute("set app.tenant = 1;")?;
+        assert_eq!(ids(&mut session, "select id from t order by id;")?, values(&[1, 2]));
+        // 索引查询和子查询中也会过滤
+        assert_eq!(ids(&mut session, "select id from t where n = 10;")?, values(&[1]));
+        let sql = "select (select count(*) from t) from t where id = 1;";
+        assert_eq!(ids(&mut session, sql)?, values(&[2]));
+        // 看不到的行不能修改
+        assert_eq!(session.execute("update t set n = 0;")?, ResultSet::Update { count: 2 });
+        let count = session.execute("delete from t where n = 0 or id = 3;")?;
+        assert_eq!(count, ResultSet::Delete { count: 2 });
+        assert_eq!(ids(&mut admin, "select id from t;")?, values(&[3]));
+
+        // 写入的行也要满足策略 不能写到其他租户
+        let code = |r: Result<ResultSet>| r.map_err(|e| e.code().to_string()).err();
+        let denied = Some("42501".to_string());
+        assert_eq!(code(session.execute("insert into t values (4, 2, 0);")), denied);
+        session.execute("insert into t values (4, 1, 0), (5, 1, 0);")?;
+        assert_eq!(code(session.execute("update t set tenant = 2 where id = 4;")), denied);
+        let sql = "insert into t values (3, 1, 0) on conflict (id) do update set n = 1;";
+        assert_eq!(code(session.execute(sql)), denied);
+        // truncate 只删除看得到的行
+        assert_eq!(session.execute("truncate t;")?, ResultSet::Delete { count: 2 });
+        assert_eq!(ids(&mut admin, "select id from t;")?, values(&[3]));
+
+        // 策略和备份只有管理员可以管理 配置了密码之后可以切换成管理员
+        assert_eq!(code(session.execute("drop policy tenant on t;")), denied);
+        assert_eq!(code(session.execute(policy)), denied);
+        assert_eq!(code(session.execute("backup to \"/nonexistent/t\";")), denied);
+        assert_eq!(code(session.execute("set admin = \"secret\";")), denied);
+        let mut other = engine.clone().with_admin_password(Some("secret".into())).session()?;
+        assert_eq!(code(other.execute("set admin = \"wrong\";")), denied);
+        let set = other.execute("set admin = \"secret\";")?;
+        assert_eq!(set, ResultSet::Set { name: "admin".into(), value: Value::Bool(true) });
+        assert_eq!(ids(&mut other, "select id from t;")?, values(&[3]));
+        other.execute("set admin = false;")?;
+        assert_eq!(ids(&mut other, "select id from t;")?, values(&[]));
+
+        // 删除之后都能看到
+        admin.execute("drop policy tenant on t;")?;
+        assert!(admin.execute("drop policy tenant on t;").is_err());
+        assert_eq!(ids(&mut session, "select id from t;")?, values(&[3]));
+        Ok(())
+    }
+
+    #[test]
+    fn plan_cache_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int );")?;
+        session.execute("insert into t values (1, 10), (2, 20);")?;
+        let plans = || -> Result<(u64, u64)> {
+            let status = engine.status()?.plans;
+            Ok((status.hits, status.misses))
+        };
+        assert_eq!(session.query("select n from t where id = 1;")?.len(), 1);
+        // 插入也会缓存 空白和大小写不同的是同一条语句 常量不同的不是
+        assert_eq!(session.query("SELECT n  FROM t WHERE id = 1;")?.len(), 1);
+        assert_eq!(session.query("select n from t where id = 2;")?.len(), 1);
+        assert_eq!(plans()?, (1, 3));
+        // 其他会话共享缓存 执行DDL之后清空
+        let mut other = engine.session()?;
+        other.query("select n from t where id = 2;")?;
+        assert_eq!(plans()?, (2, 3));
+        session.execute("analyze t;")?;
+        assert_eq!(engine.status()?.plans.entries, 0);
+        other.query("select n from t where id = 2;")?;
+        assert_eq!(plans()?, (2, 4));
+
+        // 事务中重建的表提交之前 另一个会话用旧的定义规划并缓存 提交之后缓存的计划不能再使用
+        session.execute("begin transaction;")?;
+        session.execute("drop table t;")?;
+        session.execute("create table t ( id int primary key, n int, m int );")?;
+        session.execute("insert into t values (1, 10, 100);")?;
+        assert_eq!(other.query("select * from t;")?[0].len(), 2);
+        session.execute("commit;")?;
+        assert_eq!(other.query("select * from t;")?[0].len(), 3);
+        assert_eq!(plans()?, (2, 7));
+
+        // 影响规划的会话变量不同的时候不使用其他会话缓存的计划
+        other.execute("set integer_division = true;")?;
+        assert_eq!(other.query("select n / 4 from t;")?, vec![vec![Value::Integer(2)]]);
+        assert_eq!(session.query("select n / 4 from t;")?, vec![vec![Value::Float(2.5)]]);
+        assert!(other.execute("set integer_division = 1;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn changes_test() -> Result<()> {
+        use crate::sql::engine::{Change, ChangeKind};
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        assert_eq!(engine.last_commit()?, 0);
+        // 建表也有提交日志 但是没有行修改
+        session.execute("create table t ( id int primary key, n int );")?;
+        assert_eq!(engine.last_commit()?, 1);
+        assert!(engine.commits(0, 10)?[0].changes.is_empty());
+        session.execute("insert into t values (1, 10), (2, 20);")?;
+        // 同一行的多次修改合并 插入之后又删除的行没有修改 回滚的事务没有修改
+        session.execute("begin transaction;")?;
+        session.execute("update t set n = 11 where id = 1;")?;
+        session.execute("update t set n = 12 where id = 1;")?;
+        session.execute("delete from t where id = 2;")?;
+        session.execute("insert into t values (3, 30), (4, 40);")?;
+        session.execute("delete from t where id = 4;")?;
+        session.execute("commit;")?;
+        session.execute_all("begin transaction; insert into t values (5, 50);")?;
+        session.execute("rollback;")?;
+        session.execute("select * from t;")?;
+        // 按照提交的顺序 而不是事务开始的顺序
+        let mut other = engine.session()?;
+        session.execute("begin transaction;")?;
+        other.execute("begin transaction;")?;
+        other.execute_all("insert into t values (6, 60); commit;")?;
+        session.execute_all("insert into t values (7, 70); commit;")?;
+        // 清空表只有一个修改 在之后插入的行前面
+        session.execute_all("begin transaction; insert into t values (8, 80);")?;
+        session.execute_all("truncate table t; insert into t values (9, 90); commit;")?;
+        assert_eq!(engine.last_commit()?, 6);
+
+        let row = |id: i64, n: i64| Some(vec![Value::Integer(id), Value::Integer(n)]);
+        let changes = engine
+            .changes(0, 10)?
+            .into_iter()
+            .map(|c| (c.seq, c.kind, c.before, c.after))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changes,
+            vec![
+                (2, ChangeKind::Insert, None, row(1, 10)),
+                (2, ChangeKind::Insert, None, row(2, 20)),
+                (3, ChangeKind::Update, row(1, 10), row(1, 12)),
+                (3, ChangeKind::Delete, row(2, 20), None),
+                (3, ChangeKind::Insert, None, row(3, 30)),
+                (4, ChangeKind::Insert, None, row(6, 60)),
+                (5, ChangeKind::Insert, None, row(7, 70)),
+                (6, ChangeKind::Truncate, None, None),
+                (6, ChangeKind::Insert, None, row(9, 90)),
+            ]
+        );
+        let changes: Vec<Change> = engine.changes(3, 1)?;
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].seq, changes[0].table.as_str()), (4, "t"));
+        assert!(changes[0].txn > engine.changes(4, 1)?[0].txn);
+        Ok(())
+    }
+
+    #[test]
+    fn ttl_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int index ) ttl 60;")?;
+        session.execute("insert into t values (1, 10), (2, 10);")?;
+        let ids = |session: &mut SqlSession<KV>, sql: &str| -> Result<Vec<Value>> {
+            Ok(session.query(sql)?.into_iter().map(|r| r[0].clone()).collect())
+        };
+
+        // 模拟两分钟之前写入的行
+        let write_old = |id: i64, n: i64| -> Result<()> {
+            let mut txn = engine.begin(Mode::ReadWrite)?;
+            let row = vec![Value::Integer(id), Value::Integer(n)];
+            let key = SqlKey::Row("t".into(), Some(Value::Integer(id).into())).encode();
+            txn.txn.set(&key, encode_row(&row, now() - 120)?)?;
+            let mut entry = txn.read_index("t", "n", &Value::Integer(n))?;
+            entry.insert(Value::Integer(id));
+            txn.index_save("t", "n", &Value::Integer(n), entry)?;
+            txn.commit()
+        };
+        write_old(3, 10)?;
+        write_old(4, 40)?;
+
+        let live = vec![Value::Integer(1), Value::Integer(2)];
+        assert_eq!(ids(&mut session, "select id from t order by id asc;")?, live);
+        assert_eq!(ids(&mut session, "select id from t where n = 10 order by id asc;")?, live);
+        assert_eq!(ids(&mut session, "select id from t where id = 3;")?, vec![]);
+        assert_eq!(
+            session.execute("check table t;")?,
+            ResultSet::CheckTable {
+                name: "t".to_string(),
+                rows: 2
+            }
+        );
+
+        // 过期的行让出主键 它的索引也一起删除
+        session.execute("insert into t values (3, 30);")?;
+        assert_eq!(ids(&mut session, "select id from t where n = 10 order by id asc;")?, live);
+        assert_eq!(ids(&mut session, "select id from t where n = 30;")?, vec![Value::Integer(3)]);
+
+        assert_eq!(engine.expire()?, 1);
+        assert_eq!(engine.expire()?, 0);
+        let txn = engine.begin(Mode::ReadOnly)?;
+        assert!(txn.read_index("t", "n", &Value::Integer(40))?.is_empty());
+        assert_eq!(txn.get_row("t", &Value::Integer(4))?, None);
+        txn.commit()?;
+
+        // 使用列中的时间戳 null不会过期
+        session.execute(
+            "create table e ( id int primary key, at int null default null ) ttl 10 on at;",
+        )?;
+        session.execute(&format!(
+            "insert into e values (1, 0), (2, {}), (3, null);",
+            now() + 1000
+        ))?;
+        assert_eq!(
+            ids(&mut session, "select id from e order by id asc;")?,
+            vec![Value::Integer(2), Value::Integer(3)]
+        );
+        assert!(session
+            .execute("create table f ( id int primary key, at string ) ttl 10 on at;")
+            .is_err());
+        assert!(session
+            .execute("create table f ( id int primary key ) ttl 10 on at;")
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn scan_partitions_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int );")?;
+        let values = (0..100).map(|i| format!("({}, {})", i, i * 2)).collect::<Vec<_>>();
+        session.execute(&format!("insert into t values {};", values.join(", ")))?;
+
+        let txn = engine.begin(Mode::ReadOnly)?;
+        let partitions = txn.scan_partitions("t", 4)?;
+        assert_eq!(partitions.len(), 4);
+        // 每个部分在不同的线程中读取
+        let handles = partitions
+            .into_iter()
+            .map(|p| std::thread::spawn(move || p.collect::<Result<Vec<_>>>()))
+            .collect::<Vec<_>>();
+        let mut rows = Vec::new();
+        for handle in handles {
+            let partition = handle.join().unwrap()?;
+            assert_eq!(partition.len(), 25);
+            rows.extend(partition);
+        }
+        assert_eq!(rows, txn.scan("t", None, None)?);
+
+        // 行数比n少的时候 部分的数量也更少
+        session.execute("create table u ( id int primary key );")?;
+        session.execute("insert into u values (1), (2);")?;
+        let txn = engine.begin(Mode::ReadOnly)?;
+        let sizes = txn
+            .scan_partitions("u", 8)?
+            .into_iter()
+            .map(|p| p.count())
+            .collect::<Vec<_>>();
+        assert_eq!(sizes, vec![1, 1]);
+        assert_eq!(txn.scan_partitions("t", 0)?.len(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn cursor_limit_test() -> Result<()> {
+        let engine = test_engine().with_max_cursors(2);
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key );")?;
+        session.execute("insert into t values (1), (2);")?;
+
+        let (a, _) = session.open_cursor("select * from t;")?;
+        session.open_cursor("select * from t;")?;
+        let err = session.open_cursor("select * from t;").unwrap_err();
+        assert_eq!(err.code(), "54000");
+        // 关闭或者读完之后可以再打开
+        session.close_cursor(a);
+        let (b, _) = session.open_cursor("select * from t;")?;
+        assert_eq!(session.fetch(b, 10)?.len(), 2);
+        session.open_cursor("select * from t;")?;
+        Ok(())
+    }
+
+    #[test]
+    fn quoted_ident_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session
+            .execute("create table `order` ( id int primary key, `select` int, `Name` string );")?;
+        session.execute(
+            "insert into `order` (id, `select`, `Name`) values (1, 10, \"a\"), (2, 20, \"b\");",
+        )?;
+        let sql = "select `select`, `order`.`Name` as `a b` from `order` where `select` > 10;";
+        let (columns, rows) = session.query_columns(sql)?;
+        assert_eq!(columns.len(), 2);
+        assert_eq!(rows, vec![vec![Value::Integer(20), Value::String("b".to_string())]]);
+        // 反引号中的大小写不会转换 没有引号的ident都是小写
+        assert!(session.execute("select name from `order`;").is_err());
+        // 关键字不用反引号包起来的时候提示需要加上反引号
+        let error = session.execute("create table order ( id int primary key );").unwrap_err();
+        assert!(error.to_string().contains("`order`"), "{}", error);
+
+        // 双引号是字符串 不能用来引用名字
+        let error = session.execute("create table \"group\" ( id int primary key );").unwrap_err();
+        assert!(error.to_string().contains("quote ident with `"), "{}", error);
+        assert_eq!(
+            session.query("select \"Name\" from `order` where id = 1;")?,
+            vec![vec![Value::String("Name".to_string())]]
+        );
+
+        session.execute("create table 用户 ( 编号 int primary key, 名字 string );")?;
+        session.execute("insert into 用户 values (1, \"\\u5f20\\\"三\\\"\");")?;
+        assert_eq!(
+            session.query("select 名字 from 用户 where 编号 = 1;")?,
+            vec![vec![Value::String("张\"三\"".to_string())]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bloom_filter_test() -> Result<()> {
+        let mvcc = kv::MVCC::new(Box::new(BtreeStore::new())).with_bloom_filters(KV::keyspace);
+        let engine = KV::new(mvcc);
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, name string unique );")?;
+        session.execute("insert into t values (1, \"a\"), (2, \"b\");")?;
+        let count = |session: &mut SqlSession<KV>, sql: &str| -> Result<usize> {
+            Ok(session.query(sql)?.len())
+        };
+        let skipped = |engine: &KV| -> Result<u64> { Ok(engine.kv.get_status()?.bloom.skipped) };
+        // 插入之前检查主键和唯一索引的时候建立过滤器 之后的写入一起更新
+        let before = skipped(&engine)?;
+        assert_eq!(count(&mut session, "select * from t where id = 1;")?, 1);
+        assert_eq!(count(&mut session, "select * from t where name = \"b\";")?, 1);
+        assert_eq!(skipped(&engine)?, before);
+        assert_eq!(count(&mut session, "select * from t where id = 100;")?, 0);
+        assert_eq!(count(&mut session, "select * from t where name = \"z\";")?, 0);
+        assert_eq!(skipped(&engine)?, before + 2);
+        assert_eq!(engine.kv.get_status()?.bloom.filters, 2);
+
+        // 还没有提交的写入也会放进过滤器 其他事务通过可见性判断
+        let mut other = engine.session()?;
+        session.execute_all("begin transaction; insert into t values (3, \"c\");")?;
+        assert_eq!(count(&mut other, "select * from t where id = 3;")?, 0);
+        assert_eq!(count(&mut session, "select * from t where id = 3;")?, 1);
+        session.execute("commit;")?;
+        assert_eq!(count(&mut other, "select * from t where id = 3;")?, 1);
+        assert_eq!(count(&mut other, "select * from t where name = \"c\";")?, 1);
+        // 回滚和删除的key还在过滤器中 只是不能跳过
+        session.execute_all("begin transaction; insert into t values (4, \"d\"); rollback;")?;
+        session.execute("delete from t where id = 1;")?;
+        assert_eq!(count(&mut session, "select * from t where id = 4;")?, 0);
+        assert_eq!(count(&mut session, "select * from t where id = 1;")?, 0);
+        session.execute("insert into t values (1, \"a\");")?;
+        assert_eq!(count(&mut session, "select * from t where id = 1;")?, 1);
+
+        // 写入超过容量之后重新建立 不会漏掉key
+        let values = (10..3000).map(|i| format!("({}, \"n{}\")", i, i)).collect::<Vec<_>>();
+        session.execute(&format!("insert into t values {};", values.join(", ")))?;
+        for i in (10..3000).step_by(97) {
+            assert_eq!(count(&mut session, &format!("select * from t where id = {};", i))?, 1);
+            let sql = format!("select * from t where name = \"n{}\";", i);
+            assert_eq!(count(&mut session, &sql)?, 1);
+        }
+        let before = skipped(&engine)?;
+        for i in 5000..5100 {
+            count(&mut session, &format!("select * from t where id = {};", i))?;
+        }
+        assert!(skipped(&engine)? - before > 90);
+        Ok(())
+    }
+}
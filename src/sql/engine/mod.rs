@@ -1,14 +1,30 @@
-use super::{execution::ResultSet, expression::Expression, schema::Catalog, Value};
-use crate::errors::Error;
-use crate::sql::plan::planner::Planner;
+use super::{
+    execution::{Batches, Columns, ResultSet},
+    expression::Expression,
+    schema::Catalog,
+    Value,
+};
+use crate::errors::{Error, ErrorCode};
+use crate::metrics;
+use crate::sql::parser::ast::{ExplainFormat, Isolation, Statement};
+use crate::sql::plan::planner::{Context, Planner};
 use crate::sql::plan::Plan;
-use crate::storage::kv::mvcc::Mode;
+use self::cache::{CachedPlan, PlanCache, PlanCacheStatus};
+use crate::storage::kv::mvcc::{Mode, VacuumStatus};
 use crate::{errors::*, sql::parser::Parser};
 use futures_util::poll;
-use log::debug;
+use log::{debug, warn};
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashSet;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{self, Display};
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Bound;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+pub mod cache;
 pub mod kv;
 pub mod raft;
 
@@ -16,6 +32,25 @@ pub type Row = Vec<Value>;
 pub type Rows = Vec<Row>;
 /// value 是 key（索引值）, hashset是索引的对应的主键值
 pub type IndexScan = Vec<(Value, HashSet<Value>)>;
+/// 索引值的范围 (下界, 上界)
+pub type IndexRange = (Bound<Value>, Bound<Value>);
+/// 没有解码的key value
+pub type KvItems = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// 排序和聚合默认可以使用的内存 单位字节 超过之后写到临时文件中
+pub const DEFAULT_WORK_MEMORY: usize = 64 * 1024 * 1024;
+/// 每个会话默认最多同时打开的游标数
+pub const DEFAULT_MAX_CURSORS: usize = 64;
+
+/// with_retry 最多执行多少次
+pub const RETRY_ATTEMPTS: u32 = 5;
+/// 第一次重试之前等待的时间 之后每次翻倍 实际等待的是其中随机的一段
+const RETRY_BACKOFF: Duration = Duration::from_millis(5);
+
+/// 连接和聚合默认使用的线程数 最多使用8个核
+pub fn default_workers() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get().min(8))
+}
 
 /// sql引擎接口
 pub trait Engine: Clone {
@@ -30,37 +65,186 @@ pub trait Engine: Clone {
         Ok(SqlSession {
             engine: self.clone(),
             txn: None,
+            scan_batch_size: None,
+            work_memory: None,
+            workers: None,
+            lock_timeout: None,
+            max_txn_rows: None,
+            max_txn_bytes: None,
+            statement_timeout: None,
+            slow_query: None,
+            integer_division: false,
+            cancel: Cancel::default(),
+            cursors: HashMap::new(),
+            next_cursor: 0,
+            admin: false,
+            settings: HashMap::new(),
         })
     }
 
     /// 通过事务id 重新启动一个老事务
     fn resume(&self, id: u64) -> Result<Self::Transaction>;
+
+    /// 获得存储状态
+    fn status(&self) -> Result<Status>;
+
+    /// 监控指标使用的统计信息 只读取计数器 不扫描表
+    fn metrics(&self) -> Result<Metrics>;
+
+    /// 所有会话共享的计划缓存
+    fn plan_cache(&self) -> &PlanCache;
+
+    /// 检查存储是否可以正常响应
+    fn ping(&self) -> Result<()>;
+
+    /// 基于raft的引擎返回本节点的raft状态
+    fn raft_status(&self) -> Result<Option<crate::raft::Status>> {
+        Ok(None)
+    }
+
+    /// 垃圾回收 清理不再可见的旧版本数据
+    fn vacuum(&self) -> Result<VacuumStatus>;
+
+    /// 删除过期的行 返回删除的行数 只读的副本从主节点复制删除
+    fn expire(&self) -> Result<u64> {
+        if self.read_only() {
+            return Ok(0);
+        }
+        let mut txn = self.begin(Mode::ReadWrite)?;
+        match expire_rows(&mut txn) {
+            Ok(count) => {
+                txn.commit()?;
+                Ok(count)
+            }
+            Err(e) => {
+                txn.rollback()?;
+                Err(e)
+            }
+        }
+    }
+
+    /// 关闭之前调用 把数据写到磁盘 返回是否正常关闭 下次启动的时候不需要恢复
+    /// 默认什么都不做
+    fn shutdown(&self) -> Result<bool> {
+        Ok(false)
+    }
+    /// 序号大于after的提交日志 最多limit个事务 按照提交的顺序返回
+    fn commits(&self, after: u64, limit: usize) -> Result<Vec<Commit>>;
+    /// 最后一条提交日志的序号
+    fn last_commit(&self) -> Result<u64>;
+    /// 序号大于after的已经提交的行修改 最多读取limit个事务 按照提交的顺序返回
+    fn changes(&self, after: u64, limit: usize) -> Result<Vec<Change>> {
+        let commits = self.commits(after, limit)?;
+        Ok(commits.into_iter().flat_map(|c| c.changes).collect())
+    }
+    /// 只读的副本不能开启读写事务
+    fn read_only(&self) -> bool {
+        false
+    }
+    /// 会话通过 set admin 切换成管理员用的密码 None的时候不能切换
+    fn admin_password(&self) -> Option<&str> {
+        None
+    }
+    /// 每个会话最多同时打开的游标数
+    fn max_cursors(&self) -> usize {
+        DEFAULT_MAX_CURSORS
+    }
 }
 
 /// 设置一个事务
-pub trait Transaction: Catalog {
+pub trait Transaction: Catalog + Send {
     /// 事务id
     fn id(&self) -> u64;
     /// 事务模式
     fn mode(&self) -> Mode;
+    /// 设置扫描的批大小
+    fn set_scan_batch_size(&mut self, size: usize);
+    /// 设置排序和聚合可以使用的内存
+    fn set_work_memory(&mut self, size: usize);
+    /// 排序和聚合可以使用的内存 单位字节
+    fn work_memory(&self) -> usize;
+    /// 设置连接和聚合可以使用的线程数
+    fn set_workers(&mut self, workers: usize);
+    /// 连接和聚合可以使用的线程数 为1的时候不并行
+    fn workers(&self) -> usize;
+    /// 设置写冲突时等待锁的时间 为0的时候直接报错
+    fn set_lock_timeout(&mut self, timeout: Duration);
+    /// 设置事务最多写入的行数和字节数 None 表示不限制
+    fn set_write_limits(&mut self, rows: Option<u64>, bytes: Option<u64>);
+    /// 设置当前语句的取消标记
+    fn set_cancel(&mut self, cancel: Cancel);
+    /// 当前语句的取消标记 交给其他线程检查
+    fn canceller(&self) -> Cancel;
+    /// 语句被取消或者超时的时候返回错误 执行器在批与批之间调用
+    fn check_cancel(&self) -> Result<()>;
+    /// 每条语句执行之前调用 读已提交的事务会重新获取快照
+    fn refresh(&mut self) -> Result<()>;
+    /// 当前事务的只读副本 看到的数据和当前语句一样 事务结束之后也可以继续读取
+    /// 按批读取的结果用它读取后面的批 不能用来写入和结束事务 不支持的时候是None
+    fn reader(&self) -> Result<Option<Self>>
+    where
+        Self: Sized;
     /// 提交事务
     fn commit(self) -> Result<()>;
     /// 回滚事务
     fn rollback(self) -> Result<()>;
+    /// 创建保存点
+    fn savepoint(&mut self, name: &str) -> Result<()>;
+    /// 撤销保存点之后的修改
+    fn rollback_to_savepoint(&mut self, name: &str) -> Result<()>;
+    /// 删除保存点 保留修改
+    fn release_savepoint(&mut self, name: &str) -> Result<()>;
     /// 创建一个行
     fn create(&mut self, table: &str, row: Row) -> Result<()>;
+    /// 一次创建多行 表结构只读取一次 唯一性一起检查
+    /// 有一行出错的话所有行都不会写入
+    fn create_batch(&mut self, table: &str, rows: Rows) -> Result<()>;
     /// 删除行
     fn delete(&mut self, table: &str, id: &Value) -> Result<()>;
+    /// 删除表中所有的行和索引 返回删除的行数
+    fn truncate(&mut self, table: &str) -> Result<u64>;
     /// 通过主键返回一个row
     fn read(&self, table: &str, id: &Value) -> Result<Option<Row>>;
     /// 得到column=value的行主键  column应是索引
     fn read_index(&self, table: &str, column: &str, value: &Value) -> Result<HashSet<Value>>;
-    /// scan table
-    fn scan(&self, table: &str, filter: Option<Expression>) -> Result<Rows>;
+    /// scan table columns不为None的时候只解码这些列 其他列是null
+    fn scan(&self, table: &str, filter: Option<Expression>, columns: Option<Vec<usize>>)
+        -> Result<Rows>;
+    /// 和scan一样 但是按照主键的顺序逐行读取 不会一次读出整个表
+    fn scan_iter(
+        &self,
+        table: &str,
+        filter: Option<Expression>,
+        columns: Option<Vec<usize>>,
+    ) -> Result<SqlScan>;
+    /// 把表按照主键切分成最多n个互不相交的部分 每一部分是一个独立的迭代器
+    /// 迭代器可以交给不同的线程读取 所有部分合起来就是整个表
+    fn scan_partitions(&self, table: &str, n: usize) -> Result<Vec<SqlScan>>;
     /// 得到索引entry 就是set集合， 里面有对应的主键
     fn scan_index(&self, table: &str, column: &str) -> Result<IndexScan>;
+    /// 得到索引值在范围内的entry 按照索引值排序 不包含null
+    fn read_index_range(&self, table: &str, column: &str, range: IndexRange) -> Result<IndexScan>;
     /// 更新一个表行
     fn update(&mut self, table: &str, id: &Value, row: Row) -> Result<()>;
+    /// 已经过期但是还没有删除的行的主键
+    fn expired(&self, table: &str) -> Result<Vec<Value>>;
+    /// 按照key的顺序返回start之后(不包含start)最多limit个当前事务可见的key value
+    /// 包括表结构和索引 备份的时候分页读取
+    fn dump(&self, start: Option<&[u8]>, limit: usize) -> Result<KvItems>;
+    /// 直接写入dump得到的key value 恢复备份使用
+    fn load(&mut self, items: KvItems) -> Result<()>;
+}
+
+/// 删除所有表中过期的行 返回删除的行数
+fn expire_rows<T: Transaction>(txn: &mut T) -> Result<u64> {
+    let mut count = 0;
+    for table in txn.scan_tables()?.into_iter().filter(|t| t.ttl.is_some()) {
+        for id in txn.expired(&table.name)? {
+            txn.delete(&table.name, &id)?;
+            count += 1;
+        }
+    }
+    Ok(count)
 }
 
 /// sql session 处理事务和表的请求
@@ -69,9 +253,423 @@ pub struct SqlSession<E: Engine> {
     engine: E,
     /// 当前的事务
     txn: Option<E::Transaction>,
+    /// 会话变量 scan_batch_size 没有设置就使用引擎默认的
+    scan_batch_size: Option<usize>,
+    /// 会话变量 work_memory 单位字节 没有设置就使用 DEFAULT_WORK_MEMORY
+    work_memory: Option<usize>,
+    /// 会话变量 parallel_workers 没有设置就使用 default_workers
+    workers: Option<usize>,
+    /// 会话变量 lock_timeout 单位毫秒 没有设置的时候写冲突直接报错
+    lock_timeout: Option<Duration>,
+    /// 会话变量 max_txn_rows 和 max_txn_bytes 事务最多写入的行数和字节数 没有设置就不限制
+    max_txn_rows: Option<u64>,
+    max_txn_bytes: Option<u64>,
+    /// 会话变量 statement_timeout 单位毫秒 没有设置就不会超时
+    statement_timeout: Option<Duration>,
+    /// 会话变量 slow_query_threshold 单位毫秒 超过的语句连同计划写到查询日志 没有设置就不记录慢查询
+    slow_query: Option<Duration>,
+    /// 会话变量 integer_division 打开之后 / 是整除
+    integer_division: bool,
+    /// 当前语句的取消标记
+    cancel: Cancel,
+    /// 打开的游标 没有读完的查询结果
+    cursors: HashMap<u64, Cursor>,
+    next_cursor: u64,
+    /// 管理员的会话不受行级安全策略的限制
+    admin: bool,
+    /// SET 设置的名称中带有点的自定义变量 例如 app.tenant
+    settings: HashMap<String, Value>,
+}
+
+/// 多条语句中有一条执行出错 之前执行完的语句的结果和出错的原因
+#[derive(Debug)]
+pub struct Partial {
+    pub results: Vec<ResultSet>,
+    pub error: Error,
+}
+
+impl From<Error> for Partial {
+    fn from(error: Error) -> Self {
+        Self { results: Vec::new(), error }
+    }
+}
+
+impl From<Partial> for Error {
+    fn from(partial: Partial) -> Self {
+        partial.error
+    }
+}
+
+/// 查询日志的target 可以单独设置日志级别
+pub const QUERY_LOG: &str = "coke_db::query";
+
+/// 一条语句的查询日志
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QueryLog {
+    /// 语句所在的输入 一次执行多条语句的时候是全部的输入
+    pub sql: String,
+    pub elapsed: Duration,
+    /// 查询返回的行数 修改语句是修改的行数
+    pub rows: u64,
+    /// 执行语句的事务 出错的时候可能没有
+    pub txn: Option<u64>,
+    pub error: Option<String>,
+    /// 执行的计划 只有慢查询会输出
+    pub plan: Option<String>,
+}
+
+impl Display for QueryLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "sql={:?} duration_ms={:.3} rows={} txn={}",
+            self.sql,
+            self.elapsed.as_secs_f64() * 1000.0,
+            self.rows,
+            self.txn.map_or("none".to_string(), |id| id.to_string())
+        )?;
+        if let Some(error) = &self.error {
+            write!(f, " error={:?}", error)?;
+        }
+        if let Some(plan) = &self.plan {
+            write!(f, " plan:\n{}", plan)?;
+        }
+        Ok(())
+    }
+}
+
+/// 服务端的游标 保存查询的执行器 每次读取的时候再计算下一批
+struct Cursor {
+    batches: Batches,
+    /// 上一批中没有返回的行
+    pending: std::vec::IntoIter<Row>,
+}
+
+/// 语句的取消标记 clone出来的标记共享同一个状态 可以在其他线程取消
+#[derive(Clone, Debug, Default)]
+pub struct Cancel {
+    cancelled: Arc<AtomicBool>,
+    /// 语句超时的时间
+    deadline: Option<Instant>,
+}
+
+impl Cancel {
+    /// 取消正在执行的语句
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    /// 开始执行一条新的语句 之前的取消不再生效
+    fn start(&mut self, timeout: Option<Duration>) {
+        self.cancelled.store(false, Ordering::SeqCst);
+        self.deadline = timeout.map(|t| Instant::now() + t);
+    }
+
+    /// 被取消或者超时的时候返回错误
+    pub fn check(&self) -> Result<()> {
+        if self.cancelled.load(Ordering::SeqCst) {
+            return Err(Error::Cancelled("statement cancelled".into()));
+        }
+        match self.deadline {
+            Some(deadline) if Instant::now() >= deadline => {
+                Err(Error::Cancelled("statement timeout".into()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// 语句隐式创建的保存点 sql中的保存点名字是标识符 不会和它重名
+const STATEMENT_SAVEPOINT: &str = "$statement";
+
+/// 会修改数据或者表定义的语句 只读事务和只读的副本上不能执行
+fn writes(kind: &str) -> bool {
+    matches!(
+        kind,
+        "insert"
+            | "update"
+            | "delete"
+            | "truncate"
+            | "create_table"
+            | "drop_table"
+            | "create_policy"
+            | "drop_policy"
+            | "analyze"
+            | "restore"
+    )
 }
 
 impl<E: Engine + 'static> SqlSession<E> {
+    /// 开启一个事务 并带上会话变量
+    fn begin(&self, mode: Mode) -> Result<E::Transaction> {
+        if mode.mutable() && self.engine.read_only() {
+            return Err(Error::Executor("server is a read only replica".into()));
+        }
+        let mut txn = self.engine.begin(mode)?;
+        if let Some(size) = self.scan_batch_size {
+            txn.set_scan_batch_size(size);
+        }
+        if let Some(size) = self.work_memory {
+            txn.set_work_memory(size);
+        }
+        if let Some(workers) = self.workers {
+            txn.set_workers(workers);
+        }
+        if let Some(timeout) = self.lock_timeout {
+            txn.set_lock_timeout(timeout);
+        }
+        txn.set_write_limits(self.max_txn_rows, self.max_txn_bytes);
+        txn.set_cancel(self.cancel.clone());
+        Ok(txn)
+    }
+
+    /// 设置慢查询的阈值 会话变量 slow_query_threshold 可以修改
+    pub fn with_slow_query(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_query = threshold;
+        self
+    }
+
+    /// 设置是否是管理员的会话 管理员能看到表中所有的行
+    pub fn with_admin(mut self, admin: bool) -> Self {
+        self.admin = admin;
+        self
+    }
+
+    /// 规划语句时使用的会话设置
+    fn context(&self) -> Context {
+        Context {
+            policies: !self.admin,
+            settings: Some(self.settings.clone()),
+            integer_division: self.integer_division,
+        }
+    }
+
+    /// 得到这个会话的取消标记 用来取消正在执行的语句
+    pub fn canceller(&self) -> Cancel {
+        self.cancel.clone()
+    }
+
+    /// 是否在显式开启的事务中
+    pub fn in_transaction(&self) -> bool {
+        self.txn.is_some()
+    }
+
+    /// 显式开启的事务的id和模式
+    pub fn txn(&self) -> Option<(u64, Mode)> {
+        self.txn.as_ref().map(|txn| (txn.id(), txn.mode()))
+    }
+
+    /// 为一条查询打开游标 返回游标id和列信息 之后用 fetch 分批读取
+    /// 数据在打开的时候按照当前的快照读取 之后的修改看不到
+    pub fn open_cursor(&mut self, sql: &str) -> Result<(u64, Columns)> {
+        let statement = Parser::new(sql).parse()?;
+        if !matches!(statement, Statement::Select { .. }) {
+            return Err(Error::Executor("cursor can only be opened for select".into()));
+        }
+        // 每个游标都占着一个快照 没有关闭的游标太多的时候拒绝
+        let max = self.engine.max_cursors();
+        if self.cursors.len() >= max {
+            let message = format!("session already has {} open cursors", max);
+            return Err(Error::sql(ErrorCode::TooManyCursors, message));
+        }
+        self.cancel.start(self.statement_timeout);
+        metrics::record_statement(statement.kind());
+        let context = self.context();
+        let (columns, batches) = self.with_txn(Mode::ReadOnly, |txn| {
+            Planner::with_context(txn, context)
+                .build_plan(statement)?
+                .optimize(txn)?
+                .execute_batches(txn)
+        })?;
+        self.next_cursor += 1;
+        self.cursors.insert(
+            self.next_cursor,
+            Cursor {
+                batches,
+                pending: Vec::new().into_iter(),
+            },
+        );
+        Ok((self.next_cursor, columns))
+    }
+
+    /// 从游标中读取最多count行 读完的时候游标自动关闭 返回的行数少于count
+    pub fn fetch(&mut self, id: u64, count: usize) -> Result<Rows> {
+        let cursor = self
+            .cursors
+            .get_mut(&id)
+            .ok_or_else(|| Error::Executor(format!("cursor {} does not exist", id)))?;
+        self.cancel.start(None);
+        let mut rows: Rows = cursor.pending.by_ref().take(count).collect();
+        while rows.len() < count {
+            let batch = match cursor.batches.next() {
+                Some(Ok(batch)) => batch,
+                Some(Err(e)) => {
+                    self.cursors.remove(&id);
+                    return Err(e);
+                }
+                None => break,
+            };
+            cursor.pending = batch.into_iter();
+            rows.extend(cursor.pending.by_ref().take(count - rows.len()));
+        }
+        if rows.len() < count {
+            self.cursors.remove(&id);
+        }
+        Ok(rows)
+    }
+
+    /// 关闭游标 返回游标是否存在
+    pub fn close_cursor(&mut self, id: u64) -> bool {
+        self.cursors.remove(&id).is_some()
+    }
+
+    /// 会话结束的时候调用 回滚还没有结束的事务 返回回滚的事务id
+    /// 不回滚的话 事务写入的数据会一直被锁住
+    pub fn close(&mut self) -> Result<Option<u64>> {
+        match self.txn.take() {
+            Some(txn) => {
+                let id = txn.id();
+                txn.rollback()?;
+                Ok(Some(id))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// 设置会话变量
+    fn set_variable(&mut self, name: &str, value: Value) -> Result<()> {
+        match (name.to_lowercase().as_str(), value) {
+            ("scan_batch_size", Value::Integer(size)) if size > 0 => {
+                self.scan_batch_size = Some(size as usize);
+                if let Some(ref mut txn) = self.txn {
+                    txn.set_scan_batch_size(size as usize);
+                }
+                Ok(())
+            }
+            ("scan_batch_size", value) => Err(Error::Executor(format!(
+                "scan_batch_size expect a positive integer get {}",
+                value
+            ))),
+            ("work_memory", Value::Integer(size)) if size > 0 => {
+                self.work_memory = Some(size as usize);
+                if let Some(ref mut txn) = self.txn {
+                    txn.set_work_memory(size as usize);
+                }
+                Ok(())
+            }
+            ("work_memory", value) => Err(Error::Executor(format!(
+                "work_memory expect a positive integer get {}",
+                value
+            ))),
+            ("parallel_workers", Value::Integer(workers)) if workers > 0 => {
+                self.workers = Some(workers as usize);
+                if let Some(ref mut txn) = self.txn {
+                    txn.set_workers(workers as usize);
+                }
+                Ok(())
+            }
+            ("parallel_workers", value) => Err(Error::Executor(format!(
+                "parallel_workers expect a positive integer get {}",
+                value
+            ))),
+            ("lock_timeout", Value::Integer(ms)) if ms >= 0 => {
+                let timeout = Duration::from_millis(ms as u64);
+                self.lock_timeout = Some(timeout);
+                if let Some(ref mut txn) = self.txn {
+                    txn.set_lock_timeout(timeout);
+                }
+                Ok(())
+            }
+            ("lock_timeout", value) => Err(Error::Executor(format!(
+                "lock_timeout expect a non-negative integer get {}",
+                value
+            ))),
+            // 0 表示不限制 新的限制对已经写入的部分也生效
+            ("max_txn_rows", Value::Integer(n)) if n >= 0 => {
+                self.max_txn_rows = Some(n as u64).filter(|n| *n > 0);
+                if let Some(ref mut txn) = self.txn {
+                    txn.set_write_limits(self.max_txn_rows, self.max_txn_bytes);
+                }
+                Ok(())
+            }
+            ("max_txn_rows", value) => Err(Error::Executor(format!(
+                "max_txn_rows expect a non-negative integer get {}",
+                value
+            ))),
+            ("max_txn_bytes", Value::Integer(n)) if n >= 0 => {
+                self.max_txn_bytes = Some(n as u64).filter(|n| *n > 0);
+                if let Some(ref mut txn) = self.txn {
+                    txn.set_write_limits(self.max_txn_rows, self.max_txn_bytes);
+                }
+                Ok(())
+            }
+            ("max_txn_bytes", value) => Err(Error::Executor(format!(
+                "max_txn_bytes expect a non-negative integer get {}",
+                value
+            ))),
+            // 0 表示不会超时
+            ("statement_timeout", Value::Integer(ms)) if ms >= 0 => {
+                self.statement_timeout = match ms {
+                    0 => None,
+                    ms => Some(Duration::from_millis(ms as u64)),
+                };
+                Ok(())
+            }
+            ("statement_timeout", value) => Err(Error::Executor(format!(
+                "statement_timeout expect a non-negative integer get {}",
+                value
+            ))),
+            // 0 表示不记录慢查询
+            ("slow_query_threshold", Value::Integer(ms)) if ms >= 0 => {
+                self.slow_query = match ms {
+                    0 => None,
+                    ms => Some(Duration::from_millis(ms as u64)),
+                };
+                Ok(())
+            }
+            ("slow_query_threshold", value) => Err(Error::Executor(format!(
+                "slow_query_threshold expect a non-negative integer get {}",
+                value
+            ))),
+            // 用配置的管理员密码切换成管理员的会话 设置成false的时候退出
+            ("admin", Value::Bool(false)) => {
+                self.admin = false;
+                Ok(())
+            }
+            ("admin", Value::String(password)) => match self.engine.admin_password() {
+                Some(expect) if expect == password => {
+                    self.admin = true;
+                    Ok(())
+                }
+                Some(_) => Err(Error::sql(
+                    ErrorCode::InsufficientPrivilege,
+                    "invalid admin password".to_string(),
+                )),
+                None => Err(Error::sql(
+                    ErrorCode::InsufficientPrivilege,
+                    "admin password is not configured".to_string(),
+                )),
+            },
+            ("admin", _) => Err(Error::Executor(
+                "admin expect the admin password or false".to_string(),
+            )),
+            ("integer_division", Value::Bool(on)) => {
+                self.integer_division = on;
+                Ok(())
+            }
+            ("integer_division", value) => Err(Error::Executor(format!(
+                "integer_division expect a boolean get {}",
+                value
+            ))),
+            // 自定义的变量只保存 CURRENT_SETTING 读取
+            (name, value) if name.contains('.') => {
+                self.settings.insert(name.to_string(), value);
+                Ok(())
+            }
+            (name, _) => Err(Error::Executor(format!("unknown variable {}", name))),
+        }
+    }
+
     /// Runs a closure in the session's transaction, or a new transaction if none is active.
     pub fn with_txn<R, F>(&mut self, mode: Mode, f: F) -> Result<R>
     where
@@ -83,27 +681,156 @@ impl<E: Engine + 'static> SqlSession<E> {
                     "The operation cannot run in the current transaction".into(),
                 ));
             }
+            txn.refresh()?;
+            txn.set_cancel(self.cancel.clone());
             return f(txn);
         }
-        let mut txn: <E as Engine>::Transaction = self.engine.begin(mode)?;
+        let mut txn: <E as Engine>::Transaction = self.begin(mode)?;
         let result = f(&mut txn);
-        txn.commit()?;
+        match result {
+            Ok(_) => txn.commit()?,
+            Err(_) => txn.rollback()?,
+        }
         result
     }
 
+    /// 在新的事务中执行闭包 写冲突或者死锁的时候回滚并重新执行 最多执行 RETRY_ATTEMPTS 次
+    /// 闭包可能执行多次 事务外的副作用需要自己处理 会话中已经有事务的时候不会重试
+    pub fn with_retry<R, F>(&mut self, mode: Mode, mut f: F) -> Result<R>
+    where
+        F: FnMut(&mut E::Transaction) -> Result<R>,
+    {
+        if self.txn.is_some() {
+            return self.with_txn(mode, f);
+        }
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            let error = match self.with_txn(mode, &mut f) {
+                Err(e) if e.retryable() && attempt < RETRY_ATTEMPTS => e,
+                result => return result,
+            };
+            // 随机等待一段时间 避免冲突的事务同时重试
+            let backoff = RETRY_BACKOFF * 2u32.pow(attempt - 1);
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u32(attempt);
+            let jitter = backoff.mul_f64((hasher.finish() % 1000) as f64 / 1000.0);
+            debug!("retry transaction after {:?}: {}", jitter, error);
+            std::thread::sleep(jitter);
+        }
+    }
+
     pub fn execute(&mut self, sql: &str) -> Result<ResultSet> {
         debug!("execute sql : {}", sql);
-        let r: Result<ResultSet> = match Parser::new(sql).parse()? {
+        // 缓存中有这条语句的计划的时候不需要解析
+        let key = self.plan_key(sql);
+        if let Some(key) = key.clone() {
+            if let Some(plan) = self.engine.plan_cache().get(&key)? {
+                let query = Prepared::Cached { key, plan, sql: sql.to_string() };
+                return self.execute_statement(query, sql);
+            }
+        }
+        let statement = Parser::new(sql).parse()?;
+        let key = key.filter(|_| cache::cacheable(statement.kind()));
+        self.execute_statement(Prepared::Statement { statement, key }, sql)
+    }
+
+    /// 按顺序执行多条语句 返回每条语句的结果
+    /// 先解析全部语句 有语法错误的时候一条都不执行 执行出错的时候停止 之前的语句不会回滚
+    /// 出错的时候返回之前执行完的语句的结果 例如开启事务的结果
+    pub fn execute_all(&mut self, sql: &str) -> std::result::Result<Vec<ResultSet>, Partial> {
+        self.execute_params(sql, Vec::new())
+    }
+
+    /// 和 execute_all 一样 语句中的 $n 使用 params 中第n个值 不使用计划缓存
+    pub fn execute_params(
+        &mut self,
+        sql: &str,
+        params: Vec<Value>,
+    ) -> std::result::Result<Vec<ResultSet>, Partial> {
+        debug!("execute sql : {}", sql);
+        let statements = Parser::new(sql).with_params(params).parse_all()?;
+        let mut results = Vec::with_capacity(statements.len());
+        for statement in statements {
+            match self.execute_statement(Prepared::Statement { statement, key: None }, sql) {
+                Ok(result) => results.push(result),
+                Err(error) => return Err(Partial { results, error }),
+            }
+        }
+        Ok(results)
+    }
+
+    /// 执行一条语句 并写一条查询日志 sql是这条语句所在的输入
+    fn execute_statement(&mut self, query: Prepared, sql: &str) -> Result<ResultSet> {
+        let start = Instant::now();
+        let mut log = QueryLog {
+            sql: sql.trim().to_string(),
+            txn: self.txn.as_ref().map(|txn| txn.id()),
+            ..QueryLog::default()
+        };
+        let r = self.run_statement(query, &mut log);
+        log.elapsed = start.elapsed();
+        match &r {
+            Ok(
+                ResultSet::Begin { id, .. }
+                | ResultSet::Commit { id }
+                | ResultSet::Rollback { id },
+            ) => log.txn = Some(*id),
+            Ok(ResultSet::Query { rows, .. }) => log.rows = rows.len() as u64,
+            Ok(
+                ResultSet::Create { count }
+                | ResultSet::Delete { count }
+                | ResultSet::Update { count },
+            ) => log.rows = *count,
+            Ok(_) => {}
+            Err(e) => log.error = Some(e.to_string()),
+        }
+        match self.slow_query {
+            Some(threshold) if log.elapsed >= threshold => {
+                warn!(target: QUERY_LOG, "slow query {}", log)
+            }
+            // 慢查询的计划只有超过阈值的时候才输出
+            _ => debug!(target: QUERY_LOG, "query {}", QueryLog { plan: None, ..log }),
+        }
+        r
+    }
+
+    fn run_statement(&mut self, query: Prepared, log: &mut QueryLog) -> Result<ResultSet> {
+        self.cancel.start(self.statement_timeout);
+        metrics::record_statement(query.kind());
+        let (statement, key) = match query {
+            Prepared::Statement { statement, key } => (statement, key),
+            query => return self.run_query(query, log),
+        };
+        let r: Result<ResultSet> = match statement {
             // begin 分为几种情况
             crate::sql::parser::ast::Statement::Begin { .. } if self.txn.is_some() => Err(
                 Error::Executor("there already has a transaction".to_string()),
             ),
             // 没问题的话就是 开启一个事务
             crate::sql::parser::ast::Statement::Begin {
-                readonly: false,
-                version: None,
+                readonly,
+                version,
+                isolation,
             } => {
-                let txn = self.engine.begin(Mode::ReadWrite)?;
+                let mode = match (readonly, version, isolation) {
+                    (false, None, Isolation::Snapshot) => Mode::ReadWrite,
+                    (false, None, Isolation::ReadCommitted) => Mode::ReadCommitted,
+                    (true, None, Isolation::Snapshot) => Mode::ReadOnly,
+                    // 读取某个历史版本的数据
+                    (true, Some(version), Isolation::Snapshot) => Mode::Snapshot { version },
+                    (false, Some(_), _) => {
+                        return Err(Error::Executor(
+                            "transaction as of a version must be read only".into(),
+                        ))
+                    }
+                    (true, _, Isolation::ReadCommitted) => {
+                        return Err(Error::Executor(
+                            "read only transaction can not use read committed".into(),
+                        ))
+                    }
+                };
+                let txn = self.begin(mode)?;
                 let result = ResultSet::Begin {
                     id: txn.id(),
                     mode: txn.mode(),
@@ -111,11 +838,6 @@ impl<E: Engine + 'static> SqlSession<E> {
                 self.txn = Some(txn);
                 Ok(result)
             }
-            // TODO: 目前是想要server启动的时候去检查 ，这样就不需要进行事务恢复了
-            // 所以这里暂时不写了
-            // 本来是考虑重启之后之前的事务可能没有commit 这样就导致一些数据一直被锁住了
-            // 但是还需要去考虑 raft每个raft节点的问题
-            crate::sql::parser::ast::Statement::Begin { readonly, version } => todo!(),
             crate::sql::parser::ast::Statement::Commit if self.txn.is_none() => {
                 Err(Error::Executor("not transaction to commit".into()))
             }
@@ -141,41 +863,327 @@ impl<E: Engine + 'static> SqlSession<E> {
                 txn.rollback()?;
                 Ok(ResultSet::Rollback { id })
             }
-            crate::sql::parser::ast::Statement::Explain(state) => {
+            // 保存点只能在显式开启的事务中使用
+            crate::sql::parser::ast::Statement::Savepoint(_)
+            | crate::sql::parser::ast::Statement::RollbackTo(_)
+            | crate::sql::parser::ast::Statement::Release(_)
+                if self.txn.is_none() =>
+            {
+                Err(Error::Executor(
+                    "savepoint can only be used in a transaction".into(),
+                ))
+            }
+            crate::sql::parser::ast::Statement::Savepoint(name) => {
+                self.txn.as_mut().unwrap().savepoint(&name)?;
+                Ok(ResultSet::Savepoint { name })
+            }
+            crate::sql::parser::ast::Statement::RollbackTo(name) => {
+                self.txn.as_mut().unwrap().rollback_to_savepoint(&name)?;
+                Ok(ResultSet::RollbackToSavepoint { name })
+            }
+            crate::sql::parser::ast::Statement::Release(name) => {
+                self.txn.as_mut().unwrap().release_savepoint(&name)?;
+                Ok(ResultSet::ReleaseSavepoint { name })
+            }
+            crate::sql::parser::ast::Statement::Explain {
+                statement,
+                analyze: false,
+                format,
+            } => {
+                let context = self.context();
                 self.with_txn(Mode::ReadOnly, |txn| {
-                    Ok(ResultSet::Explain(
-                        Planner::new(txn).build_plan(*state)?.optimize(txn)?.node,
-                    ))
+                    let plan = Planner::with_context(txn, context)
+                        .build_plan(*statement)?
+                        .optimize(txn)?;
+                    let estimates = plan.estimate(txn)?;
+                    Ok(match format {
+                        ExplainFormat::Text => ResultSet::Explain {
+                            plan: plan.node,
+                            estimates,
+                        },
+                        ExplainFormat::Json => ResultSet::ExplainJson {
+                            json: plan.node.format_json("estimates", &estimates)?,
+                        },
+                    })
                 })
             }
-            // 如果当前有一个事务在进行
-            statement if self.txn.is_some() => {
-                //let mut txn = self.txn.as_mut().unwrap();
-                let txn = self.txn.as_mut().unwrap();
-                Planner::new(txn)
-                    .build_plan(statement)?
-                    .optimize(txn)?
-                    .execute(txn)
+            // explain analyze 会真正执行语句 修改语句需要读写事务
+            crate::sql::parser::ast::Statement::Explain {
+                statement,
+                analyze: true,
+                format,
+            } => {
+                self.check_writable(statement.kind())?;
+                let mode = match *statement {
+                    crate::sql::parser::ast::Statement::Select { .. } => Mode::ReadOnly,
+                    _ => Mode::ReadWrite,
+                };
+                let context = self.context();
+                self.with_txn(mode, |txn| {
+                    let plan = Planner::with_context(txn, context)
+                        .build_plan(*statement)?
+                        .optimize(txn)?;
+                    let node = plan.node.clone();
+                    let (_, stats) = plan.execute_analyze(txn)?;
+                    Ok(match format {
+                        ExplainFormat::Text => ResultSet::ExplainAnalyze { plan: node, stats },
+                        ExplainFormat::Json => ResultSet::ExplainJson {
+                            json: node.format_json("stats", &stats)?,
+                        },
+                    })
+                })
             }
-            // 没有事务在进行
-            statement => {
-                let mut txn = self.engine.begin(Mode::ReadWrite)?;
-                let r = Planner::new(&txn)
-                    .build_plan(statement)?
-                    .optimize(&txn)?
-                    .execute(&mut txn);
-                txn.commit()?;
+            crate::sql::parser::ast::Statement::Set { name, value } => {
+                let value =
+                    self.with_txn(Mode::ReadOnly, |txn| Planner::new(txn).build_constant(value))?;
+                self.set_variable(&name, value.clone())?;
+                // 不把密码返回给客户端
+                let value = match name.to_lowercase().as_str() {
+                    "admin" => Value::Bool(self.admin),
+                    _ => value,
+                };
+                Ok(ResultSet::Set { name, value })
+            }
+            statement => self.run_query(Prepared::Statement { statement, key }, log),
+        };
+        r
+    }
+
+    /// 规划并执行语句 有事务的时候在当前事务中执行 否则在一个新的事务中执行
+    fn run_query(&mut self, query: Prepared, log: &mut QueryLog) -> Result<ResultSet> {
+        // 设置了慢查询阈值的时候记下执行的计划
+        let explain = self.slow_query.is_some();
+        let kind = query.kind();
+        let context = self.context();
+        let cache = self.engine.plan_cache();
+        self.check_writable(kind)?;
+        let r = if let Some(txn) = self.txn.as_mut() {
+            txn.refresh()?;
+            txn.set_cancel(self.cancel.clone());
+            let plan = query.plan(txn, context, cache)?;
+            if explain {
+                log.plan = Some(plan.node.to_string());
+            }
+            // 修改语句在一个隐式的保存点中执行 出错的时候撤销这条语句已经写入的部分
+            if writes(kind) {
+                txn.savepoint(STATEMENT_SAVEPOINT)?;
+                let r = plan.execute(txn);
+                match r {
+                    Ok(_) => txn.release_savepoint(STATEMENT_SAVEPOINT)?,
+                    Err(_) => txn.rollback_to_savepoint(STATEMENT_SAVEPOINT)?,
+                }
                 r
+            } else {
+                plan.execute(txn)
+            }
+        } else {
+            // 没有事务在进行 只读的副本上只能执行不修改数据的语句
+            let mode = match kind {
+                kind if self.engine.read_only() && !writes(kind) => Mode::ReadOnly,
+                _ => Mode::ReadWrite,
+            };
+            let mut txn = self.begin(mode)?;
+            log.txn = Some(txn.id());
+            let plan = query.plan(&txn, context, cache)?;
+            if explain {
+                log.plan = Some(plan.node.to_string());
             }
+            let r = plan.execute(&mut txn);
+            // 出错或者被取消的时候 已经执行的修改都不能留下
+            match r {
+                Ok(_) => txn.commit()?,
+                Err(_) => txn.rollback()?,
+            }
+            r
         };
+        // 表的定义变了 缓存的计划大多不能再使用
+        if r.is_ok() && cache::ddl(kind) {
+            cache.clear()?;
+        }
         r
     }
+
+    /// 只读事务中不能执行修改数据或者表定义的语句 在规划之前检查
+    fn check_writable(&self, kind: &str) -> Result<()> {
+        match &self.txn {
+            Some(txn) if !txn.mode().mutable() && writes(kind) => Err(Error::sql(
+                ErrorCode::ReadOnlyTransaction,
+                format!(
+                    "cannot execute {} in a read only transaction",
+                    kind.replace('_', " ").to_uppercase()
+                ),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// 计划缓存的key 规划的结果还和会话的设置有关
+    fn plan_key(&self, sql: &str) -> Option<String> {
+        let tokens = crate::sql::parser::normalize(sql).ok()?;
+        let settings = self.settings.iter().collect::<BTreeMap<_, _>>();
+        Some(format!("{} {} {:?} {}", self.admin, self.integer_division, settings, tokens))
+    }
+}
+
+#[cfg(test)]
+impl<E: Engine + 'static> SqlSession<E> {
+    /// 测试用 执行一条查询返回查到的行 不是查询的时候报错
+    pub(crate) fn query(&mut self, sql: &str) -> Result<Rows> {
+        match self.execute(sql)? {
+            ResultSet::Query { rows, .. } => Ok(rows),
+            r => Err(Error::Executor(format!("expect a query result get {:?}", r))),
+        }
+    }
+
+    /// 测试用 返回查询结果的列和行
+    pub(crate) fn query_columns(&mut self, sql: &str) -> Result<(Columns, Rows)> {
+        match self.execute(sql)? {
+            ResultSet::Query { columns, rows } => Ok((columns, rows)),
+            r => Err(Error::Executor(format!("expect a query result get {:?}", r))),
+        }
+    }
+
+    /// 测试用 返回语句的执行计划
+    pub(crate) fn explain(&mut self, sql: &str) -> Result<crate::sql::plan::Node> {
+        match self.execute(&format!("explain {}", sql))? {
+            ResultSet::Explain { plan, .. } => Ok(plan),
+            r => Err(Error::Executor(format!("expect a explain result get {:?}", r))),
+        }
+    }
+
+    /// 测试用 执行语句 返回执行计划和每个节点的统计
+    pub(crate) fn explain_analyze(
+        &mut self,
+        sql: &str,
+    ) -> Result<(crate::sql::plan::Node, Vec<crate::sql::execution::analyze::NodeStats>)> {
+        match self.execute(&format!("explain analyze {}", sql))? {
+            ResultSet::ExplainAnalyze { plan, stats } => Ok((plan, stats)),
+            r => Err(Error::Executor(format!("expect a explain result get {:?}", r))),
+        }
+    }
+
+    /// 测试用 开启事务 返回事务的id
+    pub(crate) fn begin_id(&mut self, sql: &str) -> Result<u64> {
+        match self.execute(sql)? {
+            ResultSet::Begin { id, .. } => Ok(id),
+            r => Err(Error::Executor(format!("expect a begin result get {:?}", r))),
+        }
+    }
+}
+
+/// 要规划的语句 计划缓存中有的时候不需要解析
+enum Prepared {
+    /// key 是缓存计划使用的key 不缓存的时候是None
+    Statement {
+        statement: Statement,
+        key: Option<String>,
+    },
+    Cached {
+        key: String,
+        plan: CachedPlan,
+        sql: String,
+    },
+}
+
+impl Prepared {
+    fn kind(&self) -> &'static str {
+        match self {
+            Self::Statement { statement, .. } => statement.kind(),
+            Self::Cached { plan, .. } => plan.kind,
+        }
+    }
+
+    /// 得到优化之后的计划 缓存的计划用到的表有变化的时候重新规划
+    fn plan<T: Transaction>(self, txn: &T, context: Context, cache: &PlanCache) -> Result<Plan> {
+        match self {
+            Self::Statement { statement, key } => {
+                let kind = statement.kind();
+                let plan = Planner::with_context(txn, context)
+                    .build_plan(statement)?
+                    .optimize(txn)?;
+                if let Some(key) = key {
+                    cache.record(false);
+                    cache.insert(key, CachedPlan::new(kind, plan.node.clone(), txn)?)?;
+                }
+                Ok(plan)
+            }
+            Self::Cached { plan, .. } if plan.valid(txn)? => {
+                cache.record(true);
+                Ok(Plan::new(plan.node))
+            }
+            Self::Cached { key, sql, .. } => {
+                let statement = Parser::new(&sql).parse()?;
+                let key = Some(key);
+                Self::Statement { statement, key }.plan(txn, context, cache)
+            }
+        }
+    }
 }
 
 /// status
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Status {
     pub mvcc: crate::storage::kv::mvcc::Status,
+    /// 每个表的行数
+    pub tables: BTreeMap<String, u64>,
+    /// 本节点的计划缓存
+    pub plans: PlanCacheStatus,
 }
+
+/// 监控指标
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Metrics {
+    pub mvcc: crate::storage::kv::mvcc::Metrics,
+    /// 本节点的计划缓存
+    pub plans: PlanCacheStatus,
+}
+
+/// 事务中看到的每个表的行数
+pub(super) fn table_rows<T: Transaction>(txn: &T) -> Result<BTreeMap<String, u64>> {
+    let mut tables = BTreeMap::new();
+    for table in txn.scan_tables()? {
+        // 不需要解码任何列
+        let rows = txn.scan(&table.name, None, Some(Vec::new()))?.len() as u64;
+        tables.insert(table.name, rows);
+    }
+    Ok(tables)
+}
+/// 行修改的类型
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum ChangeKind {
+    Insert,
+    Update,
+    Delete,
+    /// 清空了整个表 before和after都是None
+    Truncate,
+}
+
+/// 一个已经提交的行修改 同一个事务对同一行的多次修改合并成一个
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Change {
+    /// 提交的序号 同一个事务的修改序号相同
+    pub seq: u64,
+    pub txn: u64,
+    pub table: String,
+    pub kind: ChangeKind,
+    /// 修改之前的行 插入的时候是None
+    pub before: Option<Row>,
+    /// 修改之后的行 删除的时候是None
+    pub after: Option<Row>,
+}
+
+/// 一个事务提交的所有修改 副本按照提交的顺序重放
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Commit {
+    /// 提交的序号
+    pub seq: u64,
+    pub txn: u64,
+    pub changes: Vec<Change>,
+    /// 范围删除 [start, end) 在writes之前执行
+    pub ranges: Vec<(Vec<u8>, Vec<u8>)>,
+    /// 写入的key value 包括表结构和索引 值为None是删除
+    pub writes: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+}
+
 pub type SqlScan = Box<dyn DoubleEndedIterator<Item = Result<Row>> + Send>;
 pub type SqlIndexScan = Box<dyn DoubleEndedIterator<Item = Result<(Value, HashSet<Value>)>> + Send>;
@@ -0,0 +1,854 @@
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use futures::executor::block_on;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_derive::{Deserialize, Serialize as SerializeDerive};
+
+use super::cache::{PlanCache, DEFAULT_PLAN_CACHE_SIZE};
+use super::kv::KV;
+use super::{
+    Cancel, Commit, Engine, IndexRange, IndexScan, KvItems, Row, Rows, SqlScan, Transaction,
+    DEFAULT_WORK_MEMORY,
+};
+use crate::errors::*;
+use crate::raft;
+use crate::sql::expression::Expression;
+use crate::sql::schema::Catalog;
+use crate::sql::{Table, Value};
+use crate::storage::kv::mvcc::{Mode, VacuumStatus};
+
+/// 写入raft日志的修改操作 由每个节点的状态机执行
+#[derive(Debug, SerializeDerive, Deserialize)]
+enum Mutation {
+    Begin(Mode),
+    Commit(u64),
+    Rollback(u64),
+    /// 读已提交的事务刷新快照 刷新的快照需要保存 所以也要经过raft
+    Refresh(u64),
+    Savepoint {
+        txn_id: u64,
+        name: String,
+    },
+    RollbackToSavepoint {
+        txn_id: u64,
+        name: String,
+    },
+    ReleaseSavepoint {
+        txn_id: u64,
+        name: String,
+    },
+    Create {
+        txn_id: u64,
+        table: String,
+        row: Row,
+    },
+    CreateBatch {
+        txn_id: u64,
+        table: String,
+        rows: Rows,
+    },
+    Delete {
+        txn_id: u64,
+        table: String,
+        id: Value,
+    },
+    Update {
+        txn_id: u64,
+        table: String,
+        id: Value,
+        row: Row,
+    },
+    CreateTable {
+        txn_id: u64,
+        table: Table,
+    },
+    UpdateTable {
+        txn_id: u64,
+        table: Table,
+    },
+    DeleteTable {
+        txn_id: u64,
+        table: String,
+    },
+    Truncate {
+        txn_id: u64,
+        table: String,
+    },
+    Load {
+        txn_id: u64,
+        items: KvItems,
+    },
+    /// 垃圾回收会删除旧版本和快照 在每个节点上相同的日志位置执行 节点之间才能保持一致
+    Vacuum,
+}
+
+/// 只读操作 在接收请求的节点本地执行
+#[derive(Debug, SerializeDerive, Deserialize)]
+enum Query {
+    Resume(u64),
+    Read {
+        txn_id: u64,
+        table: String,
+        id: Value,
+    },
+    ReadIndex {
+        txn_id: u64,
+        table: String,
+        column: String,
+        value: Value,
+    },
+    Scan {
+        txn_id: u64,
+        table: String,
+        filter: Option<Expression>,
+        columns: Option<Vec<usize>>,
+        scan_batch_size: Option<usize>,
+    },
+    ScanIndex {
+        txn_id: u64,
+        table: String,
+        column: String,
+    },
+    ReadIndexRange {
+        txn_id: u64,
+        table: String,
+        column: String,
+        range: IndexRange,
+    },
+    ReadTable {
+        txn_id: u64,
+        table: String,
+    },
+    ScanTables {
+        txn_id: u64,
+    },
+    /// 过期的行在每个节点上按照本地时间判断 删除通过leader复制
+    Expired {
+        txn_id: u64,
+        table: String,
+    },
+    Dump {
+        txn_id: u64,
+        start: Option<Vec<u8>>,
+        limit: usize,
+    },
+    /// 提交日志在每个节点上按照raft日志的顺序写入 所以每个节点上都一样
+    Commits {
+        after: u64,
+        limit: usize,
+    },
+    LastCommit,
+    /// 只有存储的统计信息 在本地开启事务会让节点之间的事务号不一致
+    Status,
+    Metrics,
+    Ping,
+}
+
+/// 基于raft的sql引擎 修改通过leader复制到所有节点 读取在本地状态机上执行
+#[derive(Clone)]
+pub struct Raft {
+    client: raft::Client,
+    /// 本节点看到的最新的日志位置 读取之前本地状态机需要应用到这个位置
+    /// 保证能读到自己写入的数据
+    last_index: Arc<AtomicU64>,
+    /// 新事务中排序和聚合可以使用的内存 会话变量可以覆盖
+    work_memory: usize,
+    /// 新事务中连接和聚合可以使用的线程数 会话变量可以覆盖
+    workers: usize,
+    plans: Arc<PlanCache>,
+    /// 会话切换成管理员用的密码
+    admin_password: Option<String>,
+    /// 每个会话最多同时打开的游标数
+    max_cursors: usize,
+}
+
+impl Raft {
+    pub fn new(client: raft::Client) -> Self {
+        Self {
+            client,
+            last_index: Arc::new(AtomicU64::new(0)),
+            work_memory: DEFAULT_WORK_MEMORY,
+            workers: super::default_workers(),
+            plans: Arc::new(PlanCache::new(DEFAULT_PLAN_CACHE_SIZE)),
+            admin_password: None,
+            max_cursors: super::DEFAULT_MAX_CURSORS,
+        }
+    }
+
+    /// 设置计划缓存最多缓存的语句数 0的时候不缓存
+    pub fn with_plan_cache(mut self, capacity: usize) -> Self {
+        self.plans = Arc::new(PlanCache::new(capacity));
+        self
+    }
+
+    /// 设置管理员的密码 会话中 set admin = 密码 之后不受行级安全策略的限制
+    pub fn with_admin_password(mut self, password: Option<String>) -> Self {
+        self.admin_password = password;
+        self
+    }
+
+    /// 设置每个会话最多同时打开的游标数
+    pub fn with_max_cursors(mut self, max_cursors: usize) -> Self {
+        self.max_cursors = max_cursors;
+        self
+    }
+
+    /// 设置默认的排序和聚合可以使用的内存
+    pub fn with_work_memory(mut self, work_memory: usize) -> Self {
+        self.work_memory = work_memory;
+        self
+    }
+
+    /// 设置默认的连接和聚合可以使用的线程数
+    pub fn with_workers(mut self, workers: usize) -> Self {
+        self.workers = workers;
+        self
+    }
+
+    /// 创建raft状态机 所有的修改最后都会在这里执行
+    pub fn new_state(engine: KV) -> Result<State> {
+        State::new(engine)
+    }
+
+    fn mutate<V: DeserializeOwned>(&self, mutation: Mutation) -> Result<V> {
+        let (index, result) = block_on(self.client.mutate(serialize(&mutation)?))?;
+        self.last_index.fetch_max(index, Ordering::SeqCst);
+        deserialize(&result?)
+    }
+
+    fn query<V: DeserializeOwned>(&self, query: Query) -> Result<V> {
+        let min_index = self.last_index.load(Ordering::SeqCst);
+        deserialize(&block_on(self.client.query(serialize(&query)?, min_index))?)
+    }
+}
+
+impl Engine for Raft {
+    type Transaction = RaftTransaction;
+
+    fn begin(&self, mode: Mode) -> Result<Self::Transaction> {
+        let (id, mode) = self.mutate(Mutation::Begin(mode))?;
+        Ok(RaftTransaction::new(self.clone(), id, mode))
+    }
+
+    fn resume(&self, id: u64) -> Result<Self::Transaction> {
+        let mode = self.query(Query::Resume(id))?;
+        Ok(RaftTransaction::new(self.clone(), id, mode))
+    }
+
+    fn status(&self) -> Result<super::Status> {
+        // 在状态机上本地开启事务会让这个节点的事务号和其他节点不一致 行数在经过raft的事务中统计
+        let mvcc = self.query(Query::Status)?;
+        let txn = self.begin(Mode::ReadOnly)?;
+        let tables = super::table_rows(&txn)?;
+        txn.commit()?;
+        // 状态机所在的引擎没有执行过语句 计划缓存是本节点的
+        Ok(super::Status {
+            mvcc,
+            tables,
+            plans: self.plans.status()?,
+        })
+    }
+
+    fn metrics(&self) -> Result<super::Metrics> {
+        Ok(super::Metrics {
+            mvcc: self.query(Query::Metrics)?,
+            plans: self.plans.status()?,
+        })
+    }
+
+    fn plan_cache(&self) -> &PlanCache {
+        &self.plans
+    }
+
+    fn admin_password(&self) -> Option<&str> {
+        self.admin_password.as_deref()
+    }
+
+    fn max_cursors(&self) -> usize {
+        self.max_cursors
+    }
+
+    fn commits(&self, after: u64, limit: usize) -> Result<Vec<Commit>> {
+        self.query(Query::Commits { after, limit })
+    }
+
+    fn last_commit(&self) -> Result<u64> {
+        self.query(Query::LastCommit)
+    }
+
+    fn ping(&self) -> Result<()> {
+        self.query(Query::Ping)
+    }
+
+    fn raft_status(&self) -> Result<Option<raft::Status>> {
+        Ok(Some(block_on(self.client.status())?))
+    }
+
+    fn vacuum(&self) -> Result<VacuumStatus> {
+        self.mutate(Mutation::Vacuum)
+    }
+}
+
+/// 基于raft的sql事务 每个操作都交给raft执行
+pub struct RaftTransaction {
+    raft: Raft,
+    id: u64,
+    mode: Mode,
+    /// 会话设置的扫描批大小 随着扫描请求一起发送
+    scan_batch_size: Option<usize>,
+    /// 排序和聚合在本地执行 不需要交给raft
+    work_memory: usize,
+    workers: usize,
+    /// 当前语句的取消标记 只在本地检查 已经交给raft的操作不能取消
+    cancel: Cancel,
+}
+
+impl RaftTransaction {
+    fn new(raft: Raft, id: u64, mode: Mode) -> Self {
+        let (work_memory, workers) = (raft.work_memory, raft.workers);
+        Self {
+            raft,
+            id,
+            mode,
+            scan_batch_size: None,
+            work_memory,
+            workers,
+            cancel: Cancel::default(),
+        }
+    }
+}
+
+impl Transaction for RaftTransaction {
+    fn id(&self) -> u64 {
+        self.id
+    }
+
+    fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    fn set_scan_batch_size(&mut self, size: usize) {
+        self.scan_batch_size = Some(size);
+    }
+
+    fn set_work_memory(&mut self, size: usize) {
+        self.work_memory = size;
+    }
+
+    fn work_memory(&self) -> usize {
+        self.work_memory
+    }
+
+    fn set_workers(&mut self, workers: usize) {
+        self.workers = workers;
+    }
+
+    fn workers(&self) -> usize {
+        self.workers
+    }
+
+    /// raft的状态机按顺序执行每个操作 不能阻塞在等待锁上 所以写冲突总是直接报错
+    fn set_lock_timeout(&mut self, _timeout: std::time::Duration) {}
+
+    /// 状态机每个操作都重新恢复事务 没有地方累计写入量 所以不限制
+    fn set_write_limits(&mut self, _rows: Option<u64>, _bytes: Option<u64>) {}
+
+    fn set_cancel(&mut self, cancel: Cancel) {
+        self.cancel = cancel;
+    }
+
+    fn canceller(&self) -> Cancel {
+        self.cancel.clone()
+    }
+
+    fn check_cancel(&self) -> Result<()> {
+        self.cancel.check()
+    }
+
+    fn refresh(&mut self) -> Result<()> {
+        if self.mode != Mode::ReadCommitted {
+            return Ok(());
+        }
+        self.raft.mutate(Mutation::Refresh(self.id))
+    }
+
+    /// 状态机只能按照事务id读取还没有结束的事务 所以没有只读副本
+    fn reader(&self) -> Result<Option<Self>> {
+        Ok(None)
+    }
+
+    fn commit(self) -> Result<()> {
+        self.raft.mutate(Mutation::Commit(self.id))
+    }
+
+    fn rollback(self) -> Result<()> {
+        self.raft.mutate(Mutation::Rollback(self.id))
+    }
+
+    fn savepoint(&mut self, name: &str) -> Result<()> {
+        self.raft.mutate(Mutation::Savepoint {
+            txn_id: self.id,
+            name: name.to_string(),
+        })
+    }
+
+    fn rollback_to_savepoint(&mut self, name: &str) -> Result<()> {
+        self.raft.mutate(Mutation::RollbackToSavepoint {
+            txn_id: self.id,
+            name: name.to_string(),
+        })
+    }
+
+    fn release_savepoint(&mut self, name: &str) -> Result<()> {
+        self.raft.mutate(Mutation::ReleaseSavepoint {
+            txn_id: self.id,
+            name: name.to_string(),
+        })
+    }
+
+    fn create(&mut self, table: &str, row: Row) -> Result<()> {
+        self.raft.mutate(Mutation::Create {
+            txn_id: self.id,
+            table: table.to_string(),
+            row,
+        })
+    }
+
+    fn create_batch(&mut self, table: &str, rows: Rows) -> Result<()> {
+        self.raft.mutate(Mutation::CreateBatch {
+            txn_id: self.id,
+            table: table.to_string(),
+            rows,
+        })
+    }
+
+    fn delete(&mut self, table: &str, id: &Value) -> Result<()> {
+        self.raft.mutate(Mutation::Delete {
+            txn_id: self.id,
+            table: table.to_string(),
+            id: id.clone(),
+        })
+    }
+
+    fn truncate(&mut self, table: &str) -> Result<u64> {
+        self.raft.mutate(Mutation::Truncate {
+            txn_id: self.id,
+            table: table.to_string(),
+        })
+    }
+
+    fn expired(&self, table: &str) -> Result<Vec<Value>> {
+        self.raft.query(Query::Expired {
+            txn_id: self.id,
+            table: table.to_string(),
+        })
+    }
+
+    fn dump(&self, start: Option<&[u8]>, limit: usize) -> Result<KvItems> {
+        self.raft.query(Query::Dump {
+            txn_id: self.id,
+            start: start.map(|s| s.to_vec()),
+            limit,
+        })
+    }
+
+    fn load(&mut self, items: KvItems) -> Result<()> {
+        self.raft.mutate(Mutation::Load {
+            txn_id: self.id,
+            items,
+        })
+    }
+
+    fn read(&self, table: &str, id: &Value) -> Result<Option<Row>> {
+        self.raft.query(Query::Read {
+            txn_id: self.id,
+            table: table.to_string(),
+            id: id.clone(),
+        })
+    }
+
+    fn read_index(&self, table: &str, column: &str, value: &Value) -> Result<HashSet<Value>> {
+        self.raft.query(Query::ReadIndex {
+            txn_id: self.id,
+            table: table.to_string(),
+            column: column.to_string(),
+            value: value.clone(),
+        })
+    }
+
+    fn scan(
+        &self,
+        table: &str,
+        filter: Option<Expression>,
+        columns: Option<Vec<usize>>,
+    ) -> Result<Rows> {
+        self.cancel.check()?;
+        self.raft.query(Query::Scan {
+            txn_id: self.id,
+            table: table.to_string(),
+            filter,
+            columns,
+            scan_batch_size: self.scan_batch_size,
+        })
+    }
+
+    /// 读取都要经过状态机 没办法在本地持有迭代器 一次读出所有的行再切分
+    /// 状态机的查询一次返回所有的行
+    fn scan_iter(
+        &self,
+        table: &str,
+        filter: Option<Expression>,
+        columns: Option<Vec<usize>>,
+    ) -> Result<SqlScan> {
+        Ok(Box::new(self.scan(table, filter, columns)?.into_iter().map(Ok)))
+    }
+
+    fn scan_partitions(&self, table: &str, n: usize) -> Result<Vec<SqlScan>> {
+        let rows = self.scan(table, None, None)?;
+        let size = rows.len().div_ceil(n.max(1)).max(1);
+        let mut rows = rows.into_iter().peekable();
+        let mut partitions: Vec<SqlScan> = Vec::new();
+        while partitions.is_empty() || rows.peek().is_some() {
+            let partition = rows.by_ref().take(size).map(Ok).collect::<Vec<_>>();
+            partitions.push(Box::new(partition.into_iter()));
+        }
+        Ok(partitions)
+    }
+
+    fn scan_index(&self, table: &str, column: &str) -> Result<IndexScan> {
+        self.raft.query(Query::ScanIndex {
+            txn_id: self.id,
+            table: table.to_string(),
+            column: column.to_string(),
+        })
+    }
+
+    fn read_index_range(&self, table: &str, column: &str, range: IndexRange) -> Result<IndexScan> {
+        self.raft.query(Query::ReadIndexRange {
+            txn_id: self.id,
+            table: table.to_string(),
+            column: column.to_string(),
+            range,
+        })
+    }
+
+    fn update(&mut self, table: &str, id: &Value, row: Row) -> Result<()> {
+        self.raft.mutate(Mutation::Update {
+            txn_id: self.id,
+            table: table.to_string(),
+            id: id.clone(),
+            row,
+        })
+    }
+}
+
+impl Catalog for RaftTransaction {
+    fn create_table(&mut self, table: Table) -> Result<()> {
+        self.raft.mutate(Mutation::CreateTable {
+            txn_id: self.id,
+            table,
+        })
+    }
+
+    fn update_table(&mut self, table: Table) -> Result<()> {
+        self.raft.mutate(Mutation::UpdateTable {
+            txn_id: self.id,
+            table,
+        })
+    }
+
+    fn delete_table(&mut self, table: &str) -> Result<()> {
+        self.raft.mutate(Mutation::DeleteTable {
+            txn_id: self.id,
+            table: table.to_string(),
+        })
+    }
+
+    fn read_table(&self, table: &str) -> Result<Option<Table>> {
+        self.raft.query(Query::ReadTable {
+            txn_id: self.id,
+            table: table.to_string(),
+        })
+    }
+
+    fn scan_tables(&self) -> Result<Vec<Table>> {
+        self.raft.query(Query::ScanTables { txn_id: self.id })
+    }
+}
+
+/// raft 状态机 把日志中的操作交给本地的kv引擎执行
+pub struct State {
+    engine: KV,
+    /// 已经应用的日志位置 和数据保存在同一个存储中
+    applied_index: u64,
+}
+
+impl State {
+    const APPLIED_INDEX: &'static [u8] = b"applied_index";
+
+    fn new(engine: KV) -> Result<Self> {
+        let applied_index = match engine.get_metadata(Self::APPLIED_INDEX)? {
+            Some(v) => deserialize(&v)?,
+            None => 0,
+        };
+        Ok(Self {
+            engine,
+            applied_index,
+        })
+    }
+
+    fn apply(&self, mutation: Mutation) -> Result<Vec<u8>> {
+        match mutation {
+            Mutation::Begin(mode) => {
+                let txn = self.engine.begin(mode)?;
+                serialize(&(txn.id(), txn.mode()))
+            }
+            Mutation::Commit(txn_id) => serialize(&self.engine.resume(txn_id)?.commit()?),
+            Mutation::Rollback(txn_id) => serialize(&self.engine.resume(txn_id)?.rollback()?),
+            Mutation::Refresh(txn_id) => serialize(&self.engine.resume(txn_id)?.refresh()?),
+            Mutation::Savepoint { txn_id, name } => {
+                serialize(&self.engine.resume(txn_id)?.savepoint(&name)?)
+            }
+            Mutation::RollbackToSavepoint { txn_id, name } => {
+                serialize(&self.engine.resume(txn_id)?.rollback_to_savepoint(&name)?)
+            }
+            Mutation::ReleaseSavepoint { txn_id, name } => {
+                serialize(&self.engine.resume(txn_id)?.release_savepoint(&name)?)
+            }
+            Mutation::Create { txn_id, table, row } => {
+                serialize(&self.engine.resume(txn_id)?.create(&table, row)?)
+            }
+            Mutation::CreateBatch {
+                txn_id,
+                table,
+                rows,
+            } => serialize(&self.engine.resume(txn_id)?.create_batch(&table, rows)?),
+            Mutation::Delete { txn_id, table, id } => {
+                serialize(&self.engine.resume(txn_id)?.delete(&table, &id)?)
+            }
+            Mutation::Update {
+                txn_id,
+                table,
+                id,
+                row,
+            } => serialize(&self.engine.resume(txn_id)?.update(&table, &id, row)?),
+            Mutation::CreateTable { txn_id, table } => {
+                serialize(&self.engine.resume(txn_id)?.create_table(table)?)
+            }
+            Mutation::UpdateTable { txn_id, table } => {
+                serialize(&self.engine.resume(txn_id)?.update_table(table)?)
+            }
+            Mutation::DeleteTable { txn_id, table } => {
+                serialize(&self.engine.resume(txn_id)?.delete_table(&table)?)
+            }
+            Mutation::Truncate { txn_id, table } => {
+                serialize(&self.engine.resume(txn_id)?.truncate(&table)?)
+            }
+            Mutation::Load { txn_id, items } => {
+                serialize(&self.engine.resume(txn_id)?.load(items)?)
+            }
+            Mutation::Vacuum => serialize(&self.engine.vacuum()?),
+        }
+    }
+}
+
+impl raft::State for State {
+    fn applied_index(&self) -> u64 {
+        self.applied_index
+    }
+
+    fn mutate(&mut self, index: u64, command: Vec<u8>) -> Result<Vec<u8>> {
+        // 执行出错也是确定的结果 同样需要记录位置
+        let result = deserialize(&command).and_then(|m| self.apply(m));
+        self.applied_index = index;
+        self.engine
+            .set_metadata(Self::APPLIED_INDEX, serialize(&index)?)?;
+        result
+    }
+
+    fn query(&self, command: Vec<u8>) -> Result<Vec<u8>> {
+        match deserialize(&command)? {
+            Query::Resume(txn_id) => serialize(&self.engine.resume(txn_id)?.mode()),
+            Query::Read { txn_id, table, id } => {
+                serialize(&self.engine.resume(txn_id)?.read(&table, &id)?)
+            }
+            Query::ReadIndex {
+                txn_id,
+                table,
+                column,
+                value,
+            } => serialize(
+                &self
+                    .engine
+                    .resume(txn_id)?
+                    .read_index(&table, &column, &value)?,
+            ),
+            Query::Scan {
+                txn_id,
+                table,
+                filter,
+                columns,
+                scan_batch_size,
+            } => {
+                let mut txn = self.engine.resume(txn_id)?;
+                if let Some(size) = scan_batch_size {
+                    txn.set_scan_batch_size(size);
+                }
+                serialize(&txn.scan(&table, filter, columns)?)
+            }
+            Query::ScanIndex {
+                txn_id,
+                table,
+                column,
+            } => serialize(&self.engine.resume(txn_id)?.scan_index(&table, &column)?),
+            Query::ReadIndexRange {
+                txn_id,
+                table,
+                column,
+                range,
+            } => serialize(
+                &self
+                    .engine
+                    .resume(txn_id)?
+                    .read_index_range(&table, &column, range)?,
+            ),
+            Query::ReadTable { txn_id, table } => {
+                serialize(&self.engine.resume(txn_id)?.read_table(&table)?)
+            }
+            Query::ScanTables { txn_id } => serialize(&self.engine.resume(txn_id)?.scan_tables()?),
+            Query::Expired { txn_id, table } => {
+                serialize(&self.engine.resume(txn_id)?.expired(&table)?)
+            }
+            Query::Dump {
+                txn_id,
+                start,
+                limit,
+            } => serialize(&self.engine.resume(txn_id)?.dump(start.as_deref(), limit)?),
+            Query::Commits { after, limit } => serialize(&self.engine.commits(after, limit)?),
+            Query::LastCommit => serialize(&self.engine.last_commit()?),
+            Query::Status => serialize(&self.engine.kv.get_status()?),
+            Query::Metrics => serialize(&self.engine.kv.get_metrics()?),
+            Query::Ping => serialize(&self.engine.ping()?),
+        }
+    }
+}
+
+fn serialize<V: Serialize>(value: &V) -> Result<Vec<u8>> {
+    Ok(bincode::serialize(value)?)
+}
+
+fn deserialize<V: DeserializeOwned>(bytes: &[u8]) -> Result<V> {
+    Ok(bincode::deserialize(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::sql::engine::kv::test_engine;
+    use crate::storage::kv::b_tree::BtreeStore;
+
+    /// 等到大家都认同同一个leader
+    async fn wait_leader(engines: &HashMap<String, Raft>) -> Result<String> {
+        for _ in 0..100 {
+            let mut leaders = HashSet::new();
+            for engine in engines.values() {
+                leaders.insert(engine.client.status().await?.leader);
+            }
+            if let (1, Some(Some(leader))) = (leaders.len(), leaders.into_iter().next()) {
+                return Ok(leader);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        Err(Error::Internal("no leader elected".into()))
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cluster_test() -> Result<()> {
+        let ids = ["a", "b", "c"].map(String::from);
+        let mut listeners = vec![];
+        let mut addrs = HashMap::new();
+        for id in &ids {
+            let listener = TcpListener::bind("127.0.0.1:0").await?;
+            addrs.insert(id.clone(), listener.local_addr()?.to_string());
+            listeners.push(listener);
+        }
+        let mut engines = HashMap::new();
+        let mut handles = HashMap::new();
+        for (id, listener) in ids.iter().zip(listeners) {
+            let peers = addrs
+                .iter()
+                .filter(|(peer, _)| *peer != id)
+                .map(|(peer, addr)| (peer.clone(), addr.clone()))
+                .collect();
+            let state = Raft::new_state(test_engine())?;
+            let log = raft::Log::new(Box::new(BtreeStore::new()))?;
+            let server = raft::Server::new(id, peers, log, Box::new(state))?;
+            engines.insert(id.clone(), Raft::new(server.client()));
+            handles.insert(id.clone(), tokio::spawn(server.serve(listener)));
+        }
+
+        let leader = wait_leader(&engines).await?;
+        let followers = ids
+            .iter()
+            .filter(|id| **id != leader)
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // 在follower上写 在另一个follower上读
+        let (writer, reader) = (
+            engines[&followers[0]].clone(),
+            engines[&followers[1]].clone(),
+        );
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut session = writer.session()?;
+            session.execute("create table t ( id int primary key, v string );")?;
+            session.execute("insert into t values (1, \"a\"), (2, \"b\");")?;
+            // 等 reader 的状态机追上
+            reader
+                .last_index
+                .fetch_max(writer.last_index.load(Ordering::SeqCst), Ordering::SeqCst);
+            assert_eq!(reader.session()?.query("select id from t;")?.len(), 2);
+
+            // vacuum 经过raft 每个节点上删除同样的旧版本
+            session.execute("update t set v = \"x\";")?;
+            let status = writer.vacuum()?;
+            assert_eq!(status.runs, 1);
+            assert!(status.versions > 0);
+            reader
+                .last_index
+                .fetch_max(writer.last_index.load(Ordering::SeqCst), Ordering::SeqCst);
+            assert_eq!(reader.status()?.mvcc.vacuum, status);
+            Ok(())
+        })
+        .await??;
+
+        // leader 挂掉之后 剩下的两个节点可以选出新的leader 继续写
+        handles[&leader].abort();
+        engines.remove(&leader);
+        let writer = engines[&followers[0]].clone();
+        tokio::task::spawn_blocking(move || -> Result<()> {
+            let mut session = writer.session()?;
+            let mut result = Err(Error::Internal("no retry".into()));
+            for _ in 0..50 {
+                result = session.execute("insert into t values (3, \"c\");");
+                if result.is_ok() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(200));
+            }
+            result?;
+            assert_eq!(writer.session()?.query("select id from t;")?.len(), 3);
+            Ok(())
+        })
+        .await??;
+        assert_ne!(wait_leader(&engines).await?, leader);
+        Ok(())
+    }
+}
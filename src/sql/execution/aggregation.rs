@@ -1,104 +1,322 @@
+use std::any::Any;
 use std::cmp::Ordering;
+use std::collections::hash_map::{DefaultHasher, Entry};
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 
-use super::{Executor, ResultSet};
+use super::parallel::{map_chunks, PARALLEL_MIN_ROWS};
+use super::{collect, Batches, Column, Columns, Executor, ResultSet, CANCEL_CHECK_ROWS};
 use crate::errors::*;
-use crate::sql::execution::source;
-use crate::sql::{engine::Transaction, plan::Aggregate, Value};
+use crate::sql::engine::{Cancel, Row};
+use crate::sql::{decimal::Decimal, engine::Transaction, plan::Aggregate, ColumnType, Value};
+use crate::storage::spill::{SpillFile, SpillRun};
+
+/// 内存不够的时候 新的分组写到这么多个分区文件中
+const SPILL_PARTITIONS: usize = 16;
+/// 分区之后还是放不下会继续分区 超过这个深度就不再限制内存
+const MAX_SPILL_DEPTH: usize = 8;
+/// 估算的每个计算器占用的内存
+const ACCUMULATOR_SIZE: usize = 64;
+
+type Groups = HashMap<Vec<Value>, Vec<Box<dyn Accumulator>>>;
 
 pub struct Aggregation<T: Transaction> {
     source: Box<dyn Executor<T>>,
     aggregates: Vec<Aggregate>,
-    // 记录group by的字段
-    accumulators: HashMap<Vec<Value>, Vec<Box<dyn Accumulator>>>,
 }
 impl<T: Transaction> Executor<T> for Aggregation<T> {
-    fn execute(mut self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
-        let aggre_size = self.aggregates.len();
-        match self.source.execute(txn)? {
-            ResultSet::Query { columns, rows } => {
-                let mut rows = rows.into_iter();
-                while let Some(mut row) = rows.next() {
-                    // 为group by的字段设置为key value是其计算器
-                    // 例如
-                    // group by name
-                    // 那么key 有可能是 xiaoming  或者 xiaohong
-                    // 如果我们需要count  那么value就会有count计算器
-                    // xiaoming对应的value就会记录一共有多少个name=xiaoming
-                    let accumulators = self
-                        .accumulators
-                        .entry(row.split_off(aggre_size))
-                        .or_insert(
-                            self.aggregates
-                                .iter()
-                                .map(|v| <dyn Accumulator>::new(v))
-                                .collect(),
-                        );
-                    // 我们在执行 aggregation 之前 已经做过映射了 所以 数据情况应该是
-                    // count      sum   group by (1)
-                    // xiaoming   age   xiaoming
-                    // 也就说 第n个计算器 去row中的第n个数据拿取计算即可
-                    accumulators
-                        .iter_mut()
-                        .zip(row)
-                        .try_for_each(|(a, v)| a.accumulate(&v))?;
-                }
-                // 考虑数据有可能为空
-                // 例如 select count(*) from some where 1=2;
-                // 或者本身没有group by的情况
-                if self.accumulators.is_empty() && self.aggregates.len() == columns.len() {
-                    self.accumulators.insert(
-                        Vec::new(),
-                        self.aggregates
-                            .iter()
-                            .map(|agg| <dyn Accumulator>::new(agg))
-                            .collect(),
-                    );
-                }
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        let (columns, batches) = self.execute_batches(txn)?;
+        collect(columns, batches)
+    }
 
-                let columns: Vec<Option<String>> = columns
-                    .into_iter()
-                    .enumerate()
-                    // 聚合操作column是null, group_by保持原来的标签
-                    .map(|(i, c)| {
-                        if i < aggre_size {
-                            self.aggregates.get(i).map(|m| m.to_string())
-                        } else {
-                            c
+    /// 读完输入之后先返回内存中的分组 之后每次聚合一个分区文件再返回
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        let Self { source, aggregates } = *self;
+        let (columns, mut batches) = source.execute_batches(txn)?;
+        let memory = txn.work_memory();
+        let cancel = txn.canceller();
+        // 输入放得进内存的时候才能分给多个线程 超过之后剩下的一边读一边聚合
+        let mut rows = Vec::new();
+        let mut size = 0;
+        if txn.workers() > 1 {
+            while size <= memory {
+                match batches.next() {
+                    Some(batch) => {
+                        let batch = batch?;
+                        for row in batch.iter() {
+                            size += bincode::serialized_size(row)? as usize;
                         }
-                    })
-                    .collect();
-                let rows = self
-                    .accumulators
-                    .into_iter()
-                    .map(|(gb, ac)| {
-                        let mut row = Vec::new();
-                        let r1 = ac.into_iter().map(|a| a.aggregate()).collect::<Vec<_>>();
-                        row.extend(r1);
-                        row.extend(gb);
-                        row
-                    })
-                    .collect::<Vec<_>>();
-
-                Ok(ResultSet::Query { columns, rows })
-            }
-            e => {
-                return Err(Error::Executor(format!(
-                    "expect get a query set but get {:#?}",
-                    e
-                )))
+                        rows.extend(batch);
+                    }
+                    None => break,
+                }
             }
         }
+        let parallel = if size <= memory {
+            aggregate_parallel(&aggregates, &*txn, &rows)?
+        } else {
+            None
+        };
+        let (groups, partitions) = match parallel {
+            Some(groups) => (groups, Vec::new()),
+            None => {
+                let mut input = rows
+                    .into_iter()
+                    .map(Ok)
+                    .chain(batches.flat_map(|batch| match batch {
+                        Ok(batch) => batch.into_iter().map(Ok).collect::<Vec<_>>(),
+                        Err(e) => vec![Err(e)],
+                    }));
+                aggregate(&aggregates, memory, &cancel, &mut input, 0)?
+            }
+        };
+        // 考虑数据有可能为空
+        // 例如 select count(*) from some where 1=2;
+        // 或者本身没有group by的情况
+        let empty = if groups.is_empty() && aggregates.len() == columns.len() {
+            Some(aggregates.iter().map(|agg| <dyn Accumulator>::new(agg).aggregate()).collect())
+        } else {
+            None
+        };
+
+        let columns: Vec<Column> = columns
+            .into_iter()
+            .enumerate()
+            // 聚合操作column是null, group_by保持原来的标签
+            .map(|(i, c)| {
+                // 聚合结果命名为 函数名(被聚合的列)
+                let name = |aggregate: &Aggregate| {
+                    Some(format!(
+                        "{}({})",
+                        aggregate.to_string().to_lowercase(),
+                        c.name.as_deref().unwrap_or("?")
+                    ))
+                };
+                match aggregates.get(i) {
+                    // count 一定有值 其他聚合和输入的类型一样 没有数据的时候是null
+                    Some(Aggregate::Count) => Column {
+                        name: name(&Aggregate::Count),
+                        column_type: Some(ColumnType::Integer),
+                        nullable: false,
+                    },
+                    // 方差和标准差一定是浮点数 少于两个值的时候是null
+                    Some(aggregate @ (Aggregate::StdDev | Aggregate::Variance)) => Column {
+                        name: name(aggregate),
+                        column_type: Some(ColumnType::Float),
+                        nullable: true,
+                    },
+                    Some(aggregate) => Column {
+                        name: name(aggregate),
+                        column_type: c.column_type.clone(),
+                        nullable: true,
+                    },
+                    None => c,
+                }
+            })
+            .collect();
+
+        let rows = Spilled {
+            aggregates,
+            memory,
+            cancel,
+            current: Box::new(finish(groups).chain(empty)),
+            partitions: partitions.into_iter().rev().map(|p| (1, p)).collect(),
+        };
+        Ok((columns, super::stream(rows)))
     }
 }
 
 impl<T: Transaction> Aggregation<T> {
     pub fn new(source: Box<dyn Executor<T>>, aggregates: Vec<Aggregate>) -> Box<Self> {
-        Box::new(Self {
-            source,
-            aggregates,
-            accumulators: HashMap::new(),
-        })
+        Box::new(Self { source, aggregates })
+    }
+}
+
+/// 分组在第depth层分区时属于哪个分区 每一层的hash不一样 保证可以继续拆分
+fn partition(key: &[Value], depth: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    depth.hash(&mut hasher);
+    key.hash(&mut hasher);
+    hasher.finish() as usize % SPILL_PARTITIONS
+}
+
+/// 聚合结果在前 group by的字段在后
+fn finish(groups: Groups) -> impl Iterator<Item = Row> {
+    groups.into_iter().map(|(gb, ac)| {
+        let mut row = ac.into_iter().map(|a| a.aggregate()).collect::<Vec<_>>();
+        row.extend(gb);
+        row
+    })
+}
+
+/// 每个线程聚合一段数据 最后合并每个线程中相同分组的计算器
+/// 只有一个线程或者数据太少 或者某个线程的分组超过了分到的内存时返回None 交给单线程的聚合
+fn aggregate_parallel<T: Transaction>(
+    aggregates: &[Aggregate],
+    txn: &T,
+    rows: &[Row],
+) -> Result<Option<Groups>> {
+    let workers = txn.workers();
+    if workers <= 1 || rows.len() < PARALLEL_MIN_ROWS {
+        return Ok(None);
+    }
+    let memory = txn.work_memory() / workers;
+    let cancel = txn.canceller();
+    let partials = map_chunks(rows, workers, |_, chunk| {
+        let mut groups = Groups::new();
+        let mut used = 0;
+        for (i, row) in chunk.iter().enumerate() {
+            if i % CANCEL_CHECK_ROWS == 0 {
+                cancel.check()?;
+            }
+            let (values, key) = row.split_at(aggregates.len());
+            let accumulators = match groups.get_mut(key) {
+                Some(accumulators) => accumulators,
+                None => {
+                    used += bincode::serialized_size(key)? as usize
+                        + aggregates.len() * ACCUMULATOR_SIZE;
+                    if used > memory {
+                        return Ok(None);
+                    }
+                    let accumulators = aggregates.iter().map(|v| <dyn Accumulator>::new(v));
+                    groups.entry(key.to_vec()).or_insert(accumulators.collect())
+                }
+            };
+            accumulators
+                .iter_mut()
+                .zip(values)
+                .try_for_each(|(a, v)| a.accumulate(v))?;
+        }
+        Ok(Some(groups))
+    })?;
+
+    let mut result = Groups::new();
+    for partial in partials {
+        let partial = match partial {
+            Some(partial) => partial,
+            None => return Ok(None),
+        };
+        for (key, accumulators) in partial {
+            match result.entry(key) {
+                Entry::Occupied(mut entry) => {
+                    for (a, other) in entry.get_mut().iter_mut().zip(accumulators) {
+                        a.merge(other.as_ref())?;
+                    }
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(accumulators);
+                }
+            }
+        }
+    }
+    Ok(Some(result))
+}
+
+/// 聚合输入的所有行 返回内存中的分组和还没有聚合的分区文件
+/// 分组占用的内存超过 work_memory 之后 已经在内存中的分组继续计算
+/// 新的分组按照hash写到分区文件 内存中的分组先输出 之后再逐个聚合分区
+/// 同一个分组的数据只会出现在一个分区中
+fn aggregate(
+    aggregates: &[Aggregate],
+    memory: usize,
+    cancel: &Cancel,
+    rows: &mut dyn Iterator<Item = Result<Row>>,
+    depth: usize,
+) -> Result<(Groups, Vec<SpillRun<Row>>)> {
+    // 记录group by的字段
+    let mut groups = Groups::new();
+    let mut used = 0;
+    let mut partitions: Vec<SpillFile<Row>> = Vec::new();
+    for (i, row) in rows.enumerate() {
+        if i % CANCEL_CHECK_ROWS == 0 {
+            cancel.check()?;
+        }
+        let mut row = row?;
+        // 为group by的字段设置为key value是其计算器
+        // 例如
+        // group by name
+        // 那么key 有可能是 xiaoming  或者 xiaohong
+        // 如果我们需要count  那么value就会有count计算器
+        // xiaoming对应的value就会记录一共有多少个name=xiaoming
+        let accumulators = match groups.entry(row.split_off(aggregates.len())) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) if used > memory && depth < MAX_SPILL_DEPTH => {
+                if partitions.is_empty() {
+                    for _ in 0..SPILL_PARTITIONS {
+                        partitions.push(SpillFile::new(&std::env::temp_dir())?);
+                    }
+                }
+                let key = entry.into_key();
+                let index = partition(&key, depth);
+                row.extend(key);
+                partitions[index].write(&row)?;
+                continue;
+            }
+            Entry::Vacant(entry) => {
+                used += bincode::serialized_size(entry.key())? as usize
+                    + aggregates.len() * ACCUMULATOR_SIZE;
+                entry.insert(aggregates.iter().map(|v| <dyn Accumulator>::new(v)).collect())
+            }
+        };
+        // 我们在执行 aggregation 之前 已经做过映射了 所以 数据情况应该是
+        // count      sum   group by (1)
+        // xiaoming   age   xiaoming
+        // 也就说 第n个计算器 去row中的第n个数据拿取计算即可
+        accumulators
+            .iter_mut()
+            .zip(row)
+            .try_for_each(|(a, v)| a.accumulate(&v))?;
+    }
+
+    let partitions = partitions
+        .into_iter()
+        .filter(|p| !p.is_empty())
+        .map(|p| p.finish())
+        .collect::<Result<Vec<_>>>()?;
+    Ok((groups, partitions))
+}
+
+/// 逐个返回聚合的结果 当前的分组返回完之后再聚合下一个分区文件
+/// 分区文件写完就关闭 聚合的时候才打开
+struct Spilled {
+    aggregates: Vec<Aggregate>,
+    memory: usize,
+    cancel: Cancel,
+    current: Box<dyn Iterator<Item = Row> + Send>,
+    /// 还没有聚合的分区和它的深度 最后一个先聚合
+    partitions: Vec<(usize, SpillRun<Row>)>,
+}
+
+impl Spilled {
+    fn aggregate_next(&mut self) -> Result<bool> {
+        let (depth, partition) = match self.partitions.pop() {
+            Some(partition) => partition,
+            None => return Ok(false),
+        };
+        let mut rows = partition.into_reader()?;
+        let (groups, partitions) =
+            aggregate(&self.aggregates, self.memory, &self.cancel, &mut rows, depth)?;
+        self.current = Box::new(finish(groups));
+        self.partitions.extend(partitions.into_iter().rev().map(|p| (depth + 1, p)));
+        Ok(true)
+    }
+}
+
+impl Iterator for Spilled {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(row) = self.current.next() {
+                return Some(Ok(row));
+            }
+            match self.aggregate_next() {
+                Ok(true) => continue,
+                Ok(false) => return None,
+                Err(e) => return Some(Err(e)),
+            }
+        }
     }
 }
 
@@ -107,8 +325,21 @@ pub trait Accumulator: std::fmt::Debug + Send {
     // 放入一个值
     fn accumulate(&mut self, value: &Value) -> Result<()>;
 
+    // 合并另一个线程中同一种计算器的结果
+    fn merge(&mut self, other: &dyn Accumulator) -> Result<()>;
+
     // 最终值结果的计算
     fn aggregate(&self) -> Value;
+
+    fn as_any(&self) -> &dyn Any;
+}
+
+/// 合并的时候取出另一个计算器 类型不一样说明是不同的聚合
+fn downcast<A: 'static>(other: &dyn Accumulator) -> Result<&A> {
+    other
+        .as_any()
+        .downcast_ref()
+        .ok_or_else(|| Error::Executor(format!("can not merge accumulator {:?}", other)))
 }
 
 impl dyn Accumulator {
@@ -119,6 +350,8 @@ impl dyn Accumulator {
             Aggregate::Max => Box::new(Max::new()),
             Aggregate::Min => Box::new(Min::new()),
             Aggregate::Sum => Box::new(Sum::new()),
+            Aggregate::StdDev => Box::new(Variance::new(true)),
+            Aggregate::Variance => Box::new(Variance::new(false)),
         }
     }
 }
@@ -146,9 +379,18 @@ impl Accumulator for Count {
         Ok(())
     }
 
+    fn merge(&mut self, other: &dyn Accumulator) -> Result<()> {
+        self.count += downcast::<Self>(other)?.count;
+        Ok(())
+    }
+
     fn aggregate(&self) -> Value {
         Value::Integer(self.count as i64)
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 // 计算平均值
@@ -175,13 +417,26 @@ impl Accumulator for Average {
         Ok(())
     }
 
+    fn merge(&mut self, other: &dyn Accumulator) -> Result<()> {
+        let other = downcast::<Self>(other)?;
+        self.count.merge(&other.count)?;
+        self.sum.merge(&other.sum)
+    }
+
     fn aggregate(&self) -> Value {
         match (self.sum.aggregate(), self.count.aggregate()) {
             (Value::Integer(s), Value::Integer(c)) => Value::Integer(s / c),
             (Value::Float(s), Value::Integer(c)) => Value::Float(s / c as f64),
+            (Value::Decimal(s), Value::Integer(c)) => s
+                .checked_div(&Decimal::from_i64(c))
+                .map_or(Value::Null, Value::Decimal),
             _ => Value::Null,
         }
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 // 计算max值
@@ -198,28 +453,38 @@ impl Max {
 
 impl Accumulator for Max {
     fn accumulate(&mut self, value: &Value) -> Result<()> {
+        // null 不参与比较 合并的时候结果和顺序无关
+        if value == &Value::Null {
+            return Ok(());
+        }
         if let Some(max) = &mut self.max {
-            if max.datatype() != value.datatype() {
-                return Ok(());
+            // 和 ORDER BY 的顺序一样 相等的时候保留先出现的
+            if value.cmp(max) == Ordering::Greater {
+                *max = value.clone();
             }
-            match value.partial_cmp(max) {
-                // 防止所有的值是Null
-                None => *max = Value::Null,
-                Some(Ordering::Greater) => *max = value.clone(),
-                Some(Ordering::Equal) | Some(Ordering::Less) => {}
-            };
         } else {
             self.max = Some(value.clone())
         }
         Ok(())
     }
 
+    fn merge(&mut self, other: &dyn Accumulator) -> Result<()> {
+        match &downcast::<Self>(other)?.max {
+            Some(max) => self.accumulate(max),
+            None => Ok(()),
+        }
+    }
+
     fn aggregate(&self) -> Value {
         match &self.max {
             Some(value) => value.clone(),
             None => Value::Null,
         }
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 // 计算最小值
@@ -236,27 +501,37 @@ impl Min {
 
 impl Accumulator for Min {
     fn accumulate(&mut self, value: &Value) -> Result<()> {
+        // null 不参与比较 合并的时候结果和顺序无关
+        if value == &Value::Null {
+            return Ok(());
+        }
         if let Some(min) = &mut self.min {
-            if min.datatype() != value.datatype() {
-                return Ok(());
+            if value.cmp(min) == Ordering::Less {
+                *min = value.clone();
             }
-            match value.partial_cmp(min) {
-                None => *min = Value::Null,
-                Some(Ordering::Less) => *min = value.clone(),
-                Some(Ordering::Equal) | Some(Ordering::Greater) => {}
-            };
         } else {
             self.min = Some(value.clone())
         }
         Ok(())
     }
 
+    fn merge(&mut self, other: &dyn Accumulator) -> Result<()> {
+        match &downcast::<Self>(other)?.min {
+            Some(min) => self.accumulate(min),
+            None => Ok(()),
+        }
+    }
+
     fn aggregate(&self) -> Value {
         match &self.min {
             Some(value) => value.clone(),
             None => Value::Null,
         }
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
 }
 
 /// 计算总计值
@@ -274,8 +549,15 @@ impl Sum {
 impl Accumulator for Sum {
     fn accumulate(&mut self, value: &Value) -> Result<()> {
         self.sum = match (&self.sum, value) {
-            (Some(Value::Integer(s)), Value::Integer(i)) => Some(Value::Integer(s + i)),
+            // 整数溢出之后使用小数继续求和
+            (Some(Value::Integer(s)), Value::Integer(i)) => Some(match s.checked_add(*i) {
+                Some(sum) => Value::Integer(sum),
+                None => add_decimal(&Value::Integer(*s), value)?,
+            }),
             (Some(Value::Float(s)), Value::Float(f)) => Some(Value::Float(s + f)),
+            (Some(s @ Value::Decimal(_)), Value::Integer(_) | Value::Decimal(_))
+            | (Some(s @ Value::Integer(_)), Value::Decimal(_)) => Some(add_decimal(s, value)?),
+            (None, Value::Decimal(d)) => Some(Value::Decimal(*d)),
             (None, Value::Integer(i)) => Some(Value::Integer(*i)),
             (None, Value::Float(f)) => Some(Value::Float(*f)),
             _ => Some(Value::Null),
@@ -283,10 +565,228 @@ impl Accumulator for Sum {
         Ok(())
     }
 
+    fn merge(&mut self, other: &dyn Accumulator) -> Result<()> {
+        match &downcast::<Self>(other)?.sum {
+            Some(sum) => self.accumulate(sum),
+            None => Ok(()),
+        }
+    }
+
     fn aggregate(&self) -> Value {
         match &self.sum {
             Some(value) => value.clone(),
             None => Value::Null,
         }
     }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn add_decimal(a: &Value, b: &Value) -> Result<Value> {
+    match (a.to_decimal(), b.to_decimal()) {
+        (Some(a), Some(b)) => Ok(Value::Decimal(a?.checked_add(&b?)?)),
+        _ => Ok(Value::Null),
+    }
+}
+
+/// 计算样本方差 标准差就是方差开方
+/// 使用 Welford 算法 一遍遍历 避免先求和再相减带来的精度损失
+#[derive(Debug)]
+pub struct Variance {
+    count: u64,
+    mean: f64,
+    /// 与均值之差的平方和
+    m2: f64,
+    /// 为true时结果是标准差
+    stddev: bool,
+}
+
+impl Variance {
+    pub fn new(stddev: bool) -> Self {
+        Self {
+            count: 0,
+            mean: 0.0,
+            m2: 0.0,
+            stddev,
+        }
+    }
+}
+
+impl Accumulator for Variance {
+    fn accumulate(&mut self, value: &Value) -> Result<()> {
+        let value = match value {
+            Value::Null => return Ok(()),
+            Value::Integer(i) => *i as f64,
+            Value::Float(f) => *f,
+            Value::Decimal(d) => d.to_f64(),
+            v => {
+                return Err(Error::Executor(format!(
+                    "can not calculate variance of {}",
+                    v
+                )))
+            }
+        };
+        self.count += 1;
+        let delta = value - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value - self.mean);
+        Ok(())
+    }
+
+    /// 两部分的均值和平方和可以直接合并 (Chan 的并行算法)
+    fn merge(&mut self, other: &dyn Accumulator) -> Result<()> {
+        let other = downcast::<Self>(other)?;
+        if other.count == 0 {
+            return Ok(());
+        }
+        let count = self.count + other.count;
+        let delta = other.mean - self.mean;
+        let (a, b) = (self.count as f64, other.count as f64);
+        self.mean += delta * b / count as f64;
+        self.m2 += other.m2 + delta * delta * a * b / count as f64;
+        self.count = count;
+        Ok(())
+    }
+
+    fn aggregate(&self) -> Value {
+        // 样本方差至少需要两个值
+        if self.count < 2 {
+            return Value::Null;
+        }
+        let variance = self.m2 / (self.count - 1) as f64;
+        Value::Float(if self.stddev {
+            variance.sqrt()
+        } else {
+            variance
+        })
+    }
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::Result;
+    use crate::sql::engine::kv::test_engine;
+    use crate::sql::engine::{Engine, Rows};
+    use crate::sql::Value;
+
+    #[test]
+    fn variance_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int null default null, g int );")?;
+        session.execute("insert into t values (1, 1, 1), (2, 2, 1), (3, 3, 1), (4, 4, 1), (5, null, 2), (6, 6, 2);")?;
+
+        let sql = "select stddev(n), variance(n), count(*) from t;";
+        let (columns, rows) = session.query_columns(sql)?;
+        let names: Vec<_> = columns.into_iter().map(|c| c.name).collect();
+        assert_eq!(
+            names,
+            vec![
+                Some("stddev(n)".to_string()),
+                Some("variance(n)".to_string()),
+                Some("count(*)".to_string()),
+            ]
+        );
+        match rows[0][..] {
+            [Value::Float(stddev), Value::Float(variance), Value::Integer(6)] => {
+                // 1 2 3 4 6 的样本方差是 14.8 / 4
+                assert!((variance - 3.7).abs() < 1e-9);
+                assert!((stddev - 3.7f64.sqrt()).abs() < 1e-9);
+            }
+            ref row => panic!("unexpected row {:?}", row),
+        }
+
+        // 只有一个非null值的时候样本方差是null
+        assert_eq!(
+            session.query("select variance(n) from t where t.g = 2;")?,
+            vec![vec![Value::Null]]
+        );
+        Ok(())
+    }
+    #[test]
+    fn spill_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, g int, n int );")?;
+        let values = (0..2000)
+            .map(|i| format!("({}, {}, {})", i, i % 300, i))
+            .collect::<Vec<_>>()
+            .join(", ");
+        session.execute(&format!("insert into t values {};", values))?;
+
+        let sorted = |mut rows: Rows| {
+            rows.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            rows
+        };
+        let sql = "select t.g, count(id), sum(n) from t group by t.g;";
+        let expect = sorted(session.query(sql)?);
+        assert_eq!(expect.len(), 300);
+        assert_eq!(
+            expect[0],
+            vec![Value::Integer(0), Value::Integer(7), Value::Integer(6300)]
+        );
+        // 内存很小的时候分组会写到分区文件中 结果和全部在内存中一样
+        session.execute("set work_memory = 1024;")?;
+        assert_eq!(sorted(session.query(sql)?), expect);
+        session.execute("set work_memory = 1;")?;
+        assert_eq!(sorted(session.query(sql)?), expect);
+        // 分区文件一个一个聚合 游标每次读取一部分
+        let (id, _) = session.open_cursor(sql)?;
+        assert_eq!(session.fetch(id, 10)?.len(), 10);
+        assert_eq!(session.fetch(id, 1000)?.len(), 290);
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, g int, n int null default null );")?;
+        let values = (0..10000)
+            .map(|i| match i % 11 {
+                0 => format!("({}, {}, null)", i, i % 30),
+                _ => format!("({}, {}, {})", i, i % 30, i % 1000),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        session.execute(&format!("insert into t values {};", values))?;
+
+        let sorted = |mut rows: Rows| {
+            rows.sort_by(|a, b| a.partial_cmp(b).unwrap());
+            rows
+        };
+        let mut results = Vec::new();
+        for workers in [1, 4] {
+            session.execute(&format!("set parallel_workers = {};", workers))?;
+            let mut result = Vec::new();
+            for sql in [
+                "select count(n), count(*), min(n), max(n), average(n), sum(id) from t;",
+                "select t.g, count(n), min(n), max(n), average(n), sum(id) from t group by t.g;",
+                "select t.g, variance(n) from t group by t.g;",
+            ] {
+                result.push(sorted(session.query(sql)?));
+            }
+            results.push(result);
+        }
+        assert_eq!(results[0][..2], results[1][..2]);
+        // 方差合并之后只有浮点误差
+        for (a, b) in results[0][2].iter().zip(results[1][2].iter()) {
+            match (&a[1], &b[1]) {
+                (Value::Float(a), Value::Float(b)) => assert!((a - b).abs() < 1e-6),
+                r => panic!("unexpected values {:?}", r),
+            }
+        }
+
+        // 内存不够的时候回到单线程聚合 分组写到分区文件中
+        session.execute("set work_memory = 1;")?;
+        let sql = "select t.g, count(n) from t group by t.g;";
+        let expect = results[1][1].iter().map(|r| r[..2].to_vec()).collect::<Vec<_>>();
+        assert_eq!(sorted(session.query(sql)?), expect);
+        Ok(())
+    }
 }
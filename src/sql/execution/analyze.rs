@@ -0,0 +1,104 @@
+//! explain analyze 使用的执行器 包装其他执行器 统计输出的行数和耗时
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_derive::{Deserialize, Serialize};
+
+use super::{Batches, Columns, Executor, ResultSet};
+use crate::errors::*;
+use crate::sql::engine::Transaction;
+
+/// 每个执行节点的统计信息
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NodeStats {
+    /// 节点输出的行数 修改操作就是修改的行数
+    pub rows: u64,
+    /// 节点执行的耗时 包括子节点
+    pub elapsed: Duration,
+}
+
+/// 按照执行树前序遍历的顺序存放统计信息
+pub type Stats = Arc<Mutex<Vec<NodeStats>>>;
+
+pub struct Analyze<T: Transaction> {
+    /// 在stats中的位置
+    id: usize,
+    source: Box<dyn Executor<T>>,
+    stats: Stats,
+}
+
+impl<T: Transaction> Analyze<T> {
+    pub fn new(id: usize, source: Box<dyn Executor<T>>, stats: Stats) -> Box<Self> {
+        Box::new(Self { id, source, stats })
+    }
+}
+
+impl<T: Transaction> Executor<T> for Analyze<T> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        let start = Instant::now();
+        let result = self.source.execute(txn)?;
+        let elapsed = start.elapsed();
+        let rows = match &result {
+            ResultSet::Query { rows, .. } => rows.len() as u64,
+            ResultSet::Create { count }
+            | ResultSet::Delete { count }
+            | ResultSet::Update { count } => *count,
+            _ => 0,
+        };
+        if let Some(stat) = self.stats.lock()?.get_mut(self.id) {
+            *stat = NodeStats { rows, elapsed };
+        }
+        Ok(result)
+    }
+
+    /// 按批执行的时候 每拉取一批累加一次 耗时包括下层计算这一批的时间
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        let start = Instant::now();
+        let (columns, mut batches) = self.source.execute_batches(txn)?;
+        let (id, stats) = (self.id, self.stats);
+        let record = move |rows: usize, elapsed: Duration| {
+            let mut stats = stats.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(stat) = stats.get_mut(id) {
+                stat.rows += rows as u64;
+                stat.elapsed += elapsed;
+            }
+        };
+        record(0, start.elapsed());
+        let batches = std::iter::from_fn(move || {
+            let start = Instant::now();
+            let batch = batches.next();
+            let rows = match &batch {
+                Some(Ok(rows)) => rows.len(),
+                _ => 0,
+            };
+            record(rows, start.elapsed());
+            batch
+        });
+        Ok((columns, Box::new(batches)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::Result;
+    use crate::sql::engine::kv::test_engine;
+    use crate::sql::engine::Engine;
+
+    #[test]
+    fn explain_analyze_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, x int );")?;
+        session.execute("insert into t values (1, 1), (2, 2), (3, 3);")?;
+
+        let (plan, stats) = session.explain_analyze("select id from t where x > 1;")?;
+        // Projection -> Scan(filter 下推)
+        assert_eq!(stats.iter().map(|s| s.rows).collect::<Vec<_>>(), vec![2, 2]);
+        let output = plan.format_analyze(&stats);
+        assert_eq!(output.matches("rows=2").count(), 2, "{}", output);
+        // 修改语句会真正执行
+        session.execute("explain analyze delete from t where id = 1;")?;
+        assert_eq!(session.query("select id from t;")?.len(), 2);
+        Ok(())
+    }
+}
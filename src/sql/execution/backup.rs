@@ -0,0 +1,157 @@
+/* 备份和恢复 备份读取当前事务快照中所有可见的key value 包括表结构和索引
+ * 只读事务 begin transaction read only as <version> 可以备份某个历史版本
+ * 恢复只能在没有任何表的数据库中执行 所有数据在当前事务中写入 出错的时候一起回滚
+ * */
+
+use std::path::Path;
+
+use super::{Executor, ResultSet, BATCH_SIZE};
+use crate::errors::*;
+use crate::sql::engine::Transaction;
+use crate::storage::backup::{BackupReader, BackupWriter};
+use crate::storage::kv::mvcc::Mode;
+
+pub struct Backup {
+    path: String,
+}
+
+impl Backup {
+    pub fn new(path: String) -> Box<Self> {
+        Box::new(Self { path })
+    }
+}
+
+impl<T: Transaction> Executor<T> for Backup {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        let version = match txn.mode() {
+            Mode::Snapshot { version } => version,
+            _ => txn.id(),
+        };
+        let mut writer = BackupWriter::create(Path::new(&self.path), version)?;
+        // 分页读取 每次从上一页的最后一个key之后开始
+        let mut last = None;
+        loop {
+            txn.check_cancel()?;
+            let items = txn.dump(last.as_deref(), BATCH_SIZE)?;
+            for (key, value) in items.iter() {
+                writer.write(key, value)?;
+            }
+            if items.len() < BATCH_SIZE {
+                break;
+            }
+            last = items.into_iter().last().map(|(key, _)| key);
+        }
+        let keys = writer.finish()?;
+        Ok(ResultSet::Backup { version, keys })
+    }
+}
+
+pub struct Restore {
+    path: String,
+}
+
+impl Restore {
+    pub fn new(path: String) -> Box<Self> {
+        Box::new(Self { path })
+    }
+}
+
+impl<T: Transaction> Executor<T> for Restore {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        if !txn.scan_tables()?.is_empty() {
+            return Err(Error::Executor(
+                "restore requires an empty database without tables".to_string(),
+            ));
+        }
+        let mut reader = BackupReader::open(Path::new(&self.path))?;
+        let version = reader.version();
+        let mut keys = 0;
+        loop {
+            txn.check_cancel()?;
+            let items = reader.by_ref().take(BATCH_SIZE).collect::<Result<Vec<_>>>()?;
+            if items.is_empty() {
+                break;
+            }
+            keys += items.len() as u64;
+            txn.load(items)?;
+        }
+        Ok(ResultSet::Restore { version, keys })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::*;
+    use crate::sql::engine::kv::test_engine;
+    use crate::sql::engine::Engine;
+    use crate::sql::execution::ResultSet;
+
+    #[test]
+    fn backup_restore_test() -> Result<()> {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("coke_db_backup_test_{}", std::process::id()));
+        let old = dir.join(format!("coke_db_backup_test_old_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&old);
+
+        let engine = test_engine();
+        let mut session = engine.session()?.with_admin(true);
+        session.execute("create table t ( id int primary key, n int index, s string );")?;
+        let values = (0..3000)
+            .map(|i| format!("({}, {}, \"row {}\")", i, i % 7, i))
+            .collect::<Vec<_>>();
+        session.execute(&format!("insert into t values {};", values.join(", ")))?;
+        let version = session.begin_id("begin transaction read only;")?;
+        session.execute("commit;")?;
+        // 备份之后的修改不会出现在历史版本的备份中
+        session.execute("delete from t where id >= 1000;")?;
+        session.execute(&format!("begin transaction read only as {};", version))?;
+        assert!(matches!(
+            session.execute(&format!("backup to \"{}\";", old.display()))?,
+            ResultSet::Backup { version: v, .. } if v == version
+        ));
+        session.execute("commit;")?;
+        session.execute(&format!("backup to \"{}\";", path.display()))?;
+        // 不会覆盖已经存在的文件
+        assert!(session.execute(&format!("backup to \"{}\";", path.display())).is_err());
+        // 只能恢复到空的数据库
+        assert!(session.execute(&format!("restore from \"{}\";", path.display())).is_err());
+
+        let sql = "select n, count(id), max(s) from t where n > 2 group by n order by n asc;";
+        let expect = session.execute(sql)?;
+        for (file, rows) in [(&path, 1000), (&old, 3000)] {
+            let engine = test_engine();
+            let mut session = engine.session()?.with_admin(true);
+            assert!(matches!(
+                session.execute(&format!("restore from \"{}\";", file.display()))?,
+                ResultSet::Restore { keys, .. } if keys > rows
+            ));
+            assert_eq!(
+                session.execute("check table t;")?,
+                ResultSet::CheckTable {
+                    name: "t".to_string(),
+                    rows
+                }
+            );
+            if rows == 1000 {
+                assert_eq!(session.execute(sql)?, expect);
+            }
+            session.execute("insert into t values (5000, 1, \"new\");")?;
+        }
+
+        // 不完整的备份不能恢复
+        let data = std::fs::read(&path)?;
+        std::fs::write(&path, &data[..data.len() / 2])?;
+        let engine = test_engine();
+        let mut session = engine.session()?.with_admin(true);
+        assert!(matches!(
+            session.execute(&format!("restore from \"{}\";", path.display())),
+            Err(Error::Corruption(_))
+        ));
+        // 出错的时候什么都没有写入
+        assert!(session.execute("select * from t;").is_err());
+        std::fs::remove_file(&path)?;
+        std::fs::remove_file(&old)?;
+        Ok(())
+    }
+}
@@ -1,21 +1,28 @@
-use std::{collections::HashMap, todo};
+use std::collections::HashMap;
 
 use crate::sql::{
-    engine::{Row, Transaction},
-    execution::ResultSet,
+    engine::{Row, Rows, Transaction},
+    execution::{Column, ResultSet},
     expression::Expression,
-    Column, Value,
+    plan::Outer,
+    Value,
 };
 
-use super::Executor;
+use super::{
+    collect,
+    parallel::{map_chunks, PARALLEL_MIN_ROWS},
+    Batches, Columns, Executor, BATCH_SIZE, CANCEL_CHECK_ROWS,
+};
 
 use crate::errors::*;
-/// 连接join的执行器 检查一下左表是否和右表能够连接
+
+/// 连接join的执行器 左表的每一行和右表的每一行检查连接条件
+/// 左外连接的时候没有匹配的左表行补null 全外连接的时候没有匹配的右表行也补null
 pub struct NestedLoopJoin<T: Transaction> {
     left: Box<dyn Executor<T>>,
     right: Box<dyn Executor<T>>,
     predicate: Option<Expression>,
-    outer: bool,
+    outer: Option<Outer>,
 }
 
 impl<T: Transaction> NestedLoopJoin<T> {
@@ -23,7 +30,7 @@ impl<T: Transaction> NestedLoopJoin<T> {
         left: Box<dyn Executor<T>>,
         right: Box<dyn Executor<T>>,
         predicate: Option<Expression>,
-        outer: bool,
+        outer: Option<Outer>,
     ) -> Box<Self> {
         Box::new(Self {
             left,
@@ -32,97 +39,85 @@ impl<T: Transaction> NestedLoopJoin<T> {
             outer,
         })
     }
-
-    pub fn generate_row(
-        left: Vec<Row>,
-        right: Vec<Row>,
-        predicate: Option<Expression>,
-        outer: bool,
-    ) -> Result<Vec<Row>> {
-        let mut res: Vec<Row> = Vec::new();
-        let empty: Vec<_> = std::iter::repeat(Value::Null).take(right.len()).collect();
-        for lrow in left {
-            let mut base_res: Vec<Row> = Vec::new();
-            for rrow in right.clone() {
-                let mut row = lrow.clone();
-                row.extend(rrow);
-                if let Some(predicate) = &predicate {
-                    if predicate.evaluate(Some(&row))?.is_visiable()? {
-                        base_res.push(row)
-                    } else if outer {
-                    }
-                } else {
-                    base_res.push(row)
-                }
-                
-            }
-            res.extend(base_res);
-            // 没有找到 并且 是个外连接
-            if res.len() == 0 && outer {
-                let mut row = lrow.clone();
-                row.extend(empty.clone());
-                res.push(row);
-            }
-        }
-
-        Ok(res)
-    }
 }
 
 impl<T: Transaction> Executor<T> for NestedLoopJoin<T> {
     fn execute(self: Box<Self>, txn: &mut T) -> crate::errors::Result<super::ResultSet> {
-        let (column, lrow, rrow): (Vec<Option<String>>, Vec<Row>, Vec<Row>) =
-            match self.left.execute(txn)? {
-                ResultSet::Query { mut columns, rows } => match self.right.execute(txn)? {
-                    ResultSet::Query {
-                        columns: rcolumns,
-                        rows: rrows,
-                    } => {
-                        columns.extend(rcolumns);
-                        Ok((columns, rows, rrows))
+        let (columns, batches) = self.execute_batches(txn)?;
+        collect(columns, batches)
+    }
+
+    /// 右表全部读出来 左表逐批读取和右表连接
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        let (lcolumns, left) = self.left.execute_batches(txn)?;
+        let (rcolumns, rrows) = query(self.right.execute(txn)?)?;
+        let (lwidth, rwidth) = (lcolumns.len(), rcolumns.len());
+        let (predicate, outer, cancel) = (self.predicate, self.outer, txn.canceller());
+
+        let probe = move |lrows: Rows, rrows: &Rows| {
+            let mut rows = Vec::new();
+            let mut matched = Vec::new();
+            for lrow in lrows {
+                // 左表的每一行都要和整个右表连接 在这里检查语句有没有被取消
+                cancel.check()?;
+                let mut found = false;
+                for (i, rrow) in rrows.iter().enumerate() {
+                    let mut row = lrow.clone();
+                    row.extend(rrow.iter().cloned());
+                    let keep = match &predicate {
+                        Some(predicate) => predicate.evaluate(Some(&row))?.is_visiable()?,
+                        None => true,
+                    };
+                    if keep {
+                        found = true;
+                        matched.push(i);
+                        rows.push(row);
                     }
-                    r => Err(Error::Executor(format!(
-                        "expect query ResultSet get {:?}",
-                        r
-                    ))),
-                },
-                r => Err(Error::Executor(format!(
-                    "expect query ResultSet get {:?}",
-                    r
-                ))),
-            }?;
-
-        let rows = Self::generate_row(lrow, rrow, self.predicate, self.outer)?;
-        Ok(ResultSet::Query {
-            columns: column,
-            rows,
-        })
+                }
+                if !found && outer.is_some() {
+                    rows.push(pad_right(lrow, rwidth));
+                }
+            }
+            Ok((rows, matched))
+        };
+        Ok((
+            join_columns(lcolumns, rcolumns, outer),
+            Box::new(Probe::new(left, BATCH_SIZE, rrows, probe, outer, lwidth)),
+        ))
     }
 }
 
 /// HashJoin 这里的执行比较简单
 /// 就是直接用右表构建成为一个hashmap 然后左表对应寻找
+/// 连接的字段可以有多个 所有字段的值一起作为key
+/// 行数多的时候构建和查找都分段交给多个线程 结果的顺序和单线程一样
 pub struct HashJoin<T: Transaction> {
     left: Box<dyn Executor<T>>,
-    left_field: usize,
+    left_fields: Vec<usize>,
     right: Box<dyn Executor<T>>,
-    right_field: usize,
-    outer: bool,
+    right_fields: Vec<usize>,
+    null_safe: Vec<bool>,
+    predicate: Option<Expression>,
+    outer: Option<Outer>,
 }
 
 impl<T: Transaction> HashJoin<T> {
     pub fn new(
         left: Box<dyn Executor<T>>,
-        left_field: usize,
+        left_fields: Vec<usize>,
         right: Box<dyn Executor<T>>,
-        right_field: usize,
-        outer: bool,
+        right_fields: Vec<usize>,
+        null_safe: Vec<bool>,
+        predicate: Option<Expression>,
+        outer: Option<Outer>,
     ) -> Box<Self> {
         Box::new(Self {
             left,
-            left_field,
+            left_fields,
             right,
-            right_field,
+            right_fields,
+            null_safe,
+            predicate,
             outer,
         })
     }
@@ -130,64 +125,374 @@ impl<T: Transaction> HashJoin<T> {
 
 impl<T: Transaction> Executor<T> for HashJoin<T> {
     fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
-        match self.left.execute(txn)? {
-            ResultSet::Query { mut columns, rows } => match self.right.execute(txn)? {
-                ResultSet::Query {
-                    columns: rcolumns,
-                    rows: rrows,
-                } => {
-                    let mut res: Vec<Row> = Vec::new();
-                    // 将右表形成hashmap
-                    let rmap: HashMap<_, _> = rrows
-                        .into_iter()
-                        .map(|row| {
-                            if row.len() < self.right_field {
-                                // 越界
-                                Err(Error::Executor(format!(
-                                    "out of bounds at right list with index {}",
-                                    self.right_field
-                                )))
-                            } else {
-                                Ok((row[self.right_field].clone(), row))
-                            }
-                        })
-                        .collect::<Result<_>>()?;
+        let (columns, batches) = self.execute_batches(txn)?;
+        collect(columns, batches)
+    }
 
-                    let empty: Vec<_> = std::iter::repeat(Value::Null)
-                        .take(rcolumns.len())
-                        .collect();
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        let (lcolumns, left) = self.left.execute_batches(txn)?;
+        let (rcolumns, rrows) = query(self.right.execute(txn)?)?;
+        let (lwidth, rwidth) = (lcolumns.len(), rcolumns.len());
+        let (workers, cancel) = (txn.workers(), txn.canceller());
+        let Self {
+            left_fields,
+            right_fields,
+            null_safe,
+            predicate,
+            outer,
+            ..
+        } = *self;
 
-                    columns.extend(rcolumns);
+        // 将右表形成hashmap 同一个key可能对应右表的多行 保存的是行号
+        // null 和任何值都不相等 key中有null的行不放进去 <=> 连接的字段null也放进去
+        // 每一段构建自己的hashmap 再按照段的顺序合并 同一个key的行号还是从小到大
+        let mut rmap: HashMap<Vec<Value>, Vec<usize>> = HashMap::new();
+        let maps = map_chunks(&rrows, workers, |start, rows| {
+            let mut map: HashMap<Vec<&Value>, Vec<usize>> = HashMap::new();
+            for (i, row) in rows.iter().enumerate() {
+                if let Some(key) = join_key(row, &right_fields, &null_safe, "right")? {
+                    map.entry(key).or_default().push(start + i);
+                }
+            }
+            Ok(map)
+        })?;
+        for map in maps {
+            for (key, indexes) in map {
+                rmap.entry(key.into_iter().cloned().collect())
+                    .or_default()
+                    .extend(indexes);
+            }
+        }
 
-                    for mut lrow in rows {
-                        if lrow.len() < self.left_field {
-                            return Err(Error::Executor(format!(
-                                "out of bounds at left list with index {}",
-                                self.right_field
-                            )));
-                        }
-                        let rrow = rmap.get(&lrow[self.left_field]);
-                        match rrow {
-                            Some(row) => lrow.extend(row.clone()),
-                            None if self.outer => lrow.extend(empty.clone()),
-                            None => {}
+        // 左表一次读取多批 分段交给多个线程 每一段返回连接的行和匹配到的右表行号
+        let probe = move |lrows: Rows, rrows: &Rows| {
+            let probes = map_chunks(&lrows, workers, |_, lrows| {
+                let mut rows = Vec::new();
+                let mut matched = Vec::new();
+                for (i, lrow) in lrows.iter().enumerate() {
+                    if i % CANCEL_CHECK_ROWS == 0 {
+                        cancel.check()?;
+                    }
+                    let mut found = false;
+                    let indexes = match join_key(lrow, &left_fields, &null_safe, "left")? {
+                        Some(key) => {
+                            let key = key.into_iter().cloned().collect::<Vec<_>>();
+                            rmap.get(&key).map_or(&[][..], |indexes| &indexes[..])
                         }
-                        if lrow.len() == columns.len() {
-                            res.push(lrow);
+                        None => &[],
+                    };
+                    for &index in indexes {
+                        let mut row = lrow.clone();
+                        row.extend(rrows[index].iter().cloned());
+                        // 哈希匹配之后再检查剩下的连接条件
+                        if let Some(predicate) = &predicate {
+                            if !predicate.evaluate(Some(&row))?.is_visiable()? {
+                                continue;
+                            }
                         }
+                        found = true;
+                        matched.push(index);
+                        rows.push(row);
+                    }
+                    if !found && outer.is_some() {
+                        rows.push(pad_right(lrow.clone(), rwidth));
                     }
+                }
+                Ok((rows, matched))
+            })?;
+            let mut rows = Vec::new();
+            let mut matched = Vec::new();
+            for (probe, indexes) in probes {
+                rows.extend(probe);
+                matched.extend(indexes);
+            }
+            Ok((rows, matched))
+        };
+        let size = match workers {
+            0 | 1 => BATCH_SIZE,
+            n => PARALLEL_MIN_ROWS * n,
+        };
+        Ok((
+            join_columns(lcolumns, rcolumns, outer),
+            Box::new(Probe::new(left, size, rrows, probe, outer, lwidth)),
+        ))
+    }
+}
+
+/// 逐批读取左表和已经读出来的右表连接
+/// 全外连接的时候 左表读完之后最后一批是没有匹配过的右表行
+struct Probe<F> {
+    left: Batches,
+    /// 每次连接至少读取这么多左表的行 多线程连接的时候一次读取多批
+    size: usize,
+    right: Rows,
+    /// 连接一批左表的行 返回连接的行和匹配到的右表行号
+    probe: F,
+    /// 右表的每一行有没有被连接过 全外连接的时候使用
+    matched: Option<Vec<bool>>,
+    /// 右表行左边补null的数量
+    lwidth: usize,
+    done: bool,
+}
+
+impl<F> Probe<F>
+where
+    F: FnMut(Rows, &Rows) -> Result<(Rows, Vec<usize>)>,
+{
+    fn new(
+        left: Batches,
+        size: usize,
+        right: Rows,
+        probe: F,
+        outer: Option<Outer>,
+        lwidth: usize,
+    ) -> Self {
+        let matched = (outer == Some(Outer::Full)).then(|| vec![false; right.len()]);
+        Self {
+            left,
+            size,
+            right,
+            probe,
+            matched,
+            lwidth,
+            done: false,
+        }
+    }
 
-                    Ok(ResultSet::Query { columns, rows: res })
+    /// 读取下一段左表 没有了返回None
+    fn read_left(&mut self) -> Result<Option<Rows>> {
+        let mut rows = Vec::new();
+        while rows.len() < self.size {
+            match self.left.next() {
+                Some(batch) => rows.extend(batch?),
+                None => break,
+            }
+        }
+        Ok((!rows.is_empty()).then_some(rows))
+    }
+}
+
+impl<F> Iterator for Probe<F>
+where
+    F: FnMut(Rows, &Rows) -> Result<(Rows, Vec<usize>)>,
+{
+    type Item = Result<Rows>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let lrows = match self.read_left() {
+                Ok(Some(lrows)) => lrows,
+                Ok(None) => {
+                    self.done = true;
+                    break;
+                }
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
+                }
+            };
+            let (rows, indexes) = match (self.probe)(lrows, &self.right) {
+                Ok(probe) => probe,
+                Err(e) => {
+                    self.done = true;
+                    return Some(Err(e));
                 }
-                r => Err(Error::Executor(format!(
-                    "expect query ResultSet get {:?}",
-                    r
-                ))),
-            },
-            r => Err(Error::Executor(format!(
-                "expect query ResultSet get {:?}",
-                r
-            ))),
+            };
+            if let Some(matched) = self.matched.as_mut() {
+                for index in indexes {
+                    matched[index] = true;
+                }
+            }
+            if !rows.is_empty() {
+                return Some(Ok(rows));
+            }
+        }
+        let matched = self.matched.take()?;
+        let rows = unmatched_right(std::mem::take(&mut self.right), &matched, self.lwidth)
+            .collect::<Rows>();
+        (!rows.is_empty()).then_some(Ok(rows))
+    }
+}
+
+/// 取出连接字段的值 有null的时候返回None
+fn join_key<'a>(
+    row: &'a Row,
+    fields: &[usize],
+    null_safe: &[bool],
+    side: &str,
+) -> Result<Option<Vec<&'a Value>>> {
+    let mut key = Vec::with_capacity(fields.len());
+    for (&field, &null_safe) in fields.iter().zip(null_safe) {
+        match row.get(field) {
+            Some(Value::Null) if !null_safe => return Ok(None),
+            Some(value) => key.push(value),
+            None => {
+                return Err(Error::Executor(format!(
+                    "out of bounds at {} list with index {}",
+                    side, field
+                )))
+            }
+        }
+    }
+    Ok(Some(key))
+}
+
+fn query(result: ResultSet) -> Result<(Vec<Column>, Rows)> {
+    match result {
+        ResultSet::Query { columns, rows } => Ok((columns, rows)),
+        r => Err(Error::Executor(format!(
+            "expect query ResultSet get {:?}",
+            r
+        ))),
+    }
+}
+
+/// 左表的行没有匹配 右边补null
+fn pad_right(mut row: Row, width: usize) -> Row {
+    row.extend(std::iter::repeat_n(Value::Null, width));
+    row
+}
+
+/// 全外连接中右表没有匹配的行 左边补null
+fn unmatched_right(rows: Rows, matched: &[bool], width: usize) -> impl Iterator<Item = Row> + '_ {
+    rows.into_iter()
+        .zip(matched)
+        .filter(|(_, matched)| !**matched)
+        .map(move |(row, _)| {
+            let mut padded: Row = std::iter::repeat_n(Value::Null, width).collect();
+            padded.extend(row);
+            padded
+        })
+}
+
+/// 补null的一边的列都可能是null
+fn join_columns(left: Vec<Column>, right: Vec<Column>, outer: Option<Outer>) -> Vec<Column> {
+    let nullable = |columns: Vec<Column>, nullable: bool| {
+        columns.into_iter().map(move |c| Column {
+            nullable: c.nullable || nullable,
+            ..c
+        })
+    };
+    nullable(left, outer == Some(Outer::Full))
+        .chain(nullable(right, outer.is_some()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::Result;
+    use crate::sql::engine::kv::test_engine;
+    use crate::sql::engine::Engine;
+    use crate::sql::Value;
+
+    #[test]
+    fn outer_join_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table a ( id int primary key, k int null default null );")?;
+        session.execute("create table b ( id int primary key, k int null default null );")?;
+        session.execute("insert into a values (1, 1), (2, 2), (3, null);")?;
+        session.execute("insert into b values (10, 1), (11, 1), (12, 4), (13, null);")?;
+
+        let rows = |rows: &[(Option<i64>, Option<i64>)]| {
+            let value = |v: &Option<i64>| v.map_or(Value::Null, Value::Integer);
+            rows.iter()
+                .map(|(a, b)| vec![value(a), value(b)])
+                .collect::<Vec<_>>()
+        };
+
+        // 等值条件走 HashJoin 不等值条件走 NestedLoopJoin 结果应该一样
+        for on in ["a.k = b.k", "a.k >= b.k and a.k <= b.k"] {
+            let sql = |join: &str, filter: &str| {
+                format!(
+                    "select a.id, b.id from a {} join b on {} {} order by a.id asc, b.id asc;",
+                    join, on, filter
+                )
+            };
+            assert_eq!(
+                session.query(&sql("inner", ""))?,
+                rows(&[(Some(1), Some(10)), (Some(1), Some(11))]),
+                "{}",
+                on
+            );
+            // 左表的一行匹配多行的时候 不会再补null
+            assert_eq!(
+                session.query(&sql("left", ""))?,
+                rows(&[(Some(1), Some(10)), (Some(1), Some(11)), (Some(2), None), (Some(3), None)]),
+                "{}",
+                on
+            );
+            assert_eq!(
+                session.query(&sql("right", ""))?,
+                rows(&[
+                    (None, Some(12)),
+                    (None, Some(13)),
+                    (Some(1), Some(10)),
+                    (Some(1), Some(11))
+                ]),
+                "{}",
+                on
+            );
+            // null 不和任何值相等 两边都保留
+            assert_eq!(
+                session.query(&sql("full outer", ""))?,
+                rows(&[
+                    (None, Some(12)),
+                    (None, Some(13)),
+                    (Some(1), Some(10)),
+                    (Some(1), Some(11)),
+                    (Some(2), None),
+                    (Some(3), None)
+                ]),
+                "{}",
+                on
+            );
+            // where 在连接之后过滤 不能当作 on 的条件
+            assert_eq!(
+                session.query(&sql("left", "where b.id is null"))?,
+                rows(&[(Some(2), None), (Some(3), None)]),
+                "{}",
+                on
+            );
+            assert_eq!(
+                session.query(&sql("full", "where a.id > 1"))?,
+                rows(&[(Some(2), None), (Some(3), None)]),
+                "{}",
+                on
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn parallel_hash_join_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table a ( id int primary key, k int null default null );")?;
+        session.execute("create table b ( id int primary key, k int null default null );")?;
+        let values = |n: i64, m: i64| {
+            (0..n)
+                .map(|i| match i % 7 {
+                    0 => format!("({}, null)", i),
+                    _ => format!("({}, {})", i, i % m),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        session.execute(&format!("insert into a values {};", values(10000, 5000)))?;
+        session.execute(&format!("insert into b values {};", values(3000, 3000)))?;
+
+        // 多个线程的结果和顺序都和单线程一样
+        for join in ["inner", "left", "full"] {
+            let sql = format!(
+                "select a.id, b.id from a {} join b on a.k = b.k and a.id < b.id + 100;",
+                join
+            );
+            session.execute("set parallel_workers = 1;")?;
+            let expect = session.execute(&sql)?;
+            session.execute("set parallel_workers = 4;")?;
+            assert_eq!(session.execute(&sql)?, expect, "{}", join);
         }
+        Ok(())
     }
 }
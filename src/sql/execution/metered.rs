@@ -0,0 +1,63 @@
+//! 包装其他执行器 把每种执行节点的耗时记录到监控指标中
+use std::time::{Duration, Instant};
+
+use super::{Batches, Columns, Executor, ResultSet, Rows};
+use crate::errors::*;
+use crate::metrics;
+use crate::sql::engine::Transaction;
+
+pub struct Metered<T: Transaction> {
+    /// 执行节点的类型
+    name: &'static str,
+    source: Box<dyn Executor<T>>,
+}
+
+impl<T: Transaction> Metered<T> {
+    pub fn new(name: &'static str, source: Box<dyn Executor<T>>) -> Box<Self> {
+        Box::new(Self { name, source })
+    }
+}
+
+impl<T: Transaction> Executor<T> for Metered<T> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        let start = Instant::now();
+        let result = self.source.execute(txn);
+        metrics::record_node(self.name, start.elapsed());
+        result
+    }
+
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        let start = Instant::now();
+        let (columns, batches) = self.source.execute_batches(txn)?;
+        let batches = MeteredBatches {
+            name: self.name,
+            batches,
+            elapsed: start.elapsed(),
+        };
+        Ok((columns, Box::new(batches)))
+    }
+}
+
+/// 累加拉取每一批的耗时 读完或者不再读取的时候记录一次
+struct MeteredBatches {
+    name: &'static str,
+    batches: Batches,
+    elapsed: Duration,
+}
+
+impl Iterator for MeteredBatches {
+    type Item = Result<Rows>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let start = Instant::now();
+        let batch = self.batches.next();
+        self.elapsed += start.elapsed();
+        batch
+    }
+}
+
+impl Drop for MeteredBatches {
+    fn drop(&mut self) {
+        metrics::record_node(self.name, self.elapsed);
+    }
+}
@@ -1,6 +1,10 @@
 pub mod aggregation;
+pub mod analyze;
+pub mod backup;
 pub mod join;
+pub mod metered;
 pub mod mutation;
+pub mod parallel;
 pub mod query;
 pub mod schema;
 pub mod source;
@@ -10,46 +14,150 @@ use serde_derive::{Deserialize, Serialize};
 use crate::storage::kv::mvcc::Mode;
 
 use self::{
+    analyze::{Analyze, NodeStats, Stats},
+    backup::{Backup, Restore},
     aggregation::Aggregation,
     join::{HashJoin, NestedLoopJoin},
-    mutation::{Delete, Insert, Update},
-    query::{Filter, Limit, Offset, Order, Projection},
-    schema::{CreateTable, DeleteTable},
-    source::{Nothing, Scan, IndexLookUp, KeyLookUp},
+    metered::Metered,
+    mutation::{Delete, Insert, Truncate, Update},
+    query::{Filter, Limit, Order, Projection},
+    schema::{
+        AnalyzeTable, CheckTable, CreatePolicy, CreateTable, CreateTableAs, DeleteTable, DropPolicy,
+    },
+    source::{IndexLookUp, IndexOnlyScan, IndexRangeScan, KeyLookUp, Nothing, Scan, Values},
 };
 
-use super::{engine::Transaction, plan::Node, Value};
+use super::{
+    engine::Transaction,
+    plan::{estimate::NodeEstimate, Node},
+    ColumnType, Value,
+};
 
 use crate::errors::*;
 
+/// 逐行处理的执行器 每处理这么多行检查一次语句有没有被取消
+const CANCEL_CHECK_ROWS: usize = 1024;
+
+/// 执行器之间传递的一批数据的行数
+pub const BATCH_SIZE: usize = 1024;
+
+/// 按批读取的查询结果 上层执行器每次拉取一批 不需要的批不会被计算
+pub type Batches = Box<dyn Iterator<Item = Result<Rows>> + Send>;
+
 /// 执行器
 pub trait Executor<T: Transaction> {
     /// 执行器执行方法
     fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet>;
+
+    /// 按批执行 返回列信息和每批大约 BATCH_SIZE 行的迭代器
+    /// 默认全部执行完之后再分批 可以逐批处理的执行器自己实现
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        materialize(self, txn)
+    }
+}
+
+/// 全部执行完之后再分批
+pub fn materialize<T, E>(executor: Box<E>, txn: &mut T) -> Result<(Columns, Batches)>
+where
+    T: Transaction,
+    E: Executor<T> + ?Sized,
+{
+    match executor.execute(txn)? {
+        ResultSet::Query { columns, rows } => Ok((columns, batches(rows))),
+        r => Err(Error::Executor(format!(
+            "expect get resultset::query but get {:?}",
+            r
+        ))),
+    }
+}
+
+/// 把已经读出来的行分批
+pub fn batches(rows: Rows) -> Batches {
+    let mut rows = rows.into_iter();
+    Box::new(std::iter::from_fn(move || {
+        let batch: Rows = rows.by_ref().take(BATCH_SIZE).collect();
+        (!batch.is_empty()).then_some(Ok(batch))
+    }))
+}
+
+/// 逐行读取的结果分批 上层拉取一批的时候才读取这一批
+pub fn stream(rows: impl Iterator<Item = Result<Row>> + Send + 'static) -> Batches {
+    let mut rows = rows;
+    Box::new(std::iter::from_fn(move || {
+        match rows.by_ref().take(BATCH_SIZE).collect::<Result<Rows>>() {
+            Ok(batch) if batch.is_empty() => None,
+            batch => Some(batch),
+        }
+    }))
+}
+
+/// 读取所有的批 合并成一个查询结果
+pub fn collect(columns: Columns, batches: Batches) -> Result<ResultSet> {
+    let mut rows = Vec::new();
+    for batch in batches {
+        rows.extend(batch?);
+    }
+    Ok(ResultSet::Query { columns, rows })
 }
 
 impl<T: Transaction + 'static> dyn Executor<T> {
     /// 构建一个执行器
     pub fn build(node: Node) -> Box<dyn Executor<T>> {
-        match node {
+        Self::build_with(node, None)
+    }
+
+    /// 构建一个执行器 每个节点都会被包装起来收集统计信息
+    pub fn build_analyze(node: Node, stats: &Stats) -> Box<dyn Executor<T>> {
+        Self::build_with(node, Some(stats))
+    }
+
+    fn build_with(node: Node, stats: Option<&Stats>) -> Box<dyn Executor<T>> {
+        // 前序遍历分配位置 和 Node::format_analyze 的顺序一致
+        let id = stats.map(|stats| {
+            let mut stats = stats.lock().unwrap_or_else(|e| e.into_inner());
+            stats.push(NodeStats::default());
+            stats.len() - 1
+        });
+        let name = node.name();
+        let executor: Box<dyn Executor<T>> = match node {
             Node::Aggregation { source, aggregates } => {
-                Aggregation::new(Self::build(*source), aggregates)
+                Aggregation::new(Self::build_with(*source, stats), aggregates)
             }
-            Node::CreateTable { table, defaults } => CreateTable::new(table,defaults),
-            Node::Delete { table, source } => Delete::new(table, Self::build(*source)),
+            Node::CreateTable { table } => CreateTable::new(table),
+            Node::CreateTableAs { table, source } => {
+                CreateTableAs::new(table, Self::build_with(*source, stats))
+            }
+            Node::Delete {
+                table,
+                source,
+                returning,
+            } => Delete::new(table, Self::build_with(*source, stats), returning),
             Node::DropTable { table } => DeleteTable::new(table),
-            Node::Filter { source, predicate } => Filter::new(Self::build(*source), predicate),
+            Node::CreatePolicy { table, policy } => CreatePolicy::new(table, policy),
+            Node::DropPolicy { table, name } => DropPolicy::new(table, name),
+            Node::Truncate { table } => Truncate::new(table),
+            Node::CheckTable { table } => CheckTable::new(table),
+            Node::Analyze { table } => AnalyzeTable::new(table),
+            Node::Backup { path } => Backup::new(path),
+            Node::Restore { path } => Restore::new(path),
+            Node::Filter { source, predicate } => Filter::new(Self::build_with(*source, stats), predicate),
+            // having 和 filter 的执行是一样的 只是作用在聚合的结果上
+            Node::Having { source, predicate } => Filter::new(Self::build_with(*source, stats), predicate),
             Node::HashJoin {
                 left,
-                left_field,
+                left_fields,
                 right,
-                right_field,
+                right_fields,
+                null_safe,
+                predicate,
                 outer,
             } => HashJoin::new(
-                Self::build(*left),
-                left_field.0,
-                Self::build(*right),
-                right_field.0,
+                Self::build_with(*left, stats),
+                left_fields.into_iter().map(|(i, _)| i).collect(),
+                Self::build_with(*right, stats),
+                right_fields.into_iter().map(|(i, _)| i).collect(),
+                null_safe,
+                predicate,
                 outer,
             ),
             Node::IndexLookup {
@@ -58,45 +166,74 @@ impl<T: Transaction + 'static> dyn Executor<T> {
                 column,
                 values,
             } => IndexLookUp::new(table, column, values),
+            Node::IndexRangeScan {
+                table,
+                alias: _,
+                column,
+                range,
+            } => IndexRangeScan::new(table, column, range),
+            Node::IndexOnlyScan {
+                table,
+                alias: _,
+                column,
+                access,
+            } => IndexOnlyScan::new(table, column, access),
             Node::Insert {
                 table,
                 columns,
                 expressions,
-            } => Insert::new(table, columns, expressions),
+                on_conflict,
+                returning,
+                policy,
+            } => Insert::new(table, columns, expressions, on_conflict, returning, policy),
             Node::KeyLookup {
                 table,
                 alias: _,
                 keys,
             } => KeyLookUp::new(table, keys),
-            Node::Limit { source, limit } => Limit::new(Self::build(*source), limit),
+            Node::Limit {
+                source,
+                offset,
+                limit,
+            } => Limit::new(Self::build_with(*source, stats), offset, limit),
             Node::NestedLoopJoin {
                 left,
                 left_size: _,
                 right,
                 predicate,
                 outer,
-            } => NestedLoopJoin::new(Self::build(*left), Self::build(*right), predicate, outer),
+            } => NestedLoopJoin::new(Self::build_with(*left, stats), Self::build_with(*right, stats), predicate, outer),
             Node::Nothing => Nothing::new(),
-            Node::Offset { source, offset } => Offset::new(Self::build(*source), offset),
-            Node::Order { source, orders } => Order::new(Self::build(*source), orders),
+            Node::Values { columns, rows } => Values::new(columns, rows),
+            Node::Order { source, orders } => Order::new(Self::build_with(*source, stats), orders),
             Node::Projection {
                 source,
                 expressions,
-            } => Projection::new(Self::build(*source), expressions),
+            } => Projection::new(Self::build_with(*source, stats), expressions),
             Node::Scan {
                 table,
                 filter,
                 alias: _,
-            } => Scan::new(table, filter),
+                columns,
+            } => Scan::new(table, filter, columns),
             Node::Update {
                 table,
                 source,
                 set,
+                returning,
+                policy,
             } => Update::new(
                 table,
-                Self::build(*source),
+                Self::build_with(*source, stats),
                 set,
+                returning,
+                policy,
             ),
+        };
+        let executor = Metered::new(name, executor);
+        match (id, stats) {
+            (Some(id), Some(stats)) => Analyze::new(id, executor, stats.clone()),
+            _ => executor,
         }
     }
 }
@@ -117,6 +254,18 @@ pub enum ResultSet {
     Rollback {
         id: u64,
     },
+    // 创建保存点
+    Savepoint {
+        name: String,
+    },
+    // 回滚到保存点
+    RollbackToSavepoint {
+        name: String,
+    },
+    // 删除保存点
+    ReleaseSavepoint {
+        name: String,
+    },
     // 创建行
     Create {
         count: u64,
@@ -137,20 +286,91 @@ pub enum ResultSet {
     DropTable {
         name: String,
     },
+    // 创建行级安全策略
+    CreatePolicy {
+        name: String,
+    },
+    // 删除行级安全策略
+    DropPolicy {
+        name: String,
+    },
+    // 检查table 返回检查的行数
+    CheckTable {
+        name: String,
+        rows: u64,
+    },
+    // 收集table的统计信息 返回扫描的行数
+    Analyze {
+        name: String,
+        rows: u64,
+    },
+    // 备份 返回备份的版本和key的数量
+    Backup {
+        version: u64,
+        keys: u64,
+    },
+    // 恢复 返回备份的版本和key的数量
+    Restore {
+        version: u64,
+        keys: u64,
+    },
     // 查询结果
     Query {
-        columns: Vec<Option<String>>,
+        columns: Columns,
         rows: Rows,
     },
     // explain 结果
-    Explain(Node),
+    Explain {
+        plan: Node,
+        estimates: Vec<NodeEstimate>,
+    },
+    // explain analyze 结果 stats按照plan前序遍历的顺序
+    ExplainAnalyze {
+        plan: Node,
+        stats: Vec<NodeStats>,
+    },
+    // explain (format json) 结果
+    ExplainJson {
+        json: String,
+    },
+    // 设置会话变量
+    Set {
+        name: String,
+        value: Value,
+    },
 }
 
 pub type Row = Vec<Value>;
 pub type Rows = Vec<Row>;
-#[derive(Clone, Debug, PartialEq)]
+/// 查询结果的列信息 客户端可以根据类型转换数据
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Column {
     pub name: Option<String>,
+    /// 列的类型 None表示无法确定 比如 select null
+    pub column_type: Option<ColumnType>,
+    /// 是否可能为null
+    pub nullable: bool,
+}
+
+impl Column {
+    /// 只有名字 类型未知的列
+    pub fn new(name: Option<String>) -> Self {
+        Self {
+            name,
+            column_type: None,
+            nullable: true,
+        }
+    }
+}
+
+impl From<&super::Column> for Column {
+    fn from(column: &super::Column) -> Self {
+        Self {
+            name: Some(column.name.clone()),
+            column_type: Some(column.column_type.clone()),
+            nullable: column.nullable,
+        }
+    }
 }
 
 pub type Columns = Vec<Column>;
@@ -3,23 +3,63 @@
 
 use std::{collections::HashMap, ops::Index};
 
-use crate::sql::{engine::Transaction, execution::ResultSet, expression::Expression};
+use crate::sql::{
+    engine::Transaction,
+    execution::ResultSet,
+    expression::Expression,
+    plan::{OnConflict, Returning},
+    Table, Value,
+};
 
-use super::Executor;
+use super::{query::project, Column, Executor, Row};
 use crate::errors::*;
 
+/// 对修改之后的行计算 RETURNING 的表达式
+fn returning(
+    table: &Table,
+    rows: Vec<Row>,
+    expressions: Vec<(Expression, Option<String>)>,
+) -> Result<ResultSet> {
+    let columns = table.columns.iter().map(Column::from).collect::<Vec<_>>();
+    project(&columns, rows, expressions)
+}
+
+/// 写入的行不满足行级安全策略的时候报错 避免把行写到会话看不到的地方
+fn check_policy(table: &Table, policy: &Option<Expression>, row: &Row) -> Result<()> {
+    match policy {
+        Some(policy) if policy.evaluate(Some(row))? != Value::Bool(true) => Err(Error::sql(
+            ErrorCode::InsufficientPrivilege,
+            format!("new row violates row-level security policy for table {}", table.name),
+        )),
+        _ => Ok(()),
+    }
+}
+
 pub struct Insert {
     table: String,
     columns: Vec<String>,
     rows: Vec<Vec<Expression>>,
+    on_conflict: Option<OnConflict>,
+    returning: Option<Returning>,
+    policy: Option<Expression>,
 }
 
 impl Insert {
-    pub fn new(table: String, columns: Vec<String>, rows: Vec<Vec<Expression>>) -> Box<Self> {
+    pub fn new(
+        table: String,
+        columns: Vec<String>,
+        rows: Vec<Vec<Expression>>,
+        on_conflict: Option<OnConflict>,
+        returning: Option<Returning>,
+        policy: Option<Expression>,
+    ) -> Box<Self> {
         Box::new(Self {
             table,
             columns,
             rows,
+            on_conflict,
+            returning,
+            policy,
         })
     }
 }
@@ -30,6 +70,10 @@ impl<T: Transaction> Executor<T> for Insert {
         let table = txn.must_read_table(&self.table)?;
         let mut count = 0;
         let rows_len = self.rows.len();
+        // 没有on conflict的时候 所有行一起写入
+        let mut batch = Vec::new();
+        // 有 RETURNING 的时候记录写入的行
+        let mut written = Vec::new();
 
         // 如果没有columns 说明是table中的columns
         if self.columns.len() == 0 {
@@ -68,9 +112,9 @@ impl<T: Transaction> Executor<T> for Insert {
                 // 如果能在刚刚的map中找到，说明是用户自己插入的值
                 if let Some(value) = map.get(&column.name).cloned() {
                     row.push(value.clone())
-                // 否则是默认值
-                } else if let Some(value) = &column.default {
-                    row.push(value.clone())
+                // 否则是默认值 每一行单独计算
+                } else if let Some(default) = &column.default {
+                    row.push(default.evaluate(None)?)
                 } else {
                     // 没有默认值报错
                     return Err(Error::Table(format!(
@@ -79,11 +123,56 @@ impl<T: Transaction> Executor<T> for Insert {
                     )));
                 }
             }
-            txn.create(&table.name, row)?;
+            // 数字转换成列的类型 比如小数列的位数
+            let row = table.coerce_row(row)?;
+            check_policy(&table, &self.policy, &row)?;
+            let on_conflict = match &self.on_conflict {
+                Some(on_conflict) => on_conflict,
+                None => {
+                    batch.push(row);
+                    continue;
+                }
+            };
+            // 主键已经存在的时候 按照on conflict处理
+            let id = table.get_row_key(&row)?;
+            let row = match (on_conflict, txn.read(&table.name, &id)?) {
+                (OnConflict::Nothing, Some(_)) => continue,
+                (OnConflict::Update(set), Some(old)) => {
+                    // 看不到的行也不能通过冲突修改
+                    check_policy(&table, &self.policy, &old)?;
+                    // excluded的列排在已经存在的行之后
+                    let both = old.iter().chain(row.iter()).cloned().collect::<Vec<_>>();
+                    let mut new = old.clone();
+                    for (index, exp) in set.iter() {
+                        new[*index] = exp.evaluate(Some(&both))?;
+                    }
+                    let new = table.coerce_row(new)?;
+                    check_policy(&table, &self.policy, &new)?;
+                    txn.update(&table.name, &id, new.clone())?;
+                    new
+                }
+                _ => {
+                    txn.create(&table.name, row.clone())?;
+                    row
+                }
+            };
+            if self.returning.is_some() {
+                written.push(row);
+            }
             count = count + 1;
         }
+        if !batch.is_empty() {
+            count += batch.len() as u64;
+            if self.returning.is_some() {
+                written.extend(batch.iter().cloned());
+            }
+            txn.create_batch(&table.name, batch)?;
+        }
 
-        Ok(super::ResultSet::Create { count })
+        match self.returning {
+            Some(expressions) => returning(&table, written, expressions),
+            None => Ok(super::ResultSet::Create { count }),
+        }
     }
 }
 
@@ -91,6 +180,8 @@ pub struct Update<T: Transaction> {
     table: String,
     source: Box<dyn Executor<T>>,
     expression: Vec<(usize, Expression)>,
+    returning: Option<Returning>,
+    policy: Option<Expression>,
 }
 
 impl<T: Transaction> Update<T> {
@@ -98,11 +189,15 @@ impl<T: Transaction> Update<T> {
         table: String,
         source: Box<dyn Executor<T>>,
         expression: Vec<(usize, Expression)>,
+        returning: Option<Returning>,
+        policy: Option<Expression>,
     ) -> Box<Self> {
         Box::new(Self {
             table,
             source,
             expression,
+            returning,
+            policy,
         })
     }
 }
@@ -115,6 +210,7 @@ impl<T: Transaction> Executor<T> for Update<T> {
         match self.source.execute(txn)? {
             ResultSet::Query { columns, rows } => {
                 let mut count: u64 = 0;
+                let mut updated = Vec::new();
 
                 for mut row in rows {
                     let pk = row.get(key_index).cloned().ok_or(Error::Executor(format!(
@@ -127,13 +223,21 @@ impl<T: Transaction> Executor<T> for Update<T> {
                     for (index, exp) in self.expression.iter() {
                         new[*index] = exp.evaluate(Some(&row))?;
                     }
+                    let new = table.coerce_row(new)?;
+                    check_policy(&table, &self.policy, &new)?;
 
+                    if self.returning.is_some() {
+                        updated.push(new.clone());
+                    }
                     txn.update(&table.name, &pk, new)?;
 
                     count += 1;
                 }
 
-                Ok(ResultSet::Update { count })
+                match self.returning {
+                    Some(expressions) => returning(&table, updated, expressions),
+                    None => Ok(ResultSet::Update { count }),
+                }
             }
             r => Err(Error::Executor(format!(
                 "expect get query ersult set but get {:?}",
@@ -146,11 +250,20 @@ impl<T: Transaction> Executor<T> for Update<T> {
 pub struct Delete<T: Transaction> {
     table: String,
     source: Box<dyn Executor<T>>,
+    returning: Option<Returning>,
 }
 
 impl<T: Transaction> Delete<T> {
-    pub fn new(table: String, source: Box<dyn Executor<T>>) -> Box<Self> {
-        Box::new(Self { table, source })
+    pub fn new(
+        table: String,
+        source: Box<dyn Executor<T>>,
+        returning: Option<Returning>,
+    ) -> Box<Self> {
+        Box::new(Self {
+            table,
+            source,
+            returning,
+        })
     }
 }
 
@@ -163,16 +276,19 @@ impl<T: Transaction> Executor<T> for Delete<T> {
             ResultSet::Query { columns, rows } => {
                 let mut count: u64 = 0;
 
-                for row in rows {
+                for row in rows.iter() {
                     let pk = row.get(key_index).ok_or(Error::Executor(format!(
                         "try get key in row {:?} index {}",
                         row, key_index
                     )))?;
-                    txn.delete(&table.name, pk);
+                    txn.delete(&table.name, pk)?;
                     count += 1;
                 }
 
-                Ok(ResultSet::Update { count })
+                match self.returning {
+                    Some(expressions) => returning(&table, rows, expressions),
+                    None => Ok(ResultSet::Delete { count }),
+                }
             }
             r => Err(Error::Executor(format!(
                 "expect get query ersult set but get {:?}",
@@ -181,3 +297,182 @@ impl<T: Transaction> Executor<T> for Delete<T> {
         }
     }
 }
+
+pub struct Truncate {
+    table: String,
+}
+
+impl Truncate {
+    pub fn new(table: String) -> Box<Self> {
+        Box::new(Self { table })
+    }
+}
+
+impl<T: Transaction> Executor<T> for Truncate {
+    /// 返回删除的行数
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<super::ResultSet> {
+        let count = txn.truncate(&self.table)?;
+        Ok(ResultSet::Delete { count })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::Result;
+    use crate::sql::engine::kv::{test_engine, KV};
+    use crate::sql::engine::Engine;
+    use crate::sql::execution::ResultSet;
+    use crate::sql::Value;
+
+    /// 查询结果的列名和行
+    type Named = (Vec<Option<String>>, Vec<Vec<Value>>);
+
+    #[test]
+    fn upsert_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int );")?;
+        session.execute("insert into t values (1, 10), (2, 20);")?;
+
+        // 没有on conflict 主键冲突报错
+        assert!(session.execute("insert into t values (1, 11);").is_err());
+        assert_eq!(
+            session.execute("insert into t values (1, 11), (3, 30) on conflict (id) do nothing;")?,
+            ResultSet::Create { count: 1 }
+        );
+        assert_eq!(
+            session.execute(
+                "insert into t values (2, 0), (4, 40) on conflict (id) do update set n = n + 1;"
+            )?,
+            ResultSet::Create { count: 2 }
+        );
+        // excluded 引用要插入的行 不写表名的列是已经存在的行
+        assert_eq!(
+            session.execute(
+                "insert into t values (1, 5), (5, 50) on conflict (id) \
+                 do update set n = excluded.n + n;"
+            )?,
+            ResultSet::Create { count: 2 }
+        );
+        assert!(session
+            .execute("insert into t values (1, 1) on conflict (id) do update set n = excluded.m;")
+            .is_err());
+        // 只能在主键上检测冲突
+        assert!(session
+            .execute("insert into t values (1, 1) on conflict (n) do nothing;")
+            .is_err());
+
+        assert_eq!(
+            session.query("select id, n from t order by id asc;")?,
+            [(1, 15), (2, 21), (3, 30), (4, 40), (5, 50)]
+                .iter()
+                .map(|(id, n)| vec![Value::Integer(*id), Value::Integer(*n)])
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn default_expression_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute(
+            "create table t ( id int primary key, n int default 1 + 2, \
+             s string default concat(\"a\", \"b\"), d int default 1 DIV 0 );",
+        )?;
+        // 推导出来的类型和列不一致
+        assert!(session
+            .execute("create table u ( id int primary key, s string default 1 + 1 );")
+            .is_err());
+
+        // 插入的时候才计算 给了值的列不会计算默认值
+        session.execute("insert into t (id, d) values (1, 4);")?;
+        assert!(session.execute("insert into t (id) values (2);").is_err());
+        assert_eq!(
+            session.query("select * from t;")?,
+            vec![vec![
+                Value::Integer(1),
+                Value::Integer(3),
+                Value::String("ab".to_string()),
+                Value::Integer(4)
+            ]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn returning_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int );")?;
+        let mut query = |sql: &str| -> Result<Named> {
+            let (columns, rows) = session.query_columns(sql)?;
+            Ok((columns.into_iter().map(|c| c.name).collect(), rows))
+        };
+        let names = |names: &[&str]| names.iter().map(|n| Some(n.to_string())).collect::<Vec<_>>();
+        use Value::Integer;
+
+        assert_eq!(
+            query("insert into t values (1, 10), (2, 20) returning id, n * 2 as twice;")?,
+            (
+                names(&["id", "twice"]),
+                vec![vec![Integer(1), Integer(20)], vec![Integer(2), Integer(40)]]
+            )
+        );
+        // 冲突跳过的行不会返回
+        assert_eq!(
+            query("insert into t values (2, 0), (3, 30) on conflict (id) do nothing returning *;")?,
+            (names(&["id", "n"]), vec![vec![Integer(3), Integer(30)]])
+        );
+        // 返回的是更新之后的行
+        assert_eq!(
+            query("update t set n = 11 where id = 1 returning n;")?,
+            (names(&["n"]), vec![vec![Integer(11)]])
+        );
+        assert_eq!(
+            query("delete from t where id > 1 returning id;")?,
+            (names(&["id"]), vec![vec![Integer(2)], vec![Integer(3)]])
+        );
+        assert!(query("delete from t returning x;").is_err());
+        assert!(query("delete from t returning count(id);").is_err());
+        assert_eq!(query("select * from t;")?.1, vec![vec![Integer(1), Integer(11)]]);
+        Ok(())
+    }
+
+    #[test]
+    fn check_constraint_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute(
+            "create table t ( id int primary key, lo int check (lo >= 0), \
+             hi int null default null, check (hi is null or hi > lo) );",
+        )?;
+        // 约束必须是布尔值 不能有子查询
+        assert!(session
+            .execute("create table u ( id int primary key, n int check (n + 1) );")
+            .is_err());
+        assert!(session
+            .execute("create table u ( id int primary key, n int check (n > (select 1)) );")
+            .is_err());
+
+        // null 不算违反约束
+        session.execute("insert into t values (1, 0, null), (2, 1, 5);")?;
+        let err = |session: &mut crate::sql::engine::SqlSession<KV>, sql: &str| {
+            session.execute(sql).unwrap_err().to_string()
+        };
+        assert!(err(&mut session, "insert into t values (3, -1, null);").contains("t_lo_check"));
+        assert!(err(&mut session, "insert into t values (3, 2, 1);").contains("t_check"));
+        assert!(err(&mut session, "update t set hi = 0 where id = 2;").contains("t_check"));
+        let upsert = "insert into t values (2, 9, 1) on conflict (id) do update set lo = 9;";
+        assert!(err(&mut session, upsert).contains("t_check"));
+        session.execute("update t set hi = 2 where id = 2;")?;
+        assert_eq!(
+            session.query("select id, hi from t order by id asc;")?,
+            vec![
+                vec![Value::Integer(1), Value::Null],
+                vec![Value::Integer(2), Value::Integer(2)]
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,160 @@
+/* 并行执行 把数据分成几段 第一段在当前线程处理 其他的交给scoped线程
+ * 并行的线程数在启动的时候设置 并发的会话再多 同时运行的并行线程也不会超过这个数
+ * 线程都在用的时候剩下的分段在当前线程处理 任务可以直接借用执行器中的数据
+ * 线程在返回之前都会结束 然后按照分段的顺序合并结果
+ * */
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+
+use log::error;
+
+use crate::errors::*;
+use crate::sql::engine::default_workers;
+
+/// 数据少于这么多行的时候不并行 交给其他线程的开销比计算还大
+pub const PARALLEL_MIN_ROWS: usize = 8192;
+
+/// 所有会话共享的并行线程数
+static POOL: OnceLock<Pool> = OnceLock::new();
+
+struct Pool {
+    threads: usize,
+    /// 还可以启动的线程数
+    idle: Mutex<usize>,
+}
+
+impl Pool {
+    fn new(threads: usize) -> Self {
+        Self {
+            threads,
+            idle: Mutex::new(threads),
+        }
+    }
+
+    /// 占用一个线程 都在用的时候返回None
+    fn acquire(&self) -> Option<Permit<'_>> {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        if *idle == 0 {
+            return None;
+        }
+        *idle -= 1;
+        Some(Permit(self))
+    }
+}
+
+/// 线程结束或者没有启动的时候归还占用的线程
+struct Permit<'a>(&'a Pool);
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        *self.0.idle.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+    }
+}
+
+/// 设置并行的线程数 只在第一次并行执行之前有效 返回是否设置成功
+pub fn init_pool(threads: usize) -> bool {
+    let mut initialized = false;
+    POOL.get_or_init(|| {
+        initialized = true;
+        Pool::new(threads)
+    });
+    initialized
+}
+
+fn pool() -> &'static Pool {
+    POOL.get_or_init(|| Pool::new(default_workers()))
+}
+
+/// 分段panic的时候返回错误
+fn chunk_result<R>(result: thread::Result<Result<R>>) -> Result<R> {
+    result.unwrap_or_else(|_| Err(Error::Executor("parallel worker panicked".to_string())))
+}
+
+/// 把items平均分成最多workers段 每一段执行一次f 参数是这一段的起始位置和这一段的数据
+/// 结果按照分段的顺序返回 只有一个线程或者数据太少的时候直接在当前线程执行
+/// 结果可以借用items中的数据 f中可以再调用map_chunks 没有空闲线程的时候在当前线程执行
+pub fn map_chunks<'a, I, R, F>(items: &'a [I], workers: usize, f: F) -> Result<Vec<R>>
+where
+    I: Sync,
+    R: Send,
+    F: Fn(usize, &'a [I]) -> Result<R> + Sync,
+{
+    let pool = pool();
+    if workers <= 1 || pool.threads == 0 || items.len() < PARALLEL_MIN_ROWS {
+        return Ok(vec![f(0, items)?]);
+    }
+    let size = items.len().div_ceil(workers);
+    let chunks = items.chunks(size).collect::<Vec<_>>();
+    let f = &f;
+    thread::scope(|scope| {
+        let handles = chunks
+            .iter()
+            .copied()
+            .enumerate()
+            .skip(1)
+            .map(|(i, chunk)| {
+                let permit = pool.acquire()?;
+                let spawned = thread::Builder::new()
+                    .name(format!("coke-db-worker-{}", i))
+                    .spawn_scoped(scope, move || {
+                        let _permit = permit;
+                        f(i * size, chunk)
+                    });
+                spawned.map_err(|e| error!("start parallel worker get error {}", e)).ok()
+            })
+            .collect::<Vec<_>>();
+        let mut results = vec![panic::catch_unwind(AssertUnwindSafe(|| f(0, chunks[0])))];
+        for (i, handle) in handles.into_iter().enumerate() {
+            let i = i + 1;
+            results.push(match handle {
+                Some(handle) => handle.join(),
+                None => panic::catch_unwind(AssertUnwindSafe(|| f(i * size, chunks[i]))),
+            });
+        }
+        results.into_iter().map(chunk_result).collect()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn map_chunks_test() -> Result<()> {
+        let items = (0..PARALLEL_MIN_ROWS as u64 * 2).collect::<Vec<_>>();
+        let sums = map_chunks(&items, 4, |start, chunk| {
+            assert_eq!(chunk[0], start as u64);
+            Ok(chunk.iter().sum::<u64>())
+        })?;
+        assert_eq!(sums.len(), 4);
+        assert_eq!(sums.iter().sum::<u64>(), items.iter().sum::<u64>());
+        // 数据太少的时候不分段
+        assert_eq!(map_chunks(&items[..10], 4, |_, chunk| Ok(chunk.len()))?, vec![10]);
+        // 有一段出错的时候返回错误
+        assert!(map_chunks(&items, 4, |start, _| match start {
+            0 => Ok(()),
+            _ => Err(Error::Executor("failed".to_string())),
+        })
+        .is_err());
+        // 并发的调用共享并行线程 线程不够的时候在当前线程执行 分段的结果和顺序不变
+        thread::scope(|scope| {
+            let handles = (0..8)
+                .map(|_| scope.spawn(|| map_chunks(&items, 4, |start, _| Ok(start))))
+                .collect::<Vec<_>>();
+            for handle in handles {
+                let starts = handle.join().unwrap()?;
+                assert_eq!(starts, [0, 1, 2, 3].map(|i| i * PARALLEL_MIN_ROWS / 2));
+            }
+            Ok::<_, Error>(())
+        })?;
+        // 任务panic的时候返回错误
+        assert!(map_chunks(&items, 4, |start, _| match start {
+            0 => Ok(()),
+            _ => panic!("worker panic"),
+        })
+        .is_err());
+        Ok(())
+    }
+}
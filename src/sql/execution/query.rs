@@ -1,9 +1,16 @@
 use serde::de::Unexpected;
 
 use crate::sql::execution::Column;
-use crate::sql::{engine::Transaction, expression::Expression, OrderType};
+use crate::sql::plan::{Node, Plan};
+use crate::sql::engine::{Cancel, Transaction};
+use crate::sql::{expression::Expression, NullOrder, OrderType};
+use std::cell::RefCell;
+use crate::storage::spill::{SpillFile, SpillRun};
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Arc;
 
-use super::Executor;
+use super::{collect, Batches, Columns, Executor, Rows, CANCEL_CHECK_ROWS};
 use super::ResultSet;
 use crate::errors::*;
 use crate::sql::Value;
@@ -24,34 +31,39 @@ impl<T: Transaction> Filter<T> {
 /// 如果不是 布尔返回值就是错误的
 impl<T: Transaction> Executor<T> for Filter<T> {
     fn execute(self: Box<Self>, txn: &mut T) -> Result<super::ResultSet> {
-        match self.source.execute(txn)? {
-            ResultSet::Query { columns, rows } => {
-                let rows = rows
-                    .into_iter()
-                    .filter_map(|row| {
-                        let result = self.predicate.evaluate(Some(&row));
-                        match result {
-                            Ok(r) => match r {
-                                Value::Null => None,
-                                Value::Bool(false) => None,
-                                Value::Bool(true) => Some(Ok(row)),
-                                other => Some(Err(Error::Executor(format!(
-                                    "filter execution expect get bool but get {:?}",
-                                    other
-                                )))),
-                            },
-                            Err(e) => Some(Err(e)),
-                        }
-                    })
-                    .collect::<Result<Vec<_>>>()?;
-                Ok(ResultSet::Query { columns, rows })
-            }
-            r => Err(Error::Executor(format!(
-                "expect get resultset::query but get {:?}",
-                r
-            ))),
-        }
+        let (columns, batches) = self.execute_batches(txn)?;
+        collect(columns, batches)
     }
+
+    /// 每次过滤一批 过滤之后没有剩下的行就继续读下一批
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        let (columns, batches) = self.source.execute_batches(txn)?;
+        let (predicate, cancel) = (self.predicate, txn.canceller());
+        let batches = batches
+            .map(move |batch| {
+                cancel.check()?;
+                filter(&predicate, batch?)
+            })
+            .filter(|batch| !matches!(batch, Ok(rows) if rows.is_empty()));
+        Ok((columns, Box::new(batches)))
+    }
+}
+
+fn filter(predicate: &Expression, rows: Rows) -> Result<Rows> {
+    rows.into_iter()
+        .filter_map(|row| match predicate.evaluate(Some(&row)) {
+            Ok(r) => match r {
+                Value::Null => None,
+                Value::Bool(false) => None,
+                Value::Bool(true) => Some(Ok(row)),
+                other => Some(Err(Error::Executor(format!(
+                    "filter execution expect get bool but get {:?}",
+                    other
+                )))),
+            },
+            Err(e) => Some(Err(e)),
+        })
+        .collect()
 }
 
 pub struct Projection<T: Transaction> {
@@ -71,178 +83,628 @@ impl<T: Transaction> Projection<T> {
     }
 }
 
-impl<T: Transaction> Executor<T> for Projection<T> {
+impl<T: Transaction + 'static> Executor<T> for Projection<T> {
     fn execute(self: Box<Self>, txn: &mut T) -> Result<super::ResultSet> {
-        match self.source.execute(txn)? {
-            ResultSet::Query { columns, rows } => {
-                // 设置一下column 的label 没有就看看是不是filed 改成filed名字
-                let (expressions, labels): (Vec<Expression>, Vec<Option<String>>) =
-                    self.expressions.into_iter().unzip();
-
-                let columns: Vec<_> = expressions
-                    .iter()
-                    .enumerate()
-                    .map(|(i, e)| {
-                        if let Some(Some(label)) = labels.get(i) {
-                            Some(label.clone())
-                        } else if let Expression::Field(i, _) = e {
-                            columns.get(*i).cloned().unwrap_or(None)
-                        } else {
-                            None
+        let (columns, batches) = self.execute_batches(txn)?;
+        collect(columns, batches)
+    }
+
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        let (mut columns, batches) = self.source.execute_batches(txn)?;
+        // 每一行执行一次子查询 结果追加到行的后面 子查询换成对应的列
+        let width = columns.len();
+        let subqueries = RefCell::new(Vec::new());
+        let expressions = self
+            .expressions
+            .into_iter()
+            .map(|(e, label)| {
+                let e = e.transform(
+                    &|e| match e {
+                        Expression::Subquery(node) => {
+                            let mut subqueries = subqueries.borrow_mut();
+                            subqueries.push(*node);
+                            Ok(Expression::Field(width + subqueries.len() - 1, None))
                         }
-                    })
-                    .collect();
-
-                let rows: Result<Vec<_>> = rows
-                    .iter()
-                    .map(|r| {
-                        expressions
-                            .iter()
-                            .map(|e| e.evaluate(Some(&r)))
-                            .collect::<Result<Vec<_>>>()
-                    })
-                    .collect();
-
-                Ok(ResultSet::Query {
-                    columns,
-                    rows: rows?,
-                })
+                        e => Ok(e),
+                    },
+                    &|e| Ok(e),
+                )?;
+                Ok((e, label))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let subqueries = subqueries.into_inner();
+        if !subqueries.is_empty() {
+            columns.extend(subqueries.iter().map(|_| Column {
+                name: None,
+                column_type: None,
+                nullable: true,
+            }));
+        }
+        let (expressions, result_columns) = project_columns(&columns, expressions);
+        if subqueries.is_empty() {
+            let cancel = txn.canceller();
+            let batches = batches.map(move |batch| {
+                cancel.check()?;
+                project_rows(&expressions, batch?)
+            });
+            return Ok((result_columns, Box::new(batches)));
+        }
+        // 子查询用事务的只读副本执行 拉取一批的时候才计算这一批
+        let mut reader = match txn.reader()? {
+            Some(reader) => reader,
+            None => {
+                // 没有只读副本 只能在这里把每一批都算出来
+                let mut projected = Vec::new();
+                for batch in batches {
+                    let mut batch = batch?;
+                    for row in batch.iter_mut() {
+                        txn.check_cancel()?;
+                        for node in subqueries.iter() {
+                            let value = subquery(node.clone(), row, txn)?;
+                            row.push(value);
+                        }
+                    }
+                    projected.push(project_rows(&expressions, batch));
+                }
+                return Ok((result_columns, Box::new(projected.into_iter())));
+            }
+        };
+        let batches = batches.map(move |batch| {
+            let mut batch = batch?;
+            for row in batch.iter_mut() {
+                reader.check_cancel()?;
+                for node in subqueries.iter() {
+                    let value = subquery(node.clone(), row, &mut reader)?;
+                    row.push(value);
+                }
+            }
+            project_rows(&expressions, batch)
+        });
+        Ok((result_columns, Box::new(batches)))
+    }
+}
+
+/// 用外层的一行执行标量子查询 没有行的时候是null
+fn subquery<T: Transaction + 'static>(node: Node, row: &[Value], txn: &mut T) -> Result<Value> {
+    // 外层的值换成常量之后再优化 这样子查询中的条件也可以使用索引
+    let plan = Plan::new(node.bind_outer(row)?).optimize(txn)?;
+    match <dyn Executor<T>>::build(plan.node).execute(txn)? {
+        ResultSet::Query { columns, mut rows } => {
+            if columns.len() != 1 {
+                return Err(Error::Executor(format!(
+                    "subquery must return only one column but get {}",
+                    columns.len()
+                )));
+            }
+            match rows.len() {
+                0 => Ok(Value::Null),
+                1 => Ok(rows.remove(0).remove(0)),
+                n => Err(Error::Executor(format!(
+                    "subquery used as an expression must return at most one row but get {}",
+                    n
+                ))),
             }
-            r => Err(Error::Executor(format!(
-                "expect get resultset::query but get {:?}",
-                r
-            ))),
         }
+        r => Err(Error::Executor(format!(
+            "expect get resultset::query but get {:?}",
+            r
+        ))),
     }
 }
 
+/// 对每一行计算表达式 RETURNING 也使用这个
+pub(super) fn project(
+    columns: &[Column],
+    rows: Vec<Vec<Value>>,
+    expressions: Vec<(Expression, Option<String>)>,
+) -> Result<ResultSet> {
+    let (expressions, columns) = project_columns(columns, expressions);
+    Ok(ResultSet::Query {
+        columns,
+        rows: project_rows(&expressions, rows)?,
+    })
+}
+
+/// 计算投影之后的列
+fn project_columns(
+    columns: &[Column],
+    expressions: Vec<(Expression, Option<String>)>,
+) -> (Vec<Expression>, Columns) {
+    // 设置一下column 的label 没有就看看是不是filed 改成filed名字
+    let (expressions, labels): (Vec<Expression>, Vec<Option<String>>) =
+        expressions.into_iter().unzip();
+
+    let result_columns: Vec<_> = expressions
+        .iter()
+        .enumerate()
+        .map(|(i, e)| {
+            let name = if let Some(Some(label)) = labels.get(i) {
+                Some(label.clone())
+            } else if let Expression::Field(i, _) = e {
+                columns.get(*i).and_then(|c| c.name.clone())
+            } else {
+                None
+            };
+            let (column_type, nullable) = e.column_type(columns);
+            Column {
+                name,
+                column_type,
+                nullable,
+            }
+        })
+        .collect();
+    (expressions, result_columns)
+}
+
+fn project_rows(expressions: &[Expression], rows: Rows) -> Result<Rows> {
+    rows.iter()
+        .map(|r| {
+            expressions
+                .iter()
+                .map(|e| e.evaluate(Some(r)))
+                .collect::<Result<Vec<_>>>()
+        })
+        .collect()
+}
+
 pub struct Order<T: Transaction> {
     source: Box<dyn Executor<T>>,
-    order: Vec<(Expression, OrderType)>,
+    order: Vec<(Expression, OrderType, NullOrder)>,
 }
 
 impl<T: Transaction> Order<T> {
-    pub fn new(source: Box<dyn Executor<T>>, order: Vec<(Expression, OrderType)>) -> Box<Self> {
+    pub fn new(
+        source: Box<dyn Executor<T>>,
+        order: Vec<(Expression, OrderType, NullOrder)>,
+    ) -> Box<Self> {
         Box::new(Self { source, order })
     }
 }
 
-impl<T: Transaction> Executor<T> for Order<T> {
-    fn execute(self: Box<Self>, txn: &mut T) -> Result<super::ResultSet> {
-        match self.source.execute(txn)? {
-            ResultSet::Query { columns, rows } => {
-                struct Item {
-                    /// 这个是要存储的
-                    row: Vec<Value>,
-                    /// 这是个要排序的
-                    values: Vec<Value>,
-                }
-                let mut items = Vec::new();
-                for row in rows {
-                    let mut values = Vec::new();
-                    // 把需要排序的值进行计算
-                    for (expr, _) in self.order.iter() {
-                        values.push(expr.evaluate(Some(&row))?);
-                    }
-                    items.push(Item { row, values })
+/// 排序的值和原始的行
+type SortItem = (Vec<Value>, Vec<Value>);
+
+/// 按照排序键比较两行
+fn compare(order: &[(Expression, OrderType, NullOrder)], a: &[Value], b: &[Value]) -> Ordering {
+    for (i, (_, order, nulls)) in order.iter().enumerate() {
+        let value_a = &a[i];
+        let value_b = &b[i];
+        let o = match (value_a, value_b) {
+            (Value::Null, Value::Null) => Ordering::Equal,
+            // null的位置不受排序方向影响
+            (Value::Null, _) | (_, Value::Null) => {
+                let o = if *nulls == NullOrder::First {
+                    Ordering::Less
+                } else {
+                    Ordering::Greater
+                };
+                if value_a == &Value::Null {
+                    o
+                } else {
+                    o.reverse()
                 }
+            }
+            _ if *order == OrderType::ASC => value_a.cmp(value_b),
+            // 如果是 decs 需要反向排序
+            _ => value_b.cmp(value_a),
+        };
+        if o != Ordering::Equal {
+            return o;
+        }
+    }
+    Ordering::Equal
+}
 
-                let order = &self.order;
-                items.sort_by(|a, b| {
-                    for (i, (_, order)) in order.iter().enumerate() {
-                        let value_a = &a.values[i];
-                        let value_b = &b.values[i];
-                        match value_a.partial_cmp(value_b) {
-                            Some(std::cmp::Ordering::Equal) => {}
-                            // 要么大 要么 小于
-                            Some(o) => {
-                                // 如果是 decs 需要反向排序
-                                return if *order == OrderType::ASC {
-                                    o
-                                } else {
-                                    o.reverse()
-                                };
-                            }
-                            None => {}
-                        }
-                    }
-                    std::cmp::Ordering::Equal
-                });
+/// 归并的时候最多同时打开的有序段 段更多的时候先分组归并成更长的段
+const MERGE_FAN_IN: usize = 64;
 
-                Ok(ResultSet::Query {
-                    columns,
-                    rows: items.into_iter().map(|i| i.row).collect(),
-                })
+type SortOrder = Arc<Vec<(Expression, OrderType, NullOrder)>>;
+type SortRun = Box<dyn Iterator<Item = Result<SortItem>> + Send>;
+
+/// 归并时每个有序段当前的第一行
+struct MergeHead {
+    order: SortOrder,
+    item: SortItem,
+    /// 有序段的编号 相等的时候编号小的在前 保证归并之后还是稳定的
+    run: usize,
+}
+
+impl PartialEq for MergeHead {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for MergeHead {}
+
+impl PartialOrd for MergeHead {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for MergeHead {
+    /// BinaryHeap 是大顶堆 这里反过来比较
+    fn cmp(&self, other: &Self) -> Ordering {
+        compare(&self.order, &other.item.0, &self.item.0).then(other.run.cmp(&self.run))
+    }
+}
+
+/// 多路归并 每次取出所有段中最小的一行
+struct Merge {
+    order: SortOrder,
+    runs: Vec<SortRun>,
+    heap: BinaryHeap<MergeHead>,
+}
+
+impl Merge {
+    fn new(order: SortOrder, mut runs: Vec<SortRun>) -> Result<Self> {
+        let mut heap = BinaryHeap::new();
+        for (run, iter) in runs.iter_mut().enumerate() {
+            if let Some(item) = iter.next().transpose()? {
+                heap.push(MergeHead {
+                    order: order.clone(),
+                    item,
+                    run,
+                });
             }
-            r => Err(Error::Executor(format!(
-                "expect get resultset::query but get {:?}",
-                r
-            ))),
         }
+        Ok(Self { order, runs, heap })
+    }
+
+    fn pop(&mut self) -> Result<Option<SortItem>> {
+        let head = match self.heap.pop() {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        if let Some(item) = self.runs[head.run].next().transpose()? {
+            self.heap.push(MergeHead {
+                order: self.order.clone(),
+                item,
+                run: head.run,
+            });
+        }
+        Ok(Some(head.item))
     }
 }
 
-pub struct Limit<T: Transaction> {
-    source: Box<dyn Executor<T>>,
-    limit: Expression,
+impl Iterator for Merge {
+    type Item = Result<SortItem>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.pop().transpose()
+    }
 }
 
-impl<T: Transaction> Limit<T> {
-    pub fn new(source: Box<dyn Executor<T>>, limit: Expression) -> Box<Self> {
-        Box::new(Self { source, limit })
+/// 对一段数据排序之后写到临时文件 写完先关闭 归并的时候再打开
+fn spill(order: &SortOrder, mut items: Vec<SortItem>) -> Result<SpillRun<SortItem>> {
+    items.sort_by(|a, b| compare(order, &a.0, &b.0));
+    let mut file = SpillFile::new(&std::env::temp_dir())?;
+    for item in items.iter() {
+        file.write(item)?;
     }
+    file.finish()
 }
 
-impl<T: Transaction> Executor<T> for Limit<T> {
+/// 相邻的 MERGE_FAN_IN 个段归并成一段 直到剩下的段可以同时打开
+/// 分组的时候保持段的顺序 相等的行还是按照读到的顺序
+fn merge_runs(
+    order: &SortOrder,
+    cancel: &Cancel,
+    mut runs: Vec<SpillRun<SortItem>>,
+) -> Result<Vec<SpillRun<SortItem>>> {
+    // 最后一段还在内存中 也占一路
+    while runs.len() >= MERGE_FAN_IN {
+        let mut merged = Vec::new();
+        let mut runs_iter = runs.into_iter().peekable();
+        while runs_iter.peek().is_some() {
+            let group = runs_iter
+                .by_ref()
+                .take(MERGE_FAN_IN)
+                .map(|r| Ok(Box::new(r.into_reader()?) as SortRun))
+                .collect::<Result<Vec<_>>>()?;
+            let mut file = SpillFile::new(&std::env::temp_dir())?;
+            for item in Merge::new(order.clone(), group)? {
+                if file.len() % CANCEL_CHECK_ROWS == 0 {
+                    cancel.check()?;
+                }
+                file.write(&item?)?;
+            }
+            merged.push(file.finish()?);
+        }
+        runs = merged;
+    }
+    Ok(runs)
+}
+
+impl<T: Transaction> Executor<T> for Order<T> {
     fn execute(self: Box<Self>, txn: &mut T) -> Result<super::ResultSet> {
-        // 先计算出来limit的value
-        let limit = self.limit.evaluate(None)?;
-        match limit {
-            Value::Integer(i) => match self.source.execute(txn)? {
-                ResultSet::Query { columns, rows } => Ok(ResultSet::Query {
-                    columns,
-                    rows: rows.into_iter().take(i as usize).collect(),
-                }),
-                r => Err(Error::Executor(format!(
-                    "expect get resultset::query but get {:?}",
-                    r
-                ))),
-            },
-            unexpect => Err(Error::Executor(format!(
-                "get unexpect limit value {}",
-                unexpect
-            ))),
+        let (columns, batches) = self.execute_batches(txn)?;
+        collect(columns, batches)
+    }
+
+    /// 数据超过 work_memory 的时候 每一段排好序写到临时文件 最后一边归并一边返回
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        let Self { source, order } = *self;
+        let order: SortOrder = Arc::new(order);
+        // 逐批读取 超过内存限制的部分不需要等到所有的行都读出来再写到临时文件
+        let (columns, batches) = source.execute_batches(txn)?;
+        let memory = txn.work_memory();
+        let mut runs = Vec::new();
+        let mut items = Vec::new();
+        let mut size = 0;
+        for batch in batches {
+            for row in batch? {
+                let mut values = Vec::new();
+                // 把需要排序的值进行计算
+                for (expr, _, _) in order.iter() {
+                    values.push(expr.evaluate(Some(&row))?);
+                }
+                let item = (values, row);
+                size += bincode::serialized_size(&item)? as usize;
+                items.push(item);
+                if size > memory {
+                    txn.check_cancel()?;
+                    runs.push(spill(&order, std::mem::take(&mut items))?);
+                    size = 0;
+                }
+            }
+        }
+
+        // sort_by 是稳定排序 所有排序的值都相等的行保持原来的顺序
+        items.sort_by(|a, b| compare(&order, &a.0, &b.0));
+        if runs.is_empty() {
+            let rows = items.into_iter().map(|(_, row)| Ok(row));
+            return Ok((columns, super::stream(rows)));
         }
+        let cancel = txn.canceller();
+        let mut runs = merge_runs(&order, &cancel, runs)?
+            .into_iter()
+            .map(|r| Ok(Box::new(r.into_reader()?) as SortRun))
+            .collect::<Result<Vec<_>>>()?;
+        runs.push(Box::new(items.into_iter().map(Ok)));
+        let mut count = 0;
+        let rows = Merge::new(order, runs)?.map(move |item| {
+            count += 1;
+            if count % CANCEL_CHECK_ROWS == 0 {
+                cancel.check()?;
+            }
+            Ok(item?.1)
+        });
+        Ok((columns, super::stream(rows)))
     }
 }
-pub struct Offset<T: Transaction> {
+
+pub struct Limit<T: Transaction> {
     source: Box<dyn Executor<T>>,
-    offset: Expression,
+    offset: Option<Expression>,
+    limit: Option<Expression>,
 }
 
-impl<T: Transaction> Offset<T> {
-    pub fn new(source: Box<dyn Executor<T>>, offset: Expression) -> Box<Self> {
-        Box::new(Self { source, offset })
+impl<T: Transaction> Limit<T> {
+    pub fn new(
+        source: Box<dyn Executor<T>>,
+        offset: Option<Expression>,
+        limit: Option<Expression>,
+    ) -> Box<Self> {
+        Box::new(Self {
+            source,
+            offset,
+            limit,
+        })
+    }
+
+    /// 计算出来limit或者offset的值 必须是非负整数
+    fn evaluate(expr: Option<Expression>, name: &str) -> Result<Option<usize>> {
+        match expr.map(|e| e.evaluate(None)).transpose()? {
+            None => Ok(None),
+            Some(Value::Integer(i)) if i >= 0 => Ok(Some(i as usize)),
+            Some(unexpect) => Err(Error::Executor(format!(
+                "get unexpect {} value {}",
+                name, unexpect
+            ))),
+        }
     }
 }
-impl<T: Transaction> Executor<T> for Offset<T> {
+
+impl<T: Transaction> Executor<T> for Limit<T> {
     fn execute(self: Box<Self>, txn: &mut T) -> Result<super::ResultSet> {
-        let offset = self.offset.evaluate(None)?;
-        match offset {
-            Value::Integer(i) => match self.source.execute(txn)? {
-                ResultSet::Query { columns, rows } => Ok(ResultSet::Query {
-                    columns,
-                    rows: rows.into_iter().skip(i as usize).collect(),
-                }),
-                r => Err(Error::Executor(format!(
-                    "expect get resultset::query but get {:?}",
-                    r
-                ))),
-            },
-            unexpect => Err(Error::Executor(format!(
-                "get unexpect offset value {}",
-                unexpect
-            ))),
-        }
+        let (columns, batches) = self.execute_batches(txn)?;
+        collect(columns, batches)
+    }
+
+    /// 取够行数之后就不再向下层拉取
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        // 先计算出来offset和limit的value
+        let mut offset = Self::evaluate(self.offset, "offset")?.unwrap_or(0);
+        let mut limit = Self::evaluate(self.limit, "limit")?;
+        let (columns, mut batches) = self.source.execute_batches(txn)?;
+        let batches = std::iter::from_fn(move || loop {
+            if limit == Some(0) {
+                return None;
+            }
+            let mut batch = match batches.next()? {
+                Ok(batch) => batch,
+                Err(e) => return Some(Err(e)),
+            };
+            let skip = offset.min(batch.len());
+            batch.drain(..skip);
+            offset -= skip;
+            if let Some(limit) = limit.as_mut() {
+                batch.truncate(*limit);
+                *limit -= batch.len();
+            }
+            if !batch.is_empty() {
+                return Some(Ok(batch));
+            }
+        });
+        Ok((columns, Box::new(batches)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::Result;
+    use crate::sql::engine::kv::{test_engine, KV};
+    use crate::sql::engine::Engine;
+    use crate::sql::execution::{Column, BATCH_SIZE};
+    use crate::sql::{ColumnType, Value};
+    use crate::storage::kv::{b_tree::BtreeStore, MVCC};
+
+    #[test]
+    fn column_metadata_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, f float null default null, s string not null );")?;
+        session.execute("insert into t values (1, 1.5, \"a\");")?;
+
+        let column = |name: &str, column_type, nullable| Column {
+            name: Some(name.to_string()),
+            column_type,
+            nullable,
+        };
+        let sql = "select id, f * 2 as g, s = \"a\" as b, count(*) as c, null as n from t \
+                   group by t.id, t.f, t.s;";
+        assert_eq!(
+            session.query_columns(sql)?.0,
+            vec![
+                column("id", Some(ColumnType::Integer), false),
+                column("g", Some(ColumnType::Float), true),
+                column("b", Some(ColumnType::Bool), false),
+                column("c", Some(ColumnType::Integer), false),
+                column("n", None, true),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn limit_offset_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key );")?;
+        session.execute("insert into t values (1), (2), (3), (4), (5);")?;
+
+        let mut query = |sql: &str| -> Result<Vec<Value>> {
+            Ok(session.query(sql)?.into_iter().flatten().collect())
+        };
+        let ids = |ids: &[i64]| ids.iter().map(|i| Value::Integer(*i)).collect::<Vec<_>>();
+        assert_eq!(query("select id from t order by id asc limit 2;")?, ids(&[1, 2]));
+        assert_eq!(query("select id from t order by id asc limit 2 offset 1;")?, ids(&[2, 3]));
+        assert_eq!(query("select id from t order by id asc offset 1 limit 2;")?, ids(&[2, 3]));
+        // limit offset, count
+        assert_eq!(query("select id from t order by id asc limit 3, 1;")?, ids(&[4]));
+        assert_eq!(query("select id from t order by id asc offset 3;")?, ids(&[4, 5]));
+        assert!(query("select id from t limit -1;").is_err());
+        Ok(())
+    }
+    #[test]
+    fn batch_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int );")?;
+        let values = (0..3000)
+            .map(|i| format!("({}, {})", i, i % 3))
+            .collect::<Vec<_>>();
+        session.execute(&format!("insert into t values {};", values.join(", ")))?;
+
+        let mut query = |sql: &str| -> Result<Vec<Value>> {
+            Ok(session.query(sql)?.into_iter().flatten().collect())
+        };
+        let ids = |ids: &[i64]| ids.iter().map(|i| Value::Integer(*i)).collect::<Vec<_>>();
+        assert_eq!(query("select id * 2 from t;")?.len(), 3000);
+        // 取的行跨过了两批
+        assert_eq!(query("select id * 2 from t limit 3 offset 1022;")?, ids(&[2044, 2046, 2048]));
+        assert_eq!(
+            query("select id * 2 from t where n = 0 limit 2 offset 400;")?,
+            ids(&[2400, 2406])
+        );
+        assert_eq!(query("select id from t limit 2 offset 2999;")?, ids(&[2999]));
+
+        // 取够行数之后不再计算后面的批
+        let (_, stats) = session.explain_analyze("select id * 2 from t limit 5;")?;
+        assert_eq!(
+            stats.iter().map(|s| s.rows).collect::<Vec<_>>(),
+            vec![5, BATCH_SIZE as u64, BATCH_SIZE as u64]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn leaf_batches_test() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(BtreeStore::new()));
+        let engine = KV::new(mvcc.clone());
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int index );")?;
+        let values = (0..3000).map(|i| format!("({}, {})", i, i % 2)).collect::<Vec<_>>();
+        session.execute(&format!("insert into t values {};", values.join(", ")))?;
+
+        // 通过索引读取的行也是拉取一批才读一批
+        let (_, stats) = session.explain_analyze("select * from t where n = 0 limit 5;")?;
+        assert_eq!(stats.last().map(|s| s.rows), Some(BATCH_SIZE as u64));
+
+        // 游标的事务已经结束 清空表和垃圾回收之后还是读到打开时候的数据
+        let (id, _) = session.open_cursor("select * from t where n = 1;")?;
+        assert_eq!(session.fetch(id, 1)?.len(), 1);
+        engine.session()?.execute("truncate table t;")?;
+        mvcc.vacuum()?;
+        assert_eq!(session.fetch(id, 2000)?.len(), 1499);
+        Ok(())
+    }
+
+    #[test]
+    fn order_nulls_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int null default null, g int );")?;
+        session.execute("insert into t values (1, 2, 1), (2, null, 1), (3, 1, 2), (4, null, 2), (5, 2, 2);")?;
+
+        let mut query = |sql: &str| -> Result<Vec<Value>> {
+            Ok(session.query(sql)?.into_iter().map(|r| r[0].clone()).collect())
+        };
+        let ids = |ids: &[i64]| ids.iter().map(|i| Value::Integer(*i)).collect::<Vec<_>>();
+        // 默认asc null当作最小的值
+        assert_eq!(query("select id from t order by n, id;")?, ids(&[2, 4, 3, 1, 5]));
+        assert_eq!(query("select id from t order by n desc, id;")?, ids(&[1, 5, 3, 2, 4]));
+        assert_eq!(
+            query("select id from t order by n asc nulls last, id;")?,
+            ids(&[3, 1, 5, 2, 4])
+        );
+        assert_eq!(
+            query("select id from t order by n desc nulls first, id desc;")?,
+            ids(&[4, 2, 5, 1, 3])
+        );
+        // 后面的排序键只在前面相等的时候生效
+        assert_eq!(
+            query("select id from t order by g desc, n nulls last, id;")?,
+            ids(&[3, 5, 4, 1, 2])
+        );
+        assert!(query("select id from t order by n nulls;").is_err());
+        Ok(())
+    }
+    #[test]
+    fn external_sort_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int null default null );")?;
+        let values = (0..500)
+            .map(|i| match i % 7 {
+                0 => format!("({}, null)", i),
+                _ => format!("({}, {})", i, (i * 37) % 11),
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        session.execute(&format!("insert into t values {};", values))?;
+        let sql = "select id, n from t order by n desc nulls first;";
+        let expect = session.query(sql)?;
+        // 内存很小的时候会分成很多段写到临时文件 段比 MERGE_FAN_IN 多 要先分组归并一次
+        // 结果和内存中排序一样 相等的行也保持扫描的顺序
+        session.execute("set work_memory = 256;")?;
+        assert_eq!(session.query(sql)?, expect);
+        assert_eq!(expect.len(), 500);
+        assert_eq!(expect[0], vec![Value::Integer(0), Value::Null]);
+        assert_eq!(expect[500 - 1][1], Value::Integer(0));
+        assert!(session.execute("set work_memory = 0;").is_err());
+        Ok(())
     }
 }
@@ -1,44 +1,110 @@
+use std::collections::{HashMap, HashSet};
 use std::default;
 
-use super::{Executor, ResultSet};
+use super::{Executor, ResultSet, CANCEL_CHECK_ROWS};
 use crate::errors::*;
-use crate::sql::expression::Expression;
-use crate::sql::Value;
 /// 设置表结构的sql执行
 /// 不设置更新表结构
-use crate::sql::{engine::Transaction, Table};
+use crate::sql::statistics::{ColumnStats, TableStats};
+use crate::sql::{engine::Transaction, expression::Expression, Column, Policy, Table, Value};
 
 pub struct CreateTable {
     table: Table,
-    defaults: Vec<Option<Expression>>,
 }
 
 impl CreateTable {
-    pub fn new(table: Table, defaults: Vec<Option<Expression>>) -> Box<Self> {
-        Box::new(Self { table, defaults })
+    pub fn new(table: Table) -> Box<Self> {
+        Box::new(Self { table })
     }
 }
 
 impl<T: Transaction> Executor<T> for CreateTable {
-    fn execute(mut self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
         let name = self.table.name.clone();
-        // 之前default没有计算常量
-        let defaults = self
-            .defaults
-            .into_iter()
-            .map(|d| d.map(|de| de.evaluate(None)).transpose())
-            .collect::<Result<Vec<Option<Value>>>>()?;
-        self.table
-            .columns
-            .iter_mut()
-            .zip(defaults)
-            .for_each(|(c, d)| (*c).default = d);
-
         txn.create_table(self.table)?;
         Ok(ResultSet::CreateTable { name })
     }
 }
 
+/// CREATE TABLE AS SELECT 第一列是主键 其他列都可以是null 默认值是null
+/// 列的类型是查询结果的类型 推导不出来的时候用第一个不是null的值的类型
+pub struct CreateTableAs<T: Transaction> {
+    table: String,
+    source: Box<dyn Executor<T>>,
+}
+
+impl<T: Transaction> CreateTableAs<T> {
+    pub fn new(table: String, source: Box<dyn Executor<T>>) -> Box<Self> {
+        Box::new(Self { table, source })
+    }
+}
+
+impl<T: Transaction> Executor<T> for CreateTableAs<T> {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        let (columns, rows) = match self.source.execute(txn)? {
+            ResultSet::Query { columns, rows } => (columns, rows),
+            r => {
+                return Err(Error::Executor(format!(
+                    "expect get resultset::query but get {:?}",
+                    r
+                )))
+            }
+        };
+        let mut names = HashSet::new();
+        let columns = columns
+            .into_iter()
+            .enumerate()
+            .map(|(i, column)| {
+                let name = column.name.ok_or_else(|| {
+                    Error::Executor(format!(
+                        "column {} of table {} has no name, use AS to name it",
+                        i + 1,
+                        self.table
+                    ))
+                })?;
+                if !names.insert(name.clone()) {
+                    return Err(Error::Executor(format!(
+                        "try to create table that has repeat column name: {}",
+                        name
+                    )));
+                }
+                let column_type = column
+                    .column_type
+                    .or_else(|| rows.iter().find_map(|row| row[i].datatype()))
+                    .ok_or_else(|| {
+                        Error::Executor(format!("can't infer the type of column {}", name))
+                    })?;
+                Ok(Column {
+                    name,
+                    column_type,
+                    primary_key: i == 0,
+                    nullable: i != 0,
+                    // 可以是null的列默认值是null
+                    default: (i != 0).then_some(Expression::Constant(Value::Null)),
+                    unique: false,
+                    index: false,
+                    references: None,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let table = Table {
+            name: self.table.clone(),
+            columns,
+            checks: Vec::new(),
+            ttl: None,
+            policies: Vec::new(),
+            statistics: None,
+        };
+        txn.create_table(table.clone())?;
+        let rows = rows
+            .into_iter()
+            .map(|row| table.coerce_row(row))
+            .collect::<Result<Vec<_>>>()?;
+        txn.create_batch(&table.name, rows)?;
+        Ok(ResultSet::CreateTable { name: self.table })
+    }
+}
+
 pub struct DeleteTable {
     table: String,
 }
@@ -55,3 +121,238 @@ impl<T: Transaction> Executor<T> for DeleteTable {
         Ok(ResultSet::DropTable { name: self.table })
     }
 }
+
+pub struct CreatePolicy {
+    table: String,
+    policy: Policy,
+}
+
+impl CreatePolicy {
+    pub fn new(table: String, policy: Policy) -> Box<Self> {
+        Box::new(Self { table, policy })
+    }
+}
+
+impl<T: Transaction> Executor<T> for CreatePolicy {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        let mut table = txn.must_read_table(&self.table)?;
+        if table.policies.iter().any(|p| p.name == self.policy.name) {
+            return Err(Error::Schema(format!(
+                "policy {} already exists on table {}",
+                self.policy.name, table.name
+            )));
+        }
+        let name = self.policy.name.clone();
+        table.policies.push(self.policy);
+        txn.update_table(table)?;
+        Ok(ResultSet::CreatePolicy { name })
+    }
+}
+
+pub struct DropPolicy {
+    table: String,
+    name: String,
+}
+
+impl DropPolicy {
+    pub fn new(table: String, name: String) -> Box<Self> {
+        Box::new(Self { table, name })
+    }
+}
+
+impl<T: Transaction> Executor<T> for DropPolicy {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        let mut table = txn.must_read_table(&self.table)?;
+        let len = table.policies.len();
+        table.policies.retain(|p| p.name != self.name);
+        if table.policies.len() == len {
+            return Err(Error::Schema(format!(
+                "policy {} does not exist on table {}",
+                self.name, table.name
+            )));
+        }
+        txn.update_table(table)?;
+        Ok(ResultSet::DropPolicy { name: self.name })
+    }
+}
+
+/// 检查表 行的校验和在读取的时候检查 再检查每一行的类型和索引是否一致
+/// 发现问题返回 Error::Corruption
+pub struct CheckTable {
+    table: String,
+}
+
+impl CheckTable {
+    pub fn new(table: String) -> Box<Self> {
+        Box::new(Self { table })
+    }
+}
+
+impl<T: Transaction> Executor<T> for CheckTable {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        let table = txn.must_read_table(&self.table)?;
+        let rows = txn.scan(&table.name, None, None)?;
+        let key_index = table.get_key_index()?;
+        for (i, row) in rows.iter().enumerate() {
+            if i % CANCEL_CHECK_ROWS == 0 {
+                txn.check_cancel()?;
+            }
+            if row.len() != table.columns.len() {
+                return Err(Error::Corruption(format!(
+                    "row {:?} of table {} has {} columns, expect {}",
+                    row.get(key_index),
+                    table.name,
+                    row.len(),
+                    table.columns.len()
+                )));
+            }
+            for (column, value) in table.columns.iter().zip(row.iter()) {
+                column.validate_type(value).map_err(|e| {
+                    Error::Corruption(format!(
+                        "row {} of table {} is invalid: {}",
+                        row[key_index], table.name, e
+                    ))
+                })?;
+            }
+        }
+
+        // 索引中每个值对应的主键要和行中的一样 过期还没有删除的行扫描不到 但是还在索引中
+        let expired = txn.expired(&table.name)?.into_iter().collect::<HashSet<_>>();
+        for (i, column) in table.columns.iter().enumerate().filter(|(_, c)| c.index) {
+            let mut expect: HashMap<&Value, HashSet<Value>> = HashMap::new();
+            for row in rows.iter() {
+                expect
+                    .entry(&row[i])
+                    .or_default()
+                    .insert(row[key_index].clone());
+            }
+            for (value, mut keys) in txn.scan_index(&table.name, &column.name)? {
+                keys.retain(|key| !expired.contains(key));
+                if keys.is_empty() && !expect.contains_key(&value) {
+                    continue;
+                }
+                if expect.remove(&value).as_ref() != Some(&keys) {
+                    return Err(Error::Corruption(format!(
+                        "index {}.{} is inconsistent with rows at value {}",
+                        table.name, column.name, value
+                    )));
+                }
+            }
+            if let Some(value) = expect.keys().next() {
+                return Err(Error::Corruption(format!(
+                    "index {}.{} is missing value {}",
+                    table.name, column.name, value
+                )));
+            }
+        }
+
+        Ok(ResultSet::CheckTable {
+            name: table.name,
+            rows: rows.len() as u64,
+        })
+    }
+}
+
+/// 扫描全表 给每一列建立直方图 保存在表的定义中
+pub struct AnalyzeTable {
+    table: String,
+}
+
+impl AnalyzeTable {
+    pub fn new(table: String) -> Box<Self> {
+        Box::new(Self { table })
+    }
+}
+
+impl<T: Transaction> Executor<T> for AnalyzeTable {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        let mut table = txn.must_read_table(&self.table)?;
+        let rows = txn.scan(&table.name, None, None)?;
+        let mut columns = vec![Vec::with_capacity(rows.len()); table.columns.len()];
+        for (i, row) in rows.iter().enumerate() {
+            if i % CANCEL_CHECK_ROWS == 0 {
+                txn.check_cancel()?;
+            }
+            for (values, value) in columns.iter_mut().zip(row) {
+                values.push(value.clone());
+            }
+        }
+        table.statistics = Some(TableStats {
+            rows: rows.len() as u64,
+            columns: table
+                .columns
+                .iter()
+                .zip(columns)
+                .map(|(column, values)| (column.name.clone(), ColumnStats::build(values)))
+                .collect(),
+        });
+        let name = table.name.clone();
+        txn.update_table(table)?;
+        Ok(ResultSet::Analyze {
+            name,
+            rows: rows.len() as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::*;
+    use crate::sql::engine::kv::test_engine;
+    use crate::sql::decimal::Decimal;
+    use crate::sql::engine::{Engine, Transaction};
+    use crate::sql::schema::Catalog;
+    use crate::sql::{ColumnType, Value};
+    use crate::storage::kv::mvcc::Mode;
+
+    #[test]
+    fn create_table_as_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, g int, s string, d decimal(6, 2) );")?;
+        session.execute(
+            "insert into t values (1, 1, \"a\", 1.5), (2, 1, \"b\", 2), (3, 2, \"c\", 0);",
+        )?;
+
+        session.execute(
+            "create table g as select g, count(id) n, sum(d) total, max(s) s from t group by g;",
+        )?;
+        let txn = engine.begin(Mode::ReadOnly)?;
+        let table = txn.must_read_table("g")?;
+        assert_eq!(
+            table
+                .columns
+                .iter()
+                .map(|c| (c.name.as_str(), c.column_type.clone(), c.primary_key))
+                .collect::<Vec<_>>(),
+            vec![
+                ("g", ColumnType::Integer, true),
+                ("n", ColumnType::Integer, false),
+                ("total", ColumnType::Decimal(6, 2), false),
+                ("s", ColumnType::String, false),
+            ]
+        );
+        assert_eq!(txn.scan("g", None, None)?.len(), 2);
+        txn.commit()?;
+        assert_eq!(
+            session.query("select total from g where g = 1;")?,
+            vec![vec![Value::Decimal(Decimal::new(350, 2)?)]]
+        );
+        session.execute("create table v as values (1, \"x\"), (2, null);")?;
+        session.execute("insert into v values (3, \"z\");")?;
+
+        // 第一列是主键 重复的时候整个语句失败 表也不会创建
+        assert!(session.execute("create table e as select g from t;").is_err());
+        assert!(session.execute("select * from e;").is_err());
+        // 列要有名字 类型要能推导出来
+        for sql in [
+            "create table e as select id + 1 from t;",
+            "create table e as select id, null n from t;",
+            "create table e as select id, g id from t;",
+            "create table t as select id from t;",
+        ] {
+            assert!(session.execute(sql).is_err(), "{}", sql);
+        }
+        Ok(())
+    }
+}
@@ -3,9 +3,15 @@ use std::collections::HashSet;
 use log::debug;
 
 /// source文件，最低层的执行器，用于执行扫描文件
-use crate::sql::{engine::Transaction, execution::ResultSet, expression::Expression, Value};
+use crate::sql::{
+    engine::{IndexRange, Transaction},
+    execution::{Column, ResultSet},
+    expression::Expression,
+    plan::IndexAccess,
+    ColumnType, Value,
+};
 
-use super::Executor;
+use super::{materialize, stream, Batches, Columns, Executor};
 use crate::errors::*;
 
 pub struct Scan {
@@ -13,27 +19,44 @@ pub struct Scan {
     table: String,
     /// 扫描的filter条件
     filter: Option<Expression>,
+    /// 需要读取的列
+    columns: Option<Vec<usize>>,
 }
 
 impl Scan {
-    pub fn new(table: String, filter: Option<Expression>) -> Box<Self> {
-        Box::new(Self { table, filter })
+    pub fn new(table: String, filter: Option<Expression>, columns: Option<Vec<usize>>) -> Box<Self> {
+        Box::new(Self {
+            table,
+            filter,
+            columns,
+        })
     }
 }
 
 impl<T: Transaction> Executor<T> for Scan {
     fn execute(self: Box<Self>, txn: &mut T) -> Result<super::ResultSet> {
         debug!("table {:#?} , scan filter {:#?}",self.table,self.filter);
-        let rows = txn.scan(&self.table, self.filter)?;
+        let rows = txn.scan(&self.table, self.filter, self.columns)?;
         let columns: Vec<_> = txn
             .must_read_table(&self.table)?
             .columns
             .iter()
-            .map(|c| Some(c.name.to_string()))
+            .map(Column::from)
             .collect();
         let res = ResultSet::Query { columns, rows };
         return Ok(res);
     }
+
+    /// 上层拉取一批的时候才从存储中读取这一批
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        let rows = txn.scan_iter(&self.table, self.filter, self.columns)?;
+        Ok((table_columns(txn, &self.table)?, stream(rows)))
+    }
+}
+
+/// 表的所有列
+fn table_columns<T: Transaction>(txn: &T, table: &str) -> Result<Columns> {
+    Ok(txn.must_read_table(table)?.columns.iter().map(Column::from).collect())
 }
 
 pub struct KeyLookUp {
@@ -47,7 +70,7 @@ impl KeyLookUp {
     }
 }
 
-impl<T: Transaction> Executor<T> for KeyLookUp {
+impl<T: Transaction + 'static> Executor<T> for KeyLookUp {
     fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
         // 这个地方是含有option的 需要进一步转化
         // 这里被转换的都是 or 句子 所以option为none的不显示即可 使用filter_map
@@ -61,11 +84,23 @@ impl<T: Transaction> Executor<T> for KeyLookUp {
             .must_read_table(&self.table)?
             .columns
             .iter()
-            .map(|c| Some(c.name.clone()))
+            .map(Column::from)
             .collect();
 
         Ok(ResultSet::Query { columns, rows })
     }
+
+    /// 每一批读取一部分主键
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        let reader = match txn.reader()? {
+            Some(reader) => reader,
+            None => return materialize(self, txn),
+        };
+        let table = self.table;
+        let columns = table_columns(txn, &table)?;
+        let rows = self.values.into_iter().filter_map(move |v| reader.read(&table, &v).transpose());
+        Ok((columns, stream(rows)))
+    }
 }
 
 pub struct IndexLookUp {
@@ -86,7 +121,7 @@ impl IndexLookUp {
     }
 }
 
-impl<T: Transaction> Executor<T> for IndexLookUp {
+impl<T: Transaction + 'static> Executor<T> for IndexLookUp {
     fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
         let mut keys = HashSet::new();
 
@@ -108,11 +143,147 @@ impl<T: Transaction> Executor<T> for IndexLookUp {
             .must_read_table(&self.table)?
             .columns
             .iter()
-            .map(|c| Some(c.name.clone()))
+            .map(Column::from)
+            .collect();
+
+        Ok(ResultSet::Query { columns, rows })
+    }
+
+    /// 先读出所有的主键 行在拉取的时候再读取
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        let reader = match txn.reader()? {
+            Some(reader) => reader,
+            None => return materialize(self, txn),
+        };
+        let mut keys = HashSet::new();
+        for v in self.values.iter() {
+            keys.extend(txn.read_index(&self.table, &self.column, v)?);
+        }
+        let table = self.table;
+        let columns = table_columns(txn, &table)?;
+        let rows = keys.into_iter().filter_map(move |k| reader.read(&table, &k).transpose());
+        Ok((columns, stream(rows)))
+    }
+}
+
+/// 索引范围扫描
+pub struct IndexRangeScan {
+    table: String,
+    /// 索引列
+    column: String,
+    /// 索引值的范围
+    range: IndexRange,
+}
+
+impl IndexRangeScan {
+    pub fn new(table: String, column: String, range: IndexRange) -> Box<Self> {
+        Box::new(Self {
+            table,
+            column,
+            range,
+        })
+    }
+}
+
+impl<T: Transaction + 'static> Executor<T> for IndexRangeScan {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        // 索引的entry是按照索引值排好序的 结果也保持这个顺序
+        let mut rows = Vec::new();
+        for (_, keys) in txn.read_index_range(&self.table, &self.column, self.range)? {
+            for key in keys {
+                if let Some(row) = txn.read(&self.table, &key)? {
+                    rows.push(row);
+                }
+            }
+        }
+
+        let columns: Vec<_> = txn
+            .must_read_table(&self.table)?
+            .columns
+            .iter()
+            .map(Column::from)
             .collect();
 
         Ok(ResultSet::Query { columns, rows })
     }
+
+    /// 先读出范围内的主键 行在拉取的时候再读取
+    fn execute_batches(self: Box<Self>, txn: &mut T) -> Result<(Columns, Batches)> {
+        let reader = match txn.reader()? {
+            Some(reader) => reader,
+            None => return materialize(self, txn),
+        };
+        let entries = txn.read_index_range(&self.table, &self.column, self.range)?;
+        let table = self.table;
+        let columns = table_columns(txn, &table)?;
+        let rows = entries
+            .into_iter()
+            .flat_map(|(_, keys)| keys)
+            .filter_map(move |k| reader.read(&table, &k).transpose());
+        Ok((columns, stream(rows)))
+    }
+}
+
+/// 只读索引 不读取行 索引的entry中有索引值和对应的主键 其他列是null
+pub struct IndexOnlyScan {
+    table: String,
+    /// 索引列
+    column: String,
+    access: IndexAccess,
+}
+
+impl IndexOnlyScan {
+    pub fn new(table: String, column: String, access: IndexAccess) -> Box<Self> {
+        Box::new(Self {
+            table,
+            column,
+            access,
+        })
+    }
+}
+
+impl<T: Transaction> Executor<T> for IndexOnlyScan {
+    fn execute(self: Box<Self>, txn: &mut T) -> Result<ResultSet> {
+        let table = txn.must_read_table(&self.table)?;
+        let key_index = table
+            .columns
+            .iter()
+            .position(|c| c.primary_key)
+            .ok_or_else(|| Error::Executor(format!("table {} has no primary key", table.name)))?;
+        let value_index = table.get_column_index(&self.column)?;
+
+        let entries = match self.access {
+            IndexAccess::Lookup(mut values) => {
+                // 同一个值只读一次 否则会有重复的行
+                let mut seen = HashSet::new();
+                values.retain(|v| seen.insert(v.clone()));
+                values
+                    .into_iter()
+                    .map(|v| Ok((v.clone(), txn.read_index(&table.name, &self.column, &v)?)))
+                    .collect::<Result<Vec<_>>>()?
+            }
+            IndexAccess::Range(range) => txn.read_index_range(&table.name, &self.column, range)?,
+        };
+        let mut rows = Vec::new();
+        for (value, keys) in entries {
+            // 索引的key中的小数是去掉末尾的0编码的 换回列的位数
+            let value = match (&table.columns[value_index].column_type, value) {
+                (ColumnType::Decimal(_, scale), Value::Decimal(d)) => {
+                    Value::Decimal(d.rescale(*scale)?)
+                }
+                (_, value) => value,
+            };
+            for key in keys {
+                let mut row = vec![Value::Null; table.columns.len()];
+                row[key_index] = key;
+                row[value_index] = value.clone();
+                rows.push(row);
+            }
+        }
+
+        let columns = table.columns.iter().map(Column::from).collect();
+        Ok(ResultSet::Query { columns, rows })
+    }
 }
 
 /// An executor that produces a single empty row
@@ -132,3 +303,36 @@ impl<T: Transaction> Executor<T> for Nothing {
         })
     }
 }
+
+/// VALUES 中的常量行 列的类型是第一个不为null的值的类型
+pub struct Values {
+    columns: Vec<String>,
+    rows: Vec<Vec<Expression>>,
+}
+
+impl Values {
+    pub fn new(columns: Vec<String>, rows: Vec<Vec<Expression>>) -> Box<Self> {
+        Box::new(Self { columns, rows })
+    }
+}
+
+impl<T: Transaction> Executor<T> for Values {
+    fn execute(self: Box<Self>, _: &mut T) -> Result<ResultSet> {
+        let rows = self
+            .rows
+            .iter()
+            .map(|row| row.iter().map(|e| e.evaluate(None)).collect::<Result<Vec<_>>>())
+            .collect::<Result<Vec<_>>>()?;
+        let columns = self
+            .columns
+            .into_iter()
+            .enumerate()
+            .map(|(i, name)| Column {
+                name: Some(name),
+                column_type: rows.iter().find_map(|row| row[i].datatype()),
+                nullable: rows.iter().any(|row| matches!(row[i], Value::Null)),
+            })
+            .collect();
+        Ok(ResultSet::Query { columns, rows })
+    }
+}
@@ -1,10 +1,16 @@
+use std::cell::RefCell;
+use std::collections::HashSet;
 use std::fmt::{self, Display};
+use std::ops::Bound;
 
 use log::debug;
 use regex::Regex;
 use serde_derive::{Deserialize, Serialize};
 
-use super::Value;
+use super::execution::Column;
+use super::plan::Node;
+use super::decimal::{Decimal, DIVIDE_SCALE, MAX_PRECISION};
+use super::{ColumnType, Value};
 use crate::errors::{Error, Result};
 use std::convert::Into;
 
@@ -24,6 +30,8 @@ pub enum Expression {
 
     /// 比大小 大于等于会变成Or(LessThan,Equal)
     Equal(Box<Expression>, Box<Expression>),
+    /// null和null相等 null和其他值不相等 结果不会是null
+    NullSafeEqual(Box<Expression>, Box<Expression>),
     GreaterThan(Box<Expression>, Box<Expression>),
     LessThan(Box<Expression>, Box<Expression>),
 
@@ -31,15 +39,30 @@ pub enum Expression {
     Add(Box<Expression>, Box<Expression>),
     Subtract(Box<Expression>, Box<Expression>),
     Multiply(Box<Expression>, Box<Expression>),
+    /// 整数相除也得到浮点数 除以0报错
     Divide(Box<Expression>, Box<Expression>),
+    /// DIV 整除 结果向0取整
+    IntegerDivide(Box<Expression>, Box<Expression>),
+    /// 取余 结果的符号和被除数一样
+    Modulo(Box<Expression>, Box<Expression>),
     Exponentiate(Box<Expression>, Box<Expression>),
 
     /// 正负号
     Plus(Box<Expression>),
     Negative(Box<Expression>),
 
-    /// 模糊匹配 待定
-    Like(Box<Expression>, Box<Expression>),
+    /// 模糊匹配 %匹配任意个字符 _匹配一个字符 最后是转义字符 None表示不转义
+    Like(Box<Expression>, Box<Expression>, Option<char>),
+    /// 不区分大小写的模糊匹配
+    ILike(Box<Expression>, Box<Expression>, Option<char>),
+
+    /// 标量函数 名字是大写的 参数个数在plan的时候检查过
+    ScalarFn(String, Vec<Expression>),
+
+    /// 子查询中引用的外层查询的列 执行子查询之前换成外层这一行的值
+    OuterField(usize, Option<(Option<String>, String)>),
+    /// 标量子查询 投影的时候对每一行执行一次
+    Subquery(Box<Node>),
 }
 
 impl Expression {
@@ -54,11 +77,15 @@ impl Expression {
             Self::Add(lhs, rhs)
             | Self::And(lhs, rhs)
             | Self::Divide(lhs, rhs)
+            | Self::IntegerDivide(lhs, rhs)
             | Self::Equal(lhs, rhs)
+            | Self::NullSafeEqual(lhs, rhs)
             | Self::Exponentiate(lhs, rhs)
             | Self::GreaterThan(lhs, rhs)
             | Self::LessThan(lhs, rhs)
-            | Self::Like(lhs, rhs)
+            | Self::Like(lhs, rhs, _)
+            | Self::ILike(lhs, rhs, _)
+            | Self::Modulo(lhs, rhs)
             | Self::Multiply(lhs, rhs)
             | Self::Or(lhs, rhs)
             | Self::Subtract(lhs, rhs) => {
@@ -70,7 +97,14 @@ impl Expression {
                 expr.transform_ref(before, after)?
             }
 
-            Self::Constant(_) | Self::Field(_, _) => {}
+            Self::ScalarFn(_, args) => {
+                for arg in args.iter_mut() {
+                    arg.transform_ref(before, after)?;
+                }
+            }
+
+            // 子查询中的表达式引用的是子查询自己的行 不在这里转换
+            Self::Constant(_) | Self::Field(_, _) | Self::OuterField(_, _) | Self::Subquery(_) => {}
         };
         after(self)
     }
@@ -100,79 +134,51 @@ impl Expression {
                 r
             }
 
-            // 逻辑运算
-            Self::And(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
-                (Bool(lhs), Bool(rhs)) => Bool(lhs && rhs),
-                (Bool(lhs), Value::Null) if !lhs => Bool(false),
-                (Bool(_), Value::Null) => Null,
-                (Value::Null, Bool(rhs)) if !rhs => Bool(false),
-                (Value::Null, Bool(_)) => Value::Null,
-                (Null, Null) => Null,
-                (lhs, rhs) => {
-                    return Err(Error::Evaluate(format!("Can't and {} and {}", lhs, rhs)))
-                }
+            // 逻辑运算 null表示未知 左边已经决定结果的时候不计算右边
+            Self::And(lhs, rhs) => match lhs.evaluate(row)? {
+                Bool(false) => Bool(false),
+                lhs @ (Bool(true) | Null) => match rhs.evaluate(row)? {
+                    Bool(false) => Bool(false),
+                    Bool(true) => lhs,
+                    Null => Null,
+                    rhs => return Err(Error::Evaluate(format!("Can't and {} and {}", lhs, rhs))),
+                },
+                lhs => return Err(Error::Evaluate(format!("Can't and {} and {}", lhs, rhs))),
             },
             Self::Not(expr) => match expr.evaluate(row)? {
                 Bool(b) => Bool(!b),
                 Null => Null,
                 value => return Err(Error::Evaluate(format!("Can't negate {}", value))),
             },
-            Self::Or(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
-                (Bool(lhs), Bool(rhs)) => Bool(lhs || rhs),
-                (Bool(lhs), Null) if lhs => Bool(true),
-                (Bool(_), Null) => Null,
-                (Null, Bool(rhs)) if rhs => Bool(true),
-                (Null, Bool(_)) => Null,
-                (Null, Null) => Null,
-                (lhs, rhs) => return Err(Error::Evaluate(format!("Can't or {} and {}", lhs, rhs))),
+            Self::Or(lhs, rhs) => match lhs.evaluate(row)? {
+                Bool(true) => Bool(true),
+                lhs @ (Bool(false) | Null) => match rhs.evaluate(row)? {
+                    Bool(true) => Bool(true),
+                    Bool(false) => lhs,
+                    Null => Null,
+                    rhs => return Err(Error::Evaluate(format!("Can't or {} and {}", lhs, rhs))),
+                },
+                lhs => return Err(Error::Evaluate(format!("Can't or {} and {}", lhs, rhs))),
             },
 
-            // 比较
-            Self::Equal(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
-                (Bool(lhs), Bool(rhs)) => Bool(lhs == rhs),
-                (Integer(lhs), Integer(rhs)) => Bool(lhs == rhs),
-                (Integer(lhs), Float(rhs)) => Bool(lhs as f64 == rhs),
-                (Float(lhs), Integer(rhs)) => Bool(lhs == rhs as f64),
-                (Float(lhs), Float(rhs)) => Bool(lhs == rhs),
-                (String(lhs), String(rhs)) => Bool(lhs == rhs),
-                (Null, _) | (_, Null) => Null,
-                (lhs, rhs) => {
-                    return Err(Error::Evaluate(format!(
-                        "Can't compare {} and {}",
-                        lhs, rhs
-                    )))
-                }
-            },
-            Self::GreaterThan(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
-                (Bool(lhs), Bool(rhs)) => Bool(lhs > rhs),
-                (Integer(lhs), Integer(rhs)) => Bool(lhs > rhs),
-                (Integer(lhs), Float(rhs)) => Bool(lhs as f64 > rhs),
-                (Float(lhs), Integer(rhs)) => Bool(lhs > rhs as f64),
-                (Float(lhs), Float(rhs)) => Bool(lhs > rhs),
-                (String(lhs), String(rhs)) => Bool(lhs > rhs),
-                (Value::Null, _) | (_, Value::Null) => Value::Null,
-                (lhs, rhs) => {
-                    return Err(Error::Evaluate(format!(
-                        "Can't compare {} and {}",
-                        lhs, rhs
-                    )))
-                }
-            },
-            Self::LessThan(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
-                (Bool(lhs), Bool(rhs)) => Bool(lhs < rhs),
-                (Integer(lhs), Integer(rhs)) => Bool(lhs < rhs),
-                (Integer(lhs), Float(rhs)) => Bool((lhs as f64) < rhs),
-                (Float(lhs), Integer(rhs)) => Bool(lhs < rhs as f64),
-                (Float(lhs), Float(rhs)) => Bool(lhs < rhs),
-                (String(lhs), String(rhs)) => Bool(lhs < rhs),
-                (Value::Null, _) | (_, Value::Null) => Value::Null,
+            // 比较 和排序 分组使用同一个顺序
+            Self::Equal(lhs, rhs) => {
+                compare(lhs.evaluate(row)?, rhs.evaluate(row)?, |o| o.is_eq())?
+            }
+            Self::NullSafeEqual(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
+                (Null, Null) => Bool(true),
+                (Null, _) | (_, Null) => Bool(false),
                 (lhs, rhs) => {
-                    return Err(Error::Evaluate(format!(
-                        "Can't compare {} and {}",
-                        lhs, rhs
-                    )))
+                    Self::Equal(Box::new(Self::Constant(lhs)), Box::new(Self::Constant(rhs)))
+                        .evaluate(None)?
                 }
             },
+            Self::GreaterThan(lhs, rhs) => {
+                compare(lhs.evaluate(row)?, rhs.evaluate(row)?, |o| o.is_gt())?
+            }
+            Self::LessThan(lhs, rhs) => {
+                compare(lhs.evaluate(row)?, rhs.evaluate(row)?, |o| o.is_lt())?
+            }
             Self::IsNull(expr) => match expr.evaluate(row)? {
                 Null => Bool(true),
                 _ => Bool(false),
@@ -180,14 +186,18 @@ impl Expression {
 
             // 数学运算
             Self::Negative(expr) => match expr.evaluate(row)? {
-                Integer(i) => Integer(-i),
+                Integer(i) => Integer(
+                    i.checked_neg().ok_or_else(|| Error::Evaluate("Integer overflow".into()))?,
+                ),
                 Float(f) => Float(-f),
+                Value::Decimal(d) => Value::Decimal(d.neg()),
                 Null => Null,
                 value => return Err(Error::Evaluate(format!("Can't negate {}", value))),
             },
             Self::Plus(expr) => match expr.evaluate(row)? {
                 Float(f) => Float(f),
                 Integer(i) => Integer(i),
+                Value::Decimal(d) => Value::Decimal(d),
                 Null => Null,
                 expr => {
                     return Err(Error::Evaluate(format!(
@@ -196,53 +206,96 @@ impl Expression {
                     )))
                 }
             },
+            // null 和任何类型的值运算结果都是 null
             Self::Add(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
+                (Null, _) | (_, Null) => Null,
+                (lhs @ Value::Decimal(_), rhs) | (lhs, rhs @ Value::Decimal(_)) => {
+                    decimal_arithmetic("add", lhs, rhs, |l, r| l.checked_add(&r))?
+                }
                 (Integer(lhs), Integer(rhs)) => Integer(
                     lhs.checked_add(rhs)
                         .ok_or_else(|| Error::Evaluate("Integer overflow".into()))?,
                 ),
                 (Integer(lhs), Float(rhs)) => Float(lhs as f64 + rhs),
-                (Integer(_), Null) => Null,
                 (Float(lhs), Float(rhs)) => Float(lhs + rhs),
                 (Float(lhs), Integer(rhs)) => Float(lhs + rhs as f64),
-                (Float(_), Null) => Null,
-                (Null, Float(_)) => Null,
-                (Null, Integer(_)) => Null,
-                (Null, Value::Null) => Null,
                 (lhs, rhs) => {
                     return Err(Error::Evaluate(format!("Can't add {} and {}", lhs, rhs)))
                 }
             },
             Self::Divide(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
-                (Integer(_), Integer(rhs)) if rhs == 0 => {
+                (Null, _) | (_, Null) => Null,
+                (lhs @ Value::Decimal(_), rhs) | (lhs, rhs @ Value::Decimal(_)) => {
+                    decimal_arithmetic("divide", lhs, rhs, |l, r| l.checked_div(&r))?
+                }
+                (Integer(_) | Float(_), Integer(0)) | (Integer(_) | Float(_), Float(0.0)) => {
                     return Err(Error::Evaluate("Can't divide by zero".into()))
                 }
-                (Integer(lhs), Integer(rhs)) => Integer(lhs / rhs),
+                (Integer(lhs), Integer(rhs)) => Float(lhs as f64 / rhs as f64),
                 (Integer(lhs), Float(rhs)) => Float(lhs as f64 / rhs),
-                (Integer(_), Null) => Null,
                 (Float(lhs), Integer(rhs)) => Float(lhs / rhs as f64),
                 (Float(lhs), Float(rhs)) => Float(lhs / rhs),
-                (Float(_), Null) => Null,
-                (Null, Float(_)) => Null,
-                (Null, Integer(_)) => Null,
-                (Null, Value::Null) => Null,
                 (lhs, rhs) => {
                     return Err(Error::Evaluate(format!("Can't divide {} and {}", lhs, rhs)))
                 }
             },
+            Self::IntegerDivide(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
+                (Null, _) | (_, Null) => Null,
+                (Integer(_), Integer(0)) => {
+                    return Err(Error::Evaluate("Can't divide by zero".into()))
+                }
+                (Integer(lhs), Integer(rhs)) => Integer(
+                    lhs.checked_div(rhs)
+                        .ok_or_else(|| Error::Evaluate("Integer overflow".into()))?,
+                ),
+                (lhs @ Value::Decimal(_), rhs) | (lhs, rhs @ Value::Decimal(_)) => {
+                    match decimal_arithmetic("divide", lhs, rhs, |l, r| l.checked_div_trunc(&r))? {
+                        Value::Decimal(d) => Integer(
+                            i64::try_from(d.value())
+                                .map_err(|_| Error::Evaluate("Integer overflow".into()))?,
+                        ),
+                        value => value,
+                    }
+                }
+                (Integer(lhs), Float(rhs)) => float_div_trunc(lhs as f64, rhs)?,
+                (Float(lhs), Integer(rhs)) => float_div_trunc(lhs, rhs as f64)?,
+                (Float(lhs), Float(rhs)) => float_div_trunc(lhs, rhs)?,
+                (lhs, rhs) => {
+                    return Err(Error::Evaluate(format!("Can't divide {} and {}", lhs, rhs)))
+                }
+            },
+            Self::Modulo(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
+                (Null, _) | (_, Null) => Null,
+                (lhs @ Value::Decimal(_), rhs) | (lhs, rhs @ Value::Decimal(_)) => {
+                    decimal_arithmetic("modulo", lhs, rhs, |l, r| l.checked_rem(&r))?
+                }
+                (Integer(_), Integer(0)) => {
+                    return Err(Error::Evaluate("Can't divide by zero".into()))
+                }
+                // 和 MOD 一样 i64::MIN % -1 的结果是0
+                (Integer(lhs), Integer(rhs)) => Integer(lhs.wrapping_rem(rhs)),
+                (Integer(_) | Float(_), Float(0.0)) | (Float(_), Integer(0)) => {
+                    return Err(Error::Evaluate("Can't divide by zero".into()))
+                }
+                (Integer(lhs), Float(rhs)) => Float(lhs as f64 % rhs),
+                (Float(lhs), Integer(rhs)) => Float(lhs % rhs as f64),
+                (Float(lhs), Float(rhs)) => Float(lhs % rhs),
+                (lhs, rhs) => {
+                    return Err(Error::Evaluate(format!("Can't modulo {} and {}", lhs, rhs)))
+                }
+            },
             Self::Multiply(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
+                (Null, _) | (_, Null) => Null,
+                (lhs @ Value::Decimal(_), rhs) | (lhs, rhs @ Value::Decimal(_)) => {
+                    decimal_arithmetic("multiply", lhs, rhs, |l, r| l.checked_mul(&r))?
+                }
                 (Integer(lhs), Integer(rhs)) => Integer(
                     lhs.checked_mul(rhs)
                         .ok_or_else(|| Error::Evaluate("Integer overflow".into()))?,
                 ),
                 (Integer(lhs), Float(rhs)) => Float(lhs as f64 * rhs),
-                (Integer(_), Null) => Null,
                 (Float(lhs), Integer(rhs)) => Float(lhs * rhs as f64),
                 (Float(lhs), Float(rhs)) => Float(lhs * rhs),
-                (Float(_), Null) => Null,
-                (Null, Float(_)) => Null,
-                (Null, Integer(_)) => Null,
-                (Null, Value::Null) => Null,
                 (lhs, rhs) => {
                     return Err(Error::Evaluate(format!(
                         "Can't multiply {} and {}",
@@ -251,18 +304,17 @@ impl Expression {
                 }
             },
             Self::Subtract(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
+                (Null, _) | (_, Null) => Null,
+                (lhs @ Value::Decimal(_), rhs) | (lhs, rhs @ Value::Decimal(_)) => {
+                    decimal_arithmetic("subtract", lhs, rhs, |l, r| l.checked_sub(&r))?
+                }
                 (Integer(lhs), Integer(rhs)) => Integer(
                     lhs.checked_sub(rhs)
                         .ok_or_else(|| Error::Evaluate("Integer overflow".into()))?,
                 ),
                 (Integer(lhs), Float(rhs)) => Float(lhs as f64 - rhs),
-                (Integer(_), Null) => Null,
                 (Float(lhs), Integer(rhs)) => Float(lhs - rhs as f64),
                 (Float(lhs), Float(rhs)) => Float(lhs - rhs),
-                (Float(_), Null) => Null,
-                (Null, Float(_)) => Null,
-                (Null, Integer(_)) => Null,
-                (Null, Value::Null) => Null,
                 (lhs, rhs) => {
                     return Err(Error::Evaluate(format!(
                         "Can't subtract {} and {}",
@@ -272,19 +324,28 @@ impl Expression {
             },
 
             Self::Exponentiate(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
+                (Null, _) | (_, Null) => Null,
+                // 和浮点数一样 结果是浮点数
+                (lhs @ Value::Decimal(_), rhs) | (lhs, rhs @ Value::Decimal(_)) => {
+                    let (lhs, rhs) = (Self::Constant(float(lhs)), Self::Constant(float(rhs)));
+                    Self::Exponentiate(Box::new(lhs), Box::new(rhs)).evaluate(None)?
+                }
                 (Integer(lhs), Integer(rhs)) if rhs >= 0 => Integer(
-                    lhs.checked_pow(rhs as u32)
-                        .ok_or_else(|| Error::Evaluate("Integer overflow".into()))?,
+                    match u32::try_from(rhs) {
+                        Ok(rhs) => lhs.checked_pow(rhs),
+                        // 指数超过 u32 的时候只有 -1 0 1 不会溢出
+                        Err(_) => match lhs {
+                            0 | 1 => Some(lhs),
+                            -1 => Some(if rhs % 2 == 0 { 1 } else { -1 }),
+                            _ => None,
+                        },
+                    }
+                    .ok_or_else(|| Error::Evaluate("Integer overflow".into()))?,
                 ),
                 (Integer(lhs), Integer(rhs)) => Float((lhs as f64).powf(rhs as f64)),
                 (Integer(lhs), Float(rhs)) => Float((lhs as f64).powf(rhs)),
-                (Integer(_), Null) => Null,
-                (Float(lhs), Integer(rhs)) => Float((lhs).powi(rhs as i32)),
+                (Float(lhs), Integer(rhs)) => Float(lhs.powf(rhs as f64)),
                 (Float(lhs), Float(rhs)) => Float((lhs).powf(rhs)),
-                (Float(_), Null) => Null,
-                (Null, Float(_)) => Null,
-                (Null, Integer(_)) => Null,
-                (Null, Value::Null) => Null,
                 (lhs, rhs) => {
                     return Err(Error::Evaluate(format!(
                         "Can't exponentiate {} and {}",
@@ -293,27 +354,166 @@ impl Expression {
                 }
             },
             // 字符串操作
-            Self::Like(lhs, rhs) => match (lhs.evaluate(row)?, rhs.evaluate(row)?) {
-                (String(lhs), String(rhs)) => Bool(
-                    Regex::new(&format!(
-                        "^{}$",
-                        regex::escape(&rhs)
-                            .replace("%", ".*")
-                            .replace(".*.*", "%")
-                            .replace("_", ".")
-                            .replace("..", "_")
-                    ))?
-                    .is_match(&lhs),
-                ),
-                (String(_), Null) => Null,
-                (Null, String(_)) => Null,
+            Self::Like(lhs, rhs, escape) | Self::ILike(lhs, rhs, escape) => match (
+                lhs.evaluate(row)?,
+                rhs.evaluate(row)?,
+            ) {
+                (Null, _) | (_, Null) => Null,
+                (String(lhs), String(rhs)) => {
+                    let mut pattern = like_to_regex(&rhs, *escape)?;
+                    if let Self::ILike(..) = self {
+                        pattern = format!("(?i){}", pattern);
+                    }
+                    Bool(Regex::new(&pattern)?.is_match(&lhs))
+                }
                 (lhs, rhs) => {
                     return Err(Error::Evaluate(format!("Can't LIKE {} and {}", lhs, rhs)))
                 }
             },
+
+            Self::ScalarFn(name, args) => evaluate_scalar_fn(
+                name,
+                args.iter()
+                    .map(|arg| arg.evaluate(row))
+                    .collect::<Result<Vec<_>>>()?,
+            )?,
+
+            Self::OuterField(..) | Self::Subquery(_) => {
+                return Err(Error::Evaluate(format!(
+                    "subquery {} must be executed before evaluate",
+                    self
+                )))
+            }
         })
     }
 
+    /// 小数运算结果的精度和小数位数 小数位数和decimal中运算的规则一样
+    /// 整数按照 DECIMAL(19,0) 计算 浮点数转换成小数之后的位数和值有关 返回None
+    fn decimal_type(&self, lhs: &ColumnType, rhs: &ColumnType) -> Option<ColumnType> {
+        let precision = |t: &ColumnType| match t {
+            ColumnType::Decimal(p, s) => Some((*p, *s)),
+            ColumnType::Integer => Some((19, 0)),
+            _ => None,
+        };
+        let ((p1, s1), (p2, s2)) = (precision(lhs)?, precision(rhs)?);
+        // 整数部分的位数
+        let (i1, i2) = (p1 - s1, p2 - s2);
+        let (digits, scale) = match self {
+            Self::Add(..) | Self::Subtract(..) => (i1.max(i2) + 1, s1.max(s2)),
+            Self::Multiply(..) => (i1 + i2, (s1 + s2).min(MAX_PRECISION)),
+            Self::Divide(..) => (i1 + s2, s1.max(s2).max(DIVIDE_SCALE)),
+            Self::Modulo(..) => (i1.min(i2), s1.max(s2)),
+            _ => return None,
+        };
+        let precision = (digits as u16 + scale as u16).min(MAX_PRECISION as u16) as u8;
+        Some(ColumnType::Decimal(precision.max(1), scale))
+    }
+
+    /// 推导表达式结果的类型和是否可能为null columns是输入的列信息
+    /// 类型无法确定的时候返回None
+    pub fn column_type(&self, columns: &[Column]) -> (Option<ColumnType>, bool) {
+        match self {
+            Self::Constant(v) => (v.datatype(), v == &Value::Null),
+            Self::Field(i, _) => columns
+                .get(*i)
+                .map(|c| (c.column_type.clone(), c.nullable))
+                .unwrap_or((None, true)),
+
+            Self::And(lhs, rhs)
+            | Self::Or(lhs, rhs)
+            | Self::Equal(lhs, rhs)
+            | Self::GreaterThan(lhs, rhs)
+            | Self::LessThan(lhs, rhs)
+            | Self::Like(lhs, rhs, _)
+            | Self::ILike(lhs, rhs, _) => (
+                Some(ColumnType::Bool),
+                lhs.column_type(columns).1 || rhs.column_type(columns).1,
+            ),
+            Self::Not(expr) => (Some(ColumnType::Bool), expr.column_type(columns).1),
+            Self::IsNull(_) | Self::NullSafeEqual(..) => (Some(ColumnType::Bool), false),
+
+            Self::Plus(expr) | Self::Negative(expr) => expr.column_type(columns),
+            Self::IntegerDivide(lhs, rhs) => {
+                let (ltype, lnull) = lhs.column_type(columns);
+                let (rtype, rnull) = rhs.column_type(columns);
+                let numeric = |t: &Option<ColumnType>| {
+                    matches!(
+                        t,
+                        Some(ColumnType::Integer | ColumnType::Float | ColumnType::Decimal(..))
+                    )
+                };
+                let column_type =
+                    (numeric(&ltype) && numeric(&rtype)).then_some(ColumnType::Integer);
+                (column_type, lnull || rnull)
+            }
+            Self::Add(lhs, rhs)
+            | Self::Subtract(lhs, rhs)
+            | Self::Multiply(lhs, rhs)
+            | Self::Divide(lhs, rhs)
+            | Self::Modulo(lhs, rhs)
+            | Self::Exponentiate(lhs, rhs) => {
+                let (ltype, lnull) = lhs.column_type(columns);
+                let (rtype, rnull) = rhs.column_type(columns);
+                let column_type = match (ltype, rtype) {
+                    // 负数次方和除法会变成浮点数
+                    (Some(ColumnType::Integer), Some(ColumnType::Integer))
+                        if matches!(self, Self::Exponentiate(..)) =>
+                    {
+                        None
+                    }
+                    (Some(ColumnType::Integer), Some(ColumnType::Integer))
+                        if matches!(self, Self::Divide(..)) =>
+                    {
+                        Some(ColumnType::Float)
+                    }
+                    (Some(ColumnType::Integer), Some(ColumnType::Integer)) => {
+                        Some(ColumnType::Integer)
+                    }
+                    (
+                        Some(ColumnType::Integer | ColumnType::Float),
+                        Some(ColumnType::Integer | ColumnType::Float),
+                    ) => Some(ColumnType::Float),
+                    (Some(l @ ColumnType::Decimal(..)), Some(r))
+                    | (Some(l), Some(r @ ColumnType::Decimal(..))) => self.decimal_type(&l, &r),
+                    _ => None,
+                };
+                (column_type, lnull || rnull)
+            }
+
+            Self::ScalarFn(name, args) => {
+                let nullable = args.iter().any(|arg| arg.column_type(columns).1);
+                let arg_type = |i: usize| args.get(i).and_then(|arg| arg.column_type(columns).0);
+                match name.as_str() {
+                    "LENGTH" => (Some(ColumnType::Integer), nullable),
+                    // concat 会忽略null
+                    "CONCAT" => (Some(ColumnType::String), false),
+                    "ABS" | "CEIL" | "FLOOR" | "ROUND" => (arg_type(0), nullable),
+                    "MOD" => (
+                        match (arg_type(0), arg_type(1)) {
+                            (Some(ColumnType::Integer), Some(ColumnType::Integer)) => {
+                                Some(ColumnType::Integer)
+                            }
+                            (
+                                Some(ColumnType::Integer | ColumnType::Float),
+                                Some(ColumnType::Integer | ColumnType::Float),
+                            ) => Some(ColumnType::Float),
+                            _ => None,
+                        },
+                        nullable,
+                    ),
+                    "POWER" => (Some(ColumnType::Float), nullable),
+                    "SUBSTR" if arg_type(0) == Some(ColumnType::Bytes) => {
+                        (Some(ColumnType::Bytes), nullable)
+                    }
+                    _ => (Some(ColumnType::String), nullable),
+                }
+            }
+
+            // 子查询没有行的时候是null
+            Self::OuterField(..) | Self::Subquery(_) => (None, true),
+        }
+    }
+
     pub fn contains<F>(&self, predicate: &F) -> bool
     where
         F: Fn(&Expression) -> bool,
@@ -323,11 +523,15 @@ impl Expression {
                 Self::Add(lhs, rhs)
                 | Self::And(lhs, rhs)
                 | Self::Divide(lhs, rhs)
+                | Self::IntegerDivide(lhs, rhs)
                 | Self::Equal(lhs, rhs)
+                | Self::NullSafeEqual(lhs, rhs)
                 | Self::Exponentiate(lhs, rhs)
                 | Self::GreaterThan(lhs, rhs)
                 | Self::LessThan(lhs, rhs)
-                | Self::Like(lhs, rhs)
+                | Self::Like(lhs, rhs, _)
+                | Self::ILike(lhs, rhs, _)
+                | Self::Modulo(lhs, rhs)
                 | Self::Multiply(lhs, rhs)
                 | Self::Or(lhs, rhs)
                 | Self::Subtract(lhs, rhs) => lhs.contains(predicate) || rhs.contains(predicate),
@@ -335,9 +539,29 @@ impl Expression {
                 Self::Plus(expr) | Self::Negative(expr) | Self::IsNull(expr) | Self::Not(expr) => {
                     expr.contains(predicate)
                 }
+                Self::ScalarFn(_, args) => args.iter().any(|arg| arg.contains(predicate)),
                 // 如果visiter就是针对这两个，那么就会在最开始进行判断
-                Self::Constant(_) | Self::Field(_, _) => false,
+                Self::Constant(_)
+                | Self::Field(_, _)
+                | Self::OuterField(_, _)
+                | Self::Subquery(_) => false,
+            }
+    }
+
+    /// 表达式中引用的所有列 包括子查询中引用的外层的列
+    pub fn fields(&self) -> HashSet<usize> {
+        let fields = RefCell::new(HashSet::new());
+        self.contains(&|e| {
+            match e {
+                Self::Field(i, _) => {
+                    fields.borrow_mut().insert(*i);
+                }
+                Self::Subquery(node) => fields.borrow_mut().extend(node.outer_fields()),
+                _ => {}
             }
+            false
+        });
+        fields.into_inner()
     }
 
     /// 就是将expression句子 先全部转变成由and连接的子句，再拆分
@@ -353,7 +577,7 @@ impl Expression {
                         Ok(Self::And(Box::new(Self::Not(e1)), Box::new(Self::Not(e2))))
                     }
                     Expression::Not(n) => Ok(*n),
-                    e => Ok(e),
+                    e => Ok(Self::Not(Box::new(e))),
                 },
                 _ => Ok(e),
             },
@@ -409,7 +633,8 @@ impl Expression {
     pub fn look_up(&self, filed_index: usize) -> Option<Vec<Value>> {
         use Expression::*;
         match &*self {
-            Equal(lhs, rhs) => match (&**lhs, &**rhs) {
+            // <=> null 和 IS NULL 一样
+            Equal(lhs, rhs) | NullSafeEqual(lhs, rhs) => match (&**lhs, &**rhs) {
                 (Field(i, _), Constant(v)) if i == &filed_index => Some(vec![v.clone()]),
                 (Constant(v), Field(i, _)) if i == &filed_index => Some(vec![v.clone()]),
                 (_, _) => None,
@@ -428,6 +653,322 @@ impl Expression {
             _ => None,
         }
     }
+
+    /// 查找expression中对字段的范围限制 比如 filed > 1 AND filed <= 10
+    /// 返回字段的取值范围 (下界, 上界)
+    pub fn look_up_range(&self, filed_index: usize) -> Option<(Bound<Value>, Bound<Value>)> {
+        use Expression::*;
+        // 得到 字段 比较 常量 的常量 常量在左边的时候需要反转比较方向
+        let compare = |lhs: &Expression, rhs: &Expression| match (lhs, rhs) {
+            (Field(i, _), Constant(v)) if i == &filed_index && v != &Value::Null => {
+                Some((v.clone(), false))
+            }
+            (Constant(v), Field(i, _)) if i == &filed_index && v != &Value::Null => {
+                Some((v.clone(), true))
+            }
+            _ => None,
+        };
+        match self {
+            GreaterThan(lhs, rhs) => match compare(lhs, rhs)? {
+                (v, false) => Some((Bound::Excluded(v), Bound::Unbounded)),
+                (v, true) => Some((Bound::Unbounded, Bound::Excluded(v))),
+            },
+            LessThan(lhs, rhs) => match compare(lhs, rhs)? {
+                (v, false) => Some((Bound::Unbounded, Bound::Excluded(v))),
+                (v, true) => Some((Bound::Excluded(v), Bound::Unbounded)),
+            },
+            // >= 和 <= 在planner中被转换成了 a = b OR a > b
+            Or(lhs, rhs) => match (&**lhs, &**rhs) {
+                (Equal(a, b), GreaterThan(c, d)) | (Equal(a, b), LessThan(c, d))
+                    if a == c && b == d =>
+                {
+                    let (lower, upper) = rhs.look_up_range(filed_index)?;
+                    Some((include(lower), include(upper)))
+                }
+                _ => None,
+            },
+            And(lhs, rhs) => Some(intersect_range(
+                lhs.look_up_range(filed_index)?,
+                rhs.look_up_range(filed_index)?,
+            )),
+            _ => None,
+        }
+    }
+}
+
+/// 把开区间的边界换成闭区间
+fn include(bound: Bound<Value>) -> Bound<Value> {
+    match bound {
+        Bound::Excluded(v) => Bound::Included(v),
+        b => b,
+    }
+}
+
+/// 求两个范围的交集
+pub fn intersect_range(
+    a: (Bound<Value>, Bound<Value>),
+    b: (Bound<Value>, Bound<Value>),
+) -> (Bound<Value>, Bound<Value>) {
+    // tighter 为 Greater 时选择更大的边界 为 Less 时选择更小的边界
+    let pick = |x: Bound<Value>, y: Bound<Value>, tighter: std::cmp::Ordering| match (&x, &y) {
+        (Bound::Unbounded, _) => y,
+        (_, Bound::Unbounded) => x,
+        (Bound::Included(v1) | Bound::Excluded(v1), Bound::Included(v2) | Bound::Excluded(v2)) => {
+            match v1.cmp(v2) {
+                o if o == tighter => x,
+                std::cmp::Ordering::Equal if matches!(x, Bound::Included(_)) => y,
+                std::cmp::Ordering::Equal => x,
+                _ => y,
+            }
+        }
+    };
+    (
+        pick(a.0, b.0, std::cmp::Ordering::Greater),
+        pick(a.1, b.1, std::cmp::Ordering::Less),
+    )
+}
+
+/// 检查标量函数是否存在 以及参数个数是否正确
+/// 把LIKE的模式转换成正则 转义字符后面的字符按照原样匹配
+fn like_to_regex(pattern: &str, escape: Option<char>) -> Result<String> {
+    let mut regex = String::from("(?s)^");
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            c if Some(c) == escape => match chars.next() {
+                Some(c) => regex.push_str(&regex::escape(&c.to_string())),
+                None => {
+                    return Err(Error::Evaluate(format!(
+                        "LIKE pattern {} can't end with escape character",
+                        pattern
+                    )))
+                }
+            },
+            '%' => regex.push_str(".*"),
+            '_' => regex.push('.'),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Ok(regex)
+}
+
+/// 有一边是小数的运算 另一边是数字的时候也转换成小数 有null的时候结果是null
+fn decimal_arithmetic<F>(op: &str, lhs: Value, rhs: Value, f: F) -> Result<Value>
+where
+    F: Fn(Decimal, Decimal) -> Result<Decimal>,
+{
+    if lhs == Value::Null || rhs == Value::Null {
+        return Ok(Value::Null);
+    }
+    match (lhs.to_decimal(), rhs.to_decimal()) {
+        (Some(l), Some(r)) => Ok(Value::Decimal(f(l?, r?)?)),
+        _ => Err(Error::Evaluate(format!("Can't {} {} and {}", op, lhs, rhs))),
+    }
+}
+
+/// 浮点数整除 结果转换成整数 超出范围或者是 NaN 的时候报错
+fn float_div_trunc(lhs: f64, rhs: f64) -> Result<Value> {
+    if rhs == 0.0 {
+        return Err(Error::Evaluate("Can't divide by zero".into()));
+    }
+    let result = (lhs / rhs).trunc();
+    // i64::MAX 转成浮点数会变大一点 所以上界不能取等号
+    if !(result >= i64::MIN as f64 && result < i64::MAX as f64) {
+        return Err(Error::Evaluate("Integer overflow".into()));
+    }
+    Ok(Value::Integer(result as i64))
+}
+
+/// 小数和其他数字比较
+/// 比较运算使用 Value 的全序 数字之间按照数值比较 1 = 1.0 NaN 等于 NaN 并且比其他数字都大
+fn compare<F>(lhs: Value, rhs: Value, f: F) -> Result<Value>
+where
+    F: Fn(std::cmp::Ordering) -> bool,
+{
+    use Value::*;
+    match (&lhs, &rhs) {
+        (Null, _) | (_, Null) => Ok(Null),
+        (Bool(_), Bool(_)) | (String(_), String(_)) | (Bytes(_), Bytes(_)) => {
+            Ok(Bool(f(lhs.cmp(&rhs))))
+        }
+        (Integer(_) | Float(_) | Decimal(_), Integer(_) | Float(_) | Decimal(_)) => {
+            Ok(Bool(f(lhs.cmp(&rhs))))
+        }
+        _ => Err(Error::Evaluate(format!("Can't compare {} and {}", lhs, rhs))),
+    }
+}
+
+/// 小数转换成浮点数 其他的不变
+fn float(value: Value) -> Value {
+    match value {
+        Value::Decimal(d) => Value::Float(d.to_f64()),
+        value => value,
+    }
+}
+
+/// 默认的反斜杠转义不用显示
+fn display_escape(escape: Option<char>) -> String {
+    match escape {
+        Some('\\') => String::new(),
+        Some(c) => format!(" ESCAPE \"{}\"", c),
+        None => " ESCAPE \"\"".to_string(),
+    }
+}
+
+pub fn check_scalar_fn(name: &str, args: usize) -> Result<()> {
+    let ok = match name {
+        "UPPER" | "LOWER" | "LENGTH" | "TRIM" => args == 1,
+        "SUBSTR" => args == 2 || args == 3,
+        "CONCAT" => args > 0,
+        "ABS" | "CEIL" | "FLOOR" => args == 1,
+        "ROUND" => args == 1 || args == 2,
+        "MOD" | "POWER" => args == 2,
+        "CURRENT_SETTING" => args == 1,
+        _ => return Err(Error::Plan(format!("not support for function: {}", name))),
+    };
+    if !ok {
+        return Err(Error::Plan(format!(
+            "function {} can not accept {} arguments",
+            name, args
+        )));
+    }
+    Ok(())
+}
+
+/// substr 跳过的字符数 很大或者很小的下标不会溢出
+fn substr_skip(start: i64) -> usize {
+    start.saturating_sub(1).max(0).try_into().unwrap_or(usize::MAX)
+}
+
+/// substr 最多取的字符数 start小于1的部分也算在长度里 超过字符串的部分由take截断
+fn substr_take(start: i64, len: i64) -> usize {
+    let end = start.saturating_add(len);
+    end.saturating_sub(start.max(1)).max(0).try_into().unwrap_or(usize::MAX)
+}
+
+/// 计算标量函数 除了concat 参数中有null结果就是null
+fn evaluate_scalar_fn(name: &str, args: Vec<Value>) -> Result<Value> {
+    use Value::*;
+    // 规划的时候已经换成了会话中的值 剩下的是没有会话的时候
+    if name == "CURRENT_SETTING" {
+        return Err(Error::Evaluate(
+            "CURRENT_SETTING can only be used in a session".to_string(),
+        ));
+    }
+    if name == "CONCAT" {
+        return Ok(String(
+            args.into_iter()
+                .filter(|v| v != &Null)
+                .map(|v| v.to_string())
+                .collect(),
+        ));
+    }
+    if args.contains(&Null) {
+        return Ok(Null);
+    }
+    Ok(match (name, &args[..]) {
+        ("UPPER", [String(s)]) => String(s.to_uppercase()),
+        ("LOWER", [String(s)]) => String(s.to_lowercase()),
+        ("LENGTH", [String(s)]) => Integer(s.chars().count() as i64),
+        // 二进制的长度和下标都是字节
+        ("LENGTH", [Bytes(b)]) => Integer(b.len() as i64),
+        ("TRIM", [String(s)]) => String(s.trim().to_string()),
+        // substr 的下标从1开始 长度可以省略 和pg一样 start小于1的部分也算在长度里
+        ("SUBSTR", [String(s), Integer(start), rest @ ..]) => {
+            let chars = s.chars().skip(substr_skip(*start));
+            String(match rest {
+                [] => chars.collect(),
+                [Integer(len)] if *len >= 0 => chars.take(substr_take(*start, *len)).collect(),
+                _ => {
+                    return Err(Error::Evaluate(format!(
+                        "Can't SUBSTR with length {:?}",
+                        rest
+                    )))
+                }
+            })
+        }
+        ("SUBSTR", [Bytes(b), Integer(start), rest @ ..]) => {
+            let bytes = b.iter().skip(substr_skip(*start)).copied();
+            Bytes(match rest {
+                [] => bytes.collect(),
+                [Integer(len)] if *len >= 0 => bytes.take(substr_take(*start, *len)).collect(),
+                _ => {
+                    return Err(Error::Evaluate(format!(
+                        "Can't SUBSTR with length {:?}",
+                        rest
+                    )))
+                }
+            })
+        }
+
+        // 数学函数 整数的结果还是整数 除了power和运算符 ^ 一样是浮点数
+        ("ABS", [Integer(i)]) => Integer(i.checked_abs().ok_or_else(|| {
+            Error::Evaluate(format!("Can't ABS {} overflow", i))
+        })?),
+        ("ABS", [Float(f)]) => Float(f.abs()),
+        ("ABS", [Value::Decimal(d)]) => Value::Decimal(d.abs()),
+        ("CEIL", [Value::Decimal(d)]) => Value::Decimal(d.ceil()),
+        ("FLOOR", [Value::Decimal(d)]) => Value::Decimal(d.floor()),
+        ("ROUND", [Value::Decimal(d)]) => Value::Decimal(d.round(0)?),
+        ("ROUND", [Value::Decimal(d), Integer(digits)]) => Value::Decimal(d.round(*digits)?),
+        ("MOD", [lhs @ Value::Decimal(_), rhs] | [lhs, rhs @ Value::Decimal(_)]) => {
+            decimal_arithmetic("MOD", lhs.clone(), rhs.clone(), |l, r| l.checked_rem(&r))?
+        }
+        ("POWER", [lhs @ Value::Decimal(_), rhs] | [lhs, rhs @ Value::Decimal(_)]) => {
+            evaluate_scalar_fn("POWER", vec![float(lhs.clone()), float(rhs.clone())])?
+        }
+        ("CEIL", [Integer(i)]) | ("FLOOR", [Integer(i)]) => Integer(*i),
+        ("CEIL", [Float(f)]) => Float(f.ceil()),
+        ("FLOOR", [Float(f)]) => Float(f.floor()),
+        // round 可以指定保留的小数位数 负数表示整数部分的位数
+        ("ROUND", [Integer(i)]) => Integer(*i),
+        ("ROUND", [Integer(i), Integer(d)]) if *d >= 0 => Integer(*i),
+        // 整数用 i128 精确计算 位数超过整数的范围的时候结果是0
+        ("ROUND", [Integer(i), Integer(d)]) => {
+            let scale = u32::try_from(d.unsigned_abs()).ok().and_then(|d| 10i128.checked_pow(d));
+            match scale {
+                Some(scale) => {
+                    let value = crate::sql::decimal::div_round(*i as i128, scale) * scale;
+                    Integer(i64::try_from(value).map_err(|_| {
+                        Error::Evaluate(format!("Can't ROUND {} to {} digits overflow", i, d))
+                    })?)
+                }
+                None => Integer(0),
+            }
+        }
+        ("ROUND", [Float(f)]) => Float(f.round()),
+        // 位数超过浮点数的精度的时候 乘上去会溢出 结果不变
+        ("ROUND", [Float(f), Integer(d)]) => {
+            let scale = 10f64.powi((*d).clamp(-400, 400) as i32);
+            match f * scale {
+                scaled if !scaled.is_finite() => Float(*f),
+                _ if scale == 0.0 => Float(0.0),
+                scaled => Float(scaled.round() / scale),
+            }
+        }
+        ("MOD", [Integer(_), Integer(0)]) => {
+            return Err(Error::Evaluate("Can't MOD by zero".to_string()))
+        }
+        ("MOD", [Integer(lhs), Integer(rhs)]) => Integer(lhs.wrapping_rem(*rhs)),
+        ("MOD", [Integer(lhs), Float(rhs)]) => Float(*lhs as f64 % rhs),
+        ("MOD", [Float(lhs), Integer(rhs)]) => Float(lhs % *rhs as f64),
+        ("MOD", [Float(lhs), Float(rhs)]) => Float(lhs % rhs),
+        ("POWER", [Integer(lhs), Integer(rhs)]) => Float((*lhs as f64).powf(*rhs as f64)),
+        ("POWER", [Integer(lhs), Float(rhs)]) => Float((*lhs as f64).powf(*rhs)),
+        ("POWER", [Float(lhs), Integer(rhs)]) => Float(match i32::try_from(*rhs) {
+            Ok(rhs) => lhs.powi(rhs),
+            Err(_) => lhs.powf(*rhs as f64),
+        }),
+        ("POWER", [Float(lhs), Float(rhs)]) => Float(lhs.powf(*rhs)),
+
+        (name, args) => {
+            return Err(Error::Evaluate(format!(
+                "Can't evaluate {} with {:?}",
+                name, args
+            )))
+        }
+    })
 }
 
 impl Display for Expression {
@@ -437,12 +978,17 @@ impl Display for Expression {
             Self::Field(i, None) => format!("#{}", i),
             Self::Field(_, Some((None, name))) => name.to_string(),
             Self::Field(_, Some((Some(table), name))) => format!("{}.{}", table, name),
+            Self::OuterField(i, None) => format!("outer #{}", i),
+            Self::OuterField(_, Some((None, name))) => name.to_string(),
+            Self::OuterField(_, Some((Some(table), name))) => format!("{}.{}", table, name),
+            Self::Subquery(_) => "(subquery)".to_string(),
 
             Self::And(lhs, rhs) => format!("{} AND {}", lhs, rhs),
             Self::Or(lhs, rhs) => format!("{} OR {}", lhs, rhs),
             Self::Not(expr) => format!("NOT {}", expr),
 
             Self::Equal(lhs, rhs) => format!("{} = {}", lhs, rhs),
+            Self::NullSafeEqual(lhs, rhs) => format!("{} <=> {}", lhs, rhs),
             Self::GreaterThan(lhs, rhs) => format!("{} > {}", lhs, rhs),
             Self::LessThan(lhs, rhs) => format!("{} < {}", lhs, rhs),
             Self::IsNull(expr) => format!("{} IS NULL", expr),
@@ -450,13 +996,490 @@ impl Display for Expression {
             Self::Add(lhs, rhs) => format!("{} + {}", lhs, rhs),
             Self::Plus(expr) => expr.to_string(),
             Self::Divide(lhs, rhs) => format!("{} / {}", lhs, rhs),
+            Self::IntegerDivide(lhs, rhs) => format!("{} DIV {}", lhs, rhs),
             Self::Exponentiate(lhs, rhs) => format!("{} ^ {}", lhs, rhs),
+            Self::Modulo(lhs, rhs) => format!("{} % {}", lhs, rhs),
             Self::Multiply(lhs, rhs) => format!("{} * {}", lhs, rhs),
             Self::Negative(expr) => format!("-{}", expr),
             Self::Subtract(lhs, rhs) => format!("{} - {}", lhs, rhs),
 
-            Self::Like(lhs, rhs) => format!("{} LIKE {}", lhs, rhs),
+            Self::Like(lhs, rhs, escape) => {
+                format!("{} LIKE {}{}", lhs, rhs, display_escape(*escape))
+            }
+            Self::ILike(lhs, rhs, escape) => {
+                format!("{} ILIKE {}{}", lhs, rhs, display_escape(*escape))
+            }
+
+            Self::ScalarFn(name, args) => format!(
+                "{}({})",
+                name,
+                args.iter()
+                    .map(|arg| arg.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
         };
         write!(f, "{}", s)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::Result;
+    use crate::sql::engine::kv::test_engine;
+    use crate::sql::engine::Engine;
+    use crate::sql::Value;
+
+    #[test]
+    fn string_function_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, s string null default null );")?;
+        session.execute("insert into t values (1, \"  Hello \"), (2, \"world\"), (3, null);")?;
+        let string = |s: &str| Value::String(s.to_string());
+
+        assert_eq!(
+            session.query("select upper(trim(s)), lower(s), length(s) from t where t.id = 1;")?,
+            vec![vec![string("HELLO"), string("  hello "), Value::Integer(8)]]
+        );
+        assert_eq!(
+            session.query("select substr(s, 2), substr(s, 2, 3), substr(s, 0, 2), concat(s, \"!\", id) from t where t.id = 2;")?,
+            vec![vec![string("orld"), string("orl"), string("w"), string("world!2")]]
+        );
+        // 很大的下标和长度不会溢出
+        assert_eq!(
+            session.query("select substr(\"abc\", 9223372036854775807, 1), substr(\"abc\", 2, 9223372036854775807), substr(\"abc\", -9223372036854775807 - 1, 9223372036854775807) from t where t.id = 1;")?,
+            vec![vec![string(""), string("bc"), string("")]]
+        );
+        // null 参数的结果是null concat会忽略null
+        assert_eq!(
+            session.query("select upper(s), concat(s, id) from t where t.id = 3;")?,
+            vec![vec![Value::Null, string("3")]]
+        );
+        // where 中也可以使用 标量函数可以包裹聚合函数
+        assert_eq!(
+            session.query("select id from t where length(trim(s)) = 5 order by id asc;")?,
+            vec![vec![Value::Integer(1)], vec![Value::Integer(2)]]
+        );
+        assert_eq!(
+            session.query("select concat(count(s), \" rows\") from t;")?,
+            vec![vec![string("2 rows")]]
+        );
+
+        assert!(session.query("select upper(s, s) from t;").is_err());
+        assert!(session.query("select nothing_fn(s) from t;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn numeric_function_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, i int null default null, f float );")?;
+        session.execute("insert into t values (1, -7, -2.5), (2, null, 1.25);")?;
+        use Value::{Float, Integer, Null};
+
+        assert_eq!(
+            session.query(
+                "select abs(i), abs(f), ceil(f), floor(f), ceil(i) from t where t.id = 1;"
+            )?,
+            vec![vec![Integer(7), Float(2.5), Float(-2.0), Float(-3.0), Integer(-7)]]
+        );
+        assert_eq!(
+            session.query("select round(f), round(f, 1), round(1234, -2), mod(i, 3), mod(f, 2) from t where t.id = 2;")?,
+            vec![vec![Float(1.0), Float(1.3), Integer(1200), Null, Float(1.25)]]
+        );
+        assert_eq!(
+            session.query("select mod(i, 3), power(i, 2), power(2, -1) from t where t.id = 1;")?,
+            vec![vec![Integer(-1), Float(49.0), Float(0.5)]]
+        );
+        assert!(session.query("select mod(i, 0) from t where t.id = 1;").is_err());
+        assert!(session.query("select abs(\"a\") from t;").is_err());
+        // 超过 i32 的指数和位数不会被截断
+        assert_eq!(
+            session.query("select power(2.0, 4294967296), power(1.0, 4294967296), power(0.5, 4294967296) from t where t.id = 1;")?,
+            vec![vec![Float(f64::INFINITY), Float(1.0), Float(0.0)]]
+        );
+        assert_eq!(
+            session.query("select round(1234, -4294967298), round(-9223372036854775807, -18), round(1.25, 4294967297), round(1.25, -4294967297), round(1.5, 400) from t where t.id = 1;")?,
+            vec![vec![
+                Integer(0),
+                Integer(-9000000000000000000),
+                Float(1.25),
+                Float(0.0),
+                Float(1.5)
+            ]]
+        );
+        assert!(session
+            .query("select round(9223372036854775807, -1) from t where t.id = 1;")
+            .is_err());
+        let decimal = Value::Decimal(crate::sql::decimal::Decimal::parse("1.25")?);
+        assert_eq!(
+            super::evaluate_scalar_fn("ROUND", vec![decimal, Integer(-4294967296)])?,
+            Value::Decimal(crate::sql::decimal::Decimal::new(0, 0)?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn bytes_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, b blob index );")?;
+        session.execute("insert into t values (1, X'DEADBEEF'), (2, x''), (3, x'00ff');")?;
+        use Value::{Bytes, Integer};
+
+        assert_eq!(
+            session.query(
+                "select length(b), substr(b, 2, 2), substr(b, 4) from t where t.id = 1;"
+            )?,
+            vec![vec![Integer(4), Bytes(vec![0xad, 0xbe]), Bytes(vec![0xef])]]
+        );
+        // 索引查找
+        assert_eq!(
+            session.query("select id from t where b = X'deadbeef';")?,
+            vec![vec![Integer(1)]]
+        );
+        // 按字节序排序
+        assert_eq!(
+            session.query("select id from t order by b asc;")?,
+            vec![vec![Integer(2)], vec![Integer(3)], vec![Integer(1)]]
+        );
+        assert_eq!(
+            session.query("select length(b) from t where b < X'01';")?,
+            vec![vec![Integer(0)], vec![Integer(2)]]
+        );
+        assert!(session.query("select X'ABC' from t;").is_err());
+        assert!(session.query("select X'ZZ' from t;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn modulo_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, i int null default null, f float );")?;
+        session.execute("insert into t values (1, -7, 7.5), (2, null, 1.0);")?;
+        use Value::{Float, Integer, Null};
+
+        // 和乘除的优先级一样 从左往右结合
+        assert_eq!(
+            session.query(
+                "select 1 + 7 % 4 * 2, 20 DIV 6 % 2, i % 3, f % 2, i % 2.5 from t where t.id = 1;"
+            )?,
+            vec![vec![Integer(7), Integer(1), Integer(-1), Float(1.5), Float(-2.0)]]
+        );
+        assert_eq!(session.query("select id from t where id % 2 = 0;")?, vec![vec![Integer(2)]]);
+        assert_eq!(session.query("select i % 2 from t where t.id = 2;")?, vec![vec![Null]]);
+        assert!(session.query("select i % 0 from t where t.id = 1;").is_err());
+        assert!(session.query("select f % 0 from t where t.id = 1;").is_err());
+        assert!(session.query("select \"a\" % 2;").is_err());
+
+        // 常量在优化的时候计算出来
+        assert_eq!(
+            session.explain("select id from t where 10 % 3 = 1;")?.to_string(),
+            "Projection: id\n└─ Scan: t columns #0"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn division_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, i int null default null );")?;
+        session.execute("insert into t values (1, -7), (2, null);")?;
+        use Value::{Float, Integer, Null};
+
+        // / 得到浮点数 DIV 向0取整
+        assert_eq!(
+            session.query(
+                "select 5 / 2, 5 DIV 2, i DIV 2, 5.5 DIV 2, i / 2 from t where t.id = 1;"
+            )?,
+            vec![vec![Float(2.5), Integer(2), Integer(-3), Integer(2), Float(-3.5)]]
+        );
+        assert_eq!(session.query("select i DIV 2 from t where t.id = 2;")?, vec![vec![Null]]);
+        for sql in [
+            "select 1.0 / 0;",
+            "select 1 / 0.0;",
+            "select 1 DIV 0;",
+            "select 1.5 DIV 0.0;",
+            "select (-9223372036854775807 - 1) DIV -1;",
+            "select 1e30 DIV 1;",
+            "select \"a\" DIV 2;",
+        ] {
+            assert!(session.query(sql).is_err(), "{}", sql);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn operator_matrix_test() -> Result<()> {
+        use super::Expression;
+        use crate::sql::decimal::Decimal;
+        use crate::sql::ColumnType;
+        let values = [
+            Value::Null,
+            Value::Bool(true),
+            Value::Integer(3),
+            Value::Float(2.0),
+            Value::Decimal(Decimal::parse("1.5")?),
+            Value::String("a".into()),
+            Value::Bytes(vec![1]),
+        ];
+        let numeric =
+            |v: &Value| matches!(v, Value::Integer(_) | Value::Float(_) | Value::Decimal(_));
+        let same = |a: &Value, b: &Value| std::mem::discriminant(a) == std::mem::discriminant(b);
+        let constant = |v: &Value| Box::new(Expression::Constant(v.clone()));
+        // 推导的类型和计算出来的值一样 小数的小数位数也一样
+        let check_type = |e: &Expression, value: &Value| match (e.column_type(&[]).0, value) {
+            (Some(ColumnType::Decimal(_, scale)), Value::Decimal(d)) => {
+                assert_eq!(scale, d.scale(), "{}", e)
+            }
+            (None, _) => {}
+            (Some(t), value) => assert_eq!(Some(t), value.datatype(), "{}", e),
+        };
+
+        type Binary = fn(Box<Expression>, Box<Expression>) -> Expression;
+        // 两边都是整数的时候结果的类型
+        let arithmetic: [(Binary, ColumnType); 7] = [
+            (Expression::Add, ColumnType::Integer),
+            (Expression::Subtract, ColumnType::Integer),
+            (Expression::Multiply, ColumnType::Integer),
+            (Expression::Modulo, ColumnType::Integer),
+            (Expression::Divide, ColumnType::Float),
+            (Expression::IntegerDivide, ColumnType::Integer),
+            (Expression::Exponentiate, ColumnType::Integer),
+        ];
+        let comparison: [Binary; 3] =
+            [Expression::Equal, Expression::GreaterThan, Expression::LessThan];
+        for lhs in &values {
+            for rhs in &values {
+                for (op, int_type) in &arithmetic {
+                    let e = op(constant(lhs), constant(rhs));
+                    let result = e.evaluate(None);
+                    match (lhs, rhs) {
+                        // null 和任何类型运算都是 null
+                        (Value::Null, _) | (_, Value::Null) => {
+                            assert_eq!(result?, Value::Null, "{}", e)
+                        }
+                        (lhs, rhs) if numeric(lhs) && numeric(rhs) => {
+                            let value = result?;
+                            let integers =
+                                matches!((lhs, rhs), (Value::Integer(_), Value::Integer(_)));
+                            let expect = match (&e, lhs, rhs) {
+                                (Expression::IntegerDivide(..), _, _) => ColumnType::Integer,
+                                (Expression::Exponentiate(..), _, _) if integers => {
+                                    ColumnType::Integer
+                                }
+                                (Expression::Exponentiate(..), _, _) => ColumnType::Float,
+                                (_, Value::Decimal(_), _) | (_, _, Value::Decimal(_)) => {
+                                    ColumnType::Decimal(0, 0)
+                                }
+                                _ if integers => int_type.clone(),
+                                _ => ColumnType::Float,
+                            };
+                            let actual = value.datatype().map(|t| match t {
+                                ColumnType::Decimal(..) => ColumnType::Decimal(0, 0),
+                                t => t,
+                            });
+                            assert_eq!(actual, Some(expect), "{}", e);
+                            check_type(&e, &value);
+                        }
+                        _ => assert!(result.is_err(), "{}", e),
+                    }
+                }
+                for op in &comparison {
+                    let e = op(constant(lhs), constant(rhs));
+                    let result = e.evaluate(None);
+                    match (lhs, rhs) {
+                        (Value::Null, _) | (_, Value::Null) => {
+                            assert_eq!(result?, Value::Null, "{}", e)
+                        }
+                        (lhs, rhs) if same(lhs, rhs) || numeric(lhs) && numeric(rhs) => {
+                            let value = result?;
+                            assert!(matches!(value, Value::Bool(_)), "{}", e);
+                            check_type(&e, &value);
+                        }
+                        _ => assert!(result.is_err(), "{}", e),
+                    }
+                }
+                for e in [
+                    Expression::Like(constant(lhs), constant(rhs), None),
+                    Expression::ILike(constant(lhs), constant(rhs), None),
+                ] {
+                    match (lhs, rhs) {
+                        (Value::Null, _) | (_, Value::Null) => {
+                            assert_eq!(e.evaluate(None)?, Value::Null, "{}", e)
+                        }
+                        (Value::String(_), Value::String(_)) => {
+                            assert_eq!(e.evaluate(None)?, Value::Bool(true), "{}", e)
+                        }
+                        _ => assert!(e.evaluate(None).is_err(), "{}", e),
+                    }
+                }
+                // 不会是 null 类型不同的时候和 = 一样报错
+                let e = Expression::NullSafeEqual(constant(lhs), constant(rhs));
+                match (lhs, rhs) {
+                    (Value::Null, _) | (_, Value::Null) => {
+                        assert_eq!(e.evaluate(None)?, Value::Bool(lhs == rhs), "{}", e)
+                    }
+                    (lhs, rhs) if same(lhs, rhs) || numeric(lhs) && numeric(rhs) => {
+                        assert!(matches!(e.evaluate(None)?, Value::Bool(_)), "{}", e)
+                    }
+                    _ => assert!(e.evaluate(None).is_err(), "{}", e),
+                }
+            }
+        }
+
+        for value in &values {
+            for e in [Expression::Negative(constant(value)), Expression::Plus(constant(value))] {
+                match value {
+                    Value::Null => assert_eq!(e.evaluate(None)?, Value::Null),
+                    value if numeric(value) => {
+                        let result = e.evaluate(None)?;
+                        assert!(same(&result, value), "{}", e);
+                        check_type(&e, &result);
+                    }
+                    _ => assert!(e.evaluate(None).is_err(), "{}", e),
+                }
+            }
+        }
+
+        // 溢出的时候报错
+        let min = constant(&Value::Integer(i64::MIN));
+        assert!(Expression::Negative(min).evaluate(None).is_err());
+        let pow = |lhs: i64, rhs: i64| {
+            Expression::Exponentiate(constant(&Value::Integer(lhs)), constant(&Value::Integer(rhs)))
+                .evaluate(None)
+        };
+        assert!(pow(2, 1 << 32).is_err());
+        assert_eq!(pow(-1, (1 << 32) + 1)?, Value::Integer(-1));
+        assert_eq!(pow(1, 1 << 40)?, Value::Integer(1));
+        Ok(())
+    }
+
+    #[test]
+    fn to_cnf_not_test() -> Result<()> {
+        use super::Expression::*;
+        let field = |i| Box::new(Field(i, None));
+        // NOT 包住的不是 and or not 的时候要保留 NOT
+        let not_equal = Not(Box::new(Equal(field(0), field(1))));
+        assert_eq!(not_equal.clone().to_cnf_vec()?, vec![not_equal.clone()]);
+        let not_null = Not(Box::new(IsNull(field(2))));
+        assert_eq!(
+            And(Box::new(not_equal.clone()), Box::new(not_null.clone())).to_cnf_vec()?,
+            vec![not_null, not_equal]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn null_safe_equal_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        let bools = |b: &[bool]| vec![b.iter().map(|b| Value::Bool(*b)).collect::<Vec<_>>()];
+        assert_eq!(
+            session.query(
+                "select null <=> null, null <=> 1, 1 <=> null, 1 <=> 1, 1 <=> 1.0, 1 <=> 2;"
+            )?,
+            bools(&[true, false, false, true, true, false])
+        );
+        assert_eq!(
+            session.query(
+                "select null is not distinct from null, 1 is distinct from null, \
+                 1 is distinct from 1, 1 is not distinct from 1 + 1;"
+            )?,
+            bools(&[true, true, false, false])
+        );
+        // 和 = 一样 类型不能比较的时候报错
+        assert!(session.query("select 1 <=> \"a\";").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn like_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, s string null default null );")?;
+        session.execute(
+            "insert into t values (1, \"100%\"), (2, \"1000\"), (3, \"a_b\"), (4, \"axb\"), \
+             (5, \"Hello\"), (6, null);",
+        )?;
+        let ids = |ids: &[i64]| ids.iter().map(|i| vec![Value::Integer(*i)]).collect::<Vec<_>>();
+
+        assert_eq!(
+            session.query("select id from t where s like \"100%\" order by id;")?,
+            ids(&[1, 2])
+        );
+        // 转义之后按照原样匹配
+        assert_eq!(session.query("select id from t where s like \"100\\%\";")?, ids(&[1]));
+        assert_eq!(
+            session.query("select id from t where s like \"a_b\" order by id;")?,
+            ids(&[3, 4])
+        );
+        assert_eq!(
+            session.query("select id from t where s like \"a!_b\" escape \"!\";")?,
+            ids(&[3])
+        );
+        // 正则的特殊字符不会生效
+        assert_eq!(session.query("select id from t where s like \"a.b\";")?, ids(&[]));
+        assert_eq!(session.query("select id from t where s like \"hello\";")?, ids(&[]));
+        assert_eq!(session.query("select id from t where s ilike \"HEL%\";")?, ids(&[5]));
+        assert_eq!(
+            session.query("select s like \"%\", not (s like \"%\") from t where t.id = 6;")?,
+            vec![vec![Value::Null, Value::Null]]
+        );
+        assert_eq!(
+            session.query("select s like s from t where t.id = 6;")?,
+            vec![vec![Value::Null]]
+        );
+        assert!(session.query("select id from t where s like \"abc\\\";").is_err());
+        assert!(session.query("select id from t where s like \"a\" escape \"ab\";").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn three_valued_logic_test() -> Result<()> {
+        use super::Expression;
+        let constant = |v: &Value| Box::new(Expression::Constant(v.clone()));
+        let (t, f, n) = (Value::Bool(true), Value::Bool(false), Value::Null);
+        // 左边 右边 AND OR
+        let table = [
+            (&t, &t, &t, &t),
+            (&t, &f, &f, &t),
+            (&t, &n, &n, &t),
+            (&f, &t, &f, &t),
+            (&f, &f, &f, &f),
+            (&f, &n, &f, &n),
+            (&n, &t, &n, &t),
+            (&n, &f, &f, &n),
+            (&n, &n, &n, &n),
+        ];
+        for (lhs, rhs, and, or) in table {
+            let e = Expression::And(constant(lhs), constant(rhs));
+            assert_eq!(&e.evaluate(None)?, and, "{}", e);
+            let e = Expression::Or(constant(lhs), constant(rhs));
+            assert_eq!(&e.evaluate(None)?, or, "{}", e);
+        }
+        for (v, not) in [(&t, &f), (&f, &t), (&n, &n)] {
+            assert_eq!(&Expression::Not(constant(v)).evaluate(None)?, not);
+        }
+
+        // 左边已经决定结果的时候不计算右边 否则右边的错误返回出来
+        let error = || {
+            let (one, zero) = (constant(&Value::Integer(1)), constant(&Value::Integer(0)));
+            Box::new(Expression::Divide(one, zero))
+        };
+        assert_eq!(Expression::And(constant(&f), error()).evaluate(None)?, f);
+        assert_eq!(Expression::Or(constant(&t), error()).evaluate(None)?, t);
+        assert!(Expression::And(constant(&t), error()).evaluate(None).is_err());
+        assert!(Expression::And(constant(&n), error()).evaluate(None).is_err());
+        assert!(Expression::Or(constant(&f), error()).evaluate(None).is_err());
+        // 不是布尔值的时候报错
+        let one = constant(&Value::Integer(1));
+        assert!(Expression::And(one.clone(), constant(&t)).evaluate(None).is_err());
+        assert!(Expression::Or(constant(&n), one).evaluate(None).is_err());
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use std::{
     borrow::Cow,
     cmp::Ordering,
+    collections::HashSet,
     default,
     fmt::{self, format, Display},
     hash::Hasher,
@@ -10,22 +11,84 @@ use crate::errors::{Error, Result};
 use core::hash::Hash;
 use serde_derive::{Deserialize, Serialize};
 
+use self::decimal::Decimal;
 use self::engine::Transaction;
+use self::expression::Expression;
+use self::schema::Catalog;
+use self::statistics::TableStats;
 
+pub mod decimal;
 pub mod engine;
 pub mod execution;
 pub mod expression;
 pub mod parser;
 mod plan;
 pub mod schema;
+pub mod statistics;
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// 值之间是全序 ORDER BY 聚合的 MAX MIN 和统计信息都按照这个顺序比较
+/// NULL 最小 之后依次是布尔值 数字 字符串 二进制数据
+/// 整数 小数和浮点数按照数值比较 数值相同的时候相等 所以 1 和 1.0 相等
+/// 所有的 NaN 相等 比其他的数字都大 -0.0 比 0.0 小 和索引中浮点数编码的顺序一样
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Value {
     Null,
     Integer(i64),
-    Float(f64),
+    Float(#[serde(with = "float")] f64),
     String(String),
     Bool(bool),
+    /// 定点小数 运算是精确的
+    Decimal(Decimal),
+    /// 二进制数据
+    Bytes(Vec<u8>),
+}
+
+/// json中的数字不能表示 NaN 和无穷大 编码表的定义的时候这些值写成字符串 bincode的编码不变
+mod float {
+    use std::fmt;
+
+    use serde::de::{Error, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(f: &f64, serializer: S) -> Result<S::Ok, S::Error> {
+        match serializer.is_human_readable() && !f.is_finite() {
+            true => serializer.serialize_str(&f.to_string()),
+            false => serializer.serialize_f64(*f),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+        match deserializer.is_human_readable() {
+            true => deserializer.deserialize_any(FloatVisitor),
+            false => deserializer.deserialize_f64(FloatVisitor),
+        }
+    }
+
+    struct FloatVisitor;
+
+    impl Visitor<'_> for FloatVisitor {
+        type Value = f64;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "a float")
+        }
+
+        fn visit_f64<E: Error>(self, v: f64) -> Result<f64, E> {
+            Ok(v)
+        }
+
+        fn visit_i64<E: Error>(self, v: i64) -> Result<f64, E> {
+            Ok(v as f64)
+        }
+
+        fn visit_u64<E: Error>(self, v: u64) -> Result<f64, E> {
+            Ok(v as f64)
+        }
+
+        fn visit_str<E: Error>(self, v: &str) -> Result<f64, E> {
+            v.parse().map_err(E::custom)
+        }
+    }
 }
 
 impl Value {
@@ -46,6 +109,18 @@ impl Value {
             Value::Float(_) => Some(ColumnType::Float),
             Value::String(_) => Some(ColumnType::String),
             Value::Bool(_) => Some(ColumnType::Bool),
+            Value::Decimal(d) => Some(ColumnType::Decimal(decimal::MAX_PRECISION, d.scale())),
+            Value::Bytes(_) => Some(ColumnType::Bytes),
+        }
+    }
+
+    /// 整数 浮点数和小数都可以转换成小数 其他的是None
+    pub fn to_decimal(&self) -> Option<Result<Decimal>> {
+        match self {
+            Value::Integer(i) => Some(Ok(Decimal::from_i64(*i))),
+            Value::Float(f) => Some(Decimal::from_f64(*f)),
+            Value::Decimal(d) => Some(Ok(*d)),
+            _ => None,
         }
     }
 }
@@ -53,13 +128,30 @@ impl std::cmp::Eq for Value {}
 
 impl Hash for Value {
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.datatype().hash(state);
+        // 相等的数字类型可以不同 都按照小数计算哈希 小数的哈希和位数无关
+        match self {
+            Value::Integer(_) | Value::Float(_) | Value::Decimal(_) => {
+                ColumnType::Decimal(0, 0).hash(state)
+            }
+            value => value.datatype().hash(state),
+        }
         match self {
-            Value::Null => self.hash(state),
+            Value::Null => {}
             Value::Bool(v) => v.hash(state),
-            Value::Integer(v) => v.hash(state),
-            Value::Float(v) => v.to_be_bytes().hash(state),
+            Value::Integer(v) => Decimal::from_i64(*v).hash(state),
+            // 所有的 NaN 相等
+            Value::Float(v) if v.is_nan() => f64::NAN.to_be_bytes().hash(state),
+            // 整数值的浮点数和整数相等
+            Value::Float(v) if v.fract() == 0.0 && (-I64_BOUND..I64_BOUND).contains(v) => {
+                Decimal::from_i64(*v as i64).hash(state)
+            }
+            Value::Float(v) => match Decimal::from_f64(*v) {
+                Ok(d) => d.hash(state),
+                Err(_) => v.to_be_bytes().hash(state),
+            },
             Value::String(v) => v.hash(state),
+            Value::Decimal(d) => d.hash(state),
+            Value::Bytes(v) => v.hash(state),
         }
     }
 }
@@ -74,6 +166,12 @@ impl std::fmt::Display for Value {
                 Self::Integer(i) => i.to_string(),
                 Self::Float(f) => f.to_string(),
                 Self::String(s) => s.clone(),
+                Self::Decimal(d) => d.to_string(),
+                // 和字面量的写法一样
+                Self::Bytes(b) => format!(
+                    "X'{}'",
+                    b.iter().map(|b| format!("{:02X}", b)).collect::<String>()
+                ),
             }
             .as_ref(),
         )
@@ -92,23 +190,104 @@ impl<'a> From<&'a Value> for Cow<'a, Value> {
     }
 }
 
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
 impl PartialOrd for Value {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Value {
+    fn cmp(&self, other: &Self) -> Ordering {
+        use Value::*;
         match (self, other) {
-            (Self::Null, Self::Null) => Some(Ordering::Equal),
-            (Self::Null, _) => Some(Ordering::Less),
-            (_, Self::Null) => Some(Ordering::Greater),
-            (Self::Bool(a), Self::Bool(b)) => a.partial_cmp(b),
-            (Self::Float(a), Self::Float(b)) => a.partial_cmp(b),
-            (Self::Float(a), Self::Integer(b)) => a.partial_cmp(&(*b as f64)),
-            (Self::Integer(a), Self::Float(b)) => (*a as f64).partial_cmp(b),
-            (Self::Integer(a), Self::Integer(b)) => a.partial_cmp(b),
-            (Self::String(a), Self::String(b)) => a.partial_cmp(b),
-            (_, _) => None,
+            (Null, Null) => Ordering::Equal,
+            (Bool(a), Bool(b)) => a.cmp(b),
+            (Integer(a), Integer(b)) => a.cmp(b),
+            (Float(a), Float(b)) => compare_f64(*a, *b),
+            (Decimal(a), Decimal(b)) => a.cmp(b),
+            (String(a), String(b)) => a.cmp(b),
+            (Bytes(a), Bytes(b)) => a.cmp(b),
+            // 不同类型的数字按照数值比较 1 1.0 和小数 1.0 相等 和 = 运算的结果一致
+            (Integer(i), Float(f)) => compare_i64_f64(*i, *f),
+            (Float(f), Integer(i)) => compare_i64_f64(*i, *f).reverse(),
+            (Integer(i), Decimal(d)) => decimal::Decimal::from_i64(*i).cmp(d),
+            (Decimal(d), Integer(i)) => d.cmp(&decimal::Decimal::from_i64(*i)),
+            (Decimal(d), Float(f)) => compare_decimal_f64(d, *f),
+            (Float(f), Decimal(d)) => compare_decimal_f64(d, *f).reverse(),
+            (a, b) => a.rank().cmp(&b.rank()),
+        }
+    }
+}
+
+impl Value {
+    /// 不同类型之间的顺序 数字是同一类
+    fn rank(&self) -> u8 {
+        match self {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Integer(_) | Value::Decimal(_) | Value::Float(_) => 2,
+            Value::String(_) => 3,
+            Value::Bytes(_) => 4,
         }
     }
 }
 
+/// i64 的范围是 [-2^63, 2^63)
+const I64_BOUND: f64 = 9223372036854775808.0;
+
+/// NaN 最大 其他的和 total_cmp 一样
+fn compare_f64(a: f64, b: f64) -> Ordering {
+    match (a.is_nan(), b.is_nan()) {
+        (true, true) => Ordering::Equal,
+        (true, false) => Ordering::Greater,
+        (false, true) => Ordering::Less,
+        (false, false) => a.total_cmp(&b),
+    }
+}
+
+/// 整数和浮点数精确地比较 大的整数转换成浮点数会丢失精度
+fn compare_i64_f64(i: i64, f: f64) -> Ordering {
+    // 浮点数之间 -0.0 排在 0.0 前面 和整数比较的时候也是
+    if f == 0.0 && f.is_sign_negative() {
+        return if i < 0 { Ordering::Less } else { Ordering::Greater };
+    }
+    if f.is_nan() || f >= I64_BOUND {
+        return Ordering::Less;
+    }
+    if f < -I64_BOUND {
+        return Ordering::Greater;
+    }
+    let int = f.trunc();
+    i.cmp(&(int as i64)).then_with(|| int.total_cmp(&f))
+}
+
+/// 整数值的浮点数精确地比较 其他的转换成最短的十进制表示 超出小数范围的按照符号
+fn compare_decimal_f64(d: &Decimal, f: f64) -> Ordering {
+    if f.is_nan() {
+        return Ordering::Less;
+    }
+    if f == 0.0 && f.is_sign_negative() {
+        return match d.cmp(&Decimal::from_i64(0)) {
+            Ordering::Less => Ordering::Less,
+            _ => Ordering::Greater,
+        };
+    }
+    if f.fract() == 0.0 && (-I64_BOUND..I64_BOUND).contains(&f) {
+        return d.cmp(&Decimal::from_i64(f as i64));
+    }
+    match Decimal::from_f64(f) {
+        Ok(other) => d.cmp(&other),
+        Err(_) if f > 0.0 => Ordering::Less,
+        Err(_) => Ordering::Greater,
+    }
+}
+
 impl From<bool> for Value {
     fn from(v: bool) -> Self {
         Value::Bool(v)
@@ -150,23 +329,74 @@ pub struct Column {
     pub primary_key: bool,
     /// 是否可以为null
     pub nullable: bool,
-    /// 默认值
-    pub default: Option<Value>,
+    /// 默认值 插入的时候对每一行计算一次
+    #[serde(default)]
+    pub default: Option<Expression>,
     /// 是否是唯一
+    #[serde(default)]
     pub unique: bool,
     /// 是否是索引
+    #[serde(default)]
     pub index: bool,
+    /// 外键
+    #[serde(default)]
+    pub references: Option<Reference>,
+}
+
+/// 外键引用 引用的是另一个表的主键
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Reference {
+    /// 引用的表
+    pub table: String,
+    /// 引用的字段
+    pub column: String,
+    /// 引用的行被删除的时候怎么处理
+    pub on_delete: ReferenceAction,
+}
+
+/// 外键的删除策略
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum ReferenceAction {
+    /// 有引用的时候拒绝删除
+    Restrict,
+    /// 一起删除引用的行
+    Cascade,
 }
 
 impl Column {
-    // 检查一个数据是否正常
-    pub fn validate_value(
-        &self,
-        table: &Table,
-        pk: &Value,
-        val: &Value,
-        txn: &mut dyn Transaction,
-    ) -> Result<()> {
+    /// 写入之前把数字转换成列的类型 目前只有小数列需要转换
+    pub fn coerce(&self, val: Value) -> Result<Value> {
+        match (&self.column_type, val.to_decimal()) {
+            (ColumnType::Decimal(precision, scale), Some(decimal)) => Ok(Value::Decimal(
+                decimal?
+                    .fit(*precision, *scale)
+                    .map_err(|e| Error::Row(format!("column {}: {}", self.name, e)))?,
+            )),
+            _ => Ok(val),
+        }
+    }
+
+    /// 查询条件中的常量转换成列中值的类型 编码相同才能通过索引查到 小数不会舍入
+    pub fn lookup_value(&self, val: Value) -> Value {
+        match (&self.column_type, val.to_decimal()) {
+            (ColumnType::Decimal(..), Some(Ok(decimal))) => Value::Decimal(decimal),
+            _ => val,
+        }
+    }
+
+    /// 检查数据的类型和是否可以为null
+    pub fn validate_type(&self, val: &Value) -> Result<()> {
+        // 小数需要已经转换成了列的位数
+        if let (ColumnType::Decimal(precision, scale), Value::Decimal(d)) = (&self.column_type, val)
+        {
+            if d.scale() != *scale || d.digits() > *precision {
+                return Err(Error::Row(format!(
+                    "invalid decimal {} for {} column expect type : {}",
+                    d, self.name, self.column_type
+                )));
+            }
+            return Ok(());
+        }
         // 检查数据类型
         match val.datatype() {
             None => {
@@ -189,33 +419,46 @@ impl Column {
                     Ok(())
                 }
             }
-        }?;
+        }
+    }
+
+    // 检查一个数据是否正常 old 是修改主键之前的主键
+    // pending 是同一批插入中前面还没有写入的行
+    pub fn validate_value(
+        &self,
+        table: &Table,
+        pk: &Value,
+        old: Option<&Value>,
+        pending: Option<&Pending>,
+        val: &Value,
+        txn: &mut dyn Transaction,
+    ) -> Result<()> {
+        self.validate_type(val)?;
         // 校验唯一值
         // 如果不是主键的话，而且不是null(主键在之后会校验)
+        // 唯一字段都有索引 查一次索引就可以 索引里面是自己这一行的不算
         if self.unique && !self.primary_key && val != &Value::Null {
-            let index = table.get_column_index(&self.name)?;
-            // 如果是index（索引）
-            if self.index {
-                let entry = txn.read_index(&table.name, &self.name, val)?;
-                if !entry.is_empty() {
-                    return Err(Error::Row(format!(
-                        "Unique value {} already exists for index column {}",
-                        val, self.name
-                    )));
-                }
-            } else {
-                //得到这个字段是表中的第几个字段
-                let scan = txn.scan(&table.name, None)?;
-                for item in scan.iter() {
-                    if item.get(index).unwrap_or(&Value::Null) == val
-                        && &table.get_row_key(&item)? != pk
-                    {
-                        return Err(Error::Row(format!(
-                            "Unique value {} already exists for column {}",
-                            val, self.name
-                        )));
-                    }
-                }
+            let entry = txn.read_index(&table.name, &self.name, val)?;
+            if entry.iter().any(|key| key != pk && Some(key) != old)
+                || pending.is_some_and(|p| p.values.contains(&(self.name.clone(), val.clone())))
+            {
+                return Err(Error::Row(format!(
+                    "Unique value {} already exists for column {}",
+                    val, self.name
+                )));
+            }
+        }
+        // 校验外键 引用的行必须存在 引用自己的行除外
+        if let Some(reference) = &self.references {
+            if val != &Value::Null
+                && !(reference.table == table.name
+                    && (val == pk || pending.is_some_and(|p| p.keys.contains(val))))
+                && txn.read(&reference.table, val)?.is_none()
+            {
+                return Err(Error::Row(format!(
+                    "foreign key violation: {}.{} = {} does not exist",
+                    reference.table, reference.column, val
+                )));
             }
         }
         Ok(())
@@ -228,27 +471,162 @@ pub enum ColumnType {
     Float,
     String,
     Bool,
+    /// DECIMAL(精度, 小数位数) 精度是所有的有效数字
+    Decimal(u8, u8),
+    /// 二进制数据 BLOB 或者 BYTEA
+    Bytes,
+}
+
+impl ColumnType {
+    /// 这个类型的列是否可以写入value类型的值 数字可以写入小数列
+    pub fn accepts(&self, value: &ColumnType) -> bool {
+        match (self, value) {
+            (
+                Self::Decimal(..),
+                Self::Integer | Self::Float | Self::Decimal(..),
+            ) => true,
+            (column, value) => column == value,
+        }
+    }
 }
 
 impl std::fmt::Display for ColumnType {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        f.write_str(match self {
-            Self::Bool => "BOOLEAN",
-            Self::Integer => "INTEGER",
-            Self::Float => "FLOAT",
-            Self::String => "STRING",
-        })
+        match self {
+            Self::Bool => f.write_str("BOOLEAN"),
+            Self::Integer => f.write_str("INTEGER"),
+            Self::Float => f.write_str("FLOAT"),
+            Self::String => f.write_str("STRING"),
+            Self::Decimal(precision, scale) => write!(f, "DECIMAL({},{})", precision, scale),
+            Self::Bytes => f.write_str("BLOB"),
+        }
+    }
+}
+
+/// 行的存活时间 过期的行读取的时候看不到 之后由后台任务删除
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Ttl {
+    pub seconds: u64,
+    /// 保存时间戳(秒)的列 没有的时候使用行最后一次写入的时间
+    pub column: Option<String>,
+}
+
+/// CHECK 约束 写入的行计算结果是false的时候报错 null不算违反
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Check {
+    pub name: String,
+    /// 表达式中的字段是表中列的位置
+    pub expression: Expression,
+}
+
+/// 行级安全策略 非管理员的会话查询表的时候只能看到表达式为true的行
+/// 一张表有多个策略的时候需要全部满足
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Policy {
+    pub name: String,
+    /// 表达式中的字段是表中列的位置
+    pub expression: Expression,
+}
+
+/// 批量插入中已经检查过但是还没有写入的行
+#[derive(Debug, Default)]
+pub struct Pending {
+    /// 这些行的主键
+    pub keys: HashSet<Value>,
+    /// 这些行中唯一字段的 (列名, 值)
+    pub values: HashSet<(String, Value)>,
+}
+
+impl Pending {
+    /// 记录检查过的一行
+    pub fn push(&mut self, table: &Table, row: &[Value]) -> Result<()> {
+        self.keys.insert(table.get_row_key(row)?);
+        for (column, value) in table.columns.iter().zip(row) {
+            if column.unique && !column.primary_key && value != &Value::Null {
+                self.values.insert((column.name.clone(), value.clone()));
+            }
+        }
+        Ok(())
     }
 }
 
 /// 表
+/// 存储的时候编码成json 新增的字段需要 #[serde(default)] 这样之前写入的定义也能解码
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Table {
     pub name: String,
     pub columns: Vec<Column>,
+    #[serde(default)]
+    pub checks: Vec<Check>,
+    #[serde(default)]
+    pub ttl: Option<Ttl>,
+    #[serde(default)]
+    pub policies: Vec<Policy>,
+    /// ANALYZE 收集的统计信息 没有执行过的是None
+    #[serde(default)]
+    pub statistics: Option<TableStats>,
 }
 impl Table {
+    /// 行是否已经过期 written是行最后一次写入的时间 now是当前时间 单位都是秒
+    /// 时间戳列是null的行不会过期
+    pub fn expired(&self, row: &[Value], written: u64, now: u64) -> bool {
+        let ttl = match &self.ttl {
+            Some(ttl) => ttl,
+            None => return false,
+        };
+        let time = match &ttl.column {
+            None => written as i64,
+            Some(column) => match self.get_column_index(column).ok().and_then(|i| row.get(i)) {
+                Some(Value::Integer(time)) => *time,
+                _ => return false,
+            },
+        };
+        time.saturating_add(ttl.seconds as i64) <= now as i64
+    }
+
+    /// 把行中的值转换成列的类型 在检查之前调用
+    pub fn coerce_row(&self, row: Vec<Value>) -> Result<Vec<Value>> {
+        if row.len() != self.columns.len() {
+            return Ok(row);
+        }
+        self.columns
+            .iter()
+            .zip(row)
+            .map(|(column, value)| column.coerce(value))
+            .collect()
+    }
+
     pub fn check_row(&self, row: &[Value], txn: &mut dyn Transaction) -> Result<()> {
+        self.validate_row(row, None, None, txn)
+    }
+
+    /// 检查修改之后的行 修改了主键的时候 唯一索引中原来的主键也是这一行
+    pub fn check_update_row(
+        &self,
+        old: Option<&Value>,
+        row: &[Value],
+        txn: &mut dyn Transaction,
+    ) -> Result<()> {
+        self.validate_row(row, old, None, txn)
+    }
+
+    /// 检查批量插入中的一行 同一批中前面的行也要算上
+    pub fn check_batch_row(
+        &self,
+        row: &[Value],
+        pending: &Pending,
+        txn: &mut dyn Transaction,
+    ) -> Result<()> {
+        self.validate_row(row, None, Some(pending), txn)
+    }
+
+    fn validate_row(
+        &self,
+        row: &[Value],
+        old: Option<&Value>,
+        pending: Option<&Pending>,
+        txn: &mut dyn Transaction,
+    ) -> Result<()> {
         // 先判断行数
         if self.columns.len() != row.len() {
             return Err(Error::Table(format!(
@@ -261,10 +639,36 @@ impl Table {
         let pk = self.get_row_key(row)?;
 
         for (column, value) in self.columns.iter().zip(row.iter()) {
-            column.validate_value(self, &pk, value, txn)?;
+            column.validate_value(self, &pk, old, pending, value, txn)?;
         }
 
-        return Ok(());
+        return self.check_constraints(row);
+    }
+
+    /// 计算所有的 CHECK 约束 行的长度已经检查过了
+    pub fn check_constraints(&self, row: &[Value]) -> Result<()> {
+        if self.checks.is_empty() {
+            return Ok(());
+        }
+        let row = row.to_vec();
+        for check in self.checks.iter() {
+            match check.expression.evaluate(Some(&row))? {
+                Value::Bool(true) | Value::Null => {}
+                Value::Bool(false) => {
+                    return Err(Error::Row(format!(
+                        "row violates check constraint {} ({}) of table {}",
+                        check.name, check.expression, self.name
+                    )))
+                }
+                value => {
+                    return Err(Error::Row(format!(
+                        "check constraint {} returns non boolean value {}",
+                        check.name, value
+                    )))
+                }
+            }
+        }
+        Ok(())
     }
 
     fn get_row_key(&self, row: &[Value]) -> Result<Value> {
@@ -299,16 +703,43 @@ impl Table {
                 return Err(Error::Table(format!("primary_key cannot be nullable")));
             }
 
+            // 外键只能引用主键 并且类型要一致
+            if let Some(reference) = &ele.references {
+                let target = if reference.table == self.name {
+                    self.clone()
+                } else {
+                    arg.must_read_table(&reference.table)?
+                };
+                let column = &target.columns[target.get_column_index(&reference.column)?];
+                if !column.primary_key {
+                    return Err(Error::Table(format!(
+                        "column {} references {}.{} which is not a primary key",
+                        ele.name, reference.table, reference.column
+                    )));
+                }
+                if column.column_type != ele.column_type {
+                    return Err(Error::Table(format!(
+                        "column {} is {} but references {}.{} which is {}",
+                        ele.name,
+                        ele.column_type,
+                        reference.table,
+                        reference.column,
+                        column.column_type
+                    )));
+                }
+            }
+
             // 看一下默认值
+            // 表达式只能检查推导出来的类型 计算的结果在插入的时候还会再检查
             if let Some(default) = &ele.default {
-                if let Some(datatype) = default.datatype() {
-                    if datatype != ele.column_type {
+                if let (Some(datatype), _) = default.column_type(&[]) {
+                    if !ele.column_type.accepts(&datatype) {
                         return Err(Error::Table(format!(
                             "datatype of default value is {}, but datatype of column is {}",
                             datatype, ele.column_type
                         )));
                     }
-                } else if !ele.nullable {
+                } else if default == &Expression::Constant(Value::Null) && !ele.nullable {
                     return Err(Error::Table(format!(
                         "cannot use null default value with not nullable column"
                     )));
@@ -321,6 +752,32 @@ impl Table {
                 )));
             }
         }
+        // 约束的结果只能是布尔值
+        let columns = self
+            .columns
+            .iter()
+            .map(execution::Column::from)
+            .collect::<Vec<_>>();
+        for check in self.checks.iter() {
+            if let (Some(datatype), _) = check.expression.column_type(&columns) {
+                if datatype != ColumnType::Bool {
+                    return Err(Error::Table(format!(
+                        "check constraint {} is {} but must be BOOLEAN",
+                        check.name, datatype
+                    )));
+                }
+            }
+        }
+        // 时间戳列只能是整数
+        if let Some(column) = self.ttl.as_ref().and_then(|ttl| ttl.column.as_ref()) {
+            let column = &self.columns[self.get_column_index(column)?];
+            if column.column_type != ColumnType::Integer {
+                return Err(Error::Table(format!(
+                    "ttl column {} must be INTEGER but is {}",
+                    column.name, column.column_type
+                )));
+            }
+        }
         Ok(())
     }
 
@@ -354,3 +811,93 @@ impl Display for OrderType {
         )
     }
 }
+
+/// 排序时null的位置 和方向无关
+/// 没有指定的时候null当作最小的值 asc的时候在最前面 desc的时候在最后面
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub enum NullOrder {
+    First,
+    Last,
+}
+
+impl NullOrder {
+    pub fn default_for(order: &OrderType) -> Self {
+        match order {
+            OrderType::ASC => Self::First,
+            OrderType::DES => Self::Last,
+        }
+    }
+}
+
+impl Display for NullOrder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Self::First => "nulls first",
+                Self::Last => "nulls last",
+            }
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    use super::decimal::Decimal;
+    use super::Value;
+    use crate::errors::Result;
+    use crate::storage::kv::encoding::encode_value;
+
+    #[test]
+    fn value_order_test() -> Result<()> {
+        let hash = |v: &Value| {
+            let mut hasher = DefaultHasher::new();
+            v.hash(&mut hasher);
+            hasher.finish()
+        };
+        let d = |s: &str| Decimal::parse(s).map(Value::Decimal);
+        // 从小到大 每一组中的值相等 数值相同的不同类型的数字相等
+        let groups = vec![
+            vec![Value::Null],
+            vec![Value::Bool(false)],
+            vec![Value::Bool(true)],
+            vec![Value::Float(f64::NEG_INFINITY)],
+            vec![Value::Integer(i64::MIN), Value::Float(-9223372036854775808.0)],
+            vec![d("-1.5")?, d("-1.50")?, Value::Float(-1.5)],
+            vec![Value::Float(-0.0)],
+            vec![Value::Integer(0), d("0")?, Value::Float(0.0)],
+            vec![d("0.1")?, Value::Float(0.1)],
+            vec![Value::Integer(1), d("1.00")?, Value::Float(1.0)],
+            vec![Value::Integer(9007199254740992), Value::Float(9007199254740992.0)],
+            vec![Value::Integer(9007199254740993)],
+            vec![Value::Float(9007199254740994.0), d("9007199254740994")?],
+            vec![Value::Float(f64::INFINITY)],
+            vec![Value::Float(f64::NAN), Value::Float(-f64::NAN)],
+            vec![Value::String("".into())],
+            vec![Value::String("a".into())],
+            vec![Value::Bytes(vec![])],
+        ];
+        let values = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(i, group)| group.iter().map(move |v| (i, v)))
+            .collect::<Vec<_>>();
+        for (gi, a) in &values {
+            for (gj, b) in &values {
+                assert_eq!(a.cmp(b), gi.cmp(gj), "{:?} {:?}", a, b);
+                if gi == gj {
+                    assert_eq!(hash(a), hash(b), "{:?} {:?}", a, b);
+                }
+                // 同一种类型的编码顺序一样
+                if std::mem::discriminant(*a) == std::mem::discriminant(*b) {
+                    assert_eq!(encode_value(a).cmp(&encode_value(b)), gi.cmp(gj));
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -2,37 +2,91 @@ use std::collections::BTreeMap;
 
 use crate::errors::Result;
 
-use crate::sql::{ColumnType, OrderType, Value};
+use crate::sql::{
+    plan::Aggregate, ColumnType, NullOrder, OrderType, ReferenceAction, Ttl, Value,
+};
 /// Statements
 #[derive(Clone, Debug, PartialEq)]
 pub enum Statement {
     Begin {
         readonly: bool,
         version: Option<u64>,
+        isolation: Isolation,
     },
     Commit,
     Rollback,
-    Explain(Box<Statement>),
+    /// 在当前事务中创建保存点
+    Savepoint(String),
+    /// 撤销保存点之后的修改 保存点还在
+    RollbackTo(String),
+    /// 删除保存点和它之后的保存点 修改保留
+    Release(String),
+    /// analyze 为true时会真正执行 并收集每个节点的统计信息
+    Explain {
+        statement: Box<Statement>,
+        analyze: bool,
+        format: ExplainFormat,
+    },
+    /// 设置会话变量 SET name = value
+    Set {
+        name: String,
+        value: BaseExpression,
+    },
 
     CreateTable {
         name: String,
         columns: Vec<SqlClumn>,
+        /// 表级别的 CHECK (expr)
+        checks: Vec<BaseExpression>,
+        ttl: Option<Ttl>,
+    },
+    /// CREATE TABLE 表名称 AS SELECT ... 列的名字和类型来自查询的结果
+    CreateTableAs {
+        name: String,
+        query: Box<Statement>,
     },
     DropTable(String),
+    /// CREATE POLICY 名称 ON 表名称 USING (expr) 非管理员的会话只能看到满足表达式的行
+    CreatePolicy {
+        name: String,
+        table: String,
+        expression: BaseExpression,
+    },
+    /// DROP POLICY 名称 ON 表名称
+    DropPolicy {
+        name: String,
+        table: String,
+    },
+    /// 删除表中所有的行
+    Truncate(String),
+    /// 检查表中所有行的校验和 以及索引和行是否一致
+    CheckTable(String),
+    /// 收集表的统计信息
+    Analyze(String),
+    /// 把当前事务能看到的所有数据写到备份文件
+    Backup(String),
+    /// 从备份文件恢复 只能恢复到空的数据库
+    Restore(String),
 
     Delete {
         table: String,
         filter: Option<BaseExpression>,
+        returning: Option<SelectItems>,
     },
     Insert {
         table: String,
         columns: Option<Vec<String>>,
         values: Vec<Vec<BaseExpression>>,
+        /// ON CONFLICT (column) DO ...
+        on_conflict: Option<SqlOnConflict>,
+        /// RETURNING 后面的列 和select一样空的表示*
+        returning: Option<SelectItems>,
     },
     Update {
         table: String,
         set: BTreeMap<String, BaseExpression>,
         filter: Option<BaseExpression>,
+        returning: Option<SelectItems>,
     },
 
     Select {
@@ -41,12 +95,63 @@ pub enum Statement {
         filter: Option<BaseExpression>,
         group_by: Vec<BaseExpression>,
         having: Option<BaseExpression>,
-        order: Vec<(BaseExpression, OrderType)>,
+        order: Vec<(BaseExpression, OrderType, NullOrder)>,
         offset: Option<BaseExpression>,
         limit: Option<BaseExpression>,
     },
 }
 
+impl Statement {
+    /// 语句的类型 统计每种语句的执行次数使用
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Begin { .. } => "begin",
+            Self::Commit => "commit",
+            Self::Rollback => "rollback",
+            Self::Savepoint(_) => "savepoint",
+            Self::RollbackTo(_) => "rollback_to",
+            Self::Release(_) => "release",
+            Self::Explain { .. } => "explain",
+            Self::Set { .. } => "set",
+            Self::CreateTable { .. } => "create_table",
+            Self::CreateTableAs { .. } => "create_table",
+            Self::DropTable(_) => "drop_table",
+            Self::CreatePolicy { .. } => "create_policy",
+            Self::DropPolicy { .. } => "drop_policy",
+            Self::Truncate(_) => "truncate",
+            Self::CheckTable(_) => "check_table",
+            Self::Analyze(_) => "analyze",
+            Self::Backup(_) => "backup",
+            Self::Restore(_) => "restore",
+            Self::Delete { .. } => "delete",
+            Self::Insert { .. } => "insert",
+            Self::Update { .. } => "update",
+            Self::Select { .. } => "select",
+        }
+    }
+}
+
+/// select 或者 RETURNING 后面的表达式和别名
+pub type SelectItems = Vec<(BaseExpression, Option<String>)>;
+
+/// explain 结果的格式
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExplainFormat {
+    /// 缩进的执行树
+    Text,
+    /// serde 编码的执行树 给其他的工具使用
+    Json,
+}
+
+/// 事务的隔离级别
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Isolation {
+    /// 整个事务使用开始时的快照
+    Snapshot,
+    /// 每条语句使用最新的快照
+    ReadCommitted,
+}
+
 /// A FROM item
 #[derive(Clone, Debug, PartialEq)]
 pub enum FromItem {
@@ -60,6 +165,12 @@ pub enum FromItem {
         join_type: JoinType,
         predicate: Option<BaseExpression>,
     },
+    /// (VALUES (...), (...)) [AS] alias [(列名, ...)] 没有列名的时候是 column1 column2 ...
+    Values {
+        rows: Vec<Vec<BaseExpression>>,
+        alias: Option<String>,
+        columns: Vec<String>,
+    },
 }
 
 /// A JOIN type
@@ -69,6 +180,7 @@ pub enum JoinType {
     Inner,
     Left,
     Right,
+    Full,
 }
 
 /// A column
@@ -84,6 +196,33 @@ pub struct SqlClumn {
     pub default: Option<BaseExpression>,
     pub unique: bool,
     pub index: bool,
+    /// 外键 REFERENCES table(column)
+    pub references: Option<SqlReference>,
+    /// CHECK (expr)
+    pub check: Option<BaseExpression>,
+}
+
+/// 外键引用 没有写column的时候使用引用表的主键
+#[derive(Clone, Debug, PartialEq)]
+pub struct SqlReference {
+    pub table: String,
+    pub column: Option<String>,
+    pub on_delete: ReferenceAction,
+}
+
+/// 插入时主键冲突的处理 column是冲突检测的列 只能是主键
+#[derive(Clone, Debug, PartialEq)]
+pub struct SqlOnConflict {
+    pub column: String,
+    pub action: SqlConflictAction,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SqlConflictAction {
+    /// DO NOTHING 跳过这一行
+    Nothing,
+    /// DO UPDATE SET ... 更新已经存在的行
+    Update(BTreeMap<String, BaseExpression>),
 }
 
 /// Expressions
@@ -91,10 +230,15 @@ pub struct SqlClumn {
 #[allow(unconditional_recursion)]
 pub enum BaseExpression {
     Field(Option<String>, String),
+    /// select 列表中的 * 和 t.* 规划的时候展开成列
+    Wildcard(Option<String>),
     Column(usize),
     Value(Value),
-    Function(String, Box<BaseExpression>),
+    /// 函数 聚合函数只有一个参数
+    Function(String, Vec<BaseExpression>),
     Operation(Operation),
+    /// 括号中的select 只能返回一行一列
+    Subquery(Box<Statement>),
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -107,10 +251,15 @@ pub enum Operation {
     And(Box<BaseExpression>, Box<BaseExpression>),
     Or(Box<BaseExpression>, Box<BaseExpression>),
 
-    Like(Box<BaseExpression>, Box<BaseExpression>),
+    /// 模糊匹配 最后是转义字符 None表示不转义
+    Like(Box<BaseExpression>, Box<BaseExpression>, Option<char>),
+    /// 不区分大小写的模糊匹配
+    ILike(Box<BaseExpression>, Box<BaseExpression>, Option<char>),
 
     Equal(Box<BaseExpression>, Box<BaseExpression>),
     NotEqual(Box<BaseExpression>, Box<BaseExpression>),
+    /// <=> 或者 IS NOT DISTINCT FROM null和null相等 null和其他值不相等
+    NullSafeEqual(Box<BaseExpression>, Box<BaseExpression>),
     GreaterThan(Box<BaseExpression>, Box<BaseExpression>),
     GreaterThanOrEqual(Box<BaseExpression>, Box<BaseExpression>),
     LessThan(Box<BaseExpression>, Box<BaseExpression>),
@@ -120,6 +269,10 @@ pub enum Operation {
     Subtract(Box<BaseExpression>, Box<BaseExpression>),
     Multiply(Box<BaseExpression>, Box<BaseExpression>),
     Divide(Box<BaseExpression>, Box<BaseExpression>),
+    /// DIV 整数除法
+    IntegerDivide(Box<BaseExpression>, Box<BaseExpression>),
+    /// 取余
+    Modulo(Box<BaseExpression>, Box<BaseExpression>),
     Exponentiate(Box<BaseExpression>, Box<BaseExpression>),
 
     Not(Box<BaseExpression>),
@@ -141,16 +294,20 @@ impl BaseExpression {
             BaseExpression::Operation(Operation::Add(lhs, rhs))
             | Self::Operation(Operation::And(lhs, rhs))
             | Self::Operation(Operation::Divide(lhs, rhs))
+            | Self::Operation(Operation::IntegerDivide(lhs, rhs))
             | Self::Operation(Operation::Equal(lhs, rhs))
             | Self::Operation(Operation::Exponentiate(lhs, rhs))
             | Self::Operation(Operation::GreaterThan(lhs, rhs))
             | Self::Operation(Operation::GreaterThanOrEqual(lhs, rhs))
             | Self::Operation(Operation::LessThan(lhs, rhs))
             | Self::Operation(Operation::LessThanOrEqual(lhs, rhs))
-            | Self::Operation(Operation::Like(lhs, rhs))
+            | Self::Operation(Operation::Like(lhs, rhs, _))
+            | Self::Operation(Operation::ILike(lhs, rhs, _))
+            | Self::Operation(Operation::Modulo(lhs, rhs))
             | Self::Operation(Operation::Multiply(lhs, rhs))
             | Self::Operation(Operation::Or(lhs, rhs))
             | Self::Operation(Operation::NotEqual(lhs, rhs))
+            | Self::Operation(Operation::NullSafeEqual(lhs, rhs))
             | Self::Operation(Operation::Subtract(lhs, rhs)) => {
                 lhs.transform_ref(before, after)?;
                 rhs.transform_ref(before, after)?;
@@ -158,11 +315,20 @@ impl BaseExpression {
             Self::Operation(Operation::Plus(expr))
             | Self::Operation(Operation::Negative(expr))
             | Self::Operation(Operation::IsNull(expr))
-            | Self::Function(_, expr)
             | Self::Operation(Operation::Not(expr)) => {
                 expr.transform_ref(before, after)?;
             }
-            Self::Value(_) | Self::Field(_, _) | Self::Column(_) => {}
+            Self::Function(_, args) => {
+                for arg in args.iter_mut() {
+                    arg.transform_ref(before, after)?;
+                }
+            }
+            // 子查询中的表达式在规划子查询的时候处理
+            Self::Value(_)
+            | Self::Field(_, _)
+            | Self::Wildcard(_)
+            | Self::Column(_)
+            | Self::Subquery(_) => {}
         };
         after(self)
     }
@@ -191,35 +357,43 @@ impl BaseExpression {
                 Self::Operation(Add(lhs, rhs))
                 | Self::Operation(And(lhs, rhs))
                 | Self::Operation(Divide(lhs, rhs))
+                | Self::Operation(IntegerDivide(lhs, rhs))
                 | Self::Operation(Equal(lhs, rhs))
                 | Self::Operation(Exponentiate(lhs, rhs))
                 | Self::Operation(GreaterThan(lhs, rhs))
                 | Self::Operation(GreaterThanOrEqual(lhs, rhs))
                 | Self::Operation(LessThan(lhs, rhs))
                 | Self::Operation(LessThanOrEqual(lhs, rhs))
-                | Self::Operation(Like(lhs, rhs))
+                | Self::Operation(Like(lhs, rhs, _))
+                | Self::Operation(ILike(lhs, rhs, _))
+                | Self::Operation(Modulo(lhs, rhs))
                 | Self::Operation(Multiply(lhs, rhs))
                 | Self::Operation(NotEqual(lhs, rhs))
+                | Self::Operation(NullSafeEqual(lhs, rhs))
                 | Self::Operation(Or(lhs, rhs))
                 | Self::Operation(Subtract(lhs, rhs)) => {
                     lhs.contains(predicate) || rhs.contains(predicate)
                 },
-                Self::Function(_, expr)
-                | Self::Operation(Plus(expr))
+                Self::Function(_, args) => args.iter().any(|arg| arg.contains(predicate)),
+                Self::Operation(Plus(expr))
                 | Self::Operation(Negative(expr))
                 | Self::Operation(IsNull(expr))
                 | Self::Operation(Not(expr)) => expr.contains(predicate),
                 // 如果上面的predicate失败 这里也就是false
-                Self::Value(_) | Self::Field(_, _) | Self::Column(_) => false,
+                Self::Value(_)
+                | Self::Field(_, _)
+                | Self::Wildcard(_)
+                | Self::Column(_)
+                | Self::Subquery(_) => false,
             }
     }
 
+    /// 是否是聚合函数 不是聚合函数的就是标量函数
+    pub fn is_aggreate(&self) -> bool {
+        matches!(self, BaseExpression::Function(f, _) if Aggregate::from_str(f).is_ok())
+    }
+
     pub fn contains_aggreate(&self) -> bool {
-        self.contains(&|e|{
-            match e {
-                BaseExpression::Function(_,_ ) => true,
-                _ => false,
-            }
-        })
+        self.contains(&|e| e.is_aggreate())
     }
 }
@@ -1,5 +1,4 @@
-use crate::errors::{Error, Result};
-use std::{iter::Peekable, str::Chars};
+use crate::errors::{Error, Position, Result, Span};
 
 /// 定义token
 #[derive(Clone, Debug, PartialEq)]
@@ -10,8 +9,12 @@ pub enum Token {
     Number(String),
     /// 字符串
     String(String),
+    /// X'DEADBEEF' 中的十六进制数字
+    Hex(String),
     /// 标识符，表名 字符名 函数
     Ident(String),
+    /// $1 这样的参数 从1开始
+    Parameter(usize),
     // 下面是操作符号
     /// .
     Period,
@@ -27,6 +30,8 @@ pub enum Token {
     LessThanOrEqual,
     /// <>
     LessOrGreaterThan,
+    /// <=> null和null也相等
+    NullSafeEqual,
     /// +
     Plus,
     /// -
@@ -58,8 +63,10 @@ pub enum Token {
 impl std::fmt::Display for Token {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.write_str(match self {
+            Token::Parameter(n) => return write!(f, "${}", n),
             Token::Number(n) => n,
             Token::String(s) => s,
+            Token::Hex(s) => s,
             Token::Ident(s) => s,
             Token::Keyword(k) => k.to_str(),
             Token::Period => ".",
@@ -69,6 +76,7 @@ impl std::fmt::Display for Token {
             Token::LessThan => "<",
             Token::LessThanOrEqual => "<=",
             Token::LessOrGreaterThan => "<>",
+            Token::NullSafeEqual => "<=>",
             Token::Plus => "+",
             Token::Minus => "-",
             Token::Asterisk => "*",
@@ -89,28 +97,45 @@ impl std::fmt::Display for Token {
 /// 词法分析器的关键字，按照首字母排序
 #[derive(Clone, Debug, PartialEq)]
 pub enum Keyword {
+    Analyze,
     And,
     As,
     Asc,
+    Backup,
     Begin,
+    Bigint,
+    Blob,
     Bool,
     Boolean,
     By,
+    Bytea,
+    Cascade,
     Char,
+    Check,
     Commit,
+    Committed,
+    Conflict,
     Create,
     Cross,
+    Decimal,
     Default,
     Delete,
     Desc,
+    Distinct,
+    Div,
+    Do,
     Double,
     Drop,
+    Escape,
     Explain,
     False,
+    First,
     Float,
     From,
+    Full,
     Group,
     Having,
+    ILike,
     Index,
     Infinity,
     Inner,
@@ -119,14 +144,20 @@ pub enum Keyword {
     Integer,
     Into,
     Is,
+    Isolation,
     Join,
     Key,
+    Last,
     Left,
+    Level,
     Like,
     Limit,
     NaN,
     Not,
+    Nothing,
     Null,
+    Nulls,
+    Numeric,
     Of,
     Offset,
     On,
@@ -134,22 +165,33 @@ pub enum Keyword {
     Or,
     Order,
     Outer,
+    Policy,
     Primary,
     Read,
     References,
+    Release,
+    Restore,
+    Restrict,
+    Returning,
     Right,
     Rollback,
+    Savepoint,
     Select,
     Set,
+    Snapshot,
     String,
     System,
     Table,
     Text,
     Time,
+    To,
     Transaction,
     True,
+    Truncate,
+    Ttl,
     Unique,
     Update,
+    Using,
     Values,
     Varchar,
     Where,
@@ -160,28 +202,45 @@ impl Keyword {
     /// 通过string变成Keyword, 如果不匹配返回null 记得全部大写匹配
     fn from_str(s: &str) -> Option<Self> {
         match s.to_uppercase().as_str() {
+            "ANALYZE" => Some(Self::Analyze),
             "AS" => Some(Self::As),
             "ASC" => Some(Self::Asc),
             "AND" => Some(Self::And),
+            "BACKUP" => Some(Self::Backup),
             "BEGIN" => Some(Self::Begin),
             "BOOL" => Some(Self::Bool),
+            "BIGINT" => Some(Self::Bigint),
+            "BLOB" => Some(Self::Blob),
             "BOOLEAN" => Some(Self::Boolean),
             "BY" => Some(Self::By),
+            "BYTEA" => Some(Self::Bytea),
+            "CASCADE" => Some(Self::Cascade),
             "CHAR" => Some(Self::Char),
+            "CHECK" => Some(Self::Check),
             "COMMIT" => Some(Self::Commit),
+            "COMMITTED" => Some(Self::Committed),
+            "CONFLICT" => Some(Self::Conflict),
             "CREATE" => Some(Self::Create),
             "CROSS" => Some(Self::Cross),
+            "DECIMAL" => Some(Self::Decimal),
             "DEFAULT" => Some(Self::Default),
             "DELETE" => Some(Self::Delete),
             "DESC" => Some(Self::Desc),
+            "DISTINCT" => Some(Self::Distinct),
+            "DIV" => Some(Self::Div),
+            "DO" => Some(Self::Do),
             "DOUBLE" => Some(Self::Double),
             "DROP" => Some(Self::Drop),
+            "ESCAPE" => Some(Self::Escape),
             "EXPLAIN" => Some(Self::Explain),
             "FALSE" => Some(Self::False),
+            "FIRST" => Some(Self::First),
             "FLOAT" => Some(Self::Float),
             "FROM" => Some(Self::From),
+            "FULL" => Some(Self::Full),
             "GROUP" => Some(Self::Group),
             "HAVING" => Some(Self::Having),
+            "ILIKE" => Some(Self::ILike),
             "INDEX" => Some(Self::Index),
             "INFINITY" => Some(Self::Infinity),
             "INNER" => Some(Self::Inner),
@@ -190,14 +249,20 @@ impl Keyword {
             "INTEGER" => Some(Self::Integer),
             "INTO" => Some(Self::Into),
             "IS" => Some(Self::Is),
+            "ISOLATION" => Some(Self::Isolation),
             "JOIN" => Some(Self::Join),
             "KEY" => Some(Self::Key),
+            "LAST" => Some(Self::Last),
             "LEFT" => Some(Self::Left),
+            "LEVEL" => Some(Self::Level),
             "LIKE" => Some(Self::Like),
             "LIMIT" => Some(Self::Limit),
             "NAN" => Some(Self::NaN),
             "NOT" => Some(Self::Not),
+            "NOTHING" => Some(Self::Nothing),
             "NULL" => Some(Self::Null),
+            "NULLS" => Some(Self::Nulls),
+            "NUMERIC" => Some(Self::Numeric),
             "OF" => Some(Self::Of),
             "OFFSET" => Some(Self::Offset),
             "ON" => Some(Self::On),
@@ -205,22 +270,33 @@ impl Keyword {
             "OR" => Some(Self::Or),
             "ORDER" => Some(Self::Order),
             "OUTER" => Some(Self::Outer),
+            "POLICY" => Some(Self::Policy),
             "PRIMARY" => Some(Self::Primary),
             "READ" => Some(Self::Read),
             "REFERENCES" => Some(Self::References),
+            "RELEASE" => Some(Self::Release),
+            "RESTORE" => Some(Self::Restore),
+            "RESTRICT" => Some(Self::Restrict),
+            "RETURNING" => Some(Self::Returning),
             "RIGHT" => Some(Self::Right),
             "ROLLBACK" => Some(Self::Rollback),
+            "SAVEPOINT" => Some(Self::Savepoint),
             "SELECT" => Some(Self::Select),
             "SET" => Some(Self::Set),
+            "SNAPSHOT" => Some(Self::Snapshot),
             "STRING" => Some(Self::String),
             "SYSTEM" => Some(Self::System),
             "TABLE" => Some(Self::Table),
             "TEXT" => Some(Self::Text),
             "TIME" => Some(Self::Time),
+            "TO" => Some(Self::To),
             "TRANSACTION" => Some(Self::Transaction),
             "TRUE" => Some(Self::True),
+            "TRUNCATE" => Some(Self::Truncate),
+            "TTL" => Some(Self::Ttl),
             "UNIQUE" => Some(Self::Unique),
             "UPDATE" => Some(Self::Update),
+            "USING" => Some(Self::Using),
             "VALUES" => Some(Self::Values),
             "VARCHAR" => Some(Self::Varchar),
             "WHERE" => Some(Self::Where),
@@ -231,28 +307,45 @@ impl Keyword {
     /// 将自己转换为string
     fn to_str(&self) -> &str {
         match self {
+            Self::Analyze => "ANALYZE",
             Self::As => "AS",
             Self::Asc => "ASC",
             Self::And => "AND",
+            Self::Backup => "BACKUP",
             Self::Begin => "BEGIN",
+            Self::Bigint => "BIGINT",
             Self::Bool => "BOOL",
+            Self::Blob => "BLOB",
             Self::Boolean => "BOOLEAN",
             Self::By => "BY",
+            Self::Bytea => "BYTEA",
+            Self::Cascade => "CASCADE",
             Self::Char => "CHAR",
+            Self::Check => "CHECK",
             Self::Commit => "COMMIT",
+            Self::Committed => "COMMITTED",
+            Self::Conflict => "CONFLICT",
             Self::Create => "CREATE",
             Self::Cross => "CROSS",
+            Self::Decimal => "DECIMAL",
             Self::Default => "DEFAULT",
             Self::Delete => "DELETE",
             Self::Desc => "DESC",
+            Self::Distinct => "DISTINCT",
+            Self::Div => "DIV",
+            Self::Do => "DO",
             Self::Double => "DOUBLE",
             Self::Drop => "DROP",
+            Self::Escape => "ESCAPE",
             Self::Explain => "EXPLAIN",
             Self::False => "FALSE",
+            Self::First => "FIRST",
             Self::Float => "FLOAT",
             Self::From => "FROM",
+            Self::Full => "FULL",
             Self::Group => "GROUP",
             Self::Having => "HAVING",
+            Self::ILike => "ILIKE",
             Self::Index => "INDEX",
             Self::Infinity => "INFINITY",
             Self::Inner => "INNER",
@@ -261,14 +354,20 @@ impl Keyword {
             Self::Integer => "INTEGER",
             Self::Into => "INTO",
             Self::Is => "IS",
+            Self::Isolation => "ISOLATION",
             Self::Join => "JOIN",
             Self::Key => "KEY",
+            Self::Last => "LAST",
             Self::Left => "LEFT",
+            Self::Level => "LEVEL",
             Self::Like => "LIKE",
             Self::Limit => "LIMIT",
             Self::NaN => "NAN",
             Self::Not => "NOT",
+            Self::Nothing => "NOTHING",
             Self::Null => "NULL",
+            Self::Nulls => "NULLS",
+            Self::Numeric => "NUMERIC",
             Self::Of => "OF",
             Self::Offset => "OFFSET",
             Self::On => "ON",
@@ -276,22 +375,33 @@ impl Keyword {
             Self::Outer => "OUTER",
             Self::Or => "OR",
             Self::Order => "ORDER",
+            Self::Policy => "POLICY",
             Self::Primary => "PRIMARY",
             Self::Read => "READ",
             Self::References => "REFERENCES",
+            Self::Release => "RELEASE",
+            Self::Restore => "RESTORE",
+            Self::Restrict => "RESTRICT",
+            Self::Returning => "RETURNING",
             Self::Right => "RIGHT",
             Self::Rollback => "ROLLBACK",
+            Self::Savepoint => "SAVEPOINT",
             Self::Select => "SELECT",
             Self::Set => "SET",
+            Self::Snapshot => "SNAPSHOT",
             Self::String => "STRING",
             Self::System => "SYSTEM",
             Self::Table => "TABLE",
             Self::Text => "TEXT",
             Self::Time => "TIME",
+            Self::To => "TO",
             Self::Transaction => "TRANSACTION",
             Self::True => "TRUE",
+            Self::Truncate => "TRUNCATE",
+            Self::Ttl => "TTL",
             Self::Unique => "UNIQUE",
             Self::Update => "UPDATE",
+            Self::Using => "USING",
             Self::Values => "VALUES",
             Self::Varchar => "VARCHAR",
             Self::Where => "WHERE",
@@ -300,6 +410,20 @@ impl Keyword {
     }
 }
 
+/// 所有关键字 按照字母排序 客户端补全和高亮使用
+pub const KEYWORDS: &[&str] = &[
+    "ANALYZE", "AND", "AS", "ASC", "BACKUP", "BEGIN", "BIGINT", "BLOB", "BOOL", "BOOLEAN", "BY",
+    "BYTEA", "CASCADE", "CHAR", "CHECK", "COMMIT", "COMMITTED", "CONFLICT", "CREATE", "CROSS",
+    "DECIMAL", "DEFAULT", "DELETE", "DESC", "DISTINCT", "DIV", "DO", "DOUBLE", "DROP", "ESCAPE",
+    "EXPLAIN", "FALSE", "FIRST", "FLOAT", "FROM", "FULL", "GROUP", "HAVING", "ILIKE", "INDEX",
+    "INFINITY", "INNER", "INSERT", "INT", "INTEGER", "INTO", "IS", "ISOLATION", "JOIN", "KEY",
+    "LAST", "LEFT", "LEVEL", "LIKE", "LIMIT", "NAN", "NOT", "NOTHING", "NULL", "NULLS", "NUMERIC",
+    "OF", "OFFSET", "ON", "ONLY", "OR", "ORDER", "OUTER", "POLICY", "PRIMARY", "READ", "REFERENCES",
+    "RELEASE", "RESTORE", "RESTRICT", "RETURNING", "RIGHT", "ROLLBACK", "SAVEPOINT", "SELECT",
+    "SET", "SNAPSHOT", "STRING", "SYSTEM", "TABLE", "TEXT", "TIME", "TO", "TRANSACTION", "TRUE",
+    "TRUNCATE", "TTL", "UNIQUE", "UPDATE", "USING", "VALUES", "VARCHAR", "WHERE", "WRITE",
+];
+
 impl std::fmt::Display for Keyword {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.write_str(self.to_str())
@@ -312,27 +436,83 @@ impl From<Keyword> for Token {
     }
 }
 
+/// 输入的字符 记录下一个字符所在的行和列
+#[derive(Clone)]
+struct Source<'a> {
+    /// 从下一个字符开始的输入
+    rest: &'a str,
+    current: Option<char>,
+    position: Position,
+}
+
+impl<'a> Source<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            rest: input,
+            current: input.chars().next(),
+            position: Position::default(),
+        }
+    }
+
+    fn peek(&mut self) -> Option<&char> {
+        self.current.as_ref()
+    }
+}
+
+impl Iterator for Source<'_> {
+    type Item = char;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.current?;
+        self.rest = &self.rest[c.len_utf8()..];
+        self.current = self.rest.chars().next();
+        if c == '\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else {
+            self.position.column += 1;
+        }
+        Some(c)
+    }
+}
+
 pub struct Laxer<'a> {
-    iter: Peekable<Chars<'a>>,
+    input: &'a str,
+    iter: Source<'a>,
+    /// 最近一个token开始的位置
+    start: Position,
 }
 
 impl<'a> Laxer<'a> {
     pub fn new(input: &'a str) -> Self {
-        let mut iter = input.chars().peekable();
-        Self { iter }
+        Self {
+            input,
+            iter: Source::new(input),
+            start: Position::default(),
+        }
+    }
+
+    /// 最近一个token开始的位置
+    pub fn start(&self) -> Position {
+        self.start
     }
 
     pub fn get_next(&mut self) -> Result<Option<Token>> {
-        // 将空格排除
-        self.term();
+        // 将空格和注释排除
+        self.term()?;
+        self.start = self.iter.position;
+        let hex = self.iter.clone().nth(1) == Some('\'');
         match self.iter.peek() {
             // indent
             Some('`') => self.get_ident_with_backtick(),
-            Some(c) if c.is_alphabetic() => self.get_ident(),
+            // 二进制 X'...'
+            Some('x' | 'X') if hex => self.get_hex(),
+            Some(c) if c.is_alphabetic() || *c == '_' => self.get_ident(),
             // string
             Some('\"') => self.get_string(),
             // number
             Some(c) if c.is_digit(10) => self.get_number(),
+            Some('$') => self.get_parameter(),
             // 都不是的话看看是不是一些符号
             Some(_) => self.get_symbol(),
             None => Ok(None),
@@ -359,7 +539,11 @@ impl<'a> Laxer<'a> {
                     }
                     '<' => {
                         if self.next_char_expect('=').is_some() {
-                            Token::LessThanOrEqual
+                            if self.next_char_expect('>').is_some() {
+                                Token::NullSafeEqual
+                            } else {
+                                Token::LessThanOrEqual
+                            }
                         } else if self.next_char_expect('>').is_some() {
                             Token::LessOrGreaterThan
                         } else {
@@ -391,7 +575,7 @@ impl<'a> Laxer<'a> {
         Ok(r)
     }
 
-    /// 获得number 这里就不算e什么什么的了，注意一下小数点就行 不需要考虑负号
+    /// 获得number 可以有小数点和指数 例如 1.5E-3 不需要考虑负号
     /// 负号相当于一个数学前缀运算符
     fn get_number(&mut self) -> Result<Option<Token>> {
         let mut res = String::new();
@@ -411,6 +595,22 @@ impl<'a> Laxer<'a> {
                 res.push(self.iter.next().unwrap());
             }
         }
+        // e后面跟着数字才是指数 否则留给后面当作ident
+        let mut ahead = self.iter.clone();
+        let exponent = match (ahead.next(), ahead.next(), ahead.next()) {
+            (Some('e' | 'E'), Some(c), _) if c.is_ascii_digit() => true,
+            (Some('e' | 'E'), Some('+' | '-'), Some(c)) if c.is_ascii_digit() => true,
+            _ => false,
+        };
+        if exponent {
+            res.push(self.iter.next().unwrap());
+            if let Some(sign) = self.next_judge(|c| matches!(**c, '+' | '-')) {
+                res.push(sign);
+            }
+            while self.peek_judge(|c| c.is_ascii_digit()) {
+                res.push(self.iter.next().unwrap());
+            }
+        }
         Ok(Some(Token::Number(res)))
     }
 
@@ -425,7 +625,12 @@ impl<'a> Laxer<'a> {
 
         let mut res = String::new();
         while self.peek_judge(|c| **c != '\"') {
-            res.push(self.iter.next().unwrap());
+            match self.iter.next().unwrap() {
+                // \% 和 \_ 留给LIKE 作为转义
+                '\\' if self.peek_judge(|c| **c == '%' || **c == '_') => res.push('\\'),
+                '\\' => res.push(self.get_escape()?),
+                c => res.push(c),
+            }
         }
 
         match self.next_char_expect('\"') {
@@ -440,13 +645,81 @@ impl<'a> Laxer<'a> {
         Ok(Some(Token::String(res)))
     }
 
-    /// ident 开头必须是字母 后续才可以是 _ , 数字 其余报错
+    /// 字符串中 \\ 之后的转义 支持 \\ \" \n \t \r \0 和 \\uXXXX
+    fn get_escape(&mut self) -> Result<char> {
+        match self.iter.next() {
+            Some('\\') => Ok('\\'),
+            Some('\"') => Ok('\"'),
+            Some('n') => Ok('\n'),
+            Some('t') => Ok('\t'),
+            Some('r') => Ok('\r'),
+            Some('0') => Ok('\0'),
+            Some('u') => {
+                let mut hex = String::new();
+                while hex.len() < 4 {
+                    match self.next_judge(|c| c.is_ascii_hexdigit()) {
+                        Some(c) => hex.push(c),
+                        None => {
+                            return Err(Error::Parse(format!(
+                                "expect 4 hex digits after \\u get \\u{}",
+                                hex
+                            )))
+                        }
+                    }
+                }
+                u32::from_str_radix(&hex, 16)
+                    .ok()
+                    .and_then(char::from_u32)
+                    .ok_or_else(|| Error::Parse(format!("invalid unicode escape \\u{}", hex)))
+            }
+            Some(c) => Err(Error::Parse(format!("unknown escape \\{} in string", c))),
+            None => Err(Error::Parse("expect get \" in the end of string".to_string())),
+        }
+    }
+
+    /// 获得 $ 之后的参数序号
+    fn get_parameter(&mut self) -> Result<Option<Token>> {
+        self.next_char_expect('$');
+        let mut res = String::new();
+        while let Some(c) = self.next_judge(|c| c.is_ascii_digit()) {
+            res.push(c);
+        }
+        match res.parse::<usize>() {
+            Ok(n) if n > 0 => Ok(Some(Token::Parameter(n))),
+            _ => Err(Error::Parse(format!("invalid parameter ${}", res))),
+        }
+    }
+
+    /// 获得 X'...' 单引号中只能是十六进制数字 每两个是一个字节
+    fn get_hex(&mut self) -> Result<Option<Token>> {
+        self.iter.next();
+        self.iter.next();
+        let mut res = String::new();
+        while let Some(c) = self.next_judge(|c| **c != '\'') {
+            if !c.is_ascii_hexdigit() {
+                return Err(Error::Parse(format!("invalid hex digit {} in binary literal", c)));
+            }
+            res.push(c);
+        }
+        if self.next_char_expect('\'').is_none() {
+            return Err(Error::Parse("expect get ' in the end of binary literal".to_string()));
+        }
+        if !res.len().is_multiple_of(2) {
+            return Err(Error::Parse(format!(
+                "binary literal X'{}' must have an even number of hex digits",
+                res
+            )));
+        }
+        Ok(Some(Token::Hex(res)))
+    }
+
+    /// ident 开头必须是字母或者 _ 后续才可以是数字 字母可以是任意语言的
     /// 直接获得ident 注意这里需要查看一下是否有关键字
     fn get_ident(&mut self) -> Result<Option<Token>> {
         let mut res = String::new();
 
         // 开头必须是字母
-        let n = self.next_judge(|c| c.is_alphabetic());
+        let n = self.next_judge(|c| c.is_alphabetic() || **c == '_');
         match n {
             Some(c) => res.push(c),
             None => {
@@ -462,28 +735,26 @@ impl<'a> Laxer<'a> {
             .or_else(|| Some(Token::Ident(res.to_lowercase()))))
     }
 
-    /// 获得反引号包围的ident
+    /// 获得反引号包围的ident 里面可以是任意字符 包括关键字和空格 两个反引号表示一个反引号
+    /// 不会转换成小写 大小写和写的时候一样
     fn get_ident_with_backtick(&mut self) -> Result<Option<Token>> {
         if self.next_char_expect('`').is_none() {
             return Err(crate::errors::Error::Parse(format!("expact \"`\" !")));
         }
         let mut res = String::new();
-        // 考虑到只有一个 ` 的可能， 如果只有一个` 就会迭代到None
-        let n = self.next_judge(|c| c.is_alphabetic());
-        match n {
-            Some(c) => res.push(c),
-            None => {
-                return Err(Error::Parse("expact alphabetic at first".to_string()));
+        loop {
+            match self.iter.next() {
+                Some('`') if self.next_char_expect('`').is_some() => res.push('`'),
+                Some('`') => break,
+                Some(c) => res.push(c),
+                // 迭代到None就需要返回错误
+                None => return Err(Error::Parse(format!("expact \"`\" !"))),
             }
         }
-        while let Some(c) = self.next_judge(|c| c.is_alphabetic() || **c == '_') {
-            res.push(c);
-        }
-        // 迭代到None就需要返回错误
-        if self.next_char_expect('`').is_none() {
-            return Err(crate::errors::Error::Parse(format!("expact \"`\" !")));
+        if res.is_empty() {
+            return Err(Error::Parse("quoted ident can not be empty".to_string()));
         }
-        Ok(Some(Token::Ident(res.to_lowercase())))
+        Ok(Some(Token::Ident(res)))
     }
 
     /// fn是判断,如果符合就返回字符, 迭代器往下一个
@@ -514,29 +785,63 @@ impl<'a> Laxer<'a> {
         }
     }
 
-    fn term(&mut self) {
-        while self
-            .next_judge(|&&t| match t {
-                ' ' | '\n' | '\t' => true,
-                _ => false,
-            })
-            .is_some()
-        {}
+    /// 跳过空白和注释 -- 注释到行尾 /* */ 注释可以跨行 不支持嵌套
+    fn term(&mut self) -> Result<()> {
+        loop {
+            while self
+                .next_judge(|&&t| match t {
+                    ' ' | '\n' | '\t' | '\r' => true,
+                    _ => false,
+                })
+                .is_some()
+            {}
+            // 需要往后看两个字符才能知道是不是注释
+            let mut ahead = self.iter.clone();
+            match (ahead.next(), ahead.next()) {
+                (Some('-'), Some('-')) => {
+                    while self.next_judge(|&&c| c != '\n').is_some() {}
+                }
+                (Some('/'), Some('*')) => {
+                    self.iter.nth(1);
+                    let mut prev = None;
+                    loop {
+                        match self.iter.next() {
+                            Some('/') if prev == Some('*') => break,
+                            Some(c) => prev = Some(c),
+                            None => {
+                                return Err(Error::Parse(
+                                    "expect get */ in the end of comment".to_string(),
+                                ))
+                            }
+                        }
+                    }
+                }
+                _ => return Ok(()),
+            }
+        }
     }
 }
 
+/// 每个token带着它在输入中的范围
+/// 词法错误带上出错的token开始到出错的地方的位置
 impl<'a> Iterator for Laxer<'a> {
-    type Item = Result<Token>;
+    type Item = Result<(Token, Span)>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        match self.get_next() {
-            Ok(Some(token)) => Some(Ok(token)),
-            Ok(None) => self
-                .iter
-                .peek()
-                .map(|c| Err(Error::Parse(format!("get unexpected char {}", c)))),
-            Err(err) => Some(Err(err)),
-        }
+        let result = match self.get_next() {
+            Ok(Some(token)) => Ok(token),
+            Ok(None) => {
+                let c = *self.iter.peek()?;
+                self.iter.next();
+                Err(Error::Parse(format!("get unexpected char {}", c)))
+            }
+            Err(err) => Err(err),
+        };
+        let span = Span {
+            start: self.start,
+            end: self.iter.position,
+        };
+        Some(result.map(|token| (token, span)).map_err(|e| e.at(span, self.input)))
     }
 }
 
@@ -544,17 +849,128 @@ impl<'a> Iterator for Laxer<'a> {
 mod tests {
     use super::*;
 
+    fn lex(input: &str) -> Result<Vec<Token>> {
+        Laxer::new(input).map(|r| r.map(|(token, _)| token)).collect()
+    }
+
+    #[test]
+    fn keywords_test() {
+        assert!(KEYWORDS.windows(2).all(|w| w[0] < w[1]));
+        for keyword in KEYWORDS {
+            let parsed = Keyword::from_str(keyword).map(|k| k.to_string());
+            assert_eq!(parsed.as_deref(), Some(*keyword));
+        }
+    }
+
     #[test]
     fn token_iter_test() {
         let laxer = Laxer::new("Select * from nmber != 123.123 and who is null babab thi AS");
         let mut r = String::new();
         for token in laxer {
             match token {
-                Ok(token) => r = format!("{} {:?}", r, token),
+                Ok((token, _)) => r = format!("{} {:?}", r, token),
                 Err(e) => eprint!("{}", e),
             }
         }
         println!("r={}", r);
         assert_eq!(r, " Keyword(Select) Asterisk Keyword(From) Ident(\"nmber\") NotEqual Number(\"123.123\") Keyword(And) Ident(\"who\") Keyword(Is) Keyword(Null) Ident(\"babab\") Ident(\"thi\") Keyword(As)".to_string())
     }
+
+    #[test]
+    fn number_test() -> Result<()> {
+        let tokens = lex("1e10 1.5E-3 2e+2 3e")?;
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Number("1e10".to_string()),
+                Token::Number("1.5E-3".to_string()),
+                Token::Number("2e+2".to_string()),
+                // e后面没有数字不是指数
+                Token::Number("3".to_string()),
+                Token::Ident("e".to_string()),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn span_test() -> Result<()> {
+        let spans = Laxer::new("select 名字,\n  `a b`;")
+            .map(|r| r.map(|(_, span)| span))
+            .collect::<Result<Vec<_>>>()?;
+        let span = |line, start, end| Span {
+            start: Position { line, column: start },
+            end: Position { line, column: end },
+        };
+        assert_eq!(
+            spans,
+            vec![span(1, 1, 7), span(1, 8, 10), span(1, 10, 11), span(2, 3, 8), span(2, 8, 9)]
+        );
+        // 词法错误带着出错的位置
+        match Laxer::new("select \"abc").last() {
+            Some(Err(Error::Sql(e))) => {
+                assert_eq!(e.position, Some(Position { line: 1, column: 8 }))
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn comment_test() -> Result<()> {
+        let tokens = lex("-- 导出的脚本\r\nselect /* 列 */ a -- 行尾\n /* 跨\n行 */ from t; /**/ --")?;
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Keyword(Keyword::Select),
+                Token::Ident("a".to_string()),
+                Token::Keyword(Keyword::From),
+                Token::Ident("t".to_string()),
+                Token::Semicolon,
+            ]
+        );
+        // 单个的 - 和 / 还是运算符
+        let tokens = lex("1 - 2 / 3")?;
+        assert_eq!(tokens[1], Token::Minus);
+        assert_eq!(tokens[3], Token::Slash);
+        // 没有结束的块注释
+        assert!(Laxer::new("select 1; /* 没有结束").any(|t| t.is_err()));
+        Ok(())
+    }
+
+    #[test]
+    fn quoted_ident_test() -> Result<()> {
+        let tokens = lex("`Order` `a b` `a``b` `x1` Order")?;
+        assert_eq!(
+            tokens,
+            vec![
+                Token::Ident("Order".to_string()),
+                Token::Ident("a b".to_string()),
+                Token::Ident("a`b".to_string()),
+                Token::Ident("x1".to_string()),
+                Token::Keyword(Keyword::Order),
+            ]
+        );
+        assert!(Laxer::new("``").any(|t| t.is_err()));
+        assert!(Laxer::new("`abc").any(|t| t.is_err()));
+        Ok(())
+    }
+
+    #[test]
+    fn escape_test() -> Result<()> {
+        let tokens = lex(r#""a\"b\\c\n\t\u4e2D\u00e9\%" 用户 _id 名字2"#)?;
+        assert_eq!(
+            tokens,
+            vec![
+                Token::String("a\"b\\c\n\t中é\\%".to_string()),
+                Token::Ident("用户".to_string()),
+                Token::Ident("_id".to_string()),
+                Token::Ident("名字2".to_string()),
+            ]
+        );
+        for sql in [r#""\q""#, r#""\u12""#, r#""\ud800""#, r#""abc\"#] {
+            assert!(Laxer::new(sql).any(|t| t.is_err()), "{}", sql);
+        }
+        Ok(())
+    }
 }
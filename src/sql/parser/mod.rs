@@ -3,44 +3,100 @@ use std::iter::Peekable;
 
 use crate::sql::parser::laxer::{Keyword, Token};
 
-use self::ast::{BaseExpression, FromItem, JoinType, SqlClumn};
+use self::ast::{
+    BaseExpression, ExplainFormat, FromItem, Isolation, JoinType, SelectItems, SqlClumn,
+    SqlConflictAction, SqlOnConflict, SqlReference,
+};
 use self::{ast::Statement, laxer::Laxer};
 use crate::errors::Error;
-use crate::errors::Result;
+use crate::errors::{Result, Span};
 
-use super::{ColumnType, OrderType, Value};
+use super::decimal::{DEFAULT_PRECISION, MAX_PRECISION};
+use super::{ColumnType, NullOrder, OrderType, ReferenceAction, Ttl, Value};
 
 pub mod ast;
 pub mod laxer;
 
+/// 语句的token序列 只是空白或者关键字大小写不同的语句结果相同 计划缓存用作key
+pub fn normalize(input: &str) -> Result<String> {
+    let tokens = Laxer::new(input)
+        .map(|token| Ok(format!("{:?}", token?.0)))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(tokens.join(" "))
+}
+
 pub struct Parser<'a> {
     laxer: Peekable<Laxer<'a>>,
+    input: &'a str,
+    /// 最近查看或者读取的token的范围 语法错误带上这个位置
+    /// 没有token的时候是上一个token结束的地方
+    span: Span,
+    /// $1 $2 这样的参数的值
+    params: Vec<Value>,
 }
 
 impl<'a> Parser<'a> {
     pub fn new(input: &'a str) -> Self {
-        let laxer = Laxer::new(input).peekable();
-        Parser { laxer }
+        Parser {
+            laxer: Laxer::new(input).peekable(),
+            input,
+            span: Span::default(),
+            params: Vec::new(),
+        }
+    }
+
+    /// 语句中的 $n 使用 params 中第n个值 作为常量解析 不会被当作sql
+    pub fn with_params(mut self, params: Vec<Value>) -> Self {
+        self.params = params;
+        self
     }
     pub fn parse(&mut self) -> Result<ast::Statement> {
+        let result = self.parse_one();
+        result.map_err(|e| e.at(self.span, self.input))
+    }
+    /// 解析用分号分隔的多条语句 每条语句都需要以分号结尾
+    pub fn parse_all(&mut self) -> Result<Vec<ast::Statement>> {
+        let result = self.parse_many();
+        result.map_err(|e| e.at(self.span, self.input))
+    }
+    fn parse_one(&mut self) -> Result<ast::Statement> {
         let statement = self.get_statement()?;
         self.next_token_expect(Token::Semicolon)?;
         self.next_token_expect_none()?;
         Ok(statement)
     }
+    fn parse_many(&mut self) -> Result<Vec<ast::Statement>> {
+        let mut statements = vec![self.get_statement()?];
+        self.next_token_expect(Token::Semicolon)?;
+        while self.lookahead().is_some() {
+            statements.push(self.get_statement()?);
+            self.next_token_expect(Token::Semicolon)?;
+        }
+        Ok(statements)
+    }
     pub fn get_statement(&mut self) -> Result<Statement> {
-        match self.laxer.peek() {
+        match self.lookahead() {
             Some(token) => match token {
                 Ok(Token::Keyword(Keyword::Begin))
                 | Ok(Token::Keyword(Keyword::Commit))
-                | Ok(Token::Keyword(Keyword::Rollback)) => self.parse_transaction(),
+                | Ok(Token::Keyword(Keyword::Rollback))
+                | Ok(Token::Keyword(Keyword::Savepoint))
+                | Ok(Token::Keyword(Keyword::Release)) => self.parse_transaction(),
                 Ok(Token::Keyword(Keyword::Create)) => self.parse_create_statement(),
                 Ok(Token::Keyword(Keyword::Drop)) => self.parse_drop_statement(),
+                Ok(Token::Keyword(Keyword::Truncate)) => self.parse_truncate_statement(),
+                Ok(Token::Keyword(Keyword::Check)) => self.parse_check_statement(),
+                Ok(Token::Keyword(Keyword::Analyze)) => self.parse_analyze_statement(),
+                Ok(Token::Keyword(Keyword::Backup)) | Ok(Token::Keyword(Keyword::Restore)) => {
+                    self.parse_backup_statement()
+                }
                 Ok(Token::Keyword(Keyword::Select)) => self.parse_select_statement(),
+                Ok(Token::Keyword(Keyword::Values)) => self.parse_values_statement(),
                 Ok(Token::Keyword(Keyword::Update)) => self.parse_update_statement(),
                 Ok(Token::Keyword(Keyword::Delete)) => self.parse_delete_statement(),
                 Ok(Token::Keyword(Keyword::Insert)) => self.parse_insert_statement(),
                 Ok(Token::Keyword(Keyword::Explain)) => self.parse_explain(),
+                Ok(Token::Keyword(Keyword::Set)) => self.parse_set_variable(),
                 Ok(t) => Err(Error::Parse(format!("get unexpected token: {}", t))),
                 Err(e) => Err(e.clone()),
             },
@@ -54,12 +110,32 @@ impl<'a> Parser<'a> {
             Token::Keyword(Keyword::Begin) => {
                 let mut readonly = false;
                 let mut version = None;
+                let mut isolation = Isolation::Snapshot;
                 self.next_token_expect(Keyword::Transaction.into())?;
-                if self.next_token_expect(Keyword::Read.into()).is_ok() {
-                    match self.next()? {
-                        Token::Keyword(Keyword::Only) => readonly = true,
-                        Token::Keyword(Keyword::Write) => readonly = false,
-                        token => return Err(Error::Parse(format!("unexpected token {}", token))),
+                // READ ONLY | READ WRITE 和 ISOLATION LEVEL ... 的顺序不限制
+                loop {
+                    if self.next_token_expect(Keyword::Read.into()).is_ok() {
+                        match self.next()? {
+                            Token::Keyword(Keyword::Only) => readonly = true,
+                            Token::Keyword(Keyword::Write) => readonly = false,
+                            token => {
+                                return Err(Error::Parse(format!("unexpected token {}", token)))
+                            }
+                        }
+                    } else if self.next_token_expect(Keyword::Isolation.into()).is_ok() {
+                        self.next_token_expect(Keyword::Level.into())?;
+                        isolation = match self.next()? {
+                            Token::Keyword(Keyword::Snapshot) => Isolation::Snapshot,
+                            Token::Keyword(Keyword::Read) => {
+                                self.next_token_expect(Keyword::Committed.into())?;
+                                Isolation::ReadCommitted
+                            }
+                            token => {
+                                return Err(Error::Parse(format!("unexpected token {}", token)))
+                            }
+                        };
+                    } else {
+                        break;
                     }
                 }
                 if self.next_token_expect(Keyword::As.into()).is_ok() {
@@ -68,33 +144,129 @@ impl<'a> Parser<'a> {
                         token => return Err(Error::Parse(format!("unexpected token {}", token))),
                     }
                 }
-                Ok(ast::Statement::Begin { readonly, version })
+                Ok(ast::Statement::Begin {
+                    readonly,
+                    version,
+                    isolation,
+                })
             }
             Token::Keyword(Keyword::Commit) => Ok(ast::Statement::Commit),
-            Token::Keyword(Keyword::Rollback) => Ok(ast::Statement::Rollback),
+            // ROLLBACK TO [SAVEPOINT] name
+            Token::Keyword(Keyword::Rollback) => {
+                if self.next_token_expect(Keyword::To.into()).is_err() {
+                    return Ok(ast::Statement::Rollback);
+                }
+                let _ = self.next_token_expect(Keyword::Savepoint.into());
+                Ok(ast::Statement::RollbackTo(self.next_ident()?))
+            }
+            Token::Keyword(Keyword::Savepoint) => Ok(ast::Statement::Savepoint(self.next_ident()?)),
+            // RELEASE [SAVEPOINT] name
+            Token::Keyword(Keyword::Release) => {
+                let _ = self.next_token_expect(Keyword::Savepoint.into());
+                Ok(ast::Statement::Release(self.next_ident()?))
+            }
             token => Err(Error::Parse(format!("Unexpected token {}", token))),
         }
     }
 
+    /// EXPLAIN [ANALYZE] [( ANALYZE, FORMAT TEXT|JSON )] statement
+    /// FORMAT 和 JSON 不是关键字 按照名字判断
     fn parse_explain(&mut self) -> Result<Statement> {
         self.next_token_expect(Token::Keyword(Keyword::Explain))?;
-        Ok(Statement::Explain(Box::new(self.get_statement()?)))
+        let mut analyze = self.next_token_expect(Keyword::Analyze.into()).is_ok();
+        let mut format = ExplainFormat::Text;
+        if self.next_token_expect(Token::OpenParen).is_ok() {
+            loop {
+                if self.next_token_expect(Keyword::Analyze.into()).is_ok() {
+                    analyze = true;
+                } else {
+                    match self.next_ident()?.as_str() {
+                        "format" => format = self.parse_explain_format()?,
+                        option => {
+                            return Err(Error::Parse(format!("unknown explain option {}", option)))
+                        }
+                    }
+                }
+                if self.next_token_expect(Token::Comma).is_err() {
+                    break;
+                }
+            }
+            self.next_token_expect(Token::CloseParen)?;
+        }
+        Ok(Statement::Explain {
+            statement: Box::new(self.get_statement()?),
+            analyze,
+            format,
+        })
+    }
+
+    fn parse_explain_format(&mut self) -> Result<ExplainFormat> {
+        if self.next_token_expect(Keyword::Text.into()).is_ok() {
+            return Ok(ExplainFormat::Text);
+        }
+        match self.next_ident()?.as_str() {
+            "json" => Ok(ExplainFormat::Json),
+            format => Err(Error::Parse(format!("unknown explain format {}", format))),
+        }
+    }
+
+    /// SET name = value
+    fn parse_set_variable(&mut self) -> Result<Statement> {
+        self.next_token_expect(Token::Keyword(Keyword::Set))?;
+        // 自定义的变量名称可以带有点 例如 app.tenant
+        let mut name = self.next_ident()?;
+        while self.next_token_expect(Token::Period).is_ok() {
+            name = format!("{}.{}", name, self.next_ident()?);
+        }
+        self.next_token_expect(Token::Equal)?;
+        let value = self.parse_expression(0)?;
+        Ok(Statement::Set { name, value })
     }
 
     fn parse_create_statement(&mut self) -> Result<Statement> {
         // CREATE TABLE 表名称 (
         // 列名称1 数据类型,
         // 列名称2 数据类型,
-        // 列名称3 数据类型
-        // )
+        // 列名称3 数据类型,
+        // CHECK (表达式)
+        // ) [TTL 秒数 [ON 列名称]]
 
         self.next_token_expect(Token::Keyword(Keyword::Create))?;
+        if self.next_token_expect(Keyword::Policy.into()).is_ok() {
+            // CREATE POLICY 名称 ON 表名称 USING (表达式)
+            let name = self.next_ident()?;
+            self.next_token_expect(Keyword::On.into())?;
+            let table = self.next_ident()?;
+            self.next_token_expect(Keyword::Using.into())?;
+            let expression = self.parse_check()?;
+            return Ok(Statement::CreatePolicy {
+                name,
+                table,
+                expression,
+            });
+        }
         self.next_token_expect(Token::Keyword(Keyword::Table))?;
         let name = self.next_ident()?;
+        // CREATE TABLE 表名称 AS SELECT ...
+        if self.next_token_expect(Keyword::As.into()).is_ok() {
+            let query = match self.peek()? {
+                Token::Keyword(Keyword::Values) => self.parse_values_statement()?,
+                _ => self.parse_select_statement()?,
+            };
+            return Ok(Statement::CreateTableAs {
+                name,
+                query: Box::new(query),
+            });
+        }
         self.next_token_expect(Token::OpenParen)?;
         let mut columns: Vec<SqlClumn> = vec![];
+        let mut checks = vec![];
         loop {
-            columns.push(self.parse_column()?);
+            if self.next_token_expect(Keyword::Check.into()).is_ok() {
+                checks.push(self.parse_check()?);
+            } else {
+                columns.push(self.parse_column()?);
+            }
             // 下一个不是逗号的时候表示结束
             if self.next_token_expect(Token::Comma).is_err() {
                 break;
@@ -106,7 +278,58 @@ impl<'a> Parser<'a> {
             ));
         }
         self.next_token_expect(Token::CloseParen)?;
-        Ok(Statement::CreateTable { name, columns })
+        let mut ttl = None;
+        if self.next_token_expect(Keyword::Ttl.into()).is_ok() {
+            let seconds = match self.next()? {
+                Token::Number(n) => n.parse::<u64>()?,
+                token => return Err(Error::Parse(format!("unexpected token {}", token))),
+            };
+            let column = match self.next_token_expect(Keyword::On.into()) {
+                Ok(()) => Some(self.next_ident()?),
+                Err(_) => None,
+            };
+            ttl = Some(Ttl { seconds, column });
+        }
+        Ok(Statement::CreateTable {
+            name,
+            columns,
+            checks,
+            ttl,
+        })
+    }
+
+    /// DECIMAL[(precision[, scale])] 没有写的时候是 DECIMAL(10,0)
+    fn parse_decimal_type(&mut self) -> Result<ColumnType> {
+        let mut precision = DEFAULT_PRECISION;
+        let mut scale = 0;
+        if self.next_token_expect(Token::OpenParen).is_ok() {
+            let number = |parser: &mut Self| match parser.next()? {
+                Token::Number(n) => n
+                    .parse::<u8>()
+                    .map_err(|_| Error::Parse(format!("invalid decimal precision {}", n))),
+                token => Err(Error::Parse(format!("unexpected token {}", token))),
+            };
+            precision = number(self)?;
+            if self.next_token_expect(Token::Comma).is_ok() {
+                scale = number(self)?;
+            }
+            self.next_token_expect(Token::CloseParen)?;
+        }
+        if precision == 0 || precision > MAX_PRECISION || scale > precision {
+            return Err(Error::Parse(format!(
+                "invalid DECIMAL({},{}), precision must be 1 to {} and scale at most precision",
+                precision, scale, MAX_PRECISION
+            )));
+        }
+        Ok(ColumnType::Decimal(precision, scale))
+    }
+
+    /// CHECK 后面括号中的表达式
+    fn parse_check(&mut self) -> Result<BaseExpression> {
+        self.next_token_expect(Token::OpenParen)?;
+        let expression = self.parse_expression(0)?;
+        self.next_token_expect(Token::CloseParen)?;
+        Ok(expression)
     }
 
     /*
@@ -125,6 +348,9 @@ impl<'a> Parser<'a> {
             Keyword::Float => ColumnType::Float,
             Keyword::Int => ColumnType::Integer,
             Keyword::Integer => ColumnType::Integer,
+            Keyword::Bigint => ColumnType::Integer,
+            Keyword::Blob | Keyword::Bytea => ColumnType::Bytes,
+            Keyword::Decimal | Keyword::Numeric => self.parse_decimal_type()?,
             Keyword::String => ColumnType::String,
             Keyword::Text => ColumnType::String,
             Keyword::Varchar => ColumnType::String,
@@ -138,6 +364,8 @@ impl<'a> Parser<'a> {
             default: None,
             unique: false,
             index: false,
+            references: None,
+            check: None,
         };
         while let Ok(keyword) = self.next_keyword() {
             match keyword {
@@ -170,20 +398,98 @@ impl<'a> Parser<'a> {
                 }
                 Keyword::Unique => column.unique = true,
                 Keyword::Index => column.index = true,
+                Keyword::References => column.references = Some(self.parse_reference()?),
+                Keyword::Check => column.check = Some(self.parse_check()?),
                 other => return Err(Error::Parse(format!("unexpected keyword: {}", other))),
             }
         }
         Ok(column)
     }
 
+    /// REFERENCES table [(column)] [ON DELETE RESTRICT|CASCADE]
+    fn parse_reference(&mut self) -> Result<SqlReference> {
+        let table = self.next_ident()?;
+        let mut column = None;
+        if self.next_token_expect(Token::OpenParen).is_ok() {
+            column = Some(self.next_ident()?);
+            self.next_token_expect(Token::CloseParen)?;
+        }
+        let mut on_delete = ReferenceAction::Restrict;
+        if self.next_token_expect(Keyword::On.into()).is_ok() {
+            self.next_token_expect(Keyword::Delete.into())?;
+            on_delete = match self.next_keyword()? {
+                Keyword::Restrict => ReferenceAction::Restrict,
+                Keyword::Cascade => ReferenceAction::Cascade,
+                other => return Err(Error::Parse(format!("unexpected keyword: {}", other))),
+            };
+        }
+        Ok(SqlReference {
+            table,
+            column,
+            on_delete,
+        })
+    }
+
     fn parse_drop_statement(&mut self) -> Result<Statement> {
-        //  drop table table_name;
+        //  drop table table_name; drop policy name on table_name;
         self.next_token_expect(Token::Keyword(Keyword::Drop))?;
+        if self.next_token_expect(Keyword::Policy.into()).is_ok() {
+            let name = self.next_ident()?;
+            self.next_token_expect(Keyword::On.into())?;
+            let table = self.next_ident()?;
+            return Ok(Statement::DropPolicy { name, table });
+        }
         self.next_token_expect(Token::Keyword(Keyword::Table))?;
         let table_name = self.next_ident()?;
         Ok(Statement::DropTable(table_name))
     }
 
+    fn parse_truncate_statement(&mut self) -> Result<Statement> {
+        //  truncate [table] table_name;
+        self.next_token_expect(Token::Keyword(Keyword::Truncate))?;
+        self.next_token_expect(Token::Keyword(Keyword::Table)).ok();
+        let table_name = self.next_ident()?;
+        Ok(Statement::Truncate(table_name))
+    }
+
+    fn parse_check_statement(&mut self) -> Result<Statement> {
+        //  check table table_name;
+        self.next_token_expect(Token::Keyword(Keyword::Check))?;
+        self.next_token_expect(Token::Keyword(Keyword::Table))?;
+        let table_name = self.next_ident()?;
+        Ok(Statement::CheckTable(table_name))
+    }
+
+    fn parse_analyze_statement(&mut self) -> Result<Statement> {
+        //  analyze [table] table_name;
+        self.next_token_expect(Token::Keyword(Keyword::Analyze))?;
+        self.next_token_expect(Token::Keyword(Keyword::Table)).ok();
+        let table_name = self.next_ident()?;
+        Ok(Statement::Analyze(table_name))
+    }
+
+    fn parse_backup_statement(&mut self) -> Result<Statement> {
+        //  backup to "path"; restore from "path";
+        let backup = match self.next()? {
+            Token::Keyword(Keyword::Backup) => {
+                self.next_token_expect(Token::Keyword(Keyword::To))?;
+                true
+            }
+            _ => {
+                self.next_token_expect(Token::Keyword(Keyword::From))?;
+                false
+            }
+        };
+        let path = match self.next()? {
+            Token::String(path) => path,
+            t => return Err(Error::Parse(format!("expect backup file path get {}", t))),
+        };
+        Ok(match backup {
+            true => Statement::Backup(path),
+            false => Statement::Restore(path),
+        })
+    }
+
     fn parse_update_statement(&mut self) -> Result<Statement> {
         // UPDATE 表名称 SET 列名称 = 新值 WHERE 列名称 = 某值
         // update table_ set name="xiaoming", age=19+1 where expr
@@ -204,6 +510,7 @@ impl<'a> Parser<'a> {
             table: table_name,
             set: set_expression,
             filter,
+            returning: self.parse_returning()?,
         })
     }
 
@@ -237,6 +544,7 @@ impl<'a> Parser<'a> {
         Ok(Statement::Delete {
             table: table_name,
             filter,
+            returning: self.parse_returning()?,
         })
     }
     fn parse_insert_statement(&mut self) -> Result<Statement> {
@@ -259,6 +567,38 @@ impl<'a> Parser<'a> {
             columns = Some(columnss);
         }
         // values关键字必须要有
+        let values = self.parse_values()?;
+        // ON CONFLICT (column) DO NOTHING | DO UPDATE SET ...
+        let mut on_conflict = None;
+        if self.next_token_expect(Token::Keyword(Keyword::On)).is_ok() {
+            self.next_token_expect(Token::Keyword(Keyword::Conflict))?;
+            self.next_token_expect(Token::OpenParen)?;
+            let column = self.next_ident()?;
+            self.next_token_expect(Token::CloseParen)?;
+            self.next_token_expect(Token::Keyword(Keyword::Do))?;
+            let action = if self
+                .next_token_expect(Token::Keyword(Keyword::Nothing))
+                .is_ok()
+            {
+                SqlConflictAction::Nothing
+            } else {
+                self.next_token_expect(Token::Keyword(Keyword::Update))?;
+                self.next_token_expect(Token::Keyword(Keyword::Set))?;
+                SqlConflictAction::Update(self.parse_set_expression()?)
+            };
+            on_conflict = Some(SqlOnConflict { column, action });
+        }
+        Ok(Statement::Insert {
+            table: table_name,
+            columns,
+            values,
+            on_conflict,
+            returning: self.parse_returning()?,
+        })
+    }
+
+    /// VALUES (值1, 值2,....),(值1,值2....)
+    fn parse_values(&mut self) -> Result<Vec<Vec<BaseExpression>>> {
         self.next_token_expect(Token::Keyword(Keyword::Values))?;
         let mut values = Vec::new();
         loop {
@@ -280,13 +620,38 @@ impl<'a> Parser<'a> {
                 break;
             }
         }
-        Ok(Statement::Insert {
-            table: table_name,
-            columns,
-            values,
+        Ok(values)
+    }
+
+    /// 单独的 VALUES 就是 select * from (VALUES ...) 可以排序和limit
+    fn parse_values_statement(&mut self) -> Result<Statement> {
+        let rows = self.parse_values()?;
+        let order = self.parse_order_claues()?;
+        let (offset, limit) = self.parse_limit_offset()?;
+        Ok(Statement::Select {
+            select: Vec::new(),
+            from: Some(FromItem::Values {
+                rows,
+                alias: None,
+                columns: Vec::new(),
+            }),
+            filter: None,
+            group_by: Vec::new(),
+            having: None,
+            order,
+            offset,
+            limit,
         })
     }
 
+    /// RETURNING 后面和select的列一样解析
+    fn parse_returning(&mut self) -> Result<Option<SelectItems>> {
+        if self.next_token_expect(Keyword::Returning.into()).is_err() {
+            return Ok(None);
+        }
+        Ok(Some(self.parse_select_clause()?))
+    }
+
     fn parse_select_statement(&mut self) -> Result<Statement> {
         // 分为多种解析 解析select列，解析 from 解析 wheer 解析 groupby 解析 having 解析orderby
         // 解析 offset 解析 limit
@@ -346,26 +711,32 @@ impl<'a> Parser<'a> {
 
     fn parse_select_clause(&mut self) -> Result<Vec<(BaseExpression, Option<String>)>> {
         let mut select: Vec<(BaseExpression, Option<String>)> = Vec::new();
-        // 首先判断一下是不是select * ...
-        if self.next_token_expect(Token::Asterisk).is_ok() {
-            return Ok(select);
-        }
         // 解析一个expression 然后看一下有没有别名 逗号分割
         loop {
-            let expression = self.parse_expression(0)?;
+            // * 和 t.* 可以和其他列混在一起 不能有别名
+            let expression = match self.next_token_expect(Token::Asterisk) {
+                Ok(_) => BaseExpression::Wildcard(None),
+                Err(_) => self.parse_expression(0)?,
+            };
             let mut label = None;
-            if self.next_token_expect(Keyword::As.into()).is_ok() {
-                // 别名 如果有as 就必须有别名
-                label = Some(self.next_ident()?);
-            } else if let Ok(label_) = self.next_ident() {
-                // 没有as就看一下 下一个token是不是ident
-                label = Some(label_);
+            if !matches!(expression, BaseExpression::Wildcard(_)) {
+                if self.next_token_expect(Keyword::As.into()).is_ok() {
+                    // 别名 如果有as 就必须有别名
+                    label = Some(self.next_ident()?);
+                } else if let Ok(label_) = self.next_ident() {
+                    // 没有as就看一下 下一个token是不是ident
+                    label = Some(label_);
+                }
             }
             select.push((expression, label));
             if self.next_token_expect(Token::Comma).is_err() {
                 break;
             }
         }
+        // 只有一个 * 就是 select * 还是返回空的列表
+        if select == vec![(BaseExpression::Wildcard(None), None)] {
+            select.clear();
+        }
         Ok(select)
     }
 
@@ -448,15 +819,40 @@ impl<'a> Parser<'a> {
     }
 
     fn parse_table(&mut self) -> Result<FromItem> {
+        // 括号中的 VALUES 是一个临时的表
+        if self.next_token_expect(Token::OpenParen).is_ok() {
+            let rows = self.parse_values()?;
+            self.next_token_expect(Token::CloseParen)?;
+            let alias = self.parse_table_alias()?;
+            let mut columns = Vec::new();
+            if alias.is_some() && self.next_token_expect(Token::OpenParen).is_ok() {
+                loop {
+                    columns.push(self.next_ident()?);
+                    if self.next_token_expect(Token::Comma).is_err() {
+                        break;
+                    }
+                }
+                self.next_token_expect(Token::CloseParen)?;
+            }
+            return Ok(FromItem::Values {
+                rows,
+                alias,
+                columns,
+            });
+        }
         let name = self.next_ident()?;
-        let alias = if self.next_token_expect(Keyword::As.into()).is_ok() {
+        let alias = self.parse_table_alias()?;
+        Ok(FromItem::Table { name, alias })
+    }
+
+    fn parse_table_alias(&mut self) -> Result<Option<String>> {
+        Ok(if self.next_token_expect(Keyword::As.into()).is_ok() {
             Some(self.next_ident()?)
-        } else if let Some(Ok(Token::Ident(_))) = self.laxer.peek() {
+        } else if let Some(Ok(Token::Ident(_))) = self.lookahead() {
             Some(self.next_ident()?)
         } else {
             None
-        };
-        Ok(FromItem::Table { name, alias })
+        })
     }
     /// 解析一个join type 后续没有jointype就返回null
     fn parse_join_type(&mut self) -> Result<Option<JoinType>> {
@@ -478,6 +874,10 @@ impl<'a> Parser<'a> {
             let _ = self.next_token_expect(Keyword::Outer.into());
             self.next_token_expect(Keyword::Join.into())?;
             Ok(Some(ast::JoinType::Right))
+        } else if self.next_token_expect(Keyword::Full.into()).is_ok() {
+            let _ = self.next_token_expect(Keyword::Outer.into());
+            self.next_token_expect(Keyword::Join.into())?;
+            Ok(Some(ast::JoinType::Full))
         } else {
             Ok(None)
         }
@@ -521,8 +921,8 @@ impl<'a> Parser<'a> {
         Ok(Some(self.parse_expression(0)?))
     }
 
-    fn parse_order_claues(&mut self) -> Result<Vec<(BaseExpression, OrderType)>> {
-        // order by xxx DES , xxx ASC, age/10
+    fn parse_order_claues(&mut self) -> Result<Vec<(BaseExpression, OrderType, NullOrder)>> {
+        // order by xxx DESC , xxx ASC NULLS LAST, age/10
         let mut orders = Vec::new();
         // 判断
         if self.next_token_expect(Keyword::Order.into()).is_err() {
@@ -534,15 +934,26 @@ impl<'a> Parser<'a> {
         loop {
             // 获得表达式
             let expression = self.parse_expression(0)?;
-            // 获得ordertype
+            // 获得ordertype 默认是asc
             let order_type = if self.next_token_expect(Keyword::Desc.into()).is_ok() {
                 OrderType::DES
-            } else if self.next_token_expect(Keyword::Asc.into()).is_ok() {
+            } else {
+                // asc 可以省略
+                self.next_token_expect(Keyword::Asc.into()).ok();
                 OrderType::ASC
+            };
+            // nulls first / nulls last
+            let nulls = if self.next_token_expect(Keyword::Nulls.into()).is_ok() {
+                if self.next_token_expect(Keyword::First.into()).is_ok() {
+                    NullOrder::First
+                } else {
+                    self.next_token_expect(Keyword::Last.into())?;
+                    NullOrder::Last
+                }
             } else {
-                OrderType::DES
+                NullOrder::default_for(&order_type)
             };
-            orders.push((expression, order_type));
+            orders.push((expression, order_type, nulls));
             // 直到没有逗号分割表示结束
             if self.next_token_expect(Token::Comma).is_err() {
                 break;
@@ -555,10 +966,21 @@ impl<'a> Parser<'a> {
     fn parse_expression(&mut self, min: u8) -> Result<BaseExpression> {
         // 查看有没有前缀运算符
         let mut expr = if let Some(operation) = PrefixOperation::get_operation(self, min)? {
-            // 看到前缀之后递归比如 -(1+3)
-            operation.build_expresion(
-                self.parse_expression(operation.get_assoc() + operation.get_prec())?,
-            )
+            // 负号后面直接是数字的时候当作负数常量 这样 -9223372036854775808 不会溢出
+            // 前缀运算符的优先级最高 所以和先取负再计算是一样的
+            let num = match (&operation, self.lookahead()) {
+                (PrefixOperation::Negative, Some(Ok(Token::Number(num)))) => Some(num.clone()),
+                _ => None,
+            };
+            if let Some(num) = num {
+                self.next()?;
+                BaseExpression::Value(Self::parse_number(&num, true)?)
+            } else {
+                // 看到前缀之后递归比如 -(1+3)
+                operation.build_expresion(
+                    self.parse_expression(operation.get_assoc() + operation.get_prec())?,
+                )
+            }
         } else {
             self.get_atom_expression()?
         };
@@ -572,26 +994,77 @@ impl<'a> Parser<'a> {
                 expr,
                 self.parse_expression(operation.get_prec() + operation.get_assoc())?,
             );
+            if let BaseExpression::Operation(
+                ast::Operation::Like(_, _, escape) | ast::Operation::ILike(_, _, escape),
+            ) = &mut expr
+            {
+                if let Some(e) = self.parse_escape()? {
+                    *escape = e;
+                }
+            }
         }
 
         Ok(expr)
     }
 
+    /// LIKE 后面的 ESCAPE "x" 只能是一个字符 空字符串表示不转义
+    fn parse_escape(&mut self) -> Result<Option<Option<char>>> {
+        if self.next_token_expect(Keyword::Escape.into()).is_err() {
+            return Ok(None);
+        }
+        match self.next()? {
+            Token::String(s) => {
+                let mut chars = s.chars();
+                match (chars.next(), chars.next()) {
+                    (None, _) => Ok(Some(None)),
+                    (Some(c), None) => Ok(Some(Some(c))),
+                    _ => Err(Error::Parse(format!(
+                        "escape must be a single character get {}",
+                        s
+                    ))),
+                }
+            }
+            token => Err(Error::Parse(format!("expect escape string get {}", token))),
+        }
+    }
+
+    /// 有小数点或者指数的是浮点型 否则是整型 超出范围的时候报错 不会变成浮点型
+    fn parse_number(num: &str, negative: bool) -> Result<Value> {
+        let num = if negative {
+            format!("-{}", num)
+        } else {
+            num.to_string()
+        };
+        if num.contains(['.', 'e', 'E']) {
+            match num.parse::<f64>() {
+                Ok(f) if f.is_finite() => Ok(Value::Float(f)),
+                Ok(_) => Err(Error::Parse(format!("float {} out of range", num))),
+                Err(_) => Err(Error::Parse(format!("expect a number get {}!", num))),
+            }
+        } else {
+            num.parse::<i64>()
+                .map(Value::Integer)
+                .map_err(|_| Error::Parse(format!("integer {} out of range", num)))
+        }
+    }
+
     /// function filed 常量(数字，字符串) 包括被括号包裹起来的可以将整体看作atom
     fn get_atom_expression(&mut self) -> Result<BaseExpression> {
         match self.next()? {
             // 先解析常量
-            Token::Number(num) => {
-                // 判断一下是整型还是浮点性
-                if let Ok(i) = num.parse::<i64>() {
-                    Ok(BaseExpression::Value(Value::Integer(i)))
-                } else if let Ok(f) = num.parse::<f64>() {
-                    Ok(BaseExpression::Value(Value::Float(f)))
-                } else {
-                    Err(Error::Parse(format!("expect a number get {}!", num)))
-                }
-            }
+            Token::Number(num) => Ok(BaseExpression::Value(Self::parse_number(&num, false)?)),
             Token::String(string) => Ok(BaseExpression::Value(Value::String(string))),
+            Token::Parameter(n) => match self.params.get(n - 1) {
+                Some(value) => Ok(BaseExpression::Value(value.clone())),
+                None => Err(Error::Parse(format!("no value for parameter ${}", n))),
+            },
+            Token::Hex(hex) => Ok(BaseExpression::Value(Value::Bytes(
+                (0..hex.len())
+                    .step_by(2)
+                    .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+                    .collect::<std::result::Result<_, _>>()
+                    .map_err(|e| Error::Parse(format!("invalid binary literal {}: {}", hex, e)))?,
+            ))),
             Token::Keyword(Keyword::Null) => Ok(BaseExpression::Value(Value::Null)),
             Token::Keyword(Keyword::True) => Ok(BaseExpression::Value(Value::Bool(true))),
             Token::Keyword(Keyword::False) => Ok(BaseExpression::Value(Value::Bool(false))),
@@ -602,6 +1075,12 @@ impl<'a> Parser<'a> {
 
             Token::Keyword(Keyword::NaN) => Ok(BaseExpression::Value(Value::Float(f64::NAN))),
 
+            // 括号中是select的时候是子查询
+            Token::OpenParen if self.peek()? == Keyword::Select.into() => {
+                let statement = self.parse_select_statement()?;
+                self.next_token_expect(Token::CloseParen)?;
+                Ok(BaseExpression::Subquery(Box::new(statement)))
+            }
             // 碰到括号包围的
             Token::OpenParen => {
                 let expr = self.parse_expression(0)?;
@@ -612,23 +1091,32 @@ impl<'a> Parser<'a> {
             Token::Ident(ident) => {
                 // 看一下下一个是不是括号，如果是括号就是函数
                 if self.next_token_expect(Token::OpenParen).is_ok() {
-                    // 计划中函数只需要单属性就好了
-                    // 可能是count *
-                    let arg = if ident.to_uppercase() == "COUNT"
+                    // 可能是count * 其他函数的参数用逗号分割 也可以没有参数
+                    let mut args = Vec::new();
+                    if ident.to_uppercase() == "COUNT"
                         && self.next_token_expect(Token::Asterisk).is_ok()
                     {
-                        BaseExpression::Value(Value::Bool(true))
-                    } else {
-                        self.parse_expression(0)?
-                    };
+                        args.push(BaseExpression::Value(Value::Bool(true)));
+                    } else if self.peek()? != Token::CloseParen {
+                        loop {
+                            args.push(self.parse_expression(0)?);
+                            if self.next_token_expect(Token::Comma).is_err() {
+                                break;
+                            }
+                        }
+                    }
                     self.next_token_expect(Token::CloseParen)?;
-                    Ok(BaseExpression::Function(ident, Box::new(arg)))
+                    Ok(BaseExpression::Function(ident, args))
                 } else {
                     // 不是函数就是字段
                     let mut table = None;
                     let mut filed = ident;
                     // 有 点 说明是 table.filed
                     if self.next_token_expect(Token::Period).is_ok() {
+                        // table.* 只能在select列表中 规划的时候检查
+                        if self.next_token_expect(Token::Asterisk).is_ok() {
+                            return Ok(BaseExpression::Wildcard(Some(filed)));
+                        }
                         table = Some(filed);
                         filed = self.next_ident()?;
                     }
@@ -639,21 +1127,51 @@ impl<'a> Parser<'a> {
         }
     }
 
+    /// 查看下一个token 记下它的范围 出错的时候指向这个token
+    fn lookahead(&mut self) -> Option<std::result::Result<&Token, &Error>> {
+        match self.laxer.peek() {
+            Some(Ok((token, span))) => {
+                self.span = *span;
+                Some(Ok(token))
+            }
+            Some(Err(e)) => Some(Err(e)),
+            None => {
+                let end = self.span.end;
+                self.span = Span { start: end, end };
+                None
+            }
+        }
+    }
+
+    /// 读取下一个token 之前一定已经 lookahead 过
+    fn advance(&mut self) {
+        self.laxer.next();
+    }
+
     fn next(&mut self) -> Result<Token> {
-        self.laxer
-            .next()
-            .unwrap_or_else(|| Err(Error::Parse("unexpected end".into())))
+        match self.laxer.next() {
+            Some(Ok((token, span))) => {
+                self.span = span;
+                Ok(token)
+            }
+            Some(Err(e)) => Err(e),
+            None => {
+                let end = self.span.end;
+                self.span = Span { start: end, end };
+                Err(Error::Parse("unexpected end".into()))
+            }
+        }
     }
     /// 传入闭包判断，如果返回ok则调用next,并返回token err就返回err
     fn next_token_judge<F>(&mut self, judge: F) -> Result<Token>
     where
         F: Fn(&Token) -> Result<Token>,
     {
-        match self.laxer.peek() {
+        match self.lookahead() {
             Some(t) => match t {
                 Ok(token) => {
                     let r = judge(token)?;
-                    self.laxer.next();
+                    self.advance();
                     Ok(r)
                 }
                 Err(e) => Err(e.clone()),
@@ -662,7 +1180,7 @@ impl<'a> Parser<'a> {
         }
     }
     fn peek(&mut self) -> Result<Token> {
-        match self.laxer.peek() {
+        match self.lookahead() {
             Some(t) => match t {
                 Ok(token) => Ok(token.clone()),
                 Err(e) => Err(e.clone()),
@@ -672,7 +1190,7 @@ impl<'a> Parser<'a> {
     }
 
     fn next_token_expect_none(&mut self) -> Result<()> {
-        if let Some(token) = self.laxer.peek() {
+        if let Some(token) = self.lookahead() {
             match token {
                 Ok(t) => Err(Error::Parse(format!("expect token:None get:{}", t))),
                 Err(e) => Err(e.clone()),
@@ -683,11 +1201,11 @@ impl<'a> Parser<'a> {
     }
     /// 检查下一个token是否与我的匹配，如果不匹配返回不匹配的err,如果匹配无需返回
     fn next_token_expect(&mut self, judge_token: Token) -> Result<()> {
-        if let Some(token) = self.laxer.peek() {
+        if let Some(token) = self.lookahead() {
             match token {
                 Ok(t) => match t {
                     token if token == &judge_token => {
-                        self.laxer.next();
+                        self.advance();
                         Ok(())
                     }
                     _ => Err(Error::Parse(format!(
@@ -714,12 +1232,12 @@ impl<'a> Parser<'a> {
     }
     // 下一个token是keyword 否则报错
     fn next_keyword(&mut self) -> Result<Keyword> {
-        match self.laxer.peek() {
+        match self.lookahead() {
             Some(t) => match t {
                 Ok(token) => match token {
                     Token::Keyword(keyword) => {
                         let k = keyword.clone();
-                        self.laxer.next();
+                        self.advance();
                         Ok(k)
                     }
                     other => Err(Error::Parse(format!("unexpected token {}", other))),
@@ -734,6 +1252,17 @@ impl<'a> Parser<'a> {
         Ok(self
             .next_token_judge(|token| match token {
                 Token::Ident(s) => Ok(Token::Ident(s.to_string())),
+                // 双引号只是字符串 名字要用反引号
+                Token::String(s) => Err(Error::Parse(format!(
+                    "expect a ident get string \"{}\", quote ident with `",
+                    s
+                ))),
+                // 关键字需要用反引号包起来才能作为ident
+                Token::Keyword(k) => Err(Error::Parse(format!(
+                    "expect a ident get keyword {}, quote it as `{}` to use it as a ident",
+                    k,
+                    k.to_string().to_lowercase()
+                ))),
                 other => Err(Error::Parse(format!("expect a ident get {}", other))),
             })?
             .to_string())
@@ -814,16 +1343,20 @@ enum InfixOperator {
     LessThan,
     LessThanOrEqual,
     NotEqual,
+    NullSafeEqual,
 
     // 加减乘除
     Add,
     Subtract,
     Multiply,
     Divide,
+    IntegerDivide,
+    Modulo,
     // 次方
     Exponentiate,
 
     Like,
+    ILike,
 }
 
 impl InfixOperator {
@@ -857,6 +1390,9 @@ impl InfixOperator {
                 Box::new(expr1),
                 Box::new(expr2),
             )),
+            InfixOperator::NullSafeEqual => BaseExpression::Operation(
+                ast::Operation::NullSafeEqual(Box::new(expr1), Box::new(expr2)),
+            ),
             InfixOperator::Add => {
                 BaseExpression::Operation(ast::Operation::Add(Box::new(expr1), Box::new(expr2)))
             }
@@ -871,13 +1407,27 @@ impl InfixOperator {
             InfixOperator::Divide => {
                 BaseExpression::Operation(ast::Operation::Divide(Box::new(expr1), Box::new(expr2)))
             }
+            InfixOperator::IntegerDivide => BaseExpression::Operation(
+                ast::Operation::IntegerDivide(Box::new(expr1), Box::new(expr2)),
+            ),
+            InfixOperator::Modulo => {
+                BaseExpression::Operation(ast::Operation::Modulo(Box::new(expr1), Box::new(expr2)))
+            }
             InfixOperator::Exponentiate => BaseExpression::Operation(ast::Operation::Exponentiate(
                 Box::new(expr1),
                 Box::new(expr2),
             )),
-            InfixOperator::Like => {
-                BaseExpression::Operation(ast::Operation::Like(Box::new(expr1), Box::new(expr2)))
-            }
+            // 默认使用反斜杠转义 后面跟着 ESCAPE 的时候再修改
+            InfixOperator::Like => BaseExpression::Operation(ast::Operation::Like(
+                Box::new(expr1),
+                Box::new(expr2),
+                Some('\\'),
+            )),
+            InfixOperator::ILike => BaseExpression::Operation(ast::Operation::ILike(
+                Box::new(expr1),
+                Box::new(expr2),
+                Some('\\'),
+            )),
         }
     }
 }
@@ -889,6 +1439,7 @@ impl Operation for InfixOperator {
             Token::Keyword(Keyword::Or) => Some(Self::Or),
 
             Token::Keyword(Keyword::Like) => Some(Self::Like),
+            Token::Keyword(Keyword::ILike) => Some(Self::ILike),
 
             Token::GreaterThan => Some(Self::GreaterThan),
             Token::GreaterThanOrEqual => Some(Self::GreaterThanOrEqual),
@@ -896,11 +1447,14 @@ impl Operation for InfixOperator {
             Token::LessOrGreaterThan => Some(Self::NotEqual),
             Token::LessThanOrEqual => Some(Self::LessThanOrEqual),
             Token::NotEqual => Some(Self::NotEqual),
+            Token::NullSafeEqual => Some(Self::NullSafeEqual),
 
             Token::Plus => Some(Self::Add),
             Token::Minus => Some(Self::Subtract),
             Token::Asterisk => Some(Self::Multiply),
             Token::Slash => Some(Self::Divide),
+            Token::Keyword(Keyword::Div) => Some(Self::IntegerDivide),
+            Token::Percent => Some(Self::Modulo),
             Token::Caret => Some(Self::Exponentiate),
             Token::Equal => Some(Self::Equal),
             _ => None,
@@ -922,13 +1476,20 @@ impl Operation for InfixOperator {
         match self {
             InfixOperator::And => 2,
             InfixOperator::Or => 1,
-            InfixOperator::Equal | InfixOperator::NotEqual | InfixOperator::Like => 3,
+            InfixOperator::Equal
+            | InfixOperator::NotEqual
+            | InfixOperator::NullSafeEqual
+            | InfixOperator::Like
+            | InfixOperator::ILike => 3,
             InfixOperator::GreaterThan
             | InfixOperator::GreaterThanOrEqual
             | InfixOperator::LessThan
             | InfixOperator::LessThanOrEqual => 4,
             InfixOperator::Add | InfixOperator::Subtract => 5,
-            InfixOperator::Multiply | InfixOperator::Divide => 6,
+            InfixOperator::Multiply
+            | InfixOperator::Divide
+            | InfixOperator::IntegerDivide
+            | InfixOperator::Modulo => 6,
             InfixOperator::Exponentiate => 7,
         }
     }
@@ -945,6 +1506,8 @@ impl Operation for InfixOperator {
 enum PostfixOperator {
     IsNull,
     IsNotNull,
+    /// IS [NOT] DISTINCT FROM 右边的表达式在读到运算符的时候一起解析 true表示有NOT
+    DistinctFrom(bool, BaseExpression),
 }
 
 impl PostfixOperator {
@@ -956,6 +1519,16 @@ impl PostfixOperator {
             PostfixOperator::IsNotNull => BaseExpression::Operation(ast::Operation::Not(Box::new(
                 BaseExpression::Operation(ast::Operation::IsNull(Box::new(expr))),
             ))),
+            PostfixOperator::DistinctFrom(not, rhs) => {
+                let equal = BaseExpression::Operation(ast::Operation::NullSafeEqual(
+                    Box::new(expr),
+                    Box::new(rhs.clone()),
+                ));
+                match not {
+                    true => equal,
+                    false => BaseExpression::Operation(ast::Operation::Not(Box::new(equal))),
+                }
+            }
         }
     }
 }
@@ -969,7 +1542,14 @@ impl Operation for PostfixOperator {
             .next_token_expect(Token::Keyword(Keyword::Is))
             .is_ok()
         {
-            let r = if parser.next_token_expect(Keyword::Not.into()).is_ok() {
+            let not = parser.next_token_expect(Keyword::Not.into()).is_ok();
+            if parser.next_token_expect(Keyword::Distinct.into()).is_ok() {
+                parser.next_token_expect(Keyword::From.into())?;
+                // 和 = 的优先级一样 右边是比较运算符优先级以上的表达式
+                let rhs = parser.parse_expression(InfixOperator::Equal.get_prec() + 1)?;
+                return Ok(Some(PostfixOperator::DistinctFrom(not, rhs)));
+            }
+            let r = if not {
                 PostfixOperator::IsNotNull
             } else {
                 PostfixOperator::IsNull
@@ -1004,6 +1584,107 @@ mod tests {
         println!("statement {:?}", statement);
     }
     #[test]
+    fn references_test() -> Result<()> {
+        let mut parser = Parser::new(
+            "create table orders ( id int primary key, customer int references customers(id) on delete cascade );",
+        );
+        match parser.parse()? {
+            Statement::CreateTable { columns, .. } => assert_eq!(
+                columns[1].references,
+                Some(SqlReference {
+                    table: "customers".to_string(),
+                    column: Some("id".to_string()),
+                    on_delete: ReferenceAction::Cascade,
+                })
+            ),
+            statement => panic!("unexpected statement {:?}", statement),
+        }
+        Ok(())
+    }
+    #[test]
+    fn begin_isolation_test() -> Result<()> {
+        for (input, expect) in [
+            ("begin transaction;", Isolation::Snapshot),
+            ("begin transaction isolation level read committed;", Isolation::ReadCommitted),
+            ("begin transaction read write isolation level snapshot;", Isolation::Snapshot),
+        ] {
+            match Parser::new(input).parse()? {
+                Statement::Begin { isolation, .. } => assert_eq!(isolation, expect),
+                statement => panic!("unexpected statement {:?}", statement),
+            }
+        }
+        assert!(Parser::new("begin transaction isolation level read;").parse().is_err());
+        Ok(())
+    }
+    #[test]
+    fn parse_all_test() -> Result<()> {
+        let statements = Parser::new("begin transaction; select 1; \n commit;").parse_all()?;
+        assert_eq!(statements.len(), 3);
+        assert!(matches!(statements[2], Statement::Commit));
+        // 每条语句都需要以分号结尾
+        assert!(Parser::new("select 1; select 2").parse_all().is_err());
+        assert!(Parser::new("").parse_all().is_err());
+        // parse 只接受一条语句
+        assert!(Parser::new("select 1; select 2;").parse().is_err());
+        Ok(())
+    }
+    #[test]
+    fn params_test() -> Result<()> {
+        let params = vec![Value::String("a\"; drop table t;".into()), Value::Integer(2)];
+        let statement = Parser::new("select $2, $1;").with_params(params.clone()).parse()?;
+        match statement {
+            Statement::Select { select, .. } => {
+                let values = select.into_iter().map(|(expr, _)| expr).collect::<Vec<_>>();
+                let expect = [&params[1], &params[0]].map(|v| BaseExpression::Value(v.clone()));
+                assert_eq!(values, expect);
+            }
+            statement => panic!("unexpected statement {:?}", statement),
+        }
+        assert!(Parser::new("select $3;").with_params(params).parse().is_err());
+        assert!(Parser::new("select $0;").parse().is_err());
+        assert!(Parser::new("select $;").parse().is_err());
+        Ok(())
+    }
+    #[test]
+    fn number_test() -> Result<()> {
+        let values = match Parser::new(
+            "select 12, 1.5, 1e10, 1.5E-3, 2e+2, -9223372036854775808, 9223372036854775807, -2.5;",
+        )
+        .parse()?
+        {
+            Statement::Select { select, .. } => select
+                .into_iter()
+                .map(|(expr, _)| match expr {
+                    BaseExpression::Value(value) => value,
+                    expr => panic!("unexpected expression {:?}", expr),
+                })
+                .collect::<Vec<_>>(),
+            statement => panic!("unexpected statement {:?}", statement),
+        };
+        assert_eq!(
+            values,
+            vec![
+                Value::Integer(12),
+                Value::Float(1.5),
+                Value::Float(1e10),
+                Value::Float(1.5e-3),
+                Value::Float(200.0),
+                Value::Integer(i64::MIN),
+                Value::Integer(i64::MAX),
+                Value::Float(-2.5),
+            ]
+        );
+        // 超出范围报错 不会变成浮点型
+        for sql in [
+            "select 9223372036854775808;",
+            "select -9223372036854775809;",
+            "select 1e400;",
+        ] {
+            assert!(Parser::new(sql).parse().is_err(), "{}", sql);
+        }
+        Ok(())
+    }
+    #[test]
     fn select_test() {
         let mut parser = Parser::new(
             "SELECT customers.customer_id, customers.customer_name, COUNT(orders.order_id) AS num_of_orders, SUM(orders.order_total) AS total_spent 
@@ -1143,4 +1824,49 @@ mod tests {
             Err(_) => {}
         };
     }
+
+    #[test]
+    fn error_position_test() {
+        use crate::errors::{ErrorCode, Position};
+        let error = |sql: &str| match Parser::new(sql).parse_all() {
+            Err(Error::Sql(e)) => {
+                assert_eq!(e.code, ErrorCode::Syntax, "{}", sql);
+                e.position.unwrap()
+            }
+            r => panic!("unexpected result {:?}", r),
+        };
+        let at = |line, column| Position { line, column };
+        assert_eq!(error("select * form t;"), at(1, 10));
+        assert_eq!(error("select 1;\nselect 1,\n  2 +;"), at(3, 6));
+        // 词法错误的位置是出错的token开始的地方
+        assert_eq!(error("select \"abc;"), at(1, 8));
+        assert_eq!(error("select 1 ~ 2;"), at(1, 10));
+        // 缺少分号的时候是最后一个token结束的地方
+        assert_eq!(error("select 1"), at(1, 9));
+        assert_eq!(error("select 1;\nselect\n"), at(2, 7));
+
+        let e = Parser::new("select * form t;").parse().unwrap_err();
+        assert_eq!(e.code(), "42601");
+        assert!(e.to_string().ends_with("at line 1, column 10"), "{}", e);
+    }
+
+    #[test]
+    fn error_snippet_test() {
+        let snippet = |sql: &str| {
+            let error = Parser::new(sql).parse_all().unwrap_err();
+            error.snippet().unwrap().to_string()
+        };
+        assert_eq!(snippet("select * form t;"), "  |\n1 | select * form t;\n  |          ^^^^");
+        // 多行语句只输出出错的那一行
+        assert_eq!(
+            snippet("select 1;\nselect 1,\n\t2 +;"),
+            "  |\n3 | \t2 +;\n  | \t   ^"
+        );
+        assert_eq!(snippet("select \"abc\nd;"), "  |\n1 | select \"abc\n  |        ^^^^");
+        assert_eq!(snippet("select 1 ~ 2;"), "  |\n1 | select 1 ~ 2;\n  |          ^");
+        assert_eq!(snippet("select 1"), "  |\n1 | select 1\n  |         ^");
+
+        // 语义错误没有片段
+        assert_eq!(Error::Plan("x".into()).snippet(), None);
+    }
 }
@@ -0,0 +1,331 @@
+//! explain 使用的行数估计 表的行数直接数出来 过滤条件使用固定的选择率
+//! 索引的范围扫描在 ANALYZE 之后使用直方图估计
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::ops::Bound;
+
+use serde_derive::{Deserialize, Serialize};
+
+use super::{IndexAccess, Node, Outer};
+use crate::errors::*;
+use crate::sql::engine::Transaction;
+use crate::sql::expression::Expression;
+use crate::sql::Value;
+
+/// 等值条件的选择率
+const EQUAL_SELECTIVITY: f64 = 0.1;
+/// 范围条件每一边的选择率
+const RANGE_SELECTIVITY: f64 = 1.0 / 3.0;
+/// 不知道怎么估计的条件
+const DEFAULT_SELECTIVITY: f64 = 0.5;
+/// 有 group by 的时候 每组平均的行数
+const GROUP_SIZE: f64 = 10.0;
+
+/// 优化器对节点做的选择
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Access {
+    /// 没有可用的索引 扫描全表
+    FullScan,
+    /// 通过主键读取
+    PrimaryKey,
+    /// 通过索引读取
+    Index(String),
+    /// 只读索引 不读取行
+    IndexOnly(String),
+    /// 索引读取之后剩下的条件
+    PostFilter,
+}
+
+impl Display for Access {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Access::FullScan => write!(f, "full scan"),
+            Access::PrimaryKey => write!(f, "primary key"),
+            Access::Index(column) => write!(f, "index {}", column),
+            Access::IndexOnly(column) => write!(f, "index only {}", column),
+            Access::PostFilter => write!(f, "post-filter"),
+        }
+    }
+}
+
+/// 每个节点的估计 按照执行树前序遍历的顺序存放
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct NodeEstimate {
+    /// 估计输出的行数
+    pub rows: u64,
+    pub access: Option<Access>,
+}
+
+impl Display for NodeEstimate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[est rows={}", self.rows)?;
+        if let Some(access) = &self.access {
+            write!(f, ", {}", access)?;
+        }
+        write!(f, "]")
+    }
+}
+
+/// 估计整个执行树
+pub fn estimate<T: Transaction>(node: &Node, txn: &T) -> Result<Vec<NodeEstimate>> {
+    let mut estimator = Estimator {
+        txn,
+        tables: HashMap::new(),
+        estimates: Vec::new(),
+    };
+    estimator.estimate(node)?;
+    Ok(estimator.estimates)
+}
+
+struct Estimator<'a, T: Transaction> {
+    txn: &'a T,
+    /// 表的行数 同一个表只数一次
+    tables: HashMap<String, f64>,
+    estimates: Vec<NodeEstimate>,
+}
+
+impl<'a, T: Transaction> Estimator<'a, T> {
+    fn table_rows(&mut self, table: &str) -> Result<f64> {
+        if let Some(rows) = self.tables.get(table) {
+            return Ok(*rows);
+        }
+        // 不需要解码任何列
+        let rows = self.txn.scan(table, None, Some(Vec::new()))?.len() as f64;
+        self.tables.insert(table.to_string(), rows);
+        Ok(rows)
+    }
+
+    /// 前序遍历 先占一个位置 子节点估计完之后再填上
+    fn estimate(&mut self, node: &Node) -> Result<f64> {
+        let id = self.estimates.len();
+        self.estimates.push(NodeEstimate {
+            rows: 0,
+            access: None,
+        });
+        let mut access = None;
+        let rows = match node {
+            Node::CreateTable { .. }
+            | Node::DropTable { .. }
+            | Node::CreatePolicy { .. }
+            | Node::DropPolicy { .. }
+            | Node::Backup { .. }
+            | Node::Restore { .. } => 0.0,
+            Node::Truncate { table } | Node::CheckTable { table } | Node::Analyze { table } => {
+                self.table_rows(table)?
+            }
+            Node::Insert { expressions, .. } => expressions.len() as f64,
+            Node::Update { source, .. }
+            | Node::Delete { source, .. }
+            | Node::CreateTableAs { source, .. }
+            | Node::Projection { source, .. }
+            | Node::Order { source, .. } => self.estimate(source)?,
+            Node::Scan { table, filter, .. } => {
+                access = Some(Access::FullScan);
+                self.table_rows(table)? * filter.as_ref().map_or(1.0, selectivity)
+            }
+            Node::Filter { source, predicate } => {
+                if matches!(
+                    **source,
+                    Node::KeyLookup { .. }
+                        | Node::IndexLookup { .. }
+                        | Node::IndexRangeScan { .. }
+                        | Node::IndexOnlyScan { .. }
+                ) {
+                    access = Some(Access::PostFilter);
+                }
+                self.estimate(source)? * selectivity(predicate)
+            }
+            Node::Having { source, predicate } => {
+                self.estimate(source)? * selectivity(predicate)
+            }
+            Node::Aggregation { source, aggregates } => {
+                let rows = self.estimate(source)?;
+                // group by 的字段在聚合参数后面
+                let grouped = matches!(
+                    &**source,
+                    Node::Projection { expressions, .. } if expressions.len() > aggregates.len()
+                );
+                if grouped {
+                    // 有行的时候至少有一组
+                    (rows / GROUP_SIZE).max(rows.min(1.0))
+                } else {
+                    1.0
+                }
+            }
+            Node::Limit {
+                source,
+                offset,
+                limit,
+            } => {
+                let count = |e: &Option<Expression>| match e {
+                    Some(Expression::Constant(Value::Integer(n))) => Some(*n.max(&0) as f64),
+                    _ => None,
+                };
+                let rows = (self.estimate(source)? - count(offset).unwrap_or(0.0)).max(0.0);
+                count(limit).map_or(rows, |limit| rows.min(limit))
+            }
+            Node::NestedLoopJoin {
+                left,
+                right,
+                predicate,
+                outer,
+                ..
+            } => {
+                let left = self.estimate(left)?;
+                let right = self.estimate(right)?;
+                let rows = left * right * predicate.as_ref().map_or(1.0, selectivity);
+                match outer {
+                    Some(Outer::Left) => rows.max(left),
+                    Some(Outer::Full) => rows.max(left).max(right),
+                    None => rows,
+                }
+            }
+            // 等值连接 当作一边的每一行最多连接另一边的一行
+            Node::HashJoin {
+                left,
+                right,
+                predicate,
+                outer,
+                ..
+            } => {
+                let left = self.estimate(left)?;
+                let right = self.estimate(right)?;
+                let rows = left.min(right) * predicate.as_ref().map_or(1.0, selectivity);
+                match outer {
+                    Some(Outer::Left) => rows.max(left),
+                    Some(Outer::Full) => rows.max(left).max(right),
+                    None => rows,
+                }
+            }
+            Node::KeyLookup { keys, .. } => {
+                access = Some(Access::PrimaryKey);
+                keys.len() as f64
+            }
+            // 索引中记录了对应的主键 直接数出来
+            Node::IndexLookup {
+                table,
+                column,
+                values,
+                ..
+            }
+            | Node::IndexOnlyScan {
+                table,
+                column,
+                access: IndexAccess::Lookup(values),
+                ..
+            } => {
+                access = Some(match node {
+                    Node::IndexOnlyScan { .. } => Access::IndexOnly(column.clone()),
+                    _ => Access::Index(column.clone()),
+                });
+                let mut rows = 0;
+                for value in values {
+                    rows += self.txn.read_index(table, column, value)?.len();
+                }
+                rows as f64
+            }
+            Node::IndexRangeScan {
+                table,
+                column,
+                range,
+                ..
+            }
+            | Node::IndexOnlyScan {
+                table,
+                column,
+                access: IndexAccess::Range(range),
+                ..
+            } => {
+                access = Some(match node {
+                    Node::IndexOnlyScan { .. } => Access::IndexOnly(column.clone()),
+                    _ => Access::Index(column.clone()),
+                });
+                let stats = self.txn.must_read_table(table)?.statistics;
+                let selectivity = match stats.as_ref().and_then(|s| s.columns.get(column)) {
+                    Some(stats) => stats.range(range),
+                    None => {
+                        let bounded = [&range.0, &range.1]
+                            .iter()
+                            .filter(|b| !matches!(b, Bound::Unbounded))
+                            .count();
+                        RANGE_SELECTIVITY.powi(bounded as i32)
+                    }
+                };
+                self.table_rows(table)? * selectivity
+            }
+            Node::Values { rows, .. } => rows.len() as f64,
+            Node::Nothing => 1.0,
+        };
+        self.estimates[id] = NodeEstimate {
+            rows: rows.ceil() as u64,
+            access,
+        };
+        Ok(rows)
+    }
+}
+
+/// 满足条件的行所占的比例
+fn selectivity(expr: &Expression) -> f64 {
+    match expr {
+        Expression::Constant(Value::Bool(true)) => 1.0,
+        Expression::Constant(_) => 0.0,
+        Expression::And(lhs, rhs) => selectivity(lhs) * selectivity(rhs),
+        Expression::Or(lhs, rhs) => {
+            let (l, r) = (selectivity(lhs), selectivity(rhs));
+            l + r - l * r
+        }
+        Expression::Not(expr) => 1.0 - selectivity(expr),
+        Expression::Equal(..) | Expression::NullSafeEqual(..) | Expression::IsNull(_) => {
+            EQUAL_SELECTIVITY
+        }
+        Expression::GreaterThan(..) | Expression::LessThan(..) => RANGE_SELECTIVITY,
+        _ => DEFAULT_SELECTIVITY,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::Result;
+    use crate::sql::engine::kv::test_engine;
+    use crate::sql::engine::Engine;
+    use crate::sql::execution::ResultSet;
+
+    #[test]
+    fn explain_estimate_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int index, x int );")?;
+        let values = (0..30)
+            .map(|i| format!("({}, {}, {})", i, i / 10, i))
+            .collect::<Vec<_>>();
+        session.execute(&format!("insert into t values {};", values.join(", ")))?;
+
+        for (sql, expect) in [
+            (
+                "select id from t where x = 1;",
+                "Projection: id [est rows=3]\n└─ Scan: t (x = 1) columns #0,#2 [est rows=3, full scan]",
+            ),
+            (
+                "select id from t where id = 1 or id = 2;",
+                "Projection: id [est rows=2]\n└─ KeyLookup: t (1, 2) [est rows=2, primary key]",
+            ),
+            // 索引中的主键直接数出来 剩下的条件是索引读取之后的过滤
+            (
+                "select id from t where n = 1 and x > 15;",
+                "Projection: id [est rows=4]\n└─ Filter: x > 15 [est rows=4, post-filter]\n   └─ IndexLookup: t column n (1) [est rows=10, index n]",
+            ),
+            (
+                "select n, count(id) from t group by n limit 2;",
+                "Limit: 2 [est rows=2]\n└─ Projection: #1, #0 [est rows=3]\n   └─ Aggregation: Count [est rows=3]\n      └─ Projection: id, n [est rows=30]\n         └─ Scan: t columns #0,#1 [est rows=30, full scan]",
+            ),
+        ] {
+            match session.execute(&format!("explain {}", sql))? {
+                ResultSet::Explain { plan, estimates } => {
+                    assert_eq!(plan.format_explain(&estimates), expect, "{}", sql)
+                }
+                r => panic!("unexpected result {:?}", r),
+            }
+        }
+        Ok(())
+    }
+}
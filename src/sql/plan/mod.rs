@@ -1,58 +1,107 @@
+pub mod estimate;
 pub mod optimizer;
 pub mod planner;
 
 use core::fmt;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashSet};
 use std::fmt::Display;
+use std::ops::Bound;
 
 use serde_derive::{Deserialize, Serialize};
 
 use super::{
-    engine::Transaction,
-    execution::{Executor, ResultSet},
+    engine::{IndexRange, Transaction},
+    execution::{analyze::NodeStats, Batches, Columns, Executor, ResultSet},
     expression::Expression,
     schema::Catalog,
-    OrderType, Table, Value,
+    NullOrder, OrderType, Policy, Table, Value,
 };
 use crate::{
     errors::{Error, Result},
-    sql::plan::{optimizer::Optimizer, planner::Planner},
+    sql::plan::{estimate::NodeEstimate, optimizer::Optimizer, planner::Planner},
 };
 
+/// RETURNING 的表达式和别名 引用的是表的列
+pub type Returning = Vec<(Expression, Option<String>)>;
+
 /// 执行节点
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Node {
     CreateTable {
         table: Table,
-        defaults: Vec<Option<Expression>>,
+    },
+    /// 执行source 用结果的列创建表 再写入所有的行
+    CreateTableAs {
+        table: String,
+        source: Box<Node>,
     },
     DropTable {
         table: String,
     },
+    /// 给表加上行级安全策略
+    CreatePolicy {
+        table: String,
+        policy: Policy,
+    },
+    DropPolicy {
+        table: String,
+        name: String,
+    },
+    Truncate {
+        table: String,
+    },
+    /// 检查表中的行和索引
+    CheckTable {
+        table: String,
+    },
+    /// 收集表的统计信息
+    Analyze {
+        table: String,
+    },
+    Backup {
+        path: String,
+    },
+    Restore {
+        path: String,
+    },
     Insert {
         table: String,
         columns: Vec<String>,
         expressions: Vec<Vec<Expression>>,
+        /// 主键冲突时的处理 None表示报错
+        on_conflict: Option<OnConflict>,
+        /// RETURNING 的表达式 None表示只返回修改的行数
+        returning: Option<Returning>,
+        /// 写入的行需要满足的行级安全策略 None表示不检查
+        policy: Option<Expression>,
     },
     Update {
         table: String,
         source: Box<Node>,
         set: Vec<(usize, Expression)>,
+        returning: Option<Returning>,
+        /// 修改之后的行需要满足的行级安全策略 None表示不检查
+        policy: Option<Expression>,
     },
     Delete {
         table: String,
         source: Box<Node>,
+        returning: Option<Returning>,
     },
     Scan {
         table: String,
         alias: Option<String>,
         filter: Option<Expression>,
+        /// 需要读取的列 其他列不解码 读出来是null None表示全部的列
+        columns: Option<Vec<usize>>,
     },
     NestedLoopJoin {
         left: Box<Node>,
         right: Box<Node>,
         left_size: usize,
         predicate: Option<Expression>,
-        outer: bool,
+        outer: Option<Outer>,
     },
     Filter {
         source: Box<Node>,
@@ -70,24 +119,33 @@ pub enum Node {
         source: Box<Node>,
         aggregates: Vec<Aggregate>,
     },
-    Order {
+    /// 聚合之后的过滤 predicate作用于聚合的输出
+    /// 聚合结果在前 group by的字段在后
+    Having {
         source: Box<Node>,
-        orders: Vec<(Expression, OrderType)>,
+        predicate: Expression,
     },
-    Limit {
+    Order {
         source: Box<Node>,
-        limit: Expression,
+        orders: Vec<(Expression, OrderType, NullOrder)>,
     },
-    Offset {
+    /// 先跳过offset行 再最多返回limit行
+    Limit {
         source: Box<Node>,
-        offset: Expression,
+        offset: Option<Expression>,
+        limit: Option<Expression>,
     },
+    /// 左右两边的字段一一对应 全部相等才连接
     HashJoin {
         left: Box<Node>,
-        left_field: (usize, Option<(Option<String>, String)>),
+        left_fields: Vec<JoinField>,
         right: Box<Node>,
-        right_field: (usize, Option<(Option<String>, String)>),
-        outer: bool,
+        right_fields: Vec<JoinField>,
+        /// 每一对字段是不是 <=> 连接的 是的话两边都是null也能匹配
+        null_safe: Vec<bool>,
+        /// 哈希匹配之后还要满足的其他条件 在连接之后的行上计算
+        predicate: Option<Expression>,
+        outer: Option<Outer>,
     },
     IndexLookup {
         table: String,
@@ -100,9 +158,63 @@ pub enum Node {
         alias: Option<String>,
         keys: Vec<Value>,
     },
+    /// 索引范围扫描 结果按照索引值排序
+    IndexRangeScan {
+        table: String,
+        alias: Option<String>,
+        column: String,
+        range: IndexRange,
+    },
+    /// 只读索引不读行 上层只用到索引列和主键的时候代替 IndexLookup 和 IndexRangeScan
+    /// 行的宽度和表一样 其他列是null
+    IndexOnlyScan {
+        table: String,
+        alias: Option<String>,
+        column: String,
+        access: IndexAccess,
+    },
+    /// VALUES 中的常量行
+    Values {
+        columns: Vec<String>,
+        rows: Vec<Vec<Expression>>,
+    },
     Nothing,
 }
 impl Node {
+    /// 节点的类型 统计每种执行节点的耗时使用
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::CreateTable { .. } => "CreateTable",
+            Self::CreateTableAs { .. } => "CreateTableAs",
+            Self::DropTable { .. } => "DropTable",
+            Self::CreatePolicy { .. } => "CreatePolicy",
+            Self::DropPolicy { .. } => "DropPolicy",
+            Self::Truncate { .. } => "Truncate",
+            Self::CheckTable { .. } => "CheckTable",
+            Self::Analyze { .. } => "Analyze",
+            Self::Backup { .. } => "Backup",
+            Self::Restore { .. } => "Restore",
+            Self::Insert { .. } => "Insert",
+            Self::Update { .. } => "Update",
+            Self::Delete { .. } => "Delete",
+            Self::Scan { .. } => "Scan",
+            Self::NestedLoopJoin { .. } => "NestedLoopJoin",
+            Self::Filter { .. } => "Filter",
+            Self::Projection { .. } => "Projection",
+            Self::Aggregation { .. } => "Aggregation",
+            Self::Having { .. } => "Having",
+            Self::Order { .. } => "Order",
+            Self::Limit { .. } => "Limit",
+            Self::HashJoin { .. } => "HashJoin",
+            Self::IndexLookup { .. } => "IndexLookup",
+            Self::KeyLookup { .. } => "KeyLookup",
+            Self::IndexRangeScan { .. } => "IndexRangeScan",
+            Self::IndexOnlyScan { .. } => "IndexOnlyScan",
+            Self::Values { .. } => "Values",
+            Self::Nothing => "Nothing",
+        }
+    }
+
     /// 将node转化为另一个node
     pub fn transform<B, A>(mut self, before: &B, after: &A) -> Result<Self>
     where
@@ -111,12 +223,29 @@ impl Node {
     {
         self = before(self)?;
         self = match self {
-            Self::Update { table, source, set } => Self::Update {
+            Self::Update {
+                table,
+                source,
+                set,
+                returning,
+                policy,
+            } => Self::Update {
                 table,
                 source: source.transform(before, after)?.into(),
                 set,
+                returning,
+                policy,
             },
-            Self::Delete { table, source } => Self::Delete {
+            Self::Delete {
+                table,
+                source,
+                returning,
+            } => Self::Delete {
+                table,
+                source: source.transform(before, after)?.into(),
+                returning,
+            },
+            Self::CreateTableAs { table, source } => Self::CreateTableAs {
                 table,
                 source: source.transform(before, after)?.into(),
             },
@@ -142,26 +271,35 @@ impl Node {
                 source: source.transform(before, after)?.into(),
                 aggregates,
             },
+            Self::Having { source, predicate } => Self::Having {
+                source: source.transform(before, after)?.into(),
+                predicate,
+            },
             Self::HashJoin {
                 left,
-                left_field,
+                left_fields,
                 right,
-                right_field,
+                right_fields,
+                null_safe,
+                predicate,
                 outer,
             } => Self::HashJoin {
                 left: left.transform(before, after)?.into(),
-                left_field,
+                left_fields,
                 right: right.transform(before, after)?.into(),
-                right_field,
+                right_fields,
+                null_safe,
+                predicate,
                 outer,
             },
-            Self::Limit { source, limit } => Self::Limit {
-                source: source.transform(before, after)?.into(),
+            Self::Limit {
+                source,
+                offset,
                 limit,
-            },
-            Self::Offset { source, offset } => Self::Offset {
+            } => Self::Limit {
                 source: source.transform(before, after)?.into(),
                 offset,
+                limit,
             },
             Self::Order { source, orders } => Self::Order {
                 source: source.transform(before, after)?.into(),
@@ -178,15 +316,98 @@ impl Node {
             // 最低层的操作就不转换了
             n @ Self::CreateTable { .. }
             | n @ Self::DropTable { .. }
+            | n @ Self::CreatePolicy { .. }
+            | n @ Self::DropPolicy { .. }
+            | n @ Self::Truncate { .. }
+            | n @ Self::CheckTable { .. }
+            | n @ Self::Analyze { .. }
+            | n @ Self::Backup { .. }
+            | n @ Self::Restore { .. }
             | n @ Self::IndexLookup { .. }
+            | n @ Self::IndexRangeScan { .. }
+            | n @ Self::IndexOnlyScan { .. }
             | n @ Self::Insert { .. }
             | n @ Self::KeyLookup { .. }
+            | n @ Self::Values { .. }
             | n @ Self::Nothing
             | n @ Self::Scan { .. } => n,
         };
         after(self)
     }
 
+    /// 执行的时候读取或者修改的所有表 包括子查询中的
+    pub fn tables(&self) -> BTreeSet<String> {
+        let tables = RefCell::new(BTreeSet::new());
+        // 只是收集 转换的结果丢掉
+        let _ = self.clone().transform(
+            &|n| {
+                match &n {
+                    Self::Scan { table, .. }
+                    | Self::KeyLookup { table, .. }
+                    | Self::IndexLookup { table, .. }
+                    | Self::IndexRangeScan { table, .. }
+                    | Self::IndexOnlyScan { table, .. }
+                    | Self::Insert { table, .. }
+                    | Self::Update { table, .. }
+                    | Self::Delete { table, .. } => {
+                        tables.borrow_mut().insert(table.clone());
+                    }
+                    _ => {}
+                }
+                n.transform_expressions(
+                    &|e| {
+                        if let Expression::Subquery(node) = &e {
+                            tables.borrow_mut().extend(node.tables());
+                        }
+                        Ok(e)
+                    },
+                    &|e| Ok(e),
+                )
+            },
+            &|n| Ok(n),
+        );
+        tables.into_inner()
+    }
+
+    /// 子查询中引用的外层查询的列
+    pub fn outer_fields(&self) -> HashSet<usize> {
+        let fields = RefCell::new(HashSet::new());
+        // 只是收集 转换的结果丢掉
+        let _ = self.clone().transform(
+            &|n| {
+                n.transform_expressions(
+                    &|e| {
+                        if let Expression::OuterField(i, _) = e {
+                            fields.borrow_mut().insert(i);
+                        }
+                        Ok(e)
+                    },
+                    &|e| Ok(e),
+                )
+            },
+            &|n| Ok(n),
+        );
+        fields.into_inner()
+    }
+
+    /// 把子查询中引用的外层的列换成外层这一行的值
+    pub fn bind_outer(self, row: &[Value]) -> Result<Self> {
+        self.transform(
+            &|n| {
+                n.transform_expressions(
+                    &|e| match e {
+                        Expression::OuterField(i, _) => Ok(Expression::Constant(
+                            row.get(i).cloned().unwrap_or(Value::Null),
+                        )),
+                        e => Ok(e),
+                    },
+                    &|e| Ok(e),
+                )
+            },
+            &|n| Ok(n),
+        )
+    }
+
     /// 转换node中的expression
     pub fn transform_expressions<B, A>(self, before: &B, after: &A) -> Result<Self>
     where
@@ -196,28 +417,45 @@ impl Node {
         Ok(match self {
             n @ Self::Aggregation { .. }
             | n @ Self::CreateTable { .. }
-            | n @ Self::Delete { .. }
+            | n @ Self::CreateTableAs { .. }
             | n @ Self::DropTable { .. }
-            | n @ Self::HashJoin { .. }
+            | n @ Self::CreatePolicy { .. }
+            | n @ Self::DropPolicy { .. }
+            | n @ Self::Truncate { .. }
+            | n @ Self::CheckTable { .. }
+            | n @ Self::Analyze { .. }
+            | n @ Self::Backup { .. }
+            | n @ Self::Restore { .. }
+            | n @ Self::HashJoin {
+                predicate: None, ..
+            }
             | n @ Self::IndexLookup { .. }
+            | n @ Self::IndexRangeScan { .. }
+            | n @ Self::IndexOnlyScan { .. }
             | n @ Self::KeyLookup { .. }
             | n @ Self::Limit { .. }
             | n @ Self::NestedLoopJoin {
                 predicate: None, ..
             }
             | n @ Self::Nothing
-            | n @ Self::Offset { .. }
             | n @ Self::Scan { filter: None, .. } => n,
 
             Self::Filter { source, predicate } => Self::Filter {
                 source,
                 predicate: predicate.transform(before, after)?,
             },
+            Self::Having { source, predicate } => Self::Having {
+                source,
+                predicate: predicate.transform(before, after)?,
+            },
 
             Self::Insert {
                 table,
                 columns,
                 expressions,
+                on_conflict,
+                returning,
+                policy,
             } => Self::Insert {
                 table,
                 columns,
@@ -230,13 +468,41 @@ impl Node {
                             .collect()
                     })
                     .collect::<Result<_>>()?,
+                on_conflict: match on_conflict {
+                    Some(OnConflict::Update(set)) => Some(OnConflict::Update(
+                        set.into_iter()
+                            .map(|(i, e)| Ok((i, e.transform(before, after)?)))
+                            .collect::<Result<_>>()?,
+                    )),
+                    on_conflict => on_conflict,
+                },
+                returning: transform_returning(returning, before, after)?,
+                policy: policy.map(|p| p.transform(before, after)).transpose()?,
+            },
+
+            Self::Delete {
+                table,
+                source,
+                returning,
+            } => Self::Delete {
+                table,
+                source,
+                returning: transform_returning(returning, before, after)?,
+            },
+
+            Self::Values { columns, rows } => Self::Values {
+                columns,
+                rows: rows
+                    .into_iter()
+                    .map(|row| row.into_iter().map(|e| e.transform(before, after)).collect())
+                    .collect::<Result<_>>()?,
             },
 
             Self::Order { source, orders } => Self::Order {
                 source,
                 orders: orders
                     .into_iter()
-                    .map(|(e, o)| e.transform(before, after).map(|e| (e, o)))
+                    .map(|(e, o, n)| e.transform(before, after).map(|e| (e, o, n)))
                     .collect::<Result<_>>()?,
             },
 
@@ -254,6 +520,24 @@ impl Node {
                 left_size,
             },
 
+            Self::HashJoin {
+                left,
+                left_fields,
+                right,
+                right_fields,
+                null_safe,
+                predicate: Some(predicate),
+                outer,
+            } => Self::HashJoin {
+                left,
+                left_fields,
+                right,
+                right_fields,
+                null_safe,
+                predicate: Some(predicate.transform(before, after)?),
+                outer,
+            },
+
             Self::Projection {
                 source,
                 expressions,
@@ -269,25 +553,75 @@ impl Node {
                 table,
                 alias,
                 filter: Some(filter),
+                columns,
             } => Self::Scan {
                 table,
                 alias,
                 filter: Some(filter.transform(before, after)?),
+                columns,
             },
 
-            Self::Update { table, source, set } => Self::Update {
+            Self::Update {
+                table,
+                source,
+                set,
+                returning,
+                policy,
+            } => Self::Update {
                 table,
                 source,
                 set: set
                     .into_iter()
                     .map(|(i, e)| e.transform(before, after).map(|e| (i, e)))
                     .collect::<Result<_>>()?,
+                returning: transform_returning(returning, before, after)?,
+                policy: policy.map(|p| p.transform(before, after)).transpose()?,
             },
         })
     }
 
     // Displays the node, where prefix gives the node prefix.
-    pub fn format(&self, mut indent: String, root: bool, last: bool) -> String {
+    pub fn format(&self, indent: String, root: bool, last: bool) -> String {
+        self.format_node(indent, root, last, &mut None)
+    }
+
+    /// 展示 explain analyze 的结果 stats 按照执行树的前序遍历排列
+    pub fn format_analyze(&self, stats: &[NodeStats]) -> String {
+        let notes = stats
+            .iter()
+            .map(|stat| format!("[rows={} time={:?}]", stat.rows, stat.elapsed))
+            .collect::<Vec<_>>();
+        self.format_node("".into(), true, true, &mut Some(notes.into_iter()))
+    }
+
+    /// 展示 explain 的结果 estimates 按照执行树的前序遍历排列
+    pub fn format_explain(&self, estimates: &[NodeEstimate]) -> String {
+        let notes = estimates.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        self.format_node("".into(), true, true, &mut Some(notes.into_iter()))
+    }
+
+    /// explain (format json) 的结果 执行树直接用serde编码 details 按照执行树的前序遍历排列
+    /// 没有执行的时候 details 是 estimates 执行过的是 stats
+    /// 字段按照定义的顺序 同样的计划得到同样的结果
+    pub fn format_json<D: serde::Serialize>(&self, key: &str, details: &[D]) -> Result<String> {
+        use serde::ser::{SerializeMap, Serializer};
+        let mut json = Vec::new();
+        let mut serializer = serde_json::Serializer::pretty(&mut json);
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("plan", self)?;
+        map.serialize_entry(key, details)?;
+        map.end()?;
+        Ok(String::from_utf8(json)?)
+    }
+
+    /// notes 是每个节点附加在行尾的信息
+    fn format_node(
+        &self,
+        mut indent: String,
+        root: bool,
+        last: bool,
+        notes: &mut Option<std::vec::IntoIter<String>>,
+    ) -> String {
         let mut s = indent.clone();
         if !last {
             s += "├─ ";
@@ -296,6 +630,9 @@ impl Node {
             s += "└─ ";
             indent += "   ";
         }
+        // 前序遍历 先拿到自己的附加信息
+        let start = s.len();
+        let note = notes.as_mut().and_then(|notes| notes.next());
         match self {
             Self::Aggregation { source, aggregates } => {
                 s += &format!(
@@ -306,45 +643,93 @@ impl Node {
                         .collect::<Vec<_>>()
                         .join(", ")
                 );
-                s += &source.format(indent, false, true);
+                s += &source.format_node(indent, false, true, notes);
             }
-            Self::CreateTable { table, defaults } => {
+            Self::CreateTable { table } => {
                 s += &format!("CreateTable: {}\n", table.name);
             }
-            Self::Delete { source, table } => {
-                s += &format!("Delete: {}\n", table);
-                s += &source.format(indent, false, true);
+            Self::CreateTableAs { table, source } => {
+                s += &format!("CreateTableAs: {}\n", table);
+                s += &source.format_node(indent, false, true, notes);
+            }
+            Self::Delete {
+                source,
+                table,
+                returning,
+            } => {
+                s += &format!("Delete: {}{}\n", table, format_returning(returning));
+                s += &source.format_node(indent, false, true, notes);
             }
             Self::DropTable { table } => {
                 s += &format!("DropTable: {}\n", table);
             }
+            Self::CreatePolicy { table, policy } => {
+                s += &format!("CreatePolicy: {} on {}\n", policy.name, table);
+            }
+            Self::DropPolicy { table, name } => {
+                s += &format!("DropPolicy: {} on {}\n", name, table);
+            }
+            Self::Truncate { table } => {
+                s += &format!("Truncate: {}\n", table);
+            }
+            Self::CheckTable { table } => {
+                s += &format!("CheckTable: {}\n", table);
+            }
+            Self::Analyze { table } => {
+                s += &format!("Analyze: {}\n", table);
+            }
+            Self::Backup { path } => {
+                s += &format!("Backup: {}\n", path);
+            }
+            Self::Restore { path } => {
+                s += &format!("Restore: {}\n", path);
+            }
             Self::Filter { source, predicate } => {
                 s += &format!("Filter: {}\n", predicate);
-                s += &source.format(indent, false, true);
+                s += &source.format_node(indent, false, true, notes);
+            }
+            Self::Having { source, predicate } => {
+                s += &format!("Having: {}\n", predicate);
+                s += &source.format_node(indent, false, true, notes);
             }
             Self::HashJoin {
                 left,
-                left_field,
+                left_fields,
                 right,
-                right_field,
+                right_fields,
+                null_safe,
+                predicate,
                 outer,
             } => {
+                let format_field = |field: &JoinField, side: &str| match field {
+                    (_, Some((Some(t), n))) => format!("{}.{}", t, n),
+                    (_, Some((None, n))) => n.clone(),
+                    (i, None) => format!("{} #{}", side, i),
+                };
                 s += &format!(
-                    "HashJoin: {} on {} = {}\n",
-                    if *outer { "outer" } else { "inner" },
-                    match left_field {
-                        (_, Some((Some(t), n))) => format!("{}.{}", t, n),
-                        (_, Some((None, n))) => n.clone(),
-                        (i, None) => format!("left #{}", i),
-                    },
-                    match right_field {
-                        (_, Some((Some(t), n))) => format!("{}.{}", t, n),
-                        (_, Some((None, n))) => n.clone(),
-                        (i, None) => format!("right #{}", i),
-                    },
+                    "HashJoin: {} on {}",
+                    outer.map_or("inner".to_string(), |o| o.to_string()),
+                    left_fields
+                        .iter()
+                        .zip(right_fields)
+                        .zip(null_safe)
+                        .map(|((l, r), null_safe)| {
+                            format!(
+                                "{} {} {}",
+                                format_field(l, "left"),
+                                if *null_safe { "<=>" } else { "=" },
+                                format_field(r, "right")
+                            )
+                        })
+                        .collect::<Vec<_>>()
+                        .join(" and "),
                 );
-                s += &left.format(indent.clone(), false, false);
-                s += &right.format(indent, false, true);
+                if let Some(predicate) = predicate {
+                    s += &format!(" filter {}", predicate);
+                }
+                s += "\n";
+                s += &left.format_node(indent.clone(), false, false, notes);
+                s += &right.format_node(indent, false, true, notes);
             }
             Self::IndexLookup {
                 table,
@@ -356,27 +741,60 @@ impl Node {
                 if let Some(alias) = alias {
                     s += &format!(" as {}", alias);
                 }
-                s += &format!(" column {}", column);
-                if !values.is_empty() && values.len() < 10 {
-                    s += &format!(
-                        " ({})",
-                        values
-                            .iter()
-                            .map(|k| k.to_string())
-                            .collect::<Vec<_>>()
-                            .join(", ")
-                    );
-                } else {
-                    s += &format!(" ({} values)", values.len());
+                s += &format!(" column {} {}\n", column, format_values(values));
+            }
+            Self::IndexRangeScan {
+                table,
+                alias,
+                column,
+                range,
+            } => {
+                s += &format!("IndexRangeScan: {}", table);
+                if let Some(alias) = alias {
+                    s += &format!(" as {}", alias);
                 }
-                s += "\n";
+                s += &format!(" column {} {}\n", column, format_range(range));
+            }
+            Self::IndexOnlyScan {
+                table,
+                alias,
+                column,
+                access,
+            } => {
+                s += &format!("IndexOnlyScan: {}", table);
+                if let Some(alias) = alias {
+                    s += &format!(" as {}", alias);
+                }
+                let access = match access {
+                    IndexAccess::Lookup(values) => format_values(values),
+                    IndexAccess::Range(range) => format_range(range),
+                };
+                s += &format!(" column {} {}\n", column, access);
             }
             Self::Insert {
                 table,
                 columns: _,
                 expressions,
+                on_conflict,
+                returning,
+                policy: _,
             } => {
-                s += &format!("Insert: {} ({} rows)\n", table, expressions.len());
+                s += &format!("Insert: {} ({} rows)", table, expressions.len());
+                match on_conflict {
+                    Some(OnConflict::Nothing) => s += " on conflict do nothing",
+                    Some(OnConflict::Update(set)) => {
+                        s += &format!(
+                            " on conflict do update {}",
+                            set.iter()
+                                .map(|(i, e)| format!("#{}={}", i, e))
+                                .collect::<Vec<_>>()
+                                .join(",")
+                        )
+                    }
+                    None => {}
+                }
+                s += &format_returning(returning);
+                s += "\n";
             }
             Self::KeyLookup { table, alias, keys } => {
                 s += &format!("KeyLookup: {}", table);
@@ -396,9 +814,20 @@ impl Node {
                 }
                 s += "\n";
             }
-            Self::Limit { source, limit } => {
-                s += &format!("Limit: {}\n", limit);
-                s += &source.format(indent, false, true);
+            Self::Limit {
+                source,
+                offset,
+                limit,
+            } => {
+                s += "Limit:";
+                if let Some(limit) = limit {
+                    s += &format!(" {}", limit);
+                }
+                if let Some(offset) = offset {
+                    s += &format!(" offset {}", offset);
+                }
+                s += "\n";
+                s += &source.format_node(indent, false, true, notes);
             }
             Self::NestedLoopJoin {
                 left,
@@ -407,31 +836,33 @@ impl Node {
                 outer,
                 left_size: _,
             } => {
-                s += &format!("NestedLoopJoin: {}", if *outer { "outer" } else { "inner" });
+                s += &format!(
+                    "NestedLoopJoin: {}",
+                    outer.map_or("inner".to_string(), |o| o.to_string())
+                );
                 if let Some(expr) = predicate {
                     s += &format!(" on {}", expr);
                 }
                 s += "\n";
-                s += &left.format(indent.clone(), false, false);
-                s += &right.format(indent, false, true);
+                s += &left.format_node(indent.clone(), false, false, notes);
+                s += &right.format_node(indent, false, true, notes);
             }
             Self::Nothing {} => {
                 s += "Nothing\n";
             }
-            Self::Offset { source, offset } => {
-                s += &format!("Offset: {}\n", offset);
-                s += &source.format(indent, false, true);
+            Self::Values { columns, rows } => {
+                s += &format!("Values: {} ({} rows)\n", columns.join(", "), rows.len());
             }
             Self::Order { source, orders } => {
                 s += &format!(
                     "Order: {}\n",
                     orders
                         .iter()
-                        .map(|(expr, dir)| format!("{} {}", expr, dir))
+                        .map(|(expr, dir, nulls)| format!("{} {} {}", expr, dir, nulls))
                         .collect::<Vec<_>>()
                         .join(", ")
                 );
-                s += &source.format(indent, false, true);
+                s += &source.format_node(indent, false, true, notes);
             }
             Self::Projection {
                 source,
@@ -445,12 +876,13 @@ impl Node {
                         .collect::<Vec<_>>()
                         .join(", ")
                 );
-                s += &source.format(indent, false, true);
+                s += &source.format_node(indent, false, true, notes);
             }
             Self::Scan {
                 table,
                 alias,
                 filter,
+                columns,
             } => {
                 s += &format!("Scan: {}", table);
                 if let Some(alias) = alias {
@@ -459,11 +891,27 @@ impl Node {
                 if let Some(expr) = filter {
                     s += &format!(" ({})", expr);
                 }
+                if let Some(columns) = columns {
+                    s += &format!(
+                        " columns {}",
+                        columns
+                            .iter()
+                            .map(|i| format!("#{}", i))
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    );
+                }
                 s += "\n";
             }
-            Self::Update { source, table, set } => {
+            Self::Update {
+                source,
+                table,
+                set,
+                returning,
+                policy: _,
+            } => {
                 s += &format!(
-                    "Update: {} ({})\n",
+                    "Update: {} ({}){}\n",
                     table,
                     set.iter()
                         .map(|(i, e)| format!(
@@ -473,17 +921,91 @@ impl Node {
                             e
                         ))
                         .collect::<Vec<_>>()
-                        .join(",")
+                        .join(","),
+                    format_returning(returning)
                 );
-                s += &source.format(indent, false, true);
+                s += &source.format_node(indent, false, true, notes);
             }
         };
         if root {
             s = s.trim_end().to_string()
         }
+        // 把附加信息放到自己这一行的最后
+        if let Some(note) = note {
+            let end = s[start..].find('\n').map_or(s.len(), |i| start + i);
+            s.insert_str(end, &format!(" {}", note));
+        }
         s
     }
 }
+
+fn transform_returning<B, A>(
+    returning: Option<Returning>,
+    before: &B,
+    after: &A,
+) -> Result<Option<Returning>>
+where
+    B: Fn(Expression) -> Result<Expression>,
+    A: Fn(Expression) -> Result<Expression>,
+{
+    returning
+        .map(|returning| {
+            returning
+                .into_iter()
+                .map(|(e, l)| Ok((e.transform(before, after)?, l)))
+                .collect::<Result<_>>()
+        })
+        .transpose()
+}
+
+/// 值比较少的时候全部输出
+fn format_values(values: &[Value]) -> String {
+    if !values.is_empty() && values.len() < 10 {
+        format!(
+            "({})",
+            values
+                .iter()
+                .map(|k| k.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    } else {
+        format!("({} values)", values.len())
+    }
+}
+
+fn format_range(range: &IndexRange) -> String {
+    let bound = |b: &Bound<Value>, unbounded: &str| match b {
+        Bound::Included(v) => format!("{}", v),
+        Bound::Excluded(v) => format!("{}", v),
+        Bound::Unbounded => unbounded.to_string(),
+    };
+    format!(
+        "{}{}, {}{}",
+        if matches!(range.0, Bound::Included(_)) { "[" } else { "(" },
+        bound(&range.0, "-inf"),
+        bound(&range.1, "+inf"),
+        if matches!(range.1, Bound::Included(_)) { "]" } else { ")" },
+    )
+}
+
+fn format_returning(returning: &Option<Returning>) -> String {
+    match returning {
+        Some(returning) => format!(
+            " returning {}",
+            returning
+                .iter()
+                .map(|(e, l)| match l {
+                    Some(l) => format!("{} as {}", e, l),
+                    None => e.to_string(),
+                })
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+        None => String::new(),
+    }
+}
+
 impl Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(f, "{}", self.format("".into(), true, true))
@@ -510,20 +1032,86 @@ impl Plan {
     /// 进行节点优化
     pub fn optimize(self, catalog: &dyn Catalog) -> Result<Self> {
         let mut root = self.node;
-        //root = optimizer::ConstantFolder.optimize(root)?;
+        // 先计算常量 再清理掉恒为true的谓词 这样 1=1 之类的条件就不会被下推
+        root = optimizer::ConstantFolder.optimize(root)?;
+        root = optimizer::NoopCleaner.optimize(root)?;
         root = optimizer::FilterPushdown.optimize(root)?;
         root = optimizer::IndexLookup::new(catalog).optimize(root)?;
-        //root = optimizer::JoinType.optimize(root)?;
-        //root = optimizer::NoopCleaner.optimize(root)?;
+        root = optimizer::ColumnPruning::new(catalog).optimize(root)?;
+        // hash join 需要在谓词下推之后
+        root = optimizer::JoinType.optimize(root)?;
         Ok(Plan::new(root))
     }
     pub fn execute<T: Transaction + 'static>(self, txn: &mut T) -> Result<ResultSet> {
         <dyn Executor<T>>::build(self.node).execute(txn)
     }
+
+    /// 按批执行 查询的结果可以一批一批地读取
+    pub fn execute_batches<T: Transaction + 'static>(
+        self,
+        txn: &mut T,
+    ) -> Result<(Columns, Batches)> {
+        <dyn Executor<T>>::build(self.node).execute_batches(txn)
+    }
+
+    /// 执行并收集每个节点的统计信息
+    pub fn execute_analyze<T: Transaction + 'static>(
+        self,
+        txn: &mut T,
+    ) -> Result<(ResultSet, Vec<NodeStats>)> {
+        let stats = Default::default();
+        let result = <dyn Executor<T>>::build_analyze(self.node, &stats).execute(txn)?;
+        let stats = std::mem::take(&mut *stats.lock()?);
+        Ok((result, stats))
+    }
+
+    /// 估计每个节点输出的行数
+    pub fn estimate<T: Transaction>(&self, txn: &T) -> Result<Vec<NodeEstimate>> {
+        estimate::estimate(&self.node, txn)
+    }
+}
+
+/// 连接的字段 在这一边的行中的位置 以及表名和列名
+pub type JoinField = (usize, Option<(Option<String>, String)>);
+
+/// 只读索引的时候读哪些索引值
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum IndexAccess {
+    /// 等值查询 和 IndexLookup 一样
+    Lookup(Vec<Value>),
+    /// 范围查询 和 IndexRangeScan 一样 结果按照索引值排序
+    Range(IndexRange),
+}
+
+/// 外连接 没有匹配的行另一边补null 右连接在规划的时候交换成左连接
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Outer {
+    /// 保留左表没有匹配的行
+    Left,
+    /// 左右两边没有匹配的行都保留
+    Full,
+}
+
+impl Display for Outer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Outer::Left => write!(f, "left outer"),
+            Outer::Full => write!(f, "full outer"),
+        }
+    }
+}
+
+/// 插入时主键冲突的处理
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum OnConflict {
+    /// 跳过冲突的行
+    Nothing,
+    /// 更新已经存在的行 表达式作用在已经存在的行后面接上要插入的行上
+    Update(Vec<(usize, Expression)>),
 }
 
 /// 聚合函数
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum Aggregate {
     /// 求和
     Sum,
@@ -535,6 +1123,10 @@ pub enum Aggregate {
     Max,
     /// 最小值
     Min,
+    /// 样本标准差
+    StdDev,
+    /// 样本方差
+    Variance,
 }
 
 impl Display for Aggregate {
@@ -548,6 +1140,8 @@ impl Display for Aggregate {
                 Aggregate::Count => "Count",
                 Aggregate::Max => "Max",
                 Aggregate::Min => "Min",
+                Aggregate::StdDev => "StdDev",
+                Aggregate::Variance => "Variance",
             }
         )
     }
@@ -561,6 +1155,8 @@ impl Aggregate {
             "SUM" => Ok(Self::Sum),
             "COUNT" => Ok(Self::Count),
             "AVERAGE" => Ok(Self::Average),
+            "STDDEV" => Ok(Self::StdDev),
+            "VARIANCE" => Ok(Self::Variance),
             _ => Err(Error::Plan(format!("not support for aggregate: {}", f))),
         }
     }
@@ -571,6 +1167,8 @@ impl Aggregate {
             Aggregate::Count => "Count".to_string(),
             Aggregate::Max => "Max".to_string(),
             Aggregate::Min => "Min".to_string(),
+            Aggregate::StdDev => "StdDev".to_string(),
+            Aggregate::Variance => "Variance".to_string(),
         }
     }
 }
@@ -1,12 +1,17 @@
 use std::collections::HashSet;
+use std::ops::Bound;
 
 use log::debug;
 
 use crate::errors::Result;
-use crate::sql::expression::Expression;
+use crate::sql::expression::{intersect_range, Expression};
 use crate::sql::schema::Catalog;
-use crate::sql::Value;
-use crate::{errors::Error, sql::plan::Node};
+use crate::sql::statistics::TableStats;
+use crate::sql::{ColumnType, Table, Value};
+use crate::{
+    errors::Error,
+    sql::plan::{IndexAccess, JoinField, Node, Outer},
+};
 
 /// 优化器
 pub trait Optimizer {
@@ -16,6 +21,7 @@ pub trait Optimizer {
 /// 清洁工 把一些固定值清洁出来
 /// true 或上任何 都是true, false与上任何 都是false
 /// 还有filter 的过滤表达式是 Constant(Bool(true)) 那就直接把source提取上来
+/// null 和其他值的与或结果要看另一边的值 所以不做化简
 pub struct NoopCleaner;
 
 impl Optimizer for NoopCleaner {
@@ -24,30 +30,29 @@ impl Optimizer for NoopCleaner {
         node.transform(
             &|n| {
                 n.transform_expressions(&|e| Ok(e), &|e| match &e {
-                    Add(lhs, rhs) => match (&**lhs, &**rhs) {
-                        (Constant(Value::Bool(false)), _)
-                        | (_, Constant(Value::Bool(false)))
-                        | (Constant(Value::Null), _)
-                        | (_, Constant(Value::Null)) => Ok(Constant(Value::Bool(false))),
+                    And(lhs, rhs) => match (&**lhs, &**rhs) {
+                        (Constant(Value::Bool(false)), _) | (_, Constant(Value::Bool(false))) => {
+                            Ok(Constant(Value::Bool(false)))
+                        }
                         (Constant(Value::Bool(true)), e) | (e, Constant(Value::Bool(true))) => {
                             Ok(e.clone())
                         }
                         _ => Ok(e),
                     },
                     Or(lhs, rhs) => match (&**lhs, &**rhs) {
-                        (Constant(Value::Bool(false)), e)
-                        | (e, Constant(Value::Bool(false)))
-                        | (Constant(Value::Null), e)
-                        | (e, Constant(Value::Null)) => Ok(e.clone()),
                         (Constant(Value::Bool(true)), _) | (_, Constant(Value::Bool(true))) => {
                             Ok(Constant(Value::Bool(true)))
                         }
+                        (Constant(Value::Bool(false)), e) | (e, Constant(Value::Bool(false))) => {
+                            Ok(e.clone())
+                        }
                         _ => Ok(e),
                     },
                     _ => Ok(e),
                 })
             },
             // 如果是 filter转换后 predicate是ture 就不需要这个filterNode了
+            // scan 和 join 上恒为true的条件也可以去掉
             &|n| match n {
                 Node::Filter { source, predicate } => match predicate {
                     Constant(Value::Bool(true)) => Ok(*source),
@@ -56,6 +61,30 @@ impl Optimizer for NoopCleaner {
                         predicate: p,
                     }),
                 },
+                Node::Scan {
+                    table,
+                    alias,
+                    filter: Some(Constant(Value::Bool(true))),
+                    columns,
+                } => Ok(Node::Scan {
+                    table,
+                    alias,
+                    filter: None,
+                    columns,
+                }),
+                Node::NestedLoopJoin {
+                    left,
+                    left_size,
+                    right,
+                    predicate: Some(Constant(Value::Bool(true))),
+                    outer,
+                } => Ok(Node::NestedLoopJoin {
+                    left,
+                    left_size,
+                    right,
+                    predicate: None,
+                    outer,
+                }),
                 _ => Ok(n),
             },
         )
@@ -71,7 +100,9 @@ impl Optimizer for ConstantFolder {
             e.transform_expressions(
                 &|e| {
                     if !e.contains(&|e| match e {
-                        Expression::Field(_, _) => true,
+                        Expression::Field(_, _)
+                        | Expression::OuterField(_, _)
+                        | Expression::Subquery(_) => true,
                         _ => false,
                     }) {
                         Ok(Expression::Constant(e.evaluate(None)?))
@@ -105,6 +136,7 @@ impl Optimizer for FilterPushdown {
                             table,
                             alias,
                             mut filter,
+                            columns,
                         } => {
                             let predicate = std::mem::replace(
                                 &mut predicate,
@@ -120,13 +152,15 @@ impl Optimizer for FilterPushdown {
                                 table,
                                 alias,
                                 filter: Some(expr),
+                                columns,
                             })
                         }
+                        // 外连接的where条件要在补null之后再过滤 不能合并到连接条件里
                         Node::NestedLoopJoin {
                             left,
                             right,
                             predicate: mut join_predicate,
-                            outer,
+                            outer: None,
                             left_size,
                         } => {
                             let predicate = std::mem::replace(
@@ -147,7 +181,7 @@ impl Optimizer for FilterPushdown {
                                 left,
                                 right,
                                 predicate: Some(expr),
-                                outer,
+                                outer: None,
                                 left_size,
                             })
                         }
@@ -170,6 +204,7 @@ impl FilterPushdown {
                     table,
                     alias,
                     filter,
+                    columns,
                 } => {
                     if let Some(filter) = filter {
                         predicate = Expression::And(Box::new(predicate), Box::new(filter));
@@ -178,6 +213,7 @@ impl FilterPushdown {
                         table,
                         alias,
                         filter: Some(predicate),
+                        columns,
                     }
                 }
                 Node::NestedLoopJoin {
@@ -221,35 +257,38 @@ impl FilterPushdown {
                     // 拿出来都是与连接的子句 这里就需要去汲取左表和右表的相关式子了
                     // 这里要注意 其实是排除含右表列的expression
                     // 反选获得
+                    // 外连接的连接条件决定的是哪些行补null 保留的那一边不能下推
                     let (left_expr, cnf): (Vec<Expression>, Vec<Expression>) =
                         cnf.into_iter().partition(|e| {
                             debug!("{:#?}", e);
-                            !e.contains(&|expr| match expr {
-                                Expression::Field(i, _) => {
-                                    if i >= &left_size {
-                                        debug!("left left_size : {}", i);
-                                        true
-                                    } else {
-                                        debug!("left left_size : {} return fasle", i);
-                                        false
+                            outer.is_none()
+                                && !e.contains(&|expr| match expr {
+                                    Expression::Field(i, _) => {
+                                        if i >= &left_size {
+                                            debug!("left left_size : {}", i);
+                                            true
+                                        } else {
+                                            debug!("left left_size : {} return fasle", i);
+                                            false
+                                        }
                                     }
-                                }
-                                _ => return false,
-                            })
+                                    _ => return false,
+                                })
                         });
                     let (right_expr, cnf): (Vec<Expression>, Vec<Expression>) =
                         cnf.into_iter().partition(|e| {
-                            !e.contains(&|expr| match expr {
-                                Expression::Field(i, _) => {
-                                    if i < &left_size {
-                                        debug!("left right : {}", i);
-                                        true
-                                    } else {
-                                        false
+                            outer != Some(Outer::Full)
+                                && !e.contains(&|expr| match expr {
+                                    Expression::Field(i, _) => {
+                                        if i < &left_size {
+                                            debug!("left right : {}", i);
+                                            true
+                                        } else {
+                                            false
+                                        }
                                     }
-                                }
-                                _ => return false,
-                            })
+                                    _ => return false,
+                                })
                         });
 
                     let right_expr = right_expr
@@ -298,6 +337,9 @@ impl FilterPushdown {
     }
 }
 
+/// 有统计信息的时候 索引读取的行超过全表的这个比例就使用全表扫描
+pub const INDEX_SELECTIVITY: f64 = 0.2;
+
 ///  寻找索引
 pub struct IndexLookup<'a> {
     catalog: &'a dyn Catalog,
@@ -316,125 +358,843 @@ impl<'a> Optimizer for IndexLookup<'a> {
                 Node::Scan {
                     table,
                     alias,
-                    filter,
+                    filter: Some(filter),
+                    ..
                 } => {
-                    if let Some(mut filter) = filter.clone() {
-                        let table = self.catalog.must_read_table(table.as_str())?;
+                    let table = self.catalog.must_read_table(table.as_str())?;
+                    let candidates = Self::candidates(&table, alias, filter.clone().to_cnf_vec()?)?;
+                    // 没有统计信息的时候使用第一个能用的索引
+                    // 有的话选择选择率最低的 都太高的时候全表扫描更快
+                    let chosen = match &table.statistics {
+                        None => candidates.into_iter().next(),
+                        Some(stats) => candidates
+                            .into_iter()
+                            .map(|c| (c.selectivity(stats), c))
+                            .filter(|(selectivity, _)| *selectivity <= INDEX_SELECTIVITY)
+                            .min_by(|a, b| a.0.total_cmp(&b.0))
+                            .map(|(_, c)| c),
+                    };
+                    Ok(chosen.map_or(n, Candidate::into_node))
+                }
+                _ => Ok(n),
+            },
+            &|n| Ok(n),
+        )
+    }
+}
 
-                        let key_index = table.columns.iter().position(|e| e.primary_key).ok_or(
-                            Error::Optimizer(format!("failed to get table:{} key", table.name)),
-                        )?;
+impl<'a> IndexLookup<'a> {
+    /// 所有可以代替全表扫描的读取方式 先是每个条件上的等值读取 再是每个索引上的范围扫描
+    fn candidates(
+        table: &Table,
+        alias: &Option<String>,
+        cnf: Vec<Expression>,
+    ) -> Result<Vec<Candidate>> {
+        let key_index = table.columns.iter().position(|e| e.primary_key).ok_or(
+            Error::Optimizer(format!("failed to get table:{} key", table.name)),
+        )?;
 
-                        let indexs: Vec<(usize, String)> = table
-                            .columns
-                            .clone()
-                            .into_iter()
-                            .enumerate()
-                            .filter(|(_, e)| e.index)
-                            .map(|(i, e)| (i, e.name))
-                            .collect();
-
-                        let mut cnf = filter.to_cnf_vec()?;
-                        for (index, e) in cnf.clone().iter().enumerate() {
-                            if let Some(vals) = e.look_up(key_index) {
-                                cnf.remove(index);
-                                let mut node = Node::KeyLookup {
-                                    table: table.name.clone(),
-                                    alias: alias.clone(),
-                                    keys: vals,
-                                };
-                                if let Some(predicate) = Expression::from_cnf_vec(cnf) {
-                                    node = Node::Filter {
-                                        source: Box::new(node),
-                                        predicate,
-                                    }
-                                }
-                                return Ok(node);
-                            }
+        let indexs: Vec<(usize, String)> = table
+            .columns
+            .clone()
+            .into_iter()
+            .enumerate()
+            .filter(|(_, e)| e.index)
+            .map(|(i, e)| (i, e.name))
+            .collect();
 
-                            for (i_index, name) in indexs.clone().into_iter() {
-                                if let Some(vals) = e.look_up(i_index) {
-                                    cnf.remove(index);
-                                    let mut node = Node::IndexLookup {
-                                        table: table.name.clone(),
-                                        alias: alias.clone(),
-                                        values: vals,
-                                        column: name,
-                                    };
-                                    if let Some(predicate) = Expression::from_cnf_vec(cnf) {
-                                        node = Node::Filter {
-                                            source: Box::new(node),
-                                            predicate,
-                                        }
-                                    }
-                                    return Ok(node);
+        let mut candidates = Vec::new();
+        for (index, e) in cnf.iter().enumerate() {
+            let mut rest = cnf.clone();
+            rest.remove(index);
+            if let Some(vals) = e.look_up(key_index) {
+                let column = &table.columns[key_index];
+                candidates.push(Candidate {
+                    node: Node::KeyLookup {
+                        table: table.name.clone(),
+                        alias: alias.clone(),
+                        keys: vals.into_iter().map(|v| column.lookup_value(v)).collect(),
+                    },
+                    rest,
+                });
+                continue;
+            }
+
+            for (i_index, name) in indexs.iter() {
+                if let Some(vals) = e.look_up(*i_index) {
+                    let column = &table.columns[*i_index];
+                    candidates.push(Candidate {
+                        node: Node::IndexLookup {
+                            table: table.name.clone(),
+                            alias: alias.clone(),
+                            values: vals.into_iter().map(|v| column.lookup_value(v)).collect(),
+                            column: name.clone(),
+                        },
+                        rest: rest.clone(),
+                    });
+                }
+            }
+        }
+
+        // 索引字段的范围查询 id > 1 and id < 10
+        for (i_index, name) in indexs.into_iter() {
+            let column = &table.columns[i_index];
+            let (ranges, rest): (Vec<_>, Vec<_>) = cnf.clone().into_iter().partition(|e| {
+                // 常量类型和字段类型不同的话 编码也不同 不能使用范围扫描
+                e.look_up_range(i_index).is_some_and(|(lower, upper)| {
+                    [lower, upper].iter().all(|b| match b {
+                        Bound::Included(v) | Bound::Excluded(v) => {
+                            match column.lookup_value(v.clone()) {
+                                Value::Decimal(_) => {
+                                    matches!(column.column_type, ColumnType::Decimal(..))
                                 }
+                                v => v.datatype().as_ref() == Some(&column.column_type),
                             }
                         }
+                        Bound::Unbounded => true,
+                    })
+                })
+            });
+            let range = ranges
+                .iter()
+                .filter_map(|e| e.look_up_range(i_index))
+                .reduce(intersect_range)
+                .map(|(lower, upper)| {
+                    let convert = |v| column.lookup_value(v);
+                    (lower.map(convert), upper.map(convert))
+                });
+            if let Some(range) = range {
+                candidates.push(Candidate {
+                    node: Node::IndexRangeScan {
+                        table: table.name.clone(),
+                        alias: alias.clone(),
+                        column: name,
+                        range,
+                    },
+                    rest,
+                });
+            }
+        }
+        Ok(candidates)
+    }
+}
+
+/// 一种代替全表扫描的读取方式 rest 是读取之后还需要过滤的条件
+struct Candidate {
+    node: Node,
+    rest: Vec<Expression>,
+}
+
+impl Candidate {
+    fn into_node(self) -> Node {
+        match Expression::from_cnf_vec(self.rest) {
+            Some(predicate) => Node::Filter {
+                source: Box::new(self.node),
+                predicate,
+            },
+            None => self.node,
+        }
+    }
+
+    /// 用直方图估计读取的行占全表的比例 主键读取总是最快的
+    /// 没有统计信息的列和以前一样使用索引
+    fn selectivity(&self, stats: &TableStats) -> f64 {
+        match &self.node {
+            Node::IndexLookup { column, values, .. } => stats
+                .columns
+                .get(column)
+                .map_or(0.0, |c| values.iter().map(|v| c.equal(v)).sum()),
+            Node::IndexRangeScan { column, range, .. } => {
+                stats.columns.get(column).map_or(0.0, |c| c.range(range))
+            }
+            _ => 0.0,
+        }
+    }
+}
+
+/// 列裁剪 把上层节点需要的列告诉scan 让scan只解码需要的列
+/// 行的宽度不变 没有用到的列是null 所以上层的表达式不需要修改
+/// 需要在 JoinType 之前 HashJoin 中没有记录左表的宽度
+pub struct ColumnPruning<'a> {
+    catalog: &'a dyn Catalog,
+}
+
+impl<'a> ColumnPruning<'a> {
+    pub fn new(catalog: &'a dyn Catalog) -> Box<Self> {
+        Box::new(Self { catalog })
+    }
+
+    /// 加上表达式中引用的列
+    fn require<'e>(
+        required: Option<HashSet<usize>>,
+        exprs: impl IntoIterator<Item = &'e Expression>,
+    ) -> Option<HashSet<usize>> {
+        required.map(|mut required| {
+            for expr in exprs {
+                required.extend(expr.fields());
+            }
+            required
+        })
+    }
+
+    /// required 是上层节点需要的列 None表示全部都需要
+    fn prune(&self, node: Node, required: Option<HashSet<usize>>) -> Result<Node> {
+        Ok(match node {
+            Node::Scan {
+                table,
+                alias,
+                filter,
+                columns: _,
+            } => {
+                let columns = match Self::require(required, filter.iter()) {
+                    Some(required) => {
+                        let width = self.catalog.must_read_table(&table)?.columns.len();
+                        let mut columns = required
+                            .into_iter()
+                            .filter(|i| *i < width)
+                            .collect::<Vec<_>>();
+                        columns.sort();
+                        // 所有列都需要的话和不裁剪一样
+                        Some(columns).filter(|c| c.len() < width)
                     }
-                    Ok(n)
+                    None => None,
+                };
+                Node::Scan {
+                    table,
+                    alias,
+                    filter,
+                    columns,
                 }
-                _ => Ok(n),
+            }
+            Node::Filter { source, predicate } => Node::Filter {
+                source: self
+                    .prune(*source, Self::require(required, [&predicate]))?
+                    .into(),
+                predicate,
             },
-            &|n| Ok(n),
-        )
+            Node::Having { source, predicate } => Node::Having {
+                source: self
+                    .prune(*source, Self::require(required, [&predicate]))?
+                    .into(),
+                predicate,
+            },
+            Node::Order { source, orders } => Node::Order {
+                source: self
+                    .prune(*source, Self::require(required, orders.iter().map(|(e, _, _)| e)))?
+                    .into(),
+                orders,
+            },
+            Node::Limit {
+                source,
+                offset,
+                limit,
+            } => Node::Limit {
+                source: self.prune(*source, required)?.into(),
+                offset,
+                limit,
+            },
+            // 投影只需要表达式中的列
+            Node::Projection {
+                source,
+                expressions,
+            } => Node::Projection {
+                source: self
+                    .prune(
+                        *source,
+                        Self::require(Some(HashSet::new()), expressions.iter().map(|(e, _)| e)),
+                    )?
+                    .into(),
+                expressions,
+            },
+            // 聚合的输入是规划时生成的投影 每一列都会用到
+            Node::Aggregation { source, aggregates } => Node::Aggregation {
+                source: self.prune(*source, None)?.into(),
+                aggregates,
+            },
+            // 左表的列在前 右表的列在后
+            Node::NestedLoopJoin {
+                left,
+                right,
+                left_size,
+                predicate,
+                outer,
+            } => {
+                let (left_required, right_required) =
+                    match Self::require(required, predicate.iter()) {
+                        Some(required) => {
+                            let (l, r): (HashSet<_>, HashSet<_>) =
+                                required.into_iter().partition(|i| *i < left_size);
+                            (Some(l), Some(r.into_iter().map(|i| i - left_size).collect()))
+                        }
+                        None => (None, None),
+                    };
+                Node::NestedLoopJoin {
+                    left: self.prune(*left, left_required)?.into(),
+                    right: self.prune(*right, right_required)?.into(),
+                    left_size,
+                    predicate,
+                    outer,
+                }
+            }
+            // 只用到索引列和主键的时候不需要读取行
+            Node::IndexLookup {
+                table,
+                alias,
+                column,
+                values,
+            } => match self.covered(&table, &column, &required)? {
+                true => Node::IndexOnlyScan {
+                    table,
+                    alias,
+                    column,
+                    access: IndexAccess::Lookup(values),
+                },
+                false => Node::IndexLookup {
+                    table,
+                    alias,
+                    column,
+                    values,
+                },
+            },
+            Node::IndexRangeScan {
+                table,
+                alias,
+                column,
+                range,
+            } => match self.covered(&table, &column, &required)? {
+                true => Node::IndexOnlyScan {
+                    table,
+                    alias,
+                    column,
+                    access: IndexAccess::Range(range),
+                },
+                false => Node::IndexRangeScan {
+                    table,
+                    alias,
+                    column,
+                    range,
+                },
+            },
+            // 其他节点不裁剪 更新和删除会把整行写回去
+            n => n,
+        })
+    }
+
+    /// 需要的列是不是都在索引中 也就是只有索引列和主键
+    /// 有过期时间的表要读取整行才能判断是否过期 不能只读索引
+    fn covered(
+        &self,
+        table: &str,
+        column: &str,
+        required: &Option<HashSet<usize>>,
+    ) -> Result<bool> {
+        let required = match required {
+            Some(required) => required,
+            None => return Ok(false),
+        };
+        let table = self.catalog.must_read_table(table)?;
+        if table.ttl.is_some() {
+            return Ok(false);
+        }
+        let column = table.get_column_index(column)?;
+        Ok(required
+            .iter()
+            .all(|i| *i == column || table.columns.get(*i).is_some_and(|c| c.primary_key)))
     }
 }
 
-/// join优化 如果是两个字段相等的连接 可以使用hashJoin
+impl<'a> Optimizer for ColumnPruning<'a> {
+    fn optimize(&self, node: Node) -> Result<Node> {
+        self.prune(node, None)
+    }
+}
+
+/// join优化 连接条件中有左右两边字段相等的子句 可以使用hashJoin
+/// 所有这样的子句一起作为哈希的key 剩下的子句在哈希匹配之后计算
 pub struct JoinType;
 
 impl Optimizer for JoinType {
     fn optimize(&self, node: Node) -> Result<Node> {
-        use Expression::Field;
         node.transform(
             &|n| match n {
                 Node::NestedLoopJoin {
                     left,
                     right,
-                    predicate,
+                    predicate: Some(mut predicate),
                     outer,
                     left_size,
-                } => match predicate {
-                    // Join优化要一定在下推优化之后，
-                    // 这样就保证这里的predicate如果相等，肯定是包含了两个表的字段
-                    // 那么就是一个左表的，一个是右表的
-                    // 就下面这个case重要一些 下面写的比较丑陋 以后看看怎么写的优雅一点
-                    Some(Expression::Equal(e1, e2)) => match (*e1, *e2) {
-                        (Field(i1, l1), Field(i2, l2)) => {
-                            let (left_field, right_field) = if i1 < i2 {
-                                ((i1, l1), (i2 - left_size, l2))
-                            } else {
-                                ((i2, l2), (i1 - left_size, l1))
-                            };
-
-                            Ok(Node::HashJoin {
-                                left,
-                                left_field,
-                                right,
-                                right_field,
-                                outer,
-                            })
+                } => {
+                    // Join优化要一定在下推优化之后 只有一边字段的子句已经下推了
+                    let mut left_fields = Vec::new();
+                    let mut right_fields = Vec::new();
+                    let mut null_safe = Vec::new();
+                    let mut residual = Vec::new();
+                    for expr in predicate.to_cnf_vec()? {
+                        match Self::join_fields(expr, left_size) {
+                            Ok((l, r, safe)) => {
+                                left_fields.push(l);
+                                right_fields.push(r);
+                                null_safe.push(safe);
+                            }
+                            Err(expr) => residual.push(expr),
                         }
-                        (e1, e2) => Result::Ok(Node::NestedLoopJoin {
+                    }
+                    if left_fields.is_empty() {
+                        return Ok(Node::NestedLoopJoin {
                             left,
                             right,
-                            predicate: Some(Expression::Equal(Box::new(e1), Box::new(e2))),
+                            predicate: Expression::from_cnf_vec(residual),
                             outer,
                             left_size,
-                        }),
-                    },
-                    _ => Ok(Node::NestedLoopJoin {
+                        });
+                    }
+                    Ok(Node::HashJoin {
                         left,
+                        left_fields,
                         right,
-                        predicate,
+                        right_fields,
+                        null_safe,
+                        predicate: Expression::from_cnf_vec(residual),
                         outer,
-                        left_size,
-                    }),
-                },
+                    })
+                }
                 _ => Ok(n),
             },
             &|n| Ok(n),
         )
     }
 }
+
+impl JoinType {
+    /// 左表的一个字段等于右表的一个字段 返回两边的字段 右表的位置从0开始
+    /// 最后是不是 <=> null和null也相等 也可以作为哈希的key
+    fn join_fields(
+        expr: Expression,
+        left_size: usize,
+    ) -> std::result::Result<(JoinField, JoinField, bool), Expression> {
+        let (lhs, rhs, null_safe) = match expr {
+            Expression::Equal(lhs, rhs) => (lhs, rhs, false),
+            Expression::NullSafeEqual(lhs, rhs) => (lhs, rhs, true),
+            expr => return Err(expr),
+        };
+        match (*lhs, *rhs) {
+            (Expression::Field(i1, l1), Expression::Field(i2, l2))
+                if (i1 < left_size) != (i2 < left_size) =>
+            {
+                if i1 < i2 {
+                    Ok(((i1, l1), (i2 - left_size, l2), null_safe))
+                } else {
+                    Ok(((i2, l2), (i1 - left_size, l1), null_safe))
+                }
+            }
+            (lhs, rhs) if null_safe => Err(Expression::NullSafeEqual(Box::new(lhs), Box::new(rhs))),
+            (lhs, rhs) => Err(Expression::Equal(Box::new(lhs), Box::new(rhs))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::Result;
+    use crate::sql::engine::kv::test_engine;
+    use crate::sql::engine::Engine;
+    use crate::sql::execution::ResultSet;
+    use crate::sql::expression::Expression;
+    use crate::sql::plan::Node;
+    use crate::sql::Value;
+
+    #[test]
+    fn explain_json_test() -> Result<()> {
+        let explain = |sql: &str| -> Result<ResultSet> {
+            let engine = test_engine();
+            let mut session = engine.session()?;
+            session.execute("create table t ( id int primary key, x int index, y string );")?;
+            session.execute("insert into t values (1, 5, \"a\"), (2, 3, \"b\"), (3, 3, \"c\");")?;
+            session.execute(sql)
+        };
+        let text = |sql: &str| -> Result<String> {
+            match explain(sql)? {
+                ResultSet::ExplainJson { json } => Ok(json),
+                r => panic!("unexpected result {:?}", r),
+            }
+        };
+        let json =
+            |sql: &str| -> Result<serde_json::Value> { Ok(serde_json::from_str(&text(sql)?)?) };
+        let sql = "explain (format json) select y from t where x = 3 order by id;";
+        let plan = json(sql)?;
+        let order = &plan["plan"]["Projection"]["source"]["Order"];
+        let lookup = &order["source"]["Projection"]["source"]["IndexLookup"];
+        assert_eq!(lookup["column"], "x");
+        assert_eq!(lookup["values"][0]["Integer"], 3);
+        assert_eq!(plan["estimates"].as_array().map(|e| e.len()), Some(4));
+        // 每次规划的结果都一样 字段按照定义的顺序输出
+        assert_eq!(text(sql)?, text(sql)?);
+
+        let analyze = json("explain (analyze, format json) select y from t where x = 3;")?;
+        assert!(analyze.get("estimates").is_none());
+        assert_eq!(analyze["stats"][0]["rows"], 2);
+
+        let plain = explain("explain (format text) select y from t where x = 3;")?;
+        assert_eq!(plain, explain("explain select y from t where x = 3;")?);
+        assert!(explain("explain (format xml) select y from t;").is_err());
+        assert!(explain("explain (costs) select y from t;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn index_range_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, x int index, y int );")?;
+        session.execute(
+            "insert into t values (1, 5, 1), (2, 3, 2), (3, 8, 3), (4, 1, 4), (5, 10, 5);",
+        )?;
+
+        match session.explain("select id from t where x >= 3 and x < 10 and y > 1;")? {
+            Node::Projection { source, .. } => match *source {
+                Node::Filter { source, .. } => {
+                    assert!(matches!(*source, Node::IndexRangeScan { .. }))
+                }
+                n => panic!("unexpected node {}", n),
+            },
+            n => panic!("unexpected node {}", n),
+        }
+        // 结果按照索引值排序
+        assert_eq!(
+            session.query("select id from t where x >= 3 and x < 10 and y > 1;")?,
+            vec![vec![Value::Integer(2)], vec![Value::Integer(3)]]
+        );
+        assert_eq!(
+            session.query("select id from t where 5 < x;")?,
+            vec![vec![Value::Integer(3)], vec![Value::Integer(5)]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn index_only_scan_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute(
+            "create table t ( id int primary key, x int null default null index, y int );",
+        )?;
+        session.execute("create table u ( id int primary key, x int index ) ttl 3600;")?;
+        session.execute(
+            "insert into t values (1, 5, 1), (2, 3, 2), (3, 8, 3), (4, null, 4), (5, 5, 5);",
+        )?;
+        session.execute("insert into u values (1, 5);")?;
+
+        for (sql, plan) in [
+            (
+                "select id, x from t where x > 4;",
+                "Projection: id, x\n└─ IndexOnlyScan: t column x (4, +inf)",
+            ),
+            (
+                "select id from t where x = 5 or x = 3 or x = 5;",
+                "Projection: id\n└─ IndexOnlyScan: t column x (5, 3, 5)",
+            ),
+            (
+                "select id from t where x = 5 and id > 1;",
+                "Projection: id\n└─ Filter: id > 1\n   └─ IndexOnlyScan: t column x (5)",
+            ),
+            // 用到其他列的时候还要读取行
+            (
+                "select id, y from t where x > 4;",
+                "Projection: id, y\n└─ IndexRangeScan: t column x (4, +inf)",
+            ),
+            // 有过期时间的表要读取行才能判断是否过期
+            ("select id from u where x = 5;", "Projection: id\n└─ IndexLookup: u column x (5)"),
+        ] {
+            assert_eq!(session.explain(sql)?.to_string(), plan, "{}", sql);
+        }
+        let pairs = |pairs: &[(i64, Option<i64>)]| {
+            pairs
+                .iter()
+                .map(|(a, b)| vec![Value::Integer(*a), b.map_or(Value::Null, Value::Integer)])
+                .collect::<Vec<_>>()
+        };
+        // 结果按照索引值排序
+        assert_eq!(
+            session.query("select x, id from t where x >= 5 order by x asc, id asc;")?,
+            pairs(&[(5, Some(1)), (5, Some(5)), (8, Some(3))])
+        );
+        assert_eq!(
+            session.query("select id, x from t where x = 5 or x = 3 or x = 5 order by id asc;")?,
+            pairs(&[(1, Some(5)), (2, Some(3)), (5, Some(5))])
+        );
+        assert_eq!(
+            session.query("select id, x from t where x is null;")?,
+            pairs(&[(4, None)])
+        );
+        // 删除和修改之后的索引也是一样的
+        session.execute("update t set x = 9 where id = 1;")?;
+        session.execute("delete from t where id = 5;")?;
+        assert_eq!(
+            session.query("select id, x + 1 from t where x > 4 order by id asc;")?,
+            pairs(&[(1, Some(10)), (3, Some(9))])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn histogram_index_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, n int index, m int index, y int );")?;
+        // n 有90%的行是1 m 每行都不同
+        let values = (0..100)
+            .map(|i| format!("({}, {}, {}, {})", i, if i < 90 { 1 } else { i }, i, i))
+            .collect::<Vec<_>>();
+        session.execute(&format!("insert into t values {};", values.join(", ")))?;
+
+        // 没有统计信息的时候使用第一个能用的索引
+        let sql = "select * from t where m = 5 and n = 1;";
+        assert_eq!(
+            session.explain(sql)?.to_string(),
+            "Filter: m = 5\n└─ IndexLookup: t column n (1)"
+        );
+        assert_eq!(
+            session.execute("analyze t;")?,
+            ResultSet::Analyze {
+                name: "t".into(),
+                rows: 100
+            }
+        );
+        for (sql, plan) in [
+            (sql, "Filter: n = 1\n└─ IndexLookup: t column m (5)"),
+            // 选择率太高的时候全表扫描
+            ("select * from t where n = 1;", "Scan: t (n = 1)"),
+            ("select * from t where n = 95;", "IndexLookup: t column n (95)"),
+            ("select * from t where m > 5;", "Scan: t (m > 5)"),
+            ("select * from t where m > 95;", "IndexRangeScan: t column m (95, +inf)"),
+            // 主键读取总是最快的
+            ("select * from t where n = 1 and id = 1;", "Filter: n = 1\n└─ KeyLookup: t (1)"),
+        ] {
+            assert_eq!(session.explain(sql)?.to_string(), plan, "{}", sql);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn constant_folder_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, x int );")?;
+
+        // 参数是常量的函数会被折叠 引用了字段的不会
+        let node = session.explain("select abs(-3) + power(2, 2), mod(x, 2) from t;")?;
+        match node {
+            Node::Projection { expressions, .. } => {
+                assert_eq!(expressions[0].0, Expression::Constant(Value::Float(7.0)));
+                assert!(matches!(expressions[1].0, Expression::ScalarFn(..)));
+            }
+            node => panic!("unexpected node {:?}", node),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn noop_cleaner_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, x int );")?;
+        session.execute("create table u ( id int primary key, t int );")?;
+        session.execute("insert into t values (1, 1), (2, 4);")?;
+        session.execute("insert into u values (1, 1), (2, 1), (3, 2);")?;
+
+        // 恒为true的条件和常量计算在执行计划中消失
+        for (sql, plan) in [
+            ("select id from t where 1 = 1;", "Projection: id\n└─ Scan: t columns #0"),
+            (
+                "select id from t where x > 1 + 2 and 1 = 1;",
+                "Projection: id\n└─ Scan: t (x > 3)",
+            ),
+            (
+                "select id * (2 + 3) from t where false or x = 1;",
+                "Projection: id * 5\n└─ Scan: t (x = 1)",
+            ),
+            // null 不能化简
+            (
+                "select id from t where null and x = 1;",
+                "Projection: id\n└─ Scan: t (NULL AND x = 1)",
+            ),
+            (
+                "select t.id from t join u on t.id = u.t where 2 > 1;",
+                "Projection: t.id\n└─ HashJoin: inner on t.id = u.t\n   ├─ Scan: t columns #0\n   └─ Scan: u columns #1",
+            ),
+        ] {
+            assert_eq!(session.explain(sql)?.to_string(), plan, "{}", sql);
+        }
+
+        // hash join 右表同一个值有多行的时候都要连接上
+        let mut rows = session.query("select t.id, u.id from t join u on t.id = u.t where 1 = 1;")?;
+        rows.sort_by_key(|r| format!("{:?}", r));
+        assert_eq!(
+            rows,
+            [(1, 1), (1, 2), (2, 3)]
+                .iter()
+                .map(|(a, b)| vec![Value::Integer(*a), Value::Integer(*b)])
+                .collect::<Vec<_>>()
+        );
+        Ok(())
+    }
+    #[test]
+    fn column_pruning_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute(
+            "create table t ( id int primary key, a string, b int, c string null default null );",
+        )?;
+        session.execute("create table u ( id int primary key, t int, d string );")?;
+        session.execute("insert into t values (1, \"x\", 10, \"p\"), (2, \"y\", 20, null);")?;
+        session.execute("insert into u values (1, 2, \"q\");")?;
+
+        // scan 只读取投影 过滤 排序中用到的列
+        for (sql, plan) in [
+            (
+                "select a from t where b > 5 order by id asc;",
+                "Projection: #0\n└─ Order: #1 asc nulls first\n   └─ Projection: a, id\n      └─ Scan: t (b > 5) columns #0,#1,#2",
+            ),
+            ("select * from t;", "Scan: t"),
+            (
+                "select count(id) from t;",
+                "Projection: #0\n└─ Aggregation: Count\n   └─ Projection: id\n      └─ Scan: t columns #0",
+            ),
+            (
+                "select u.d from t join u on t.id = u.t;",
+                "Projection: u.d\n└─ HashJoin: inner on t.id = u.t\n   ├─ Scan: t columns #0\n   └─ Scan: u columns #1,#2",
+            ),
+            // 更新和删除需要整行
+            ("delete from t where b = 10;", "Delete: t\n└─ Scan: t (b = 10)"),
+        ] {
+            assert_eq!(session.explain(sql)?.to_string(), plan, "{}", sql);
+        }
+        let string = |s: &str| Value::String(s.to_string());
+        assert_eq!(
+            session.query("select a, c from t where b > 5 order by id asc;")?,
+            vec![vec![string("x"), string("p")], vec![string("y"), Value::Null]]
+        );
+        assert_eq!(
+            session.query("select t.a, u.d from t join u on t.id = u.t;")?,
+            vec![vec![string("y"), string("q")]]
+        );
+        // 更新之后没有用到的列也保持原来的值
+        session.execute("update t set b = 30 where id = 1;")?;
+        assert_eq!(
+            session.query("select * from t where id = 1;")?,
+            vec![vec![Value::Integer(1), string("x"), Value::Integer(30), string("p")]]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn multi_key_hash_join_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, a int, b int null default null );")?;
+        session.execute("create table u ( id int primary key, a int, b int null default null );")?;
+        session.execute("insert into t values (1, 1, 1), (2, 1, 2), (3, 2, null);")?;
+        session.execute("insert into u values (1, 1, 1), (2, 1, 2), (3, 1, 2), (4, 2, null);")?;
+
+        // 所有左右字段相等的子句都作为key 其他的子句在匹配之后计算
+        for (sql, plan) in [
+            (
+                "select t.id from t join u on t.a = u.a and u.b = t.b;",
+                "Projection: t.id\n└─ HashJoin: inner on t.a = u.a and t.b = u.b\n   ├─ Scan: t\n   └─ Scan: u columns #1,#2",
+            ),
+            (
+                "select t.id from t left join u on t.a = u.a and t.b = u.b and t.id < u.id;",
+                "Projection: t.id\n└─ HashJoin: left outer on t.a = u.a and t.b = u.b filter t.id < u.id\n   ├─ Scan: t\n   └─ Scan: u",
+            ),
+            (
+                "select t.id from t join u on t.a = u.a and t.b is not distinct from u.b;",
+                "Projection: t.id\n└─ HashJoin: inner on t.a = u.a and t.b <=> u.b\n   ├─ Scan: t\n   └─ Scan: u columns #1,#2",
+            ),
+        ] {
+            assert_eq!(session.explain(sql)?.to_string(), plan, "{}", sql);
+        }
+        let pairs = |pairs: &[(i64, Option<i64>)]| {
+            pairs
+                .iter()
+                .map(|(a, b)| vec![Value::Integer(*a), b.map_or(Value::Null, Value::Integer)])
+                .collect::<Vec<_>>()
+        };
+        // key中有null的行不会匹配
+        assert_eq!(
+            session.query(
+                "select t.id, u.id from t join u on t.a = u.a and t.b = u.b \
+                 order by t.id asc, u.id asc;"
+            )?,
+            pairs(&[(1, Some(1)), (2, Some(2)), (2, Some(3))])
+        );
+        // 剩下的条件不满足的时候 外连接仍然补null
+        assert_eq!(
+            session.query(
+                "select t.id, u.id from t left join u on t.a = u.a and t.b = u.b and u.id > 2 \
+                 order by t.id asc, u.id asc;"
+            )?,
+            pairs(&[(1, None), (2, Some(3)), (3, None)])
+        );
+        // <=> 的字段两边都是null也能匹配
+        assert_eq!(
+            session.query(
+                "select t.id, u.id from t join u on t.a = u.a and t.b <=> u.b \
+                 order by t.id asc, u.id asc;"
+            )?,
+            pairs(&[(1, Some(1)), (2, Some(2)), (2, Some(3)), (3, Some(4))])
+        );
+        assert_eq!(
+            session.query(
+                "select t.id, u.id from t join u on t.a = u.a and t.b is distinct from u.b \
+                 order by t.id asc, u.id asc;"
+            )?,
+            pairs(&[(1, Some(2)), (1, Some(3)), (2, Some(1))])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn self_join_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute(
+            "create table emp ( id int primary key, boss int null default null, age int );",
+        )?;
+        session.execute(
+            "insert into emp values (1, null, 50), (2, 1, 30), (3, 1, 40), (4, 2, 20);",
+        )?;
+
+        // 同一个表的两个别名各自下推自己的条件
+        let sql = "select a.id, b.id from emp a join emp b on a.boss = b.id \
+                   where a.age < 35 and b.id > 1;";
+        assert_eq!(
+            session.explain(sql)?.to_string(),
+            "Projection: a.id, b.id\n└─ HashJoin: inner on a.boss = b.id\n   \
+             ├─ Scan: emp as a (a.age < 35)\n   \
+             └─ Scan: emp as b (b.id > 1) columns #0"
+        );
+        let pairs = |pairs: &[(i64, Option<i64>)]| {
+            pairs
+                .iter()
+                .map(|(a, b)| vec![Value::Integer(*a), b.map_or(Value::Null, Value::Integer)])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(session.query(sql)?, pairs(&[(4, Some(2))]));
+        assert_eq!(
+            session.query(
+                "select a.id, b.id from emp a left join emp b on a.boss = b.id \
+                 order by a.id asc;"
+            )?,
+            pairs(&[(1, None), (2, Some(1)), (3, Some(1)), (4, Some(2))])
+        );
+        // 主键的等值条件只作用在其中一个别名上
+        assert_eq!(
+            session.query(
+                "select a.id, b.age from emp a join emp b on a.boss = b.id where b.id = 1 \
+                 order by a.id asc;"
+            )?,
+            pairs(&[(2, Some(50)), (3, Some(50))])
+        );
+        Ok(())
+    }
+}
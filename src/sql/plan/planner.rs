@@ -4,22 +4,50 @@ use log::debug;
 
 use crate::sql::{
     expression::{self, Expression},
-    parser::ast::{BaseExpression, FromItem, JoinType, Operation, Statement},
+    parser::ast::{
+        BaseExpression, FromItem, JoinType, Operation, SelectItems, SqlConflictAction,
+        SqlOnConflict, Statement,
+    },
     plan::Aggregate,
     schema::Catalog,
-    Column, OrderType, Table,
+    Check, Column, NullOrder, OrderType, Policy, Reference, Table, Value,
 };
 
-use super::{Node, Plan};
-use crate::errors::{Error, Result};
+use super::{Node, OnConflict, Outer, Plan, Returning};
+use crate::errors::{Error, ErrorCode, Result};
+
+/// on conflict do update 中要插入的行的表名
+const EXCLUDED: &str = "excluded";
+
+/// 会话中影响规划的设置
+#[derive(Clone, Debug, Default)]
+pub struct Context {
+    /// 是否给查询的表加上行级安全策略 管理员的会话不加
+    pub policies: bool,
+    /// SET 设置的自定义变量 CURRENT_SETTING 读取 None的时候不计算 保留成函数
+    pub settings: Option<HashMap<String, Value>>,
+    /// SET integer_division 打开之后 / 和 DIV 一样是整除
+    pub integer_division: bool,
+}
 
 pub struct Planner<'a> {
     catalog: &'a dyn Catalog,
+    /// 规划子查询的时候是外层查询的作用域
+    outer: Option<Scope>,
+    context: Context,
 }
 
 impl<'a> Planner<'a> {
     pub fn new(catalog: &'a dyn Catalog) -> Self {
-        Self { catalog }
+        Self::with_context(catalog, Context::default())
+    }
+
+    pub fn with_context(catalog: &'a dyn Catalog, context: Context) -> Self {
+        Self {
+            catalog,
+            outer: None,
+            context,
+        }
     }
 
     pub fn build_plan(&mut self, statement: Statement) -> Result<Plan> {
@@ -27,23 +55,53 @@ impl<'a> Planner<'a> {
         Ok(Plan::new(node))
     }
 
+    /// 计算一个常量表达式 比如SET的值
+    pub fn build_constant(&self, expression: BaseExpression) -> Result<Value> {
+        self.build_expresion(&Scope::constant(), expression)?
+            .evaluate(None)
+    }
+
     pub fn build_node(&mut self, statement: Statement) -> Result<Node> {
         match statement {
             Statement::Begin { .. }
             | Statement::Commit
             | Statement::Rollback
-            | Statement::Explain(_) => {
+            | Statement::Savepoint(_)
+            | Statement::RollbackTo(_)
+            | Statement::Release(_)
+            | Statement::Set { .. }
+            | Statement::Explain { .. } => {
                 return Err(Error::Plan(format!(
                     "get unexpected statement: {:?}",
                     statement
                 )));
             }
 
-            Statement::CreateTable { name, columns } => {
-                // 在 column 中有default字段类型是BaseExpression，其实default字段应该是一个Constant常量
+            Statement::CreateTable {
+                name,
+                columns,
+                checks,
+                ttl,
+            } => {
+                // default 保存成表达式 每次插入的时候再计算 所以不能引用任何列
                 let mut set = HashSet::new();
-                // 首先分离出<column,Expression>
-                // expression后续会进行常量计算，所以先提取出来，Cloumn中先置为None
+                // 自引用的外键没有写引用字段时 使用当前表的主键
+                let own_key = columns
+                    .iter()
+                    .find(|c| c.primary_key)
+                    .map(|c| c.name.clone());
+                // 约束的名字 列上的是 表_列_check 表上的是 表_check 多个的时候后面加上序号
+                let mut named = columns
+                    .iter()
+                    .filter_map(|c| {
+                        let check = c.check.clone()?;
+                        Some((format!("{}_{}_check", name, c.name), check))
+                    })
+                    .collect::<Vec<_>>();
+                named.extend(checks.into_iter().enumerate().map(|(i, check)| match i {
+                    0 => (format!("{}_check", name), check),
+                    i => (format!("{}_check{}", name, i), check),
+                }));
                 let columns = columns
                     .into_iter()
                     .map(|c| {
@@ -58,31 +116,156 @@ impl<'a> Planner<'a> {
                                 c.name
                             )));
                         }
+                        let references = c
+                            .references
+                            .map(|r| {
+                                let column = match r.column {
+                                    Some(column) => column,
+                                    None if r.table == name => own_key.clone().ok_or_else(|| {
+                                        Error::Plan(format!("table {} has no primary key", name))
+                                    })?,
+                                    None => self
+                                        .catalog
+                                        .must_read_table(&r.table)?
+                                        .columns
+                                        .into_iter()
+                                        .find(|c| c.primary_key)
+                                        .ok_or_else(|| {
+                                            Error::Plan(format!(
+                                                "table {} has no primary key",
+                                                r.table
+                                            ))
+                                        })?
+                                        .name,
+                                };
+                                Result::Ok(Reference {
+                                    table: r.table,
+                                    column,
+                                    on_delete: r.on_delete,
+                                })
+                            })
+                            .transpose()?;
                         let column = Column {
                             name: c.name,
                             column_type: c.column_type,
                             primary_key: c.primary_key,
                             nullable: c.nullable.unwrap_or_else(|| false),
-                            default: None,
+                            default,
                             unique: c.unique,
                             index: c.index,
+                            references,
                         };
-                        Result::Ok((column, default))
+                        Result::Ok(column)
                     })
-                    .collect::<Result<Vec<(Column, Option<Expression>)>>>()?;
-                // 将columns和defaults进行分离
-                let (columns, defaults): (Vec<Column>, Vec<Option<Expression>>) =
-                    columns.into_iter().unzip();
-                let table = Table { name, columns };
-                Ok(Node::CreateTable { table, defaults })
+                    .collect::<Result<Vec<Column>>>()?;
+                let mut table = Table {
+                    name,
+                    columns,
+                    checks: Vec::new(),
+                    ttl,
+                    policies: Vec::new(),
+                    statistics: None,
+                };
+                // 约束可以引用表中的所有列
+                let mut scope = Scope::new();
+                scope.register_table(table.clone(), None)?;
+                table.checks = named
+                    .into_iter()
+                    .map(|(name, check)| {
+                        Ok(Check {
+                            name,
+                            expression: self.build_expresion(&scope, check)?,
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(Node::CreateTable { table })
             }
 
+            Statement::CreateTableAs { name, query } => {
+                if self.catalog.read_table(&name)?.is_some() {
+                    let message = format!("table {} already exists", name);
+                    let error = Error::sql(ErrorCode::DuplicateTable, message);
+                    return Err(error.with_identifier(&name));
+                }
+                Ok(Node::CreateTableAs {
+                    table: name,
+                    source: Box::new(self.build_node(*query)?),
+                })
+            }
             Statement::DropTable(table_name) => Ok(Node::DropTable { table: table_name }),
 
+            Statement::CreatePolicy {
+                name,
+                table,
+                expression,
+            } => {
+                self.require_admin("CREATE POLICY")?;
+                let table = self.catalog.must_read_table(&table)?;
+                let mut scope = Scope::new();
+                scope.register_table(table.clone(), None)?;
+                // 保存的策略中 CURRENT_SETTING 不计算 每次查询的时候读取当时会话的设置
+                let settings = self.context.settings.take();
+                let expression = self.build_expresion(&scope, expression);
+                self.context.settings = settings;
+                Ok(Node::CreatePolicy {
+                    table: table.name,
+                    policy: Policy {
+                        name,
+                        expression: expression?,
+                    },
+                })
+            }
+            Statement::DropPolicy { name, table } => {
+                self.require_admin("DROP POLICY")?;
+                self.catalog.must_read_table(&table)?;
+                Ok(Node::DropPolicy { table, name })
+            }
+
+            Statement::Truncate(table) => {
+                let definition = self.catalog.must_read_table(&table)?;
+                // 有策略的时候只删除会话能看到的行 和不带条件的 delete 一样
+                match self.build_policy(&definition)? {
+                    Some(policy) => Ok(Node::Delete {
+                        table: table.clone(),
+                        source: Box::new(Node::Scan {
+                            table,
+                            alias: None,
+                            filter: Some(policy),
+                            columns: None,
+                        }),
+                        returning: None,
+                    }),
+                    None => Ok(Node::Truncate { table }),
+                }
+            }
+
+            Statement::CheckTable(table) => {
+                self.catalog.must_read_table(&table)?;
+                Ok(Node::CheckTable { table })
+            }
+
+            Statement::Analyze(table) => {
+                self.catalog.must_read_table(&table)?;
+                Ok(Node::Analyze { table })
+            }
+
+            // 备份和恢复读写所有的行 不受行级安全策略的限制
+            Statement::Backup(path) => {
+                self.require_admin("BACKUP")?;
+                Ok(Node::Backup { path })
+            }
+
+            Statement::Restore(path) => {
+                self.require_admin("RESTORE")?;
+                Ok(Node::Restore { path })
+            }
+
             Statement::Insert {
                 table,
                 columns,
                 values,
+                on_conflict,
+                returning,
             } => {
                 let table_name = table.clone();
                 // 得到table
@@ -96,8 +279,9 @@ impl<'a> Planner<'a> {
                         .map(|c| c.name.clone())
                         .collect::<Vec<String>>(),
                 };
+                let policy = self.build_policy(&table)?;
                 let mut scope = Scope::new();
-                scope.register_table(table)?;
+                scope.register_table(table, None)?;
 
                 // 检查一下这些column是否存在
                 for ele in columns.iter() {
@@ -116,42 +300,105 @@ impl<'a> Planner<'a> {
                         )
                     })
                     .collect::<Result<Vec<Vec<Expression>>>>()?;
+                // 冲突检测只支持主键 更新的表达式可以引用已经存在的行和要插入的行
+                let on_conflict = match on_conflict {
+                    Some(SqlOnConflict { column, action }) => {
+                        let index = *scope.get_column_index(Some(table_name.clone()), column.clone())?;
+                        let definition = self.catalog.must_read_table(&table_name)?;
+                        if index != definition.get_key_index()? {
+                            return Err(Error::Plan(format!(
+                                "on conflict column {} is not primary key of table {}",
+                                column, table_name
+                            )));
+                        }
+                        let mut excluded = scope.clone();
+                        let names = definition.columns.iter().map(|c| c.name.clone());
+                        excluded.register_excluded(&names.collect::<Vec<_>>());
+                        Some(match action {
+                            SqlConflictAction::Nothing => OnConflict::Nothing,
+                            SqlConflictAction::Update(set) => OnConflict::Update(
+                                set.into_iter()
+                                    .map(|(k, v)| {
+                                        let index =
+                                            *scope.get_column_index(Some(table_name.clone()), k)?;
+                                        Result::Ok((index, self.build_expresion(&excluded, v)?))
+                                    })
+                                    .collect::<Result<Vec<_>>>()?,
+                            ),
+                        })
+                    }
+                    None => None,
+                };
                 // 后续会对常量统一进行计算，这里就不进行了
                 Ok(Node::Insert {
                     table: table_name,
                     columns,
                     expressions: values,
+                    on_conflict,
+                    returning: self.build_returning(&scope, returning)?,
+                    policy,
                 })
             }
-            Statement::Delete { table, filter } => {
+            Statement::Delete {
+                table,
+                filter,
+                returning,
+            } => {
                 let mut scope = Scope::new();
-                scope.register_table(self.catalog.must_read_table(table.as_str())?)?;
+                let definition = self.catalog.must_read_table(table.as_str())?;
+                let policy = self.build_policy(&definition)?;
+                scope.register_table(definition, None)?;
                 let filter = match filter {
                     Some(expr) => Some(self.build_expresion(&scope, expr)?),
                     None => None,
                 };
+                // 看不到的行也不能修改
+                let filter = match (filter, policy) {
+                    (Some(filter), Some(policy)) => {
+                        Some(Expression::And(Box::new(filter), Box::new(policy)))
+                    }
+                    (filter, policy) => filter.or(policy),
+                };
                 Ok(Node::Delete {
                     table: table.clone(),
                     source: Box::new(Node::Scan {
                         table,
                         alias: None,
                         filter,
+                        columns: None,
                     }),
+                    returning: self.build_returning(&scope, returning)?,
                 })
             }
-            Statement::Update { table, set, filter } => {
+            Statement::Update {
+                table,
+                set,
+                filter,
+                returning,
+            } => {
                 let mut scope = Scope::new();
-                scope.register_table(self.catalog.must_read_table(table.as_str())?)?;
+                let definition = self.catalog.must_read_table(table.as_str())?;
+                let policy = self.build_policy(&definition)?;
+                scope.register_table(definition, None)?;
                 let filter = match filter {
                     Some(expr) => Some(self.build_expresion(&scope, expr)?),
                     None => None,
                 };
+                // 看不到的行也不能修改 修改之后的行也要满足策略
+                let check = policy.clone();
+                let filter = match (filter, policy) {
+                    (Some(filter), Some(policy)) => {
+                        Some(Expression::And(Box::new(filter), Box::new(policy)))
+                    }
+                    (filter, policy) => filter.or(policy),
+                };
 
+                // 新的值可以引用修改之前的列 例如 set id = id + 1
                 let set = set
                     .into_iter()
                     .map(|(k, v)| {
                         let index = scope.get_column_index(Some(table.clone()), k)?.to_owned();
-                        Result::Ok((index, self.build_expresion(&Scope::constant(), v)?))
+                        Result::Ok((index, self.build_expresion(&scope, v)?))
                     })
                     .collect::<Result<Vec<_>>>()?;
                 Ok(Node::Update {
@@ -160,12 +407,15 @@ impl<'a> Planner<'a> {
                         table,
                         alias: None,
                         filter,
+                        columns: None,
                     }),
                     set,
+                    returning: self.build_returning(&scope, returning)?,
+                    policy: check,
                 })
             }
             Statement::Select {
-                mut select,
+                select,
                 from,
                 filter,
                 group_by,
@@ -175,12 +425,17 @@ impl<'a> Planner<'a> {
                 limit,
             } => {
                 // 从from中获取from
+                // 子查询中找不到的列去外层查询中找
+                let outer = self.outer.clone().map(Box::new);
                 let (mut node, mut scope) = if let Some(from) = from {
                     let mut scope = Scope::new();
+                    scope.outer = outer;
                     (self.build_from_table(&mut scope, from)?, scope)
                 } else if !select.is_empty() {
                     // 如果from是none ，但是select不是就返回noting
-                    (Node::Nothing, Scope::new())
+                    let mut scope = Scope::new();
+                    scope.outer = outer;
+                    (Node::Nothing, scope)
                 } else {
                     // 啥也没有就报错
                     return Err(Error::Plan(format!("get select and from empty")));
@@ -194,6 +449,9 @@ impl<'a> Planner<'a> {
                     };
                 }
 
+                // select 列表中的 * 和 t.* 展开成具体的列
+                let mut select = self.expand_wildcards(&scope, select)?;
+
                 // select * where .... group_by ...
                 // 这种情况不允许出现
                 if select.is_empty() && !group_by.is_empty() {
@@ -202,29 +460,60 @@ impl<'a> Planner<'a> {
                     ));
                 }
 
-                // 设置需要隐藏的数目
-                let mut hidden = 0;
+                // order by 加到 select 中的列在 select 中的位置 排序之后去掉
+                let mut hidden = Vec::new();
 
                 // 开始解析select
                 if !select.is_empty() {
-                    // having orderby 需要
-                    if let Some(ref mut expr) = having {
-                        hidden += self.transform_and_inject_hidden(expr, &mut select)?;
-                    }
-                    for (expr, _) in order.iter_mut() {
-                        hidden += self.transform_and_inject_hidden(expr, &mut select)?;
+                    // group by 中的别名先换成select中的表达式 之后都按照表达式匹配
+                    let group_by = self.resolve_group_by(&select, group_by)?;
+                    // orderby 需要
+                    for (expr, _, _) in order.iter_mut() {
+                        let select = &mut select;
+                        self.transform_and_inject_hidden(expr, select, &group_by, &mut hidden)?;
                     }
 
                     // 将函数和group by提取出来 这两个需要单独生成node节点
-                    let aggregates = self.extract_aggreates(&mut select)?;
+                    let mut aggregates = self.extract_aggreates(&mut select)?;
+                    // having 在聚合之后 投影之前执行
+                    // 所以having中的聚合函数也要交给聚合节点计算 必须在group by之前提取
+                    if let Some(ref mut expr) = having {
+                        self.transform_having(expr, &select, &mut aggregates)?;
+                    }
                     let gourps = self.extract_group_by(aggregates.len(), &mut select, group_by)?;
+                    // having 中和group by一样的表达式 直接使用聚合节点输出的列
+                    if let Some(ref mut expr) = having {
+                        for (i, (group, _)) in gourps.iter().enumerate() {
+                            let column = BaseExpression::Column(aggregates.len() + i);
+                            expr.transform_ref(
+                                &mut |e| Ok(if &e == group { column.clone() } else { e }),
+                                &mut |e| Ok(e),
+                            )?;
+                        }
+                    }
 
                     // 如果有group_by aggregates 则需要构建聚合函数的node
                     if aggregates.len() > 0 || gourps.len() > 0 {
                         node = self.build_aggregates(&mut scope, aggregates, gourps, node)?;
+                        // 聚合之后只剩下聚合结果和group by的列 其他的列要明确报错
+                        for (expr, _) in select.iter() {
+                            self.check_grouped(&scope, expr)?;
+                        }
+                        if let Some(ref expr) = having {
+                            self.check_grouped(&scope, expr)?;
+                        }
+                    }
+
+                    if let Some(having) = having.take() {
+                        node = Node::Having {
+                            source: Box::new(node),
+                            predicate: self.build_expresion(&scope, having)?,
+                        };
                     }
 
                     // 最后终于可以构建select了 就是建立一个投影
+                    // 只有这里可以使用子查询 投影的时候对每一行执行
+                    scope.subquery = true;
                     let expressions: Vec<(Expression, Option<String>)> = select
                         .into_iter()
                         .map(|(e, l)| Ok((self.build_expresion(&scope, e)?, l)))
@@ -236,8 +525,9 @@ impl<'a> Planner<'a> {
                         expressions,
                     };
                 }
+                // select * 的情况 having 就是对原始数据的过滤
                 if let Some(having) = having {
-                    node = Node::Filter {
+                    node = Node::Having {
                         source: Box::new(node),
                         predicate: self.build_expresion(&scope, having)?,
                     }
@@ -248,33 +538,32 @@ impl<'a> Planner<'a> {
                         source: Box::new(node),
                         orders: order
                             .into_iter()
-                            .map(|(expr, order_type)| {
-                                Result::Ok((self.build_expresion(&scope, expr)?, order_type))
+                            .map(|(expr, order_type, nulls)| {
+                                Result::Ok((self.build_expresion(&scope, expr)?, order_type, nulls))
                             })
-                            .collect::<Result<Vec<(Expression, OrderType)>>>()?,
+                            .collect::<Result<Vec<(Expression, OrderType, NullOrder)>>>()?,
                     }
                 }
 
-                if let Some(offset) = offset {
-                    node = Node::Offset {
-                        source: Box::new(node),
-                        offset: self.build_expresion(&Scope::constant(), offset)?,
-                    }
-                }
-
-                if let Some(limit) = limit {
+                // limit 和 offset 放在同一个节点 保证先offset再limit
+                if offset.is_some() || limit.is_some() {
                     node = Node::Limit {
                         source: Box::new(node),
-                        limit: self.build_expresion(&Scope::constant(), limit)?,
+                        offset: offset
+                            .map(|e| self.build_expresion(&Scope::constant(), e))
+                            .transpose()?,
+                        limit: limit
+                            .map(|e| self.build_expresion(&Scope::constant(), e))
+                            .transpose()?,
                     }
                 }
 
-                // 这里进行投影把后面hidden删除
-                if hidden > 0 {
+                // 这里进行投影把hidden的列删除
+                if !hidden.is_empty() {
                     node = Node::Projection {
                         source: Box::new(node),
-                        expressions: (0..scope.get_column_size() - hidden)
-                            .into_iter()
+                        expressions: (0..scope.get_column_size())
+                            .filter(|index| !hidden.contains(index))
                             .map(|index| (Expression::Field(index, None), None))
                             .collect(),
                     }
@@ -299,8 +588,16 @@ impl<'a> Planner<'a> {
         let mut expressions = Vec::new();
 
         for (agg, expr) in aggregate {
+            // 被聚合的列名用来给聚合结果命名 例如 stddev(age)
+            // count(*) 解析出来是常量true
+            let label = match &expr {
+                BaseExpression::Value(crate::sql::Value::Bool(true)) if agg == Aggregate::Count => {
+                    "*".to_string()
+                }
+                _ => self.build_expresion(scope, expr.clone())?.to_string(),
+            };
             aggregates.push(agg);
-            expressions.push((self.build_expresion(scope, expr)?, None));
+            expressions.push((self.build_expresion(scope, expr)?, Some(label)));
         }
 
         for (expr, label) in group_by {
@@ -338,6 +635,21 @@ impl<'a> Planner<'a> {
         })
     }
 
+    /// 拆出聚合函数和它唯一的参数
+    fn aggregate_arg(&self, expr: BaseExpression) -> Result<(Aggregate, BaseExpression)> {
+        match expr {
+            BaseExpression::Function(f, mut args) if args.len() == 1 => {
+                Ok((Aggregate::from_str(&f)?, args.remove(0)))
+            }
+            BaseExpression::Function(f, args) => Err(Error::Plan(format!(
+                "aggregate function {} expect 1 argument but get {}",
+                f,
+                args.len()
+            ))),
+            e => Err(Error::Plan(format!("expect aggregate function but get {:?}", e))),
+        }
+    }
+
     /// 将聚合函数提取出来
     fn extract_aggreates(
         &self,
@@ -348,9 +660,10 @@ impl<'a> Planner<'a> {
             expr.transform_ref(
                 &mut |e| {
                     Ok(match e {
-                        BaseExpression::Function(f, exprx) => {
-                            let aggregate = Aggregate::from_str(f.as_str())?;
-                            res.push((aggregate, *exprx));
+                        // 标量函数不需要提取 它的参数中还可能有聚合函数
+                        e @ BaseExpression::Function(..) if e.is_aggreate() => {
+                            let (aggregate, arg) = self.aggregate_arg(e)?;
+                            res.push((aggregate, arg));
                             BaseExpression::Column(res.len() - 1)
                         }
                         _ => e,
@@ -369,62 +682,164 @@ impl<'a> Planner<'a> {
         Ok(res)
     }
 
+    /// 转换having 使其可以直接作用于聚合节点的输出
+    /// 1. select中的别名替换为对应的表达式 例如 select sum(x) total ... having total > 10
+    /// 2. 聚合函数替换为聚合节点的输出列 select中没有的聚合函数追加到aggregates中
+    fn transform_having(
+        &self,
+        expr: &mut BaseExpression,
+        select: &[(BaseExpression, Option<String>)],
+        aggregates: &mut Vec<(Aggregate, BaseExpression)>,
+    ) -> Result<()> {
+        let alias = |e: BaseExpression| -> Result<BaseExpression> {
+            Ok(match e {
+                BaseExpression::Field(None, ref label) => {
+                    match select.iter().find(|(_, l)| l.as_ref() == Some(label)) {
+                        Some((expr, _)) => expr.clone(),
+                        None => e,
+                    }
+                }
+                _ => e,
+            })
+        };
+        expr.transform_ref(
+            &mut |e| {
+                Ok(match e {
+                    e @ BaseExpression::Field(None, _) => alias(e)?,
+                    e @ BaseExpression::Function(..) if e.is_aggreate() => {
+                        let (aggregate, mut arg) = self.aggregate_arg(e)?;
+                        // 参数中也可以使用别名 sum(a)
+                        arg.transform_ref(&mut |e| alias(e), &mut |e| Ok(e))?;
+                        // 参数中有Column说明引用了聚合函数的别名
+                        if arg.contains(&|e| e.is_aggreate() || matches!(e, BaseExpression::Column(_)))
+                        {
+                            return Err(Error::Plan(
+                                "not support for aggregate function reference aggregate"
+                                    .to_string(),
+                            ));
+                        }
+                        // 和select中一样的聚合函数直接复用
+                        let index = match aggregates
+                            .iter()
+                            .position(|(a, e)| a == &aggregate && e == &arg)
+                        {
+                            Some(index) => index,
+                            None => {
+                                aggregates.push((aggregate, arg));
+                                aggregates.len() - 1
+                            }
+                        };
+                        BaseExpression::Column(index)
+                    }
+                    _ => e,
+                })
+            },
+            &mut |e| Ok(e),
+        )
+    }
+
+    /// group by 中的别名替换为select中对应的表达式 去掉重复的表达式
+    /// 找不到别名的时候当作普通的列 select g ... group by g
+    fn resolve_group_by(
+        &self,
+        select: &[(BaseExpression, Option<String>)],
+        group_by: Vec<BaseExpression>,
+    ) -> Result<Vec<BaseExpression>> {
+        let mut groups: Vec<BaseExpression> = Vec::new();
+        for group in group_by.into_iter() {
+            let group = match &group {
+                BaseExpression::Field(None, label) => select
+                    .iter()
+                    .find(|(_, l)| l.as_ref() == Some(label))
+                    .map(|(expr, _)| expr.clone())
+                    .unwrap_or(group),
+                _ => group,
+            };
+            if group.contains_aggreate() {
+                return Err(Error::Plan(format!(
+                    "group by cannot contain aggregate function: {:?}",
+                    group
+                )));
+            }
+            if !groups.contains(&group) {
+                groups.push(group);
+            }
+        }
+        Ok(groups)
+    }
+
+    /// 把select中和group by一样的表达式替换为聚合节点输出的列
+    /// 返回的每个group by会带上select中对应的别名 上层节点就可以通过别名拿到这一列
     fn extract_group_by(
         &self,
         offset: usize,
         select: &mut Vec<(BaseExpression, Option<String>)>,
         group_by: Vec<BaseExpression>,
     ) -> Result<Vec<(BaseExpression, Option<String>)>> {
-        let mut groups = Vec::new();
-        for group in group_by.into_iter() {
-            // 如果是label说明需要在select中找
-            if let BaseExpression::Field(None, label) = &group {
-                if let Some(index) = select
+        let groups = group_by
+            .into_iter()
+            .map(|group| {
+                let label = select
                     .iter()
-                    .position(|(_, l)| l == &Some(label.to_string()))
-                {
-                    // 在select中的expr找到之后替代select
-                    let (expr, label) = select.get_mut(index).unwrap();
-                    let swap = BaseExpression::Column(offset + groups.len());
-                    let expr = std::mem::replace(expr, swap);
-                    groups.push((expr, label.clone()));
-                    continue;
-                } else {
-                    return Err(Error::Plan(format!(
-                        "can't find label of group_by : {}",
-                        label
-                    )));
-                }
-            }
-            // 没有label 查看是否有 表达式一致的
-            if let Some(index) = select.iter().position(|(e, _)| e == &group) {
-                // 在select中的expr找到之后替代select
-                let (expr, label) = select.get_mut(index).unwrap();
-                let swap = BaseExpression::Column(offset + groups.len());
-                let expr = std::mem::replace(expr, swap);
-                groups.push((expr, label.clone()));
-                continue;
-            }
-            // 和select中毫无关系的话 就直接插入进去
-            groups.push((group, None));
-        }
+                    .find(|(e, _)| e == &group)
+                    .and_then(|(_, l)| l.clone());
+                (group, label)
+            })
+            .collect::<Vec<_>>();
 
-        //  查看group中是否有聚合操作
-        for (expr, _) in &groups {
-            if expr.contains_aggreate() {
-                return Err(Error::Plan("group cannot contain aggregates".into()));
-            }
+        // 子表达式也要替换 select g * 2 + 1 ... group by g * 2
+        for (expr, _) in select.iter_mut() {
+            expr.transform_ref(
+                &mut |e| {
+                    Ok(match groups.iter().position(|(g, _)| g == &e) {
+                        Some(i) => BaseExpression::Column(offset + i),
+                        None => e,
+                    })
+                },
+                &mut |e| Ok(e),
+            )?;
         }
-
         Ok(groups)
     }
 
+    /// 聚合之后还没有被替换的列 说明既不在group by中也不在聚合函数中
+    fn check_grouped(&self, scope: &Scope, expr: &BaseExpression) -> Result<()> {
+        let mut missing = None;
+        expr.clone().transform(
+            &mut |e| {
+                if let BaseExpression::Field(table, name) = &e {
+                    // 有歧义的列交给后面构建表达式的时候报错
+                    let ambiguous = table.is_none() && scope.ambiguous.contains(name);
+                    if missing.is_none()
+                        && !ambiguous
+                        && scope.get_column_index(table.clone(), name.clone()).is_err()
+                    {
+                        missing = Some(match table {
+                            Some(table) => format!("{}.{}", table, name),
+                            None => name.clone(),
+                        });
+                    }
+                }
+                Ok(e)
+            },
+            &mut |e| Ok(e),
+        )?;
+        match missing {
+            Some(column) => Err(Error::Plan(format!(
+                "column {} must appear in group by or be used in an aggregate function",
+                column
+            ))),
+            None => Ok(()),
+        }
+    }
+
     fn transform_and_inject_hidden(
         &mut self,
         expr: &mut BaseExpression,
         select: &mut Vec<(BaseExpression, Option<String>)>,
-    ) -> Result<usize> {
-        let mut hidden = 0;
+        group_by: &[BaseExpression],
+        hidden: &mut Vec<usize>,
+    ) -> Result<()> {
         for (i, (select_expr, lable)) in select.iter().enumerate() {
             // 表达式一样的话就把当前 expr 改一下
             if select_expr == expr {
@@ -452,22 +867,45 @@ impl<'a> Planner<'a> {
         // 因为having执行会比select更前 所以column(2) 是会出问题的 所以function中的expr改回去
         // 因为这里的column(2)是找的select的结果， 但是having执行的早，压根找不到
         // 这里有点不好理解，需要了解后面的聚合以及groupby原理
+        // 标量函数在select之后执行 参数中的column(i)是可以用的
         expr.transform_ref(&mut |e| Ok(e), &mut |e| match e {
-            BaseExpression::Function(f, mut ex) => {
-                ex.transform_ref(&mut |e| Ok(e), &mut |e| match e {
-                    BaseExpression::Column(i) => {
-                        let (r, _) = select.get(i).cloned().ok_or(Error::Plan(format!("")))?;
-                        Ok(r)
-                    }
-                    _ => Ok(e),
-                })?;
-                Ok(BaseExpression::Function(f, ex))
+            BaseExpression::Function(f, mut args) if Aggregate::from_str(&f).is_ok() => {
+                for arg in args.iter_mut() {
+                    arg.transform_ref(&mut |e| Ok(e), &mut |e| match e {
+                        BaseExpression::Column(i) => {
+                            let (r, _) = select.get(i).cloned().ok_or(Error::Plan(format!("")))?;
+                            Ok(r)
+                        }
+                        _ => Ok(e),
+                    })?;
+                }
+                Ok(BaseExpression::Function(f, args))
             }
             _ => Ok(e),
         })?;
 
+        // 和group by一样的子表达式整个放到select中 之后会被替换为聚合节点输出的列
+        // 只放其中的列的话 聚合之后就找不到了 select count(id) ... group by g * 2 order by g * 2
+        expr.transform_ref(
+            &mut |e| {
+                if !group_by.contains(&e) {
+                    return Ok(e);
+                }
+                let index = match select.iter().position(|(s, _)| s == &e) {
+                    Some(index) => index,
+                    None => {
+                        select.push((e, None));
+                        hidden.push(select.len() - 1);
+                        select.len() - 1
+                    }
+                };
+                Ok(BaseExpression::Column(index))
+            },
+            &mut |e| Ok(e),
+        )?;
+
         // 如果上面转换了一边之后 还有没有转换的，那就需要加到select中了
-        // 同时hidden+1 之后再根据hidden删除就行了
+        // 同时记到hidden中 之后再根据hidden删除就行了
         // orderby和having是 需要select执行之后才进行
         // 因为他们两个是可以读取select作用域中的label的 这也是为什么要做上面的操作
         expr.transform_ref(
@@ -476,14 +914,13 @@ impl<'a> Planner<'a> {
                     BaseExpression::Field(_, _) => {
                         // 这个时候还能找到field说明select压根没有 直接放到select
                         select.push((e, None));
-                        hidden += 1;
+                        hidden.push(select.len() - 1);
                         BaseExpression::Column(select.len() - 1)
                     }
-                    BaseExpression::Function(f, a) => {
-                        // 判断一下有没有这个function 不需要管arg, 因为已经放到select了
-                        Aggregate::from_str(&f)?;
+                    // 聚合函数 不需要管arg, 因为已经放到select了
+                    BaseExpression::Function(..) if e.is_aggreate() => {
                         select.push((e, None));
-                        hidden += 1;
+                        hidden.push(select.len() - 1);
                         BaseExpression::Column(select.len() - 1)
                     }
                     _ => e,
@@ -492,7 +929,91 @@ impl<'a> Planner<'a> {
             &mut |e| Ok(e),
         )?;
 
-        Ok(hidden)
+        Ok(())
+    }
+
+    /// 按照作用域中列的顺序展开 * 和 t.* 列名保持原来的
+    fn expand_wildcards(
+        &self,
+        scope: &Scope,
+        select: Vec<(BaseExpression, Option<String>)>,
+    ) -> Result<Vec<(BaseExpression, Option<String>)>> {
+        let mut expanded = Vec::new();
+        for (expr, label) in select {
+            let table = match expr {
+                BaseExpression::Wildcard(table) => table,
+                expr => {
+                    expanded.push((expr, label));
+                    continue;
+                }
+            };
+            if let Some(ref table) = table {
+                if !scope.has_table(table) {
+                    return Err(scope.unknown_table(table));
+                }
+            }
+            for (i, (t, name)) in scope.columns.iter().enumerate() {
+                if table.is_some() && t != &table {
+                    continue;
+                }
+                // 有表名的用全限定的字段 不会有歧义
+                let expr = match (t, name) {
+                    (Some(t), Some(name)) => BaseExpression::Field(Some(t.clone()), name.clone()),
+                    _ => BaseExpression::Column(i),
+                };
+                expanded.push((expr, None));
+            }
+        }
+        Ok(expanded)
+    }
+
+    /// 只有管理员的会话可以执行的语句 行级安全策略不能被绕过
+    fn require_admin(&self, statement: &str) -> Result<()> {
+        if self.context.policies {
+            return Err(Error::sql(
+                ErrorCode::InsufficientPrivilege,
+                format!("{} requires an admin session", statement),
+            ));
+        }
+        Ok(())
+    }
+
+    /// 表的所有行级安全策略 用 AND 连起来 管理员的会话或者表没有策略的时候返回None
+    fn build_policy(&self, table: &Table) -> Result<Option<Expression>> {
+        if !self.context.policies {
+            return Ok(None);
+        }
+        table
+            .policies
+            .iter()
+            .map(|p| self.resolve_settings(p.expression.clone()))
+            .reduce(|a, b| Ok(Expression::And(Box::new(a?), Box::new(b?))))
+            .transpose()
+    }
+
+    /// 把 CURRENT_SETTING(名称) 换成会话中设置的值 没有设置的是null
+    fn resolve_settings(&self, expression: Expression) -> Result<Expression> {
+        let settings = match &self.context.settings {
+            Some(settings) => settings,
+            None => return Ok(expression),
+        };
+        expression.transform(
+            &|e| match e {
+                Expression::ScalarFn(name, args) if name == "CURRENT_SETTING" => {
+                    match args[0].evaluate(None)? {
+                        Value::String(s) => Ok(Expression::Constant(
+                            settings.get(&s.to_lowercase()).cloned().unwrap_or(Value::Null),
+                        )),
+                        v => Err(Error::Plan(format!(
+                            "CURRENT_SETTING expect a setting name get {}",
+                            v
+                        ))),
+                    }
+                }
+                e => Ok(e),
+            },
+            &|e| Ok(e),
+        )
     }
 
     fn build_from_table(&self, scope: &mut Scope, from: FromItem) -> Result<Node> {
@@ -501,13 +1022,53 @@ impl<'a> Planner<'a> {
                 // 如果是table 则是最底层的操作
                 let table = self.catalog.must_read_table(&name);
                 let table = table?;
-                scope.register_table(table)?;
-                Ok(Node::Scan {
+                let policy = self.build_policy(&table)?;
+                scope.register_table(table, alias.clone())?;
+                let scan = Node::Scan {
                     table: name,
                     alias,
                     filter: None,
+                    columns: None,
+                };
+                // 行级安全策略作为扫描上面的过滤 优化的时候和其他条件一样下推
+                Ok(match policy {
+                    Some(predicate) => Node::Filter {
+                        source: Box::new(scan),
+                        predicate,
+                    },
+                    None => scan,
                 })
             }
+            FromItem::Values {
+                rows,
+                alias,
+                mut columns,
+            } => {
+                // 每一行的列数要一样 只能是常量
+                let width = rows.first().map_or(0, |row| row.len());
+                if rows.iter().any(|row| row.len() != width) {
+                    return Err(Error::Plan("VALUES lists must all be the same length".into()));
+                }
+                if columns.is_empty() {
+                    columns = (1..=width).map(|i| format!("column{}", i)).collect();
+                } else if columns.len() != width {
+                    return Err(Error::Plan(format!(
+                        "VALUES has {} columns but {} column names are specified",
+                        width,
+                        columns.len()
+                    )));
+                }
+                scope.register_values(alias, &columns)?;
+                let rows = rows
+                    .into_iter()
+                    .map(|row| {
+                        row.into_iter()
+                            .map(|e| self.build_expresion(&Scope::constant(), e))
+                            .collect::<Result<Vec<_>>>()
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(Node::Values { columns, rows })
+            }
             FromItem::Join {
                 left,
                 right,
@@ -531,8 +1092,9 @@ impl<'a> Planner<'a> {
                 };
 
                 let outer = match join_type {
-                    JoinType::Left | JoinType::Right => true,
-                    JoinType::Inner | JoinType::Cross => false,
+                    JoinType::Left | JoinType::Right => Some(Outer::Left),
+                    JoinType::Full => Some(Outer::Full),
+                    JoinType::Inner | JoinType::Cross => None,
                 };
 
                 // 构建连接
@@ -583,20 +1145,82 @@ impl<'a> Planner<'a> {
         }
     }
 
+    /// RETURNING 的表达式 引用的是修改之后的行 *展开成表的所有列
+    fn build_returning(
+        &self,
+        scope: &Scope,
+        returning: Option<SelectItems>,
+    ) -> Result<Option<Returning>> {
+        let returning = match returning {
+            Some(returning) => returning,
+            None => return Ok(None),
+        };
+        if returning.is_empty() {
+            return Ok(Some(
+                scope
+                    .columns
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (table, name))| {
+                        (Expression::Field(i, name.clone().map(|n| (table.clone(), n))), None)
+                    })
+                    .collect(),
+            ));
+        }
+        returning
+            .into_iter()
+            .map(|(e, label)| Ok((self.build_expresion(scope, e)?, label)))
+            .collect::<Result<Vec<_>>>()
+            .map(Some)
+    }
+
     pub fn build_expresion(&self, scope: &Scope, expression: BaseExpression) -> Result<Expression> {
         match expression {
-            BaseExpression::Field(table, name) => Ok(Expression::Field(
-                scope
-                    .get_column_index(table.clone(), name.clone())?
-                    .to_owned(),
-                Some((table, name)),
-            )),
+            BaseExpression::Field(table, name) => {
+                match (scope.get_column_index(table.clone(), name.clone()), &scope.outer) {
+                    (Ok(index), _) => Ok(Expression::Field(*index, Some((table, name)))),
+                    // 子查询中找不到 是外层查询的列
+                    (Err(e), Some(outer)) => Ok(Expression::OuterField(
+                        *outer
+                            .get_column_index(table.clone(), name.clone())
+                            .map_err(|_| e)?,
+                        Some((table, name)),
+                    )),
+                    (Err(e), None) => Err(e),
+                }
+            }
+            BaseExpression::Subquery(statement) => {
+                if !scope.subquery {
+                    return Err(Error::Plan(
+                        "subquery can only be used in select list".to_string(),
+                    ));
+                }
+                let mut planner = Planner {
+                    catalog: self.catalog,
+                    outer: Some(scope.clone()),
+                    context: self.context.clone(),
+                };
+                Ok(Expression::Subquery(Box::new(planner.build_node(*statement)?)))
+            }
+            BaseExpression::Wildcard(_) => {
+                Err(Error::Plan("* can only be used in select list".to_string()))
+            }
             BaseExpression::Column(i) => Ok(Expression::Field(i, None)),
             BaseExpression::Value(value) => Ok(Expression::Constant(value)),
-            BaseExpression::Function(_, _) => Err(Error::Plan(format!(
-                "get unexpected base_expression: {:?}",
-                expression
-            ))),
+            // 聚合函数在这之前都被提取了 剩下的只能是标量函数
+            BaseExpression::Function(ref f, _) if expression.is_aggreate() => Err(Error::Plan(
+                format!("get unexpected aggregate function: {}", f),
+            )),
+            BaseExpression::Function(f, args) => {
+                let name = f.to_uppercase();
+                expression::check_scalar_fn(&name, args.len())?;
+                self.resolve_settings(Expression::ScalarFn(
+                    name,
+                    args.into_iter()
+                        .map(|arg| self.build_expresion(scope, arg))
+                        .collect::<Result<_>>()?,
+                ))
+            }
             BaseExpression::Operation(operation) => match operation {
                 Operation::Negative(a) => Ok(Expression::Negative(Box::new(
                     self.build_expresion(scope, *a)?,
@@ -612,9 +1236,15 @@ impl<'a> Planner<'a> {
                     Box::new(self.build_expresion(scope, *a)?),
                     Box::new(self.build_expresion(scope, *b)?),
                 )),
-                Operation::Like(a, b) => Ok(Expression::Like(
+                Operation::Like(a, b, escape) => Ok(Expression::Like(
                     Box::new(self.build_expresion(scope, *a)?),
                     Box::new(self.build_expresion(scope, *b)?),
+                    escape,
+                )),
+                Operation::ILike(a, b, escape) => Ok(Expression::ILike(
+                    Box::new(self.build_expresion(scope, *a)?),
+                    Box::new(self.build_expresion(scope, *b)?),
+                    escape,
                 )),
                 Operation::Equal(a, b) => Ok(Expression::Equal(
                     Box::new(self.build_expresion(scope, *a)?),
@@ -624,6 +1254,10 @@ impl<'a> Planner<'a> {
                     Box::new(self.build_expresion(scope, *a)?),
                     Box::new(self.build_expresion(scope, *b)?),
                 )))),
+                Operation::NullSafeEqual(a, b) => Ok(Expression::NullSafeEqual(
+                    Box::new(self.build_expresion(scope, *a)?),
+                    Box::new(self.build_expresion(scope, *b)?),
+                )),
                 Operation::GreaterThan(a, b) => Ok(Expression::GreaterThan(
                     Box::new(self.build_expresion(scope, *a)?),
                     Box::new(self.build_expresion(scope, *b)?),
@@ -665,10 +1299,24 @@ impl<'a> Planner<'a> {
                     Box::new(self.build_expresion(scope, *a)?),
                     Box::new(self.build_expresion(scope, *b)?),
                 )),
+                Operation::Divide(a, b) if self.context.integer_division => {
+                    Ok(Expression::IntegerDivide(
+                        Box::new(self.build_expresion(scope, *a)?),
+                        Box::new(self.build_expresion(scope, *b)?),
+                    ))
+                }
                 Operation::Divide(a, b) => Ok(Expression::Divide(
                     Box::new(self.build_expresion(scope, *a)?),
                     Box::new(self.build_expresion(scope, *b)?),
                 )),
+                Operation::IntegerDivide(a, b) => Ok(Expression::IntegerDivide(
+                    Box::new(self.build_expresion(scope, *a)?),
+                    Box::new(self.build_expresion(scope, *b)?),
+                )),
+                Operation::Modulo(a, b) => Ok(Expression::Modulo(
+                    Box::new(self.build_expresion(scope, *a)?),
+                    Box::new(self.build_expresion(scope, *b)?),
+                )),
                 Operation::Exponentiate(a, b) => Ok(Expression::Exponentiate(
                     Box::new(self.build_expresion(scope, *a)?),
                     Box::new(self.build_expresion(scope, *b)?),
@@ -692,6 +1340,8 @@ pub struct Scope {
     constant: bool,
     // 放入已知的table
     tables: HashMap<String, Table>,
+    // VALUES 的别名 没有对应的table
+    values: HashSet<String>,
     // 放入已经知道的column
     columns: Vec<(Option<String>, Option<String>)>,
     // 给columns加一个索引 key = (table_name, column_name) val = 上面columns中column所在的index
@@ -707,6 +1357,10 @@ pub struct Scope {
     // 这个时候就无法判断select 是哪个表中的name
     // 这个需要直到解析的时候才能知道错误,如果select没有出现可能出现歧义的字段则无需理会
     ambiguous: HashSet<String>,
+    // 子查询的作用域中 外层查询的作用域
+    outer: Option<Box<Scope>>,
+    // 能不能使用子查询
+    subquery: bool,
 }
 
 impl Scope {
@@ -715,10 +1369,13 @@ impl Scope {
         Self {
             constant: false,
             tables: HashMap::new(),
+            values: HashSet::new(),
             columns: Vec::new(),
             qualified: HashMap::new(),
             unqualified: HashMap::new(),
             ambiguous: HashSet::new(),
+            outer: None,
+            subquery: false,
         }
     }
 
@@ -743,6 +1400,8 @@ impl Scope {
         }
         let mut scope = Self::new();
         scope.tables = self.tables.clone();
+        scope.values = self.values.clone();
+        scope.outer = self.outer.clone();
 
         expr.iter()
             .map(|(filed, label)| {
@@ -782,20 +1441,20 @@ impl Scope {
         self.columns.push((table, label));
     }
 
-    fn register_table(&mut self, table: Table) -> Result<()> {
+    /// 有别名的时候只能通过别名引用这个表
+    fn register_table(&mut self, table: Table, alias: Option<String>) -> Result<()> {
         if self.constant {
             return Err(Error::Plan(
                 "constant scope can't register table".to_string(),
             ));
         }
 
-        if self.tables.contains_key(&table.name) {
-            return Err(Error::Plan(format!(
-                "try to register repeat table: {:?}",
-                table
-            )));
+        // 作用域中的表按照别名注册 同一层的别名不能重复 子查询中可以遮住外层的
+        let table_name = alias.unwrap_or_else(|| table.name.clone());
+        if self.has_table(&table_name) {
+            let message = format!("table name or alias {} is specified more than once", table_name);
+            return Err(Error::sql(ErrorCode::DuplicateAlias, message).with_identifier(&table_name));
         }
-        let table_name = table.name.clone();
         for ele in table.columns.iter() {
             let column_name = ele.name.clone();
             debug!("register table {}, filed {}", &table_name, column_name);
@@ -805,6 +1464,61 @@ impl Scope {
         Ok(())
     }
 
+    /// VALUES 有别名的时候和表一样可以用 别名.列 引用
+    fn register_values(&mut self, alias: Option<String>, columns: &[String]) -> Result<()> {
+        if self.constant {
+            return Err(Error::Plan(
+                "constant scope can't register table".to_string(),
+            ));
+        }
+        if let Some(ref alias) = alias {
+            if self.has_table(alias) {
+                let message = format!("table name or alias {} is specified more than once", alias);
+                return Err(Error::sql(ErrorCode::DuplicateAlias, message).with_identifier(alias));
+            }
+            self.values.insert(alias.clone());
+        }
+        for column in columns {
+            self.add_column(alias.clone(), Some(column.clone()));
+        }
+        Ok(())
+    }
+
+    /// on conflict 中用 excluded.列 引用要插入的行 排在已经存在的行的列之后
+    /// 不加入非限定列名 不写表名的列仍然是已经存在的行
+    fn register_excluded(&mut self, columns: &[String]) {
+        let table = EXCLUDED.to_string();
+        for column in columns {
+            self.qualified.insert((table.clone(), column.clone()), self.columns.len());
+            self.columns.push((Some(table.clone()), Some(column.clone())));
+        }
+        self.values.insert(table);
+    }
+
+    fn has_table(&self, name: &str) -> bool {
+        self.tables.contains_key(name) || self.values.contains(name)
+    }
+
+    /// 表不在作用域中 用了别名的表只能用别名引用
+    fn unknown_table(&self, table: &str) -> Error {
+        let mut aliases = self
+            .tables
+            .iter()
+            .filter(|(alias, t)| t.name == table && alias.as_str() != table)
+            .map(|(alias, _)| alias.clone())
+            .collect::<Vec<_>>();
+        aliases.sort();
+        let message = match aliases.is_empty() {
+            true => format!("can't get table: {} in this scope", table),
+            false => format!(
+                "table {} is aliased as {} in this scope, use the alias instead",
+                table,
+                aliases.join(", ")
+            ),
+        };
+        Error::sql(ErrorCode::UndefinedTable, message).with_identifier(table)
+    }
+
     fn get_column_index(&self, table: Option<String>, name: String) -> Result<&usize> {
         if self.constant {
             return Err(Error::Plan(
@@ -814,32 +1528,276 @@ impl Scope {
         // 先查看有没有table
         match table {
             Some(table) => {
-                if !self.tables.contains_key(table.as_str()) {
-                    return Err(Error::Plan(format!(
-                        "can't get table: {} in this scope",
-                        table
-                    )));
+                if !self.has_table(&table) {
+                    return Err(self.unknown_table(&table));
                 }
                 // 存在的话就直接在全限定map中找
-                self.qualified
-                    .get(&(table.clone(), name.clone()))
-                    .ok_or(Error::Plan(format!(
-                        "can't find table: {}, filed: {}",
-                        table.clone(),
-                        name.clone()
-                    )))
+                self.qualified.get(&(table.clone(), name.clone())).ok_or_else(|| {
+                    let message = format!("can't find table: {}, filed: {}", table, name);
+                    Error::sql(ErrorCode::UndefinedColumn, message)
+                        .with_identifier(&format!("{}.{}", table, name))
+                })
             }
             // 如果没有设定table
             // 需要看看是否在ambiguous中，里面的字段表示有争议，如果存在就说明我们也不知道应该给哪个了
             None => {
                 if self.ambiguous.contains(name.as_str()) {
-                    Err(Error::Plan(format!("try to get ambiguous filed: {}", name)))
+                    // 把有这个列的表都列出来
+                    let mut tables = self
+                        .columns
+                        .iter()
+                        .filter(|(_, label)| label.as_deref() == Some(name.as_str()))
+                        .filter_map(|(table, _)| table.clone())
+                        .collect::<Vec<_>>();
+                    tables.dedup();
+                    let message = format!(
+                        "column {} is ambiguous, qualify it with one of: {}",
+                        name,
+                        tables.join(", ")
+                    );
+                    Err(Error::sql(ErrorCode::AmbiguousColumn, message).with_identifier(&name))
                 } else {
-                    self.unqualified
-                        .get(&name)
-                        .ok_or(Error::Plan(format!("can't find filed: {}", name)))
+                    self.unqualified.get(&name).ok_or_else(|| {
+                        let message = format!("can't find filed: {}", name);
+                        Error::sql(ErrorCode::UndefinedColumn, message).with_identifier(&name)
+                    })
                 }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::Result;
+    use crate::sql::engine::kv::test_engine;
+    use crate::sql::engine::Engine;
+    use crate::sql::Value;
+
+    /// 查询结果的列名和行
+    type Named = (Vec<Option<String>>, Vec<Vec<Value>>);
+
+    fn query(sql: &str) -> Result<Vec<Vec<Value>>> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, g int, x int );")?;
+        session.execute("insert into t values (1, 1, 5), (2, 1, 6), (3, 2, 1), (4, 3, 20);")?;
+        let mut rows = session.query(sql)?;
+        rows.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        Ok(rows)
+    }
+
+    #[test]
+    fn having_test() -> Result<()> {
+        let expect = vec![
+            vec![Value::Integer(1), Value::Integer(11)],
+            vec![Value::Integer(3), Value::Integer(20)],
+        ];
+        // 别名和原始的聚合函数
+        assert_eq!(
+            query("select t.g, sum(x) total from t group by t.g having total > 10;")?,
+            expect
+        );
+        assert_eq!(query("select t.g, sum(x) from t group by t.g having sum(x) > 10;")?, expect);
+        // 聚合函数不在select中
+        assert_eq!(
+            query("select t.g, sum(x) from t group by t.g having count(id) = 2;")?,
+            vec![vec![Value::Integer(1), Value::Integer(11)]]
+        );
+        // group by 的字段
+        assert_eq!(
+            query("select t.g, sum(x) from t group by t.g having t.g > 1 and max(x) > 10;")?,
+            vec![vec![Value::Integer(3), Value::Integer(20)]]
+        );
+        Ok(())
+    }
+    #[test]
+    fn group_by_test() -> Result<()> {
+        let int = |v: &[i64]| v.iter().map(|v| Value::Integer(*v)).collect::<Vec<_>>();
+        // 没有出现在select中的表达式 order by 也可以使用
+        assert_eq!(
+            query("select count(id) from t group by t.g * 2 order by t.g * 2 asc;")?,
+            vec![int(&[1]), int(&[1]), int(&[2])]
+        );
+        // select中包含group by的表达式
+        assert_eq!(
+            query("select t.g * 2 + 1, count(id) from t group by t.g * 2;")?,
+            vec![int(&[3, 2]), int(&[5, 1]), int(&[7, 1])]
+        );
+        // group by的别名可以在having和order by中使用
+        assert_eq!(
+            query("select t.g * 2 k, sum(x) s from t group by k having k > 2 order by k asc;")?,
+            vec![int(&[4, 1]), int(&[6, 20])]
+        );
+        // 不带表名的列
+        assert_eq!(
+            query("select g, sum(x) from t group by g;")?,
+            vec![int(&[1, 11]), int(&[2, 1]), int(&[3, 20])]
+        );
+        // 没有group by的列报错会带上列名
+        for sql in [
+            "select t.x, sum(x) from t group by t.g;",
+            "select sum(x) from t group by t.g having t.x > 1;",
+            "select sum(x) from t group by t.g order by t.x asc;",
+        ] {
+            let e = query(sql).unwrap_err();
+            assert!(e.to_string().contains("t.x must appear in group by"), "{}", e);
+        }
+        assert!(query("select sum(x) s from t group by s;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn subquery_test() -> Result<()> {
+        let int = |v: Option<i64>| v.map_or(Value::Null, Value::Integer);
+        let rows = |rows: &[(i64, Option<i64>)]| {
+            rows.iter()
+                .map(|(a, b)| vec![Value::Integer(*a), int(*b)])
+                .collect::<Vec<_>>()
+        };
+
+        // 子查询中引用外层的列 每一行执行一次
+        assert_eq!(
+            query("select id, (select count(*) from t u where u.g = t.g) from t;")?,
+            rows(&[(1, Some(2)), (2, Some(2)), (3, Some(1)), (4, Some(1))])
+        );
+        // 没有行的时候是null
+        assert_eq!(
+            query("select id, (select max(x) from t u where u.g = t.g and u.id > t.id) from t;")?,
+            rows(&[(1, Some(6)), (2, None), (3, None), (4, None)])
+        );
+        assert_eq!(
+            query("select g, (select count(*) from t u where u.g = t.g) + 1 from t group by g;")?,
+            rows(&[(1, Some(3)), (2, Some(2)), (3, Some(2))])
+        );
+        assert_eq!(query("select (select 1 + 1);")?, vec![vec![Value::Integer(2)]]);
+
+        assert!(query("select id, (select x from t u where u.g = t.g) from t;").is_err());
+        assert!(query("select id, (select id, x from t u where u.id = t.id) from t;").is_err());
+        assert!(query("select id from t where (select 1) = 1;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn wildcard_test() -> Result<()> {
+        let engine = test_engine();
+        let mut session = engine.session()?;
+        session.execute("create table t ( id int primary key, g int );")?;
+        session.execute("create table u ( id int primary key, name string );")?;
+        session.execute("insert into t values (1, 10), (2, 20);")?;
+        session.execute("insert into u values (1, \"a\"), (2, \"b\");")?;
+        let mut query = |sql: &str| -> Result<Named> {
+            let (columns, rows) = session.query_columns(sql)?;
+            Ok((columns.into_iter().map(|c| c.name).collect(), rows))
+        };
+        let names = |n: &[&str]| n.iter().map(|n| Some(n.to_string())).collect::<Vec<_>>();
+        let string = |s: &str| Value::String(s.to_string());
+        use Value::Integer;
+
+        // t.* 按照表中的顺序展开 保留列名
+        let (columns, rows) =
+            query("select t.*, u.name from t join u on t.id = u.id order by t.id asc;")?;
+        assert_eq!(columns, names(&["id", "g", "name"]));
+        assert_eq!(
+            rows,
+            vec![
+                vec![Integer(1), Integer(10), string("a")],
+                vec![Integer(2), Integer(20), string("b")]
+            ]
+        );
+        // 同名的列不会有歧义
+        let (columns, rows) = query("select u.*, * from t join u on t.id = u.id where t.id = 2;")?;
+        assert_eq!(columns, names(&["id", "name", "id", "g", "id", "name"]));
+        assert_eq!(
+            rows,
+            vec![vec![Integer(2), string("b"), Integer(2), Integer(20), Integer(2), string("b")]]
+        );
+        let (_, rows) = query("select g * 2, x.* from t x where x.id = 1;")?;
+        assert_eq!(rows, vec![vec![Integer(20), Integer(1), Integer(10)]]);
+
+        assert!(query("select v.* from t;").is_err());
+        assert!(query("select id from t where t.* = 1;").is_err());
+        assert!(query("select t.* x from t;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn values_test() -> Result<()> {
+        let string = |s: &str| Value::String(s.to_string());
+        use Value::Integer;
+        // 单独的 VALUES 列名是 column1 column2 ...
+        assert_eq!(
+            query("values (2, \"b\"), (1, \"a\");")?,
+            vec![vec![Integer(1), string("a")], vec![Integer(2), string("b")]]
+        );
+        assert_eq!(
+            query("values (1), (3), (2) order by column1 desc limit 1;")?,
+            vec![vec![Integer(3)]]
+        );
+        // 作为 from 中的表和其他表连接
+        assert_eq!(
+            query(
+                "select t.id, v.name from t join (values (1, \"one\"), (2, \"two\")) as v(k, name) \
+                 on t.g = v.k where t.x > 5;"
+            )?,
+            vec![vec![Integer(2), string("one")]]
+        );
+        assert_eq!(
+            query("select column2 * 2, v.* from (values (1, 10 + 1), (2, null)) v \
+                   where column1 = 1;")?,
+            vec![vec![Integer(22), Integer(1), Integer(11)]]
+        );
+        assert_eq!(
+            query("select count(*), max(k) from (values (3), (1), (2)) v(k);")?,
+            vec![vec![Integer(3), Integer(3)]]
+        );
+
+        assert!(query("values (1, 2), (3);").is_err());
+        assert!(query("select * from (values (1, 2)) v(a);").is_err());
+        assert!(query("select * from (values (t.id)) v;").is_err());
+        assert!(query("select * from t v, (values (1)) v;").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn alias_test() -> Result<()> {
+        let message = |sql: &str| query(sql).unwrap_err().to_string();
+        // 别名可以用在where 和 join 的条件中
+        assert_eq!(query("select a.x from t a where a.id = 1;")?, vec![vec![Value::Integer(5)]]);
+        assert_eq!(
+            query("select a.id from t a join t b on a.g = b.g where b.id = 2 and a.id < 2;")?,
+            vec![vec![Value::Integer(1)]]
+        );
+        // 子查询中的别名可以和外层一样
+        assert_eq!(
+            query("select a.id, (select count(*) from t a where a.g = 1) from t a where a.id = 4;")?,
+            vec![vec![Value::Integer(4), Value::Integer(2)]]
+        );
+        assert!(message("select id from t a, t a;").contains("alias a is specified more than once"));
+        assert!(message("select id from t, t;").contains("alias t is specified more than once"));
+        assert!(message("select t.id from t a;").contains("table t is aliased as a"));
+        assert!(message("select id from t a, t b;").contains("qualify it with one of: a, b"));
+        Ok(())
+    }
+
+    #[test]
+    fn error_code_test() -> Result<()> {
+        use crate::errors::{Error, ErrorCode};
+        let error = |sql: &str| match query(sql).unwrap_err() {
+            Error::Sql(e) => (e.code, e.identifier),
+            e => panic!("unexpected error {:?}", e),
+        };
+        let table = |t: &str| Some(t.to_string());
+        assert_eq!(error("select id from u;"), (ErrorCode::UndefinedTable, table("u")));
+        assert_eq!(error("select t.id from t a;"), (ErrorCode::UndefinedTable, table("t")));
+        assert_eq!(error("select y from t;"), (ErrorCode::UndefinedColumn, table("y")));
+        assert_eq!(error("select t.y from t;"), (ErrorCode::UndefinedColumn, table("t.y")));
+        assert_eq!(error("select id from t a, t b;"), (ErrorCode::AmbiguousColumn, table("id")));
+        assert_eq!(error("select id from t, t;"), (ErrorCode::DuplicateAlias, table("t")));
+        let create = "create table t ( id int primary key );";
+        assert_eq!(error(create), (ErrorCode::DuplicateTable, table("t")));
+        assert_eq!(query("select id from u;").unwrap_err().code(), "42P01");
+        assert_eq!(query("select from t;").unwrap_err().code(), "42601");
+        Ok(())
+    }
+}
@@ -1,10 +1,12 @@
 use super::Table;
-use crate::errors::{Error, Result};
+use crate::errors::{Error, ErrorCode, Result};
 
 /// 对于模式的定义
 pub trait Catalog {
     /// 创建一个表
     fn create_table(&mut self, table: Table) -> Result<()>;
+    /// 修改一个已经存在的表的定义 不会改动表中的数据
+    fn update_table(&mut self, table: Table) -> Result<()>;
     /// 删除一个表
     fn delete_table(&mut self, table: &str) -> Result<()>;
     /// 根据表名称获取
@@ -14,8 +16,10 @@ pub trait Catalog {
 
     /// 找到一个table 如果没有就返回错误
     fn must_read_table(&self, table: &str) -> Result<Table> {
-        self.read_table(table)?
-            .ok_or_else(|| Error::Schema(format!("Table {} does not exist", table)))
+        self.read_table(table)?.ok_or_else(|| {
+            Error::sql(ErrorCode::UndefinedTable, format!("Table {} does not exist", table))
+                .with_identifier(table)
+        })
     }
 
 }
@@ -0,0 +1,179 @@
+//! ANALYZE 收集的统计信息 优化器用来估计条件的选择率
+//! 每一列有一个等深直方图 每个桶中的行数大致相同 统计信息不会随着写入更新 需要重新 ANALYZE
+use std::collections::BTreeMap;
+use std::ops::Bound;
+
+use serde_derive::{Deserialize, Serialize};
+
+use super::Value;
+
+/// 直方图最多的桶数
+pub const HISTOGRAM_BUCKETS: usize = 32;
+
+/// 一张表的统计信息
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct TableStats {
+    pub rows: u64,
+    /// 列名到列的统计信息
+    pub columns: BTreeMap<String, ColumnStats>,
+}
+
+/// 一列的统计信息
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ColumnStats {
+    /// 包括null的所有行数
+    pub rows: u64,
+    pub nulls: u64,
+    /// 不是null的不同的值的个数
+    pub distinct: u64,
+    /// 第一个是最小值 之后是每个桶的上界 第i个桶是 (bounds[i], bounds[i+1]]
+    pub bounds: Vec<Value>,
+}
+
+impl ColumnStats {
+    /// 用一列所有的值建立
+    pub fn build(mut values: Vec<Value>) -> Self {
+        let rows = values.len() as u64;
+        values.retain(|v| v != &Value::Null);
+        let nulls = rows - values.len() as u64;
+        values.sort();
+        let mut distinct = values.len() as u64;
+        for pair in values.windows(2) {
+            if pair[0] == pair[1] {
+                distinct -= 1;
+            }
+        }
+        let n = values.len();
+        let buckets = n.min(HISTOGRAM_BUCKETS);
+        let mut bounds = Vec::with_capacity(buckets + 1);
+        if let Some(min) = values.first() {
+            bounds.push(min.clone());
+            bounds.extend((1..=buckets).map(|i| values[i * n / buckets - 1].clone()));
+        }
+        Self {
+            rows,
+            nulls,
+            distinct,
+            bounds,
+        }
+    }
+
+    /// 等于value的行占所有行的比例
+    pub fn equal(&self, value: &Value) -> f64 {
+        if self.rows == 0 {
+            return 0.0;
+        }
+        if value == &Value::Null {
+            return self.nulls as f64 / self.rows as f64;
+        }
+        self.equal_in_values(value) * self.values_fraction()
+    }
+
+    /// 在范围中的行占所有行的比例
+    pub fn range(&self, range: &(Bound<Value>, Bound<Value>)) -> f64 {
+        if self.rows == 0 || self.bounds.is_empty() {
+            return 0.0;
+        }
+        let lower = match &range.0 {
+            Bound::Included(v) => self.position(v) - self.equal_in_values(v),
+            Bound::Excluded(v) => self.position(v),
+            Bound::Unbounded => 0.0,
+        };
+        let upper = match &range.1 {
+            Bound::Included(v) => self.position(v),
+            Bound::Excluded(v) => self.position(v) - self.equal_in_values(v),
+            Bound::Unbounded => 1.0,
+        };
+        (upper - lower).clamp(0.0, 1.0) * self.values_fraction()
+    }
+
+    /// 不是null的行的比例
+    fn values_fraction(&self) -> f64 {
+        (self.rows - self.nulls) as f64 / self.rows as f64
+    }
+
+    /// 不是null的值中等于value的比例 出现在多个桶的上界的是高频值 按照桶数计算
+    fn equal_in_values(&self, value: &Value) -> f64 {
+        let (first, last) = match (self.bounds.first(), self.bounds.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => return 0.0,
+        };
+        if value < first || value > last {
+            return 0.0;
+        }
+        let buckets = self.bounds.len() - 1;
+        let repeats = self.bounds[1..]
+            .iter()
+            .filter(|b| *b == value)
+            .count();
+        match repeats {
+            0 | 1 => 1.0 / self.distinct.max(1) as f64,
+            repeats => repeats as f64 / buckets as f64,
+        }
+    }
+
+    /// 不是null的值中小于等于value的比例 数字在桶中按照线性插值
+    fn position(&self, value: &Value) -> f64 {
+        let bounds = &self.bounds;
+        let buckets = bounds.len() - 1;
+        if value < &bounds[0] {
+            return 0.0;
+        }
+        let full = bounds[1..]
+            .iter()
+            .filter(|b| *b <= value)
+            .count();
+        if full == buckets {
+            return 1.0;
+        }
+        let partial = match (number(&bounds[full]), number(value), number(&bounds[full + 1])) {
+            (Some(lo), Some(v), Some(hi)) if hi > lo => (v - lo) / (hi - lo),
+            _ => 0.5,
+        };
+        (full as f64 + partial) / buckets as f64
+    }
+}
+
+
+fn number(value: &Value) -> Option<f64> {
+    match value {
+        Value::Integer(i) => Some(*i as f64),
+        Value::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_test() {
+        // 0..100 各一行 加上90行的1000和10行null
+        let mut values = (0..100).map(Value::Integer).collect::<Vec<_>>();
+        values.extend((0..90).map(|_| Value::Integer(1000)));
+        values.extend((0..10).map(|_| Value::Null));
+        let stats = ColumnStats::build(values);
+        assert_eq!((stats.rows, stats.nulls, stats.distinct), (200, 10, 101));
+        assert_eq!(stats.bounds.len(), HISTOGRAM_BUCKETS + 1);
+
+        let close = |a: f64, b: f64| (a - b).abs() < 0.03;
+        assert!(close(stats.equal(&Value::Null), 0.05));
+        // 高频值按照桶数计算 其他的值按照不同值的个数
+        let frequent = stats.equal(&Value::Integer(1000));
+        assert!(close(frequent, 0.45), "{}", frequent);
+        assert!(stats.equal(&Value::Integer(5)) < 0.01);
+        assert_eq!(stats.equal(&Value::Integer(-1)), 0.0);
+
+        let range = |lower, upper| stats.range(&(lower, upper));
+        let half = range(Bound::Unbounded, Bound::Excluded(Value::Integer(50)));
+        assert!(close(half, 0.25), "{}", half);
+        let all = range(Bound::Included(Value::Integer(0)), Bound::Unbounded);
+        assert!(close(all, 0.95), "{}", all);
+        assert_eq!(range(Bound::Excluded(Value::Integer(1000)), Bound::Unbounded), 0.0);
+
+        let empty = ColumnStats::build(vec![Value::Null]);
+        assert_eq!(empty.equal(&Value::Integer(1)), 0.0);
+        assert_eq!(empty.range(&(Bound::Unbounded, Bound::Unbounded)), 0.0);
+    }
+}
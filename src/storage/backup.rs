@@ -0,0 +1,206 @@
+/* 备份文件 保存某个版本所有可见的key value
+ * 文件格式: [魔数 8字节][版本 u64] 之后是记录 [长度 u32][校验和 u32][bincode编码的(key, value)]
+ * 最后是结尾 [0 u32][记录数 u64] 没有结尾或者记录数对不上说明文件不完整
+ * 写入的时候先写到临时文件 写完之后再改名 所以不会留下写了一半的备份
+ * */
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+use super::wal::Wal;
+use crate::errors::*;
+
+const MAGIC: &[u8; 8] = b"COKEBAK1";
+
+pub struct BackupWriter {
+    path: PathBuf,
+    /// 写完之前的临时文件 没有写完drop的时候删除
+    temp: PathBuf,
+    writer: Option<BufWriter<File>>,
+    count: u64,
+}
+
+impl BackupWriter {
+    /// 创建备份文件 已经存在的时候报错
+    pub fn create(path: &Path, version: u64) -> Result<Self> {
+        if path.exists() {
+            return Err(Error::IO(format!("backup file {} already exists", path.display())));
+        }
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)?;
+        let mut writer = BufWriter::new(file);
+        writer.write_all(MAGIC)?;
+        writer.write_all(&version.to_be_bytes())?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            temp,
+            writer: Some(writer),
+            count: 0,
+        })
+    }
+
+    pub fn write(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let data = bincode::serialize(&(key, value))?;
+        let writer = self.writer()?;
+        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        writer.write_all(&Wal::checksum(&data).to_be_bytes())?;
+        writer.write_all(&data)?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// 写入结尾 fsync之后改成最终的文件名 返回写入的记录数
+    pub fn finish(mut self) -> Result<u64> {
+        let count = self.count;
+        let mut writer = self.writer.take().ok_or(Error::IO("backup already finished".into()))?;
+        writer.write_all(&0u32.to_be_bytes())?;
+        writer.write_all(&count.to_be_bytes())?;
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        std::fs::rename(&self.temp, &self.path)?;
+        Ok(count)
+    }
+
+    fn writer(&mut self) -> Result<&mut BufWriter<File>> {
+        self.writer.as_mut().ok_or(Error::IO("backup already finished".into()))
+    }
+}
+
+impl Drop for BackupWriter {
+    fn drop(&mut self) {
+        if self.writer.is_some() {
+            let _ = std::fs::remove_file(&self.temp);
+        }
+    }
+}
+
+/// 按照写入的顺序读取备份中的key value 读到结尾的时候检查记录数
+pub struct BackupReader {
+    reader: BufReader<File>,
+    version: u64,
+    count: u64,
+    done: bool,
+}
+
+impl BackupReader {
+    pub fn open(path: &Path) -> Result<Self> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut header = [0; 16];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| corruption("missing header"))?;
+        if &header[0..8] != MAGIC {
+            return Err(corruption("not a backup file"));
+        }
+        Ok(Self {
+            reader,
+            version: u64::from_be_bytes(header[8..16].try_into()?),
+            count: 0,
+            done: false,
+        })
+    }
+
+    /// 备份的版本
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    fn read(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        let mut len = [0; 4];
+        self.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len == 0 {
+            let mut count = [0; 8];
+            self.read_exact(&mut count)?;
+            if u64::from_be_bytes(count) != self.count {
+                return Err(corruption("record count mismatch"));
+            }
+            return Ok(None);
+        }
+        let mut checksum = [0; 4];
+        self.read_exact(&mut checksum)?;
+        let mut data = vec![0; len];
+        self.read_exact(&mut data)?;
+        if Wal::checksum(&data) != u32::from_be_bytes(checksum) {
+            return Err(corruption(&format!("checksum mismatch for record {}", self.count)));
+        }
+        self.count += 1;
+        Ok(Some(bincode::deserialize(&data)?))
+    }
+
+    /// 文件提前结束说明备份不完整
+    fn read_exact(&mut self, buf: &mut [u8]) -> Result<()> {
+        match self.reader.read_exact(buf) {
+            Ok(()) => Ok(()),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Err(corruption("unexpected end of file"))
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Iterator for BackupReader {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.read().transpose();
+        // 出错之后也不再继续读
+        if !matches!(item, Some(Ok(_))) {
+            self.done = true;
+        }
+        item
+    }
+}
+
+fn corruption(message: &str) -> Error {
+    Error::Corruption(format!("invalid backup file: {}", message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backup_file_test() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("coke_db_backup_{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut writer = BackupWriter::create(&path, 7)?;
+        for i in 0..100u32 {
+            writer.write(&i.to_be_bytes(), format!("value {}", i).as_bytes())?;
+        }
+        assert_eq!(writer.finish()?, 100);
+        // 不会覆盖已经存在的备份
+        assert!(BackupWriter::create(&path, 8).is_err());
+
+        let reader = BackupReader::open(&path)?;
+        assert_eq!(reader.version(), 7);
+        let items = reader.collect::<Result<Vec<_>>>()?;
+        assert_eq!(items.len(), 100);
+        assert_eq!(items[42], (42u32.to_be_bytes().to_vec(), b"value 42".to_vec()));
+
+        // 截断的文件
+        let data = std::fs::read(&path)?;
+        std::fs::write(&path, &data[..data.len() - 4])?;
+        let items = BackupReader::open(&path)?.collect::<Result<Vec<_>>>();
+        assert!(matches!(items, Err(Error::Corruption(_))));
+        // 记录被修改
+        let mut data = data;
+        data[30] ^= 0xff;
+        std::fs::write(&path, &data)?;
+        let items = BackupReader::open(&path)?.collect::<Result<Vec<_>>>();
+        assert!(matches!(items, Err(Error::Corruption(_))));
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
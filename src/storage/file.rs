@@ -0,0 +1,359 @@
+/* 基于文件的存储 所有的写入追加到一个数据文件中 内存中只保存每个key的值在文件中的位置
+ * 读取的时候再从文件中读出值 数据量可以超过内存 重启的时候扫描文件重建位置
+ * 和预写日志一样 每次 flush 写入一条提交记录 没有提交的写入在重启之后丢弃
+ * 被覆盖和删除的记录留在文件中 启动的时候超过一半的话重写文件
+ *
+ * 记录格式: [长度 u32][校验和 u32][类型 u8][key长度 u32][key][value]
+ * */
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use log::{debug, info};
+
+use super::kv::{KvBatch, MyRange, Scan, SqlStore};
+use super::wal::{SyncPolicy, Wal};
+use crate::errors::*;
+
+const SET: u8 = 1;
+const DELETE: u8 = 2;
+const COMMIT: u8 = 3;
+/// 长度 校验和 类型和key长度
+const HEADER: u64 = 13;
+/// 写入的记录先放在内存中 超过之后写到文件
+const BUFFER_SIZE: usize = 1024 * 1024;
+/// 文件小于这个大小的时候不压缩
+const COMPACT_SIZE: u64 = 1024 * 1024;
+
+/// key的值在文件中的位置和长度
+#[derive(Clone, Copy, Debug)]
+struct Location {
+    offset: u64,
+    len: u32,
+}
+
+pub struct FileStore {
+    path: PathBuf,
+    file: File,
+    keydir: BTreeMap<Vec<u8>, Location>,
+    /// 还没有写到文件的记录 从文件结尾开始
+    buffer: Vec<u8>,
+    /// 文件中已经写入的字节数
+    written: u64,
+    sync: SyncPolicy,
+    last_sync: Instant,
+}
+
+impl FileStore {
+    /// 打开数据文件 重建已经提交的key的位置
+    pub fn new(path: &Path, sync: SyncPolicy) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let mut file = Self::open_file(path)?;
+        let (keydir, committed) = Self::recover(&mut file)?;
+        file.set_len(committed)?;
+        let mut store = Self {
+            path: path.to_path_buf(),
+            file,
+            keydir,
+            buffer: Vec::new(),
+            written: committed,
+            sync,
+            last_sync: Instant::now(),
+        };
+        let live = store.live();
+        info!(
+            "file store {} load {} keys, {} of {} bytes live",
+            path.display(),
+            store.keydir.len(),
+            live,
+            committed
+        );
+        if committed > COMPACT_SIZE && live < committed / 2 {
+            store.compact()?;
+        }
+        Ok(store)
+    }
+
+    fn open_file(path: &Path) -> Result<File> {
+        Ok(OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?)
+    }
+
+    /// 读取文件 返回提交的key的位置和最后一条提交记录的结束位置
+    fn recover(file: &mut File) -> Result<(BTreeMap<Vec<u8>, Location>, u64)> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file);
+        let mut keydir = BTreeMap::new();
+        let mut pending = Vec::new();
+        let mut offset = 0;
+        let mut committed = 0;
+        while let Some((kind, key, len)) = Self::read_record(&mut reader)? {
+            let location = Location {
+                offset: offset + HEADER + key.len() as u64,
+                len,
+            };
+            offset = location.offset + len as u64;
+            match kind {
+                SET => pending.push((key, Some(location))),
+                DELETE => pending.push((key, None)),
+                _ => {
+                    for (key, location) in pending.drain(..) {
+                        match location {
+                            Some(location) => keydir.insert(key, location),
+                            None => keydir.remove(&key),
+                        };
+                    }
+                    committed = offset;
+                }
+            }
+        }
+        if !pending.is_empty() {
+            debug!("file store discard {} uncommitted records", pending.len());
+        }
+        Ok((keydir, committed))
+    }
+
+    /// 读取一条记录 返回类型 key和值的长度 文件结束或者记录不完整 校验失败都返回None
+    fn read_record(reader: &mut impl Read) -> Result<Option<(u8, Vec<u8>, u32)>> {
+        let mut header = [0; 8];
+        if !Self::read_full(reader, &mut header)? {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(header[0..4].try_into()?) as usize;
+        let checksum = u32::from_be_bytes(header[4..8].try_into()?);
+        let mut data = vec![0; len];
+        if len < 5 || !Self::read_full(reader, &mut data)? || Wal::checksum(&data) != checksum {
+            return Ok(None);
+        }
+        let key_len = u32::from_be_bytes(data[1..5].try_into()?) as usize;
+        if data[0] < SET || data[0] > COMMIT || 5 + key_len > len {
+            return Ok(None);
+        }
+        let key = data[5..5 + key_len].to_vec();
+        Ok(Some((data[0], key, (len - 5 - key_len) as u32)))
+    }
+
+    /// 读满buf 读到文件结尾返回false
+    fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool> {
+        match reader.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn encode(kind: u8, key: &[u8], value: &[u8]) -> Vec<u8> {
+        let mut data = Vec::with_capacity(5 + key.len() + value.len());
+        data.push(kind);
+        data.extend_from_slice(&(key.len() as u32).to_be_bytes());
+        data.extend_from_slice(key);
+        data.extend_from_slice(value);
+        let mut record = Vec::with_capacity(8 + data.len());
+        record.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        record.extend_from_slice(&Wal::checksum(&data).to_be_bytes());
+        record.extend_from_slice(&data);
+        record
+    }
+
+    /// 追加一条记录 返回值在文件中的位置
+    fn append(&mut self, kind: u8, key: &[u8], value: &[u8]) -> Result<Location> {
+        let location = Location {
+            offset: self.written + self.buffer.len() as u64 + HEADER + key.len() as u64,
+            len: value.len() as u32,
+        };
+        self.buffer.extend_from_slice(&Self::encode(kind, key, value));
+        if self.buffer.len() >= BUFFER_SIZE {
+            self.write_buffer()?;
+        }
+        Ok(location)
+    }
+
+    fn write_buffer(&mut self) -> Result<()> {
+        self.file.write_all_at(&self.buffer, self.written)?;
+        self.written += self.buffer.len() as u64;
+        self.buffer.clear();
+        Ok(())
+    }
+
+    fn read(&self, location: Location) -> Result<Vec<u8>> {
+        let len = location.len as usize;
+        let mut value = vec![0; len];
+        match location.offset.checked_sub(self.written) {
+            Some(start) => {
+                let start = start as usize;
+                value.copy_from_slice(&self.buffer[start..start + len]);
+            }
+            None => self.file.read_exact_at(&mut value, location.offset)?,
+        }
+        Ok(value)
+    }
+
+    /// 没有被覆盖和删除的记录的字节数
+    fn live(&self) -> u64 {
+        self.keydir
+            .iter()
+            .map(|(key, location)| HEADER + key.len() as u64 + location.len as u64)
+            .sum()
+    }
+
+    /// 只把现在的值写到新的文件 写完之后替换原来的文件
+    fn compact(&mut self) -> Result<()> {
+        let mut temp = self.path.as_os_str().to_owned();
+        temp.push(".compact");
+        let temp = PathBuf::from(temp);
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        let mut keydir = BTreeMap::new();
+        let mut offset = 0;
+        for (key, location) in self.keydir.iter() {
+            let record = Self::encode(SET, key, &self.read(*location)?);
+            let location = Location {
+                offset: offset + HEADER + key.len() as u64,
+                len: location.len,
+            };
+            offset += record.len() as u64;
+            writer.write_all(&record)?;
+            keydir.insert(key.clone(), location);
+        }
+        let commit = Self::encode(COMMIT, &[], &[]);
+        writer.write_all(&commit)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        std::fs::rename(&temp, &self.path)?;
+        info!("file store {} compact {} to {} bytes", self.path.display(), self.written, offset);
+        self.file = Self::open_file(&self.path)?;
+        self.keydir = keydir;
+        self.written = offset + commit.len() as u64;
+        Ok(())
+    }
+}
+
+impl Display for FileStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FileStore({})", self.path.display())
+    }
+}
+
+impl SqlStore for FileStore {
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        if self.keydir.remove(key).is_some() {
+            self.append(DELETE, key, &[])?;
+        }
+        Ok(())
+    }
+
+    /// 写入提交记录 根据策略决定是否fsync
+    fn flush(&mut self) -> Result<()> {
+        self.append(COMMIT, &[], &[])?;
+        self.write_buffer()?;
+        let sync = match self.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if sync {
+            self.file.sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.keydir.get(key).map(|location| self.read(*location)).transpose()
+    }
+
+    fn scan(&self, range: MyRange) -> Scan {
+        Box::new(
+            self.keydir
+                .range(range)
+                .map(|(key, location)| Ok((key.clone(), self.read(*location)?)))
+                .collect::<Vec<_>>()
+                .into_iter(),
+        )
+    }
+
+    /// 只读取返回的值
+    fn scan_limit(&self, range: MyRange, limit: usize, reverse: bool) -> Result<KvBatch> {
+        let read = |(key, location): (&Vec<u8>, &Location)| -> Result<(Vec<u8>, Vec<u8>)> {
+            Ok((key.clone(), self.read(*location)?))
+        };
+        let iter = self.keydir.range(range);
+        if reverse {
+            iter.rev().take(limit).map(read).collect()
+        } else {
+            iter.take(limit).map(read).collect()
+        }
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        let location = self.append(SET, key, &value)?;
+        self.keydir.insert(key.to_vec(), location);
+        Ok(())
+    }
+}
+
+impl Drop for FileStore {
+    fn drop(&mut self) {
+        // 没有提交的记录写下去也没关系 重启的时候会被丢弃
+        let _ = self.write_buffer();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_store_test() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("coke_db_file_test_{}", std::process::id()))
+            .join("sql.data");
+        let _ = std::fs::remove_file(&path);
+        let open = || FileStore::new(&path, SyncPolicy::Always);
+
+        let mut store = open()?;
+        store.set(b"a", vec![1])?;
+        store.set(b"b", vec![2])?;
+        store.flush()?;
+        store.delete(b"a")?;
+        store.set(b"b", vec![3])?;
+        store.flush()?;
+        // 没有flush的写入在重启之后消失
+        store.set(b"c", vec![4])?;
+        drop(store);
+        // 模拟写了一半的记录
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[0, 0, 0, 9, 1, 2])?;
+        drop(file);
+
+        let mut store = open()?;
+        assert_eq!(store.get(b"a")?, None);
+        assert_eq!(store.get(b"b")?, Some(vec![3]));
+        assert_eq!(store.get(b"c")?, None);
+
+        // 大部分是垃圾的时候重启之后压缩
+        for i in 0..200u32 {
+            store.set(b"big", vec![i as u8; 10000])?;
+        }
+        store.flush()?;
+        drop(store);
+        let before = std::fs::metadata(&path)?.len();
+        let store = open()?;
+        assert!(std::fs::metadata(&path)?.len() < before / 10);
+        assert_eq!(store.get(b"big")?, Some(vec![199; 10000]));
+        assert_eq!(store.get(b"b")?, Some(vec![3]));
+        drop(store);
+        assert_eq!(open()?.get(b"big")?, Some(vec![199; 10000]));
+
+        std::fs::remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+}
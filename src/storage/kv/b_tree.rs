@@ -2,7 +2,8 @@ use crate::errors::Result;
 
 use std::collections::BTreeMap;
 use std::fmt::Display;
-use super::{MyRange, Scan, SqlStore};
+use std::ops::Bound;
+use super::{KvBatch, MyRange, Scan, SqlStore};
 
 pub struct BtreeStore {
     data: BTreeMap<Vec<u8>, Vec<u8>>,
@@ -32,6 +33,34 @@ impl SqlStore for BtreeStore {
         Ok(())
     }
 
+    /// 把范围切出来整个丢掉 不需要逐个删除
+    fn delete_range(&mut self, range: MyRange) -> Result<u64> {
+        let mut middle = match &range.start {
+            Bound::Included(start) => self.data.split_off(start),
+            Bound::Excluded(start) => {
+                let mut middle = self.data.split_off(start);
+                if let Some(v) = middle.remove(start) {
+                    self.data.insert(start.clone(), v);
+                }
+                middle
+            }
+            Bound::Unbounded => std::mem::take(&mut self.data),
+        };
+        let mut rest = match &range.end {
+            Bound::Included(end) => {
+                let mut rest = middle.split_off(end);
+                if let Some(v) = rest.remove(end) {
+                    middle.insert(end.clone(), v);
+                }
+                rest
+            }
+            Bound::Excluded(end) => middle.split_off(end),
+            Bound::Unbounded => BTreeMap::new(),
+        };
+        self.data.append(&mut rest);
+        Ok(middle.len() as u64)
+    }
+
     fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         Ok(self.data.get(key).cloned())
     }
@@ -46,8 +75,66 @@ impl SqlStore for BtreeStore {
         )
     }
 
+    fn scan_limit(&self, range: MyRange, limit: usize, reverse: bool) -> Result<KvBatch> {
+        let iter = self.data.range(range).map(|(k, v)| (k.clone(), v.clone()));
+        if reverse {
+            Ok(iter.rev().take(limit).collect())
+        } else {
+            Ok(iter.take(limit).collect())
+        }
+    }
+
     fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
         let r = self.data.insert(key.to_vec(), value);
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delete_range_test() -> Result<()> {
+        let mut store = BtreeStore::new();
+        let reset = |store: &mut BtreeStore| -> Result<()> {
+            for i in 0..10u8 {
+                store.set(&[i], vec![i])?;
+            }
+            Ok(())
+        };
+        let keys = |store: &BtreeStore| store.data.keys().map(|k| k[0]).collect::<Vec<_>>();
+        reset(&mut store)?;
+        assert_eq!(store.delete_range(MyRange::new(vec![2]..vec![5]))?, 3);
+        assert_eq!(keys(&store), vec![0, 1, 5, 6, 7, 8, 9]);
+        let range = MyRange::new((Bound::Excluded(vec![5]), Bound::Included(vec![7])));
+        assert_eq!(store.delete_range(range)?, 2);
+        assert_eq!(keys(&store), vec![0, 1, 5, 8, 9]);
+        // 空的范围什么都不删除
+        assert_eq!(store.delete_range(MyRange::new(vec![6]..vec![7]))?, 0);
+        assert_eq!(store.delete_range(MyRange::new(vec![9]..vec![1]))?, 0);
+        assert_eq!(keys(&store), vec![0, 1, 5, 8, 9]);
+        assert_eq!(store.delete_range(MyRange::new(vec![8]..))?, 2);
+        assert_eq!(store.delete_range(MyRange::new(..))?, 3);
+        assert!(store.data.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn inverted_range_test() -> Result<()> {
+        let mut store = BtreeStore::new();
+        for i in 0..10u8 {
+            store.set(&[i], vec![i])?;
+        }
+        let excluded = (Bound::Excluded(vec![3]), Bound::Excluded(vec![3]));
+        for range in [MyRange::new(vec![7]..vec![2]), MyRange::new(excluded)] {
+            assert!(range.is_empty());
+            assert_eq!(store.scan(range.clone()).count(), 0);
+            assert!(store.scan_limit(range.clone(), 5, true)?.is_empty());
+            assert_eq!(store.delete_range(range)?, 0);
+        }
+        assert!(!MyRange::new(vec![3]..=vec![3]).is_empty());
+        assert_eq!(store.data.len(), 10);
+        Ok(())
+    }
+}
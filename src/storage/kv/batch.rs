@@ -0,0 +1,232 @@
+//! 分批扫描 每次只从store中拿出batch_size个kv 避免一次性把整个范围拷贝出来
+//! 在tokio运行时中 会在后台提前拿取下一批数据 隐藏存储的延迟
+//! 设置了分组的时候 同一组的kv总是在同一批中拿出 不会被两次拿取之间的修改拆开
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, RwLock};
+
+use super::{KvBatch, MyRange, SqlStore};
+use crate::errors::*;
+
+/// 默认的批大小
+pub const DEFAULT_SCAN_BATCH_SIZE: usize = 1024;
+
+/// 得到一个key所在的组 返回的是key的前缀
+pub type Group = fn(&[u8]) -> &[u8];
+
+pub struct BatchScan {
+    store: Arc<RwLock<Box<dyn SqlStore>>>,
+    /// 还没有被拿取的范围
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    batch_size: usize,
+    group: Option<Group>,
+    /// 正向拿到的数据
+    front: VecDeque<(Vec<u8>, Vec<u8>)>,
+    /// 反向拿到的数据
+    back: VecDeque<(Vec<u8>, Vec<u8>)>,
+    /// 后台正在预取的下一批
+    prefetch: Option<Receiver<Result<KvBatch>>>,
+    /// 范围内已经没有数据了
+    exhausted: bool,
+}
+
+impl BatchScan {
+    pub fn new(store: Arc<RwLock<Box<dyn SqlStore>>>, range: MyRange, batch_size: usize) -> Self {
+        Self {
+            store,
+            start: range.start,
+            end: range.end,
+            batch_size: batch_size.max(1),
+            group: None,
+            front: VecDeque::new(),
+            back: VecDeque::new(),
+            prefetch: None,
+            exhausted: false,
+        }
+    }
+
+    /// 每一批的最后一个kv所在组的剩下的kv也放在这一批中
+    pub fn with_group(mut self, group: Group) -> Self {
+        self.group = Some(group);
+        self
+    }
+
+    /// 从store中拿出一批数据
+    fn fetch(
+        store: &Arc<RwLock<Box<dyn SqlStore>>>,
+        range: MyRange,
+        limit: usize,
+        reverse: bool,
+        group: Option<Group>,
+    ) -> Result<KvBatch> {
+        let store = store.read()?;
+        let rest = range.clone();
+        let mut batch = store.scan_limit(range, limit, reverse)?;
+        let (group, last) = match (group, batch.last()) {
+            (Some(group), Some((last, _))) if batch.len() == limit => (group, last.clone()),
+            _ => return Ok(batch),
+        };
+        // 持有同一个读锁拿完这一组
+        let rest = match reverse {
+            false => MyRange::new((Bound::Excluded(last.clone()), rest.end)),
+            true => MyRange::new((rest.start, Bound::Excluded(last.clone()))),
+        };
+        let mut scan = store.scan(rest);
+        loop {
+            let item = match reverse {
+                false => scan.next(),
+                true => scan.next_back(),
+            };
+            match item.transpose()? {
+                Some((k, v)) if group(&k) == group(&last) => batch.push((k, v)),
+                _ => return Ok(batch),
+            }
+        }
+    }
+
+    fn range(&self) -> MyRange {
+        MyRange::new((self.start.clone(), self.end.clone()))
+    }
+
+    /// 在后台预取下一批 只有在tokio运行时中才会这么做
+    fn spawn_prefetch(&mut self) {
+        if self.exhausted || self.prefetch.is_some() {
+            return;
+        }
+        if let Ok(handle) = tokio::runtime::Handle::try_current() {
+            let (tx, rx) = channel();
+            let store = self.store.clone();
+            let range = self.range();
+            let limit = self.batch_size;
+            let group = self.group;
+            handle.spawn_blocking(move || {
+                let _ = tx.send(Self::fetch(&store, range, limit, false, group));
+            });
+            self.prefetch = Some(rx);
+        }
+    }
+
+    /// 正向拿取下一批 放入front
+    fn fill_front(&mut self) -> Result<()> {
+        let batch = match self.prefetch.take().and_then(|rx| rx.recv().ok()) {
+            Some(batch) => batch?,
+            None => Self::fetch(&self.store, self.range(), self.batch_size, false, self.group)?,
+        };
+        match batch.last() {
+            Some((k, _)) => self.start = Bound::Excluded(k.clone()),
+            None => self.exhausted = true,
+        }
+        if batch.len() < self.batch_size {
+            self.exhausted = true;
+        }
+        self.front.extend(batch);
+        self.spawn_prefetch();
+        Ok(())
+    }
+
+    /// 反向拿取下一批 放入back
+    fn fill_back(&mut self) -> Result<()> {
+        // 反向拿取会修改end 预取的结果可能和back重叠 直接丢弃
+        self.prefetch = None;
+        let batch = Self::fetch(&self.store, self.range(), self.batch_size, true, self.group)?;
+        match batch.last() {
+            Some((k, _)) => self.end = Bound::Excluded(k.clone()),
+            None => self.exhausted = true,
+        }
+        if batch.len() < self.batch_size {
+            self.exhausted = true;
+        }
+        self.back.extend(batch);
+        Ok(())
+    }
+
+    fn try_next(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.front.is_empty() && !self.exhausted {
+            self.fill_front()?;
+        }
+        if let Some(item) = self.front.pop_front() {
+            return Ok(Some(item));
+        }
+        // 范围已经拿完了 剩下的在back中 back是倒序放入的
+        Ok(self.back.pop_back())
+    }
+
+    fn try_next_back(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.back.is_empty() && !self.exhausted {
+            self.fill_back()?;
+        }
+        if let Some(item) = self.back.pop_front() {
+            return Ok(Some(item));
+        }
+        Ok(self.front.pop_back())
+    }
+}
+
+impl Iterator for BatchScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.try_next().transpose()
+    }
+}
+
+impl DoubleEndedIterator for BatchScan {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.try_next_back().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::kv::b_tree::BtreeStore;
+
+    #[test]
+    fn batch_scan_test() -> Result<()> {
+        let mut store = BtreeStore::new();
+        for i in 0..10u8 {
+            store.set(&[i], vec![i])?;
+        }
+        let store: Arc<RwLock<Box<dyn SqlStore>>> = Arc::new(RwLock::new(Box::new(store)));
+
+        let scan = BatchScan::new(store.clone(), MyRange::new(vec![1]..vec![9]), 3);
+        let keys = scan.map(|r| r.map(|(k, _)| k[0])).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, (1..9).collect::<Vec<_>>());
+
+        // 两头交替拿 不会重复也不会遗漏
+        let mut scan = BatchScan::new(store, MyRange::new(..), 3);
+        let mut keys = vec![];
+        while let Some(item) = scan.next() {
+            keys.push(item?.0[0]);
+            if let Some(item) = scan.next_back() {
+                keys.push(item?.0[0]);
+            }
+        }
+        keys.sort();
+        assert_eq!(keys, (0..10).collect::<Vec<_>>());
+        Ok(())
+    }
+
+    #[test]
+    fn group_scan_test() -> Result<()> {
+        let mut store = BtreeStore::new();
+        for i in 0..4u8 {
+            for j in 0..3u8 {
+                store.set(&[i, j], vec![])?;
+            }
+        }
+        let store: Arc<RwLock<Box<dyn SqlStore>>> = Arc::new(RwLock::new(Box::new(store)));
+        // 每一批都拿完最后一个组
+        let batch = BatchScan::fetch(&store, MyRange::new(..), 2, false, Some(|k| &k[..1]))?;
+        assert_eq!(batch.len(), 3);
+        let batch = BatchScan::fetch(&store, MyRange::new(..), 4, true, Some(|k| &k[..1]))?;
+        assert_eq!(batch.len(), 6);
+        assert_eq!(batch.last().map(|(k, _)| k.clone()), Some(vec![2, 0]));
+
+        let scan = BatchScan::new(store, MyRange::new(..), 2).with_group(|k| &k[..1]);
+        assert_eq!(scan.count(), 12);
+        Ok(())
+    }
+}
@@ -0,0 +1,179 @@
+//! 布隆过滤器 点查询的key不存在的时候不需要读取存储
+//! 每个keyspace一个过滤器 keyspace由上层决定 例如一张表的所有行或者一个索引的所有值
+//! 过滤器只在内存中 第一次查询一个keyspace的时候扫描存储建立 之后写入的时候一起更新
+use std::collections::HashMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::Result;
+
+/// 每个key使用的位数 大约有1%的误判
+const BITS_PER_KEY: usize = 10;
+/// 哈希函数的个数 BITS_PER_KEY * ln2
+const HASHES: u64 = 7;
+/// 过滤器最少能放多少个key
+const MIN_CAPACITY: usize = 1024;
+
+/// 得到一个key所在的keyspace 返回None的key不使用过滤器
+pub type Keyspace = fn(&[u8]) -> Option<Vec<u8>>;
+
+/// 一个布隆过滤器 容量是固定的 超过容量之后误判会变多 需要重新建立
+#[derive(Clone, Debug)]
+pub struct BloomFilter {
+    bits: Vec<u64>,
+    /// 放进去的key的数量 重复的key也会计数
+    keys: usize,
+    capacity: usize,
+}
+
+impl BloomFilter {
+    pub fn new(capacity: usize) -> Self {
+        let capacity = capacity.max(MIN_CAPACITY);
+        Self {
+            bits: vec![0; (capacity * BITS_PER_KEY).div_ceil(64)],
+            keys: 0,
+            capacity,
+        }
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in self.positions(key) {
+            self.bits[bit / 64] |= 1 << (bit % 64);
+        }
+        self.keys += 1;
+    }
+
+    /// 返回false的时候key一定不存在
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.positions(key)
+            .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+    }
+
+    pub fn full(&self) -> bool {
+        self.keys > self.capacity
+    }
+
+    /// 两个哈希值组合出所有的位置
+    fn positions(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let h1 = hasher.finish();
+        0xb10f.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+        let len = (self.bits.len() * 64) as u64;
+        (0..HASHES).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % len) as usize)
+    }
+}
+
+/// 过滤器的统计信息
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct BloomStatus {
+    /// 当前建立了多少个过滤器
+    pub filters: u64,
+    /// 一共检查了多少次
+    pub checks: u64,
+    /// 有多少次检查确定key不存在 跳过了读取存储
+    pub skipped: u64,
+}
+
+/// 所有keyspace的过滤器
+/// 调用的时候需要持有存储的锁 建立过滤器的时候持有读锁 写入的时候持有写锁
+/// 这样建立过滤器的扫描和写入不会交错 不会漏掉key
+pub struct BloomFilters {
+    keyspace: Keyspace,
+    filters: Mutex<HashMap<Vec<u8>, BloomFilter>>,
+    checks: AtomicU64,
+    skipped: AtomicU64,
+}
+
+impl BloomFilters {
+    pub fn new(keyspace: Keyspace) -> Self {
+        Self {
+            keyspace,
+            filters: Mutex::new(HashMap::new()),
+            checks: AtomicU64::new(0),
+            skipped: AtomicU64::new(0),
+        }
+    }
+
+    /// key可能存在的时候返回true 过滤器还没有建立的话 用build扫描出keyspace中所有的key
+    pub fn may_contain<F>(&self, key: &[u8], build: F) -> Result<bool>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<Vec<u8>>>,
+    {
+        let keyspace = match (self.keyspace)(key) {
+            Some(keyspace) => keyspace,
+            None => return Ok(true),
+        };
+        let mut filters = self.filters.lock()?;
+        if !filters.contains_key(&keyspace) {
+            let keys = build(&keyspace)?;
+            // 留出一倍的空间给之后的写入
+            let mut filter = BloomFilter::new(keys.len() * 2);
+            for key in keys {
+                filter.insert(&key);
+            }
+            filters.insert(keyspace.clone(), filter);
+        }
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        let contains = filters[&keyspace].contains(key);
+        if !contains {
+            self.skipped.fetch_add(1, Ordering::Relaxed);
+        }
+        Ok(contains)
+    }
+
+    /// 写入一个key 过滤器还没有建立的时候不需要做什么 建立的时候会扫描到它
+    /// 超过容量的过滤器直接丢掉 下次查询的时候按照新的大小重新建立
+    pub fn insert(&self, key: &[u8]) -> Result<()> {
+        let keyspace = match (self.keyspace)(key) {
+            Some(keyspace) => keyspace,
+            None => return Ok(()),
+        };
+        let mut filters = self.filters.lock()?;
+        if let Some(filter) = filters.get_mut(&keyspace) {
+            filter.insert(key);
+            if filter.full() {
+                filters.remove(&keyspace);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn status(&self) -> Result<BloomStatus> {
+        Ok(BloomStatus {
+            filters: self.filters.lock()?.len() as u64,
+            checks: self.checks.load(Ordering::Relaxed),
+            skipped: self.skipped.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bloom_filter_test() {
+        let mut filter = BloomFilter::new(1000);
+        for i in 0..1000u32 {
+            filter.insert(&i.to_be_bytes());
+        }
+        assert!(!filter.full());
+        // 放进去的key一定能找到
+        assert!((0..1000u32).all(|i| filter.contains(&i.to_be_bytes())));
+        // 误判大约是1%
+        let false_positives = (1000..11000u32)
+            .filter(|i| filter.contains(&i.to_be_bytes()))
+            .count();
+        assert!(false_positives < 300, "{}", false_positives);
+
+        for i in 1000..1025u32 {
+            filter.insert(&i.to_be_bytes());
+        }
+        assert!(filter.full());
+    }
+}
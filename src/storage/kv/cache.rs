@@ -0,0 +1,174 @@
+//! 点查询的 LRU 缓存 缓存一个key在存储中的所有版本
+//! 同一个key不同快照的事务共享一份缓存 读取的时候再按照快照判断可见性
+//! 写入 回滚和垃圾回收的时候持有存储的写锁删除对应的缓存 填充的时候持有读锁 所以缓存和存储总是一致的
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+use serde_derive::{Deserialize, Serialize};
+
+use crate::errors::Result;
+
+/// 一个key的所有版本 按照版本从小到大 值为None的是删除标记
+pub type Versions = Vec<(u64, Option<Vec<u8>>)>;
+
+/// 点查询缓存默认最多使用的字节数
+pub const DEFAULT_ROW_CACHE_SIZE: usize = 32 * 1024 * 1024;
+
+/// 每个缓存项除了key和值之外大约占用的字节数
+const ENTRY_OVERHEAD: usize = 64;
+/// 每个版本除了值之外大约占用的字节数
+const VERSION_OVERHEAD: usize = 16;
+
+/// 缓存的统计信息
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct CacheStatus {
+    /// 缓存了多少个key
+    pub entries: u64,
+    /// 缓存占用的字节数
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+struct Entry {
+    versions: Versions,
+    size: usize,
+    /// 最近一次使用的时间 越大越新
+    tick: u64,
+}
+
+#[derive(Default)]
+struct Lru {
+    entries: HashMap<Vec<u8>, Entry>,
+    /// 使用时间到key 最小的是最久没有使用的
+    order: BTreeMap<u64, Vec<u8>>,
+    tick: u64,
+    bytes: usize,
+}
+
+impl Lru {
+    fn touch(&mut self, key: &[u8]) -> Option<&Entry> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(key)?;
+        let key = self.order.remove(&entry.tick)?;
+        entry.tick = tick;
+        self.order.insert(tick, key);
+        Some(entry)
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.tick);
+            self.bytes -= entry.size;
+        }
+    }
+}
+
+/// 以字节数限制大小的 LRU 缓存
+pub struct RowCache {
+    capacity: usize,
+    lru: Mutex<Lru>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl RowCache {
+    /// capacity 是缓存最多占用的字节数
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            lru: Mutex::new(Lru::default()),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// 缓存中有的时候直接返回 否则用load从存储中读取并放进缓存
+    pub fn get_or_load<F>(&self, key: &[u8], load: F) -> Result<Versions>
+    where
+        F: FnOnce() -> Result<Versions>,
+    {
+        if let Some(entry) = self.lru.lock()?.touch(key) {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(entry.versions.clone());
+        }
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        let versions = load()?;
+        self.insert(key, versions.clone())?;
+        Ok(versions)
+    }
+
+    fn insert(&self, key: &[u8], versions: Versions) -> Result<()> {
+        let size = ENTRY_OVERHEAD
+            + key.len()
+            + versions
+                .iter()
+                .map(|(_, v)| VERSION_OVERHEAD + v.as_ref().map_or(0, |v| v.len()))
+                .sum::<usize>();
+        // 比整个缓存还大的不缓存
+        if size > self.capacity {
+            return Ok(());
+        }
+        let mut lru = self.lru.lock()?;
+        lru.remove(key);
+        while lru.bytes + size > self.capacity {
+            let oldest = match lru.order.first_key_value() {
+                Some((_, key)) => key.clone(),
+                None => break,
+            };
+            lru.remove(&oldest);
+        }
+        lru.tick += 1;
+        let tick = lru.tick;
+        lru.order.insert(tick, key.to_vec());
+        lru.entries.insert(key.to_vec(), Entry { versions, size, tick });
+        lru.bytes += size;
+        Ok(())
+    }
+
+    /// key在存储中的版本有修改的时候调用
+    pub fn invalidate(&self, key: &[u8]) -> Result<()> {
+        self.lru.lock()?.remove(key);
+        Ok(())
+    }
+
+    pub fn status(&self) -> Result<CacheStatus> {
+        let lru = self.lru.lock()?;
+        Ok(CacheStatus {
+            entries: lru.entries.len() as u64,
+            bytes: lru.bytes as u64,
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn row_cache_test() -> Result<()> {
+        let cache = RowCache::new(3 * (ENTRY_OVERHEAD + 1 + VERSION_OVERHEAD + 10));
+        let load = |v: u8| move || Ok(vec![(1, Some(vec![v; 10]))]);
+        for key in [b"a", b"b", b"c"] {
+            cache.get_or_load(key, load(key[0]))?;
+        }
+        // a 最近使用过 放入 d 的时候淘汰的是 b
+        assert_eq!(cache.get_or_load(b"a", load(0))?, vec![(1, Some(vec![b'a'; 10]))]);
+        cache.get_or_load(b"d", load(b'd'))?;
+        assert_eq!(cache.get_or_load(b"b", load(0))?, vec![(1, Some(vec![0; 10]))]);
+        let status = cache.status()?;
+        assert_eq!((status.entries, status.hits, status.misses), (3, 1, 5));
+        assert!(status.bytes <= cache.capacity as u64);
+
+        cache.invalidate(b"b")?;
+        assert_eq!(cache.get_or_load(b"b", load(1))?, vec![(1, Some(vec![1; 10]))]);
+        // 比容量还大的不缓存
+        cache.get_or_load(b"e", || Ok(vec![(1, Some(vec![0; 1000]))]))?;
+        assert_eq!(cache.status()?.entries, 3);
+        Ok(())
+    }
+}
@@ -6,7 +6,11 @@
 //! u64:     Big-endian binary representation.
 //! i64:     Big-endian binary representation, with sign bit flipped.
 //! f64:     Big-endian binary representation, with sign bit flipped if +, all flipped if -.
+//! Decimal: 0x01 for zero, 0x02 exponent digits 0x00 for +, 0x00 with the rest flipped for -.
 //! Value:   Like above, with type prefix 0x00=Null 0x01=Boolean 0x02=Float 0x03=Integer 0x04=String
+//!          0x05=Decimal 0x06=Bytes
+//!          一个索引中除了 NULL 都是同一种类型 和 Value 的顺序一样 所有的 NaN 编码成同一个值
+use crate::sql::decimal::Decimal;
 use crate::sql::Value;
 use crate::errors::*;
 
@@ -150,14 +154,70 @@ pub fn take_u64(bytes: &mut &[u8]) -> Result<u64> {
     Ok(n)
 }
 
+/// 小数的编码和小数位数无关 1.5 和 1.50 相同
+/// 指数是第一位有效数字的位置 指数相同的时候按照有效数字的字符串排序 短的更小
+pub fn encode_decimal(decimal: &Decimal) -> Vec<u8> {
+    let decimal = decimal.normalize();
+    if decimal.value() == 0 {
+        return vec![0x01];
+    }
+    let digits = decimal.value().unsigned_abs().to_string();
+    let exponent = digits.len() as i16 - decimal.scale() as i16;
+    let mut bytes = ((exponent as u16) ^ (1 << 15)).to_be_bytes().to_vec();
+    bytes.extend(digits.bytes());
+    bytes.push(0x00);
+    if decimal.value() > 0 {
+        [&[0x02][..], &bytes].concat()
+    } else {
+        bytes.iter_mut().for_each(|b| *b = !*b);
+        [&[0x00][..], &bytes].concat()
+    }
+}
+
+pub fn take_decimal(bytes: &mut &[u8]) -> Result<Decimal> {
+    let flip = match take_byte(bytes)? {
+        0x01 => return Decimal::new(0, 0),
+        0x00 => 0xff,
+        0x02 => 0x00,
+        n => return Err(Error::Encoding(format!("Invalid decimal prefix {:x?}", n))),
+    };
+    let exponent = u16::from_be_bytes([take_byte(bytes)? ^ flip, take_byte(bytes)? ^ flip]);
+    let exponent = (exponent ^ (1 << 15)) as i16;
+    let mut value: i128 = 0;
+    let mut digits = 0;
+    loop {
+        match take_byte(bytes)? ^ flip {
+            0x00 => break,
+            b @ b'0'..=b'9' => {
+                value = value
+                    .checked_mul(10)
+                    .and_then(|v| v.checked_add((b - b'0') as i128))
+                    .ok_or_else(|| Error::Encoding("decimal overflow".to_string()))?;
+                digits += 1;
+            }
+            b => return Err(Error::Encoding(format!("Invalid decimal digit {:x?}", b))),
+        }
+    }
+    if flip == 0xff {
+        value = -value;
+    }
+    // 去掉了末尾的0之后 整数只有整数部分
+    let scale = u8::try_from(digits - exponent)
+        .map_err(|_| Error::Encoding(format!("Invalid decimal exponent {}", exponent)))?;
+    Decimal::new(value, scale)
+}
+
 /// 编码一个value
 pub fn encode_value(value: &Value) -> Vec<u8> {
     match value {
         Value::Null => vec![0x00],
         Value::Bool(b) => vec![0x01, encode_boolean(*b)],
+        Value::Float(f) if f.is_nan() => [&[0x02][..], &encode_f64(f64::NAN)].concat(),
         Value::Float(f) => [&[0x02][..], &encode_f64(*f)].concat(),
         Value::Integer(i) => [&[0x03][..], &encode_i64(*i)].concat(),
         Value::String(s) => [&[0x04][..], &encode_string(s)].concat(),
+        Value::Decimal(d) => [&[0x05][..], &encode_decimal(d)].concat(),
+        Value::Bytes(b) => [&[0x06][..], &encode_bytes(b)].concat(),
     }
 }
 
@@ -169,6 +229,8 @@ pub fn take_value(bytes: &mut &[u8]) -> Result<Value> {
         0x02 => Ok(Value::Float(take_f64(bytes)?)),
         0x03 => Ok(Value::Integer(take_i64(bytes)?)),
         0x04 => Ok(Value::String(take_string(bytes)?)),
+        0x05 => Ok(Value::Decimal(take_decimal(bytes)?)),
+        0x06 => Ok(Value::Bytes(take_bytes(bytes)?)),
         n => Err(Error::Encoding(format!("Invalid value prefix {:x?}", n))),
     }
 }
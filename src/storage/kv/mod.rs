@@ -1,6 +1,10 @@
 pub mod mvcc;
 pub mod encoding;
 pub mod b_tree;
+pub mod batch;
+pub mod bloom;
+pub mod cache;
+pub mod suite;
 use std::{ops::{Bound, RangeBounds}, fmt::Display};
 use crate::errors::*;
 
@@ -11,6 +15,15 @@ pub use mvcc::MVCC;
 pub trait SqlStore: Display + Send + Sync {
     /// 删除key
     fn delete(&mut self, key: &[u8]) -> Result<()>;
+
+    /// 删除范围内所有的key 返回删除的数量
+    fn delete_range(&mut self, range: MyRange) -> Result<u64> {
+        let keys = self.scan(range).map(|r| r.map(|(k, _)| k)).collect::<Result<Vec<_>>>()?;
+        for key in keys.iter() {
+            self.delete(key)?;
+        }
+        Ok(keys.len() as u64)
+    }
     /// flush数据
     fn flush(&mut self) -> Result<()>;
 
@@ -20,20 +33,31 @@ pub trait SqlStore: Display + Send + Sync {
     /// 规定一个范围进行kv查询
     fn scan(&self, range: MyRange) -> Scan;
 
+    /// 范围查询 最多返回limit个kv reverse为true时从后往前拿
+    fn scan_limit(&self, range: MyRange, limit: usize, reverse: bool) -> Result<KvBatch> {
+        let scan = self.scan(range);
+        if reverse {
+            scan.rev().take(limit).collect()
+        } else {
+            scan.take(limit).collect()
+        }
+    }
+
     /// 设置key
     fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()>;
 }
 
 /// 设置范围
+#[derive(Clone)]
 pub struct MyRange {
     start: Bound<Vec<u8>>,
     end: Bound<Vec<u8>>,
 }
 
 impl MyRange {
-    /// 设置自己的range
+    /// 设置自己的range 开始大于结束的范围换成一个空的范围 BTreeMap::range 遇到这样的范围会panic
     pub fn new<R: RangeBounds<Vec<u8>>>(range: R) -> Self {
-        Self {
+        let range = Self {
             start: match range.start_bound() {
                 Bound::Included(v) => Bound::Included(v.to_vec()),
                 Bound::Excluded(v) => Bound::Excluded(v.to_vec()),
@@ -44,6 +68,23 @@ impl MyRange {
                 Bound::Excluded(v) => Bound::Excluded(v.to_vec()),
                 Bound::Unbounded => Bound::Unbounded,
             },
+        };
+        if range.is_empty() {
+            return Self {
+                start: Bound::Included(vec![]),
+                end: Bound::Excluded(vec![]),
+            };
+        }
+        range
+    }
+
+    /// 范围中一定没有key 例如 x > 10 and x < 5
+    pub fn is_empty(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Bound::Included(start), Bound::Included(end)) => start > end,
+            (Bound::Included(start) | Bound::Excluded(start), Bound::Excluded(end))
+            | (Bound::Excluded(start), Bound::Included(end)) => start >= end,
+            _ => false,
         }
     }
 
@@ -80,6 +121,8 @@ impl RangeBounds<Vec<u8>> for MyRange {
 }
 
 
+pub type KvBatch = Vec<(Vec<u8>, Vec<u8>)>;
+
 pub type KvRange = Vec<Result<(Vec<u8>,Vec<u8>)>>;
 
 pub type Scan = Box<dyn DoubleEndedIterator<Item = Result<(Vec<u8>, Vec<u8>)>> + Send>;
@@ -12,12 +12,19 @@ use crate::{
 use std::ops::Bound;
 use std::{
     borrow::Cow,
-    collections::HashSet,
+    collections::{BTreeMap, HashMap, HashSet},
     iter::Peekable,
     ops::RangeBounds,
-    sync::{Arc, RwLock, RwLockReadGuard},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock, RwLockReadGuard,
+    },
+    time::{Duration, Instant},
 };
 
+use super::batch::{BatchScan, DEFAULT_SCAN_BATCH_SIZE};
+use super::bloom::{BloomFilters, BloomStatus, Keyspace};
+use super::cache::{CacheStatus, RowCache, Versions};
 use super::SqlStore;
 use crate::errors::Result;
 
@@ -29,29 +36,150 @@ pub struct Status {
     pub txns_active: u64,
     /// 当前的存储实现是什么
     pub storage: String,
+    /// 垃圾回收的统计信息
+    pub vacuum: VacuumStatus,
+    /// 最新版本不是删除标记的key的数量
+    pub keys: u64,
+    /// 所有版本的记录数量 包括删除标记
+    pub versions: u64,
+    /// 存储中所有key和value的字节数 包括事务的元数据
+    pub size: u64,
+    /// 布隆过滤器的统计信息 没有开启的时候都是0
+    #[serde(default)]
+    pub bloom: BloomStatus,
+    /// 点查询缓存的统计信息 没有开启的时候都是0
+    #[serde(default)]
+    pub cache: CacheStatus,
 }
 
+/// 不需要扫描存储就能得到的统计信息 用来输出监控指标
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Metrics {
+    /// 当前有多少个活跃事务
+    pub txns_active: u64,
+    /// 布隆过滤器的统计信息 没有开启的时候都是0
+    pub bloom: BloomStatus,
+    /// 点查询缓存的统计信息 没有开启的时候都是0
+    pub cache: CacheStatus,
+}
+
+/// 垃圾回收(vacuum)的统计信息
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct VacuumStatus {
+    /// 一共执行了多少次vacuum
+    pub runs: u64,
+    /// 最近一次vacuum使用的水位线 低于水位线的版本对所有事务都可见
+    pub watermark: u64,
+    /// 一共删除了多少个旧版本的记录
+    pub versions: u64,
+    /// 一共删除了多少个快照
+    pub snapshots: u64,
+}
+
+/// 等待锁的时候每次重新检查的间隔
+const LOCK_WAIT_INTERVAL: Duration = Duration::from_millis(5);
+
+/// 正常关闭的时候写入的元数据 下次启动的时候看到它就不需要恢复
+const CLEAN_SHUTDOWN: &[u8] = b"clean_shutdown";
+
+/// 垃圾回收的时候默认保留最近多少条提交日志
+pub const DEFAULT_COMMIT_LOG_RETENTION: u64 = 100_000;
+
+/// 等待图 key是正在等待的事务 value是它在等待的事务 用来检测死锁
+type WaitGraph = Arc<Mutex<HashMap<u64, u64>>>;
+
+/// 还没有结束的扫描固定住的快照 key是快照的水位线 value是扫描的数量
+/// 事务结束之后扫描还可以继续 垃圾回收不能删除这些快照可见的版本
+type Pins = Arc<Mutex<BTreeMap<u64, usize>>>;
+
 #[derive(Clone)]
 pub struct MVCC {
     store: Arc<RwLock<Box<dyn SqlStore>>>,
+    /// 事务扫描时默认的批大小
+    scan_batch_size: usize,
+    /// 所有事务共享的等待图
+    waits: WaitGraph,
+    /// 点查询使用的布隆过滤器 None表示不使用
+    blooms: Option<Arc<BloomFilters>>,
+    /// 点查询的缓存 None表示不使用
+    cache: Option<Arc<RowCache>>,
+    pins: Pins,
+    /// 活跃事务的数量 开启的时候加一 提交或者回滚的时候减一
+    active: Arc<AtomicU64>,
+    /// 垃圾回收的时候保留最近多少条提交日志 为0的时候不删除
+    commit_log_retention: u64,
 }
 
 impl MVCC {
     /// 创建一个mvcc
     pub fn new(store: Box<dyn SqlStore>) -> Self {
+        // 只在创建的时候扫描一次上次留下的活跃事务
+        let active = Self::active_txns(&*store).map_or(0, |txns| txns.len() as u64);
         Self {
             store: Arc::new(RwLock::new(store)),
+            scan_batch_size: DEFAULT_SCAN_BATCH_SIZE,
+            waits: Arc::new(Mutex::new(HashMap::new())),
+            blooms: None,
+            cache: None,
+            pins: Pins::default(),
+            active: Arc::new(AtomicU64::new(active)),
+            commit_log_retention: DEFAULT_COMMIT_LOG_RETENTION,
         }
     }
 
+    /// 垃圾回收的时候只保留最近retention条提交日志 为0的时候全部保留
+    pub fn with_commit_log_retention(mut self, retention: u64) -> Self {
+        self.commit_log_retention = retention;
+        self
+    }
+
+    /// get 的时候缓存key的所有版本 最多使用capacity字节 为0的时候不使用缓存
+    pub fn with_row_cache(mut self, capacity: usize) -> Self {
+        self.cache = (capacity > 0).then(|| Arc::new(RowCache::new(capacity)));
+        self
+    }
+
+    /// get 之前先检查布隆过滤器 keyspace 决定每个key使用哪一个过滤器
+    pub fn with_bloom_filters(mut self, keyspace: Keyspace) -> Self {
+        self.blooms = Some(Arc::new(BloomFilters::new(keyspace)));
+        self
+    }
+
+    /// 设置扫描的批大小
+    pub fn with_scan_batch_size(mut self, scan_batch_size: usize) -> Self {
+        self.scan_batch_size = scan_batch_size;
+        self
+    }
+
     /// 开启一个事务 基于给定的mode
     pub fn begin_with_mode(&self, mode: Mode) -> Result<MvccTransaction> {
-        MvccTransaction::begin(self.store.clone(), mode)
+        let mut txn = MvccTransaction::begin(
+            self.store.clone(),
+            self.waits.clone(),
+            mode,
+            self.scan_batch_size,
+        )?;
+        txn.blooms = self.blooms.clone();
+        txn.cache = self.cache.clone();
+        txn.pins = self.pins.clone();
+        txn.active = self.active.clone();
+        self.active.fetch_add(1, Ordering::Relaxed);
+        Ok(txn)
     }
 
     /// 恢复事务
     pub fn resume(&self, id: u64) -> Result<MvccTransaction> {
-        MvccTransaction::resume(self.store.clone(), id)
+        let mut txn = MvccTransaction::resume(
+            self.store.clone(),
+            self.waits.clone(),
+            id,
+            self.scan_batch_size,
+        )?;
+        txn.blooms = self.blooms.clone();
+        txn.cache = self.cache.clone();
+        txn.pins = self.pins.clone();
+        txn.active = self.active.clone();
+        Ok(txn)
     }
 
     /// 设置 元数据
@@ -66,9 +194,168 @@ impl MVCC {
         store.get(&Key::Metadata(key.into()).encode())
     }
 
+    /// 检查存储是否可以正常读取
+    pub fn ping(&self) -> Result<()> {
+        self.store.read()?.get(&Key::TxnNext.encode())?;
+        Ok(())
+    }
+
+    /// 所有活跃事务的id
+    fn active_txns(store: &dyn SqlStore) -> Result<Vec<u64>> {
+        store
+            .scan(MyRange::new(
+                Key::TxnActive(0).encode()..Key::TxnActive(u64::MAX).encode(),
+            ))
+            .map(|r| match Key::decode(&r?.0)? {
+                Key::TxnActive(id) => Ok(id),
+                k => Err(Error::Internal(format!(
+                    "expect get TxnActive but get {:?}",
+                    k
+                ))),
+            })
+            .collect()
+    }
+
+    /// 启动的时候调用 上次没有正常关闭的话 回滚所有还在活跃的事务
+    /// 重启之后没有客户端能继续这些事务 不回滚的话它们的写入会一直挡住其他事务
+    /// 返回回滚的事务数
+    pub fn recover(&self) -> Result<u64> {
+        {
+            let mut store = self.store.write()?;
+            let marker = Key::Metadata(CLEAN_SHUTDOWN.into()).encode();
+            if store.get(&marker)?.is_some() {
+                // 删除标记 这次运行中崩溃的话下次启动还需要恢复
+                store.delete(&marker)?;
+                return store.flush().map(|_| 0);
+            }
+        }
+        let active = Self::active_txns(&**self.store.read()?)?;
+        for id in active.iter() {
+            self.resume(*id)?.rollback()?;
+        }
+        Ok(active.len() as u64)
+    }
+
+    /// 最后一条提交日志的序号 没有的时候是0
+    pub fn last_commit_seq(&self) -> Result<u64> {
+        Self::commit_seq(&**self.store.read()?)
+    }
+
+    /// 已经删除的最后一条提交日志的序号 没有删除过的时候是0
+    fn commit_trim(store: &dyn SqlStore) -> Result<u64> {
+        match store.get(&Key::CommitTrim.encode())? {
+            Some(ref v) => deserialize(v),
+            None => Ok(0),
+        }
+    }
+
+    /// 删除序号不超过 最后的序号 - retention 的提交日志 返回删除的条数
+    fn trim_commit_log(store: &mut dyn SqlStore, retention: u64) -> Result<u64> {
+        let trim = Self::commit_seq(store)?.saturating_sub(retention);
+        if retention == 0 || trim <= Self::commit_trim(store)? {
+            return Ok(0);
+        }
+        let trimmed = store.delete_range(MyRange::new(
+            Key::CommitLog(0).encode()..=Key::CommitLog(trim).encode(),
+        ))?;
+        store.set(&Key::CommitTrim.encode(), serialize(&trim)?)?;
+        Ok(trimmed)
+    }
+
+    fn commit_seq(store: &dyn SqlStore) -> Result<u64> {
+        match store.get(&Key::CommitSeq.encode())? {
+            Some(ref v) => deserialize(v),
+            None => Ok(0),
+        }
+    }
+
+    /// 序号大于after的提交日志 最多limit条 按照提交的顺序返回
+    /// after之后的日志已经被删除的时候报错 不能跳过中间的提交
+    pub fn commit_log(&self, after: u64, limit: usize) -> Result<Vec<(u64, Vec<u8>)>> {
+        let store = self.store.read()?;
+        let trimmed = Self::commit_trim(&**store)?;
+        if after < trimmed {
+            let message = format!("commit log up to {} has been trimmed", trimmed);
+            return Err(Error::sql(ErrorCode::SnapshotTooOld, message));
+        }
+        store
+            .scan(MyRange::new((
+                Bound::Excluded(Key::CommitLog(after).encode()),
+                Bound::Included(Key::CommitLog(u64::MAX).encode()),
+            )))
+            .take(limit)
+            .map(|item| {
+                let (k, v) = item?;
+                match Key::decode(&k)? {
+                    Key::CommitLog(seq) => Ok((seq, v)),
+                    k => Err(Error::Mvcc(format!("expect get commit log key get : {:?}", k))),
+                }
+            })
+            .collect()
+    }
+
+    /// 正常关闭 把数据写到磁盘 没有活跃事务的时候写入正常关闭的标记
+    /// 返回是否写入了标记
+    pub fn shutdown(&self) -> Result<bool> {
+        let mut store = self.store.write()?;
+        let clean = Self::active_txns(&**store)?.is_empty();
+        if clean {
+            store.set(&Key::Metadata(CLEAN_SHUTDOWN.into()).encode(), Vec::new())?;
+        }
+        store.flush()?;
+        Ok(clean)
+    }
+
     /// 获得当前存储状态
+    /// 监控指标只读取计数器 不扫描存储
+    pub fn get_metrics(&self) -> Result<Metrics> {
+        Ok(Metrics {
+            txns_active: self.active.load(Ordering::Relaxed),
+            bloom: match &self.blooms {
+                Some(blooms) => blooms.status()?,
+                None => BloomStatus::default(),
+            },
+            cache: match &self.cache {
+                Some(cache) => cache.status()?,
+                None => CacheStatus::default(),
+            },
+        })
+    }
+
     pub fn get_status(&self) -> Result<Status> {
         let store = self.store.read()?;
+        let size = store
+            .scan(MyRange::new(..))
+            .try_fold(0, |size, r| r.map(|(k, v)| size + (k.len() + v.len()) as u64))?;
+        // 同一个key的版本是连续的 最后一个是最新的版本
+        let (mut keys, mut versions) = (0, 0);
+        let mut last: Option<(Vec<u8>, bool)> = None;
+        let scan = store.scan(MyRange::new((
+            Bound::Included(vec![0xff]),
+            Bound::Unbounded,
+        )));
+        for item in scan {
+            let (k, v) = item?;
+            let key = match Key::decode(&k)? {
+                Key::Record(key, _) => key.into_owned(),
+                k => {
+                    return Err(Error::Internal(format!(
+                        "expect get Record but get {:?}",
+                        k
+                    )))
+                }
+            };
+            let deleted = deserialize::<Option<Vec<u8>>>(&v)?.is_none();
+            match last.take() {
+                Some((last_key, false)) if last_key != key => keys += 1,
+                _ => {}
+            }
+            last = Some((key, deleted));
+            versions += 1;
+        }
+        if let Some((_, false)) = last {
+            keys += 1;
+        }
         return Ok(Status {
             txns: match store.get(&Key::TxnNext.encode())? {
                 Some(ref v) => deserialize(v)?,
@@ -81,8 +368,191 @@ impl MVCC {
                 .into_iter()
                 .try_fold(0, |count, r| r.map(|_| count + 1))?,
             storage: store.to_string(),
+            vacuum: match store.get(&Key::Vacuum.encode())? {
+                Some(ref v) => deserialize(v)?,
+                None => VacuumStatus::default(),
+            },
+            keys,
+            versions,
+            size,
+            bloom: match &self.blooms {
+                Some(blooms) => blooms.status()?,
+                None => BloomStatus::default(),
+            },
+            cache: match &self.cache {
+                Some(cache) => cache.status()?,
+                None => CacheStatus::default(),
+            },
         });
     }
+
+    /// 垃圾回收 删除已经对任何活跃事务都不可见的旧版本记录 以及不再需要的快照
+    /// 返回的是本次回收的统计信息
+    pub fn vacuum(&self) -> Result<VacuumStatus> {
+        let mut store = self.store.write()?;
+        let next: u64 = match store.get(&Key::TxnNext.encode())? {
+            Some(ref v) => deserialize(v)?,
+            None => 1,
+        };
+
+        // 计算水位线 对于每一个活跃事务 版本号小于 min(invisible, version) 的记录一定是可见的
+        // 没有活跃事务的话 水位线就是下一个事务号
+        let mut watermark = next;
+        let active = store
+            .scan(MyRange::new(
+                Key::TxnActive(0).encode()..Key::TxnActive(u64::MAX).encode(),
+            ))
+            .collect::<Result<Vec<_>>>()?;
+        for (k, v) in active {
+            let id = match Key::decode(&k)? {
+                Key::TxnActive(id) => id,
+                k => {
+                    return Err(Error::Internal(format!(
+                        "expect get TxnActive but get {:?}",
+                        k
+                    )))
+                }
+            };
+            let version = match deserialize(&v)? {
+                Mode::Snapshot { version } => version,
+                _ => id,
+            };
+            let invisible: HashSet<u64> = match store.get(&Key::TxnSnapshot(version).encode())? {
+                Some(ref v) => deserialize(v)?,
+                None => HashSet::new(),
+            };
+            let horizon = invisible.into_iter().chain(Some(version)).min().unwrap_or(version);
+            watermark = watermark.min(horizon);
+        }
+        // 还在进行的扫描 它们的事务可能已经结束了
+        if let Some((horizon, _)) = self.pins.lock()?.first_key_value() {
+            watermark = watermark.min(*horizon);
+        }
+
+        // 水位线之下的范围删除所有事务都能看到 真正删除它删除的版本之后就不需要它了
+        let mut compacted = 0;
+        for delete in RangeDelete::load(&**store)? {
+            if delete.version >= watermark {
+                continue;
+            }
+            let scan = store.scan(MyRange::new(
+                Key::Record((&delete.start).into(), 0).encode()
+                    ..Key::Record((&delete.end).into(), 0).encode(),
+            ));
+            // 同一个key被删除的是比delete.version旧的所有版本 编码之后是从版本0开始连续的一段
+            let mut keys: Vec<Vec<u8>> = Vec::new();
+            for item in scan {
+                match Key::decode(&item?.0)? {
+                    Key::Record(key, version) if version < delete.version => {
+                        if keys.last().map(|k| k.as_slice()) != Some(&*key) {
+                            keys.push(key.into_owned());
+                        }
+                    }
+                    Key::Record(..) => {}
+                    k => return Err(Error::Internal(format!("expect get Record but get {:?}", k))),
+                }
+            }
+            for key in keys {
+                if let Some(cache) = &self.cache {
+                    cache.invalidate(&key)?;
+                }
+                compacted += store.delete_range(MyRange::new(
+                    Key::Record((&key).into(), 0).encode()
+                        ..Key::Record(key.into(), delete.version).encode(),
+                ))?;
+            }
+            store.delete(&Key::RangeDelete(delete.start.into(), delete.version).encode())?;
+        }
+        // 水位线之下的事务不会再和其他事务冲突 不需要保留它们写入的key
+        store.delete_range(MyRange::new(
+            Key::TxnUpdate(0, vec![].into()).encode()
+                ..Key::TxnUpdate(watermark, vec![].into()).encode(),
+        ))?;
+
+        // 找到所有需要删除的版本
+        // 同一个key 只保留水位线之下最新的那个版本 如果这个版本是删除标记 那它也可以删除
+        let mut garbage = Vec::new();
+        let mut last: Option<(Vec<u8>, Vec<u8>, bool)> = None;
+        let scan = store.scan(MyRange::new((
+            Bound::Included(vec![0xff]),
+            Bound::Unbounded,
+        )));
+        for item in scan {
+            let (k, v) = item?;
+            let (key, version) = match Key::decode(&k)? {
+                Key::Record(key, version) => (key.into_owned(), version),
+                k => {
+                    return Err(Error::Internal(format!(
+                        "expect get Record but get {:?}",
+                        k
+                    )))
+                }
+            };
+            if version >= watermark {
+                continue;
+            }
+            let deleted = deserialize::<Option<Vec<u8>>>(&v)?.is_none();
+            match last.take() {
+                // 同一个key的更老版本
+                Some((last_key, last_k, _)) if last_key == key => garbage.push(last_k),
+                // 上一个key最新的版本是删除标记
+                Some((_, last_k, true)) => garbage.push(last_k),
+                _ => {}
+            }
+            last = Some((key, k, deleted));
+        }
+        if let Some((_, k, true)) = last {
+            garbage.push(k);
+        }
+
+        // 同一个key需要删除的版本总是从最老的版本开始连续的 每个key删除一个范围
+        let mut ranges: Vec<(Vec<u8>, u64)> = Vec::new();
+        for k in garbage.iter() {
+            match Key::decode(k)? {
+                Key::Record(key, version) => match ranges.last_mut() {
+                    Some((last, max)) if **last == *key => *max = version,
+                    _ => ranges.push((key.into_owned(), version)),
+                },
+                k => return Err(Error::Internal(format!("expect get Record but get {:?}", k))),
+            }
+        }
+        for (key, version) in ranges {
+            if let Some(cache) = &self.cache {
+                cache.invalidate(&key)?;
+            }
+            store.delete_range(MyRange::new(
+                Key::Record((&key).into(), 0).encode()..=Key::Record(key.into(), version).encode(),
+            ))?;
+        }
+        // 水位线之下的快照都不再需要了
+        let snapshots = store.delete_range(MyRange::new(
+            Key::TxnSnapshot(0).encode()..Key::TxnSnapshot(watermark).encode(),
+        ))?;
+
+        let mut vacuum = VacuumStatus {
+            runs: 1,
+            watermark,
+            versions: garbage.len() as u64 + compacted,
+            snapshots,
+        };
+
+        // 累计统计信息
+        let mut total: VacuumStatus = match store.get(&Key::Vacuum.encode())? {
+            Some(ref v) => deserialize(v)?,
+            None => VacuumStatus::default(),
+        };
+        total.runs += vacuum.runs;
+        total.watermark = watermark;
+        total.versions += vacuum.versions;
+        total.snapshots += vacuum.snapshots;
+        store.set(&Key::Vacuum.encode(), serialize(&total)?)?;
+        let commits = Self::trim_commit_log(&mut **store, self.commit_log_retention)?;
+        store.flush()?;
+        debug!("vacuum trim {} commit logs", commits);
+        debug!("vacuum {:?}", vacuum);
+        vacuum.runs = total.runs;
+        Ok(vacuum)
+    }
 }
 
 /// mvcc 事务模式
@@ -94,6 +564,9 @@ pub enum Mode {
     ReadOnly,
     /// 只读事务 只读一个已经提交的事务
     Snapshot { version: u64 },
+    /// 读已提交的可读可写事务 每条语句开始的时候重新获取快照
+    /// 可以看到事务开始之后其他事务提交的数据
+    ReadCommitted,
 }
 
 impl Mode {
@@ -102,6 +575,7 @@ impl Mode {
             Mode::ReadWrite => true,
             Mode::ReadOnly => false,
             Mode::Snapshot { .. } => false,
+            Mode::ReadCommitted => true,
         }
     }
 
@@ -111,12 +585,20 @@ impl Mode {
         match (self, other) {
             (Mode::ReadWrite, Mode::ReadOnly) => true,
             (Mode::Snapshot { .. }, Mode::ReadOnly) => true,
+            (Mode::ReadCommitted, Mode::ReadOnly) => true,
+            (Mode::ReadCommitted, Mode::ReadWrite) => true,
             (_, _) if self == other => true,
             (_, _) => false,
         }
     }
 }
 
+/// 撤销日志 (日志的key,record的key,之前的值)
+type Undo = (Vec<u8>, Vec<u8>, Option<Vec<u8>>);
+
+/// 事务写入的 (key, 事务开始之前的值, 现在的值)
+pub type TxnWrite = (Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>);
+
 /// An MVCC transaction.
 pub struct MvccTransaction {
     /// 存储
@@ -127,13 +609,35 @@ pub struct MvccTransaction {
     mode: Mode,
     /// 快照 存储版本信息的
     snapshot: Snapshot,
+    /// 扫描的批大小
+    scan_batch_size: usize,
+    /// 等待图
+    waits: WaitGraph,
+    /// 写冲突的时候最多等待多久 为0的时候直接报错
+    lock_timeout: Duration,
+    blooms: Option<Arc<BloomFilters>>,
+    cache: Option<Arc<RowCache>>,
+    pins: Pins,
+    active: Arc<AtomicU64>,
+    /// 只读副本固定住快照 事务结束之后垃圾回收也不会删除它能看到的版本
+    _pin: Option<Arc<Pin>>,
 }
 
 impl MvccTransaction {
     /// 开启一个事务
-    fn begin(store: Arc<RwLock<Box<dyn SqlStore>>>, mode: Mode) -> Result<Self> {
+    fn begin(
+        store: Arc<RwLock<Box<dyn SqlStore>>>,
+        waits: WaitGraph,
+        mode: Mode,
+        scan_batch_size: usize,
+    ) -> Result<Self> {
         // 先找到新的
         let mut store_ = store.write()?;
+        // 快照事务要读取的版本必须存在 在分配事务号之前检查
+        let as_of = match mode {
+            Mode::Snapshot { version } => Some(Snapshot::as_of(&**store_, version)?),
+            _ => None,
+        };
         let next = store_.get(&Key::TxnNext.encode())?;
         let id: u64 = match next {
             Some(v) => deserialize(&v)?,
@@ -166,7 +670,10 @@ impl MvccTransaction {
         // 设置保存一下快照
         store_.set(&Key::TxnSnapshot(id).encode(), serialize(&invisible)?)?;
 
-        let snapshot = Snapshot::new(id, invisible);
+        let snapshot = match as_of {
+            Some(snapshot) => snapshot,
+            None => Snapshot::new(id, invisible),
+        };
 
         drop(store_);
 
@@ -175,11 +682,24 @@ impl MvccTransaction {
             id,
             mode,
             snapshot,
+            scan_batch_size,
+            waits,
+            lock_timeout: Duration::ZERO,
+            blooms: None,
+            cache: None,
+            pins: Pins::default(),
+            active: Arc::default(),
+            _pin: None,
         })
     }
 
     /// 恢复一个旧的活跃事务
-    fn resume(store: Arc<RwLock<Box<dyn SqlStore>>>, id: u64) -> Result<Self> {
+    fn resume(
+        store: Arc<RwLock<Box<dyn SqlStore>>>,
+        waits: WaitGraph,
+        id: u64,
+        scan_batch_size: usize,
+    ) -> Result<Self> {
         let store_ = store.read()?;
 
         // 获得之前事务的mode
@@ -188,7 +708,12 @@ impl MvccTransaction {
             None => return Err(Error::Internal(format!("No active transaction {}", id))),
         };
         let snapshot = match &mode {
-            Mode::Snapshot { version } => Snapshot::restore(&store_, *version)?,
+            Mode::Snapshot { version } => Snapshot::as_of(&**store_, *version)?,
+            // 读已提交的事务使用最近一次刷新的快照
+            Mode::ReadCommitted => match store_.get(&Key::TxnRefresh(id).encode())? {
+                Some(ref v) => deserialize(v)?,
+                None => Snapshot::restore(&store_, id)?,
+            },
             _ => Snapshot::restore(&store_, id)?,
         };
         std::mem::drop(store_);
@@ -198,9 +723,68 @@ impl MvccTransaction {
             id,
             mode,
             snapshot,
+            scan_batch_size,
+            waits,
+            lock_timeout: Duration::ZERO,
+            blooms: None,
+            cache: None,
+            pins: Pins::default(),
+            active: Arc::default(),
+            _pin: None,
         })
     }
 
+    /// 设置扫描的批大小
+    pub fn set_scan_batch_size(&mut self, scan_batch_size: usize) {
+        self.scan_batch_size = scan_batch_size;
+    }
+
+    /// 扫描的批大小
+    pub fn scan_batch_size(&self) -> usize {
+        self.scan_batch_size
+    }
+
+    /// 设置写冲突时等待的时间
+    pub fn set_lock_timeout(&mut self, lock_timeout: Duration) {
+        self.lock_timeout = lock_timeout;
+    }
+
+    /// 读已提交的事务重新获取快照 其他模式什么也不做
+    /// 新的快照可以看到所有已经提交的事务 以及自己写入的数据
+    pub fn refresh(&mut self) -> Result<()> {
+        if self.mode != Mode::ReadCommitted {
+            return Ok(());
+        }
+        let mut store = self.store.write()?;
+        let next: u64 = match store.get(&Key::TxnNext.encode())? {
+            Some(ref v) => deserialize(v)?,
+            None => 1,
+        };
+        let mut invisible = HashSet::new();
+        for r in store.scan(MyRange::new(
+            Key::TxnActive(0).encode()..Key::TxnActive(next).encode(),
+        )) {
+            let (k, _) = r?;
+            match Key::decode(&k)? {
+                Key::TxnActive(id) if id == self.id => {}
+                Key::TxnActive(id) => {
+                    invisible.insert(id);
+                }
+                k => {
+                    return Err(Error::Internal(format!(
+                        "expect get TxnActive but get {:?}",
+                        k
+                    )))
+                }
+            };
+        }
+        let snapshot = Snapshot::new(next - 1, invisible);
+        // 保存下来 恢复事务的时候使用
+        store.set(&Key::TxnRefresh(self.id).encode(), serialize(&snapshot)?)?;
+        self.snapshot = snapshot;
+        Ok(())
+    }
+
     /// 获得当前事务的事务id
     pub fn get_id(&self) -> u64 {
         self.id
@@ -211,14 +795,112 @@ impl MvccTransaction {
         self.mode
     }
 
+    /// 同一个事务的只读副本 快照和自己写入的数据都和当前事务一样
+    /// 给还没有读完的结果继续读取 不能写入 也不能用来提交或者回滚
+    pub fn reader(&self) -> Result<Self> {
+        let pin = Pin::new(self.pins.clone(), self.snapshot.horizon())?;
+        Ok(Self {
+            store: self.store.clone(),
+            id: self.id,
+            mode: Mode::ReadOnly,
+            snapshot: self.snapshot.clone(),
+            scan_batch_size: self.scan_batch_size,
+            waits: self.waits.clone(),
+            lock_timeout: self.lock_timeout,
+            blooms: self.blooms.clone(),
+            cache: self.cache.clone(),
+            pins: self.pins.clone(),
+            active: self.active.clone(),
+            _pin: Some(Arc::new(pin)),
+        })
+    }
+
     /// 提交一个事务
     pub fn commit(&self) -> Result<()> {
-        // 将update key删除
-        self.get_rollback_delete_update_key()?;
+        self.commit_with_log(None).map(|_| ())
+    }
+
+    /// 提交一个事务 log不为None的时候和提交一起写入一条提交日志 返回日志的序号
+    pub fn commit_with_log(&self, log: Option<Vec<u8>>) -> Result<Option<u64>> {
+        // update key保留到垃圾回收 范围删除需要用它检查冲突
         let mut store = self.store.write()?;
+        self.clear_savepoints(&mut **store)?;
+        // 在同一个锁中分配序号 序号的顺序就是提交的顺序
+        let seq = match log {
+            Some(log) => {
+                let seq = MVCC::commit_seq(&**store)? + 1;
+                store.set(&Key::CommitLog(seq).encode(), log)?;
+                store.set(&Key::CommitSeq.encode(), serialize(&seq)?)?;
+                Some(seq)
+            }
+            None => None,
+        };
         // 将活跃的事务删除一个
-        store.delete(&Key::TxnActive(self.id).encode())?;
-        store.flush()
+        self.deactivate(&mut **store)?;
+        store.delete(&Key::TxnRefresh(self.id).encode())?;
+        store.flush()?;
+        Ok(seq)
+    }
+
+    /// 当前事务写入过的key 以及事务开始之前和现在的值 None表示不存在或者已经删除
+    /// 按照key的顺序返回
+    /// 被范围删除的值是None 范围删除本身在range_deletes中
+    pub fn writes(&self) -> Result<Vec<TxnWrite>> {
+        let store = self.store.read()?;
+        let keys = store
+            .scan(MyRange::new(
+                Key::TxnUpdate(self.id, vec![].into()).encode()
+                    ..Key::TxnUpdate(self.id + 1, vec![].into()).encode(),
+            ))
+            .map(|item| match Key::decode(&item?.0)? {
+                // 保存的是record的key
+                Key::TxnUpdate(_, key) => match Key::decode(&key)? {
+                    Key::Record(key, _) => Ok(Some(key.into_owned())),
+                    Key::RangeDelete(..) => Ok(None),
+                    k => Err(Error::Mvcc(format!("expect get record key get : {:?}", k))),
+                },
+                k => Err(Error::Mvcc(format!("expect get txnUpdate key get : {:?}", k))),
+            })
+            .filter_map(Result::transpose)
+            .collect::<Result<Vec<_>>>()?;
+        let deletes = RangeDelete::visible(&**store, &self.snapshot)?;
+        let mut writes = Vec::new();
+        for key in keys {
+            let (mut before, mut after) = (None, None);
+            let scan = store.scan(MyRange::new(
+                Key::Record(key.as_slice().into(), 0).encode()
+                    ..=Key::Record(key.as_slice().into(), self.snapshot.version).encode(),
+            ));
+            for item in scan.rev() {
+                let (k, v) = item?;
+                match Key::decode(&k)? {
+                    Key::Record(_, version) if version == self.id => after = deserialize(&v)?,
+                    // 有写冲突检测 快照中最新的其他版本就是提交之前最新的版本
+                    Key::Record(_, version)
+                        if before.is_none() && self.snapshot.is_visible(version) =>
+                    {
+                        before = match deletes.iter().any(|d| d.hides(&key, version)) {
+                            true => Some(None),
+                            false => Some(deserialize(&v)?),
+                        }
+                    }
+                    Key::Record(..) => {}
+                    k => return Err(Error::Mvcc(format!("expect get record key get : {:?}", k))),
+                }
+            }
+            writes.push((key, before.flatten(), after));
+        }
+        Ok(writes)
+    }
+
+    /// 当前事务的范围删除 [start, end) 按照start的顺序返回
+    pub fn range_deletes(&self) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let store = self.store.read()?;
+        Ok(RangeDelete::load(&**store)?
+            .into_iter()
+            .filter(|d| d.version == self.id)
+            .map(|d| (d.start, d.end))
+            .collect())
     }
 
     /// 回滚当前事务
@@ -227,29 +909,149 @@ impl MvccTransaction {
         let rollback = self.get_rollback_delete_update_key()?;
         let mut store = self.store.write()?;
         for item in rollback {
+            self.invalidate(&item)?;
             store.delete(&item)?;
         }
-        store.delete(&Key::TxnActive(self.id).encode())?;
+        self.clear_savepoints(&mut **store)?;
+        self.deactivate(&mut **store)?;
+        store.delete(&Key::TxnRefresh(self.id).encode())?;
 
         store.flush()
     }
 
+    /// 删除活跃事务的标记 重复提交或者回滚的时候计数不会减两次
+    fn deactivate(&self, store: &mut dyn SqlStore) -> Result<()> {
+        let key = Key::TxnActive(self.id).encode();
+        if store.get(&key)?.is_some() {
+            store.delete(&key)?;
+            self.active.fetch_sub(1, Ordering::Relaxed);
+        }
+        Ok(())
+    }
+
+    /// 创建一个保存点 同名的保存点可以有多个 使用的时候找最近的一个
+    pub fn savepoint(&self, name: &str) -> Result<()> {
+        let mut store = self.store.write()?;
+        let mut savepoints = Self::load_savepoints(&**store, self.id)?;
+        savepoints.push(name.to_string());
+        store.set(
+            &Key::TxnSavepoints(self.id).encode(),
+            serialize(&savepoints)?,
+        )
+    }
+
+    /// 撤销保存点之后的修改 保存点之后创建的保存点都会被删除
+    pub fn rollback_to_savepoint(&self, name: &str) -> Result<()> {
+        let mut store = self.store.write()?;
+        let mut savepoints = Self::load_savepoints(&**store, self.id)?;
+        let position = Self::find_savepoint(&savepoints, name)?;
+        let undo = self.scan_undo(&**store, position as u64 + 1)?;
+        // 从最新的一层开始恢复 最后留下的是创建保存点的时候的值
+        for (undo_key, key, value) in undo.into_iter().rev() {
+            self.invalidate(&key)?;
+            match value {
+                Some(value) => store.set(&key, value)?,
+                None => {
+                    store.delete(&key)?;
+                    store.delete(&Key::TxnUpdate(self.id, key.into()).encode())?;
+                }
+            }
+            store.delete(&undo_key)?;
+        }
+        savepoints.truncate(position + 1);
+        store.set(
+            &Key::TxnSavepoints(self.id).encode(),
+            serialize(&savepoints)?,
+        )
+    }
+
+    /// 删除保存点 之后的修改合并到上一个保存点
+    pub fn release_savepoint(&self, name: &str) -> Result<()> {
+        let mut store = self.store.write()?;
+        let mut savepoints = Self::load_savepoints(&**store, self.id)?;
+        let position = Self::find_savepoint(&savepoints, name)?;
+        // 从旧到新合并 上一层已经有的key保留上一层的值
+        for (undo_key, key, value) in self.scan_undo(&**store, position as u64 + 1)? {
+            store.delete(&undo_key)?;
+            if position > 0 {
+                let target = Key::TxnUndo(self.id, position as u64, key.into()).encode();
+                if store.get(&target)?.is_none() {
+                    store.set(&target, serialize(&value)?)?;
+                }
+            }
+        }
+        savepoints.truncate(position);
+        store.set(
+            &Key::TxnSavepoints(self.id).encode(),
+            serialize(&savepoints)?,
+        )
+    }
+
+    fn load_savepoints(store: &dyn SqlStore, id: u64) -> Result<Vec<String>> {
+        match store.get(&Key::TxnSavepoints(id).encode())? {
+            Some(ref v) => deserialize(v),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    fn find_savepoint(savepoints: &[String], name: &str) -> Result<usize> {
+        savepoints
+            .iter()
+            .rposition(|s| s == name)
+            .ok_or_else(|| {
+                Error::sql(ErrorCode::InvalidSavepoint, format!("savepoint {} does not exist", name))
+            })
+    }
+
+    /// 得到层数不小于level的撤销日志 按照层数排序
+    fn scan_undo(&self, store: &dyn SqlStore, level: u64) -> Result<Vec<Undo>> {
+        let scan = store.scan(MyRange::new(
+            Key::TxnUndo(self.id, level, vec![].into()).encode()
+                ..Key::TxnUndo(self.id + 1, 0, vec![].into()).encode(),
+        ));
+        let mut undo = Vec::new();
+        for item in scan {
+            let (k, v) = item?;
+            match Key::decode(&k)? {
+                Key::TxnUndo(_, _, key) => {
+                    let key = key.into_owned();
+                    undo.push((k, key, deserialize(&v)?))
+                }
+                k => return Err(Error::Mvcc(format!("expect get TxnUndo key get : {:?}", k))),
+            }
+        }
+        Ok(undo)
+    }
+
+    /// 事务结束的时候删除保存点和撤销日志
+    fn clear_savepoints(&self, store: &mut dyn SqlStore) -> Result<()> {
+        store.delete_range(MyRange::new(
+            Key::TxnUndo(self.id, 0, vec![].into()).encode()
+                ..Key::TxnUndo(self.id + 1, 0, vec![].into()).encode(),
+        ))?;
+        store.delete(&Key::TxnSavepoints(self.id).encode())
+    }
+
+    /// 存储中的一个版本有修改 删除这个key的缓存 需要持有存储的写锁
+    fn invalidate(&self, record: &[u8]) -> Result<()> {
+        if let (Some(cache), Key::Record(key, _)) = (&self.cache, Key::decode(record)?) {
+            cache.invalidate(&key)?;
+        }
+        Ok(())
+    }
+
     fn get_rollback_delete_update_key(&self) -> Result<Vec<Vec<u8>>> {
         let mut roallback = Vec::new();
         let mut store = self.store.write()?;
-        let scan = store.scan(MyRange::new(
+        let range = MyRange::new(
             Key::TxnUpdate(self.id, vec![].into()).encode()
                 ..Key::TxnUpdate(self.id + 1, vec![].into()).encode(),
-        ));
+        );
 
-        for item in scan {
+        for item in store.scan(range.clone()) {
             let (k, _) = item?;
             match Key::decode(&k)? {
-                Key::TxnUpdate(_, key) => {
-                    // 把update 的key删除 已经不需要了
-                    store.delete(&k)?;
-                    roallback.push(key.into_owned());
-                }
+                Key::TxnUpdate(_, key) => roallback.push(key.into_owned()),
                 k => {
                     return Err(Error::Mvcc(format!(
                         "expect get txnUpdate key get : {:?}",
@@ -258,6 +1060,8 @@ impl MvccTransaction {
                 }
             };
         }
+        // 把update 的key删除 已经不需要了
+        store.delete_range(range)?;
         store.flush()?;
         return Ok(roallback);
     }
@@ -265,15 +1069,33 @@ impl MvccTransaction {
     /// 下面的操作都是recored相关的操作 所以获得的都应该是record
     /// 删除key数据
     pub fn delete(&mut self, key: &[u8]) -> Result<()> {
-        self.write(key, None)
+        self.write(vec![(key.to_vec(), None)])
     }
 
     /// 得到一个key
     pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
         let store = self.store.read()?;
-        //   从0版本到当前版本 获取
+        if let Some(blooms) = &self.blooms {
+            if !blooms.may_contain(key, |keyspace| Self::record_keys(&**store, keyspace))? {
+                return Ok(None);
+            }
+        }
+        // 可见的最新版本被范围删除了 就是已经删除
+        let deletes = RangeDelete::visible(&**store, &self.snapshot)?;
+        if let Some(cache) = &self.cache {
+            // 缓存的是所有的版本 比快照新的版本也在里面
+            let versions = cache.get_or_load(key, || Self::load_versions(&**store, key))?;
+            return Ok(versions
+                .into_iter()
+                .rev()
+                .find(|(version, _)| self.snapshot.is_visible(*version))
+                .filter(|(version, _)| !deletes.iter().any(|d| d.hides(key, *version)))
+                .and_then(|(_, value)| value));
+        }
+        //   从0版本到快照的版本 获取 包括当前事务自己写入的版本
         let scan = store.scan(MyRange::new(
-            Key::Record(key.into(), 0).encode()..Key::Record(key.into(), self.id).encode(),
+            Key::Record(key.into(), 0).encode()
+                ..=Key::Record(key.into(), self.snapshot.version).encode(),
         ));
         let mut res = Ok(None);
         // 开始寻找我们需要的
@@ -283,6 +1105,9 @@ impl MvccTransaction {
             match Key::decode(&k)? {
                 Key::Record(_, version) => {
                     if self.snapshot.is_visible(version) {
+                        if deletes.iter().any(|d| d.hides(key, version)) {
+                            return Ok(None);
+                        }
                         let re = deserialize(&v);
                         debug!("get version record {}",version);
                         //res = re;
@@ -301,6 +1126,40 @@ impl MvccTransaction {
         res
     }
 
+    /// 存储中一个key的所有版本
+    fn load_versions(store: &dyn SqlStore, key: &[u8]) -> Result<Versions> {
+        let scan = store.scan(MyRange::new(
+            Key::Record(key.into(), 0).encode()..=Key::Record(key.into(), u64::MAX).encode(),
+        ));
+        let mut versions = Vec::new();
+        for item in scan {
+            let (k, v) = item?;
+            match Key::decode(&k)? {
+                Key::Record(_, version) => versions.push((version, deserialize(&v)?)),
+                k => return Err(Error::Encoding(format!("expect a key recored but get {:?}", k))),
+            }
+        }
+        Ok(versions)
+    }
+
+    /// 存储中前缀是prefix的所有key 包括所有的版本和删除标记 用来建立布隆过滤器
+    fn record_keys(store: &dyn SqlStore, prefix: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let start = Key::Record(prefix.into(), 0).encode();
+        let end = Key::Record(prefix_end(prefix).into(), 0).encode();
+        let scan = store.scan(MyRange::new(start..end));
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        for item in scan {
+            let (k, _) = item?;
+            match Key::decode(&k)? {
+                // 同一个key的版本是连续的
+                Key::Record(key, _) if keys.last().map(|k| k.as_slice()) == Some(&*key) => {}
+                Key::Record(key, _) => keys.push(key.into_owned()),
+                k => return Err(Error::Internal(format!("expect get Record but get {:?}", k))),
+            }
+        }
+        Ok(keys)
+    }
+
     /// 根据范围获得多个数据
     pub fn scan(&self, range: impl RangeBounds<Vec<u8>>) -> Result<super::Scan> {
         // 重新设置一下start end 因为我们的record还包括version
@@ -315,8 +1174,16 @@ impl MvccTransaction {
             Bound::Unbounded => Bound::Unbounded,
         };
 
-        let scan = self.store.read()?.scan(MyRange::new((start, end)));
-        Ok(Box::new(MvccScan::new(scan, self.snapshot.clone())))
+        // 分批从store中拿数据 不需要一直持有锁
+        // 同一个key的所有版本在同一批中 垃圾回收删除一个key的版本的时候不会只看到一部分
+        let scan: super::Scan = Box::new(
+            BatchScan::new(self.store.clone(), MyRange::new((start, end)), self.scan_batch_size)
+                .with_group(Key::record_group),
+        );
+        let pin = Pin::new(self.pins.clone(), self.snapshot.horizon())?;
+        let deletes = RangeDelete::visible(&**self.store.read()?, &self.snapshot)?;
+        let deleted = serialize(&None::<Vec<u8>>)?;
+        Ok(Box::new(MvccScan::new(scan, self.snapshot.clone(), deletes, deleted, pin)))
     }
 
     /// 根据前缀获取多个数据 (k,v)
@@ -325,35 +1192,222 @@ impl MvccTransaction {
         if prefix.len() == 0 {
             return Err(Error::Internal("Scan prefix cannot be empty".to_string()));
         }
-        let start = prefix.to_vec();
-        let mut end = start.clone();
-        for i in (0..end.len()).rev() {
-            match end[i] {
-                0xff => {
-                    end[i] = 0x00;
-                    continue;
-                }
-                v => {
-                    end[i] = v + 1;
-                    break;
+        self.scan(prefix.to_vec()..prefix_end(prefix))
+    }
+
+    /// 删除前缀下所有的key
+    pub fn delete_prefix(&mut self, prefix: &[u8]) -> Result<()> {
+        if prefix.is_empty() {
+            return Err(Error::Internal("Delete prefix cannot be empty".to_string()));
+        }
+        self.delete_range(prefix, &prefix_end(prefix))
+    }
+
+    /// 删除[start, end)中所有的key 只写一个范围删除 不需要扫描范围内的key
+    /// 范围内有并发事务写入的时候冲突 和写入单个key一样等待它结束
+    /// 被删除的版本在垃圾回收的时候才真正删除
+    pub fn delete_range(&mut self, start: &[u8], end: &[u8]) -> Result<()> {
+        let records = || {
+            (Key::Record(start.into(), 0).encode(), Key::Record(end.into(), 0).encode())
+        };
+        let conflict = |session: &dyn SqlStore| -> Result<Option<u64>> {
+            let next: u64 = match session.get(&Key::TxnNext.encode())? {
+                Some(ref v) => deserialize(v)?,
+                None => 1,
+            };
+            // 不可见的事务和比自己新的事务在范围内写入过
+            let (first, last) = records();
+            let concurrent = self.snapshot.invisible.iter().copied().chain(self.id + 1..next);
+            for id in concurrent {
+                let mut scan = session.scan(MyRange::new(
+                    Key::TxnUpdate(id, (&first).into()).encode()
+                        ..Key::TxnUpdate(id, (&last).into()).encode(),
+                ));
+                if scan.next().transpose()?.is_some() {
+                    return Ok(Some(id));
                 }
             }
-        }
-        self.scan(start..end)
+            // 和并发事务的范围删除有重叠
+            Ok(RangeDelete::load(session)?
+                .into_iter()
+                .find(|d| {
+                    d.version != self.id
+                        && d.overlaps(start, end)
+                        && (!self.snapshot.is_visible(d.version) || d.version > self.id)
+                })
+                .map(|d| d.version))
+        };
+        let apply = |session: &mut dyn SqlStore| -> Result<()> {
+            // 自己在范围内写入的版本和范围删除的版本号相同 直接改成删除标记
+            let (first, last) = records();
+            let own = session
+                .scan(MyRange::new(
+                    Key::TxnUpdate(self.id, first.into()).encode()
+                        ..Key::TxnUpdate(self.id, last.into()).encode(),
+                ))
+                .map(|item| match Key::decode(&item?.0)? {
+                    Key::TxnUpdate(_, key) => match Key::decode(&key)? {
+                        Key::Record(key, _) => Ok((key.into_owned(), None)),
+                        k => Err(Error::Mvcc(format!("expect get record key get : {:?}", k))),
+                    },
+                    k => Err(Error::Mvcc(format!("expect get txnUpdate key get : {:?}", k))),
+                })
+                .collect::<Result<Vec<_>>>()?;
+            self.write_records(session, &own)?;
+            // 同一个事务从同一个位置删除过 保留更大的范围
+            let key = Key::RangeDelete(start.into(), self.id).encode();
+            let end = match session.get(&key)? {
+                Some(ref v) => end.to_vec().max(deserialize(v)?),
+                None => end.to_vec(),
+            };
+            let level = Self::load_savepoints(session, self.id)?.len() as u64;
+            self.put(session, level, key, serialize(&end)?)
+        };
+        self.write_with(conflict, apply)
     }
 
     /// 设置key val
     pub fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
-        self.write(key, Some(value))
+        self.write(vec![(key.to_vec(), Some(value))])
+    }
+
+    /// 一次设置多个key val 只获取一次存储的锁
+    /// 有一个key冲突的话所有的key都不会写入
+    pub fn set_batch(&mut self, items: Vec<(Vec<u8>, Vec<u8>)>) -> Result<()> {
+        self.write(items.into_iter().map(|(k, v)| (k, Some(v))).collect())
+    }
+
+    /// 一次设置或者删除多个key 值为None的时候删除
+    pub fn write_batch(&mut self, items: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Result<()> {
+        self.write(items)
     }
 
     /// 写记录
-    fn write(&self, key: &[u8], value: Option<Vec<u8>>) -> Result<()> {
+    /// 写冲突的时候 如果冲突的事务还没有结束 就等待它结束之后再检查一次
+    /// 等待超时或者出现死锁都会报错
+    fn write(&self, items: Vec<(Vec<u8>, Option<Vec<u8>>)>) -> Result<()> {
+        let conflict = |session: &dyn SqlStore| -> Result<Option<u64>> {
+            let deletes = RangeDelete::load(session)?;
+            for (key, _) in items.iter() {
+                if let Some(blocker) = self.conflict(session, &deletes, key)? {
+                    return Ok(Some(blocker));
+                }
+            }
+            Ok(None)
+        };
+        self.write_with(conflict, |session| self.write_records(session, &items))
+    }
+
+    /// 没有冲突的时候在同一个写锁中调用apply写入
+    fn write_with<C, A>(&self, conflict: C, apply: A) -> Result<()>
+    where
+        C: Fn(&dyn SqlStore) -> Result<Option<u64>>,
+        A: FnOnce(&mut dyn SqlStore) -> Result<()>,
+    {
         if !self.mode.mutable() {
-            return Err(Error::Mvcc("unwritable mvcc mode".to_string()));
+            return Err(Error::sql(
+                ErrorCode::ReadOnlyTransaction,
+                "cannot write in a read only transaction".to_string(),
+            ));
+        }
+        let result = self.write_wait(conflict, apply);
+        // 不管成功失败 都不再等待了
+        self.waits.lock()?.remove(&self.id);
+        result
+    }
+
+    fn write_wait<C, A>(&self, conflict: C, apply: A) -> Result<()>
+    where
+        C: Fn(&dyn SqlStore) -> Result<Option<u64>>,
+        A: FnOnce(&mut dyn SqlStore) -> Result<()>,
+    {
+        let deadline = Instant::now() + self.lock_timeout;
+        loop {
+            let mut session = self.store.write()?;
+            let blocker = match conflict(&**session)? {
+                None => return apply(&mut **session),
+                Some(version) => version,
+            };
+            // 冲突的事务已经提交了 等多久都没有用
+            if self.lock_timeout.is_zero()
+                || session.get(&Key::TxnActive(blocker).encode())?.is_none()
+            {
+                return Err(Error::sql(
+                    ErrorCode::SerializationFailure,
+                    "record cannot be write".to_string(),
+                ));
+            }
+            drop(session);
+            if Instant::now() >= deadline {
+                return Err(Error::sql(
+                    ErrorCode::SerializationFailure,
+                    format!("lock wait timeout, record is locked by transaction {}", blocker),
+                ));
+            }
+            self.wait_for(blocker)?;
+            std::thread::sleep(LOCK_WAIT_INTERVAL);
         }
-        let mut session = self.store.write()?;
+    }
 
+    /// 写入当前事务的版本 需要持有存储的写锁
+    fn write_records(
+        &self,
+        session: &mut dyn SqlStore,
+        items: &[(Vec<u8>, Option<Vec<u8>>)],
+    ) -> Result<()> {
+        // 有保存点的时候 记录这一层第一次修改之前的值
+        let level = Self::load_savepoints(session, self.id)?.len() as u64;
+        for (key, value) in items {
+            // 持有写锁的时候放进过滤器 建立过滤器的扫描不会漏掉它
+            if let Some(blooms) = &self.blooms {
+                blooms.insert(key)?;
+            }
+            if let Some(cache) = &self.cache {
+                cache.invalidate(key)?;
+            }
+            // 设置key  并设置version 为当前事务的id
+            let key = Key::Record(key.as_slice().into(), self.id).encode();
+            self.put(session, level, key, serialize(value)?)?;
+        }
+        Ok(())
+    }
+
+    /// 写入一个当前事务的key 记录撤销日志和update
+    fn put(
+        &self,
+        session: &mut dyn SqlStore,
+        level: u64,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<()> {
+        if level > 0 {
+            let undo = Key::TxnUndo(self.id, level, (&key).into()).encode();
+            if session.get(&undo)?.is_none() {
+                let old = session.get(&key)?;
+                session.set(&undo, serialize(&old)?)?;
+            }
+        }
+        let update = Key::TxnUpdate(self.id, (&key).into()).encode();
+        // 设置update 这里是为了方便后续roallback
+        session.set(&update, vec![])?;
+        session.set(&key, value)
+    }
+
+    /// 找到和当前事务冲突的版本 deletes是存储中所有的范围删除
+    fn conflict(
+        &self,
+        session: &dyn SqlStore,
+        deletes: &[RangeDelete],
+        key: &[u8],
+    ) -> Result<Option<u64>> {
+        // 并发事务的范围删除覆盖了这个key
+        if let Some(d) = deletes.iter().find(|d| {
+            d.version != self.id
+                && d.covers(key)
+                && (!self.snapshot.is_visible(d.version) || d.version > self.id)
+        }) {
+            return Ok(Some(d.version));
+        }
         // 得到当前不可见的事务id最小值 没有就是 当前id+1
         let min = self
             .snapshot
@@ -362,7 +1416,7 @@ impl MvccTransaction {
             .min()
             .cloned()
             .unwrap_or(self.id + 1);
-        let mut scan = session
+        let scan = session
             .scan(MyRange::new(
                 // 找到记录
                 Key::Record(key.into(), min).encode()
@@ -372,11 +1426,13 @@ impl MvccTransaction {
 
         // 查询一下当前的记录是否可见
         // 但凡有一个不可见的 就不能操作
-        while let Some((k, _)) = scan.next().transpose()? {
+        // 读已提交的事务可以看到比自己新的版本 但是不能覆盖它
+        for r in scan {
+            let (k, _) = r?;
             match Key::decode(&k)? {
                 Key::Record(_, version) => {
-                    if !self.snapshot.is_visible(version) {
-                        return Err(Error::Mvcc("record cannot be write".to_string()));
+                    if !self.snapshot.is_visible(version) || version > self.id {
+                        return Ok(Some(version));
                     }
                 }
                 k => {
@@ -387,14 +1443,33 @@ impl MvccTransaction {
                 }
             };
         }
-        std::mem::drop(scan);
+        Ok(None)
+    }
 
-        // 设置key  并设置version 为当前事务的id
-        let key = Key::Record(key.into(), self.id).encode();
-        let update = Key::TxnUpdate(self.id, (&key).into()).encode();
-        // 设置update 这里是为了方便后续roallback
-        session.set(&update, vec![])?;
-        session.set(&key, serialize(&value)?)
+    /// 在等待图中记录当前事务在等待blocker
+    /// 沿着等待图能走回当前事务 说明出现了死锁 由当前事务报错退出
+    fn wait_for(&self, blocker: u64) -> Result<()> {
+        let mut waits = self.waits.lock()?;
+        let mut next = blocker;
+        // 最多走等待图的大小那么多步
+        for _ in 0..=waits.len() {
+            let id = match waits.get(&next) {
+                Some(&id) => id,
+                None => break,
+            };
+            if id == self.id {
+                return Err(Error::sql(
+                    ErrorCode::Deadlock,
+                    format!(
+                        "deadlock detected, transaction {} and {} wait for each other",
+                        self.id, blocker
+                    ),
+                ));
+            }
+            next = id;
+        }
+        waits.insert(self.id, blocker);
+        Ok(())
     }
 }
 
@@ -412,6 +1487,11 @@ impl Snapshot {
         Self { version, invisible }
     }
 
+    /// 版本号小于水位线的数据一定是可见的
+    fn horizon(&self) -> u64 {
+        self.invisible.iter().copied().chain(Some(self.version)).min().unwrap_or(self.version)
+    }
+
     /// 传入的版本号对应的数据是否可见
     pub fn is_visible(&self, version: u64) -> bool {
         // 如果小于当前的版本号，并且不在invisible中 就是可见
@@ -431,6 +1511,26 @@ impl Snapshot {
             ))),
         }
     }
+
+    /// 快照事务读取的视图 只能看到version这个事务开始之前已经提交的数据
+    /// version这个事务自己的写入不可见 这样不管它之后有没有提交 看到的数据都不会变
+    fn as_of(session: &dyn SqlStore, version: u64) -> Result<Self> {
+        match session.get(&Key::TxnSnapshot(version).encode())? {
+            Some(ref v) => {
+                let mut invisible: HashSet<u64> = deserialize(v)?;
+                invisible.insert(version);
+                Ok(Self { version, invisible })
+            }
+            // 水位线之下的快照已经被垃圾回收删除
+            None => match session.get(&Key::Vacuum.encode())? {
+                Some(ref v) if version < deserialize::<VacuumStatus>(v)?.watermark => {
+                    let message = format!("version {} has been vacuumed", version);
+                    Err(Error::sql(ErrorCode::SnapshotTooOld, message))
+                }
+                _ => Err(Error::Mvcc(format!("version {} does not exist", version))),
+            },
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -442,12 +1542,29 @@ enum Key<'a> {
     /// Txn snapshot, containing concurrent active txns at start of txn.
     TxnSnapshot(u64),
     /// 更新 标记 用于rollback
-    /// (version,record_key)
+    /// (version,record_key) 提交之后保留到垃圾回收 范围删除用它检查并发事务的写入
     TxnUpdate(u64, Cow<'a, [u8]>),
     /// 记录的key和version
     Record(Cow<'a, [u8]>, u64),
     /// 保存元数据的key
     Metadata(Cow<'a, [u8]>),
+    /// 垃圾回收的统计信息
+    Vacuum,
+    /// 读已提交的事务最近一次刷新的快照
+    TxnRefresh(u64),
+    /// 事务的保存点 按照创建的顺序保存名字
+    TxnSavepoints(u64),
+    /// 撤销日志 (事务id,层数,record_key) 值是record在这一层第一次修改之前的值
+    /// 第n个保存点之后的修改在第n层
+    TxnUndo(u64, u64, Cow<'a, [u8]>),
+    /// 最后一条提交日志的序号
+    CommitSeq,
+    /// 提交日志 (序号) 值是事务提交的时候写入的内容 按照提交的顺序排列
+    CommitLog(u64),
+    /// 垃圾回收删除的最后一条提交日志的序号
+    CommitTrim,
+    /// 范围删除 (开始的key,事务id) 值是结束的key 范围内比它旧的版本都被删除
+    RangeDelete(Cow<'a, [u8]>, u64),
 }
 
 impl<'a> Key<'a> {
@@ -462,12 +1579,33 @@ impl<'a> Key<'a> {
                 [&[0x04][..], &encode_u64(id), &encode_bytes(&key)].concat()
             }
             Self::Metadata(key) => [&[0x05][..], &encode_bytes(&key)].concat(),
+            Self::Vacuum => vec![0x06],
+            Self::TxnRefresh(id) => [&[0x07][..], &encode_u64(id)].concat(),
+            Self::TxnSavepoints(id) => [&[0x08][..], &encode_u64(id)].concat(),
+            Self::TxnUndo(id, level, key) => [
+                &[0x09][..],
+                &encode_u64(id),
+                &encode_u64(level),
+                &encode_bytes(&key),
+            ]
+            .concat(),
+            Self::CommitSeq => vec![0x0a],
+            Self::CommitLog(seq) => [&[0x0b][..], &encode_u64(seq)].concat(),
+            Self::CommitTrim => vec![0x0c],
+            Self::RangeDelete(start, version) => {
+                [&[0x0d][..], &encode_bytes(&start), &encode_u64(version)].concat()
+            }
             Self::Record(key, version) => {
                 [&[0xff][..], &encode_bytes(&key), &encode_u64(version)].concat()
             }
         }
     }
 
+    /// 编码之后的Record去掉版本号 同一个key的所有版本是同一组
+    fn record_group(key: &[u8]) -> &[u8] {
+        &key[..key.len().saturating_sub(8)]
+    }
+
     /// 解码
     fn decode(mut bytes: &[u8]) -> Result<Self> {
         use encoding::*;
@@ -478,6 +1616,14 @@ impl<'a> Key<'a> {
             0x03 => Self::TxnSnapshot(take_u64(bytes)?),
             0x04 => Self::TxnUpdate(take_u64(bytes)?, take_bytes(bytes)?.into()),
             0x05 => Self::Metadata(take_bytes(bytes)?.into()),
+            0x06 => Self::Vacuum,
+            0x07 => Self::TxnRefresh(take_u64(bytes)?),
+            0x08 => Self::TxnSavepoints(take_u64(bytes)?),
+            0x09 => Self::TxnUndo(take_u64(bytes)?, take_u64(bytes)?, take_bytes(bytes)?.into()),
+            0x0a => Self::CommitSeq,
+            0x0b => Self::CommitLog(take_u64(bytes)?),
+            0x0c => Self::CommitTrim,
+            0x0d => Self::RangeDelete(take_bytes(bytes)?.into(), take_u64(bytes)?),
             0xff => Self::Record(take_bytes(bytes)?.into(), take_u64(bytes)?),
             b => {
                 return Err(Error::Internal(format!(
@@ -495,20 +1641,102 @@ impl<'a> Key<'a> {
     }
 }
 
+/// 范围删除 [start, end) 中版本比version旧的记录都被删除
+#[derive(Debug)]
+struct RangeDelete {
+    start: Vec<u8>,
+    end: Vec<u8>,
+    version: u64,
+}
+
+impl RangeDelete {
+    fn covers(&self, key: &[u8]) -> bool {
+        self.start.as_slice() <= key && key < self.end.as_slice()
+    }
+
+    fn overlaps(&self, start: &[u8], end: &[u8]) -> bool {
+        self.start.as_slice() < end && start < self.end.as_slice()
+    }
+
+    /// key的这个版本是否被删除了
+    fn hides(&self, key: &[u8], version: u64) -> bool {
+        version < self.version && self.covers(key)
+    }
+
+    /// 存储中所有的范围删除 数量和执行过的truncate差不多 垃圾回收之后就删除了
+    fn load(store: &dyn SqlStore) -> Result<Vec<Self>> {
+        store
+            .scan(MyRange::new(vec![0x0d]..vec![0x0e]))
+            .map(|item| {
+                let (k, v) = item?;
+                match Key::decode(&k)? {
+                    Key::RangeDelete(start, version) => Ok(Self {
+                        start: start.into_owned(),
+                        end: deserialize(&v)?,
+                        version,
+                    }),
+                    k => Err(Error::Internal(format!("expect get RangeDelete but get {:?}", k))),
+                }
+            })
+            .collect()
+    }
+
+    /// 快照中可以看到的范围删除
+    fn visible(store: &dyn SqlStore, snapshot: &Snapshot) -> Result<Vec<Self>> {
+        Ok(Self::load(store)?.into_iter().filter(|d| snapshot.is_visible(d.version)).collect())
+    }
+}
+
+/// 扫描固定住的快照 drop的时候释放
+struct Pin {
+    pins: Pins,
+    horizon: u64,
+}
+
+impl Pin {
+    fn new(pins: Pins, horizon: u64) -> Result<Self> {
+        *pins.lock()?.entry(horizon).or_default() += 1;
+        Ok(Self { pins, horizon })
+    }
+}
+
+impl Drop for Pin {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(count) = pins.get_mut(&self.horizon) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&self.horizon);
+            }
+        }
+    }
+}
+
 struct MvccScan {
     /// 这个scan是最原始的scan 我们需要进行包装 来解决隔离性问题，因为同一个key会对应不同的版本
     scan: Peekable<super::Scan>,
     /// 保留next_back上一个得到的item 这样才能对比version
     next_back_seen: Option<Vec<u8>>,
+    _pin: Pin,
 }
 impl MvccScan {
-    /// 创建scan
-    fn new(mut scan: super::Scan, snapshot: Snapshot) -> Self {
+    /// 创建scan deleted是编码之后的删除标记
+    fn new(
+        mut scan: super::Scan,
+        snapshot: Snapshot,
+        deletes: Vec<RangeDelete>,
+        deleted: Vec<u8>,
+        pin: Pin,
+    ) -> Self {
         // 我们首先过滤掉不可见的版本
         // 这里的k-v会包含多个版本，我们需要的是最新的版本
         scan = Box::new(scan.filter_map(move |r| {
             r.and_then(|(k, v)| match Key::decode(&k)? {
                 Key::Record(_, version) if !snapshot.is_visible(version) => Ok(None),
+                // 被范围删除的版本当作删除标记
+                Key::Record(key, version) if deletes.iter().any(|d| d.hides(&key, version)) => {
+                    Ok(Some((key.into_owned(), deleted.clone())))
+                }
                 Key::Record(key, _) => Ok(Some((key.into_owned(), v))),
                 k => Err(Error::Internal(format!("Expected Record, got {:?}", k))),
             })
@@ -517,6 +1745,7 @@ impl MvccScan {
         Self {
             scan: scan.peekable(),
             next_back_seen: None,
+            _pin: pin,
         }
     }
 
@@ -578,6 +1807,24 @@ impl DoubleEndedIterator for MvccScan {
     }
 }
 
+/// 前缀的结束位置 所有以prefix开头的key都比它小
+pub fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    for i in (0..end.len()).rev() {
+        match end[i] {
+            0xff => {
+                end[i] = 0x00;
+                continue;
+            }
+            v => {
+                end[i] = v + 1;
+                break;
+            }
+        }
+    }
+    end
+}
+
 fn serialize<V: Serialize>(value: &V) -> Result<Vec<u8>> {
     Ok(bincode::serialize(value)?)
 }
@@ -585,3 +1832,449 @@ fn serialize<V: Serialize>(value: &V) -> Result<Vec<u8>> {
 fn deserialize<'a, V: Deserialize<'a>>(bytes: &'a [u8]) -> Result<V> {
     Ok(bincode::deserialize(bytes)?)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::kv::b_tree::BtreeStore;
+
+    #[test]
+    fn own_writes_test() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(BtreeStore::new()));
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.set(b"a", vec![1])?;
+        // 能读到自己还没有提交的写入 其他事务读不到
+        assert_eq!(txn.get(b"a")?, Some(vec![1]));
+        let reader = mvcc.begin_with_mode(Mode::ReadOnly)?;
+        assert_eq!(reader.get(b"a")?, None);
+        txn.delete(b"a")?;
+        assert_eq!(txn.get(b"a")?, None);
+        txn.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn vacuum_test() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(BtreeStore::new()));
+        for value in [vec![1], vec![2], vec![3]] {
+            let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+            txn.set(b"a", value)?;
+            txn.set(b"b", vec![0])?;
+            txn.commit()?;
+        }
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.delete(b"b")?;
+        txn.commit()?;
+
+        // 一个读事务在进行 它之后的写入不能被回收
+        let reader = mvcc.begin_with_mode(Mode::ReadOnly)?;
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.set(b"a", vec![4])?;
+        txn.commit()?;
+
+        let status = mvcc.vacuum()?;
+        assert_eq!(status.watermark, reader.get_id());
+        // a 删除两个旧版本  b 删除三个版本和一个删除标记
+        assert_eq!(status.versions, 6);
+        assert_eq!(reader.get(b"a")?, Some(vec![3]));
+        assert_eq!(reader.get(b"b")?, None);
+        reader.commit()?;
+
+        mvcc.vacuum()?;
+        let txn = mvcc.begin_with_mode(Mode::ReadOnly)?;
+        assert_eq!(txn.get(b"a")?, Some(vec![4]));
+        assert_eq!(mvcc.get_status()?.vacuum.versions, 7);
+        Ok(())
+    }
+
+    #[test]
+    fn scan_vacuum_test() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(BtreeStore::new())).with_scan_batch_size(1);
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        for key in [b"a", b"b", b"c", b"d"] {
+            txn.set(key, vec![1])?;
+        }
+        txn.commit()?;
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.delete(b"b")?;
+        txn.commit()?;
+
+        // 扫描的过程中垃圾回收删除了b的所有版本 不会只看到b的旧版本
+        let reader = mvcc.begin_with_mode(Mode::ReadOnly)?;
+        let mut scan = reader.scan(..)?;
+        assert_eq!(scan.next().transpose()?, Some((b"a".to_vec(), vec![1])));
+        assert_eq!(mvcc.vacuum()?.versions, 2);
+
+        // 事务结束之后扫描还可以继续 之后的写入和垃圾回收不会影响它
+        reader.commit()?;
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.set(b"d", vec![2])?;
+        txn.delete(b"c")?;
+        txn.commit()?;
+        assert_eq!(mvcc.vacuum()?.versions, 0);
+        let rest = scan.collect::<Result<Vec<_>>>()?;
+        assert_eq!(rest, vec![(b"c".to_vec(), vec![1]), (b"d".to_vec(), vec![1])]);
+
+        // 扫描结束之后可以回收
+        assert_eq!(mvcc.vacuum()?.versions, 3);
+        Ok(())
+    }
+
+    #[test]
+    fn recover_test() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(BtreeStore::new()));
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.set(b"a", vec![1])?;
+        // 有活跃的事务 不是正常关闭
+        assert!(!mvcc.shutdown()?);
+        // 模拟崩溃 事务没有提交也没有回滚
+        drop(txn);
+
+        assert_eq!(mvcc.recover()?, 1);
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        assert_eq!(txn.get(b"a")?, None);
+        txn.set(b"a", vec![2])?;
+        txn.commit()?;
+
+        // 正常关闭之后启动不需要恢复 标记只使用一次
+        assert!(mvcc.shutdown()?);
+        let txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        drop(txn);
+        assert_eq!(mvcc.recover()?, 0);
+        assert_eq!(mvcc.recover()?, 1);
+        assert_eq!(mvcc.get_status()?.txns_active, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn status_test() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(BtreeStore::new()));
+        let status = mvcc.get_status()?;
+        assert_eq!((status.keys, status.versions, status.size), (0, 0, 0));
+
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.set(b"a", vec![1])?;
+        txn.set(b"b", vec![2])?;
+        txn.commit()?;
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.set(b"a", vec![3])?;
+        txn.delete(b"b")?;
+        txn.set(b"c", vec![4])?;
+        txn.commit()?;
+
+        // b 的最新版本是删除标记
+        let status = mvcc.get_status()?;
+        assert_eq!((status.keys, status.versions), (2, 5));
+        assert!(status.size > 0);
+
+        mvcc.vacuum()?;
+        let vacuumed = mvcc.get_status()?;
+        assert_eq!((vacuumed.keys, vacuumed.versions), (2, 2));
+        assert!(vacuumed.size < status.size);
+        Ok(())
+    }
+
+    #[test]
+    fn metrics_test() -> Result<()> {
+        // 上次留下的活跃事务在创建的时候计算进去
+        let mut store = BtreeStore::new();
+        store.set(&Key::TxnActive(1).encode(), serialize(&Mode::ReadWrite)?)?;
+        store.set(&Key::TxnSnapshot(1).encode(), serialize(&HashSet::<u64>::new())?)?;
+        store.set(&Key::TxnNext.encode(), serialize(&2u64)?)?;
+        let mvcc = MVCC::new(Box::new(store));
+        assert_eq!(mvcc.get_metrics()?.txns_active, 1);
+
+        let a = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        let b = mvcc.begin_with_mode(Mode::ReadOnly)?;
+        assert_eq!(mvcc.get_metrics()?.txns_active, 3);
+        a.commit()?;
+        b.rollback()?;
+        b.rollback()?;
+        assert_eq!(mvcc.get_metrics()?.txns_active, 1);
+        assert_eq!(mvcc.recover()?, 1);
+        assert_eq!(mvcc.get_metrics()?.txns_active, 0);
+        assert_eq!(mvcc.get_status()?.txns_active, 0);
+        Ok(())
+    }
+
+    #[test]
+    fn commit_log_trim_test() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(BtreeStore::new())).with_commit_log_retention(2);
+        for i in 1..=5 {
+            let txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+            assert_eq!(txn.commit_with_log(Some(vec![i]))?, Some(i as u64));
+        }
+        mvcc.vacuum()?;
+        // 只保留最后两条 已经删除的位置之后不能继续读取
+        let logs = mvcc.commit_log(3, 10)?;
+        assert_eq!(logs, vec![(4, vec![4]), (5, vec![5])]);
+        match mvcc.commit_log(2, 10) {
+            Err(Error::Sql(e)) => assert_eq!(e.code, ErrorCode::SnapshotTooOld),
+            r => panic!("unexpected result {:?}", r),
+        }
+        // 序号继续增加 再次回收的时候删除新的旧日志
+        let txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        assert_eq!(txn.commit_with_log(Some(vec![6]))?, Some(6));
+        mvcc.vacuum()?;
+        assert_eq!(mvcc.commit_log(4, 10)?.len(), 2);
+        assert!(mvcc.commit_log(3, 10).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn row_cache_test() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(BtreeStore::new())).with_row_cache(1024 * 1024);
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.set(b"a", vec![1])?;
+        txn.commit()?;
+
+        // 不同快照的事务共享缓存 各自只能看到自己快照中的版本
+        let reader = mvcc.begin_with_mode(Mode::ReadOnly)?;
+        assert_eq!(reader.get(b"a")?, Some(vec![1]));
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.set(b"a", vec![2])?;
+        assert_eq!(txn.get(b"a")?, Some(vec![2]));
+        assert_eq!(reader.get(b"a")?, Some(vec![1]));
+        txn.commit()?;
+        assert_eq!(reader.get(b"a")?, Some(vec![1]));
+        let status = mvcc.get_status()?.cache;
+        assert_eq!((status.entries, status.hits, status.misses), (1, 2, 2));
+
+        // 回滚和撤销保存点之后缓存也会失效
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.set(b"a", vec![3])?;
+        txn.savepoint("s")?;
+        txn.delete(b"a")?;
+        assert_eq!(txn.get(b"a")?, None);
+        txn.rollback_to_savepoint("s")?;
+        assert_eq!(txn.get(b"a")?, Some(vec![3]));
+        txn.rollback()?;
+        let txn = mvcc.begin_with_mode(Mode::ReadOnly)?;
+        assert_eq!(txn.get(b"a")?, Some(vec![2]));
+        assert_eq!(reader.get(b"a")?, Some(vec![1]));
+        reader.commit()?;
+        txn.commit()?;
+
+        // 垃圾回收删除的旧版本也不会留在缓存中
+        mvcc.vacuum()?;
+        let txn = mvcc.begin_with_mode(Mode::ReadOnly)?;
+        assert_eq!(txn.get(b"a")?, Some(vec![2]));
+        assert_eq!(mvcc.get_status()?.cache.entries, 1);
+        assert!(mvcc.get_status()?.cache.bytes > 0);
+        Ok(())
+    }
+
+    #[test]
+    fn read_committed_test() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(BtreeStore::new()));
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.set(b"a", vec![1])?;
+        txn.commit()?;
+
+        let mut snapshot = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        let mut committed = mvcc.begin_with_mode(Mode::ReadCommitted)?;
+        committed.set(b"c", vec![0])?;
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.set(b"a", vec![2])?;
+        txn.set(b"b", vec![2])?;
+        txn.commit()?;
+
+        // 刷新之前和快照隔离一样
+        assert_eq!(committed.get(b"a")?, Some(vec![1]));
+        committed.refresh()?;
+        assert_eq!(committed.get(b"a")?, Some(vec![2]));
+        assert_eq!(committed.get(b"b")?, Some(vec![2]));
+        assert_eq!(committed.get(b"c")?, Some(vec![0]));
+        // 快照隔离的事务刷新之后也看不到
+        snapshot.refresh()?;
+        assert_eq!(snapshot.get(b"a")?, Some(vec![1]));
+
+        // 恢复事务的时候使用刷新之后的快照
+        let resumed = mvcc.resume(committed.get_id())?;
+        assert_eq!(resumed.get(b"a")?, Some(vec![2]));
+
+        // 不能覆盖比自己新的事务写入的数据
+        assert!(committed.set(b"a", vec![3]).is_err());
+        committed.set(b"d", vec![3])?;
+        committed.commit()?;
+        snapshot.commit()?;
+        Ok(())
+    }
+
+    #[test]
+    fn lock_wait_test() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(BtreeStore::new()));
+        let begin = |timeout: u64| -> Result<MvccTransaction> {
+            let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+            txn.set_lock_timeout(Duration::from_millis(timeout));
+            Ok(txn)
+        };
+
+        // 冲突的事务回滚之后可以继续写
+        let mut holder = begin(0)?;
+        holder.set(b"a", vec![1])?;
+        let mut waiter = begin(5000)?;
+        let handle = std::thread::spawn(move || waiter.set(b"a", vec![2]).map(|_| waiter));
+        std::thread::sleep(Duration::from_millis(50));
+        holder.rollback()?;
+        handle.join().unwrap()?.commit()?;
+
+        // 冲突的事务提交了 等待之后还是报错
+        let mut holder = begin(0)?;
+        holder.set(b"a", vec![3])?;
+        let mut waiter = begin(5000)?;
+        let handle = std::thread::spawn(move || waiter.set(b"a", vec![4]));
+        std::thread::sleep(Duration::from_millis(50));
+        holder.commit()?;
+        assert!(handle.join().unwrap().is_err());
+
+        // 等待超时
+        let mut holder = begin(0)?;
+        holder.set(b"a", vec![5])?;
+        let mut waiter = begin(50)?;
+        assert!(waiter.set(b"a", vec![6]).is_err());
+        let mut nowait = begin(0)?;
+        assert!(nowait.set(b"a", vec![6]).is_err());
+        // 不同的key不会冲突
+        waiter.set(b"b", vec![6])?;
+        holder.rollback()?;
+        waiter.rollback()?;
+        nowait.rollback()?;
+
+        // 两个事务互相等待 后等待的那个报错
+        let mut first = begin(5000)?;
+        let mut second = begin(5000)?;
+        first.set(b"a", vec![7])?;
+        second.set(b"b", vec![7])?;
+        let handle = std::thread::spawn(move || second.set(b"a", vec![8]).map(|_| second));
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(first.set(b"b", vec![8]).is_err());
+        first.rollback()?;
+        handle.join().unwrap()?.commit()?;
+        assert_eq!(begin(0)?.get(b"a")?, Some(vec![8]));
+        Ok(())
+    }
+
+    #[test]
+    fn range_delete_test() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(BtreeStore::new())).with_row_cache(16);
+        let begin = || -> Result<MvccTransaction> {
+            let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+            txn.set_lock_timeout(Duration::ZERO);
+            Ok(txn)
+        };
+        let keys = |txn: &MvccTransaction| -> Result<Vec<Vec<u8>>> {
+            txn.scan(..)?.map(|r| r.map(|(k, _)| k)).collect()
+        };
+        let mut txn = begin()?;
+        for key in [b"a1", b"a2", b"b1"] {
+            txn.set(key, vec![1])?;
+        }
+        txn.commit()?;
+
+        // 自己之前的写入也被删除 之后的写入可以看到
+        let reader = mvcc.begin_with_mode(Mode::ReadOnly)?;
+        let mut txn = begin()?;
+        txn.set(b"a3", vec![2])?;
+        txn.delete_prefix(b"a")?;
+        assert_eq!(txn.get(b"a1")?, None);
+        assert_eq!(txn.get(b"a3")?, None);
+        txn.set(b"a2", vec![3])?;
+        assert_eq!(keys(&txn)?, vec![b"a2".to_vec(), b"b1".to_vec()]);
+        assert_eq!(txn.range_deletes()?, vec![(b"a".to_vec(), b"b".to_vec())]);
+        assert_eq!(
+            txn.writes()?,
+            vec![(b"a2".to_vec(), None, Some(vec![3])), (b"a3".to_vec(), None, None)]
+        );
+
+        // 并发事务不能写入或者删除范围内的key
+        let mut other = begin()?;
+        assert!(other.set(b"a4", vec![4]).is_err());
+        assert!(other.delete_range(b"a0", b"a5").is_err());
+        other.set(b"b2", vec![4])?;
+        txn.commit()?;
+        other.commit()?;
+        let mut other = begin()?;
+        other.set(b"b3", vec![5])?;
+        let mut txn = begin()?;
+        assert!(txn.delete_prefix(b"b").is_err());
+        txn.rollback()?;
+        other.rollback()?;
+
+        // 快照中还能看到删除之前的数据
+        assert_eq!(keys(&reader)?.len(), 3);
+        assert_eq!(reader.get(b"a1")?, Some(vec![1]));
+        reader.commit()?;
+
+        // 回滚到保存点之后范围删除也撤销了
+        let mut txn = begin()?;
+        txn.savepoint("s")?;
+        txn.delete_range(b"a", b"c")?;
+        assert!(keys(&txn)?.is_empty());
+        txn.rollback_to_savepoint("s")?;
+        assert_eq!(keys(&txn)?.len(), 3);
+        txn.commit()?;
+
+        // 垃圾回收真正删除被范围删除的版本 之后不再需要范围删除
+        // 删除a1 a2被范围删除的版本 以及a3的删除标记
+        let status = mvcc.vacuum()?;
+        assert_eq!(status.versions, 3);
+        let txn = begin()?;
+        assert!(txn.range_deletes()?.is_empty());
+        assert!(RangeDelete::load(&**mvcc.store.read()?)?.is_empty());
+        assert_eq!(txn.get(b"a2")?, Some(vec![3]));
+        assert_eq!(keys(&txn)?, vec![b"a2".to_vec(), b"b1".to_vec(), b"b2".to_vec()]);
+        Ok(())
+    }
+
+    #[test]
+    fn range_delete_vacuum_test() -> Result<()> {
+        let mvcc = MVCC::new(Box::new(BtreeStore::new())).with_row_cache(16);
+        let write = |key: &[u8], value: u8| -> Result<()> {
+            let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+            txn.set(key, vec![value])?;
+            txn.commit()
+        };
+        // a和a\0编码之后相邻 b在范围之外
+        write(b"a", 1)?;
+        write(b"a\0", 1)?;
+        write(b"b", 1)?;
+        write(b"a", 2)?;
+        // 范围删除的事务自己写入的版本和之后写入的版本都要保留
+        let mut txn = mvcc.begin_with_mode(Mode::ReadWrite)?;
+        txn.delete_range(b"a", b"b")?;
+        txn.set(b"a", vec![3])?;
+        txn.commit()?;
+        write(b"a\0", 4)?;
+        let txn = mvcc.begin_with_mode(Mode::ReadOnly)?;
+        assert_eq!(txn.get(b"a")?, Some(vec![3]));
+        txn.commit()?;
+
+        // 只删除a的两个旧版本和a\0的一个旧版本
+        assert_eq!(mvcc.vacuum()?.versions, 3);
+        let records = mvcc
+            .store
+            .read()?
+            .scan(MyRange::new((Bound::Included(vec![0xff]), Bound::Unbounded)))
+            .map(|item| {
+                let (k, v) = item?;
+                match Key::decode(&k)? {
+                    Key::Record(key, _) => Ok((key.into_owned(), deserialize(&v)?)),
+                    k => Err(Error::Internal(format!("expect get Record but get {:?}", k))),
+                }
+            })
+            .collect::<Result<Vec<(Vec<u8>, Option<Vec<u8>>)>>>()?;
+        assert_eq!(
+            records,
+            vec![
+                (b"a".to_vec(), Some(vec![3])),
+                (b"a\0".to_vec(), Some(vec![4])),
+                (b"b".to_vec(), Some(vec![1])),
+            ]
+        );
+        let txn = mvcc.begin_with_mode(Mode::ReadOnly)?;
+        assert_eq!(txn.get(b"a")?, Some(vec![3]));
+        assert_eq!(txn.get(b"a\0")?, Some(vec![4]));
+        Ok(())
+    }
+}
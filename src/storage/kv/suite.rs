@@ -0,0 +1,83 @@
+//! 所有存储都需要通过的检查 新的存储在自己的测试中用一个空的存储调用 run
+//! 结果不对的时候直接panic
+use std::ops::Bound;
+
+use super::{MyRange, SqlStore};
+use crate::errors::Result;
+
+pub fn run(store: &mut dyn SqlStore) -> Result<()> {
+    assert_eq!(store.get(b"a")?, None);
+    assert_eq!(store.scan(MyRange::new(..)).count(), 0);
+
+    // 覆盖 删除 空的值
+    store.set(b"a", vec![1])?;
+    store.set(b"b", vec![2])?;
+    store.set(b"b", vec![3])?;
+    store.set(b"c", Vec::new())?;
+    assert_eq!(store.get(b"a")?, Some(vec![1]));
+    assert_eq!(store.get(b"b")?, Some(vec![3]));
+    assert_eq!(store.get(b"c")?, Some(Vec::new()));
+    store.delete(b"a")?;
+    store.delete(b"x")?;
+    assert_eq!(store.get(b"a")?, None);
+    store.flush()?;
+    assert_eq!(store.get(b"b")?, Some(vec![3]));
+    store.delete(b"b")?;
+    store.delete(b"c")?;
+
+    // 按照字节序排列 包括0x00和0xff
+    let keys: Vec<Vec<u8>> = (0..=255u8)
+        .flat_map(|i| [vec![i], vec![i, 0], vec![i, 0xff]])
+        .collect();
+    for key in keys.iter().rev() {
+        store.set(key, key.clone())?;
+    }
+    let scan = |store: &dyn SqlStore, range| -> Result<Vec<Vec<u8>>> {
+        store.scan(range).map(|r| r.map(|(k, _)| k)).collect()
+    };
+    assert_eq!(scan(store, MyRange::new(..))?, keys);
+    let reverse = store.scan(MyRange::new(..)).rev().map(|r| r.map(|(k, _)| k));
+    assert!(reverse.collect::<Result<Vec<_>>>()?.into_iter().eq(keys.iter().rev().cloned()));
+    for (key, value) in store.scan(MyRange::new(..)).collect::<Result<Vec<_>>>()? {
+        assert_eq!(key, value);
+    }
+
+    // 各种边界的范围
+    let index = |key: &[u8]| keys.iter().position(|k| k == key).unwrap();
+    let bounds = [
+        (Bound::Included(vec![3]), Bound::Excluded(vec![5])),
+        (Bound::Excluded(vec![3]), Bound::Included(vec![5, 0])),
+        (Bound::Unbounded, Bound::Excluded(vec![0, 0xff])),
+        (Bound::Excluded(vec![255, 0]), Bound::Unbounded),
+    ];
+    for (start, end) in bounds {
+        let from = match &start {
+            Bound::Included(k) => index(k),
+            Bound::Excluded(k) => index(k) + 1,
+            Bound::Unbounded => 0,
+        };
+        let to = match &end {
+            Bound::Included(k) => index(k) + 1,
+            Bound::Excluded(k) => index(k),
+            Bound::Unbounded => keys.len(),
+        };
+        let range = MyRange::new((start, end));
+        assert_eq!(scan(store, range.clone())?, keys[from..to]);
+        let limit = store.scan_limit(range.clone(), 2, false)?;
+        assert!(limit.iter().map(|(k, _)| k).eq(keys[from..to].iter().take(2)));
+        let limit = store.scan_limit(range, 2, true)?;
+        assert!(limit.iter().map(|(k, _)| k).eq(keys[from..to].iter().rev().take(2)));
+    }
+    assert_eq!(scan(store, MyRange::new(vec![9]..vec![9]))?, Vec::<Vec<u8>>::new());
+
+    // 范围删除返回删除的数量
+    assert_eq!(store.delete_range(MyRange::new(vec![1]..vec![2]))?, 3);
+    assert_eq!(store.get(&[1, 0])?, None);
+    assert_eq!(store.get(&[2])?, Some(vec![2]));
+    assert_eq!(store.delete_range(MyRange::new(vec![1]..vec![2]))?, 0);
+    let count = keys.len() as u64 - 3;
+    assert_eq!(store.delete_range(MyRange::new(..))?, count);
+    assert_eq!(store.scan(MyRange::new(..)).count(), 0);
+    store.flush()?;
+    Ok(())
+}
@@ -1,2 +1,6 @@
+pub mod backup;
+pub mod file;
 pub mod kv;
-
+pub mod registry;
+pub mod spill;
+pub mod wal;
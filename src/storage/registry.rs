@@ -0,0 +1,100 @@
+/* 存储后端的注册表 server 启动的时候按照配置中的名字打开存储
+ * 内置 memory (只在内存中) wal (内存中的B树加预写日志 启动的时候重放) 和 file (数据在文件中 内存只保存位置)
+ * 其他的存储可以用 register 加进来 新的存储需要通过 kv::suite 中的检查
+ * */
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use super::file::FileStore;
+use super::kv::{b_tree::BtreeStore, SqlStore};
+use super::wal::{SyncPolicy, Wal};
+use crate::errors::*;
+
+/// 打开存储需要的参数
+#[derive(Clone, Debug)]
+pub struct StoreOptions {
+    /// 数据目录 不保存到磁盘的存储不使用
+    pub dir: PathBuf,
+    pub sync: SyncPolicy,
+}
+
+/// 打开一个存储 name 是存储的用途 例如 sql 或者 raft 同一个目录下不同用途的存储不能使用相同的文件
+pub type OpenStore = fn(name: &str, options: &StoreOptions) -> Result<Box<dyn SqlStore>>;
+
+pub struct Registry {
+    backends: BTreeMap<String, OpenStore>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        let mut registry = Self {
+            backends: BTreeMap::new(),
+        };
+        registry.register("memory", |_, _| Ok(Box::new(BtreeStore::new())));
+        registry.register("wal", |name, options| {
+            let path = options.dir.join(format!("{}.wal", name));
+            Ok(Box::new(Wal::new(&path, Box::new(BtreeStore::new()), options.sync)?))
+        });
+        registry.register("file", |name, options| {
+            let path = options.dir.join(format!("{}.data", name));
+            Ok(Box::new(FileStore::new(&path, options.sync)?))
+        });
+        registry
+    }
+}
+
+impl Registry {
+    /// 注册一个存储 名字相同的时候替换原来的
+    pub fn register(&mut self, backend: &str, open: OpenStore) {
+        self.backends.insert(backend.to_string(), open);
+    }
+
+    /// 所有注册的存储的名字
+    pub fn backends(&self) -> Vec<&str> {
+        self.backends.keys().map(|b| b.as_str()).collect()
+    }
+
+    pub fn open(
+        &self,
+        backend: &str,
+        name: &str,
+        options: &StoreOptions,
+    ) -> Result<Box<dyn SqlStore>> {
+        match self.backends.get(backend) {
+            Some(open) => open(name, options),
+            None => Err(Error::Config(format!(
+                "unknown storage {}, expected one of {}",
+                backend,
+                self.backends().join(", ")
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::kv::suite;
+
+    #[test]
+    fn registry_test() -> Result<()> {
+        let dir = std::env::temp_dir()
+            .join(format!("coke_db_registry_test_{}", std::process::id()));
+        let options = StoreOptions {
+            dir: dir.clone(),
+            sync: SyncPolicy::Always,
+        };
+        let mut registry = Registry::default();
+        assert_eq!(registry.backends(), vec!["file", "memory", "wal"]);
+        for backend in ["memory", "wal", "file"] {
+            suite::run(registry.open(backend, "sql", &options)?.as_mut())?;
+        }
+        assert!(dir.join("sql.wal").exists() && dir.join("sql.data").exists());
+        assert!(registry.open("sled", "sql", &options).is_err());
+        registry.register("sled", |_, _| Ok(Box::new(BtreeStore::new())));
+        registry.open("sled", "sql", &options)?;
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
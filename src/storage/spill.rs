@@ -0,0 +1,160 @@
+/* 临时文件 排序之类的操作数据超过内存限制的时候先写到这里 之后再按顺序读回来
+ * 记录格式: [长度 u32][bincode编码的数据]
+ * 文件只在当前进程中使用 写完或者读完之后drop的时候删除
+ * 文件名是随机的 创建的时候文件已经存在就失败 不会打开别人提前放好的文件
+ * */
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::Path;
+
+use serde::{de::DeserializeOwned, Serialize};
+use tempfile::TempPath;
+
+use crate::errors::*;
+
+/// 只能追加写入的临时文件
+pub struct SpillFile<T> {
+    path: TempPath,
+    writer: BufWriter<File>,
+    len: usize,
+    _item: PhantomData<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> SpillFile<T> {
+    /// 在dir中创建一个新的临时文件
+    pub fn new(dir: &Path) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+        let (file, path) = tempfile::Builder::new()
+            .prefix("coke_db_spill_")
+            .tempfile_in(dir)?
+            .into_parts();
+        Ok(Self {
+            path,
+            writer: BufWriter::new(file),
+            len: 0,
+            _item: PhantomData,
+        })
+    }
+
+    pub fn write(&mut self, item: &T) -> Result<()> {
+        let data = bincode::serialize(item)?;
+        self.writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        self.writer.write_all(&data)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// 写入的数量
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// 写入结束 从头开始按照写入的顺序读取
+    pub fn into_reader(self) -> Result<SpillReader<T>> {
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?;
+        file.seek(SeekFrom::Start(0))?;
+        Ok(SpillReader {
+            _path: self.path,
+            reader: BufReader::new(file),
+            remain: self.len,
+            _item: PhantomData,
+        })
+    }
+
+    /// 写入结束 关闭文件 之后再打开读取 还没有读取的文件不占用文件描述符
+    pub fn finish(self) -> Result<SpillRun<T>> {
+        self.writer.into_inner().map_err(|e| e.into_error())?;
+        Ok(SpillRun {
+            path: self.path,
+            len: self.len,
+            _item: PhantomData,
+        })
+    }
+}
+
+/// 已经写完关闭的临时文件
+pub struct SpillRun<T> {
+    path: TempPath,
+    len: usize,
+    _item: PhantomData<T>,
+}
+
+impl<T> SpillRun<T> {
+    /// 重新打开文件 按照写入的顺序读取
+    pub fn into_reader(self) -> Result<SpillReader<T>> {
+        let file = File::open(&self.path)?;
+        Ok(SpillReader {
+            _path: self.path,
+            reader: BufReader::new(file),
+            remain: self.len,
+            _item: PhantomData,
+        })
+    }
+}
+
+pub struct SpillReader<T> {
+    _path: TempPath,
+    reader: BufReader<File>,
+    remain: usize,
+    _item: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> SpillReader<T> {
+    fn read(&mut self) -> Result<T> {
+        let mut len = [0; 4];
+        self.reader.read_exact(&mut len)?;
+        let mut data = vec![0; u32::from_be_bytes(len) as usize];
+        self.reader.read_exact(&mut data)?;
+        Ok(bincode::deserialize(&data)?)
+    }
+}
+
+impl<T: DeserializeOwned> Iterator for SpillReader<T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remain == 0 {
+            return None;
+        }
+        self.remain -= 1;
+        Some(self.read())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spill_test() -> Result<()> {
+        let dir = std::env::temp_dir();
+        let mut file = SpillFile::new(&dir)?;
+        for i in 0..100u64 {
+            file.write(&(i, format!("row {}", i)))?;
+        }
+        assert_eq!(file.len(), 100);
+        let path = file.path.to_path_buf();
+        let rows = file.into_reader()?.collect::<Result<Vec<(u64, String)>>>()?;
+        assert_eq!(rows.len(), 100);
+        assert_eq!(rows[42], (42, "row 42".to_string()));
+        // 读完drop之后删除
+        assert!(!path.exists());
+
+        // 关闭之后再打开读取
+        let mut file = SpillFile::new(&dir)?;
+        file.write(&1u64)?;
+        file.write(&2u64)?;
+        let run = file.finish()?;
+        let path = run.path.to_path_buf();
+        assert!(path.exists());
+        assert_eq!(run.into_reader()?.collect::<Result<Vec<u64>>>()?, vec![1, 2]);
+        assert!(!path.exists());
+        Ok(())
+    }
+}
@@ -0,0 +1,404 @@
+/* 预写日志 所有的 set/delete 先写到日志文件再交给底层存储
+ * 每次 flush 写入一条提交记录 启动的时候只重放最后一条提交记录之前的操作
+ * 没有提交的操作或者写了一半的记录都会被丢弃 所以崩溃之后状态总是某次 flush 时的样子
+ *
+ * 日志超过检查点文件的大小(至少 checkpoint_size)的时候做检查点 把整个存储写到检查点文件再清空日志
+ * 启动的时候先加载检查点 再重放之后的日志 清空日志之前崩溃的话日志会在检查点上再重放一次
+ * 日志中都是直接写入的 set/delete 重放到已经包含它们的状态上结果不变
+ *
+ * 记录格式: [长度 u32][校验和 u32][bincode编码的Record] 检查点文件使用同样的格式
+ * */
+
+use std::fmt::Display;
+use std::ops::{Bound, RangeBounds};
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use log::{debug, info};
+use serde_derive::{Deserialize, Serialize};
+
+use super::kv::{KvBatch, MyRange, Scan, SqlStore};
+use crate::errors::*;
+
+/// 什么时候把日志 fsync 到磁盘
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    /// 每次 flush 都 fsync
+    Always,
+    /// flush 的时候距离上次 fsync 超过间隔才 fsync
+    /// 崩溃时最多丢失一个间隔内提交的数据 但是不会出现不一致
+    Interval(Duration),
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+enum Record {
+    Set(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    /// 范围删除只记录范围 不记录删除了哪些key
+    DeleteRange(Bound<Vec<u8>>, Bound<Vec<u8>>),
+    Commit,
+}
+
+/// 日志至少有这么大的时候才做检查点
+pub const DEFAULT_CHECKPOINT_SIZE: u64 = 64 * 1024 * 1024;
+
+/// 带预写日志的存储 包装一个其他的存储
+pub struct Wal {
+    store: Box<dyn SqlStore>,
+    path: PathBuf,
+    file: BufWriter<File>,
+    sync: SyncPolicy,
+    last_sync: Instant,
+    /// 日志文件的字节数
+    size: u64,
+    /// 上一个检查点文件的字节数
+    checkpointed: u64,
+    checkpoint_size: u64,
+}
+
+impl Wal {
+    /// 打开日志文件 重放已经提交的操作到store中
+    pub fn new(path: &Path, mut store: Box<dyn SqlStore>, sync: SyncPolicy) -> Result<Self> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        let checkpoint = Self::checkpoint_path(path);
+        let checkpointed = match File::open(&checkpoint) {
+            Ok(mut file) => Self::recover(&mut file, store.as_mut())?.0,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let (committed, replayed) = Self::recover(&mut file, store.as_mut())?;
+        // 把没有提交的部分截掉 之后的记录接着最后一次提交写
+        file.set_len(committed)?;
+        file.seek(SeekFrom::End(0))?;
+        store.flush()?;
+        info!(
+            "wal {} load checkpoint {} bytes, replay {} records, {} bytes",
+            path.display(),
+            checkpointed,
+            replayed,
+            committed
+        );
+        Ok(Self {
+            store,
+            path: path.to_path_buf(),
+            file: BufWriter::new(file),
+            sync,
+            last_sync: Instant::now(),
+            size: committed,
+            checkpointed,
+            checkpoint_size: DEFAULT_CHECKPOINT_SIZE,
+        })
+    }
+
+    /// 日志至少有size字节并且超过上一个检查点的时候做检查点
+    pub fn with_checkpoint_size(mut self, size: u64) -> Self {
+        self.checkpoint_size = size;
+        self
+    }
+
+    fn checkpoint_path(path: &Path) -> PathBuf {
+        path.with_extension("checkpoint")
+    }
+
+    /// 把整个存储写到新的检查点文件 替换旧的之后清空日志
+    /// 调用之前日志中的操作都已经提交
+    pub fn checkpoint(&mut self) -> Result<()> {
+        let path = Self::checkpoint_path(&self.path);
+        let tmp = path.with_extension("checkpoint.tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        let mut size = 0;
+        for item in self.store.scan(MyRange::new(..)) {
+            let (key, value) = item?;
+            size += Self::write_record(&mut writer, &Record::Set(key, value))?;
+        }
+        size += Self::write_record(&mut writer, &Record::Commit)?;
+        writer.flush()?;
+        writer.get_ref().sync_all()?;
+        drop(writer);
+        std::fs::rename(&tmp, &path)?;
+        if let Some(dir) = path.parent() {
+            File::open(dir)?.sync_all()?;
+        }
+        // 检查点已经在磁盘上 之后清空日志
+        self.file.flush()?;
+        self.file.get_ref().set_len(0)?;
+        self.file.seek(SeekFrom::Start(0))?;
+        self.file.get_ref().sync_data()?;
+        let wal = self.path.display();
+        debug!("wal {} checkpoint {} bytes, truncate {} bytes", wal, size, self.size);
+        self.size = 0;
+        self.checkpointed = size;
+        Ok(())
+    }
+
+    /// 读取日志 返回最后一条提交记录的结束位置和重放的记录数
+    fn recover(file: &mut File, store: &mut dyn SqlStore) -> Result<(u64, u64)> {
+        file.seek(SeekFrom::Start(0))?;
+        let mut reader = BufReader::new(file);
+        let mut pending = Vec::new();
+        let mut offset = 0;
+        let mut committed = 0;
+        let mut replayed = 0;
+        while let Some((record, size)) = Self::read_record(&mut reader)? {
+            offset += size;
+            match record {
+                Record::Commit => {
+                    for record in pending.drain(..) {
+                        match record {
+                            Record::Set(key, value) => store.set(&key, value)?,
+                            Record::Delete(key) => store.delete(&key)?,
+                            Record::DeleteRange(start, end) => {
+                                store.delete_range(MyRange::new((start, end)))?;
+                            }
+                            Record::Commit => {}
+                        }
+                        replayed += 1;
+                    }
+                    committed = offset;
+                }
+                record => pending.push(record),
+            }
+        }
+        if !pending.is_empty() {
+            debug!("wal discard {} uncommitted records", pending.len());
+        }
+        Ok((committed, replayed))
+    }
+
+    /// 读取一条记录 文件结束或者记录不完整 校验失败都返回None
+    fn read_record(reader: &mut impl Read) -> Result<Option<(Record, u64)>> {
+        let mut header = [0; 8];
+        if !Self::read_full(reader, &mut header)? {
+            return Ok(None);
+        }
+        let len = u32::from_be_bytes(header[0..4].try_into()?) as usize;
+        let checksum = u32::from_be_bytes(header[4..8].try_into()?);
+        let mut data = vec![0; len];
+        if !Self::read_full(reader, &mut data)? || Self::checksum(&data) != checksum {
+            return Ok(None);
+        }
+        match bincode::deserialize(&data) {
+            Ok(record) => Ok(Some((record, (header.len() + len) as u64))),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// 读满buf 读到文件结尾返回false
+    fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> Result<bool> {
+        match reader.read_exact(buf) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// FNV-1a 只用来发现写了一半的记录
+    pub(crate) fn checksum(data: &[u8]) -> u32 {
+        data.iter().fold(0x811c9dc5, |hash, b| {
+            (hash ^ *b as u32).wrapping_mul(0x01000193)
+        })
+    }
+
+    fn append(&mut self, record: &Record) -> Result<()> {
+        self.size += Self::write_record(&mut self.file, record)?;
+        Ok(())
+    }
+
+    /// 返回写入的字节数
+    fn write_record(writer: &mut impl Write, record: &Record) -> Result<u64> {
+        let data = bincode::serialize(record)?;
+        writer.write_all(&(data.len() as u32).to_be_bytes())?;
+        writer.write_all(&Self::checksum(&data).to_be_bytes())?;
+        writer.write_all(&data)?;
+        Ok((8 + data.len()) as u64)
+    }
+}
+
+impl Display for Wal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Wal({}, {})", self.path.display(), self.store)
+    }
+}
+
+impl SqlStore for Wal {
+    fn delete(&mut self, key: &[u8]) -> Result<()> {
+        self.append(&Record::Delete(key.to_vec()))?;
+        self.store.delete(key)
+    }
+
+    fn delete_range(&mut self, range: MyRange) -> Result<u64> {
+        let record = Record::DeleteRange(range.start_bound().cloned(), range.end_bound().cloned());
+        self.append(&record)?;
+        self.store.delete_range(range)
+    }
+
+    /// 写入提交记录 根据策略决定是否fsync
+    fn flush(&mut self) -> Result<()> {
+        self.append(&Record::Commit)?;
+        self.file.flush()?;
+        let sync = match self.sync {
+            SyncPolicy::Always => true,
+            SyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+        if sync {
+            self.file.get_ref().sync_data()?;
+            self.last_sync = Instant::now();
+        }
+        self.store.flush()?;
+        // 写检查点的代价和存储的大小成正比 日志比上一个检查点还大的时候才做
+        if self.size >= self.checkpoint_size.max(self.checkpointed) {
+            self.checkpoint()?;
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.store.get(key)
+    }
+
+    fn scan(&self, range: MyRange) -> Scan {
+        self.store.scan(range)
+    }
+
+    fn scan_limit(&self, range: MyRange, limit: usize, reverse: bool) -> Result<KvBatch> {
+        self.store.scan_limit(range, limit, reverse)
+    }
+
+    fn set(&mut self, key: &[u8], value: Vec<u8>) -> Result<()> {
+        self.append(&Record::Set(key.to_vec(), value.clone()))?;
+        self.store.set(key, value)
+    }
+}
+
+impl Drop for Wal {
+    fn drop(&mut self) {
+        // 没有提交的记录写下去也没关系 重放的时候会被丢弃
+        let _ = self.file.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::kv::b_tree::BtreeStore;
+
+    #[test]
+    fn recover_test() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("coke_db_wal_test_{}", std::process::id()))
+            .join("sql.wal");
+        let _ = std::fs::remove_file(&path);
+        let open = || Wal::new(&path, Box::new(BtreeStore::new()), SyncPolicy::Always);
+
+        let mut wal = open()?;
+        wal.set(b"a", vec![1])?;
+        wal.set(b"b", vec![2])?;
+        wal.flush()?;
+        wal.delete(b"a")?;
+        wal.set(b"c", vec![3])?;
+        wal.flush()?;
+        // 没有flush的操作在重启之后消失
+        wal.set(b"d", vec![4])?;
+        drop(wal);
+
+        // 模拟写了一半的记录
+        let mut file = OpenOptions::new().append(true).open(&path)?;
+        file.write_all(&[0, 0, 0, 9, 1, 2])?;
+        drop(file);
+
+        let mut wal = open()?;
+        assert_eq!(wal.get(b"a")?, None);
+        assert_eq!(wal.get(b"b")?, Some(vec![2]));
+        assert_eq!(wal.get(b"c")?, Some(vec![3]));
+        assert_eq!(wal.get(b"d")?, None);
+
+        // 范围删除在重放的时候也是范围删除
+        for key in [b"x1", b"x2", b"x3"] {
+            wal.set(key, vec![6])?;
+        }
+        assert_eq!(wal.delete_range(MyRange::new(b"x".to_vec()..b"x3".to_vec()))?, 2);
+        wal.flush()?;
+        drop(wal);
+        let mut wal = open()?;
+        assert_eq!(wal.get(b"x1")?, None);
+        assert_eq!(wal.get(b"x2")?, None);
+        assert_eq!(wal.get(b"x3")?, Some(vec![6]));
+
+        // 截掉坏的部分之后可以接着写
+        wal.set(b"e", vec![5])?;
+        wal.flush()?;
+        drop(wal);
+        let wal = open()?;
+        assert_eq!(wal.get(b"e")?, Some(vec![5]));
+        assert_eq!(wal.get(b"c")?, Some(vec![3]));
+
+        std::fs::remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+
+    #[test]
+    fn checkpoint_test() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("coke_db_wal_checkpoint_test_{}", std::process::id()))
+            .join("sql.wal");
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+        let open = |size| -> Result<Wal> {
+            let wal = Wal::new(&path, Box::new(BtreeStore::new()), SyncPolicy::Always)?;
+            Ok(wal.with_checkpoint_size(size))
+        };
+        let len = |path: &Path| std::fs::metadata(path).map_or(0, |m| m.len());
+        let checkpoint = path.with_extension("checkpoint");
+
+        let mut wal = open(u64::MAX)?;
+        for key in [b"a", b"b", b"c"] {
+            wal.set(key, vec![1])?;
+        }
+        wal.delete_range(MyRange::new(b"c".to_vec()..))?;
+        wal.flush()?;
+        let log = std::fs::read(&path)?;
+        wal.checkpoint()?;
+        assert_eq!(len(&path), 0);
+        assert!(len(&checkpoint) > 0);
+
+        // 重启的时候加载检查点 只重放之后的日志
+        wal.set(b"d", vec![2])?;
+        wal.delete(b"a")?;
+        wal.flush()?;
+        drop(wal);
+        let tail = len(&path);
+        let wal = open(u64::MAX)?;
+        assert_eq!(len(&path), tail);
+        let keys = wal.scan(MyRange::new(..)).collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, vec![(b"b".to_vec(), vec![1]), (b"d".to_vec(), vec![2])]);
+        drop(wal);
+
+        // 清空日志之前崩溃 检查点之前的日志再重放一次结果不变
+        std::fs::write(&path, log)?;
+        let wal = open(u64::MAX)?;
+        assert_eq!(wal.get(b"a")?, Some(vec![1]));
+        assert_eq!(wal.get(b"b")?, Some(vec![1]));
+        assert_eq!(wal.get(b"c")?, None);
+        drop(wal);
+
+        // 日志比检查点大的时候 flush 自动做检查点
+        let mut wal = open(1)?;
+        wal.set(b"e", vec![3])?;
+        wal.flush()?;
+        assert_eq!(len(&path), 0);
+        drop(wal);
+        assert_eq!(open(1)?.get(b"e")?, Some(vec![3]));
+
+        std::fs::remove_dir_all(path.parent().unwrap())?;
+        Ok(())
+    }
+}
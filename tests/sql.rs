@@ -0,0 +1,202 @@
+//! 运行 tests/sql 下的sql脚本 每个脚本使用一个新的kv引擎和会话 结果和脚本中写的不同的时候失败
+//! 设置环境变量 UPDATE_GOLDEN=1 的时候用实际的结果改写脚本中查询的结果
+//!
+//! 脚本由空行分开的记录组成 #开头的行是注释
+//!   statement ok            语句执行成功
+//!   statement error <文字>  语句执行失败 错误信息中包含后面的文字 可以不写
+//!   statement count <n>     插入 更新或者删除了n行
+//!   query [rowsort]         之后是查询语句 ---- 之后是结果 每行一个 列之间用空格分开
+//!                           rowsort 的时候结果排序之后比较 explain 的结果是计划的每一行
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use coke_db::errors::Result;
+use coke_db::sql::engine::{kv::KV, Engine, SqlSession};
+use coke_db::sql::execution::ResultSet;
+use coke_db::sql::Value;
+use coke_db::storage::kv::{b_tree::BtreeStore, MVCC};
+
+#[derive(Debug)]
+enum Kind {
+    Ok,
+    Error(String),
+    Count(u64),
+    Query { rowsort: bool },
+}
+
+#[derive(Debug)]
+struct Record {
+    /// 记录开始的行号 从1开始
+    line: usize,
+    kind: Kind,
+    sql: String,
+    /// 查询的结果在脚本中的行的范围
+    expected: std::ops::Range<usize>,
+}
+
+fn parse(lines: &[&str]) -> std::result::Result<Vec<Record>, String> {
+    let mut records = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i].trim();
+        if line.is_empty() || line.starts_with('#') {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let words = line.split_whitespace().collect::<Vec<_>>();
+        let kind = match words.as_slice() {
+            ["statement", "ok"] => Kind::Ok,
+            ["statement", "error", message @ ..] => Kind::Error(message.join(" ")),
+            ["statement", "count", n] => {
+                Kind::Count(n.parse().map_err(|_| format!("line {}: bad count", i + 1))?)
+            }
+            ["query"] => Kind::Query { rowsort: false },
+            ["query", "rowsort"] => Kind::Query { rowsort: true },
+            _ => return Err(format!("line {}: unknown record {}", i + 1, line)),
+        };
+        i += 1;
+        let mut sql = Vec::new();
+        while i < lines.len() && !lines[i].trim().is_empty() && lines[i].trim() != "----" {
+            sql.push(lines[i]);
+            i += 1;
+        }
+        if sql.is_empty() {
+            return Err(format!("line {}: record without sql", start + 1));
+        }
+        let mut expected = i..i;
+        if let Kind::Query { .. } = kind {
+            if i >= lines.len() || lines[i].trim() != "----" {
+                return Err(format!("line {}: query without ----", start + 1));
+            }
+            i += 1;
+            expected = i..i;
+            while i < lines.len() && !lines[i].trim().is_empty() {
+                i += 1;
+            }
+            expected.end = i;
+        }
+        records.push(Record {
+            line: start + 1,
+            kind,
+            sql: sql.join("\n"),
+            expected,
+        });
+    }
+    Ok(records)
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(s) if s.is_empty() => "(empty)".into(),
+        value => value.to_string(),
+    }
+}
+
+/// 查询的结果 每行一个字符串
+fn format_result(result: ResultSet) -> std::result::Result<Vec<String>, String> {
+    match result {
+        ResultSet::Query { rows, .. } => Ok(rows
+            .iter()
+            .map(|row| row.iter().map(format_value).collect::<Vec<_>>().join(" "))
+            .collect()),
+        ResultSet::Explain { plan, estimates } => {
+            Ok(plan.format_explain(&estimates).lines().map(|l| l.to_string()).collect())
+        }
+        ResultSet::ExplainJson { json } => Ok(json.lines().map(|l| l.to_string()).collect()),
+        r => Err(format!("expected rows, got {:?}", r)),
+    }
+}
+
+/// 执行一条记录 返回查询的结果 结果不对的时候返回错误
+fn run_record(
+    session: &mut SqlSession<KV>,
+    record: &Record,
+    lines: &[&str],
+) -> std::result::Result<Vec<String>, String> {
+    let result = session.execute(&record.sql);
+    match (&record.kind, result) {
+        (Kind::Ok, Ok(_)) => Ok(Vec::new()),
+        (Kind::Error(message), Err(e)) if e.to_string().contains(message.as_str()) => {
+            Ok(Vec::new())
+        }
+        (Kind::Error(message), Err(e)) => {
+            Err(format!("expected error containing {:?}, got {}", message, e))
+        }
+        (Kind::Error(_), Ok(r)) => Err(format!("expected error, got {:?}", r)),
+        (Kind::Count(n), Ok(r)) => match r {
+            ResultSet::Create { count } | ResultSet::Update { count } | ResultSet::Delete { count }
+                if count == *n =>
+            {
+                Ok(Vec::new())
+            }
+            r => Err(format!("expected count {}, got {:?}", n, r)),
+        },
+        (Kind::Query { rowsort }, Ok(r)) => {
+            let mut actual = format_result(r)?;
+            if *rowsort {
+                actual.sort();
+            }
+            let expected = &lines[record.expected.clone()];
+            if update() || expected.iter().map(|l| l.trim_end()).eq(actual.iter()) {
+                return Ok(actual);
+            }
+            Err(format!("expected:\n{}\nactual:\n{}", expected.join("\n"), actual.join("\n")))
+        }
+        (_, Err(e)) => Err(format!("unexpected error {}", e)),
+    }
+}
+
+fn update() -> bool {
+    std::env::var("UPDATE_GOLDEN").is_ok_and(|v| v == "1")
+}
+
+/// 运行一个脚本 返回所有失败的记录
+fn run_script(path: &Path) -> Result<Vec<String>> {
+    let content = fs::read_to_string(path)?;
+    let lines = content.lines().collect::<Vec<_>>();
+    let records = match parse(&lines) {
+        Ok(records) => records,
+        Err(e) => return Ok(vec![format!("{}: {}", path.display(), e)]),
+    };
+    let engine = KV::new(MVCC::new(Box::new(BtreeStore::new())));
+    let mut session = engine.session()?;
+    let mut failures = Vec::new();
+    let mut updated = Vec::new();
+    let mut next = 0;
+    for record in records.iter() {
+        match run_record(&mut session, record, &lines) {
+            Ok(actual) => {
+                updated.extend(lines[next..record.expected.start].iter().map(|l| l.to_string()));
+                updated.extend(actual);
+                next = record.expected.end;
+            }
+            Err(e) => {
+                let sql = record.sql.replace('\n', " ");
+                failures.push(format!("{}:{}: {}\n{}", path.display(), record.line, sql, e));
+            }
+        }
+    }
+    if update() {
+        updated.extend(lines[next..].iter().map(|l| l.to_string()));
+        fs::write(path, updated.join("\n") + "\n")?;
+    }
+    Ok(failures)
+}
+
+#[test]
+fn sql_scripts() -> Result<()> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("sql");
+    let mut paths = fs::read_dir(&dir)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    paths.retain(|p| p.extension().is_some_and(|e| e == "sql"));
+    paths.sort();
+    assert!(!paths.is_empty(), "no scripts in {}", dir.display());
+    let mut failures = Vec::new();
+    for path in paths {
+        failures.extend(run_script(&path)?);
+    }
+    assert!(failures.is_empty(), "{} failures\n\n{}", failures.len(), failures.join("\n\n"));
+    Ok(())
+}
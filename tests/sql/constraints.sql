@@ -0,0 +1,39 @@
+# 主键 唯一 非空 外键和 CHECK 约束
+
+statement ok
+create table parent ( id int primary key, code string unique );
+
+statement ok
+create table child ( id int primary key, parent_id int references parent, n int not null check (n > 0) );
+
+statement ok
+insert into parent values (1, "a"), (2, "b");
+
+statement error
+insert into parent values (1, "c");
+
+statement error
+insert into parent values (3, "a");
+
+statement error
+insert into child values (1, 9, 1);
+
+statement error
+insert into child values (1, 1, null);
+
+statement error
+insert into child values (1, 1, 0);
+
+statement count 1
+insert into child values (1, 1, 5);
+
+statement error
+delete from parent where id = 1;
+
+statement count 1
+delete from parent where id = 2;
+
+query
+select id, code from parent;
+----
+1 a
@@ -0,0 +1,74 @@
+# 多列 group by 和 order by having 一起使用 order by 中加到 select 的列不出现在结果中
+
+statement ok
+create table t ( id int primary key, a int, b int, c int );
+
+statement ok
+insert into t values (1, 1, 1, 10), (2, 1, 2, 20), (3, 2, 1, 30), (4, 2, 2, 5), (5, 1, 1, 7);
+
+query
+select a, sum(c) from t group by a, b order by b, max(c);
+----
+1 17
+2 30
+2 5
+1 20
+
+query
+select a from t group by a, b having sum(c) > 10 order by b, count(*);
+----
+2
+1
+1
+
+query
+select a, b from t group by a, b order by a * 2 + b desc, min(c) desc;
+----
+2 2
+2 1
+1 2
+1 1
+
+query
+select sum(c) s from t group by a, b order by s, a, b;
+----
+5
+17
+20
+30
+
+query
+select a + b, count(*) from t group by a, b having max(c) > 7 order by min(c), b desc;
+----
+2 2
+3 1
+3 1
+
+query
+select a from t group by a, b order by b desc, a limit 3;
+----
+1
+2
+1
+
+query
+select max(c) from t group by a, b having count(*) > 0 order by a, b;
+----
+10
+20
+30
+5
+
+query
+select a x, a + b from t group by a, b order by x + b, b, max(c), x;
+----
+1 2
+2 3
+1 3
+2 4
+
+query
+select count(*) from t group by a, b having min(c) < 30 order by max(c) desc, sum(c) limit 2;
+----
+1
+2
@@ -0,0 +1,121 @@
+# 示例数据上的查询
+
+statement ok
+create table student ( id int primary key, sex bool not null, year int not null, name string not null );
+
+statement count 4
+insert into student values (1, true, 2001, "xiaoming"), (2, false, 2002, "xiaohong"), (3, true, 2002, "xiaogang"), (4, false, 2003, "xiaoli");
+
+statement ok
+create table grade ( id int primary key, stu_id int not null, course string not null, grade float not null );
+
+statement count 4
+insert into grade values (1, 1, "语文", 99.0), (2, 1, "数学", 80.0), (3, 2, "英语", 70.0), (4, 2, "语文", 99.0);
+
+query
+select id, name from student where year = 2002 order by id;
+----
+2 xiaohong
+3 xiaogang
+
+query rowsort
+select year, count(*) from student group by year;
+----
+2001 1
+2002 2
+2003 1
+
+query
+select s.name, g.course, g.grade from student s join grade g on s.id = g.stu_id order by g.id;
+----
+xiaoming 语文 99
+xiaoming 数学 80
+xiaohong 英语 70
+xiaohong 语文 99
+
+query
+select s.name, sum(g.grade) from student s left join grade g on s.id = g.stu_id group by s.name order by s.name;
+----
+xiaogang NULL
+xiaohong 169
+xiaoli NULL
+xiaoming 179
+
+query
+select name from student order by year desc, id limit 2 offset 1;
+----
+xiaohong
+xiaogang
+
+query
+select "" , null, 1 + 2 * 3;
+----
+(empty) NULL 7
+
+statement error
+select missing from student;
+
+# 三值逻辑 null and false 是 false null or true 是 true
+query
+select s.name, g.grade > 90 or g.grade is null, g.grade < 90 and s.sex from student s left join grade g on s.id = g.stu_id order by g.id;
+----
+xiaogang TRUE NULL
+xiaoli TRUE FALSE
+xiaoming TRUE FALSE
+xiaoming FALSE TRUE
+xiaohong FALSE FALSE
+xiaohong TRUE FALSE
+
+# 浮点数 NaN 比其他的数字都大 -0.0 排在 0.0 前面
+query
+select column1 from (values (1.5), (nan), (0.0), (-0.0), (-infinity)) order by column1;
+----
+-inf
+-0
+0
+1.5
+NaN
+
+query
+select max(column1), min(column1) from (values (1.5), (nan), (-infinity));
+----
+NaN -inf
+
+# 比较运算和排序 分组使用同一个顺序 数值相同的整数和浮点数相等 NaN 等于 NaN
+query
+select 1 = 1.0, 9007199254740993 = 9007199254740992.0, 9007199254740993 > 9007199254740992.0, nan = nan, nan > infinity, -0.0 < 0;
+----
+TRUE FALSE TRUE TRUE TRUE TRUE
+
+query
+select count(*) from (values (1), (1.0), (2)) group by column1 order by column1;
+----
+2
+1
+
+query
+select column1 from (values (1), (1.0), (0.0), (-0.0), (0)) group by column1 order by column1;
+----
+-0
+0
+1
+
+# 开始大于结束的索引范围是空的
+statement ok
+create table r ( id int primary key, x int index );
+
+statement ok
+insert into r values (1, 1), (2, 7), (3, 12);
+
+query
+select id from r where x > 10 and x < 5;
+----
+
+query
+select x from r where x >= 7 and x < 7;
+----
+
+query
+select count(*) from r where x > 10 and x < 5;
+----
+0
@@ -0,0 +1,207 @@
+# 事务的提交 回滚和保存点
+
+statement ok
+create table t ( id int primary key, n int );
+
+statement ok
+begin transaction;
+
+statement count 2
+insert into t values (1, 10), (2, 20);
+
+statement ok
+rollback;
+
+query
+select count(*) from t;
+----
+0
+
+statement ok
+begin transaction;
+
+statement ok
+insert into t values (1, 10);
+
+statement ok
+savepoint a;
+
+statement count 1
+update t set n = 11 where id = 1;
+
+statement ok
+rollback to savepoint a;
+
+statement ok
+commit;
+
+query
+select id, n from t;
+----
+1 10
+
+statement error
+commit;
+
+# 只读事务中可以查询 修改的语句在规划之前报错 事务还可以继续使用
+statement ok
+begin transaction read only;
+
+query
+select id, n from t where id = 1;
+----
+1 10
+
+statement error cannot execute INSERT in a read only transaction
+insert into t values (3, 30);
+
+statement error cannot execute UPDATE in a read only transaction
+update t set n = 0;
+
+statement error cannot execute DELETE in a read only transaction
+delete from t;
+
+statement error cannot execute CREATE TABLE in a read only transaction
+create table u ( id int primary key );
+
+statement error cannot execute TRUNCATE in a read only transaction
+truncate table t;
+
+statement error cannot execute DROP TABLE in a read only transaction
+drop table t;
+
+statement error cannot execute INSERT in a read only transaction
+explain analyze insert into t values (3, 30);
+
+query
+select count(*) from t;
+----
+1
+
+statement ok
+commit;
+
+# 读取历史版本的事务也是只读的
+statement ok
+begin transaction read only as 1;
+
+statement error cannot execute UPDATE in a read only transaction
+update t set n = 0;
+
+statement ok
+rollback;
+
+# 一个事务最多写入的行数 超过之后语句报错 事务可以回滚
+statement ok
+create table w ( id int primary key, n int );
+
+statement ok
+set max_txn_rows = 3;
+
+statement ok
+begin transaction;
+
+statement count 2
+insert into w values (1, 1), (2, 2);
+
+statement error transaction writes 4 rows, exceeds max_txn_rows 3
+insert into w values (3, 3), (4, 4);
+
+statement count 1
+insert into w values (3, 3);
+
+statement error exceeds max_txn_rows 3
+update w set n = 0 where id = 1;
+
+statement ok
+rollback;
+
+# 没有显式开启的事务 每条语句分别计算
+statement count 3
+insert into w values (1, 1), (2, 2), (3, 3);
+
+statement count 3
+update w set n = 0;
+
+statement error exceeds max_txn_rows 3
+insert into w values (4, 4), (5, 5), (6, 6), (7, 7);
+
+query
+select count(*) from w;
+----
+3
+
+# 修改主键是删除再插入 算两行
+statement ok
+set max_txn_rows = 1;
+
+statement error transaction writes 2 rows, exceeds max_txn_rows 1
+update w set id = 10 where id = 1;
+
+statement ok
+set max_txn_rows = 0;
+
+statement count 1
+update w set id = 10 where id = 1;
+
+statement error max_txn_rows expect a non-negative integer get -1
+set max_txn_rows = -1;
+
+statement ok
+set max_txn_bytes = 10;
+
+statement error exceeds max_txn_bytes 10
+delete from w;
+
+statement ok
+set max_txn_bytes = 0;
+
+statement count 3
+delete from w;
+
+# 超过限制的语句整条撤销 已经写入的行和索引都不会留下
+statement ok
+create table v ( id int primary key, n int index );
+
+statement ok
+insert into v values (1, 1), (2, 2), (3, 3);
+
+statement ok
+begin transaction;
+
+statement ok
+set max_txn_bytes = 55;
+
+statement error exceeds max_txn_bytes 55
+update v set n = 5;
+
+statement ok
+set max_txn_rows = 2;
+
+statement error exceeds max_txn_rows 2
+truncate table v;
+
+statement ok
+commit;
+
+statement ok
+set max_txn_rows = 0;
+
+statement ok
+set max_txn_bytes = 0;
+
+query
+select * from v where n = 5;
+----
+
+query
+select * from v where n = 1;
+----
+1 1
+
+query
+select * from v;
+----
+1 1
+2 2
+3 3
@@ -0,0 +1,78 @@
+# update 的新值可以引用修改之前的列
+
+statement ok
+create table t ( id int primary key, n int, s string unique );
+
+statement ok
+insert into t values (1, 10, "a"), (2, 20, "b"), (3, 30, "c");
+
+statement count 3
+update t set n = n + id;
+
+query
+select id, n from t;
+----
+1 11
+2 22
+3 33
+
+# 按照主键的顺序更新 id + 1 的位置上还有没更新的行 在删除原来的行之前就报错
+statement error primary key collision on update: 2 already exists in table t
+update t set id = id + 1;
+
+query
+select id, n, s from t;
+----
+1 11 a
+2 22 b
+3 33 c
+
+# id - 1 的位置上的行已经更新过了
+statement count 3
+update t set id = id - 1;
+
+query
+select id, n, s from t;
+----
+0 11 a
+1 22 b
+2 33 c
+
+statement count 3
+update t set id = id + 10;
+
+query
+select id, s from t where s = "b";
+----
+11 b
+
+statement error primary key collision on update: 12 already exists in table t
+update t set id = 12 where id = 10;
+
+# 显式事务中报错之后 原来的行还在
+statement ok
+begin transaction;
+
+statement error primary key collision on update: 11 already exists in table t
+update t set id = 11, n = 0 where id = 10;
+
+query
+select id, n from t where id = 10;
+----
+10 11
+
+statement ok
+commit;
+
+# on conflict 修改主键的时候同样检查
+statement error primary key collision on update: 12 already exists in table t
+insert into t values (11, 0, "x") on conflict (id) do update set id = 12;
+
+# 修改主键的同时唯一字段和其他行重复 原来的行不会被删除
+statement error Unique value b already exists for column s
+update t set id = 20, s = "b" where id = 10;
+
+query
+select id, s from t where id = 10;
+----
+10 a